@@ -0,0 +1,32 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // only on request, so using the library doesn't need a C compiler
+    #[cfg(feature = "c-roundtrip")]
+    roundtrip();
+}
+
+/// Builds the round trip test, which is written in C against the checked in header
+#[cfg(feature = "c-roundtrip")]
+fn roundtrip() {
+    use std::{env, path::PathBuf};
+
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    cc::Build::new()
+        .file("tests/roundtrip.c")
+        .include(crate_dir.join("include"))
+        .cargo_metadata(false)
+        .compile("vdd_roundtrip");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let lib = if env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc") {
+        "vdd_roundtrip.lib"
+    } else {
+        "libvdd_roundtrip.a"
+    };
+    println!("cargo:rustc-link-arg-tests={}", out_dir.join(lib).display());
+
+    println!("cargo:rerun-if-changed=include/vdd.h");
+    println!("cargo:rerun-if-changed=tests/roundtrip.c");
+}
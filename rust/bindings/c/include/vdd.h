@@ -0,0 +1,159 @@
+#ifndef VDD_H
+#define VDD_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of every function of this library
+ */
+typedef enum VddStatus {
+  VDD_STATUS_OK = 0,
+  /**
+   * A pointer was NULL or a string not valid UTF-8
+   */
+  VDD_STATUS_INVALID_ARGUMENT,
+  /**
+   * Talking to the driver failed
+   */
+  VDD_STATUS_CONNECTION,
+  /**
+   * A monitor ID does not exist
+   */
+  VDD_STATUS_NOT_FOUND,
+  /**
+   * The monitor configuration was rejected
+   */
+  VDD_STATUS_INVALID_MONITOR,
+  /**
+   * The driver failed to apply the command
+   */
+  VDD_STATUS_DRIVER,
+  /**
+   * A bug in this library, the client should not be used anymore
+   */
+  VDD_STATUS_PANIC,
+} VddStatus;
+
+/**
+ * Connection to the driver, created with [vdd_connect]
+ */
+typedef struct VddClient VddClient;
+
+/**
+ * A single resolution at a single refresh rate
+ */
+typedef struct VddMode {
+  uint32_t width;
+  uint32_t height;
+  uint32_t refresh_rate;
+} VddMode;
+
+/**
+ * A monitor, as returned from [vdd_list]
+ */
+typedef struct VddMonitor {
+  uint32_t id;
+  bool enabled;
+  /**
+   * NULL if the monitor has no name
+   */
+  char *name;
+  struct VddMode *modes;
+  size_t mode_count;
+} VddMonitor;
+
+/**
+ * List of monitors, free with [vdd_free_list]
+ */
+typedef struct VddMonitorList {
+  struct VddMonitor *monitors;
+  size_t count;
+} VddMonitorList;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Connect to the driver.
+ *
+ * `pipe_name` may be NULL to use the default pipe. On success, `*out_client`
+ * is set to a client which must be freed with [vdd_free_client]. A client
+ * may be used from several threads at the same time, the calls share the
+ * connection and each waits for its own reply. It must not be freed while
+ * another thread still uses it.
+ */
+enum VddStatus vdd_connect(const char *pipe_name, struct VddClient **out_client);
+
+/**
+ * Close the connection and free the client. NULL is ignored.
+ */
+void vdd_free_client(struct VddClient *client);
+
+/**
+ * Add a monitor with the smallest free ID.
+ *
+ * The ID is reserved with the driver first, so monitors added at the same
+ * time, from other threads or other clients, never get the same ID. `name`
+ * may be NULL. `modes` points to `mode_count` modes, modes with the
+ * same resolution are merged into one mode with several refresh rates. The
+ * ID of the new monitor is written to `out_id` unless it is NULL.
+ */
+enum VddStatus vdd_add_monitor(const struct VddClient *client,
+                               const char *name,
+                               const struct VddMode *modes,
+                               size_t mode_count,
+                               bool enabled,
+                               uint32_t *out_id);
+
+/**
+ * Remove the `count` monitors in `ids`.
+ *
+ * Nothing is removed if one of them doesn't exist.
+ */
+enum VddStatus vdd_remove(const struct VddClient *client, const uint32_t *ids, size_t count);
+
+/**
+ * Enable or disable the `count` monitors in `ids`.
+ *
+ * Nothing is changed if one of them doesn't exist.
+ */
+enum VddStatus vdd_set_enabled(const struct VddClient *client,
+                               const uint32_t *ids,
+                               size_t count,
+                               bool enabled);
+
+/**
+ * Get all monitors of the driver.
+ *
+ * Every mode is reported once per refresh rate. On success, `*out_list` must
+ * be freed with [vdd_free_list].
+ */
+enum VddStatus vdd_list(const struct VddClient *client, struct VddMonitorList *out_list);
+
+/**
+ * Free a list from [vdd_list], and reset it to an empty list.
+ *
+ * NULL and empty lists are ignored.
+ */
+void vdd_free_list(struct VddMonitorList *list);
+
+/**
+ * Message of the last error on this thread, NULL after a successful call.
+ *
+ * The string is owned by the library, and valid until the next call of a
+ * function of this library on the same thread.
+ */
+const char *vdd_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* VDD_H */
@@ -0,0 +1,463 @@
+//! C interface to the driver-ipc client.
+//!
+//! The header `include/vdd.h` is generated by cbindgen and checked in,
+//! `tests/header.rs` fails once it is out of date. All functions return a
+//! [VddStatus]; on anything but [VddStatus::Ok], the reason can be read with
+//! [vdd_last_error_message].
+//!
+//! Memory handed out by this library is owned by the caller, and must be
+//! released with the matching `vdd_free_*` function, never with `free()`.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use driver_ipc::{
+    error::{ConnectionError, RequestError, SendError},
+    sync::Client,
+    DriverCommand, ErrorCode, Id, Mode, Monitor, MonitorError,
+};
+use thiserror::Error;
+
+/// Result of every function of this library
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VddStatus {
+    Ok = 0,
+    /// A pointer was NULL or a string not valid UTF-8
+    InvalidArgument,
+    /// Talking to the driver failed
+    Connection,
+    /// A monitor ID does not exist
+    NotFound,
+    /// The monitor configuration was rejected
+    InvalidMonitor,
+    /// The driver failed to apply the command
+    Driver,
+    /// A bug in this library, the client should not be used anymore
+    Panic,
+}
+
+/// Connection to the driver, created with [vdd_connect]
+pub struct VddClient(Client);
+
+/// A single resolution at a single refresh rate
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VddMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u32,
+}
+
+/// A monitor, as returned from [vdd_list]
+#[repr(C)]
+#[derive(Debug)]
+pub struct VddMonitor {
+    pub id: u32,
+    pub enabled: bool,
+    /// NULL if the monitor has no name
+    pub name: *mut c_char,
+    pub modes: *mut VddMode,
+    pub mode_count: usize,
+}
+
+/// List of monitors, free with [vdd_free_list]
+#[repr(C)]
+#[derive(Debug)]
+pub struct VddMonitorList {
+    pub monitors: *mut VddMonitor,
+    pub count: usize,
+}
+
+#[derive(Debug, Error)]
+enum FfiError {
+    #[error("{0} must not be NULL")]
+    Null(&'static str),
+    #[error("{0} is not valid UTF-8")]
+    Utf8(&'static str),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+    #[error("Monitor {0} not found")]
+    NotFound(Id),
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    InvalidMonitor(Vec<MonitorError>),
+    #[error("Internal error: {0}")]
+    Panic(String),
+}
+
+impl FfiError {
+    fn status(&self) -> VddStatus {
+        match self {
+            Self::Null(_) | Self::Utf8(_) => VddStatus::InvalidArgument,
+            Self::NotFound(_) => VddStatus::NotFound,
+            Self::InvalidMonitor(_) => VddStatus::InvalidMonitor,
+            Self::Panic(_) => VddStatus::Panic,
+            Self::Request(RequestError::Ipc(e)) => match e.code {
+                ErrorCode::MonitorNotFound => VddStatus::NotFound,
+                ErrorCode::InvalidMonitors => VddStatus::InvalidMonitor,
+                _ => VddStatus::Driver,
+            },
+            Self::Connection(_) | Self::Send(_) | Self::Request(_) => VddStatus::Connection,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, turning errors and panics into a status and the last error.
+fn ffi(f: impl FnOnce() -> Result<(), FfiError>) -> VddStatus {
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(ToString::to_string)
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+
+        Err(FfiError::Panic(msg))
+    });
+
+    let (status, message) = match result {
+        Ok(()) => (VddStatus::Ok, None),
+        Err(e) => {
+            // messages never contain NUL, except for names coming from C
+            let msg = e.to_string().replace('\0', " ");
+            (e.status(), CString::new(msg).ok())
+        }
+    };
+
+    LAST_ERROR.with_borrow_mut(|last| *last = message);
+    status
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &'static str) -> Result<&'a str, FfiError> {
+    if s.is_null() {
+        return Err(FfiError::Null(name));
+    }
+
+    // SAFETY: caller guarantees a NUL terminated string
+    let s = unsafe { CStr::from_ptr(s) };
+    s.to_str().map_err(|_| FfiError::Utf8(name))
+}
+
+unsafe fn slice_arg<'a, T>(
+    data: *const T,
+    len: usize,
+    name: &'static str,
+) -> Result<&'a [T], FfiError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+
+    if data.is_null() {
+        return Err(FfiError::Null(name));
+    }
+
+    // SAFETY: caller guarantees `len` valid elements
+    Ok(unsafe { slice::from_raw_parts(data, len) })
+}
+
+unsafe fn client_arg<'a>(client: *const VddClient) -> Result<&'a Client, FfiError> {
+    // SAFETY: caller guarantees a client from vdd_connect, or NULL
+    unsafe { client.as_ref() }
+        .map(|c| &c.0)
+        .ok_or(FfiError::Null("client"))
+}
+
+/// Send a driver command, waiting for the driver to apply it if the driver
+/// is new enough to tell.
+fn run(client: &Client, command: DriverCommand) -> Result<(), FfiError> {
+    if client.protocol_version() >= 2 {
+        client.execute(&command)?;
+    } else {
+        match command {
+            DriverCommand::Notify(monitors) => client.notify(&monitors)?,
+            DriverCommand::Remove(ids) => client.remove(&ids)?,
+            _ => unreachable!("only notify and remove are sent"),
+        }
+    }
+
+    Ok(())
+}
+
+fn check_exists(monitors: &[Monitor], ids: &[Id]) -> Result<(), FfiError> {
+    match ids.iter().find(|id| !monitors.iter().any(|m| m.id == **id)) {
+        Some(&id) => Err(FfiError::NotFound(id)),
+        None => Ok(()),
+    }
+}
+
+/// Connect to the driver.
+///
+/// `pipe_name` may be NULL to use the default pipe. On success, `*out_client`
+/// is set to a client which must be freed with [vdd_free_client]. A client
+/// may be used from several threads at the same time, the calls share the
+/// connection and each waits for its own reply. It must not be freed while
+/// another thread still uses it.
+#[no_mangle]
+pub unsafe extern "C" fn vdd_connect(
+    pipe_name: *const c_char,
+    out_client: *mut *mut VddClient,
+) -> VddStatus {
+    ffi(|| {
+        if out_client.is_null() {
+            return Err(FfiError::Null("out_client"));
+        }
+
+        let client = if pipe_name.is_null() {
+            Client::connect()?
+        } else {
+            // SAFETY: caller guarantees a NUL terminated string
+            let name = unsafe { str_arg(pipe_name, "pipe_name") }?;
+            Client::connect_to(name)?
+        };
+
+        let client = Box::into_raw(Box::new(VddClient(client)));
+        // SAFETY: checked for NULL above, caller guarantees it is writable
+        unsafe { out_client.write(client) };
+        Ok(())
+    })
+}
+
+/// Close the connection and free the client. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn vdd_free_client(client: *mut VddClient) {
+    if !client.is_null() {
+        // SAFETY: caller guarantees a client from vdd_connect, freed only once
+        drop(unsafe { Box::from_raw(client) });
+    }
+}
+
+/// Add a monitor with the smallest free ID.
+///
+/// The ID is reserved with the driver first, so monitors added at the same
+/// time, from other threads or other clients, never get the same ID. `name`
+/// may be NULL. `modes` points to `mode_count` modes, modes with the
+/// same resolution are merged into one mode with several refresh rates. The
+/// ID of the new monitor is written to `out_id` unless it is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn vdd_add_monitor(
+    client: *const VddClient,
+    name: *const c_char,
+    modes: *const VddMode,
+    mode_count: usize,
+    enabled: bool,
+    out_id: *mut u32,
+) -> VddStatus {
+    ffi(|| {
+        // SAFETY: caller guarantees a valid client
+        let client = unsafe { client_arg(client) }?;
+        let name = if name.is_null() {
+            None
+        } else {
+            // SAFETY: caller guarantees a NUL terminated string
+            Some(unsafe { str_arg(name, "name") }?.to_owned())
+        };
+        // SAFETY: caller guarantees `mode_count` modes
+        let vdd_modes = unsafe { slice_arg(modes, mode_count, "modes") }?;
+
+        let mut modes = Vec::<Mode>::new();
+        for mode in vdd_modes {
+            match modes
+                .iter_mut()
+                .find(|m| m.width == mode.width && m.height == mode.height)
+            {
+                Some(m) => m.refresh_rates.push(mode.refresh_rate),
+                None => modes.push(Mode {
+                    width: mode.width,
+                    height: mode.height,
+                    refresh_rates: vec![mode.refresh_rate],
+                    timing: None,
+                }),
+            }
+        }
+
+        let id = match client.new_id() {
+            Ok(id) => id,
+            // drivers from before reservations only tell which IDs are used
+            Err(RequestError::Unsupported(_)) => {
+                let state = client.request_state()?;
+                (0..)
+                    .find(|id| !state.iter().any(|m| m.id == *id))
+                    .expect("no free monitor id")
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let monitor = Monitor {
+            id,
+            name,
+            enabled,
+            modes,
+            ..Default::default()
+        };
+        monitor.validate().map_err(FfiError::InvalidMonitor)?;
+
+        run(client, DriverCommand::Notify(vec![monitor]))?;
+
+        if !out_id.is_null() {
+            // SAFETY: caller guarantees it is writable
+            unsafe { out_id.write(id) };
+        }
+
+        Ok(())
+    })
+}
+
+/// Remove the `count` monitors in `ids`.
+///
+/// Nothing is removed if one of them doesn't exist.
+#[no_mangle]
+pub unsafe extern "C" fn vdd_remove(
+    client: *const VddClient,
+    ids: *const u32,
+    count: usize,
+) -> VddStatus {
+    ffi(|| {
+        // SAFETY: caller guarantees a valid client
+        let client = unsafe { client_arg(client) }?;
+        // SAFETY: caller guarantees `count` ids
+        let ids = unsafe { slice_arg(ids, count, "ids") }?;
+
+        check_exists(&client.request_state()?, ids)?;
+        run(client, DriverCommand::Remove(ids.to_vec()))
+    })
+}
+
+/// Enable or disable the `count` monitors in `ids`.
+///
+/// Nothing is changed if one of them doesn't exist.
+#[no_mangle]
+pub unsafe extern "C" fn vdd_set_enabled(
+    client: *const VddClient,
+    ids: *const u32,
+    count: usize,
+    enabled: bool,
+) -> VddStatus {
+    ffi(|| {
+        // SAFETY: caller guarantees a valid client
+        let client = unsafe { client_arg(client) }?;
+        // SAFETY: caller guarantees `count` ids
+        let ids = unsafe { slice_arg(ids, count, "ids") }?;
+
+        let state = client.request_state()?;
+        check_exists(&state, ids)?;
+
+        let changed = state
+            .into_iter()
+            .filter(|m| ids.contains(&m.id))
+            .map(|m| Monitor { enabled, ..m })
+            .collect();
+
+        run(client, DriverCommand::Notify(changed))
+    })
+}
+
+/// Get all monitors of the driver.
+///
+/// Every mode is reported once per refresh rate. On success, `*out_list` must
+/// be freed with [vdd_free_list].
+#[no_mangle]
+pub unsafe extern "C" fn vdd_list(
+    client: *const VddClient,
+    out_list: *mut VddMonitorList,
+) -> VddStatus {
+    ffi(|| {
+        // SAFETY: caller guarantees a valid client
+        let client = unsafe { client_arg(client) }?;
+        if out_list.is_null() {
+            return Err(FfiError::Null("out_list"));
+        }
+
+        let monitors = client
+            .request_state()?
+            .into_iter()
+            .map(|monitor| {
+                let modes = monitor
+                    .modes
+                    .iter()
+                    .flat_map(|mode| {
+                        mode.refresh_rates.iter().map(|&refresh_rate| VddMode {
+                            width: mode.width,
+                            height: mode.height,
+                            refresh_rate,
+                        })
+                    })
+                    .collect::<Box<[_]>>();
+
+                let name = monitor
+                    .name
+                    .and_then(|name| CString::new(name).ok())
+                    .map_or(ptr::null_mut(), CString::into_raw);
+
+                let mode_count = modes.len();
+                VddMonitor {
+                    id: monitor.id,
+                    enabled: monitor.enabled,
+                    name,
+                    modes: Box::into_raw(modes).cast(),
+                    mode_count,
+                }
+            })
+            .collect::<Box<[_]>>();
+
+        let list = VddMonitorList {
+            count: monitors.len(),
+            monitors: Box::into_raw(monitors).cast(),
+        };
+        // SAFETY: checked for NULL above, caller guarantees it is writable
+        unsafe { out_list.write(list) };
+        Ok(())
+    })
+}
+
+/// Free a list from [vdd_list], and reset it to an empty list.
+///
+/// NULL and empty lists are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn vdd_free_list(list: *mut VddMonitorList) {
+    // SAFETY: caller guarantees a list from vdd_list, or NULL
+    let Some(list) = (unsafe { list.as_mut() }) else {
+        return;
+    };
+
+    if !list.monitors.is_null() {
+        let monitors = ptr::slice_from_raw_parts_mut(list.monitors, list.count);
+        // SAFETY: allocated as boxed slice of `count` monitors in vdd_list
+        let monitors = unsafe { Box::from_raw(monitors) };
+
+        for monitor in &monitors {
+            if !monitor.name.is_null() {
+                // SAFETY: allocated with CString::into_raw in vdd_list
+                drop(unsafe { CString::from_raw(monitor.name) });
+            }
+
+            let modes = ptr::slice_from_raw_parts_mut(monitor.modes, monitor.mode_count);
+            // SAFETY: allocated as boxed slice of `mode_count` modes in vdd_list
+            drop(unsafe { Box::from_raw(modes) });
+        }
+    }
+
+    list.monitors = ptr::null_mut();
+    list.count = 0;
+}
+
+/// Message of the last error on this thread, NULL after a successful call.
+///
+/// The string is owned by the library, and valid until the next call of a
+/// function of this library on the same thread.
+#[no_mangle]
+pub extern "C" fn vdd_last_error_message() -> *const c_char {
+    LAST_ERROR.with_borrow(|last| last.as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
@@ -0,0 +1,27 @@
+//! `include/vdd.h` is checked in, so using the library needs neither cbindgen nor a build script
+//! writing into the sources. This keeps it in step with `src/lib.rs`.
+
+use std::{env, fs, path::Path};
+
+#[test]
+fn header_is_current() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let path = crate_dir.join("include/vdd.h");
+
+    let mut generated = Vec::new();
+    cbindgen::generate(crate_dir)
+        .expect("failed to generate bindings")
+        .write(&mut generated);
+
+    if env::var_os("UPDATE_HEADER").is_some() {
+        fs::write(&path, &generated).unwrap();
+        return;
+    }
+
+    let checked_in = fs::read(&path).unwrap();
+    assert!(
+        checked_in == generated,
+        "include/vdd.h is out of date, regenerate it with \
+         `UPDATE_HEADER=1 cargo test -p driver-ipc-ffi --test header`"
+    );
+}
@@ -0,0 +1,67 @@
+/* Round trip through the C interface, driven by tests/roundtrip.rs against a
+ * mock driver. Returns 0, or the number of the step which failed. */
+
+#include <string.h>
+
+#include "vdd.h"
+
+int vdd_roundtrip(const char *pipe_name) {
+  VddClient *client = NULL;
+  if (vdd_connect(pipe_name, &client) != VDD_STATUS_OK) {
+    return 1;
+  }
+
+  VddMode modes[] = {
+      {1920, 1080, 60},
+      {1920, 1080, 120},
+      {1280, 720, 60},
+  };
+  uint32_t id = 0;
+  if (vdd_add_monitor(client, "c", modes, 3, true, &id) != VDD_STATUS_OK) {
+    return 2;
+  }
+
+  VddMonitorList list;
+  if (vdd_list(client, &list) != VDD_STATUS_OK) {
+    return 3;
+  }
+  if (list.count != 1 || list.monitors[0].id != id || !list.monitors[0].enabled ||
+      strcmp(list.monitors[0].name, "c") != 0 || list.monitors[0].mode_count != 3 ||
+      list.monitors[0].modes[1].refresh_rate != 120) {
+    return 4;
+  }
+  vdd_free_list(&list);
+  if (list.monitors != NULL || list.count != 0) {
+    return 5;
+  }
+
+  if (vdd_set_enabled(client, &id, 1, false) != VDD_STATUS_OK) {
+    return 6;
+  }
+  if (vdd_list(client, &list) != VDD_STATUS_OK || list.count != 1 ||
+      list.monitors[0].enabled) {
+    return 7;
+  }
+  vdd_free_list(&list);
+
+  if (vdd_remove(client, &id, 1) != VDD_STATUS_OK) {
+    return 8;
+  }
+  if (vdd_last_error_message() != NULL) {
+    return 9;
+  }
+
+  if (vdd_remove(client, &id, 1) != VDD_STATUS_NOT_FOUND) {
+    return 10;
+  }
+  if (vdd_last_error_message() == NULL) {
+    return 11;
+  }
+
+  if (vdd_add_monitor(NULL, NULL, NULL, 0, true, NULL) != VDD_STATUS_INVALID_ARGUMENT) {
+    return 12;
+  }
+
+  vdd_free_client(client);
+  return 0;
+}
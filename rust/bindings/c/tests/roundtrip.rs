@@ -0,0 +1,38 @@
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use driver_ipc::mock::MockServer;
+use tokio::runtime::Runtime;
+
+const PIPE_NAME: &str = "virtualdisplaydriver-ffi-roundtrip";
+
+extern "C" {
+    // tests/roundtrip.c, linked by the build script with the c-roundtrip feature
+    fn vdd_roundtrip(pipe_name: *const c_char) -> c_int;
+}
+
+#[test]
+fn roundtrip() {
+    let runtime = Runtime::new().unwrap();
+    let mut server = runtime.block_on(async { MockServer::new(PIPE_NAME) });
+    runtime.spawn(async move {
+        loop {
+            server.pump().await;
+        }
+    });
+
+    let name = CString::new(PIPE_NAME).unwrap();
+    // SAFETY: valid NUL terminated string
+    let step = unsafe { vdd_roundtrip(name.as_ptr()) };
+
+    let message = driver_ipc_ffi::vdd_last_error_message();
+    let message = if message.is_null() {
+        String::new()
+    } else {
+        // SAFETY: valid until the next call on this thread
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    assert_eq!(step, 0, "step {step} failed: {message}");
+}
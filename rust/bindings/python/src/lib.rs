@@ -11,11 +11,13 @@ use std::{
     collections::HashSet,
     fmt::Display,
     sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
 use driver_ipc::{
     sync::{DriverClient, EventsSubscription},
-    Dimen, EventCommand, Id, Mode, Monitor, RefreshRate,
+    ColorCalibration, ColorSpace, CustomTiming, Dimen, EventCommand, Id, Mode, Monitor,
+    Orientation, RefreshRate, SessionScope,
 };
 use pyo3::prelude::*;
 use pyo3::{
@@ -354,7 +356,7 @@ impl PyDriverClient {
 
         let client = DriverClient::new().into_py_err()?;
 
-        let monitors = state_to_pytypedlist(py, client.monitors())?;
+        let monitors = state_to_pytypedlist(py, &client.monitors())?;
 
         let slf = Self { client, monitors };
 
@@ -394,22 +396,84 @@ impl PyDriverClient {
     fn get_state(&mut self, py: Python) -> PyResult<Py<PyList>> {
         self.client.refresh_state();
 
-        let monitors = state_to_pylist(py, self.client.monitors())?;
+        let monitors = state_to_pylist(py, &self.client.monitors())?;
 
         Ok(monitors)
     }
 
+    /// Maximum number of monitors the driver can have enabled at the same time
+    /// Sig: max_monitors() -> int
+    fn max_monitors(&self) -> u32 {
+        self.client.max_monitors()
+    }
+
+    /// Version of the driver, or None if the driver is too old to report it
+    /// Sig: driver_version() -> Optional[str]
+    fn driver_version(&self) -> Option<&str> {
+        self.client.driver_version()
+    }
+
+    /// Largest mode width and height the driver accepts
+    /// Sig: max_resolution() -> tuple[int, int]
+    fn max_resolution(&self) -> (Dimen, Dimen) {
+        let caps = self.client.capabilities();
+        (caps.max_width, caps.max_height)
+    }
+
+    /// Whether the driver accepts a mode with this resolution and refresh rate
+    /// Any aspect ratio and any non-zero refresh rate within max_resolution() is accepted
+    /// Sig: supports_mode(width: int, height: int, refresh_rate: int) -> bool
+    fn supports_mode(&self, width: Dimen, height: Dimen, refresh_rate: RefreshRate) -> bool {
+        self.client
+            .capabilities()
+            .supports(width, height, refresh_rate)
+    }
+
     /// Send notification to driver of changes
-    /// Sig: notify()
-    fn notify(&mut self, py: Python) -> PyResult<()> {
+    /// If persistence is set, the driver saves the new state and restores it on next start
+    /// Sig: notify(persistence: bool = False)
+    #[pyo3(signature = (persistence=false))]
+    fn notify(&mut self, py: Python, persistence: bool) -> PyResult<()> {
+        let state = pytypedlist_to_state(py, &self.monitors)?;
+        self.client.set_monitors(&state).into_py_err()?;
+
+        self.client.notify_with(persistence).into_py_err()?;
+
+        Ok(())
+    }
+
+    /// Make the driver save its current state, restored on next driver start
+    /// Sig: persist_now()
+    fn persist_now(&self) -> PyResult<()> {
+        self.client.persist_now().into_py_err()?;
+        Ok(())
+    }
+
+    /// Move monitors to their position. Call after notify(); waits up to timeout seconds
+    /// for the driver to attach the monitors
+    /// Sig: apply_positions(timeout: float = 5.0)
+    #[pyo3(signature = (timeout=5.0))]
+    fn apply_positions(&mut self, py: Python, timeout: f64) -> PyResult<()> {
         let state = pytypedlist_to_state(py, &self.monitors)?;
         self.client.set_monitors(&state).into_py_err()?;
 
-        self.client.notify().into_py_err()?;
+        let timeout = Duration::try_from_secs_f64(timeout).into_py_err()?;
+        self.client.apply_positions(timeout).into_py_err()?;
 
         Ok(())
     }
 
+    /// Describe a physical display, like "\\\\.\\DISPLAY1", as a new monitor with the same
+    /// modes and EDID. The monitor gets a free id, but is not added
+    /// Sig: clone_physical(device_name: str) -> Monitor
+    fn clone_physical(&mut self, py: Python, device_name: &str) -> PyResult<Py<PyMonitor>> {
+        let state = pytypedlist_to_state(py, &self.monitors)?;
+        self.client.set_monitors(&state).into_py_err()?;
+
+        let monitor = self.client.clone_physical(device_name).into_py_err()?;
+        monitor_to_py(py, &monitor)
+    }
+
     /// Get notified of other clients changing driver configuration
     /// Sig: receive(Callable[list[Monitor], None]])
     fn receive(&mut self, callback: PyObject) -> PyEventsSubscription {
@@ -475,7 +539,8 @@ impl PyDriverClient {
         Ok(None)
     }
 
-    /// Get the closest available free ID. Note that if internal state is stale, this may result in a duplicate ID
+    /// Get the closest available free ID, or preferred_id if it's free. None if preferred_id is taken already.
+    /// Note that if internal state is stale, this may result in a duplicate ID
     /// which the driver will ignore when you notify it of changes
     /// Sig: new_id(preferred_id: Optional[int] = None) -> Optional[int]
     #[pyo3(signature = (preferred_id=None))]
@@ -605,10 +670,77 @@ struct PyMonitor {
     /// Sig: enabled: bool
     #[pyo3(get, set)]
     enabled: bool,
+    /// Whether the monitor is parked. A parked monitor keeps its config but is
+    /// detached from the OS until unparked
+    /// Sig: parked: bool
+    #[pyo3(get, set)]
+    parked: bool,
+    /// Desired desktop position of the top left corner, or None to let Windows
+    /// decide. Applied with DriverClient.apply_positions()
+    /// Sig: position: Optional[tuple[int, int]]
+    #[pyo3(get, set)]
+    position: Option<(i32, i32)>,
+    /// Whether the monitor is made the primary display. Applied with
+    /// DriverClient.apply_positions()
+    /// Sig: primary: bool
+    #[pyo3(get, set)]
+    primary: bool,
     /// The monitor modes (resolution and refresh rates)
     /// Sig: modes: list[Mode]
     #[pyo3(get)]
     modes: Py<PyTypedList>,
+    /// EDID reported instead of the generated one, or None. Its serial number
+    /// is replaced with the monitor's serial
+    /// Sig: edid: Optional[bytes]
+    #[pyo3(get, set)]
+    edid: Option<Vec<u8>>,
+    /// Three letter PNP manufacturer ID of the generated EDID, or None for the
+    /// driver's own
+    /// Sig: manufacturer_id: Optional[str]
+    #[pyo3(get, set)]
+    manufacturer_id: Option<String>,
+    /// Product code of the generated EDID
+    /// Sig: product_code: int
+    #[pyo3(get, set)]
+    product_code: u16,
+    /// EDID serial number, or None to use the monitor id. Must be unique from
+    /// all other monitors
+    /// Sig: serial: Optional[int]
+    #[pyo3(get, set)]
+    serial: Option<u32>,
+    /// Pass edid to the OS exactly as it is, without checking it or fixing its
+    /// checksum. Meant for testing with malformed EDIDs only, Windows may
+    /// refuse or mishandle the monitor
+    /// Sig: raw_edid: bool
+    #[pyo3(get, set)]
+    raw_edid: bool,
+    // not exposed yet, but kept so it survives a round trip through python
+    color: Option<ColorCalibration>,
+    /// Adaptive sync range (min, max) in Hz advertised in the generated EDID,
+    /// or None. Only apps reading the EDID see it, Windows doesn't enable VRR
+    /// for virtual monitors
+    /// Sig: vrr_range: Optional[tuple[int, int]]
+    #[pyo3(get, set)]
+    vrr_range: Option<(u16, u16)>,
+    /// Advertise basic PCM audio in the generated EDID. This only changes the
+    /// EDID, no audio is routed
+    /// Sig: audio: bool
+    #[pyo3(get, set)]
+    audio: bool,
+    // not exposed yet, but kept so they survive a round trip through python
+    session_scope: SessionScope,
+    orientation: Option<Orientation>,
+    /// Labels to operate on groups of monitors at once. A monitor with several
+    /// tags is part of each of their groups
+    /// Sig: tags: list[str]
+    #[pyo3(get, set)]
+    tags: Vec<String>,
+    /// Sig: frame_rate_limit: Optional[int]
+    #[pyo3(get, set)]
+    frame_rate_limit: Option<u32>,
+    // not exposed yet, but kept so they survive a round trip through python
+    color_space: Option<ColorSpace>,
+    sdr_white_level_nits: Option<u16>,
 }
 
 impl Clone for PyMonitor {
@@ -617,7 +749,24 @@ impl Clone for PyMonitor {
             id: self.id,
             name: self.name.clone(),
             enabled: self.enabled,
+            parked: self.parked,
+            position: self.position,
+            primary: self.primary,
             modes: self.modes.clone_ref(py),
+            edid: self.edid.clone(),
+            manufacturer_id: self.manufacturer_id.clone(),
+            product_code: self.product_code,
+            serial: self.serial,
+            raw_edid: self.raw_edid,
+            color: self.color,
+            vrr_range: self.vrr_range,
+            audio: self.audio,
+            session_scope: self.session_scope,
+            orientation: self.orientation,
+            tags: self.tags.clone(),
+            color_space: self.color_space,
+            sdr_white_level_nits: self.sdr_white_level_nits,
+            frame_rate_limit: self.frame_rate_limit,
         })
     }
 }
@@ -630,7 +779,24 @@ impl PyMonitor {
             id: 0,
             name: None,
             enabled: false,
+            parked: false,
+            position: None,
+            primary: false,
             modes: PyTypedList::new(py, ListType::Mode).try_into()?,
+            edid: None,
+            manufacturer_id: None,
+            product_code: 0,
+            serial: None,
+            raw_edid: false,
+            color: None,
+            vrr_range: None,
+            audio: false,
+            session_scope: SessionScope::System,
+            orientation: None,
+            tags: Vec::new(),
+            color_space: None,
+            sdr_white_level_nits: None,
+            frame_rate_limit: None,
         };
 
         Ok(inst)
@@ -672,7 +838,24 @@ impl Debug for PyMonitor {
                 id,
                 name,
                 enabled,
+                parked,
+                position,
+                primary,
                 modes,
+                edid,
+                manufacturer_id,
+                product_code,
+                serial,
+                raw_edid,
+                color,
+                vrr_range,
+                audio,
+                session_scope,
+                orientation,
+                tags,
+                color_space,
+                sdr_white_level_nits,
+                frame_rate_limit,
             } = self;
 
             let modes = modes
@@ -684,7 +867,24 @@ impl Debug for PyMonitor {
                 .field("id", &id)
                 .field("name", &name)
                 .field("enabled", &enabled)
+                .field("parked", &parked)
+                .field("position", &position)
+                .field("primary", &primary)
                 .field("modes", &modes)
+                .field("edid", &edid)
+                .field("manufacturer_id", &manufacturer_id)
+                .field("product_code", &product_code)
+                .field("serial", &serial)
+                .field("raw_edid", &raw_edid)
+                .field("color", &color)
+                .field("vrr_range", &vrr_range)
+                .field("audio", &audio)
+                .field("session_scope", &session_scope)
+                .field("orientation", &orientation)
+                .field("tags", &tags)
+                .field("color_space", &color_space)
+                .field("sdr_white_level_nits", &sdr_white_level_nits)
+                .field("frame_rate_limit", &frame_rate_limit)
                 .finish()
         })
     }
@@ -708,6 +908,8 @@ struct PyMode {
     /// Sig: refresh_rates: list[int]
     #[pyo3(get)]
     refresh_rates: Py<PyTypedList>,
+    // not exposed yet, but kept so it survives a round trip through python
+    timing: Option<CustomTiming>,
 }
 
 impl Clone for PyMode {
@@ -716,6 +918,7 @@ impl Clone for PyMode {
             width: self.width,
             height: self.height,
             refresh_rates: self.refresh_rates.clone_ref(py),
+            timing: self.timing,
         })
     }
 }
@@ -735,6 +938,7 @@ impl Debug for PyMode {
                 width,
                 height,
                 refresh_rates,
+                timing,
             } = self;
 
             let refresh_rates = refresh_rates
@@ -747,6 +951,7 @@ impl Debug for PyMode {
                 .field("width", &width)
                 .field("height", &height)
                 .field("refresh_rates", &refresh_rates)
+                .field("timing", &timing)
                 .finish()
         })
     }
@@ -760,6 +965,7 @@ impl PyMode {
             width: 0,
             height: 0,
             refresh_rates: PyTypedList::new(py, ListType::RefreshRate).try_into()?,
+            timing: None,
         };
 
         Ok(inst)
@@ -795,37 +1001,57 @@ fn state_to_pylist(py: Python, monitors: &[Monitor]) -> PyResult<Py<PyList>> {
     let py_state = PyList::empty_bound(py);
 
     for monitor in monitors {
-        let modes = PyList::empty_bound(py);
-
-        for mode in &monitor.modes {
-            let py_refresh_rates = PyList::new_bound(py, &mode.refresh_rates);
-
-            let mode: Py<PyMode> = PyMode {
-                width: mode.width,
-                height: mode.height,
-                refresh_rates: PyTypedList::new_from_list(
-                    py_refresh_rates.into(),
-                    ListType::RefreshRate,
-                )
-                .try_into()?,
-            }
-            .try_into()?;
+        py_state.append(monitor_to_py(py, monitor)?)?;
+    }
 
-            modes.append(mode)?;
-        }
+    Ok(py_state.into())
+}
 
-        let monitor: Py<PyMonitor> = PyMonitor {
-            id: monitor.id,
-            name: monitor.name.clone(),
-            enabled: monitor.enabled,
-            modes: PyTypedList::new_from_list(modes.into(), ListType::Mode).try_into()?,
+fn monitor_to_py(py: Python, monitor: &Monitor) -> PyResult<Py<PyMonitor>> {
+    let modes = PyList::empty_bound(py);
+
+    for mode in &monitor.modes {
+        let py_refresh_rates = PyList::new_bound(py, &mode.refresh_rates);
+
+        let mode: Py<PyMode> = PyMode {
+            width: mode.width,
+            height: mode.height,
+            refresh_rates: PyTypedList::new_from_list(
+                py_refresh_rates.into(),
+                ListType::RefreshRate,
+            )
+            .try_into()?,
+            timing: mode.timing,
         }
         .try_into()?;
 
-        py_state.append(monitor)?;
+        modes.append(mode)?;
     }
 
-    Ok(py_state.into())
+    PyMonitor {
+        id: monitor.id,
+        name: monitor.name.clone(),
+        enabled: monitor.enabled,
+        parked: monitor.parked,
+        position: monitor.position,
+        primary: monitor.primary,
+        modes: PyTypedList::new_from_list(modes.into(), ListType::Mode).try_into()?,
+        edid: monitor.edid.clone(),
+        manufacturer_id: monitor.manufacturer_id.clone(),
+        product_code: monitor.product_code,
+        serial: monitor.serial,
+        raw_edid: monitor.raw_edid,
+        color: monitor.color,
+        vrr_range: monitor.vrr_range,
+        audio: monitor.audio,
+        session_scope: monitor.session_scope,
+        orientation: monitor.orientation,
+        tags: monitor.tags.clone(),
+        color_space: monitor.color_space,
+        sdr_white_level_nits: monitor.sdr_white_level_nits,
+        frame_rate_limit: monitor.frame_rate_limit,
+    }
+    .try_into()
 }
 
 fn state_to_pytypedlist(py: Python, monitors: &[Monitor]) -> PyResult<Py<PyTypedList>> {
@@ -859,6 +1085,7 @@ fn pytypedlist_to_state(py: Python, monitors: &Py<PyTypedList>) -> PyResult<Vec<
                 width: mode.width,
                 height: mode.height,
                 refresh_rates,
+                timing: mode.timing,
             });
         }
 
@@ -866,7 +1093,24 @@ fn pytypedlist_to_state(py: Python, monitors: &Py<PyTypedList>) -> PyResult<Vec<
             id: py_monitor.id,
             name: py_monitor.name.clone(),
             enabled: py_monitor.enabled,
+            parked: py_monitor.parked,
+            position: py_monitor.position,
+            primary: py_monitor.primary,
             modes,
+            edid: py_monitor.edid.clone(),
+            manufacturer_id: py_monitor.manufacturer_id.clone(),
+            product_code: py_monitor.product_code,
+            serial: py_monitor.serial,
+            raw_edid: py_monitor.raw_edid,
+            color: py_monitor.color,
+            vrr_range: py_monitor.vrr_range,
+            audio: py_monitor.audio,
+            session_scope: py_monitor.session_scope,
+            orientation: py_monitor.orientation,
+            tags: py_monitor.tags.clone(),
+            color_space: py_monitor.color_space,
+            sdr_white_level_nits: py_monitor.sdr_white_level_nits,
+            frame_rate_limit: py_monitor.frame_rate_limit,
         });
     }
 
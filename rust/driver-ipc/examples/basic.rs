@@ -0,0 +1,74 @@
+//! Adds a 1080p60 virtual monitor, saves it, and removes it again.
+//!
+//! Needs the driver installed and running:
+//!
+//! ```text
+//! cargo run -p driver-ipc --example basic
+//! ```
+
+#[cfg(windows)]
+use std::{error::Error, time::Duration};
+
+#[cfg(windows)]
+use driver_ipc::{sync::DriverClient, Id, MonitorBuilder};
+
+/// How long Windows gets to attach the new monitor
+#[cfg(windows)]
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("The driver only runs on Windows");
+}
+
+#[cfg(windows)]
+fn main() -> Result<(), Box<dyn Error>> {
+    // the client starts out with the monitors the driver has
+    let client = DriverClient::new()
+        .map_err(|e| format!("Failed to connect, is the driver installed and running? {e}"))?;
+    println!(
+        "Connected, the driver has {} monitors",
+        client.monitors().len()
+    );
+
+    let id = client.new_id(None).ok_or("No free monitor ID")?;
+    let monitor = MonitorBuilder::new(id)
+        .name("basic example")
+        .mode(1920, 1080, &[60])
+        .build()
+        .map_err(|errors| format!("Invalid monitor: {errors:?}"))?;
+
+    // changes only live in the client until they are sent to the driver
+    client.add(monitor)?;
+
+    let result = attach_and_persist(&client, id);
+
+    // clean up even if attaching failed, so the example leaves no monitor behind
+    client.remove(&[id]);
+    client.notify()?;
+    // the saved monitors are the client state, which no longer has the monitor
+    client.persist()?;
+    println!("Removed monitor {id}");
+
+    result
+}
+
+#[cfg(windows)]
+fn attach_and_persist(client: &DriverClient, id: Id) -> Result<(), Box<dyn Error>> {
+    // sends the state and waits until Windows attached every active monitor
+    client.apply_and_wait(ATTACH_TIMEOUT)?;
+
+    match client.active_mode(id)? {
+        Some(mode) => println!(
+            "Monitor {id} is attached at {}x{}@{}",
+            mode.width, mode.height, mode.refresh_rate
+        ),
+        None => println!("Monitor {id} is attached, but not part of the desktop"),
+    }
+
+    // the driver brings saved monitors back when it starts again, e.g. after a reboot
+    client.persist()?;
+    println!("Saved monitor {id}");
+
+    Ok(())
+}
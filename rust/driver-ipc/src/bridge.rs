@@ -0,0 +1,581 @@
+//! Access to the driver pipe over TCP, to control the driver from another
+//! machine.
+//!
+//! A remote client opens a TCP connection and sends a single length prefixed
+//! frame (see [framing](crate::framing)) with a [BridgeAuth], then waits for
+//! the [BridgeReply]. Once authenticated, the connection carries the pipe
+//! protocol unchanged: the bridge opens a connection to the driver pipe for
+//! it and copies frames both ways, so the remote sends the same hello,
+//! requests and commands as a local client, and receives the same replies and
+//! events.
+//!
+//! The bridge is a plain proxy. It doesn't read what it copies, nor turn it
+//! into calls of a [Client](crate::Client), so a remote needs a client
+//! speaking the pipe protocol, and gets the same control over the driver as a
+//! local one, including e.g. [DriverCommand::Reset](crate::DriverCommand::Reset).
+//! A remote which breaks the protocol is disconnected by the driver, like a
+//! local client would be.
+//!
+//! Guessing the secret is slowed down: a remote has [AUTH_TIMEOUT] to
+//! authenticate, at most [MAX_PENDING_AUTH] connections may be
+//! authenticating at a time, and an address which sent a wrong secret is
+//! refused for a while, longer with every further wrong secret.
+//!
+//! The secret, and everything sent after it, crosses the network
+//! unencrypted. The bridge should only listen on trusted networks, or be
+//! reached through a tunnel.
+//!
+//! Given an address in [BridgeOptions::metrics], the bridge also answers
+//! scrapes of the driver's [metrics](crate::metrics) over HTTP.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{windows::named_pipe, TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, timeout, Instant},
+};
+
+use crate::{
+    framing::{FrameTooLarge, Framing},
+    metrics, DEFAULT_PIPE_NAME,
+};
+
+/// Port the bridge listens on unless configured otherwise.
+pub const DEFAULT_BRIDGE_PORT: u16 = 23557;
+
+/// Time a remote has to authenticate after connecting.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most connections authenticating at the same time, further ones are closed
+/// right away.
+pub const MAX_PENDING_AUTH: usize = 16;
+/// Time an address is refused after its first failed authentication, doubled
+/// with every further one up to [MAX_BACKOFF]. Counts from before the
+/// [AUTH_FAILURE_DELAY], so it must be longer.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Wait before refusing a wrong secret, to slow down guessing.
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+/// Longest [BridgeAuth] frame, far more than any reasonable secret needs.
+const MAX_AUTH_LEN: usize = 4096;
+
+/// First frame a remote sends.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BridgeAuth {
+    pub secret: String,
+}
+
+/// Answer to a [BridgeAuth], the bridge closes the connection unless it is
+/// authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BridgeReply {
+    pub authenticated: bool,
+}
+
+/// Where the bridge listens, and what remotes have to know.
+#[derive(Debug, Clone)]
+pub struct BridgeOptions {
+    pub bind: SocketAddr,
+    /// Secret every remote has to send, must not be empty.
+    pub secret: String,
+    /// Pipe of the driver, the {name} portion of \\.\pipe\{name}.
+    pub pipe_name: String,
+    /// Where to answer scrapes of `/metrics` over HTTP, see
+    /// [metrics](crate::metrics). Off by default.
+    ///
+    /// The metrics need no secret, they are readable by anyone who can reach
+    /// the address.
+    pub metrics: Option<SocketAddr>,
+}
+
+impl BridgeOptions {
+    /// Options for the driver's default pipe.
+    pub fn new(bind: SocketAddr, secret: impl Into<String>) -> Self {
+        Self {
+            bind,
+            secret: secret.into(),
+            pipe_name: DEFAULT_PIPE_NAME.to_owned(),
+            metrics: None,
+        }
+    }
+}
+
+/// Accept remotes on `options.bind` until the listener fails, see the module
+/// docs.
+///
+/// Connections are served concurrently, each with its own connection to the
+/// driver. A failing connection is logged and closed, it doesn't stop the
+/// bridge.
+pub async fn serve(options: BridgeOptions) -> Result<(), BridgeError> {
+    if options.secret.is_empty() {
+        return Err(BridgeError::EmptySecret);
+    }
+
+    let listener = TcpListener::bind(options.bind)
+        .await
+        .map_err(|e| BridgeError::Bind(options.bind, e))?;
+
+    if let Some(bind) = options.metrics {
+        let metrics = TcpListener::bind(bind)
+            .await
+            .map_err(|e| BridgeError::Bind(bind, e))?;
+
+        // the bridge keeps running without them
+        let pipe_name = Arc::from(options.pipe_name.as_str());
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_on(metrics, pipe_name).await {
+                error!("Stopped serving metrics: {e}");
+            }
+        });
+    }
+
+    serve_on(listener, Arc::new(options)).await
+}
+
+async fn serve_on(listener: TcpListener, options: Arc<BridgeOptions>) -> Result<(), BridgeError> {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Bridge listening on {addr}");
+    }
+
+    let pending = Arc::new(Semaphore::new(MAX_PENDING_AUTH));
+    let backoff = Arc::new(Backoff::default());
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // e.g. the remote reset the connection before it was accepted
+            Err(e) if is_transient(&e) => {
+                warn!("Failed to accept bridge connection: {e}");
+                continue;
+            }
+            Err(e) => return Err(BridgeError::Accept(e)),
+        };
+
+        // dropping the stream closes the connection, without a reply
+        if backoff.is_refused(peer.ip()) {
+            debug!("Refused bridge connection from {peer}, it failed to authenticate recently");
+            continue;
+        }
+
+        let Ok(permit) = pending.clone().try_acquire_owned() else {
+            warn!("Refused bridge connection from {peer}, too many connections are authenticating");
+            continue;
+        };
+
+        let options = options.clone();
+        let backoff = backoff.clone();
+        tokio::spawn(async move {
+            match bridge_connection(stream, peer.ip(), permit, &options, &backoff).await {
+                Ok(()) => info!("Bridge connection from {peer} closed"),
+                Err(e) => warn!("Bridge connection from {peer} failed: {e}"),
+            }
+        });
+    }
+}
+
+async fn bridge_connection(
+    mut stream: TcpStream,
+    peer: IpAddr,
+    permit: OwnedSemaphorePermit,
+    options: &BridgeOptions,
+    backoff: &Backoff,
+) -> Result<(), ConnectionError> {
+    // frames are small, and a remote waits for every reply
+    stream.set_nodelay(true)?;
+
+    let auth = timeout(AUTH_TIMEOUT, read_auth(&mut stream))
+        .await
+        .map_err(|_| ConnectionError::AuthTimeout)
+        .and_then(|auth| auth);
+
+    let authenticated = match auth {
+        Ok(auth) => secrets_match(&auth.secret, &options.secret),
+        Err(e) => {
+            backoff.failed(peer);
+            return Err(e);
+        }
+    };
+
+    if authenticated {
+        backoff.succeeded(peer);
+    } else {
+        backoff.failed(peer);
+        sleep(AUTH_FAILURE_DELAY).await;
+    }
+
+    let reply = serde_json::to_vec(&BridgeReply { authenticated })?;
+    stream
+        .write_all(&Framing::LengthPrefixed.encode(reply)?)
+        .await?;
+
+    if !authenticated {
+        return Err(ConnectionError::WrongSecret);
+    }
+
+    drop(permit);
+
+    let mut pipe = named_pipe::ClientOptions::new()
+        .read(true)
+        .write(true)
+        .pipe_mode(named_pipe::PipeMode::Byte)
+        .open(format!(r"\\.\pipe\{}", options.pipe_name))
+        .map_err(ConnectionError::Pipe)?;
+
+    info!("Bridge connection authenticated");
+
+    match tokio::io::copy_bidirectional(&mut stream, &mut pipe).await {
+        Ok(_) => Ok(()),
+        // either side going away ends the connection
+        Err(e) if is_transient(&e) || e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the [BridgeAuth] frame, and nothing after it.
+async fn read_auth(stream: &mut TcpStream) -> Result<BridgeAuth, ConnectionError> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await?;
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_AUTH_LEN {
+        return Err(FrameTooLarge(len).into());
+    }
+
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame).await?;
+
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+/// Compare without returning early. Every byte of the secret is compared,
+/// also for a guess of the wrong length, so the time taken only depends on
+/// the secret and tells nothing about how much of a guess was right.
+fn secrets_match(given: &str, secret: &str) -> bool {
+    let given = given.as_bytes();
+
+    secret
+        .bytes()
+        .enumerate()
+        .fold(given.len() ^ secret.len(), |diff, (i, b)| {
+            diff | usize::from(given.get(i).copied().unwrap_or(0) ^ b)
+        })
+        == 0
+}
+
+/// Addresses which failed to authenticate, and until when they are refused
+#[derive(Debug, Default)]
+struct Backoff(Mutex<HashMap<IpAddr, Failures>>);
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    until: Instant,
+}
+
+impl Backoff {
+    fn is_refused(&self, peer: IpAddr) -> bool {
+        let peers = self.0.lock().unwrap();
+        peers
+            .get(&peer)
+            .is_some_and(|failures| Instant::now() < failures.until)
+    }
+
+    fn failed(&self, peer: IpAddr) {
+        let now = Instant::now();
+        let mut peers = self.0.lock().unwrap();
+
+        // addresses which stayed away for the longest backoff start over, which also keeps the
+        // map from growing without bounds
+        peers.retain(|_, failures| now < failures.until + MAX_BACKOFF);
+
+        let count = peers.get(&peer).map_or(0, |failures| failures.count) + 1;
+        let backoff = MIN_BACKOFF
+            .saturating_mul(2u32.saturating_pow(count - 1))
+            .min(MAX_BACKOFF);
+
+        peers.insert(
+            peer,
+            Failures {
+                count,
+                until: now + backoff,
+            },
+        );
+    }
+
+    fn succeeded(&self, peer: IpAddr) {
+        self.0.lock().unwrap().remove(&peer);
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Error returned from [serve].
+#[derive(Debug, Error)]
+pub enum BridgeError {
+    #[error("The bridge secret must not be empty")]
+    EmptySecret,
+    #[error("Failed to listen on {0}: {1}")]
+    Bind(SocketAddr, io::Error),
+    #[error("Failed to accept connections: {0}")]
+    Accept(io::Error),
+}
+
+/// Why a single bridge connection ended, only logged
+#[derive(Debug, Error)]
+enum ConnectionError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Malformed authentication: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Authentication frame too large: {0}")]
+    TooLarge(#[from] FrameTooLarge),
+    #[error("No authentication in time")]
+    AuthTimeout,
+    #[error("Wrong secret")]
+    WrongSecret,
+    #[error("Failed to open driver pipe: {0}")]
+    Pipe(io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        framing::FrameDecoder, mock::*, ClientCommand, Envelope, ReplyCommand, RequestCommand,
+        ServerCommand, PROTOCOL_VERSION,
+    };
+
+    const SECRET: &str = "correct horse battery staple";
+
+    /// Starts a bridge to pipe `name` on a free port, returns its address
+    async fn start_bridge(name: &str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut options = BridgeOptions::new(addr, SECRET);
+        options.pipe_name = name.to_owned();
+        tokio::spawn(serve_on(listener, Arc::new(options)));
+
+        addr
+    }
+
+    async fn send<T: Serialize>(stream: &mut TcpStream, message: &T) {
+        let frame = serde_json::to_vec(message).unwrap();
+        let frame = Framing::LengthPrefixed.encode(frame).unwrap();
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    /// Next frame of `stream`, `None` once it is closed
+    async fn receive(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Option<Vec<u8>> {
+        loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                return Some(frame);
+            }
+
+            let mut buf = [0; 4096];
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return None,
+                len => decoder.push(&buf[..len]),
+            }
+        }
+    }
+
+    async fn authenticate(addr: SocketAddr, secret: &str) -> (TcpStream, FrameDecoder, bool) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut decoder = FrameDecoder::with_framing(Framing::LengthPrefixed);
+
+        send(
+            &mut stream,
+            &BridgeAuth {
+                secret: secret.to_owned(),
+            },
+        )
+        .await;
+
+        let reply = receive(&mut stream, &mut decoder).await.unwrap();
+        let reply = serde_json::from_slice::<BridgeReply>(&reply).unwrap();
+
+        (stream, decoder, reply.authenticated)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn forwards_the_pipe_protocol() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-bridge_forwards";
+
+        let _server = MockServer::new(PIPE_NAME);
+        let addr = start_bridge(PIPE_NAME).await;
+
+        let (mut stream, mut decoder, authenticated) = authenticate(addr, SECRET).await;
+        assert!(authenticated);
+
+        let hello = ServerCommand::Request(RequestCommand::Hello {
+            protocol: PROTOCOL_VERSION,
+            client_name: "bridge test".to_owned(),
+        });
+        send(&mut stream, &Envelope::new(Some(7), hello)).await;
+
+        let reply = receive(&mut stream, &mut decoder).await.unwrap();
+        let reply = Envelope::<ClientCommand>::parse(&reply).unwrap();
+        assert_eq!(reply.id, Some(7));
+        assert!(matches!(
+            reply.command,
+            ClientCommand::Reply(ReplyCommand::HelloReply { protocol, .. })
+                if protocol == PROTOCOL_VERSION
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn wrong_secret_is_refused() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-bridge_wrong_secret";
+
+        let _server = MockServer::new(PIPE_NAME);
+        let addr = start_bridge(PIPE_NAME).await;
+
+        let (mut stream, mut decoder, authenticated) = authenticate(addr, "guess").await;
+        assert!(!authenticated);
+        assert_eq!(receive(&mut stream, &mut decoder).await, None);
+    }
+
+    /// Whether the bridge closes a new connection without waiting for its
+    /// authentication
+    async fn is_refused(addr: SocketAddr) -> bool {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+
+        // shorter than AUTH_TIMEOUT, after which every connection is closed
+        let read = timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
+        matches!(read, Ok(Ok(0) | Err(_)))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn wrong_secret_backs_off() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-bridge_backs_off";
+
+        let _server = MockServer::new(PIPE_NAME);
+        let addr = start_bridge(PIPE_NAME).await;
+
+        let (_, _, authenticated) = authenticate(addr, "guess").await;
+        assert!(!authenticated);
+
+        // refused even with the right secret, until the backoff ends
+        assert!(is_refused(addr).await);
+        sleep(MIN_BACKOFF).await;
+
+        let (_, _, authenticated) = authenticate(addr, SECRET).await;
+        assert!(authenticated);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn limits_pending_authentications() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-bridge_pending_auth";
+
+        let _server = MockServer::new(PIPE_NAME);
+        let addr = start_bridge(PIPE_NAME).await;
+
+        let mut pending = Vec::new();
+        for _ in 0..MAX_PENDING_AUTH {
+            pending.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        assert!(is_refused(addr).await);
+
+        // a connection which authenticated doesn't count anymore
+        let mut stream = pending.pop().unwrap();
+        let auth = BridgeAuth {
+            secret: SECRET.to_owned(),
+        };
+        send(&mut stream, &auth).await;
+        let mut decoder = FrameDecoder::with_framing(Framing::LengthPrefixed);
+        let reply = receive(&mut stream, &mut decoder).await.unwrap();
+        assert!(
+            serde_json::from_slice::<BridgeReply>(&reply)
+                .unwrap()
+                .authenticated
+        );
+
+        let (_, _, authenticated) = authenticate(addr, SECRET).await;
+        assert!(authenticated);
+    }
+
+    /// Sends an HTTP request for `path` to `addr`, returns the whole response
+    async fn http_get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn serves_metrics() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-bridge_metrics";
+
+        let mut server = MockServer::new(PIPE_NAME);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics::serve_on(listener, Arc::from(PIPE_NAME)));
+
+        assert!(http_get(addr, "/")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // the monitor states, the monitors to find them in the display config, and the ipc stats
+        let (response, ()) = tokio::join!(http_get(addr, "/metrics"), async {
+            server.pump().await;
+            server.pump().await;
+            server.pump().await;
+        });
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains(metrics::CONTENT_TYPE));
+        assert!(body.contains("\nvdd_monitors 0\n"), "{body}");
+        assert!(body.contains("\nvdd_ipc_messages_total 0\n"), "{body}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn metrics_unavailable_without_driver() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-bridge_metrics_without_driver";
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics::serve_on(listener, Arc::from(PIPE_NAME)));
+
+        let response = http_get(addr, "/metrics").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{head}"
+        );
+        assert!(!body.is_empty());
+    }
+
+    #[test]
+    fn secrets_are_compared_fully() {
+        assert!(secrets_match(SECRET, SECRET));
+        assert!(!secrets_match("correct horse battery stapler", SECRET));
+        assert!(!secrets_match("correct horse battery stapla", SECRET));
+        assert!(!secrets_match("", SECRET));
+        assert!(!secrets_match("correct horse battery staples", SECRET));
+        assert!(!secrets_match(SECRET, ""));
+        assert!(secrets_match("", ""));
+    }
+}
@@ -0,0 +1,426 @@
+#[cfg(all(windows, feature = "transport"))]
+use crate::{error, Client};
+use crate::{
+    stable_id, ColorCalibration, ColorSpace, CustomTiming, Dimen, Id, Mode, Monitor, MonitorError,
+    Orientation, RefreshRate, SessionScope,
+};
+
+/// Fluent construction of a validated [Monitor].
+///
+/// ```
+/// # use driver_ipc::MonitorBuilder;
+/// let monitor = MonitorBuilder::new(0)
+///     .name("Desk")
+///     .mode(1920, 1080, &[60, 120])
+///     .mode(1280, 720, &[60])
+///     .preferred_mode(2560, 1440, 144)
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(monitor.modes[0].width, 2560);
+/// ```
+///
+/// Modes with the same resolution are merged, so modes can be added in any
+/// order. The monitor is enabled unless [MonitorBuilder::enabled] says
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct MonitorBuilder {
+    monitor: Monitor,
+    id: IdChoice,
+    preferred: Option<(Dimen, Dimen, RefreshRate)>,
+}
+
+/// ID of a monitor made by a [MonitorBuilder].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdChoice {
+    Fixed(Id),
+    /// Reserve an ID no other client uses, see
+    /// [MonitorBuilder::build_reserved].
+    Auto,
+    /// Derive the ID from the monitor's name with [stable_id], so it is the
+    /// same every time the monitor is added.
+    FromName,
+}
+
+impl From<Id> for IdChoice {
+    fn from(id: Id) -> Self {
+        Self::Fixed(id)
+    }
+}
+
+impl MonitorBuilder {
+    /// Start an enabled monitor with the given ID and no modes.
+    pub fn new(id: impl Into<IdChoice>) -> Self {
+        let id = id.into();
+
+        Self {
+            monitor: Monitor {
+                // filled in once reserved
+                id: match id {
+                    IdChoice::Fixed(id) => id,
+                    IdChoice::Auto | IdChoice::FromName => 0,
+                },
+                ..Default::default()
+            },
+            id,
+            preferred: None,
+        }
+    }
+
+    #[must_use]
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.monitor.name = Some(name.into());
+        self
+    }
+
+    #[must_use]
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.monitor.enabled = enabled;
+        self
+    }
+
+    #[must_use]
+    pub fn parked(mut self, parked: bool) -> Self {
+        self.monitor.parked = parked;
+        self
+    }
+
+    #[must_use]
+    pub fn position(mut self, x: i32, y: i32) -> Self {
+        self.monitor.position = Some((x, y));
+        self
+    }
+
+    /// Make this the primary display, see [Monitor::primary].
+    #[must_use]
+    pub fn primary(mut self, primary: bool) -> Self {
+        self.monitor.primary = primary;
+        self
+    }
+
+    /// Add a resolution with its refresh rates.
+    #[must_use]
+    pub fn mode(mut self, width: Dimen, height: Dimen, refresh_rates: &[RefreshRate]) -> Self {
+        self.monitor.modes.push(Mode {
+            width,
+            height,
+            refresh_rates: refresh_rates.to_vec(),
+            timing: None,
+        });
+        self
+    }
+
+    /// Add a resolution with an exact signal timing, at the refresh rate of
+    /// the timing.
+    #[must_use]
+    pub fn timed_mode(mut self, width: Dimen, height: Dimen, timing: CustomTiming) -> Self {
+        let refresh_rate = timing
+            .refresh_rate(width, height)
+            .map_or(0, |rate| rate.round() as RefreshRate);
+
+        self.monitor.modes.push(Mode {
+            width,
+            height,
+            refresh_rates: vec![refresh_rate],
+            timing: Some(timing),
+        });
+        self
+    }
+
+    /// Add several modes at once.
+    #[must_use]
+    pub fn modes(mut self, modes: impl IntoIterator<Item = impl Into<Mode>>) -> Self {
+        self.monitor.modes.extend(modes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Make a mode the one Windows picks by default, adding it if needed.
+    ///
+    /// The preferred mode is the first mode, with the refresh rate first.
+    #[must_use]
+    pub fn preferred_mode(
+        mut self,
+        width: Dimen,
+        height: Dimen,
+        refresh_rate: RefreshRate,
+    ) -> Self {
+        self.preferred = Some((width, height, refresh_rate));
+        self
+    }
+
+    /// EDID reported instead of the generated one, see [Monitor::edid].
+    #[must_use]
+    pub fn edid(mut self, edid: Vec<u8>) -> Self {
+        self.monitor.edid = Some(edid);
+        self
+    }
+
+    /// EDID handed to the OS without any checks or fixes, for testing only.
+    /// See [Monitor::raw_edid].
+    #[must_use]
+    pub fn raw_edid(mut self, edid: Vec<u8>) -> Self {
+        self.monitor.edid = Some(edid);
+        self.monitor.raw_edid = true;
+        self
+    }
+
+    #[must_use]
+    pub fn manufacturer_id(mut self, manufacturer_id: impl Into<String>) -> Self {
+        self.monitor.manufacturer_id = Some(manufacturer_id.into());
+        self
+    }
+
+    #[must_use]
+    pub fn product_code(mut self, product_code: u16) -> Self {
+        self.monitor.product_code = product_code;
+        self
+    }
+
+    #[must_use]
+    pub fn serial(mut self, serial: u32) -> Self {
+        self.monitor.serial = Some(serial);
+        self
+    }
+
+    /// Gamma and primaries of the generated EDID, sRGB if not set.
+    #[must_use]
+    pub fn color(mut self, color: ColorCalibration) -> Self {
+        self.monitor.color = Some(color);
+        self
+    }
+
+    /// Color space of the generated EDID, see [Monitor::color_space].
+    #[must_use]
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.monitor.color_space = Some(color_space);
+        self
+    }
+
+    /// SDR white level in nits, see [Monitor::sdr_white_level_nits].
+    #[must_use]
+    pub fn sdr_white_level(mut self, nits: u16) -> Self {
+        self.monitor.sdr_white_level_nits = Some(nits);
+        self
+    }
+
+    /// Most frames per second handed to consumers, see
+    /// [Monitor::frame_rate_limit].
+    #[must_use]
+    pub fn frame_rate_limit(mut self, fps: u32) -> Self {
+        self.monitor.frame_rate_limit = Some(fps);
+        self
+    }
+
+    /// Adaptive sync range in Hz, see [Monitor::vrr_range].
+    #[must_use]
+    pub fn vrr_range(mut self, min: u16, max: u16) -> Self {
+        self.monitor.vrr_range = Some((min, max));
+        self
+    }
+
+    /// Advertise basic audio in the generated EDID, see [Monitor::audio].
+    #[must_use]
+    pub fn audio(mut self, audio: bool) -> Self {
+        self.monitor.audio = audio;
+        self
+    }
+
+    /// Sessions in which the driver attaches the monitor, see
+    /// [Monitor::session_scope].
+    #[must_use]
+    pub fn session_scope(mut self, scope: SessionScope) -> Self {
+        self.monitor.session_scope = scope;
+        self
+    }
+
+    /// Rotation of the desktop on the monitor, see [Monitor::orientation].
+    #[must_use]
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.monitor.orientation = Some(orientation);
+        self
+    }
+
+    /// Add a tag to group the monitor by, see [Monitor::tags].
+    #[must_use]
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        let tag = tag.into();
+        if !self.monitor.tags.contains(&tag) {
+            self.monitor.tags.push(tag);
+        }
+        self
+    }
+
+    /// Finish the monitor, checked with [Monitor::validate].
+    ///
+    /// Fails with [MonitorError::MissingId] for an [IdChoice::Auto] ID, use
+    /// [MonitorBuilder::build_reserved] for those, and for an
+    /// [IdChoice::FromName] ID of a monitor without a name.
+    pub fn build(self) -> Result<Monitor, Vec<MonitorError>> {
+        let mut monitor = self.monitor;
+
+        match (self.id, &monitor.name) {
+            (IdChoice::Fixed(_), _) => (),
+            (IdChoice::FromName, Some(name)) => monitor.id = stable_id(name),
+            (IdChoice::Auto | IdChoice::FromName, _) => {
+                return Err(vec![MonitorError::MissingId]);
+            }
+        }
+
+        if let Some((width, height, refresh_rate)) = self.preferred {
+            monitor.modes.insert(
+                0,
+                Mode {
+                    width,
+                    height,
+                    refresh_rates: vec![refresh_rate],
+                    timing: None,
+                },
+            );
+        }
+
+        monitor.dedup_modes();
+        monitor.validate()?;
+        Ok(monitor)
+    }
+
+    /// Finish the monitor like [MonitorBuilder::build], reserving its ID with
+    /// [Client::new_id] if it is [IdChoice::Auto], or with
+    /// [Client::reserve_id] if it is [IdChoice::FromName].
+    ///
+    /// The ID stays reserved for `client` until it disconnects, so the
+    /// monitor should be sent through the same connection. Fails if the
+    /// derived ID is taken already.
+    #[cfg(all(windows, feature = "transport"))]
+    pub async fn build_reserved(mut self, client: &Client) -> Result<Monitor, error::BuildError> {
+        let id = match (self.id, &self.monitor.name) {
+            (IdChoice::Auto, _) => Some(client.new_id().await?),
+            (IdChoice::FromName, Some(name)) => Some(client.reserve_id(stable_id(name)).await?),
+            _ => None,
+        };
+
+        if let Some(id) = id {
+            self.id = IdChoice::Fixed(id);
+            self.monitor.id = id;
+        }
+
+        self.build().map_err(error::BuildError::Invalid)
+    }
+}
+
+/// Continue from an existing monitor, e.g. one from
+/// [clone_physical](crate::display_config::clone_physical).
+impl From<Monitor> for MonitorBuilder {
+    fn from(monitor: Monitor) -> Self {
+        Self {
+            id: IdChoice::Fixed(monitor.id),
+            monitor,
+            preferred: None,
+        }
+    }
+}
+
+/// A resolution with its refresh rates, like `(1920, 1080, [60, 120])`
+impl<const N: usize> From<(Dimen, Dimen, [RefreshRate; N])> for Mode {
+    fn from((width, height, refresh_rates): (Dimen, Dimen, [RefreshRate; N])) -> Self {
+        Self {
+            width,
+            height,
+            refresh_rates: refresh_rates.to_vec(),
+            timing: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mode(width: Dimen, height: Dimen, refresh_rates: &[RefreshRate]) -> Mode {
+        Mode {
+            width,
+            height,
+            refresh_rates: refresh_rates.to_vec(),
+            timing: None,
+        }
+    }
+
+    #[test]
+    fn builds_validated_monitor() {
+        let monitor = MonitorBuilder::new(2)
+            .name("Desk")
+            .enabled(false)
+            .position(-1920, 0)
+            .mode(1920, 1080, &[60])
+            .modes([(1280, 720, [60, 75])])
+            .mode(1920, 1080, &[120, 60])
+            .preferred_mode(1920, 1080, 120)
+            .serial(7)
+            .build()
+            .unwrap();
+
+        assert_eq!(monitor.id, 2);
+        assert_eq!(monitor.name.as_deref(), Some("Desk"));
+        assert!(!monitor.enabled);
+        assert_eq!(monitor.position, Some((-1920, 0)));
+        assert_eq!(monitor.edid_serial(), 7);
+        assert_eq!(
+            monitor.modes,
+            [mode(1920, 1080, &[120, 60]), mode(1280, 720, &[60, 75])]
+        );
+    }
+
+    #[test]
+    fn build_reports_invalid_monitor() {
+        let result = MonitorBuilder::new(1)
+            .mode(0, 1080, &[60])
+            .manufacturer_id("dell")
+            .build();
+
+        assert_eq!(
+            result,
+            Err(vec![
+                MonitorError::InvalidDimensions(1, 0, 1080),
+                MonitorError::InvalidManufacturerId(1, "dell".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn auto_id_needs_reservation() {
+        let result = MonitorBuilder::new(IdChoice::Auto)
+            .mode(1920, 1080, &[60])
+            .build();
+
+        assert_eq!(result, Err(vec![MonitorError::MissingId]));
+    }
+
+    #[test]
+    fn id_from_name() {
+        let monitor = MonitorBuilder::new(IdChoice::FromName)
+            .name("capture")
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        assert_eq!(monitor.id, stable_id("capture"));
+
+        let result = MonitorBuilder::new(IdChoice::FromName)
+            .mode(1920, 1080, &[60])
+            .build();
+        assert_eq!(result, Err(vec![MonitorError::MissingId]));
+    }
+
+    #[test]
+    fn continues_from_monitor() {
+        let monitor = MonitorBuilder::new(0)
+            .mode(800, 600, &[60])
+            .build()
+            .unwrap();
+        let renamed = MonitorBuilder::from(monitor.clone())
+            .name("renamed")
+            .build()
+            .unwrap();
+
+        assert_eq!(renamed.modes, monitor.modes);
+        assert_eq!(renamed.name.as_deref(), Some("renamed"));
+    }
+}
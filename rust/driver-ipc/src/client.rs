@@ -1,19 +1,38 @@
-use std::{io, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
-use log::error;
+use log::{debug, error};
 use serde::Serialize;
 use tokio::{
     net::windows::named_pipe,
-    sync::{broadcast, Notify, RwLock},
+    sync::{broadcast, oneshot, Notify, RwLock},
     task,
-    time::timeout,
+    time::{sleep, timeout},
 };
 use tokio_stream::{Stream, StreamExt};
 
-use crate::*;
+use crate::{
+    framing::{FrameDecoder, FrameTooLarge, Framing, LEGACY_EOF},
+    *,
+};
+
+/// Time a request waits for the driver to reply, unless changed with
+/// [Client::set_request_timeout].
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time [Client::resolve_display] waits for a monitor to show up as a display.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
 
-// EOF byte used to separate messages
-pub(crate) const EOF: u8 = 0x4;
+/// Time to wait for the driver to answer the handshake. Drivers from before
+/// the handshake never answer, so this delays connecting to them.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Client for interacting with the Virtual Display Driver.
 ///
@@ -27,6 +46,7 @@ pub(crate) const EOF: u8 = 0x4;
 pub struct Client {
     shared: Arc<_Shared>,
     command_rx: broadcast::Receiver<Result<ClientCommand, error::ReceiveError>>,
+    request_timeout: Duration,
 }
 
 #[derive(Debug)]
@@ -34,6 +54,20 @@ struct _Shared {
     client: named_pipe::NamedPipeClient,
     abort_receiver: Notify,
     receive_error: RwLock<Option<Arc<io::Error>>>,
+    // requests waiting for the reply tagged with their id
+    pending: Mutex<HashMap<RequestId, oneshot::Sender<ReplyCommand>>>,
+    next_id: AtomicU64,
+    // outcome of the handshake, set once while connecting
+    negotiated: OnceLock<Negotiated>,
+    // set if the driver is from before the length prefix, see framing
+    legacy_framing: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Negotiated {
+    protocol: u32,
+    // `None` for drivers from before the handshake
+    driver_version: Option<String>,
 }
 
 impl Client {
@@ -46,17 +80,44 @@ impl Client {
         Self::connect_to(DEFAULT_PIPE_NAME).await
     }
 
+    /// Connect to driver on pipe with default name, waiting up to `timeout`
+    /// for the pipe to become available.
+    ///
+    /// See [Client::connect_to_timeout].
+    pub async fn connect_timeout(timeout: Duration) -> Result<Self, error::ConnectionError> {
+        Self::connect_to_timeout(DEFAULT_PIPE_NAME, timeout).await
+    }
+
     /// Connect to driver on pipe with specified name.
     ///
     /// `name` is ONLY the {name} portion of \\.\pipe\{name}.
     ///
+    /// Fails immediately if the driver is busy serving another connection,
+    /// use [Client::connect_to_timeout] to wait instead.
+    ///
+    /// Agrees on a protocol version with the driver before returning, and
+    /// fails with [ConnectionError::Incompatible](error::ConnectionError::Incompatible)
+    /// if there is none both understand.
+    ///
+    /// Fails with [ConnectionError::NotRunning](error::ConnectionError::NotRunning)
+    /// if there is no such pipe, and with
+    /// [ConnectionError::AccessDenied](error::ConnectionError::AccessDenied)
+    /// if the driver restricts its pipe to users this process doesn't run as.
+    ///
+    /// See [ClientBuilder] for retries and other settings.
+    ///
     /// This method is async because it requires a running tokio reactor.
     pub async fn connect_to(name: &str) -> Result<Self, error::ConnectionError> {
         let client = named_pipe::ClientOptions::new()
             .read(true)
             .write(true)
             .pipe_mode(named_pipe::PipeMode::Byte)
-            .open(format!(r"\\.\pipe\{name}"))?;
+            .open(format!(r"\\.\pipe\{name}"))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => error::ConnectionError::NotRunning,
+                io::ErrorKind::PermissionDenied => error::ConnectionError::AccessDenied,
+                _ => error::ConnectionError::Failed(e),
+            })?;
 
         let abort_receiver = Notify::new();
 
@@ -64,6 +125,10 @@ impl Client {
             client,
             abort_receiver,
             receive_error: RwLock::new(None),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            negotiated: OnceLock::new(),
+            legacy_framing: AtomicBool::new(false),
         });
 
         let (command_tx, command_rx) =
@@ -72,7 +137,7 @@ impl Client {
         {
             let shared = shared.clone();
             task::spawn(async move {
-                let r = receive_command(&shared.client, &command_tx, &shared.abort_receiver).await;
+                let r = receive_command(&shared, &command_tx).await;
                 if let Err(e) = r {
                     let error = Arc::new(e);
                     shared.receive_error.write().await.replace(error.clone());
@@ -81,517 +146,2179 @@ impl Client {
             });
         }
 
-        Ok(Self { shared, command_rx })
-    }
+        let client = Self {
+            shared,
+            command_rx,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        };
 
-    /// Send new state to the driver.
-    pub async fn notify(&self, monitors: &[Monitor]) -> Result<(), error::SendError> {
-        let command = DriverCommand::Notify(monitors.to_owned());
+        client.handshake().await?;
 
-        send_command(&self.shared.client, &command).await?;
-        Ok(())
+        Ok(client)
     }
 
-    /// Remove all monitors with the specified IDs.
-    pub async fn remove(&self, ids: &[Id]) -> Result<(), error::SendError> {
-        let command = DriverCommand::Remove(ids.to_owned());
+    /// Agree on a protocol version with the driver.
+    ///
+    /// Drivers from before the handshake don't answer it (or answer with an
+    /// error), they are spoken to with [LEGACY_PROTOCOL_VERSION]. A driver
+    /// which doesn't answer at all might also be from before the length
+    /// prefix, so the legacy framing is used from then on.
+    async fn handshake(&self) -> Result<(), error::ConnectionError> {
+        let command = RequestCommand::Hello {
+            protocol: PROTOCOL_VERSION,
+            client_name: client_name(),
+        };
 
-        send_command(&self.shared.client, &command).await?;
-        Ok(())
-    }
+        let reply = self
+            .request_with_timeout(&command, HANDSHAKE_TIMEOUT, |reply| match reply {
+                ReplyCommand::HelloReply {
+                    protocol,
+                    driver_version,
+                } => Some((protocol, driver_version)),
+                _ => None,
+            })
+            .await;
+
+        let negotiated = match reply {
+            Ok((protocol, driver_version))
+                if (LEGACY_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol) =>
+            {
+                Negotiated {
+                    protocol,
+                    driver_version: Some(driver_version),
+                }
+            }
+            Ok((protocol, driver_version)) => {
+                return Err(error::ConnectionError::Incompatible {
+                    protocol,
+                    driver_version,
+                })
+            }
+            Err(error::RequestError::Timeout(_)) => {
+                // such a driver still waits for the end of the hello, terminate it so the
+                // next message isn't glued to it
+                self.shared.legacy_framing.store(true, Ordering::Relaxed);
+                write_all(&self.shared.client, &[LEGACY_EOF])
+                    .await
+                    .map_err(|e| error::ConnectionError::Handshake(error::RequestError::Send(e)))?;
+
+                Negotiated {
+                    protocol: LEGACY_PROTOCOL_VERSION,
+                    driver_version: None,
+                }
+            }
+            Err(error::RequestError::Ipc(_)) => Negotiated {
+                protocol: LEGACY_PROTOCOL_VERSION,
+                driver_version: None,
+            },
+            Err(e) => return Err(error::ConnectionError::Handshake(e)),
+        };
 
-    /// Remove all monitors.
-    pub async fn remove_all(&self) -> Result<(), error::SendError> {
-        let command = DriverCommand::RemoveAll;
+        self.shared
+            .negotiated
+            .set(negotiated)
+            .expect("handshake is only done once");
 
-        send_command(&self.shared.client, &command).await?;
         Ok(())
     }
 
-    /// Request the current state of the driver.
+    /// Version of the driver, as reported in the handshake.
     ///
-    /// Returns [IpcError::Timeout] if the driver does not respond within 5
-    /// seconds.
-    pub async fn request_state(&self) -> Result<Vec<Monitor>, error::RequestError> {
-        use broadcast::error::RecvError;
+    /// `None` if the driver is older than the handshake.
+    pub fn driver_version(&self) -> Option<&str> {
+        self.shared
+            .negotiated
+            .get()
+            .and_then(|negotiated| negotiated.driver_version.as_deref())
+    }
 
-        let mut rx = self.command_rx.resubscribe();
+    /// Protocol version agreed on with the driver, see [PROTOCOL_VERSION].
+    pub fn protocol_version(&self) -> u32 {
+        self.shared
+            .negotiated
+            .get()
+            .map_or(LEGACY_PROTOCOL_VERSION, |negotiated| negotiated.protocol)
+    }
 
-        send_command(&self.shared.client, &RequestCommand::State).await?;
+    /// Connect to driver on pipe with specified name, waiting up to `timeout`
+    /// for the pipe to become available.
+    ///
+    /// `name` is ONLY the {name} portion of \\.\pipe\{name}.
+    ///
+    /// Returns [ConnectionError::Timeout] if the driver stayed busy. Other
+    /// errors, like a missing pipe, are returned immediately.
+    pub async fn connect_to_timeout(
+        name: &str,
+        timeout: Duration,
+    ) -> Result<Self, error::ConnectionError> {
+        use windows::Win32::Foundation::ERROR_PIPE_BUSY;
 
         let fut = async {
             loop {
-                match rx.recv().await {
-                    Ok(Ok(ClientCommand::Reply(ReplyCommand::State(monitors)))) => {
-                        break Ok(monitors)
-                    }
-                    Ok(Err(e)) => break Err(error::RequestError::Receive(e.0.clone())),
-                    Ok(_) => continue,
-                    Err(RecvError::Lagged(_n)) => continue,
-                    Err(RecvError::Closed) => match self.shared.receive_error.read().await.as_ref()
+                match Self::connect_to(name).await {
+                    Err(error::ConnectionError::Failed(e))
+                        if e.raw_os_error().and_then(|c| u32::try_from(c).ok())
+                            == Some(ERROR_PIPE_BUSY.0) =>
                     {
-                        Some(e) => break Err(error::RequestError::Receive(e.clone())),
-                        None => {
-                            break Err(error::RequestError::Receive(Arc::new(io::Error::new(
-                                io::ErrorKind::BrokenPipe,
-                                "Pipe closed",
-                            ))))
-                        }
-                    },
+                        sleep(Duration::from_millis(50)).await;
+                    }
+                    result => break result,
                 }
             }
         };
 
-        match timeout(Duration::from_secs(5), fut).await {
-            Ok(result) => result,
-            Err(_) => Err(error::RequestError::Timeout(Duration::from_secs(5))),
-        }
+        tokio::time::timeout(timeout, fut)
+            .await
+            .unwrap_or(Err(error::ConnectionError::Timeout(timeout)))
     }
 
-    /// Receive continuous events from the driver.
-    ///
-    /// Only new events after calling this method are received.
+    /// Time requests wait for the driver to reply.
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// Change how long requests wait for the driver to reply.
     ///
-    /// May be called multiple times.
+    /// Only affects this copy of the client, so a clone can be used for
+    /// requests with a different timeout.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Send new state to the driver.
     ///
-    /// Note: If multiple copies of this client exist, the receiver will only be
-    /// closed after all copies are dropped.
-    pub fn receive_events(&self) -> impl Stream<Item = Result<EventCommand, error::ReceiveError>> {
-        use tokio_stream::wrappers::*;
+    /// The change is temporary, see [Client::notify_with].
+    pub async fn notify(&self, monitors: &[Monitor]) -> Result<(), error::SendError> {
+        self.notify_with(monitors, false).await
+    }
 
-        let stream = BroadcastStream::new(self.command_rx.resubscribe());
+    /// Send new state to the driver.
+    ///
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    pub async fn notify_with(
+        &self,
+        monitors: &[Monitor],
+        persistence: bool,
+    ) -> Result<(), error::SendError> {
+        let command = DriverCommand::Notify(monitors.to_owned());
+        self.send_driver_command(command, persistence).await
+    }
 
-        stream.filter_map(|cmd| match cmd {
-            Ok(Ok(ClientCommand::Event(event))) => Some(Ok(event)),
-            Ok(Err(e)) => Some(Err(e)),
-            Err(errors::BroadcastStreamRecvError::Lagged(_n)) => None, // TODO: Indicate lagged? (Maybe changing Item to Result<EventCommand, ...> is better?)
-            _ => None,
-        })
+    /// Remove all monitors with the specified IDs.
+    ///
+    /// The change is temporary, see [Client::remove_with].
+    pub async fn remove(&self, ids: &[Id]) -> Result<(), error::SendError> {
+        self.remove_with(ids, false).await
     }
 
-    /// Write `monitors` to the registry for current user.
+    /// Remove all monitors with the specified IDs.
     ///
-    /// Next time the driver is started, it will load this state from the
-    /// registry. This might be after a reboot or a driver restart.
-    pub fn persist(monitors: &[Monitor]) -> Result<(), error::PersistError> {
-        use winreg::*;
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    pub async fn remove_with(&self, ids: &[Id], persistence: bool) -> Result<(), error::SendError> {
+        let command = DriverCommand::Remove(ids.to_owned());
+        self.send_driver_command(command, persistence).await
+    }
 
-        let hklm = RegKey::predef(enums::HKEY_CURRENT_USER);
-        let key = r"SOFTWARE\VirtualDisplayDriver";
+    /// Remove all monitors.
+    ///
+    /// The change is temporary, see [Client::remove_all_with].
+    pub async fn remove_all(&self) -> Result<(), error::SendError> {
+        self.remove_all_with(false).await
+    }
 
-        let mut reg_key = hklm.open_subkey_with_flags(key, enums::KEY_WRITE);
+    /// Remove all monitors.
+    ///
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    pub async fn remove_all_with(&self, persistence: bool) -> Result<(), error::SendError> {
+        self.send_driver_command(DriverCommand::RemoveAll, persistence)
+            .await
+    }
 
-        // if open failed, try to create key and subkey
-        if reg_key.is_err() {
-            reg_key = hklm.create_subkey(key).map(|(key, _)| key);
+    /// Make the driver save its current state.
+    ///
+    /// The driver restores this state the next time it is started.
+    pub async fn persist_now(&self) -> Result<(), error::SendError> {
+        send_command(&self.shared, &DriverCommand::PersistNow).await?;
+        Ok(())
+    }
 
-            if let Err(e) = reg_key {
-                return Err(error::PersistError::Open(e));
+    async fn send_driver_command(
+        &self,
+        command: DriverCommand,
+        persistence: bool,
+    ) -> Result<(), error::SendError> {
+        // plain commands are temporary, which keeps the message readable by older drivers
+        let command = if persistence {
+            DriverCommand::WithPersistence {
+                command: Box::new(command),
+                persistence,
             }
-        }
+        } else {
+            command
+        };
 
-        let reg_key = reg_key.unwrap();
+        send_command(&self.shared, &command).await?;
+        Ok(())
+    }
 
-        let data = serde_json::to_string(monitors)?;
+    /// Run a driver command and wait until the driver applied it.
+    ///
+    /// Unlike [Client::notify] and friends, failures on the driver side (for
+    /// example a rejected monitor list, or an IddCx call failing) are returned
+    /// as [error::RequestError::Ipc] instead of only ending up in the driver log.
+    ///
+    /// Returns [error::RequestError::Timeout] if the driver does not respond within
+    /// the request timeout (5 seconds by default), and
+    /// [error::RequestError::Unsupported] if the driver is too old to answer
+    /// driver commands.
+    pub async fn execute(&self, command: &DriverCommand) -> Result<(), error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
 
-        reg_key
-            .set_value("data", &data)
-            .map_err(error::PersistError::Set)?;
+        self.request(command, |reply| match reply {
+            ReplyCommand::Done => Some(()),
+            _ => None,
+        })
+        .await
+    }
 
-        Ok(())
+    /// Make `monitors` the complete state of the driver, in one pass.
+    ///
+    /// The driver adds, updates and removes monitors as needed before any of
+    /// them show up in Windows, so the desktop is only rearranged once. A
+    /// monitor failing doesn't stop the others, the returned report lists the
+    /// outcome per monitor. A list failing validation is rejected as a whole
+    /// with [error::RequestError::Ipc].
+    ///
+    /// Returns [error::RequestError::Timeout] if the driver does not respond
+    /// within the request timeout (5 seconds by default), and
+    /// [error::RequestError::Unsupported] if the driver is too old to apply
+    /// monitors in one pass.
+    pub async fn set_monitors(
+        &self,
+        monitors: &[Monitor],
+    ) -> Result<ApplyReport, error::RequestError> {
+        self.apply(monitors, ApplyMode::Replace).await
     }
-}
 
-impl Clone for Client {
-    fn clone(&self) -> Self {
-        Self {
-            shared: self.shared.clone(),
-            command_rx: self.command_rx.resubscribe(),
+    /// Reserve the lowest monitor ID which is neither used nor reserved, so
+    /// other clients can't take it.
+    ///
+    /// The driver refuses monitors with this ID from other connections with
+    /// [ErrorCode::IdReserved], until this client disconnects. Once a monitor
+    /// uses the ID it isn't handed out again anyway. A reservation is not
+    /// saved, only monitors are.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// reserve IDs.
+    pub async fn new_id(&self) -> Result<Id, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
         }
+
+        self.request(&DriverCommand::ReserveId, |reply| match reply {
+            ReplyCommand::ReservedId(id) => Some(id),
+            _ => None,
+        })
+        .await
     }
-}
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        if Arc::strong_count(&self.shared) == 2 {
-            self.shared.abort_receiver.notify_waiters();
+    /// Reserve the monitor ID `id` like [Client::new_id], e.g. one from
+    /// [stable_id](crate::stable_id), so a re-added monitor keeps its ID.
+    ///
+    /// Fails with [ErrorCode::IdInUse] if a monitor has the ID, and with
+    /// [ErrorCode::IdReserved] if another client reserved it.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// reserve IDs.
+    pub async fn reserve_id(&self, id: Id) -> Result<Id, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
         }
+
+        self.request(
+            &DriverCommand::ReserveRequestedId { id },
+            |reply| match reply {
+                ReplyCommand::ReservedId(id) => Some(id),
+                _ => None,
+            },
+        )
+        .await
     }
-}
 
-async fn send_command(
-    client: &named_pipe::NamedPipeClient,
-    command: &impl Serialize,
-) -> Result<(), error::SendCommandError> {
-    // Create a vector with the full message, then send it as a single
-    // write. This is required because the pipe is in message mode.
-    let mut message = serde_json::to_vec(command)?;
-    message.push(EOF);
+    /// Add or update `monitors` in one pass, keeping the other monitors of
+    /// the driver as they are.
+    ///
+    /// Otherwise the same as [Client::set_monitors], the report lists only
+    /// the given monitors.
+    pub async fn merge_monitors(
+        &self,
+        monitors: &[Monitor],
+    ) -> Result<ApplyReport, error::RequestError> {
+        self.apply(monitors, ApplyMode::Merge).await
+    }
 
-    // write to pipe without needing to block or split it
+    async fn apply(
+        &self,
+        monitors: &[Monitor],
+        mode: ApplyMode,
+    ) -> Result<ApplyReport, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
 
-    let mut written = 0;
-    loop {
-        // wait for pipe to be writable
-        client.writable().await?;
+        let command = DriverCommand::Apply {
+            monitors: monitors.to_owned(),
+            mode,
+        };
 
-        match client.try_write(&message[written..]) {
-            // we wrote less than the entire size
-            Ok(n) if written + n < message.len() => {
-                written += n;
-                continue;
-            }
+        self.request(&command, |reply| match reply {
+            ReplyCommand::Applied(report) => Some(report),
+            _ => None,
+        })
+        .await
+    }
 
-            // write succeeded
-            Ok(n) if written + n >= message.len() => {
-                break;
-            }
+    /// Return the driver to the state of a fresh install.
+    ///
+    /// Removes all monitors, deletes the monitors the driver saved, and drops
+    /// an adapter preference set with [Client::set_preferred_adapter].
+    /// Subscribers receive the empty state. Nothing is removed if the saved
+    /// monitors can't be deleted.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// reset.
+    pub async fn reset(&self) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::Reset).await
+    }
 
-            // nothing wrote, retry again
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
-            }
+    /// Enable or disable a monitor without changing anything else about it.
+    ///
+    /// A disabled monitor is detached from Windows, but the driver keeps its
+    /// configuration, so enabling it again brings it back as it was. Setting
+    /// the state the monitor is already in succeeds without doing anything.
+    ///
+    /// The change is temporary, see [Client::set_enabled_with].
+    pub async fn set_enabled(&self, id: Id, enabled: bool) -> Result<(), error::RequestError> {
+        self.set_enabled_with(id, enabled, false).await
+    }
 
-            // actual error
-            Err(e) => {
-                return Err(e.into());
+    /// Enable or disable a monitor without changing anything else about it.
+    ///
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    ///
+    /// Fails with [ErrorCode::MonitorNotFound] if the driver has no monitor
+    /// with this ID, and with [error::RequestError::Unsupported] if the
+    /// driver is too old to enable single monitors.
+    pub async fn set_enabled_with(
+        &self,
+        id: Id,
+        enabled: bool,
+        persistence: bool,
+    ) -> Result<(), error::RequestError> {
+        let command = DriverCommand::SetEnabled { id, enabled };
+        let command = if persistence {
+            DriverCommand::WithPersistence {
+                command: Box::new(command),
+                persistence,
             }
+        } else {
+            command
+        };
 
-            _ => unreachable!(),
-        }
+        self.execute(&command).await
     }
 
-    Ok(())
-}
+    /// Make the driver render on the adapter matching `preference`.
+    ///
+    /// Windows moves the swap chains over on its own, check where they ended
+    /// up with [Client::state]. If no adapter matches, the driver logs a
+    /// warning and keeps the adapter IddCx picked. `None` drops the preference
+    /// set here or in the driver settings.
+    ///
+    /// The preference is kept until the driver restarts. Returns
+    /// [error::RequestError::Unsupported] if the driver is too old to select
+    /// adapters.
+    pub async fn set_preferred_adapter(
+        &self,
+        preference: Option<AdapterPreference>,
+    ) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::SetPreferredAdapter(preference))
+            .await
+    }
 
-// receive all commands and send them back to the receiver
-async fn receive_command(
-    client: &named_pipe::NamedPipeClient,
-    tx: &broadcast::Sender<Result<ClientCommand, error::ReceiveError>>,
-    abort: &Notify,
-) -> Result<(), io::Error> {
-    let mut buf = vec![0; 4096];
-    let mut recv_buf = Vec::with_capacity(4096);
+    /// Tell the driver whether a user is logged on and the session is
+    /// unlocked, which attaches or detaches the [SessionScope::User]
+    /// monitors.
+    ///
+    /// Meant for the user session service, which follows the session changes.
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// know about sessions.
+    pub async fn set_user_session(&self, active: bool) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::SetUserSession { active })
+            .await
+    }
 
-    loop {
-        // wait for client to be readable
-        tokio::select! {
-            r = client.readable() => r?,
-            _ = abort.notified() => return Ok(()),
+    /// Start or stop exporting the frames of monitor `id` into shared memory,
+    /// returns the name of the export while it is enabled.
+    ///
+    /// Open the name with [FrameReceiver](crate::frame_export::FrameReceiver)
+    /// to read the frames. Enabling an export which runs already returns its
+    /// name again. The export ends when it is disabled or the monitor is
+    /// removed, and frames only flow while Windows renders to the monitor.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// export frames.
+    pub async fn set_frame_export(
+        &self,
+        id: Id,
+        enabled: bool,
+    ) -> Result<Option<String>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
         }
 
-        match client.try_read(&mut buf) {
-            Ok(0) => return Err(io::Error::last_os_error()),
-            Ok(n) => recv_buf.extend(&buf[..n]),
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(e) => return Err(e),
-        }
+        self.request(
+            &DriverCommand::SetFrameExport { id, enabled },
+            |reply| match reply {
+                ReplyCommand::FrameExport(name) => Some(name),
+                _ => None,
+            },
+        )
+        .await
+    }
 
-        let eof_iter =
-            recv_buf.iter().enumerate().filter_map(
-                |(i, &byte)| {
-                    if byte == EOF {
-                        Some(i)
-                    } else {
-                        None
-                    }
-                },
-            );
+    /// Set the monitors the driver starts with while it has no saved
+    /// monitors, e.g. for a server which needs a display before anyone logs
+    /// on.
+    ///
+    /// They are checked like the monitors of [Client::notify], but only
+    /// stored, the current monitors stay as they are. Monitors saved by a
+    /// persisting command take precedence, even if none are saved, until
+    /// [Client::reset] deletes them. An empty list removes the defaults.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// start with defaults.
+    pub async fn set_boot_defaults(&self, monitors: &[Monitor]) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::SetBootDefaults {
+            monitors: monitors.to_owned(),
+        })
+        .await
+    }
 
-        let mut offset = 0;
-        for pos in eof_iter {
-            let data = &recv_buf[offset..pos];
-            offset = pos + 1;
+    /// Set the most verbose level the driver logs at, until it is restarted.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// change its log level.
+    pub async fn set_log_level(&self, level: LogLevel) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::SetLogLevel(level)).await
+    }
 
-            let Ok(command) = serde_json::from_slice::<ClientCommand>(data) else {
-                continue;
-            };
+    /// Set how the adapter exposes monitors, from the next driver start on.
+    ///
+    /// The current monitors stay as they are. Saved monitors are kept while
+    /// the adapter has [static connectors](AdapterConfig::Static), and are
+    /// restored once it is [dynamic](AdapterConfig::Dynamic) again.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// configure its adapter.
+    pub async fn set_adapter_config(
+        &self,
+        config: &AdapterConfig,
+    ) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::SetAdapterConfig(config.clone()))
+            .await
+    }
 
-            if tx.send(Ok(command)).is_err() {
-                // Client closed, abort
-                return Ok(());
-            }
-        }
+    /// Run the monitor with the given ID in `mode` alone for `ttl`, after
+    /// which the driver brings back the modes it had before.
+    ///
+    /// The driver reverts on its own, also if this client exits or crashes.
+    /// Calling this again before then renews the override, the modes from
+    /// before the first call are the ones which come back. Changing the modes
+    /// of the monitor in the meantime ends the override and keeps the change.
+    /// Persisting saves the modes from before the override.
+    ///
+    /// Fails with [ErrorCode::MonitorNotFound] if there is no such monitor, and
+    /// with [ErrorCode::InvalidMonitors] if the monitor can't run `mode`.
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// override modes.
+    pub async fn override_mode(
+        &self,
+        id: Id,
+        mode: &Mode,
+        ttl: Duration,
+    ) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::OverrideMode {
+            id,
+            mode: mode.clone(),
+            ttl_ms: u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX),
+        })
+        .await
+    }
 
-        // drain all processed messages
-        recv_buf.drain(..offset);
+    /// End the override of [Client::override_mode] now, bringing back the
+    /// modes from before it.
+    ///
+    /// Does nothing if the monitor isn't overridden. Returns
+    /// [error::RequestError::Unsupported] if the driver is too old to
+    /// override modes.
+    pub async fn revert_mode(&self, id: Id) -> Result<(), error::RequestError> {
+        self.execute(&DriverCommand::RevertMode { id }).await
     }
-}
 
-pub mod error {
-    use super::*;
-    use thiserror::Error;
+    /// Make the driver read its settings from the registry again.
+    ///
+    /// The driver reloads them on its own when they change, this returns
+    /// which settings it applied and which only take effect on the next
+    /// driver start.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// reload its settings.
+    pub async fn reload_settings(&self) -> Result<SettingsReload, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
 
-    /// Error returned from [Client::connect] and [Client::connect_to].
-    #[derive(Debug, Error)]
-    pub enum ConnectionError {
-        #[error("Failed to open pipe: {0}")]
-        Failed(#[from] io::Error),
+        self.request(&DriverCommand::ReloadSettings, |reply| match reply {
+            ReplyCommand::SettingsReloaded(reload) => Some(reload),
+            _ => None,
+        })
+        .await
     }
 
-    /// Error returned from [send_command]
-    #[derive(Debug, Error)]
-    pub(super) enum SendCommandError {
-        #[error("Failed to encode message: {0}")]
-        Encode(#[from] serde_json::Error),
-        #[error("Failed to send message: {0}")]
-        PipeBroken(#[from] io::Error),
+    /// Request the current state of the driver.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub async fn request_state(&self) -> Result<Vec<Monitor>, error::RequestError> {
+        self.request(&RequestCommand::State, |reply| match reply {
+            ReplyCommand::State(monitors) => Some(monitors),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request a single monitor of the driver's state, `None` if there is no
+    /// monitor with `id`.
+    ///
+    /// Cheaper than [Client::request_state] when only one monitor matters.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// send single monitors.
+    pub async fn request_monitor(&self, id: Id) -> Result<Option<Monitor>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::Monitor { id }, |reply| match reply {
+            ReplyCommand::Monitor(monitor) => Some(monitor),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request what the driver and Windows actually did with each monitor.
+    ///
+    /// Unlike [Client::request_state], this includes the mode Windows
+    /// committed, whether it renders to the monitor, and where the monitor is
+    /// attached. The [active orientation](MonitorState::active_orientation)
+    /// and [SDR white level](MonitorState::active_sdr_white_level_nits) are
+    /// looked up in the display config of this process' session, and left out
+    /// if that fails.
+    ///
+    /// Returns [error::RequestError::Timeout] if the driver does not respond
+    /// within the request timeout (5 seconds by default), and
+    /// [error::RequestError::Unsupported] if the driver is too old to report
+    /// it.
+    pub async fn state(&self) -> Result<Vec<MonitorState>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        let mut states = self
+            .request(&RequestCommand::QueryState, |reply| match reply {
+                ReplyCommand::QueryState(states) => Some(states),
+                _ => None,
+            })
+            .await?;
+
+        // the display config finds monitors by their EDID serial, which only the monitors have
+        let monitors = self.request_state().await?;
+        let (orientations, white_levels) = task::spawn_blocking(move || {
+            (
+                display_config::active_orientations(&monitors),
+                display_config::active_sdr_white_levels(&monitors),
+            )
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+
+        match orientations {
+            Ok(orientations) => {
+                for state in &mut states {
+                    state.active_orientation = orientations
+                        .iter()
+                        .find_map(|&(id, orientation)| (id == state.id).then_some(orientation));
+                }
+            }
+            Err(e) => debug!("Failed to query the orientation of the monitors: {e}"),
+        }
+
+        match white_levels {
+            Ok(white_levels) => {
+                for state in &mut states {
+                    state.active_sdr_white_level_nits = white_levels
+                        .iter()
+                        .find_map(|&(id, nits)| (id == state.id).then_some(nits));
+                }
+            }
+            Err(e) => debug!("Failed to query the SDR white level of the monitors: {e}"),
+        }
+
+        Ok(states)
+    }
+
+    /// Find the display Windows shows the monitor with ID `id` as, e.g. to
+    /// move a window onto it.
+    ///
+    /// The driver attaches monitors asynchronously after a notify, and
+    /// Windows takes a moment to enumerate them, so this retries for up to 5
+    /// seconds. Returns `None` if the driver has no monitor with this ID, or
+    /// if it isn't part of the desktop by then, e.g. because it is disabled.
+    /// See [display_config::resolve_display] for the lookup.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// report where it attached the monitor.
+    pub async fn resolve_display(
+        &self,
+        id: Id,
+    ) -> Result<Option<display_config::DisplayPath>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        let deadline = Instant::now() + RESOLVE_TIMEOUT;
+
+        let target = loop {
+            let states = self
+                .request(&RequestCommand::QueryState, |reply| match reply {
+                    ReplyCommand::QueryState(states) => Some(states),
+                    _ => None,
+                })
+                .await?;
+
+            let Some(state) = states.into_iter().find(|state| state.id == id) else {
+                return Ok(None);
+            };
+
+            if let Some(target) = state.os_target {
+                break target;
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            sleep(Duration::from_millis(50)).await;
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let path =
+            task::spawn_blocking(move || display_config::resolve_display(id, target, remaining))
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+
+        Ok(path)
+    }
+
+    /// Request the frame counters of a monitor's swap chain.
+    ///
+    /// Returns `None` while Windows has no swap chain attached to the
+    /// monitor. Fails with [ErrorCode::MonitorNotFound] if the driver has no
+    /// monitor with this ID, and with [error::RequestError::Unsupported] if
+    /// the driver is too old to count frames.
+    pub async fn stats(&self, id: Id) -> Result<Option<Stats>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::QueryStats { id }, |reply| match reply {
+            ReplyCommand::Stats(stats) => Some(stats),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request which build of the driver is running, for bug reports.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// report it, in which case [Client::driver_version] may still be known.
+    pub async fn version(&self) -> Result<DriverInfo, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::DriverInfo, |reply| match reply {
+            ReplyCommand::DriverInfo(info) => Some(info),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request whether the driver finished initializing IddCx.
+    ///
+    /// If it failed, the pipe server still runs so this can tell why. The
+    /// status' `Display` is a message for users, with a hint on what to do.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// report it.
+    pub async fn driver_status(&self) -> Result<DriverStatus, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::DriverStatus, |reply| match reply {
+            ReplyCommand::DriverStatus(status) => Some(status),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request the open connections of the driver's pipe, to find out which
+    /// processes talk to the driver. This client is among them, see
+    /// [ClientInfo::current].
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// list them.
+    pub async fn list_clients(&self) -> Result<Vec<ClientInfo>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::ListClients, |reply| match reply {
+            ReplyCommand::Clients(clients) => Some(clients),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request how many messages the driver's pipe server received from all
+    /// clients, and how many it answered with an error.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// count them.
+    pub async fn ipc_stats(&self) -> Result<IpcStats, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::IpcStats, |reply| match reply {
+            ReplyCommand::IpcStats(stats) => Some(stats),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request the monitors set with [Client::set_boot_defaults], empty if
+    /// there are none.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// start with defaults.
+    pub async fn boot_defaults(&self) -> Result<Vec<Monitor>, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(&RequestCommand::BootDefaults, |reply| match reply {
+            ReplyCommand::BootDefaults(monitors) => Some(monitors),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request the records of the driver's log with a sequence number of at
+    /// least `since_seq`, which are at least as severe as `min_level`.
+    ///
+    /// The driver keeps only its latest records, and returns a limited number
+    /// per request. Also returns the sequence number to pass to get the
+    /// records after these, so following the log is calling this again with
+    /// it.
+    ///
+    /// Returns [error::RequestError::Unsupported] if the driver is too old to
+    /// keep its log.
+    pub async fn logs(
+        &self,
+        min_level: LogLevel,
+        since_seq: u64,
+    ) -> Result<(Vec<LogRecord>, u64), error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            return Err(error::RequestError::Unsupported(self.protocol_version()));
+        }
+
+        self.request(
+            &RequestCommand::Logs {
+                min_level,
+                since_seq,
+            },
+            |reply| match reply {
+                ReplyCommand::Logs { records, next_seq } => Some((records, next_seq)),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Request the maximum number of monitors the driver can have enabled at
+    /// the same time.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub async fn request_max_monitors(&self) -> Result<u32, error::RequestError> {
+        self.request(&RequestCommand::MaxMonitors, |reply| match reply {
+            ReplyCommand::MaxMonitors(max) => Some(max),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Request the limits the driver enforces on monitors and modes.
+    ///
+    /// Drivers too old to report them only report their monitor limit, the
+    /// other limits are then filled in from this crate, see
+    /// [Capabilities::with_max_monitors].
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub async fn request_capabilities(&self) -> Result<Capabilities, error::RequestError> {
+        if self.protocol_version() < PROTOCOL_VERSION {
+            let max_monitors = self.request_max_monitors().await?;
+            return Ok(Capabilities::with_max_monitors(max_monitors));
+        }
+
+        self.request(&RequestCommand::Capabilities, |reply| match reply {
+            ReplyCommand::Capabilities(capabilities) => Some(capabilities),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Ask the driver whether it would accept `monitors`, without applying
+    /// them.
+    ///
+    /// The outer result is the request itself, the inner result is the outcome
+    /// of the validation.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub async fn validate(
+        &self,
+        monitors: &[Monitor],
+    ) -> Result<Result<(), Vec<MonitorError>>, error::RequestError> {
+        let command = RequestCommand::Validate(monitors.to_owned());
+
+        self.request(&command, |reply| match reply {
+            ReplyCommand::Validated(errors) if errors.is_empty() => Some(Ok(())),
+            ReplyCommand::Validated(errors) => Some(Err(errors)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Ask the driver to push per monitor events to this connection.
+    ///
+    /// After this returns, [Client::receive_events] also yields
+    /// [EventCommand::MonitorAdded], [EventCommand::MonitorRemoved] and
+    /// [EventCommand::MonitorChanged] for every state change, no matter which
+    /// client (or the driver itself) caused it. The subscription lasts until
+    /// the connection is closed.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub async fn subscribe(&self) -> Result<(), error::RequestError> {
+        self.request(&RequestCommand::Subscribe, |reply| match reply {
+            ReplyCommand::Subscribed => Some(()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Send a request and wait for its reply.
+    ///
+    /// The request is tagged with an id, and the reply echoing it is routed
+    /// back here, so concurrent requests on one connection can't take each
+    /// other's replies. Untagged replies (from a driver that doesn't echo ids)
+    /// are taken in order, the first one accepted by `extract` wins.
+    ///
+    /// A tagged [ReplyCommand::Error] fails the request with
+    /// [error::RequestError::Ipc].
+    async fn request<T>(
+        &self,
+        command: &impl Serialize,
+        extract: impl FnMut(ReplyCommand) -> Option<T>,
+    ) -> Result<T, error::RequestError> {
+        self.request_with_timeout(command, self.request_timeout, extract)
+            .await
+    }
+
+    /// [Client::request] with a different timeout
+    async fn request_with_timeout<T>(
+        &self,
+        command: &impl Serialize,
+        request_timeout: Duration,
+        mut extract: impl FnMut(ReplyCommand) -> Option<T>,
+    ) -> Result<T, error::RequestError> {
+        use broadcast::error::RecvError;
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let _pending = PendingRequest::new(&self.shared, id, reply_tx);
+
+        let mut rx = self.command_rx.resubscribe();
+
+        send_command(&self.shared, &Envelope::new(Some(id), command)).await?;
+
+        let fut = async {
+            let mut reply_rx = Some(reply_rx);
+
+            loop {
+                let received = tokio::select! {
+                    reply = async { reply_rx.as_mut().unwrap().await }, if reply_rx.is_some() => {
+                        reply_rx = None;
+
+                        if let Some(e) = reply.as_ref().ok().and_then(IpcError::from_reply) {
+                            break Err(error::RequestError::Ipc(e));
+                        }
+
+                        // a tagged reply of the wrong kind is ignored, like an untagged one
+                        match reply.ok().and_then(&mut extract) {
+                            Some(value) => break Ok(value),
+                            None => continue,
+                        }
+                    }
+
+                    received = rx.recv() => received,
+                };
+
+                match received {
+                    Ok(Ok(ClientCommand::Reply(reply))) => {
+                        if let Some(value) = extract(reply) {
+                            break Ok(value);
+                        }
+                    }
+                    Ok(Err(e)) => break Err(error::RequestError::Receive(e.0.clone())),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_n)) => continue,
+                    Err(RecvError::Closed) => match self.shared.receive_error.read().await.as_ref()
+                    {
+                        Some(e) => break Err(error::RequestError::Receive(e.clone())),
+                        None => {
+                            break Err(error::RequestError::Receive(Arc::new(io::Error::new(
+                                io::ErrorKind::BrokenPipe,
+                                "Pipe closed",
+                            ))))
+                        }
+                    },
+                }
+            }
+        };
+
+        match timeout(request_timeout, fut).await {
+            Ok(result) => result,
+            Err(_) => Err(error::RequestError::Timeout(request_timeout)),
+        }
+    }
+
+    /// Receive continuous events from the driver.
+    ///
+    /// Only new events after calling this method are received.
+    ///
+    /// May be called multiple times.
+    ///
+    /// Note: If multiple copies of this client exist, the receiver will only be
+    /// closed after all copies are dropped.
+    pub fn receive_events(&self) -> impl Stream<Item = Result<EventCommand, error::ReceiveError>> {
+        use tokio_stream::wrappers::*;
+
+        let stream = BroadcastStream::new(self.command_rx.resubscribe());
+
+        stream.filter_map(|cmd| match cmd {
+            Ok(Ok(ClientCommand::Event(event))) => Some(Ok(event)),
+            Ok(Err(e)) => Some(Err(e)),
+            Err(errors::BroadcastStreamRecvError::Lagged(_n)) => None, // TODO: Indicate lagged? (Maybe changing Item to Result<EventCommand, ...> is better?)
+            _ => None,
+        })
+    }
+
+    /// Write `monitors` to the registry for current user.
+    ///
+    /// Next time the driver is started, it will load this state from the
+    /// registry. This might be after a reboot or a driver restart.
+    ///
+    /// The write is atomic, see [persist::save].
+    pub fn persist(monitors: &[Monitor]) -> Result<(), error::PersistError> {
+        use winreg::*;
+
+        let hkcu = RegKey::predef(enums::HKEY_CURRENT_USER);
+        persist::save(&hkcu, r"SOFTWARE\VirtualDisplayDriver", monitors)
+    }
+
+    /// Read the monitors written by [Client::persist].
+    ///
+    /// A list written by an older version is migrated, and written back in
+    /// the current format, see [persist::load_and_upgrade]. If nothing could
+    /// be loaded, the list is empty.
+    pub fn load_persisted() -> persist::Loaded {
+        use winreg::*;
+
+        let hkcu = RegKey::predef(enums::HKEY_CURRENT_USER);
+        persist::load_and_upgrade(&hkcu, r"SOFTWARE\VirtualDisplayDriver")
+    }
+
+    /// Delete the monitors written by [Client::persist], see
+    /// [persist::clear].
+    pub fn clear_persisted() -> Result<(), error::PersistError> {
+        use winreg::*;
+
+        let hkcu = RegKey::predef(enums::HKEY_CURRENT_USER);
+        persist::clear(&hkcu, r"SOFTWARE\VirtualDisplayDriver")
+    }
+}
+
+/// Registration of a request waiting for its tagged reply
+///
+/// Unregisters on drop, so timed out or cancelled requests don't leak.
+struct PendingRequest<'a> {
+    shared: &'a _Shared,
+    id: RequestId,
+}
+
+impl<'a> PendingRequest<'a> {
+    fn new(shared: &'a _Shared, id: RequestId, reply_tx: oneshot::Sender<ReplyCommand>) -> Self {
+        shared.pending.lock().unwrap().insert(id, reply_tx);
+        Self { shared, id }
+    }
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.shared.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Clone for Client {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            command_rx: self.command_rx.resubscribe(),
+            request_timeout: self.request_timeout,
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.shared) == 2 {
+            self.shared.abort_receiver.notify_waiters();
+        }
+    }
+}
+
+/// Name this process introduces itself with in the handshake
+fn client_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned())
+}
+
+async fn send_command(
+    shared: &_Shared,
+    command: &impl Serialize,
+) -> Result<(), error::SendCommandError> {
+    let framing = if shared.legacy_framing.load(Ordering::Relaxed) {
+        Framing::Legacy
+    } else {
+        Framing::LengthPrefixed
+    };
+
+    // Create a vector with the full message, then send it as a single write
+    let message = framing.encode(serde_json::to_vec(command)?)?;
+
+    write_all(&shared.client, &message).await?;
+
+    Ok(())
+}
+
+// write to pipe without needing to block
+async fn write_all(client: &named_pipe::NamedPipeClient, message: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    loop {
+        // wait for pipe to be writable
+        client.writable().await?;
+
+        match client.try_write(&message[written..]) {
+            // we wrote less than the entire size
+            Ok(n) if written + n < message.len() => {
+                written += n;
+                continue;
+            }
+
+            // write succeeded
+            Ok(n) if written + n >= message.len() => {
+                break;
+            }
+
+            // nothing wrote, retry again
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                continue;
+            }
+
+            // actual error
+            Err(e) => {
+                return Err(e);
+            }
+
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+// receive all commands, hand tagged replies to their request and send everything else to the receiver
+async fn receive_command(
+    shared: &_Shared,
+    tx: &broadcast::Sender<Result<ClientCommand, error::ReceiveError>>,
+) -> Result<(), io::Error> {
+    let client = &shared.client;
+    let abort = &shared.abort_receiver;
+
+    let mut buf = vec![0; 4096];
+    // replies come in the framing the driver detected from our messages
+    let mut decoder = FrameDecoder::new();
+
+    loop {
+        // wait for client to be readable
+        tokio::select! {
+            r = client.readable() => r?,
+            _ = abort.notified() => return Ok(()),
+        }
+
+        match client.try_read(&mut buf) {
+            Ok(0) => return Err(io::Error::last_os_error()),
+            Ok(n) => decoder.push(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+
+        while let Some(data) = decoder
+            .next_frame()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        {
+            // a reply this version doesn't understand is skipped, the request waiting for it times out
+            let Envelope { id, command } = match Envelope::<ClientCommand>::parse(&data) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    debug!("Skipping {e}");
+                    continue;
+                }
+            };
+
+            let command = match (id, command) {
+                (Some(id), ClientCommand::Reply(reply)) => {
+                    let reply_tx = shared.pending.lock().unwrap().remove(&id);
+                    match reply_tx {
+                        Some(reply_tx) => {
+                            // the request gave up already if this fails
+                            _ = reply_tx.send(reply);
+                            continue;
+                        }
+                        // nobody waits for this id, treat it like an untagged reply
+                        None => ClientCommand::Reply(reply),
+                    }
+                }
+                (_, command) => command,
+            };
+
+            if tx.send(Ok(command)).is_err() {
+                // Client closed, abort
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub mod error {
+    use super::*;
+    use thiserror::Error;
+
+    /// Error returned from [Client::connect], [Client::connect_to], their
+    /// `_timeout` variants and [ClientBuilder::connect].
+    #[derive(Debug, Error)]
+    pub enum ConnectionError {
+        #[error("Failed to open pipe: {0}")]
+        Failed(#[from] io::Error),
+        #[error("Driver is not running: its pipe does not exist")]
+        NotRunning,
+        #[error("Access denied by the security descriptor of the driver pipe (see the driver's PipeAccess setting)")]
+        AccessDenied,
+        #[error("Driver pipe stayed busy for {0:?}")]
+        Timeout(Duration),
+        #[error("Handshake with driver failed: {0}")]
+        Handshake(RequestError),
+        #[error(
+            "Driver {driver_version} speaks protocol version {protocol}, which is not supported"
+        )]
+        Incompatible {
+            protocol: u32,
+            driver_version: String,
+        },
+    }
+
+    impl ConnectionError {
+        /// Whether connecting again fails the same way, because the pipe
+        /// is not accessible to this process or the driver is incompatible.
+        pub fn is_permanent(&self) -> bool {
+            matches!(self, Self::AccessDenied | Self::Incompatible { .. })
+        }
+    }
+
+    /// Error returned from [MonitorBuilder::build_reserved](crate::MonitorBuilder::build_reserved).
+    #[derive(Debug, Error)]
+    pub enum BuildError {
+        #[error("Failed to reserve a monitor ID: {0}")]
+        Reserve(#[from] RequestError),
+        #[error("Invalid monitor: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+        Invalid(Vec<MonitorError>),
+    }
+
+    /// Error returned from [send_command]
+    #[derive(Debug, Error)]
+    pub(super) enum SendCommandError {
+        #[error("Failed to encode message: {0}")]
+        Encode(#[from] serde_json::Error),
+        #[error("{0}")]
+        TooLarge(#[from] FrameTooLarge),
+        #[error("Failed to send message: {0}")]
+        PipeBroken(#[from] io::Error),
+    }
+
+    /// Error returned from [Client::notify], [Client::remove] and
+    /// [Client::remove_all].
+    #[derive(Debug, Error)]
+    pub enum SendError {
+        #[error("Failed to send message: {0}")]
+        PipeBroken(#[from] io::Error),
+        #[error("Failed to send message: {0}")]
+        TooLarge(FrameTooLarge),
+        #[error("Failed to encode message: {0}")]
+        Encode(serde_json::Error),
+    }
+
+    /// Error returned from [Client::request_state],
+    /// [Client::request_max_monitors], [Client::validate],
+    /// [Client::subscribe] and [Client::execute].
+    #[derive(Debug, Error)]
+    pub enum RequestError {
+        #[error("Failed to send message (pipe broken): {0}")]
+        Send(io::Error),
+        #[error("Failed to receive message (pipe broken): {0}")]
+        Receive(Arc<io::Error>),
+        #[error("Did not get a response in time ({0:?})")]
+        Timeout(Duration),
+        #[error("{0}")]
+        Ipc(#[from] IpcError),
+        #[error("Driver speaks protocol version {0}, which does not support this request")]
+        Unsupported(u32),
+        #[error("Failed to send message: {0}")]
+        TooLarge(FrameTooLarge),
+        #[error("Failed to encode message: {0}")]
+        Encode(serde_json::Error),
+    }
+
+    /// Error reply of the driver, see [ReplyCommand::Error].
+    pub use crate::IpcError;
+
+    /// Error returned from [Client::receive_events].
+    #[derive(Debug, Error, Clone)]
+    #[error("Failed to receive event: {0}")]
+    pub struct ReceiveError(#[from] pub Arc<io::Error>);
+
+    /// Error returned from [Client::persist].
+    pub use crate::persist::PersistError;
+
+    impl From<SendCommandError> for SendError {
+        fn from(e: SendCommandError) -> Self {
+            match e {
+                SendCommandError::PipeBroken(e) => Self::PipeBroken(e),
+                SendCommandError::TooLarge(e) => Self::TooLarge(e),
+                SendCommandError::Encode(e) => Self::Encode(e),
+            }
+        }
+    }
+
+    impl From<SendCommandError> for RequestError {
+        fn from(e: SendCommandError) -> Self {
+            match e {
+                SendCommandError::PipeBroken(e) => Self::Send(e),
+                SendCommandError::TooLarge(e) => Self::TooLarge(e),
+                SendCommandError::Encode(e) => Self::Encode(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::mock::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn receiver_stops_when_client_closed() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-receiver_stops_when_client_closed";
+
+        let mut server = MockServer::new(PIPE_NAME);
+
+        let client1 = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+        let stream1 = client1.receive_events();
+
+        let client2 = client1.clone();
+        let stream2 = client2.receive_events();
+
+        client1.notify(&[]).await.expect("Failed to notify");
+        server.pump().await;
+
+        sleep(Duration::from_millis(50)).await;
+
+        drop(client1);
+
+        client2.notify(&[]).await.expect("Failed to notify");
+        server.pump().await;
+
+        sleep(Duration::from_millis(50)).await;
+
+        drop(client2);
+
+        let events1: Vec<_> = stream1.collect().await;
+        let events2: Vec<_> = stream2.collect().await;
+
+        assert_eq!(events1.len(), 2, "{:?}", events1);
+        assert_eq!(events2.len(), 2, "{:?}", events2);
     }
 
-    /// Error returned from [Client::notify], [Client::remove] and
-    /// [Client::remove_all].
-    #[derive(Debug, Error)]
-    pub enum SendError {
-        #[error("Failed to send message: {0}")]
-        PipeBroken(#[from] io::Error),
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn receiver_stops_when_server_closed() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-receiver_stops_when_server_closed";
+
+        let server = MockServer::new(PIPE_NAME);
+
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+
+        let stream = client.receive_events();
+
+        sleep(Duration::from_millis(50)).await;
+
+        drop(server);
+
+        let events: Vec<_> = stream.collect().await;
+
+        println!("{:?}", events);
+
+        assert!(
+            matches!(events[..], [Err(error::ReceiveError(ref e))] if e.kind() == io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn general_test_1() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-general_test_1";
+
+        let mut server = MockServer::new(PIPE_NAME);
+
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+
+        // Get receiver stream
+
+        let stream = client.receive_events();
+
+        // Check request_state
+
+        server.check_next(|cmd| {
+            assert!(matches!(cmd, ServerCommand::Request(RequestCommand::State)));
+        });
+
+        let (state, _) = tokio::join!(client.request_state(), server.pump());
+
+        let state = state.expect("Failed to request state");
+        assert!(state.is_empty());
+
+        // Check request_max_monitors
+
+        let (max, _) = tokio::join!(client.request_max_monitors(), server.pump());
+
+        assert_eq!(
+            max.expect("Failed to request max monitors"),
+            MOCK_MAX_MONITORS
+        );
+
+        // Check validate
+
+        let invalid = [Monitor {
+            id: 0,
+            modes: vec![Mode {
+                width: 0,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        }];
+
+        let (result, _) = tokio::join!(client.validate(&invalid), server.pump());
+
+        assert_eq!(
+            result.expect("Failed to validate"),
+            Err(vec![MonitorError::InvalidDimensions(0, 0, 1080)])
+        );
+        assert!(server.state().is_empty());
+
+        // Check notify
+
+        let mons1 = [Monitor {
+            id: 0,
+            name: Some("test".to_string()),
+            modes: vec![Mode {
+                width: 1920,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        }];
+
+        let fut = client.notify(&mons1);
+
+        tokio::join!(fut, server.pump())
+            .0
+            .expect("Failed to notify");
+
+        assert_eq!(server.state(), &mons1);
+
+        // Check request_state
+
+        let (state, _) = tokio::join!(client.request_state(), server.pump());
+
+        let state = state.expect("Failed to request state");
+        assert_eq!(&state, &mons1);
+
+        // Check notify multiple
+
+        let mons2 = [
+            Monitor {
+                id: 0,
+                enabled: false,
+                name: Some("test1".to_string()),
+                modes: vec![Mode {
+                    width: 100,
+                    height: 200,
+                    refresh_rates: vec![80, 90],
+                    timing: None,
+                }],
+                ..Default::default()
+            },
+            Monitor {
+                id: 1,
+                name: Some("test2".to_string()),
+                modes: vec![Mode {
+                    width: 300,
+                    height: 400,
+                    refresh_rates: vec![50],
+                    timing: None,
+                }],
+                ..Default::default()
+            },
+        ];
+
+        tokio::join!(client.notify(&mons2), server.pump())
+            .0
+            .expect("Failed to notify");
+
+        assert_eq!(server.state(), &mons2);
+
+        // Give some time for the server to send the last event
+        sleep(Duration::from_millis(50)).await;
+
+        let stream2 = client.receive_events();
+
+        // Check remove
+
+        tokio::join!(client.remove(&[0]), server.pump())
+            .0
+            .expect("Failed to remove");
+
+        assert_eq!(server.state().len(), 1);
+        assert_eq!(server.state()[0].id, 1);
+
+        // Check remove all
+
+        tokio::join!(client.remove_all(), server.pump())
+            .0
+            .expect("Failed to remove all");
+
+        assert!(server.state().is_empty());
+
+        // Check streams
+
+        // Give some time for the server to send the last event
+        sleep(Duration::from_millis(50)).await;
+
+        drop(client);
+
+        let events: Vec<_> = stream.collect().await;
+
+        assert!(matches!(events[..], [
+                Ok(EventCommand::Changed(ref e1)),
+                Ok(EventCommand::Changed(ref e2)),
+                Ok(EventCommand::Changed(ref e3)),
+                Ok(EventCommand::Changed(ref e4)),
+            ] if *e1 == mons1
+                && *e2 == mons2
+                && *e3 == mons2[1..]
+                && e4.is_empty()
+        ));
+
+        let events: Vec<_> = stream2.collect().await;
+
+        assert!(matches!(events[..], [
+                Ok(EventCommand::Changed(ref e1)),
+                Ok(EventCommand::Changed(ref e2)),
+            ] if  *e1 == mons2[1..]
+                && e2.is_empty()
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn persistence_flag() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-persistence_flag";
+
+        let mut server = MockServer::new(PIPE_NAME);
+
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+
+        let mons = [Monitor {
+            id: 0,
+            modes: vec![Mode {
+                width: 1920,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        }];
+
+        // Plain commands are temporary
+
+        tokio::join!(client.notify(&mons), server.pump())
+            .0
+            .expect("Failed to notify");
+
+        assert_eq!(server.state(), &mons);
+        assert_eq!(server.persisted(), None);
+
+        // Persist current state on request
+
+        tokio::join!(client.persist_now(), server.pump())
+            .0
+            .expect("Failed to persist");
+
+        assert_eq!(server.persisted(), Some(&mons[..]));
+
+        // Persist together with a change
+
+        tokio::join!(client.remove_all_with(true), server.pump())
+            .0
+            .expect("Failed to remove all");
+
+        assert!(server.state().is_empty());
+        assert_eq!(server.persisted(), Some(&[][..]));
+
+        // Temporary change keeps the saved state
+
+        tokio::join!(client.notify_with(&mons, false), server.pump())
+            .0
+            .expect("Failed to notify");
+
+        assert_eq!(server.state(), &mons);
+        assert_eq!(server.persisted(), Some(&[][..]));
+
+        // Reset drops the saved state as well
+
+        tokio::join!(client.reset(), server.pump())
+            .0
+            .expect("Failed to reset");
+
+        assert!(server.state().is_empty());
+        assert_eq!(server.persisted(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn request_times_out() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-request_times_out";
+
+        let _server = MockServer::new(PIPE_NAME);
+
+        let mut client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+
+        assert_eq!(client.request_timeout(), DEFAULT_REQUEST_TIMEOUT);
+
+        let timeout = Duration::from_millis(100);
+        client.set_request_timeout(timeout);
+
+        // the server is never pumped, so no reply arrives
+        let result = client.request_state().await;
+        assert!(matches!(result, Err(error::RequestError::Timeout(t)) if t == timeout));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn connect_times_out_when_busy() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-connect_times_out_when_busy";
+
+        let _server = MockServer::new(PIPE_NAME);
+
+        let _client = Client::connect_to_timeout(PIPE_NAME, Duration::from_secs(1))
+            .await
+            .expect("Failed to connect to pipe");
+
+        // the mock only has a single pipe instance, which is taken now
+        let timeout = Duration::from_millis(200);
+        let result = Client::connect_to_timeout(PIPE_NAME, timeout).await;
+        assert!(matches!(result, Err(error::ConnectionError::Timeout(t)) if t == timeout));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_get_their_reply() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-concurrent_requests_get_their_reply";
+        const REQUESTS: u32 = 50;
+
+        let mut server = MockServer::new(PIPE_NAME);
+
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+
+        // every request gets a reply naming its own monitor, so a mixed up reply is detected
+        let requests = (0..REQUESTS)
+            .map(|id| {
+                let client = client.clone();
+                task::spawn(async move {
+                    let invalid = [Monitor {
+                        id,
+                        modes: vec![Mode {
+                            width: 0,
+                            height: 1080,
+                            refresh_rates: vec![60],
+                            timing: None,
+                        }],
+                        ..Default::default()
+                    }];
+
+                    (id, client.validate(&invalid).await)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..REQUESTS {
+            server.pump().await;
+        }
+
+        for request in requests {
+            let (id, result) = request.await.unwrap();
+            assert_eq!(
+                result.expect("Failed to validate"),
+                Err(vec![MonitorError::InvalidDimensions(id, 0, 1080)])
+            );
+        }
     }
 
-    /// Error returned from [Client::request_state].
-    #[derive(Debug, Error)]
-    pub enum RequestError {
-        #[error("Failed to send message (pipe broken): {0}")]
-        Send(io::Error),
-        #[error("Failed to receive message (pipe broken): {0}")]
-        Receive(Arc<io::Error>),
-        #[error("Did not get a response in time ({0:?})")]
-        Timeout(Duration),
-    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_state_requests_get_their_reply() {
+        const PIPE_NAME: &str =
+            "virtualdisplaydriver-test-concurrent_state_requests_get_their_reply";
+        const REQUESTS: usize = 50;
 
-    /// Error returned from [Client::receive_events].
-    #[derive(Debug, Error, Clone)]
-    #[error("Failed to receive event: {0}")]
-    pub struct ReceiveError(#[from] pub Arc<io::Error>);
+        let mut server = MockServer::new(PIPE_NAME);
 
-    /// Error returned from [Client::persist].
-    #[derive(Debug, Error)]
-    pub enum PersistError {
-        #[error("Failed to open registry key: {0}")]
-        Open(io::Error),
-        #[error("Failed to set registry value: {0}")]
-        Set(io::Error),
-        #[error("Failed to serialize monitors: {0}")]
-        Serialize(#[from] serde_json::Error),
-    }
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-    impl From<SendCommandError> for SendError {
-        fn from(e: SendCommandError) -> Self {
-            match e {
-                SendCommandError::PipeBroken(e) => Self::PipeBroken(e),
-                SendCommandError::Encode(e) => unreachable!("{:?}", e),
-            }
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        let command = DriverCommand::Notify(vec![monitor.clone()]);
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        result.expect("Failed to execute");
+
+        // the replies are all alike, a reply routed to the wrong caller leaves another one
+        // waiting until its timeout
+        let requests = (0..REQUESTS)
+            .map(|_| {
+                let client = client.clone();
+                task::spawn(async move { client.request_state().await })
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..REQUESTS {
+            server.pump().await;
         }
-    }
 
-    impl From<SendCommandError> for RequestError {
-        fn from(e: SendCommandError) -> Self {
-            match e {
-                SendCommandError::PipeBroken(e) => Self::Send(e),
-                SendCommandError::Encode(e) => unreachable!("{:?}", e),
-            }
+        for request in requests {
+            let state = request.await.unwrap().expect("Failed to request state");
+            assert_eq!(state, [monitor.clone()]);
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use tokio::time::sleep;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn execute_returns_driver_errors() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-execute_returns_driver_errors";
 
-    use super::*;
-    use crate::mock::*;
+        let mut server = MockServer::new(PIPE_NAME);
+
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
+
+        let monitors = vec![Monitor {
+            id: 0,
+            modes: vec![Mode {
+                width: 1920,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        }];
+
+        let command = DriverCommand::Notify(monitors.clone());
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        result.expect("Failed to execute");
+        assert_eq!(server.state(), monitors);
+
+        let command = DriverCommand::Remove(vec![5]);
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        let Err(error::RequestError::Ipc(e)) = result else {
+            panic!("Expected an error reply, got {result:?}");
+        };
+        assert_eq!(e.code, ErrorCode::MonitorNotFound);
+        assert!(e.source_id.is_some());
+        assert_eq!(server.state(), monitors);
+    }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn receiver_stops_when_client_closed() {
-        const PIPE_NAME: &str = "virtualdisplaydriver-test-receiver_stops_when_client_closed";
+    async fn set_monitors_replaces_state() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-set_monitors_replaces_state";
 
         let mut server = MockServer::new(PIPE_NAME);
 
-        let client1 = Client::connect_to(PIPE_NAME)
+        let client = Client::connect_to(PIPE_NAME)
             .await
             .expect("Failed to connect to pipe");
-        let stream1 = client1.receive_events();
 
-        let client2 = client1.clone();
-        let stream2 = client2.receive_events();
+        let monitor = |id| Monitor {
+            id,
+            modes: vec![Mode {
+                width: 1920,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        };
 
-        client1.notify(&[]).await.expect("Failed to notify");
-        server.pump().await;
+        let monitors = [monitor(0), monitor(1)];
+        let (result, ()) = tokio::join!(client.set_monitors(&monitors), server.pump());
+        let report = result.expect("Failed to apply");
+        assert_eq!(report.succeeded, vec![0, 1]);
+        assert!(report.is_success());
+
+        // monitor 0 is removed, and reported like the others
+        let monitors = [monitor(1), monitor(2)];
+        let (result, ()) = tokio::join!(client.set_monitors(&monitors), server.pump());
+        let report = result.expect("Failed to apply");
+        assert_eq!(report.succeeded, vec![1, 2, 0]);
+        assert_eq!(server.state(), vec![monitor(1), monitor(2)]);
+
+        // an invalid list is rejected as a whole
+        let monitors = [monitor(3), monitor(3)];
+        let (result, ()) = tokio::join!(client.set_monitors(&monitors), server.pump());
+        let Err(error::RequestError::Ipc(e)) = result else {
+            panic!("Expected an error reply, got {result:?}");
+        };
+        assert_eq!(e.code, ErrorCode::InvalidMonitors);
+        assert_eq!(server.state(), vec![monitor(1), monitor(2)]);
+    }
 
-        sleep(Duration::from_millis(50)).await;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn set_enabled_keeps_monitor() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-set_enabled_keeps_monitor";
 
-        drop(client1);
+        let mut server = MockServer::new(PIPE_NAME);
 
-        client2.notify(&[]).await.expect("Failed to notify");
-        server.pump().await;
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        sleep(Duration::from_millis(50)).await;
+        let monitor = Monitor {
+            id: 4,
+            name: Some("desk".to_owned()),
+            modes: vec![Mode {
+                width: 2560,
+                height: 1440,
+                refresh_rates: vec![144],
+                timing: None,
+            }],
+            ..Default::default()
+        };
 
-        drop(client2);
+        let command = DriverCommand::Notify(vec![monitor.clone()]);
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        result.expect("Failed to execute");
 
-        let events1: Vec<_> = stream1.collect().await;
-        let events2: Vec<_> = stream2.collect().await;
+        let (result, ()) = tokio::join!(client.set_enabled(4, false), server.pump());
+        result.expect("Failed to disable");
+        let disabled = Monitor {
+            enabled: false,
+            ..monitor.clone()
+        };
+        assert_eq!(server.state(), vec![disabled.clone()]);
 
-        assert_eq!(events1.len(), 2, "{:?}", events1);
-        assert_eq!(events2.len(), 2, "{:?}", events2);
+        // already disabled, nothing happens
+        let (result, ()) = tokio::join!(client.set_enabled(4, false), server.pump());
+        result.expect("Failed to disable again");
+        assert_eq!(server.state(), vec![disabled]);
+
+        let (result, ()) = tokio::join!(client.set_enabled(4, true), server.pump());
+        result.expect("Failed to enable");
+        assert_eq!(server.state(), vec![monitor.clone()]);
+
+        let (result, ()) = tokio::join!(client.set_enabled(9, true), server.pump());
+        let Err(error::RequestError::Ipc(e)) = result else {
+            panic!("Expected an error reply, got {result:?}");
+        };
+        assert_eq!(e.code, ErrorCode::MonitorNotFound);
+        assert_eq!(server.state(), vec![monitor]);
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn receiver_stops_when_server_closed() {
-        const PIPE_NAME: &str = "virtualdisplaydriver-test-receiver_stops_when_server_closed";
+    async fn handshake_reports_driver_version() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-handshake_reports_driver_version";
 
-        let server = MockServer::new(PIPE_NAME);
+        let mut server = MockServer::new(PIPE_NAME);
 
         let client = Client::connect_to(PIPE_NAME)
             .await
             .expect("Failed to connect to pipe");
 
-        let stream = client.receive_events();
+        assert_eq!(client.driver_version(), Some(MOCK_DRIVER_VERSION));
+        assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
 
-        sleep(Duration::from_millis(50)).await;
+        let (info, ()) = tokio::join!(client.version(), server.pump());
+        let info = info.expect("Failed to request driver info");
+        assert_eq!(info.version, MOCK_DRIVER_VERSION);
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+    }
 
-        drop(server);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn legacy_driver_without_handshake() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-legacy_driver_without_handshake";
 
-        let events: Vec<_> = stream.collect().await;
+        let mut server = MockServer::new_legacy(PIPE_NAME);
 
-        println!("{:?}", events);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        assert!(
-            matches!(events[..], [Err(error::ReceiveError(ref e))] if e.kind() == io::ErrorKind::BrokenPipe)
-        );
+        assert_eq!(client.driver_version(), None);
+        assert_eq!(client.protocol_version(), LEGACY_PROTOCOL_VERSION);
+
+        // everything but the new requests keeps working
+        let (max, ()) = tokio::join!(client.request_max_monitors(), server.pump());
+        assert_eq!(max.expect("Failed to request"), MOCK_MAX_MONITORS);
+
+        let result = client.execute(&DriverCommand::RemoveAll).await;
+        assert!(matches!(
+            result,
+            Err(error::RequestError::Unsupported(LEGACY_PROTOCOL_VERSION))
+        ));
+
+        let result = client.version().await;
+        assert!(matches!(
+            result,
+            Err(error::RequestError::Unsupported(LEGACY_PROTOCOL_VERSION))
+        ));
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn general_test_1() {
-        const PIPE_NAME: &str = "virtualdisplaydriver-test-general_test_1";
+    async fn request_capabilities() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-request_capabilities";
 
         let mut server = MockServer::new(PIPE_NAME);
-
         let client = Client::connect_to(PIPE_NAME)
             .await
             .expect("Failed to connect to pipe");
 
-        // Get receiver stream
-
-        let stream = client.receive_events();
+        let (caps, ()) = tokio::join!(client.request_capabilities(), server.pump());
+        let caps = caps.expect("Failed to request");
+        assert_eq!(caps, Capabilities::with_max_monitors(MOCK_MAX_MONITORS));
 
-        // Check request_state
+        // legacy drivers only know their monitor limit
+        const LEGACY_PIPE_NAME: &str = "virtualdisplaydriver-test-request_capabilities-legacy";
 
-        server.check_next(|cmd| {
-            assert!(matches!(cmd, ServerCommand::Request(RequestCommand::State)));
-        });
+        let mut server = MockServer::new_legacy(LEGACY_PIPE_NAME);
+        let client = Client::connect_to(LEGACY_PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        let (state, _) = tokio::join!(client.request_state(), server.pump());
+        let (caps, ()) = tokio::join!(client.request_capabilities(), server.pump());
+        let caps = caps.expect("Failed to request");
+        assert_eq!(caps.max_monitors, MOCK_MAX_MONITORS);
+        assert_eq!(caps.max_width, MAX_DIMEN);
+    }
 
-        let state = state.expect("Failed to request state");
-        assert!(state.is_empty());
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn state_reports_runtime_state() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-state_reports_runtime_state";
 
-        // Check notify
+        let mut server = MockServer::new(PIPE_NAME);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        let mons1 = [Monitor {
-            id: 0,
-            enabled: true,
-            name: Some("test".to_string()),
+        let monitor = Monitor {
+            id: 4,
             modes: vec![Mode {
                 width: 1920,
                 height: 1080,
-                refresh_rates: vec![60],
+                refresh_rates: vec![60, 120],
+                timing: None,
             }],
-        }];
+            orientation: Some(Orientation::Portrait),
+            ..Default::default()
+        };
 
-        let fut = client.notify(&mons1);
+        let command = DriverCommand::Notify(vec![monitor.clone()]);
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        result.expect("Failed to execute");
 
-        tokio::join!(fut, server.pump())
-            .0
-            .expect("Failed to notify");
+        // the state, and the monitors to find them in the display config
+        let (states, ()) = tokio::join!(client.state(), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let states = states.expect("Failed to request");
+        assert_eq!(states, [MonitorState::new(&monitor)]);
+        assert_eq!(states[0].modes, monitor.modes);
+        assert_eq!(states[0].active_mode, None);
+        assert_eq!(states[0].orientation, Some(Orientation::Portrait));
+        // not attached to this session's desktop
+        assert_eq!(states[0].active_orientation, None);
+        assert_eq!(states[0].active_sdr_white_level_nits, None);
+    }
 
-        assert_eq!(server.state(), &mons1);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn stats_without_swap_chain() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-stats_without_swap_chain";
 
-        // Check request_state
+        let mut server = MockServer::new(PIPE_NAME);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        let (state, _) = tokio::join!(client.request_state(), server.pump());
+        let monitor = MonitorBuilder::new(4)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        let command = DriverCommand::Notify(vec![monitor]);
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        result.expect("Failed to execute");
 
-        let state = state.expect("Failed to request state");
-        assert_eq!(&state, &mons1);
+        let (stats, ()) = tokio::join!(client.stats(4), server.pump());
+        assert_eq!(stats.expect("Failed to request"), None);
 
-        // Check notify multiple
+        let (result, ()) = tokio::join!(client.stats(9), server.pump());
+        let Err(error::RequestError::Ipc(e)) = result else {
+            panic!("Expected an error reply, got {result:?}");
+        };
+        assert_eq!(e.code, ErrorCode::MonitorNotFound);
+    }
 
-        let mons2 = [
-            Monitor {
-                id: 0,
-                enabled: false,
-                name: Some("test1".to_string()),
-                modes: vec![Mode {
-                    width: 100,
-                    height: 200,
-                    refresh_rates: vec![80, 90],
-                }],
-            },
-            Monitor {
-                id: 1,
-                enabled: true,
-                name: Some("test2".to_string()),
-                modes: vec![Mode {
-                    width: 300,
-                    height: 400,
-                    refresh_rates: vec![50],
-                }],
-            },
-        ];
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn frame_export_names_the_export() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-frame_export_names_the_export";
 
-        tokio::join!(client.notify(&mons2), server.pump())
-            .0
-            .expect("Failed to notify");
+        let mut server = MockServer::new(PIPE_NAME);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        assert_eq!(server.state(), &mons2);
+        let monitor = MonitorBuilder::new(2)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        let command = DriverCommand::Notify(vec![monitor]);
+        let (result, ()) = tokio::join!(client.execute(&command), server.pump());
+        result.expect("Failed to execute");
+
+        let (name, ()) = tokio::join!(client.set_frame_export(2, true), server.pump());
+        let name = name
+            .expect("Failed to enable")
+            .expect("Enabled export has no name");
+        assert!(name.ends_with("-2"));
+
+        let (name, ()) = tokio::join!(client.set_frame_export(2, false), server.pump());
+        assert_eq!(name.expect("Failed to disable"), None);
+
+        let (result, ()) = tokio::join!(client.set_frame_export(9, true), server.pump());
+        let Err(error::RequestError::Ipc(e)) = result else {
+            panic!("Expected an error reply, got {result:?}");
+        };
+        assert_eq!(e.code, ErrorCode::MonitorNotFound);
+    }
 
-        // Give some time for the server to send the last event
-        sleep(Duration::from_millis(50)).await;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn subscribe_pushes_monitor_events() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-subscribe_pushes_monitor_events";
 
-        let stream2 = client.receive_events();
+        let mut server = MockServer::new(PIPE_NAME);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        // Check remove
+        let mut events = Box::pin(client.receive_events());
 
-        tokio::join!(client.remove(&[0]), server.pump())
-            .0
-            .expect("Failed to remove");
+        let (result, ()) = tokio::join!(client.subscribe(), server.pump());
+        result.expect("Failed to subscribe");
+        assert!(server.last_request_id().is_some());
 
-        assert_eq!(server.state().len(), 1);
-        assert_eq!(server.state()[0].id, 1);
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        client.notify(&[monitor.clone()]).await.unwrap();
+        server.pump().await;
 
-        // Check remove all
+        // the state change every connection gets, then the one for subscribers
+        let event = events.next().await.unwrap().unwrap();
+        assert!(matches!(event, EventCommand::Changed(_)), "{event:?}");
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(
+            MonitorEvent::from_event(event),
+            Some(MonitorEvent::Added(monitor))
+        );
+    }
 
-        tokio::join!(client.remove_all(), server.pump())
-            .0
-            .expect("Failed to remove all");
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn driver_queries_are_tagged() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-driver_queries_are_tagged";
 
-        assert!(server.state().is_empty());
+        let mut server = MockServer::new(PIPE_NAME);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        // Check streams
+        let mut ids = Vec::new();
 
-        // Give some time for the server to send the last event
-        sleep(Duration::from_millis(50)).await;
+        let (info, ()) = tokio::join!(client.version(), server.pump());
+        assert_eq!(
+            info.expect("Failed to request driver info").version,
+            MOCK_DRIVER_VERSION
+        );
+        ids.push(server.last_request_id());
 
-        drop(client);
+        let (status, ()) = tokio::join!(client.driver_status(), server.pump());
+        assert_eq!(
+            status.expect("Failed to request driver status"),
+            DriverStatus::Ready
+        );
+        ids.push(server.last_request_id());
+
+        let (result, ()) = tokio::join!(client.subscribe(), server.pump());
+        result.expect("Failed to subscribe");
+        ids.push(server.last_request_id());
+
+        let (clients, ()) = tokio::join!(client.list_clients(), server.pump());
+        let clients = clients.expect("Failed to list clients");
+        assert_eq!(clients.len(), 1);
+        assert!(clients[0].current);
+        assert!(clients[0].subscribed);
+        ids.push(server.last_request_id());
+
+        let (defaults, ()) = tokio::join!(client.boot_defaults(), server.pump());
+        assert!(defaults
+            .expect("Failed to request boot defaults")
+            .is_empty());
+        ids.push(server.last_request_id());
+
+        // every request got an id of its own
+        assert!(ids.iter().all(Option::is_some), "{ids:?}");
+        let mut unique = ids.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len(), "{ids:?}");
+    }
 
-        let events: Vec<_> = stream.collect().await;
+    #[tokio::test]
+    async fn boot_defaults_are_only_stored() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-boot_defaults_are_only_stored";
 
-        assert!(matches!(events[..], [
-                Ok(EventCommand::Changed(ref e1)),
-                Ok(EventCommand::Changed(ref e2)),
-                Ok(EventCommand::Changed(ref e3)),
-                Ok(EventCommand::Changed(ref e4)),
-            ] if *e1 == mons1
-                && *e2 == mons2
-                && *e3 == mons2[1..]
-                && e4.is_empty()
-        ));
+        let mut server = MockServer::new(PIPE_NAME);
+        let client = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect to pipe");
 
-        let events: Vec<_> = stream2.collect().await;
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        let defaults = [monitor.clone()];
+        let (result, ()) = tokio::join!(client.set_boot_defaults(&defaults), server.pump());
+        result.expect("Failed to set boot defaults");
+        assert!(server.state().is_empty());
 
-        assert!(matches!(events[..], [
-                Ok(EventCommand::Changed(ref e1)),
-                Ok(EventCommand::Changed(ref e2)),
-            ] if  *e1 == mons2[1..]
-                && e2.is_empty()
-        ));
+        let (defaults, ()) = tokio::join!(client.boot_defaults(), server.pump());
+        assert_eq!(
+            defaults.expect("Failed to request boot defaults"),
+            [monitor.clone()]
+        );
+
+        let defaults = [monitor.clone(), monitor];
+        let (result, ()) = tokio::join!(client.set_boot_defaults(&defaults), server.pump());
+        let Err(error::RequestError::Ipc(e)) = result else {
+            panic!("Expected an error reply, got {result:?}");
+        };
+        assert_eq!(e.code, ErrorCode::InvalidMonitors);
+
+        let (result, ()) = tokio::join!(client.set_boot_defaults(&[]), server.pump());
+        result.expect("Failed to remove boot defaults");
+        assert!(server.boot_defaults().is_empty());
     }
 }
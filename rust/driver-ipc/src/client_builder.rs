@@ -0,0 +1,255 @@
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use crate::{error, Client, DEFAULT_PIPE_NAME, DEFAULT_REQUEST_TIMEOUT};
+
+/// Connects a [Client] with other settings than [Client::connect].
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use driver_ipc::ClientBuilder;
+/// # async fn run() -> Result<(), driver_ipc::error::ConnectionError> {
+/// let client = ClientBuilder::new()
+///     .pipe_name("virtualdisplaydriver-dev")
+///     .connect_timeout(Duration::from_secs(2))
+///     .retry(5, Duration::from_millis(200))
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Without any settings it connects exactly like [Client::connect].
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    pipe_name: String,
+    connect_timeout: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    read_timeout: Duration,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            pipe_name: DEFAULT_PIPE_NAME.to_owned(),
+            connect_timeout: None,
+            retries: 0,
+            backoff: Duration::ZERO,
+            read_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Name of the pipe, [DEFAULT_PIPE_NAME] unless set.
+    ///
+    /// `name` is ONLY the {name} portion of \\.\pipe\{name}.
+    #[must_use]
+    pub fn pipe_name(mut self, name: &str) -> Self {
+        name.clone_into(&mut self.pipe_name);
+        self
+    }
+
+    /// Wait up to `timeout` for the driver while it is busy serving another
+    /// connection, see [Client::connect_to_timeout].
+    ///
+    /// Unless set, connecting fails right away if the driver is busy.
+    #[must_use]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Try to connect up to `count` more times if connecting fails, e.g.
+    /// while the driver is starting. Waits `backoff` before the first retry,
+    /// and twice as long before every further one.
+    ///
+    /// Errors which another attempt can't fix are returned right away, see
+    /// [ConnectionError::is_permanent](error::ConnectionError::is_permanent).
+    #[must_use]
+    pub fn retry(mut self, count: u32, backoff: Duration) -> Self {
+        self.retries = count;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Time requests wait for the driver to reply, see
+    /// [Client::set_request_timeout]. [DEFAULT_REQUEST_TIMEOUT] unless set.
+    #[must_use]
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Connect to the driver.
+    ///
+    /// Fails with [ConnectionError::NotRunning](error::ConnectionError::NotRunning)
+    /// if the pipe doesn't exist,
+    /// [ConnectionError::AccessDenied](error::ConnectionError::AccessDenied)
+    /// if this process may not open it, and
+    /// [ConnectionError::Timeout](error::ConnectionError::Timeout) if the
+    /// driver stayed busy for the [connect timeout](ClientBuilder::connect_timeout).
+    /// With retries, the error of the last attempt is returned.
+    ///
+    /// This method is async because it requires a running tokio reactor.
+    pub async fn connect(&self) -> Result<Client, error::ConnectionError> {
+        let mut retries = self.retries;
+        let mut backoff = self.backoff;
+
+        loop {
+            let result = match self.connect_timeout {
+                Some(timeout) => Client::connect_to_timeout(&self.pipe_name, timeout).await,
+                None => Client::connect_to(&self.pipe_name).await,
+            };
+
+            match result {
+                Ok(mut client) => {
+                    client.set_request_timeout(self.read_timeout);
+                    return Ok(client);
+                }
+                Err(e) if retries > 0 && !e.is_permanent() => {
+                    retries -= 1;
+                    sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, ptr};
+
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+    use windows::{
+        core::w,
+        Win32::Security::{
+            Authorization::{
+                ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+            },
+            PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+        },
+    };
+
+    use super::*;
+    use crate::mock::MockServer;
+
+    // a pipe nobody may open, not even its creator
+    fn locked_pipe(name: &str) -> io::Result<NamedPipeServer> {
+        let mut sd = PSECURITY_DESCRIPTOR::default();
+        unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                w!("D:P"),
+                SDDL_REVISION_1,
+                &mut sd,
+                None,
+            )
+        }?;
+
+        let mut sa = SECURITY_ATTRIBUTES {
+            #[allow(clippy::cast_possible_truncation)]
+            nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: sd.0,
+            bInheritHandle: false.into(),
+        };
+
+        unsafe {
+            ServerOptions::new().create_with_security_attributes_raw(
+                format!(r"\\.\pipe\{name}"),
+                ptr::from_mut(&mut sa).cast(),
+            )
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn defaults_match_connect() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-builder_defaults_match_connect";
+
+        let _server = MockServer::new(PIPE_NAME);
+
+        let builder = ClientBuilder::new().pipe_name(PIPE_NAME);
+        assert_eq!(builder.connect_timeout, None);
+        assert_eq!(builder.retries, 0);
+
+        let client = builder.connect().await.expect("Failed to connect");
+        assert_eq!(client.request_timeout(), DEFAULT_REQUEST_TIMEOUT);
+        assert_eq!(ClientBuilder::new().pipe_name, DEFAULT_PIPE_NAME);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn missing_pipe_is_not_running() {
+        let result = ClientBuilder::new()
+            .pipe_name("virtualdisplaydriver-test-builder_missing_pipe")
+            .connect()
+            .await;
+
+        assert!(matches!(result, Err(error::ConnectionError::NotRunning)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn locked_pipe_is_access_denied() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-builder_locked_pipe";
+
+        let _server = locked_pipe(PIPE_NAME).expect("Failed to create pipe");
+
+        // retrying can't help, so it is not tried
+        let result = ClientBuilder::new()
+            .pipe_name(PIPE_NAME)
+            .retry(3, Duration::from_secs(10))
+            .connect()
+            .await;
+
+        assert!(matches!(result, Err(error::ConnectionError::AccessDenied)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn busy_pipe_times_out() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-builder_busy_pipe";
+
+        let _server = MockServer::new(PIPE_NAME);
+        let _first = Client::connect_to(PIPE_NAME)
+            .await
+            .expect("Failed to connect");
+
+        // the mock only has a single pipe instance, which is taken now
+        let timeout = Duration::from_millis(100);
+        let result = ClientBuilder::new()
+            .pipe_name(PIPE_NAME)
+            .connect_timeout(timeout)
+            .retry(1, Duration::from_millis(10))
+            .connect()
+            .await;
+
+        assert!(matches!(result, Err(error::ConnectionError::Timeout(t)) if t == timeout));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn retries_until_driver_starts() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-builder_retries_until_driver_starts";
+
+        let server = tokio::spawn(async {
+            sleep(Duration::from_millis(200)).await;
+            MockServer::new(PIPE_NAME)
+        });
+
+        let timeout = Duration::from_millis(500);
+        let client = ClientBuilder::new()
+            .pipe_name(PIPE_NAME)
+            .retry(10, Duration::from_millis(50))
+            .read_timeout(timeout)
+            .connect()
+            .await
+            .expect("Failed to connect");
+
+        assert_eq!(client.request_timeout(), timeout);
+        drop(server.await);
+    }
+}
@@ -0,0 +1,304 @@
+//! Monitor configuration files, to move a setup to another machine.
+//!
+//! A file holds `{"version": 1, "monitors": [...]}` as JSON or TOML, with the
+//! monitors as in the protocol, except that custom EDIDs are base64 strings
+//! instead of byte arrays. Fields this version doesn't know are skipped and
+//! reported, so files written by newer versions still load.
+
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::{Id, Monitor};
+
+/// Version of the file format written by [ConfigFile::to_string].
+pub const FILE_VERSION: u32 = 1;
+
+/// Field of a monitor holding its custom EDID.
+const EDID_FIELD: &str = "edid";
+
+/// Text format of a configuration file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// Format by the extension of `path`, `None` if it is neither `.json` nor
+    /// `.toml`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?;
+
+        if extension.eq_ignore_ascii_case("json") {
+            Some(Self::Json)
+        } else if extension.eq_ignore_ascii_case("toml") {
+            Some(Self::Toml)
+        } else {
+            None
+        }
+    }
+}
+
+/// Contents of a configuration file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigFile {
+    /// Format version the file was written with, see [FILE_VERSION].
+    pub version: u32,
+    pub monitors: Vec<Monitor>,
+}
+
+/// A configuration file read by [ConfigFile::parse].
+#[derive(Debug, Clone)]
+pub struct Parsed {
+    pub config: ConfigFile,
+    /// Paths of the fields which were skipped because this version doesn't
+    /// know them, like `monitors.0.hdr`.
+    pub unknown_fields: Vec<String>,
+}
+
+impl ConfigFile {
+    pub fn new(monitors: Vec<Monitor>) -> Self {
+        Self {
+            version: FILE_VERSION,
+            monitors,
+        }
+    }
+
+    /// Write the file contents in `format`.
+    pub fn to_string(&self, format: Format) -> Result<String, ConfigFileError> {
+        let mut value = serde_json::to_value(self)?;
+        for monitor in monitors_mut(&mut value) {
+            encode_edid(monitor);
+        }
+
+        match format {
+            Format::Json => Ok(serde_json::to_string_pretty(&value)?),
+            Format::Toml => {
+                // TOML has no null, a missing field is read back as `None`
+                remove_nulls(&mut value);
+                Ok(toml::to_string_pretty(&value)?)
+            }
+        }
+    }
+
+    /// Read file contents in `format`.
+    ///
+    /// The monitors are not validated, see
+    /// [validate_monitors](crate::validate_monitors).
+    pub fn parse(data: &str, format: Format) -> Result<Parsed, ConfigFileError> {
+        let mut value: Value = match format {
+            Format::Json => serde_json::from_str(data)?,
+            Format::Toml => toml::from_str(data)?,
+        };
+
+        for (index, monitor) in monitors_mut(&mut value).enumerate() {
+            decode_edid(monitor).map_err(|source| ConfigFileError::Edid { index, source })?;
+        }
+
+        let mut unknown_fields = Vec::new();
+        let config = serde_ignored::deserialize(value, |path| {
+            unknown_fields.push(path.to_string());
+        })?;
+
+        Ok(Parsed {
+            config,
+            unknown_fields,
+        })
+    }
+
+    /// IDs of the monitors in the file.
+    pub fn ids(&self) -> impl Iterator<Item = Id> + '_ {
+        self.monitors.iter().map(|monitor| monitor.id)
+    }
+}
+
+/// Error returned from [ConfigFile::to_string] and [ConfigFile::parse].
+#[derive(Debug, Error)]
+pub enum ConfigFileError {
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid TOML: {0}")]
+    TomlRead(#[from] toml::de::Error),
+    #[error("Failed to write TOML: {0}")]
+    TomlWrite(#[from] toml::ser::Error),
+    #[error("Invalid EDID of monitor {index} in the file: {source}")]
+    Edid {
+        /// Position of the monitor in the file
+        index: usize,
+        source: base64::DecodeError,
+    },
+}
+
+// the monitor objects of a serialized `ConfigFile`, none if it has no monitor list
+fn monitors_mut(value: &mut Value) -> impl Iterator<Item = &mut Map<String, Value>> {
+    value
+        .get_mut("monitors")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+
+fn encode_edid(monitor: &mut Map<String, Value>) {
+    let Some(Value::Array(bytes)) = monitor.get(EDID_FIELD) else {
+        return;
+    };
+
+    // serialized from a `Vec<u8>`, so every item is a byte
+    let bytes = bytes
+        .iter()
+        .filter_map(Value::as_u64)
+        .filter_map(|byte| u8::try_from(byte).ok())
+        .collect::<Vec<_>>();
+
+    monitor.insert(EDID_FIELD.to_owned(), Value::String(STANDARD.encode(bytes)));
+}
+
+// a byte array is kept as it is, it fails or passes the deserialization on its own
+fn decode_edid(monitor: &mut Map<String, Value>) -> Result<(), base64::DecodeError> {
+    let Some(Value::String(encoded)) = monitor.get(EDID_FIELD) else {
+        return Ok(());
+    };
+
+    let bytes = STANDARD.decode(encoded.trim())?;
+    monitor.insert(EDID_FIELD.to_owned(), bytes.into());
+
+    Ok(())
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(remove_nulls),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ColorCalibration, CustomTiming, MonitorBuilder};
+
+    fn config() -> ConfigFile {
+        // CTA-861 1080p60
+        let timing = CustomTiming {
+            pixel_clock_khz: 148_500,
+            h_front_porch: 88,
+            h_sync_width: 44,
+            h_back_porch: 148,
+            v_front_porch: 4,
+            v_sync_width: 5,
+            v_back_porch: 36,
+            h_sync_positive: true,
+            v_sync_positive: true,
+        };
+
+        let mut edid = crate::EDID_HEADER.to_vec();
+        edid.extend(0..120);
+
+        ConfigFile::new(vec![
+            MonitorBuilder::new(0)
+                .name("Left")
+                .mode(1920, 1080, &[60, 120])
+                .position(-1920, 0)
+                .vrr_range(60, 120)
+                .build()
+                .unwrap(),
+            MonitorBuilder::new(1)
+                .name("Center")
+                .timed_mode(1920, 1080, timing)
+                .primary(true)
+                .color(ColorCalibration::SRGB)
+                .edid(edid)
+                .build()
+                .unwrap(),
+            MonitorBuilder::new(2)
+                .enabled(false)
+                .mode(2560, 1440, &[144])
+                .serial(7)
+                .tag("testing")
+                .build()
+                .unwrap(),
+        ])
+    }
+
+    #[test]
+    fn round_trips() {
+        let config = config();
+
+        for format in [Format::Json, Format::Toml] {
+            let data = config.to_string(format).unwrap();
+            let parsed = ConfigFile::parse(&data, format).unwrap();
+
+            assert_eq!(parsed.config, config, "{format:?}:\n{data}");
+            assert!(parsed.unknown_fields.is_empty());
+        }
+    }
+
+    #[test]
+    fn writes_edid_as_base64() {
+        let config = config();
+        let edid = config.monitors[1].edid.as_deref().unwrap();
+
+        let data = config.to_string(Format::Json).unwrap();
+        assert!(data.contains(&format!("\"{}\"", STANDARD.encode(edid))));
+
+        let data = config.to_string(Format::Toml).unwrap();
+        assert!(data.contains(&format!("edid = \"{}\"", STANDARD.encode(edid))));
+    }
+
+    #[test]
+    fn reports_unknown_fields() {
+        let data = r#"
+            version = 2
+            future = true
+
+            [[monitors]]
+            id = 4
+            enabled = true
+            hdr = { peak_nits = 1000 }
+
+            [[monitors.modes]]
+            width = 1920
+            height = 1080
+            refresh_rates = [60]
+        "#;
+
+        let parsed = ConfigFile::parse(data, Format::Toml).unwrap();
+
+        assert_eq!(parsed.config.version, 2);
+        assert_eq!(parsed.config.monitors[0].id, 4);
+        assert_eq!(parsed.unknown_fields, ["future", "monitors.0.hdr"]);
+    }
+
+    #[test]
+    fn rejects_invalid_edid() {
+        let data = r#"{"version": 1, "monitors": [{"id": 0, "enabled": true, "modes": [], "edid": "not base64!"}]}"#;
+
+        assert!(matches!(
+            ConfigFile::parse(data, Format::Json),
+            Err(ConfigFileError::Edid { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            Format::from_path(Path::new("setup.TOML")),
+            Some(Format::Toml)
+        );
+        assert_eq!(
+            Format::from_path(Path::new("setup.json")),
+            Some(Format::Json)
+        );
+        assert_eq!(Format::from_path(Path::new("setup")), None);
+    }
+}
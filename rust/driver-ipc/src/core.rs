@@ -1,16 +1,366 @@
-use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use serde::{
+    de::DeserializeOwned,
+    ser::{Error as _, SerializeMap},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use thiserror::Error;
 
 pub type Id = u32;
 pub type Dimen = u32;
 pub type RefreshRate = u32;
+/// Correlation id of a request, echoed back in its reply
+pub type RequestId = u64;
+
+/// Pipe protocol version spoken by peers from before the handshake.
+///
+/// Messages may carry a correlation id, see [Envelope].
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+/// Pipe protocol version spoken by this crate.
+///
+/// Adds the [RequestCommand::Hello] handshake, [RequestCommand::Capabilities],
+/// [RequestCommand::QueryState], [RequestCommand::QueryStats],
+/// [RequestCommand::DriverInfo], [RequestCommand::DriverStatus],
+/// [RequestCommand::ListClients], [RequestCommand::Monitor],
+/// [RequestCommand::BootDefaults], [RequestCommand::Logs],
+/// [RequestCommand::IpcStats],
+/// [DriverCommand::Apply], [DriverCommand::SetEnabled],
+/// [DriverCommand::SetPreferredAdapter], [DriverCommand::Reset],
+/// [DriverCommand::ReserveId], [DriverCommand::ReserveRequestedId],
+/// [DriverCommand::SetUserSession],
+/// [DriverCommand::SetFrameExport], [DriverCommand::SetBootDefaults],
+/// [DriverCommand::SetLogLevel], [DriverCommand::SetAdapterConfig],
+/// [DriverCommand::OverrideMode], [DriverCommand::RevertMode] and
+/// [DriverCommand::ReloadSettings], and answers tagged driver commands with
+/// [ReplyCommand::Done] or [ReplyCommand::Error].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Largest width or height accepted for a mode
+pub const MAX_DIMEN: Dimen = 16384;
+/// Maximum number of (width, height, refresh rate) combinations a single
+/// monitor can advertise to IddCx
+pub const MAX_MODES: usize = 256;
+/// Largest absolute desktop coordinate accepted for a monitor position
+pub const MAX_POSITION: i32 = 32767;
+/// Bits per pixel assumed by [Monitor::estimated_bandwidth], RGB with 8 bits
+/// per channel
+pub const BANDWIDTH_BITS_PER_PIXEL: u64 = 24;
+/// Size of an EDID block, a custom EDID is made of one or more of these
+pub const EDID_BLOCK_LEN: usize = 128;
+/// Most records in a reply to [RequestCommand::Logs], a reply with fewer has
+/// all records logged so far
+pub const MAX_LOG_RECORDS: usize = 256;
+/// Lowest SDR white level in nits Windows offers, the one of plain sRGB
+pub const MIN_SDR_WHITE_LEVEL: u16 = 80;
+/// Highest SDR white level in nits Windows offers
+pub const MAX_SDR_WHITE_LEVEL: u16 = 480;
+/// Fixed pattern every EDID starts with
+pub(crate) const EDID_HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
 pub struct Monitor {
     // identifier
     pub id: Id,
+    /// Also reported as the monitor name in the generated EDID, cut to 13
+    /// ASCII characters.
     pub name: Option<String>,
     pub enabled: bool,
+    /// A parked monitor stays in the driver with its configuration intact,
+    /// but is detached from the OS regardless of `enabled`.
+    #[serde(default)]
+    pub parked: bool,
+    /// Desired desktop coordinates of the top left corner. Windows decides
+    /// where the monitor goes if this is not set.
+    #[serde(default)]
+    pub position: Option<(i32, i32)>,
+    /// Make this the primary display, which sits at desktop coordinates
+    /// `(0, 0)`. Other displays keep their place relative to it.
+    #[serde(default)]
+    pub primary: bool,
     pub modes: Vec<Mode>,
+    /// EDID reported to the OS instead of the generated one. The driver
+    /// replaces its serial number with [Monitor::edid_serial], which is how it
+    /// tells monitors apart.
+    #[serde(default)]
+    pub edid: Option<Vec<u8>>,
+    /// Three letter PNP ID of the manufacturer in the generated EDID, like
+    /// `"DEL"`. The driver's own ID is used if this is not set.
+    #[serde(default)]
+    pub manufacturer_id: Option<String>,
+    /// Product code in the generated EDID.
+    #[serde(default)]
+    pub product_code: u16,
+    /// Serial number in the EDID, the monitor ID if this is not set. See
+    /// [Monitor::edid_serial]. If set, the generated EDID also carries it as
+    /// serial number string.
+    #[serde(default)]
+    pub serial: Option<u32>,
+    /// Hand [Monitor::edid] to the OS exactly as it is.
+    ///
+    /// **Warning:** this is meant for testing how Windows and applications
+    /// cope with malformed EDIDs. The EDID is neither validated nor is its
+    /// checksum fixed, and its serial number is not replaced, so the driver
+    /// tells this monitor apart by the EDID bytes instead. Windows may refuse
+    /// the monitor, or misbehave with it. Has no effect without a custom EDID.
+    #[serde(default)]
+    pub raw_edid: bool,
+    /// Gamma and primaries written into the generated EDID. Without one, the
+    /// EDID describes an sRGB display, see [ColorCalibration::SRGB].
+    #[serde(default)]
+    pub color: Option<ColorCalibration>,
+    /// Adaptive sync range `(min, max)` in Hz, advertised in the generated
+    /// EDID as an AMD FreeSync data block of a CTA-861 extension.
+    ///
+    /// Only software reading the EDID observes it, like the AMD drivers,
+    /// FreeSync-aware tools and EDID viewers. Windows' own variable refresh
+    /// rate and DXGI tearing support only engage if the driver reports
+    /// [Capabilities::adaptive_sync]. Otherwise the driver still presents
+    /// frames at the fixed rate of the mode, and an [ApplyReport] carries an
+    /// [ApplyWarning] for the monitor.
+    ///
+    /// The maximum must be one of the refresh rates of the modes, the
+    /// minimum at least the lowest of them.
+    #[serde(default)]
+    pub vrr_range: Option<(u16, u16)>,
+    /// Advertise basic audio in the generated EDID, with a CTA-861 audio data
+    /// block of 2 channel PCM, so the monitor looks like an HDMI sink with
+    /// audio.
+    ///
+    /// This only changes the EDID. The driver has no audio to route, whether
+    /// an audio endpoint shows up is up to Windows and the audio drivers.
+    #[serde(default)]
+    pub audio: bool,
+    /// Whether the monitor is also attached while nobody is logged on or the
+    /// session is locked, see [SessionScope].
+    #[serde(default)]
+    pub session_scope: SessionScope,
+    /// Desired rotation of the desktop on the monitor. Windows decides if
+    /// this is not set, which is [Orientation::Landscape] for a monitor it
+    /// never saw before.
+    ///
+    /// Windows owns the rotation, so like [Monitor::position] this is applied
+    /// from a user session, see
+    /// [apply_positions](crate::display_config::apply_positions). The driver
+    /// only keeps it with the monitor, so it is still there to apply after a
+    /// reboot.
+    #[serde(default)]
+    pub orientation: Option<Orientation>,
+    /// Labels to operate on groups of monitors at once, like `"streaming"`,
+    /// see [DriverClient::set_enabled_by_tag](crate::DriverClient::set_enabled_by_tag).
+    ///
+    /// A monitor with several tags is part of each of their groups, so an
+    /// operation on any of them includes it. Tags are compared exactly, and
+    /// only the clients use them, the driver keeps them with the monitor.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Color space the monitor claims, sRGB if this is not set.
+    ///
+    /// Its chromaticities go into the generated EDID, and wide gamut spaces
+    /// are also announced in a CTA-861 colorimetry data block, so software
+    /// reading the EDID, like an HDR capture pipeline, treats the frames
+    /// accordingly. Can't be combined with [Monitor::color], which sets the
+    /// chromaticities directly.
+    #[serde(default)]
+    pub color_space: Option<ColorSpace>,
+    /// Brightness of SDR white in nits the monitor's content is meant for,
+    /// between [MIN_SDR_WHITE_LEVEL] and [MAX_SDR_WHITE_LEVEL].
+    ///
+    /// The driver only keeps it with the monitor, for consumers mapping the
+    /// frames into an HDR pipeline. Windows picks the level it renders with,
+    /// see [MonitorState::active_sdr_white_level_nits].
+    #[serde(default)]
+    pub sdr_white_level_nits: Option<u16>,
+    /// Most frames per second handed to consumers like the frame export.
+    ///
+    /// Windows still renders at the refresh rate of the mode, the frames in
+    /// between are handed back right away and counted in
+    /// [Stats::frames_skipped]. Changing it takes effect on the next frame,
+    /// the monitor isn't reattached for it.
+    #[serde(default)]
+    pub frame_rate_limit: Option<u32>,
+}
+
+/// Color space of a monitor, see [Monitor::color_space].
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Deserialize, Serialize)]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    /// DCI-P3 primaries with a D65 white point, as used by Display P3.
+    DciP3,
+    /// BT.2020 primaries. Only HDR displays use them, and the driver has no
+    /// HDR modes yet, so monitors with this fail validation.
+    Bt2020,
+}
+
+impl ColorSpace {
+    /// Gamma and chromaticities written into the EDID.
+    #[must_use]
+    pub fn calibration(self) -> ColorCalibration {
+        match self {
+            Self::Srgb => ColorCalibration::SRGB,
+            Self::DciP3 => ColorCalibration {
+                red: (0.680, 0.320),
+                green: (0.265, 0.690),
+                blue: (0.150, 0.060),
+                ..ColorCalibration::SRGB
+            },
+            Self::Bt2020 => ColorCalibration {
+                gamma: 2.4,
+                red: (0.708, 0.292),
+                green: (0.170, 0.797),
+                blue: (0.131, 0.046),
+                white: (0.3127, 0.3290),
+            },
+        }
+    }
+
+    /// Whether only HDR displays can have this color space.
+    #[must_use]
+    pub fn requires_hdr(self) -> bool {
+        matches!(self, Self::Bt2020)
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Srgb => "sRGB",
+            Self::DciP3 => "DCI-P3",
+            Self::Bt2020 => "BT.2020",
+        })
+    }
+}
+
+/// Sessions in which the driver attaches a monitor.
+///
+/// The driver runs outside of any session, so it relies on the user session
+/// service to tell it with [DriverCommand::SetUserSession] when a user logs
+/// on, locks, unlocks or logs off. Without the service, [SessionScope::User]
+/// monitors are never attached.
+///
+/// Windows shows the logon and lock screen on every attached monitor, and
+/// rearranges the windows each time monitors come and go. A machine whose
+/// only displays are [SessionScope::User] monitors, like a headless streaming
+/// host, has no display for the logon screen at all: nobody can log on
+/// locally, and capture tools see nothing until someone logs on remotely.
+///
+/// The scope is no security boundary. The lock screen covers every monitor
+/// anyway, so hiding a monitor from it protects nothing on the desktop, and
+/// any process which may open the driver's pipe can claim a user session, see
+/// the driver's pipe access setting.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Deserialize, Serialize)]
+pub enum SessionScope {
+    /// Attached whenever the driver runs, including on the logon and lock
+    /// screen.
+    #[default]
+    System,
+    /// Only attached while a user is logged on and the session is unlocked.
+    User,
+}
+
+/// Rotation of the desktop on a monitor, see [Monitor::orientation].
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Deserialize, Serialize)]
+pub enum Orientation {
+    #[default]
+    Landscape,
+    /// Rotated by 90 degrees clockwise.
+    Portrait,
+    /// Upside down.
+    LandscapeFlipped,
+    /// Rotated by 270 degrees clockwise.
+    PortraitFlipped,
+}
+
+impl Orientation {
+    /// Clockwise rotation in degrees.
+    #[must_use]
+    pub fn degrees(self) -> u16 {
+        match self {
+            Self::Landscape => 0,
+            Self::Portrait => 90,
+            Self::LandscapeFlipped => 180,
+            Self::PortraitFlipped => 270,
+        }
+    }
+
+    /// Whether the desktop is taller than wide on a landscape mode, so its
+    /// width and height are swapped.
+    #[must_use]
+    pub fn is_portrait(self) -> bool {
+        matches!(self, Self::Portrait | Self::PortraitFlipped)
+    }
+}
+
+/// Colour characteristics of a monitor, as stored in the EDID.
+///
+/// Chromaticities are CIE 1931 `(x, y)` coordinates. The EDID stores them with
+/// 10 bits, so they are rounded to multiples of 1/1024.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+pub struct ColorCalibration {
+    /// Display gamma, between [ColorCalibration::MIN_GAMMA] and
+    /// [ColorCalibration::MAX_GAMMA]
+    pub gamma: f32,
+    pub red: (f32, f32),
+    pub green: (f32, f32),
+    pub blue: (f32, f32),
+    pub white: (f32, f32),
+}
+
+impl ColorCalibration {
+    pub const MIN_GAMMA: f32 = 1.0;
+    /// Largest gamma the EDID gamma byte can hold
+    pub const MAX_GAMMA: f32 = 3.54;
+
+    /// sRGB primaries with a D65 white point and a gamma of 2.2.
+    pub const SRGB: Self = Self {
+        gamma: 2.2,
+        red: (0.64, 0.33),
+        green: (0.30, 0.60),
+        blue: (0.15, 0.06),
+        white: (0.3127, 0.3290),
+    };
+
+    /// The chromaticities as `(name, (x, y))`, red, green, blue and white.
+    pub fn primaries(&self) -> [(&'static str, (f32, f32)); 4] {
+        [
+            ("red", self.red),
+            ("green", self.green),
+            ("blue", self.blue),
+            ("white", self.white),
+        ]
+    }
+
+    /// Problems with the values, empty if they fit into an EDID.
+    fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !(Self::MIN_GAMMA..=Self::MAX_GAMMA).contains(&self.gamma) {
+            problems.push(format!(
+                "gamma {} is not between {} and {}",
+                self.gamma,
+                Self::MIN_GAMMA,
+                Self::MAX_GAMMA
+            ));
+        }
+
+        for (name, (x, y)) in self.primaries() {
+            if !(0.0..1.0).contains(&x) || !(0.0..1.0).contains(&y) {
+                problems.push(format!("{name} point ({x}, {y}) is not within 0 to 1"));
+            }
+        }
+
+        problems
+    }
+}
+
+impl Default for ColorCalibration {
+    fn default() -> Self {
+        Self::SRGB
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
@@ -18,6 +368,620 @@ pub struct Mode {
     pub width: Dimen,
     pub height: Dimen,
     pub refresh_rates: Vec<RefreshRate>,
+    /// Exact signal timing, in place of the one the driver derives from the
+    /// resolution
+    ///
+    /// A mode with a timing has a single refresh rate, the one of the timing.
+    /// Drivers from before this field ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<CustomTiming>,
+}
+
+/// Blanking and pixel clock of a mode, as in an EDID detailed timing
+/// descriptor.
+///
+/// The active width and height are the ones of the [Mode]. The driver writes
+/// the timing of the preferred mode into the EDID, and reports the exact
+/// pixel rate and totals of every mode with one to IddCx.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub struct CustomTiming {
+    /// Pixel clock in kHz, a multiple of 10 up to [CustomTiming::MAX_PIXEL_CLOCK_KHZ]
+    pub pixel_clock_khz: u32,
+    pub h_front_porch: u16,
+    pub h_sync_width: u16,
+    pub h_back_porch: u16,
+    pub v_front_porch: u16,
+    pub v_sync_width: u16,
+    pub v_back_porch: u16,
+    #[serde(default)]
+    pub h_sync_positive: bool,
+    #[serde(default)]
+    pub v_sync_positive: bool,
+}
+
+impl CustomTiming {
+    /// Largest pixel clock a detailed timing descriptor can hold
+    pub const MAX_PIXEL_CLOCK_KHZ: u32 = 655_350;
+    /// Largest active size or blanking of a detailed timing descriptor
+    pub const MAX_ACTIVE: Dimen = 4095;
+    /// Largest horizontal front porch or sync width of a detailed timing
+    /// descriptor
+    pub const MAX_H_SYNC: u16 = 1023;
+    /// Largest vertical front porch or sync width of a detailed timing
+    /// descriptor
+    pub const MAX_V_SYNC: u16 = 63;
+
+    /// Horizontal blanking in pixels
+    pub fn h_blank(&self) -> u32 {
+        u32::from(self.h_front_porch) + u32::from(self.h_sync_width) + u32::from(self.h_back_porch)
+    }
+
+    /// Vertical blanking in lines
+    pub fn v_blank(&self) -> u32 {
+        u32::from(self.v_front_porch) + u32::from(self.v_sync_width) + u32::from(self.v_back_porch)
+    }
+
+    /// Exact refresh rate in Hz of a mode of `width` x `height` with this
+    /// timing, `None` if the totals are 0
+    pub fn refresh_rate(&self, width: Dimen, height: Dimen) -> Option<f64> {
+        let total = (u64::from(width) + u64::from(self.h_blank()))
+            * (u64::from(height) + u64::from(self.v_blank()));
+
+        (total != 0).then(|| f64::from(self.pixel_clock_khz) * 1000.0 / total as f64)
+    }
+
+    /// Problems with the timing of `mode`, empty if it fits into a detailed
+    /// timing descriptor and matches the refresh rate of the mode.
+    fn check(&self, mode: &Mode) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.pixel_clock_khz == 0
+            || self.pixel_clock_khz > Self::MAX_PIXEL_CLOCK_KHZ
+            || self.pixel_clock_khz % 10 != 0
+        {
+            problems.push(format!(
+                "pixel clock {} kHz is not a multiple of 10 kHz between 10 and {}",
+                self.pixel_clock_khz,
+                Self::MAX_PIXEL_CLOCK_KHZ
+            ));
+        }
+
+        if mode.width > Self::MAX_ACTIVE || mode.height > Self::MAX_ACTIVE {
+            problems.push(format!(
+                "resolution is larger than {max}x{max}",
+                max = Self::MAX_ACTIVE
+            ));
+        }
+
+        if self.h_blank() > Self::MAX_ACTIVE || self.v_blank() > Self::MAX_ACTIVE {
+            problems.push(format!(
+                "blanking {}x{} is larger than {max}x{max}",
+                self.h_blank(),
+                self.v_blank(),
+                max = Self::MAX_ACTIVE
+            ));
+        }
+
+        if self.h_front_porch > Self::MAX_H_SYNC || self.h_sync_width > Self::MAX_H_SYNC {
+            problems.push(format!(
+                "horizontal front porch and sync width must be at most {}",
+                Self::MAX_H_SYNC
+            ));
+        }
+
+        if self.v_front_porch > Self::MAX_V_SYNC || self.v_sync_width > Self::MAX_V_SYNC {
+            problems.push(format!(
+                "vertical front porch and sync width must be at most {}",
+                Self::MAX_V_SYNC
+            ));
+        }
+
+        if self.h_sync_width == 0 || self.v_sync_width == 0 {
+            problems.push("sync widths must be at least 1".to_owned());
+        }
+
+        let rate = self.refresh_rate(mode.width, mode.height);
+        match (mode.refresh_rates.as_slice(), rate) {
+            (&[rr], Some(rate)) if f64::from(rr) == rate.round() => (),
+            (_, Some(rate)) => problems.push(format!(
+                "mode must have the single refresh rate {:.0} Hz of the timing ({rate:.3} Hz)",
+                rate.round()
+            )),
+            (_, None) => (),
+        }
+
+        problems
+    }
+}
+
+/// Limits the driver enforces on monitors and modes.
+///
+/// Any aspect ratio and any non-zero refresh rate within these limits is
+/// accepted, so a mode picker only needs to check the dimensions.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Capabilities {
+    /// Maximum number of monitors which can be active at the same time
+    pub max_monitors: u32,
+    /// Largest accepted mode width
+    pub max_width: Dimen,
+    /// Largest accepted mode height
+    pub max_height: Dimen,
+    /// Maximum number of (width, height, refresh rate) combinations per monitor
+    pub max_modes: usize,
+    /// Whether Windows runs monitors with a [Monitor::vrr_range] at a
+    /// variable refresh rate, instead of only advertising it in the EDID
+    #[serde(default)]
+    pub adaptive_sync: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of a driver which only reports its monitor limit.
+    ///
+    /// The other limits are the ones of this crate, which drivers from before
+    /// [PROTOCOL_VERSION] enforce as well.
+    pub fn with_max_monitors(max_monitors: u32) -> Self {
+        Self {
+            max_monitors,
+            max_width: MAX_DIMEN,
+            max_height: MAX_DIMEN,
+            max_modes: MAX_MODES,
+            adaptive_sync: false,
+        }
+    }
+
+    /// Whether a mode with these dimensions and refresh rate is accepted.
+    pub fn supports(&self, width: Dimen, height: Dimen, refresh_rate: RefreshRate) -> bool {
+        (1..=self.max_width).contains(&width)
+            && (1..=self.max_height).contains(&height)
+            && refresh_rate > 0
+    }
+}
+
+/// A single reason why a monitor configuration was rejected
+#[non_exhaustive]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Error)]
+pub enum MonitorError {
+    #[error("Duplicate monitor with ID {0}")]
+    DuplicateMonitor(Id),
+    #[error("Duplicate mode {1}x{2} on monitor {0}")]
+    DuplicateMode(Id, Dimen, Dimen),
+    #[error("Duplicate refresh rate {3} on mode {1}x{2} on monitor {0}")]
+    DuplicateRefreshRate(Id, Dimen, Dimen, RefreshRate),
+    #[error("Invalid mode {1}x{2} on monitor {0} (dimensions must be between 1 and {max})", max = MAX_DIMEN)]
+    InvalidDimensions(Id, Dimen, Dimen),
+    #[error("Invalid refresh rate {3} on mode {1}x{2} on monitor {0}")]
+    InvalidRefreshRate(Id, Dimen, Dimen, RefreshRate),
+    #[error("Monitor {0} has {1} modes, but at most {max} are supported", max = MAX_MODES)]
+    TooManyModes(Id, usize),
+    #[error("Requested {0} enabled monitors, but the driver supports up to {1} monitors")]
+    TooManyMonitors(usize, u32),
+    #[error("Invalid position {1},{2} on monitor {0} (coordinates must be between -{max} and {max})", max = MAX_POSITION)]
+    InvalidPosition(Id, i32, i32),
+    #[error("Monitors {0} and {1} overlap at their positions")]
+    OverlappingPositions(Id, Id),
+    #[error("Invalid position {1},{2} on primary monitor {0} (the primary display is at 0,0)")]
+    InvalidPrimaryPosition(Id, i32, i32),
+    #[error("Monitors {0} and {1} are both primary")]
+    MultiplePrimary(Id, Id),
+    #[error("Invalid EDID of {1} bytes on monitor {0} (must be whole {len} byte blocks, starting with the EDID header)", len = EDID_BLOCK_LEN)]
+    InvalidEdid(Id, usize),
+    #[error("Invalid manufacturer ID {1:?} on monitor {0} (must be three uppercase letters)")]
+    InvalidManufacturerId(Id, String),
+    #[error("Monitors {0} and {1} have the same EDID serial number {2}")]
+    DuplicateSerial(Id, Id, u32),
+    #[error("Monitors {0} and {1} have the same raw EDID")]
+    DuplicateRawEdid(Id, Id),
+    #[error("Invalid color calibration on monitor {0}: {1}")]
+    InvalidColor(Id, String),
+    #[error("Invalid timing of mode {1}x{2} on monitor {0}: {3}")]
+    InvalidTiming(Id, Dimen, Dimen, String),
+    #[error("Invalid adaptive sync range {1}-{2} Hz on monitor {0} (the minimum must be at least 1 and below the maximum)")]
+    InvalidVrrRange(Id, u16, u16),
+    #[error("Adaptive sync range {1}-{2} Hz on monitor {0} is outside of its refresh rates")]
+    VrrRangeOutsideModes(Id, u16, u16),
+    #[error("Monitor has no ID yet, it must be reserved first")]
+    MissingId,
+    #[error("Adaptive sync maximum {1} Hz on monitor {0} is none of its refresh rates")]
+    VrrMaxNotAMode(Id, u16),
+    #[error("Monitor {0} has both a color calibration and a color space, only one can set its chromaticities")]
+    ConflictingColor(Id),
+    #[error("Color space {1} on monitor {0} needs an HDR mode, which the driver doesn't support")]
+    ColorSpaceNeedsHdr(Id, ColorSpace),
+    #[error("Invalid SDR white level of {1} nits on monitor {0} (must be between {min} and {max})", min = MIN_SDR_WHITE_LEVEL, max = MAX_SDR_WHITE_LEVEL)]
+    InvalidSdrWhiteLevel(Id, u16),
+    #[error("Frame rate limit on monitor {0} must be at least 1 frame per second")]
+    InvalidFrameRateLimit(Id),
+}
+
+/// An enabled monitor with ID 0 and no modes, everything else unset.
+///
+/// Not a valid monitor on its own, but a base for struct literals which only
+/// name the fields they care about, like
+/// `Monitor { id, modes, ..Monitor::default() }`. [MonitorBuilder] checks
+/// the monitor it builds.
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            name: None,
+            enabled: true,
+            parked: false,
+            position: None,
+            primary: false,
+            modes: Vec::new(),
+            edid: None,
+            manufacturer_id: None,
+            product_code: 0,
+            serial: None,
+            raw_edid: false,
+            color: None,
+            vrr_range: None,
+            audio: false,
+            session_scope: SessionScope::System,
+            orientation: None,
+            tags: Vec::new(),
+            color_space: None,
+            sdr_white_level_nits: None,
+            frame_rate_limit: None,
+        }
+    }
+}
+
+impl Monitor {
+    /// Whether the driver attaches this monitor to the OS.
+    ///
+    /// Only active monitors count towards the driver's monitor limit.
+    pub fn is_active(&self) -> bool {
+        self.enabled && !self.parked
+    }
+
+    /// Whether `tag` is one of the monitor's [tags](Monitor::tags).
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Gamma and chromaticities of the generated EDID, from
+    /// [Monitor::color] or [Monitor::color_space].
+    pub fn color_calibration(&self) -> ColorCalibration {
+        self.color
+            .unwrap_or_else(|| self.color_space.unwrap_or_default().calibration())
+    }
+
+    /// Serial number the driver puts into the EDID of this monitor.
+    ///
+    /// The driver tells monitors apart by it, so it must be unique.
+    pub fn edid_serial(&self) -> u32 {
+        self.serial.unwrap_or(self.id)
+    }
+
+    /// Approximate bandwidth of this monitor in bits per second, 0 if it is
+    /// not active.
+    ///
+    /// Windows may pick any of the modes, so this is the one with the highest
+    /// pixel rate: width × height × refresh rate × [BANDWIDTH_BITS_PER_PIXEL].
+    /// Blanking is left out, also of modes with a [CustomTiming].
+    pub fn estimated_bandwidth(&self) -> u64 {
+        if !self.is_active() {
+            return 0;
+        }
+
+        self.modes
+            .iter()
+            .flat_map(|mode| {
+                mode.refresh_rates.iter().map(|&refresh_rate| {
+                    u64::from(mode.width)
+                        * u64::from(mode.height)
+                        * u64::from(refresh_rate)
+                        * BANDWIDTH_BITS_PER_PIXEL
+                })
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The custom EDID, if the driver passes it on untouched, see
+    /// [Monitor::raw_edid].
+    pub fn raw_edid(&self) -> Option<&[u8]> {
+        self.edid.as_deref().filter(|_| self.raw_edid)
+    }
+
+    /// Check that this monitor can be handed to the driver.
+    ///
+    /// A monitor is valid if it has no duplicate modes, no duplicate refresh
+    /// rates per mode, all dimensions are within `1..=MAX_DIMEN`, no refresh
+    /// rate is 0, and it has at most [MAX_MODES] modes in total (counting
+    /// every refresh rate of a mode separately). A custom EDID must consist of
+    /// whole blocks and start with the EDID header, unless it is a
+    /// [raw EDID](Monitor::raw_edid), and a manufacturer ID must be three
+    /// uppercase ASCII letters. A gamma must be within the range of
+    /// [ColorCalibration], and chromaticities within `0.0..1.0`. A
+    /// [CustomTiming] must fit into an EDID detailed timing descriptor, and
+    /// its mode have only the refresh rate of the timing. An adaptive
+    /// sync range must have a minimum below its maximum, and lie within the
+    /// lowest and highest refresh rate of the modes. A primary monitor can
+    /// only be positioned at `(0, 0)`.
+    ///
+    /// All problems are collected, not only the first one.
+    pub fn validate(&self) -> Result<(), Vec<MonitorError>> {
+        let mut errors = Vec::new();
+
+        let mut mode_iter = self.modes.iter();
+        while let Some(mode) = mode_iter.next() {
+            if mode_iter
+                .clone()
+                .any(|m| mode.width == m.width && mode.height == m.height)
+            {
+                errors.push(MonitorError::DuplicateMode(
+                    self.id,
+                    mode.width,
+                    mode.height,
+                ));
+            }
+
+            if !(1..=MAX_DIMEN).contains(&mode.width) || !(1..=MAX_DIMEN).contains(&mode.height) {
+                errors.push(MonitorError::InvalidDimensions(
+                    self.id,
+                    mode.width,
+                    mode.height,
+                ));
+            }
+
+            let mut refresh_iter = mode.refresh_rates.iter().copied();
+            while let Some(rr) = refresh_iter.next() {
+                if refresh_iter.clone().any(|r| rr == r) {
+                    errors.push(MonitorError::DuplicateRefreshRate(
+                        self.id,
+                        mode.width,
+                        mode.height,
+                        rr,
+                    ));
+                }
+
+                if rr == 0 {
+                    errors.push(MonitorError::InvalidRefreshRate(
+                        self.id,
+                        mode.width,
+                        mode.height,
+                        rr,
+                    ));
+                }
+            }
+
+            if let Some(timing) = &mode.timing {
+                for problem in timing.check(mode) {
+                    errors.push(MonitorError::InvalidTiming(
+                        self.id,
+                        mode.width,
+                        mode.height,
+                        problem,
+                    ));
+                }
+            }
+        }
+
+        let mode_count = self.modes.iter().map(|m| m.refresh_rates.len()).sum();
+        if mode_count > MAX_MODES {
+            errors.push(MonitorError::TooManyModes(self.id, mode_count));
+        }
+
+        if let Some((x, y)) = self.position {
+            let range = -MAX_POSITION..=MAX_POSITION;
+            if !range.contains(&x) || !range.contains(&y) {
+                errors.push(MonitorError::InvalidPosition(self.id, x, y));
+            }
+        }
+
+        match self.position {
+            Some((x, y)) if self.primary && (x, y) != (0, 0) => {
+                errors.push(MonitorError::InvalidPrimaryPosition(self.id, x, y));
+            }
+            _ => (),
+        }
+
+        if let Some(edid) = self.edid.as_ref().filter(|_| !self.raw_edid) {
+            if edid.is_empty()
+                || edid.len() % EDID_BLOCK_LEN != 0
+                || !edid.starts_with(&EDID_HEADER)
+            {
+                errors.push(MonitorError::InvalidEdid(self.id, edid.len()));
+            }
+        }
+
+        if let Some(manufacturer_id) = &self.manufacturer_id {
+            if manufacturer_id.len() != 3
+                || !manufacturer_id.bytes().all(|b| b.is_ascii_uppercase())
+            {
+                errors.push(MonitorError::InvalidManufacturerId(
+                    self.id,
+                    manufacturer_id.clone(),
+                ));
+            }
+        }
+
+        if let Some(color) = &self.color {
+            for problem in color.check() {
+                errors.push(MonitorError::InvalidColor(self.id, problem));
+            }
+        }
+
+        match (self.color, self.color_space) {
+            (Some(_), Some(_)) => errors.push(MonitorError::ConflictingColor(self.id)),
+            (None, Some(space)) if space.requires_hdr() => {
+                errors.push(MonitorError::ColorSpaceNeedsHdr(self.id, space));
+            }
+            _ => (),
+        }
+
+        if self.frame_rate_limit == Some(0) {
+            errors.push(MonitorError::InvalidFrameRateLimit(self.id));
+        }
+
+        if let Some(level) = self.sdr_white_level_nits {
+            if !(MIN_SDR_WHITE_LEVEL..=MAX_SDR_WHITE_LEVEL).contains(&level) {
+                errors.push(MonitorError::InvalidSdrWhiteLevel(self.id, level));
+            }
+        }
+
+        if let Some((min, max)) = self.vrr_range {
+            let mut rates = self
+                .modes
+                .iter()
+                .flat_map(|m| m.refresh_rates.iter().copied());
+            let lowest = rates.clone().min();
+            let highest = rates.clone().max();
+
+            if min == 0 || min >= max {
+                errors.push(MonitorError::InvalidVrrRange(self.id, min, max));
+            } else if lowest.map_or(true, |l| RefreshRate::from(min) < l)
+                || highest.map_or(true, |h| RefreshRate::from(max) > h)
+            {
+                errors.push(MonitorError::VrrRangeOutsideModes(self.id, min, max));
+            } else if !rates.any(|rate| rate == RefreshRate::from(max)) {
+                // the range is advertised next to the modes, so it has to end at one of them
+                errors.push(MonitorError::VrrMaxNotAMode(self.id, max));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Desktop area covered at the requested position, using the preferred
+    /// (first) mode and the requested orientation, as
+    /// `(left, top, right, bottom)`.
+    ///
+    /// Returns `None` if no position is requested or there are no modes.
+    pub fn desktop_rect(&self) -> Option<(i64, i64, i64, i64)> {
+        let (x, y) = self.position?;
+        let mode = self.modes.first()?;
+
+        let (mut width, mut height) = (i64::from(mode.width), i64::from(mode.height));
+        if self.orientation.is_some_and(Orientation::is_portrait) {
+            (width, height) = (height, width);
+        }
+
+        let (x, y) = (i64::from(x), i64::from(y));
+        Some((x, y, x + width, y + height))
+    }
+
+    /// Merge modes with the same resolution and drop repeated refresh rates.
+    ///
+    /// The order of first appearance is kept, and so is the first
+    /// [CustomTiming] of a resolution.
+    pub fn dedup_modes(&mut self) {
+        let mut modes: Vec<Mode> = Vec::with_capacity(self.modes.len());
+
+        for mode in self.modes.drain(..) {
+            let pos = modes
+                .iter()
+                .position(|m| m.width == mode.width && m.height == mode.height);
+
+            let target = if let Some(pos) = pos {
+                &mut modes[pos]
+            } else {
+                modes.push(Mode {
+                    width: mode.width,
+                    height: mode.height,
+                    refresh_rates: Vec::with_capacity(mode.refresh_rates.len()),
+                    timing: None,
+                });
+                modes.last_mut().unwrap()
+            };
+
+            target.timing = target.timing.or(mode.timing);
+
+            for rr in mode.refresh_rates {
+                if !target.refresh_rates.contains(&rr) {
+                    target.refresh_rates.push(rr);
+                }
+            }
+        }
+
+        self.modes = modes;
+    }
+}
+
+/// Monitor ID derived from `name`, the same for the same name in every
+/// session and version.
+///
+/// Lets scripts re-add a monitor under the ID it had before, instead of the
+/// lowest free one. Different names can map to the same ID, which is then
+/// refused like any other taken ID, see
+/// [DriverCommand::ReserveRequestedId]. The ID is the 32-bit FNV-1a hash of
+/// the UTF-8 name.
+#[must_use]
+pub fn stable_id(name: &str) -> Id {
+    name.bytes().fold(0x811C_9DC5, |hash: Id, byte| {
+        (hash ^ Id::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Validate a full monitor list, as done by the driver before applying it.
+///
+/// In addition to [Monitor::validate], this checks that all IDs, EDID serial
+/// numbers and raw EDIDs are unique, that no two active monitors with a
+/// position overlap, and that at most one active monitor is primary.
+pub fn validate_monitors(monitors: &[Monitor]) -> Result<(), Vec<MonitorError>> {
+    let mut errors = Vec::new();
+
+    let mut monitor_iter = monitors.iter();
+    while let Some(monitor) = monitor_iter.next() {
+        if monitor_iter.clone().any(|m| m.id == monitor.id) {
+            errors.push(MonitorError::DuplicateMonitor(monitor.id));
+        }
+
+        // duplicate ids already collide here, they are reported above
+        if let Some(edid) = monitor.raw_edid() {
+            // raw EDIDs keep their serial, so the driver tells them apart by their bytes
+            if let Some(other) = monitor_iter
+                .clone()
+                .find(|m| m.id != monitor.id && m.raw_edid() == Some(edid))
+            {
+                errors.push(MonitorError::DuplicateRawEdid(monitor.id, other.id));
+            }
+        } else {
+            let serial = monitor.edid_serial();
+            if let Some(other) = monitor_iter
+                .clone()
+                .find(|m| m.id != monitor.id && m.raw_edid().is_none() && m.edid_serial() == serial)
+            {
+                errors.push(MonitorError::DuplicateSerial(monitor.id, other.id, serial));
+            }
+        }
+
+        if let Some(rect) = monitor.desktop_rect().filter(|_| monitor.is_active()) {
+            let overlapping = monitor_iter.clone().filter(|m| {
+                m.is_active()
+                    && m.desktop_rect().is_some_and(|other| {
+                        rect.0 < other.2 && other.0 < rect.2 && rect.1 < other.3 && other.1 < rect.3
+                    })
+            });
+
+            for other in overlapping {
+                errors.push(MonitorError::OverlappingPositions(monitor.id, other.id));
+            }
+        }
+
+        if monitor.primary && monitor.is_active() {
+            if let Some(other) = monitor_iter.clone().find(|m| m.primary && m.is_active()) {
+                errors.push(MonitorError::MultiplePrimary(monitor.id, other.id));
+            }
+        }
+
+        if let Err(e) = monitor.validate() {
+            errors.extend(e);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
 }
 
 #[non_exhaustive]
@@ -32,6 +996,220 @@ pub enum DriverCommand {
     Remove(Vec<Id>),
     // Remove all monitors from system
     RemoveAll,
+    // Save the current monitor state, so the driver restores it on next start
+    PersistNow,
+    // Run `command`, and afterwards save the resulting state if `persistence` is set
+    WithPersistence {
+        command: Box<DriverCommand>,
+        persistence: bool,
+    },
+    // Apply all monitor changes in one pass, answered with ReplyCommand::Applied
+    //
+    // A monitor failing doesn't stop the others from being applied
+    Apply {
+        monitors: Vec<Monitor>,
+        mode: ApplyMode,
+    },
+    // Render on the matching adapter, Windows moves the swap chains over
+    //
+    // Replaces the preference from the driver settings until the driver
+    // restarts. If no adapter matches, IddCx keeps its choice. None drops the
+    // preference, but the current adapter is kept until the driver restarts
+    SetPreferredAdapter(Option<AdapterPreference>),
+    // Enable or disable a monitor, keeping its configuration
+    //
+    // A disabled monitor is detached from the system but stays in the state,
+    // so enabling it again brings it back as it was. Setting the state the
+    // monitor is already in does nothing
+    SetEnabled {
+        id: Id,
+        enabled: bool,
+    },
+    // Go back to the state of a fresh install: remove all monitors, delete
+    // the saved monitors including their backup, and use the adapter
+    // preference from the driver settings again. Boot defaults are kept like
+    // the other settings, so they are used on the next start
+    //
+    // Nothing is removed if the saved monitors can't be deleted
+    Reset,
+    // Tell the driver whether a user is logged on and the console session is
+    // unlocked, sent by the user session service
+    //
+    // SessionScope::User monitors are only attached while it is set, the
+    // driver starts without a user session. The monitor state stays the same
+    SetUserSession {
+        active: bool,
+    },
+    // Reserve the lowest ID neither used by a monitor nor reserved, answered
+    // with ReplyCommand::ReservedId
+    //
+    // Until the connection closes, other connections can't add a monitor with
+    // it, see ErrorCode::IdReserved. A reservation is no monitor, so it is
+    // neither part of the state nor saved. Runs in order with the other
+    // driver commands, so a monitor added at the same time isn't reserved
+    ReserveId,
+    // Reserve `id` like ReserveId, answered with ReplyCommand::ReservedId
+    //
+    // Fails with ErrorCode::IdInUse if a monitor has the id, and with
+    // ErrorCode::IdReserved if another connection reserved it. Reserving an
+    // id the connection holds already succeeds
+    ReserveRequestedId {
+        id: Id,
+    },
+    // Start or stop copying the frames of a monitor into shared memory,
+    // answered with ReplyCommand::FrameExport
+    //
+    // Enabling an export which is running already answers with its name
+    // again. The export ends when it is disabled, when the monitor is removed
+    // and when the driver stops, see the frame_export module for the layout
+    SetFrameExport {
+        id: Id,
+        enabled: bool,
+    },
+    // Set the monitors the driver starts with while no monitors are saved,
+    // e.g. for a server without a user logging on
+    //
+    // They are checked like the monitors of Notify, but only stored, the
+    // current monitors stay as they are. Once a persisting command saved a
+    // list, even an empty one, the saved list is used instead, until Reset
+    // deletes it. An empty list removes the defaults
+    SetBootDefaults {
+        monitors: Vec<Monitor>,
+    },
+    // Log records down to this level from now on, until the driver restarts
+    //
+    // Raise it to debug a running driver, the records are read with
+    // RequestCommand::Logs or in the event log
+    SetLogLevel(LogLevel),
+    // Store how the adapter exposes monitors, used from the next driver start
+    //
+    // The current monitors stay as they are. Saved monitors are kept while
+    // the adapter has static connectors, and used again once it is dynamic
+    SetAdapterConfig(AdapterConfig),
+    // Replace the modes of a monitor with `mode` for `ttl_ms` milliseconds,
+    // then go back to the modes it had before
+    //
+    // The driver reverts on its own, so a client exiting can't leave the
+    // monitor in the mode. Overriding it again before then renews the
+    // override with the new mode and ttl, the modes from before the first
+    // override are the ones which come back. A command changing the modes in
+    // the meantime ends the override, they are kept then. Persisting commands
+    // save the modes from before the override
+    OverrideMode {
+        id: Id,
+        mode: Mode,
+        ttl_ms: u64,
+    },
+    // End the override of a monitor now, see OverrideMode
+    //
+    // Does nothing if the monitor isn't overridden
+    RevertMode {
+        id: Id,
+    },
+    // Read the driver settings from the registry again, answered with
+    // ReplyCommand::SettingsReloaded
+    //
+    // The driver does this on its own whenever the settings change, this
+    // forces it, e.g. to learn which changes still need a driver restart
+    ReloadSettings,
+}
+
+/// How the adapter exposes monitors, see [DriverCommand::SetAdapterConfig]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub enum AdapterConfig {
+    /// Monitors arrive and depart as clients add and remove them, the default
+    #[default]
+    Dynamic,
+    /// A fixed number of connectors, each with a monitor attached from the
+    /// start that never departs, like the ports of a graphics card.
+    ///
+    /// The monitors have IDs from 0 and no EDID, and offer `modes`, or the
+    /// driver's default modes if it is empty. Clients can't add, change or
+    /// remove monitors while the adapter has static connectors.
+    Static { connectors: u32, modes: Vec<Mode> },
+}
+
+/// How [DriverCommand::Apply] treats monitors missing from its list
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ApplyMode {
+    /// The list is the complete new state, missing monitors are removed
+    Replace,
+    /// Listed monitors are added or updated, the others are kept
+    Merge,
+}
+
+impl ApplyMode {
+    /// The state resulting from applying `monitors` on top of `current`.
+    pub fn resolve(self, current: &[Monitor], monitors: Vec<Monitor>) -> Vec<Monitor> {
+        match self {
+            Self::Replace => monitors,
+            Self::Merge => {
+                let mut state = current
+                    .iter()
+                    .filter(|m| !monitors.iter().any(|new| new.id == m.id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                state.extend(monitors);
+                state
+            }
+        }
+    }
+}
+
+/// Outcome of a [DriverCommand::Apply], per monitor
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApplyReport {
+    /// Monitors which were added, updated, removed or left as they were
+    pub succeeded: Vec<Id>,
+    /// Monitors for which applying the change failed
+    pub failed: Vec<ApplyFailure>,
+    /// Monitors which were applied, but without some of their settings
+    #[serde(default)]
+    pub warnings: Vec<ApplyWarning>,
+}
+
+impl ApplyReport {
+    /// Report for the change from `old` to `new`, where everything but
+    /// `failed` succeeded.
+    pub fn new(old: &[Monitor], new: &[Monitor], failed: Vec<ApplyFailure>) -> Self {
+        let removed = old.iter().filter(|m| !new.iter().any(|new| new.id == m.id));
+
+        let mut succeeded = Vec::new();
+        for monitor in new.iter().chain(removed) {
+            if !succeeded.contains(&monitor.id) && !failed.iter().any(|f| f.id == monitor.id) {
+                succeeded.push(monitor.id);
+            }
+        }
+
+        Self {
+            succeeded,
+            failed,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Whether every monitor was applied.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// A monitor which could not be applied
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApplyFailure {
+    pub id: Id,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// A setting of an applied monitor which the driver dropped, e.g. a
+/// [Monitor::vrr_range] without [Capabilities::adaptive_sync]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApplyWarning {
+    pub id: Id,
+    pub message: String,
 }
 
 /// Request command sent from client->server
@@ -40,6 +1218,43 @@ pub enum DriverCommand {
 pub enum RequestCommand {
     // Request information on the current system monitor state
     State,
+    // Request a single monitor of the state, instead of all of them
+    Monitor { id: Id },
+    // Request the maximum number of monitors which can be enabled at once
+    MaxMonitors,
+    // Run the same checks as Notify, but don't apply anything
+    Validate(Vec<Monitor>),
+    // Push per monitor events to this connection from now on
+    Subscribe,
+    // Agree on a protocol version, sent by the client before anything else
+    //
+    // A connection whose first message is something else is a legacy
+    // connection and uses LEGACY_PROTOCOL_VERSION
+    Hello { protocol: u32, client_name: String },
+    // Request the limits the driver enforces on monitors and modes
+    Capabilities,
+    // Request what the driver and Windows actually did with each monitor
+    QueryState,
+    // Request the frame counters of a monitor's swap chain
+    QueryStats { id: Id },
+    // Request which build of the driver is running
+    DriverInfo,
+    // Request whether the driver initialized IddCx, and why not
+    DriverStatus,
+    // Request the open connections of the pipe, including this one
+    ListClients,
+    // Request the monitors set with DriverCommand::SetBootDefaults
+    BootDefaults,
+    // Request the records the driver logged from `since_seq` on which are at
+    // least as severe as `min_level`, answered with ReplyCommand::Logs
+    //
+    // The driver keeps its latest records only, and sends a limited number
+    // per reply. Continue with the `next_seq` of the reply for the rest, and
+    // for records logged later
+    Logs { min_level: LogLevel, since_seq: u64 },
+    // Request the message counters of the pipe server, answered with
+    // ReplyCommand::IpcStats
+    IpcStats,
 }
 
 /// Reply command sent from server->client
@@ -48,6 +1263,703 @@ pub enum RequestCommand {
 pub enum ReplyCommand {
     // Reply to previous current system monitor state request
     State(Vec<Monitor>),
+    // Reply to previous monitor request, `None` if there is no monitor with
+    // that id
+    Monitor(Option<Monitor>),
+    // Sent instead of applying a notify whose monitors failed validation
+    InvalidMonitors(Vec<MonitorError>),
+    // Reply to previous maximum monitor count request
+    MaxMonitors(u32),
+    // Reply to previous validate request, empty if the monitors would be accepted
+    Validated(Vec<MonitorError>),
+    // Reply to previous subscribe request, events are pushed after this
+    Subscribed,
+    // Reply to hello, with the protocol version used from now on
+    //
+    // That is the lower of the client's and the driver's version
+    HelloReply {
+        protocol: u32,
+        driver_version: String,
+    },
+    // Reply to a driver command sent with an id, once it was fully applied
+    //
+    // Only sent on connections which agreed on PROTOCOL_VERSION or newer
+    Done,
+    // Reply to a message sent with an id which could not be handled
+    //
+    // Only sent to messages carrying an id, on connections which agreed on
+    // PROTOCOL_VERSION or newer, so older clients never see it. `source_id`
+    // is the id of the failed message. A message which could not be parsed
+    // is answered with ErrorCode::ParseError even without an id, untagged and
+    // with `source_id` set to `None`.
+    Error {
+        code: ErrorCode,
+        message: String,
+        source_id: Option<RequestId>,
+    },
+    // Reply to previous capabilities request
+    Capabilities(Capabilities),
+    // Reply to an apply command, once all monitors were handled
+    Applied(ApplyReport),
+    // Reply to previous query state request, in the order of the monitor state
+    QueryState(Vec<MonitorState>),
+    // Reply to previous query stats request, `None` while no swap chain is
+    // attached to the monitor
+    Stats(Option<Stats>),
+    // Reply to previous driver info request
+    DriverInfo(DriverInfo),
+    // Reply to previous driver status request
+    DriverStatus(DriverStatus),
+    // Reply to previous list clients request, oldest connection first
+    Clients(Vec<ClientInfo>),
+    // Reply to a reserve id command, with the reserved id
+    ReservedId(Id),
+    // Reply to a set frame export command, with the name of the export while
+    // it is enabled
+    FrameExport(Option<String>),
+    // Reply to previous boot defaults request, empty if there are none
+    BootDefaults(Vec<Monitor>),
+    // Reply to previous logs request, oldest record first
+    Logs {
+        records: Vec<LogRecord>,
+        next_seq: u64,
+    },
+    // Reply to a reload settings command
+    SettingsReloaded(SettingsReload),
+    // Reply to previous ipc stats request
+    IpcStats(IpcStats),
+}
+
+/// Outcome of [DriverCommand::ReloadSettings]
+///
+/// Settings are named like their registry values, e.g. `LogLevel`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SettingsReload {
+    /// Settings which changed since the last reload, and were applied
+    pub applied: Vec<String>,
+    /// Settings which differ from the ones the driver started with, and only
+    /// take effect on the next driver start
+    pub restart_required: Vec<String>,
+}
+
+impl SettingsReload {
+    pub fn new(applied: Vec<String>, restart_required: Vec<String>) -> Self {
+        Self {
+            applied,
+            restart_required,
+        }
+    }
+}
+
+/// Runtime state of a monitor in the driver, see [RequestCommand::QueryState]
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MonitorState {
+    pub id: Id,
+    /// Modes the monitor was configured with
+    pub modes: Vec<Mode>,
+    /// Mode Windows committed, `None` while the monitor isn't part of the
+    /// desktop
+    pub active_mode: Option<ActiveMode>,
+    /// Whether Windows attached a swap chain, meaning it renders to the
+    /// monitor
+    pub swap_chain: bool,
+    /// Frames received since the monitor was attached
+    pub frames: u64,
+    /// Connector the monitor is attached to, `None` while it is detached
+    pub connector_index: Option<u32>,
+    /// Adapter rendering the frames, set while a swap chain is attached
+    pub render_adapter: Option<Luid>,
+    /// Rotation the monitor was configured with, see [Monitor::orientation]
+    #[serde(default)]
+    pub orientation: Option<Orientation>,
+    /// Rotation Windows applied, `None` while the monitor isn't part of the
+    /// desktop.
+    ///
+    /// The driver can't see the rotation, so
+    /// [Client::state](crate::Client::state) fills this in from the display
+    /// config of the session it runs in.
+    #[serde(default)]
+    pub active_orientation: Option<Orientation>,
+    /// Display target Windows created for the monitor, `None` while it is
+    /// detached. See [Client::resolve_display](crate::Client::resolve_display)
+    #[serde(default)]
+    pub os_target: Option<OsTarget>,
+    /// Color space the monitor claims, see [Monitor::color_space]
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    /// SDR white level in nits Windows renders the monitor with, `None` while
+    /// the monitor isn't part of the desktop.
+    ///
+    /// Like [MonitorState::active_orientation], this is filled in by
+    /// [Client::state](crate::Client::state) from the session it runs in.
+    #[serde(default)]
+    pub active_sdr_white_level_nits: Option<u16>,
+}
+
+impl MonitorState {
+    /// State of a monitor the OS did nothing with yet.
+    pub fn new(monitor: &Monitor) -> Self {
+        Self {
+            id: monitor.id,
+            modes: monitor.modes.clone(),
+            active_mode: None,
+            swap_chain: false,
+            frames: 0,
+            connector_index: None,
+            render_adapter: None,
+            orientation: monitor.orientation,
+            active_orientation: None,
+            os_target: None,
+            color_space: monitor.color_space.unwrap_or_default(),
+            active_sdr_white_level_nits: None,
+        }
+    }
+}
+
+/// Frame counters of a monitor's swap chain, see [RequestCommand::QueryStats]
+///
+/// The counters start over whenever Windows assigns a new swap chain.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Stats {
+    pub id: Id,
+    /// Buffers acquired from the swap chain
+    pub frames_acquired: u64,
+    /// Acquired buffers handed back to the OS as processed
+    pub frames_completed: u64,
+    /// Average time from acquiring a buffer to handing it back, in
+    /// microseconds
+    pub avg_latency_us: u64,
+    /// QPC time the last acquired frame was presented at, 0 before the
+    /// first frame
+    pub last_present_qpc: u64,
+    /// Time since the swap chain was assigned, in milliseconds
+    pub uptime_ms: u64,
+    /// Acquired buffers handed back without reaching consumers, because of
+    /// the [frame rate limit](Monitor::frame_rate_limit)
+    #[serde(default)]
+    pub frames_skipped: u64,
+}
+
+/// Message counters of the driver's pipe server, see [RequestCommand::IpcStats]
+///
+/// The counters start over when the driver restarts.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IpcStats {
+    /// Messages received from all clients, including malformed ones
+    pub messages: u64,
+    /// Replies which reported an error, like [ReplyCommand::Error]
+    pub errors: u64,
+}
+
+impl IpcStats {
+    pub fn new(messages: u64, errors: u64) -> Self {
+        Self { messages, errors }
+    }
+}
+
+/// A connection to the driver's pipe, see [RequestCommand::ListClients]
+///
+/// Every field has a default, so replies of older or newer drivers still
+/// deserialize when fields are added or missing.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ClientInfo {
+    /// Number of the connection, counting up from 1 since the pipe server
+    /// started
+    pub id: u64,
+    /// Process id of the client, `None` if the driver couldn't query it
+    pub pid: Option<u32>,
+    /// Name the client sent with [RequestCommand::Hello], `None` for clients
+    /// from before the handshake
+    pub name: Option<String>,
+    /// Unix time in seconds the client connected at
+    pub connected_at: u64,
+    /// Protocol version agreed on with the client, `None` until it sent its
+    /// first message
+    pub protocol: Option<u32>,
+    /// Whether the client receives events, see [RequestCommand::Subscribe]
+    pub subscribed: bool,
+    /// Whether the request was sent on this connection
+    pub current: bool,
+}
+
+impl ClientInfo {
+    /// Connection `id` which opened at `connected_at`, with nothing else
+    /// known about it yet.
+    pub fn new(id: u64, connected_at: u64) -> Self {
+        Self {
+            id,
+            connected_at,
+            ..Self::default()
+        }
+    }
+}
+
+/// Severity of a record of the driver's log, most severe first
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Self::Error,
+            log::Level::Warn => Self::Warn,
+            log::Level::Info => Self::Info,
+            log::Level::Debug => Self::Debug,
+            log::Level::Trace => Self::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&log::Level::from(*self), f)
+    }
+}
+
+/// A record of the driver's log, see [RequestCommand::Logs]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogRecord {
+    /// Position in the driver's log, increasing by one per record
+    pub seq: u64,
+    pub level: LogLevel,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Module which logged the record
+    pub target: String,
+    pub message: String,
+}
+
+/// Build of the running driver, see [RequestCommand::DriverInfo]
+///
+/// Every field has a default, so replies of older or newer drivers still
+/// deserialize when fields are added or missing.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DriverInfo {
+    /// Crate version of the driver, like `0.3.0`
+    pub version: String,
+    /// Git commit the driver was built from
+    pub commit: String,
+    /// Day the driver was built, like `2024-07-26`
+    pub build_date: String,
+    /// Cargo profile the driver was built with, `debug` or `release`
+    pub profile: String,
+    /// Newest pipe protocol version the driver speaks
+    pub protocol_version: u32,
+    /// Version of the IddCx framework the driver runs on, `None` if the
+    /// framework is too old to report it
+    pub iddcx_version: Option<u32>,
+    /// Version string reported by the WDF runtime the driver is bound to
+    pub wdf_version: Option<String>,
+}
+
+impl DriverInfo {
+    /// Info of a driver speaking [PROTOCOL_VERSION].
+    pub fn new(
+        version: impl Into<String>,
+        commit: impl Into<String>,
+        build_date: impl Into<String>,
+        profile: impl Into<String>,
+        iddcx_version: Option<u32>,
+        wdf_version: Option<String>,
+    ) -> Self {
+        Self {
+            version: version.into(),
+            commit: commit.into(),
+            build_date: build_date.into(),
+            profile: profile.into(),
+            protocol_version: PROTOCOL_VERSION,
+            iddcx_version,
+            wdf_version,
+        }
+    }
+}
+
+/// How far the driver got initializing IddCx, see
+/// [RequestCommand::DriverStatus]
+///
+/// Its `Display` is a message for users, with a hint on what to do if
+/// initializing failed.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DriverStatus {
+    /// The adapter is still being initialized, monitors are added once it is
+    /// done
+    Starting,
+    /// IddCx is initialized and monitors can be added
+    Ready,
+    /// A step failed, the driver can't add monitors until it is restarted
+    Failed(InitFailure),
+}
+
+impl fmt::Display for DriverStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Starting => write!(f, "The driver is still initializing"),
+            Self::Ready => write!(f, "The driver is ready"),
+            Self::Failed(failure) => write!(f, "The driver failed to initialize: {failure}"),
+        }
+    }
+}
+
+/// Step of the driver initialization, see [InitFailure]
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum InitStep {
+    /// Looking up the IddCx functions and passing the driver's callbacks to
+    /// IddCx, `IddCxDeviceInitConfig`
+    ClientConfig,
+    /// `WdfDeviceCreate`
+    DeviceCreate,
+    /// `IddCxDeviceInitialize`
+    DeviceInitialize,
+    /// `IddCxAdapterInitAsync`
+    AdapterInit,
+    /// The callback IddCx calls when the adapter is ready
+    AdapterInitFinished,
+}
+
+impl fmt::Display for InitStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::ClientConfig => "IddCxDeviceInitConfig",
+            Self::DeviceCreate => "WdfDeviceCreate",
+            Self::DeviceInitialize => "IddCxDeviceInitialize",
+            Self::AdapterInit => "IddCxAdapterInitAsync",
+            Self::AdapterInitFinished => "EvtIddCxAdapterInitFinished",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// Why the driver failed to initialize, see [DriverStatus::Failed]
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct InitFailure {
+    pub step: InitStep,
+    /// `NTSTATUS` the step failed with
+    pub status: i32,
+}
+
+impl InitFailure {
+    // NTSTATUS codes, as the i32 they are on the wire
+    const NOT_IMPLEMENTED: i32 = 0xC000_0002_u32 as i32;
+    const INVALID_DEVICE_REQUEST: i32 = 0xC000_0010_u32 as i32;
+    const ACCESS_DENIED: i32 = 0xC000_0022_u32 as i32;
+    const REVISION_MISMATCH: i32 = 0xC000_0059_u32 as i32;
+    const INSUFFICIENT_RESOURCES: i32 = 0xC000_009A_u32 as i32;
+    const NOT_SUPPORTED: i32 = 0xC000_00BB_u32 as i32;
+    const INVALID_DEVICE_STATE: i32 = 0xC000_0184_u32 as i32;
+    const NOT_FOUND: i32 = 0xC000_0225_u32 as i32;
+
+    pub fn new(step: InitStep, status: i32) -> Self {
+        Self { step, status }
+    }
+
+    /// What to do about the failure, for the common causes.
+    pub fn hint(&self) -> Option<&'static str> {
+        match (self.step, self.status) {
+            (_, Self::NOT_IMPLEMENTED | Self::REVISION_MISMATCH | Self::NOT_SUPPORTED)
+            // IddCx functions missing from the function table of older IddCx versions
+            | (
+                InitStep::ClientConfig | InitStep::DeviceInitialize | InitStep::AdapterInit,
+                Self::NOT_FOUND,
+            ) => Some(
+                "IddCx version mismatch, the driver needs IddCx 1.4 (Windows 10 1903) or newer. \
+                 Update Windows",
+            ),
+            (
+                InitStep::ClientConfig | InitStep::DeviceInitialize,
+                Self::INVALID_DEVICE_REQUEST | Self::INVALID_DEVICE_STATE,
+            ) => Some(
+                "IddCx didn't accept the device, its INF settings are likely missing. \
+                 Reinstall the driver from VirtualDisplayDriver.inf, which sets IndirectKmd as \
+                 upper filter and the IddCx UMDF extension",
+            ),
+            (_, Self::ACCESS_DENIED) => Some(
+                "Access was denied, the driver must run in the UMDF host set up by its INF. \
+                 Reinstall the driver",
+            ),
+            (_, Self::INSUFFICIENT_RESOURCES) => {
+                Some("The system ran out of resources, restart the device or reboot")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InitFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = self.status as u32;
+        write!(f, "{} failed with 0x{status:08X}", self.step)?;
+
+        if let Some(hint) = self.hint() {
+            write!(f, ". {hint}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single mode, as committed by Windows
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActiveMode {
+    pub width: Dimen,
+    pub height: Dimen,
+    pub refresh_rate: RefreshRate,
+}
+
+impl ActiveMode {
+    /// Whether this is one of `modes`.
+    ///
+    /// Windows may commit a mode the monitor wasn't configured with, like a
+    /// lower refresh rate, this tells when that happened.
+    pub fn is_in(&self, modes: &[Mode]) -> bool {
+        modes.iter().any(|mode| {
+            mode.width == self.width
+                && mode.height == self.height
+                && mode.refresh_rates.contains(&self.refresh_rate)
+        })
+    }
+}
+
+/// Display target of a monitor, as Windows reported it when the monitor
+/// arrived
+///
+/// This is what the display config APIs know the monitor as, see
+/// `DISPLAYCONFIG_PATH_TARGET_INFO`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct OsTarget {
+    /// Adapter Windows shows the monitor on, which is not the render adapter
+    pub adapter: Luid,
+    pub target_id: u32,
+}
+
+/// Locally unique identifier of a graphics adapter
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Luid {
+    pub low_part: u32,
+    pub high_part: i32,
+}
+
+/// Written as the high and low part in hex, like `00000000:0000D3A1`
+impl std::fmt::Display for Luid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08X}:{:08X}", self.high_part, self.low_part)
+    }
+}
+
+impl std::str::FromStr for Luid {
+    type Err = InvalidLuid;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let part = |part: &str| {
+            let part = part.trim();
+            let part = part
+                .strip_prefix("0x")
+                .or_else(|| part.strip_prefix("0X"))
+                .unwrap_or(part);
+
+            u32::from_str_radix(part, 16).ok()
+        };
+
+        let (high, low) = s.split_once(':').ok_or_else(|| InvalidLuid(s.to_owned()))?;
+        match (part(high), part(low)) {
+            (Some(high), Some(low)) => Ok(Self {
+                low_part: low,
+                high_part: i32::from_ne_bytes(high.to_ne_bytes()),
+            }),
+            _ => Err(InvalidLuid(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid LUID {0:?} (must be two hex numbers like 00000000:0000D3A1)")]
+pub struct InvalidLuid(String);
+
+/// Graphics adapter the driver should render on, see
+/// [DriverCommand::SetPreferredAdapter]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AdapterPreference {
+    /// The adapter with this LUID. LUIDs change when the system restarts.
+    Luid(Luid),
+    /// The first adapter whose description contains this text, ignoring
+    /// case, like `"nvidia"`
+    Name(String),
+}
+
+impl AdapterPreference {
+    /// Whether an adapter with this LUID and description is the preferred
+    /// one.
+    pub fn matches(&self, luid: Luid, description: &str) -> bool {
+        match self {
+            Self::Luid(preferred) => *preferred == luid,
+            Self::Name(name) => description
+                .to_lowercase()
+                .contains(&name.trim().to_lowercase()),
+        }
+    }
+}
+
+/// A [Luid] if the text is one, otherwise a name
+impl From<&str> for AdapterPreference {
+    fn from(s: &str) -> Self {
+        s.parse()
+            .map_or_else(|_| Self::Name(s.trim().to_owned()), Self::Luid)
+    }
+}
+
+/// Stable reason of a [ReplyCommand::Error]
+///
+/// Sent as a number, so codes added later don't break older clients. Those
+/// see them as [ErrorCode::Unknown].
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    /// The driver doesn't support the command
+    ///
+    /// Drivers from before [ErrorCode::ParseError] also send this for
+    /// messages which could not be parsed.
+    InvalidCommand,
+    /// The monitors failed validation, nothing was applied
+    InvalidMonitors,
+    /// A monitor referenced by the command does not exist
+    MonitorNotFound,
+    /// An IddCx call failed while applying the command
+    IddCx,
+    /// Reading or writing the persisted state failed
+    Registry,
+    /// The message could not be parsed, e.g. it is no valid JSON or names an
+    /// unknown command. The message of the reply is the parse error.
+    ParseError,
+    /// A monitor ID is reserved by another connection, see
+    /// [DriverCommand::ReserveId]
+    IdReserved,
+    /// A monitor ID requested with [DriverCommand::ReserveRequestedId] is
+    /// used by a monitor already
+    IdInUse,
+    /// The device is going away, the driver no longer accepts commands.
+    /// Nothing of the rejected command was applied.
+    DriverStopping,
+    /// More monitors would be active than the driver supports, see
+    /// [Capabilities::max_monitors]. Sent instead of
+    /// [ErrorCode::InvalidMonitors] when that is the only problem, nothing
+    /// was applied.
+    MonitorLimitReached,
+    /// A code this version doesn't know about
+    Unknown(u16),
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::InvalidCommand => 1,
+            ErrorCode::InvalidMonitors => 2,
+            ErrorCode::MonitorNotFound => 3,
+            ErrorCode::IddCx => 4,
+            ErrorCode::Registry => 5,
+            ErrorCode::ParseError => 6,
+            ErrorCode::IdReserved => 7,
+            ErrorCode::IdInUse => 8,
+            ErrorCode::DriverStopping => 9,
+            ErrorCode::MonitorLimitReached => 10,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => Self::InvalidCommand,
+            2 => Self::InvalidMonitors,
+            3 => Self::MonitorNotFound,
+            4 => Self::IddCx,
+            5 => Self::Registry,
+            6 => Self::ParseError,
+            7 => Self::IdReserved,
+            8 => Self::IdInUse,
+            9 => Self::DriverStopping,
+            10 => Self::MonitorLimitReached,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidCommand => write!(f, "invalid command"),
+            Self::InvalidMonitors => write!(f, "invalid monitors"),
+            Self::MonitorNotFound => write!(f, "monitor not found"),
+            Self::IddCx => write!(f, "IddCx failure"),
+            Self::Registry => write!(f, "registry failure"),
+            Self::ParseError => write!(f, "parse error"),
+            Self::IdReserved => write!(f, "monitor id reserved"),
+            Self::IdInUse => write!(f, "monitor id in use"),
+            Self::DriverStopping => write!(f, "driver stopping"),
+            Self::MonitorLimitReached => write!(f, "monitor limit reached"),
+            Self::Unknown(code) => write!(f, "unknown error {code}"),
+        }
+    }
+}
+
+/// A [ReplyCommand::Error] received from the driver
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Driver reported {code}: {message}")]
+pub struct IpcError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Id of the message which failed
+    pub source_id: Option<RequestId>,
+}
+
+impl IpcError {
+    /// Extract the error from a reply, if it is one.
+    pub fn from_reply(reply: &ReplyCommand) -> Option<Self> {
+        match reply {
+            ReplyCommand::Error {
+                code,
+                message,
+                source_id,
+            } => Some(Self {
+                code: *code,
+                message: message.clone(),
+                source_id: *source_id,
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// An event happened
@@ -56,6 +1968,78 @@ pub enum ReplyCommand {
 pub enum EventCommand {
     // Monitor state was changed while client was connected
     Changed(Vec<Monitor>),
+    // Per monitor events, only sent to subscribed clients
+    //
+    // A monitor was added
+    MonitorAdded(Monitor),
+    // A monitor was removed
+    MonitorRemoved(Id),
+    // A monitor was updated (enabled, disabled, renamed, modes changed)
+    MonitorChanged(Monitor),
+    // The device left D0, e.g. because the system sleeps, no frames are
+    // processed until Resumed. Only sent to subscribed clients
+    Suspended,
+    // The device is back in D0 and processes frames again. Only sent to
+    // subscribed clients
+    Resumed,
+}
+
+/// A change to a single monitor, as pushed to subscribed clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorEvent {
+    Added(Monitor),
+    Removed(Id),
+    Changed(Monitor),
+}
+
+impl MonitorEvent {
+    /// Extract the monitor event from an event command, if it is one.
+    pub fn from_event(event: EventCommand) -> Option<Self> {
+        match event {
+            EventCommand::MonitorAdded(monitor) => Some(Self::Added(monitor)),
+            EventCommand::MonitorRemoved(id) => Some(Self::Removed(id)),
+            EventCommand::MonitorChanged(monitor) => Some(Self::Changed(monitor)),
+            _ => None,
+        }
+    }
+
+    /// ID of the monitor this event is about.
+    pub fn id(&self) -> Id {
+        match self {
+            Self::Added(monitor) | Self::Changed(monitor) => monitor.id,
+            Self::Removed(id) => *id,
+        }
+    }
+}
+
+impl From<MonitorEvent> for EventCommand {
+    fn from(event: MonitorEvent) -> Self {
+        match event {
+            MonitorEvent::Added(monitor) => Self::MonitorAdded(monitor),
+            MonitorEvent::Removed(id) => Self::MonitorRemoved(id),
+            MonitorEvent::Changed(monitor) => Self::MonitorChanged(monitor),
+        }
+    }
+}
+
+/// Compute the events which turn monitor state `old` into `new`.
+///
+/// Removals come first, then additions and changes in the order of `new`.
+pub fn monitor_events(old: &[Monitor], new: &[Monitor]) -> Vec<MonitorEvent> {
+    let removed = old
+        .iter()
+        .filter(|o| !new.iter().any(|n| n.id == o.id))
+        .map(|o| MonitorEvent::Removed(o.id));
+
+    let updated = new
+        .iter()
+        .filter_map(|n| match old.iter().find(|o| o.id == n.id) {
+            None => Some(MonitorEvent::Added(n.clone())),
+            Some(o) if o != n => Some(MonitorEvent::Changed(n.clone())),
+            Some(_) => None,
+        });
+
+    removed.chain(updated).collect()
 }
 
 /// An untagged enum of commands to be used with deserialization.
@@ -79,3 +2063,1057 @@ pub enum ClientCommand {
     Reply(ReplyCommand),
     Event(EventCommand),
 }
+
+/// A message with an optional correlation id.
+///
+/// A request sent with an id gets its reply tagged with the same id, so
+/// several requests can be in flight on one connection. On the wire the id is
+/// an extra `id` field next to the command. Messages without an id keep the
+/// plain format, which is what peers that don't know about ids send and
+/// expect.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope<T> {
+    pub id: Option<RequestId>,
+    pub command: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(id: Option<RequestId>, command: T) -> Self {
+        Self { id, command }
+    }
+}
+
+impl<T: Message> Envelope<T> {
+    /// Parse a received message.
+    ///
+    /// Never panics, whatever the input is. Invalid UTF-8, unknown commands,
+    /// duplicate or missing fields and too deeply nested JSON are all returned
+    /// as [MalformedMessage], with the id of the message if it had a valid one,
+    /// so the sender can still be answered.
+    pub fn parse(msg: &[u8]) -> Result<Self, MalformedMessage> {
+        serde_json::from_slice(msg).map_err(|source| MalformedMessage {
+            id: message_id(msg),
+            source: command_error::<T>(msg).unwrap_or(source),
+        })
+    }
+}
+
+/// A command sent in an [Envelope]
+pub trait Message: DeserializeOwned {
+    /// Why `value`, a message without its id, is no valid command, `None` if
+    /// it is one
+    fn error(value: serde_json::Value) -> Option<serde_json::Error> {
+        serde_json::from_value::<Self>(value).err()
+    }
+}
+
+impl Message for DriverCommand {}
+impl Message for RequestCommand {}
+impl Message for ReplyCommand {}
+impl Message for EventCommand {}
+
+// the untagged enums only report that no variant matched, so the error of
+// the variant the command is meant for is used instead
+
+impl Message for ServerCommand {
+    fn error(value: serde_json::Value) -> Option<serde_json::Error> {
+        either::<DriverCommand, RequestCommand>(value)
+    }
+}
+
+impl Message for ClientCommand {
+    fn error(value: serde_json::Value) -> Option<serde_json::Error> {
+        either::<ReplyCommand, EventCommand>(value)
+    }
+}
+
+/// The error of the one of `A` and `B` which knows the name of the command
+fn either<A: Message, B: Message>(value: serde_json::Value) -> Option<serde_json::Error> {
+    let is_unknown = |e: &serde_json::Error| e.to_string().starts_with("unknown variant");
+
+    let name = match &value {
+        serde_json::Value::String(name) => Some(name.clone()),
+        serde_json::Value::Object(object) if object.len() == 1 => object.keys().next().cloned(),
+        _ => None,
+    };
+
+    let a = A::error(value.clone())?;
+    let b = B::error(value)?;
+
+    Some(match (is_unknown(&a), is_unknown(&b), name) {
+        (true, true, Some(name)) => serde::de::Error::custom(format!("unknown command `{name}`")),
+        (true, false, _) => b,
+        _ => a,
+    })
+}
+
+/// Why the command of a message could not be parsed, if the message is an
+/// object otherwise
+///
+/// The envelope tries the message with and without an id, so its own error
+/// only says that neither matched
+fn command_error<T: Message>(msg: &[u8]) -> Option<serde_json::Error> {
+    let serde_json::Value::Object(mut object) = serde_json::from_slice(msg).ok()? else {
+        return None;
+    };
+    object.remove("id");
+
+    T::error(serde_json::Value::Object(object))
+}
+
+/// Id of a message which could not be parsed as a command, if it has one
+fn message_id(msg: &[u8]) -> Option<RequestId> {
+    serde_json::from_slice::<serde_json::Value>(msg)
+        .ok()?
+        .get("id")?
+        .as_u64()
+}
+
+/// Error returned from [Envelope::parse]
+#[derive(Debug, Error)]
+#[error("Malformed message: {source}")]
+pub struct MalformedMessage {
+    /// Id of the message, if it could be found
+    pub id: Option<RequestId>,
+    pub source: serde_json::Error,
+}
+
+impl<T: Serialize> Serialize for Envelope<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(id) = self.id else {
+            return self.command.serialize(serializer);
+        };
+
+        // flattening can't handle unit variants, which serialize as a plain
+        // string, so the map is written by hand. A unit variant gets null as
+        // its value, like the other variants get their fields
+        let command = serde_json::to_value(&self.command).map_err(S::Error::custom)?;
+        let fields = match command {
+            serde_json::Value::Object(fields) => fields,
+            serde_json::Value::String(variant) => {
+                serde_json::Map::from_iter([(variant, serde_json::Value::Null)])
+            }
+            other => {
+                return Err(S::Error::custom(format!(
+                    "only commands can be sent with an id, got {other}"
+                )))
+            }
+        };
+
+        let mut map = serializer.serialize_map(Some(fields.len() + 1))?;
+        map.serialize_entry("id", &id)?;
+        for (key, value) in &fields {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Envelope<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Tagged<T> {
+            id: RequestId,
+            #[serde(flatten)]
+            command: T,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr<T> {
+            Tagged(Tagged<T>),
+            Plain(T),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Tagged(Tagged { id, command }) => Self::new(Some(id), command),
+            Repr::Plain(command) => Self::new(None, command),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn monitor(id: Id, modes: Vec<Mode>) -> Monitor {
+        Monitor {
+            id,
+            modes,
+            ..Default::default()
+        }
+    }
+
+    fn mode(width: Dimen, height: Dimen, refresh_rates: &[RefreshRate]) -> Mode {
+        Mode {
+            width,
+            height,
+            refresh_rates: refresh_rates.to_vec(),
+            timing: None,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_valid_monitor() {
+        let mon = monitor(
+            0,
+            vec![mode(1920, 1080, &[60, 120]), mode(1280, 720, &[60])],
+        );
+        assert_eq!(mon.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_collects_all_errors() {
+        let mon = monitor(
+            3,
+            vec![
+                mode(0, 1080, &[60]),
+                mode(1920, 1080, &[60, 60]),
+                mode(1920, 1080, &[0]),
+                mode(MAX_DIMEN + 1, 100, &[60]),
+            ],
+        );
+
+        assert_eq!(
+            mon.validate(),
+            Err(vec![
+                MonitorError::InvalidDimensions(3, 0, 1080),
+                MonitorError::DuplicateMode(3, 1920, 1080),
+                MonitorError::DuplicateRefreshRate(3, 1920, 1080, 60),
+                MonitorError::InvalidRefreshRate(3, 1920, 1080, 0),
+                MonitorError::InvalidDimensions(3, MAX_DIMEN + 1, 100),
+            ])
+        );
+    }
+
+    #[test]
+    fn validate_rejects_too_many_modes() {
+        #[allow(clippy::cast_possible_truncation)]
+        let rates = (1..=MAX_MODES as RefreshRate + 1).collect::<Vec<_>>();
+        let mon = monitor(0, vec![mode(1920, 1080, &rates)]);
+
+        assert_eq!(
+            mon.validate(),
+            Err(vec![MonitorError::TooManyModes(0, MAX_MODES + 1)])
+        );
+    }
+
+    #[test]
+    fn dedup_merges_modes() {
+        let mut mon = monitor(
+            0,
+            vec![
+                mode(1920, 1080, &[60, 60]),
+                mode(1280, 720, &[60]),
+                mode(1920, 1080, &[120, 60]),
+            ],
+        );
+
+        mon.dedup_modes();
+
+        assert_eq!(
+            mon.modes,
+            vec![mode(1920, 1080, &[60, 120]), mode(1280, 720, &[60])]
+        );
+        assert_eq!(mon.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_custom_timing() {
+        // CVT reduced blanking 2560x1440 at 120 Hz
+        let timing = CustomTiming {
+            pixel_clock_khz: 497_760,
+            h_front_porch: 48,
+            h_sync_width: 32,
+            h_back_porch: 80,
+            v_front_porch: 3,
+            v_sync_width: 5,
+            v_back_porch: 77,
+            h_sync_positive: true,
+            v_sync_positive: false,
+        };
+        let rate = timing.refresh_rate(2560, 1440).unwrap();
+        assert!((rate - 120.0).abs() < 0.01, "{rate}");
+
+        let mut timed = mode(2560, 1440, &[120]);
+        timed.timing = Some(timing);
+        let mut mon = monitor(0, vec![timed, mode(1920, 1080, &[60])]);
+        assert_eq!(mon.validate(), Ok(()));
+
+        let invalid = |problem: &str| {
+            Err(vec![MonitorError::InvalidTiming(
+                0,
+                2560,
+                1440,
+                problem.to_owned(),
+            )])
+        };
+
+        mon.modes[0].refresh_rates = vec![120, 60];
+        assert_eq!(
+            mon.validate(),
+            invalid("mode must have the single refresh rate 120 Hz of the timing (120.000 Hz)")
+        );
+
+        mon.modes[0].refresh_rates = vec![120];
+        mon.modes[0].timing = Some(CustomTiming {
+            pixel_clock_khz: 497_765,
+            ..timing
+        });
+        assert_eq!(
+            mon.validate(),
+            invalid("pixel clock 497765 kHz is not a multiple of 10 kHz between 10 and 655350")
+        );
+
+        mon.modes[0].timing = Some(CustomTiming {
+            v_front_porch: 64,
+            v_back_porch: 16,
+            ..timing
+        });
+        assert_eq!(
+            mon.validate(),
+            invalid("vertical front porch and sync width must be at most 63")
+        );
+
+        // merged into the first mode of the resolution
+        let mut mon = monitor(0, vec![mode(2560, 1440, &[120]), mon.modes[0].clone()]);
+        mon.modes[1].timing = Some(timing);
+        mon.dedup_modes();
+        assert_eq!(mon.modes[0].timing, Some(timing));
+        assert_eq!(mon.validate(), Ok(()));
+    }
+
+    #[test]
+    fn tags_round_trip_and_default_to_empty() {
+        let mut mon = monitor(0, vec![mode(1920, 1080, &[60])]);
+
+        let json = serde_json::to_string(&mon).unwrap();
+        assert!(!json.contains("tags"));
+        assert_eq!(
+            serde_json::from_str::<Monitor>(&json).unwrap().tags,
+            Vec::<String>::new()
+        );
+
+        mon.tags = vec!["streaming".to_owned(), "testing".to_owned()];
+        let json = serde_json::to_string(&mon).unwrap();
+        assert_eq!(serde_json::from_str::<Monitor>(&json).unwrap(), mon);
+
+        assert!(mon.has_tag("streaming") && mon.has_tag("testing"));
+        assert!(!mon.has_tag("Streaming"));
+    }
+
+    #[test]
+    fn validate_frame_rate_limit() {
+        let mut mon = monitor(2, vec![mode(1920, 1080, &[60])]);
+
+        mon.frame_rate_limit = Some(30);
+        assert_eq!(mon.validate(), Ok(()));
+
+        mon.frame_rate_limit = Some(0);
+        assert_eq!(
+            mon.validate(),
+            Err(vec![MonitorError::InvalidFrameRateLimit(2)])
+        );
+    }
+
+    #[test]
+    fn stable_id_only_depends_on_the_name() {
+        // pinned, scripts rely on getting the same IDs after an update
+        assert_eq!(stable_id(""), 0x811C_9DC5);
+        assert_eq!(stable_id("a"), 0xE40C_292C);
+        assert_eq!(stable_id("capture"), stable_id("capture"));
+        assert_ne!(stable_id("capture"), stable_id("Capture"));
+    }
+
+    #[test]
+    fn color_space_and_sdr_white_level() {
+        let mut mon = monitor(0, vec![mode(1920, 1080, &[60])]);
+        assert_eq!(mon.color_calibration(), ColorCalibration::SRGB);
+
+        mon.color_space = Some(ColorSpace::DciP3);
+        mon.sdr_white_level_nits = Some(MIN_SDR_WHITE_LEVEL);
+        assert_eq!(mon.validate(), Ok(()));
+        assert_eq!(mon.color_calibration().red, (0.680, 0.320));
+        assert_eq!(mon.color_calibration().white, ColorCalibration::SRGB.white);
+
+        mon.color = Some(ColorCalibration::SRGB);
+        assert_eq!(mon.validate(), Err(vec![MonitorError::ConflictingColor(0)]));
+        assert_eq!(mon.color_calibration(), ColorCalibration::SRGB);
+
+        mon.color = None;
+        mon.color_space = Some(ColorSpace::Bt2020);
+        mon.sdr_white_level_nits = Some(MAX_SDR_WHITE_LEVEL + 1);
+        assert_eq!(
+            mon.validate(),
+            Err(vec![
+                MonitorError::ColorSpaceNeedsHdr(0, ColorSpace::Bt2020),
+                MonitorError::InvalidSdrWhiteLevel(0, MAX_SDR_WHITE_LEVEL + 1),
+            ])
+        );
+
+        // older clients don't send either
+        let json = serde_json::to_string(&monitor(0, vec![mode(1920, 1080, &[60])])).unwrap();
+        let json = json
+            .replace(r#","color_space":null"#, "")
+            .replace(r#","sdr_white_level_nits":null"#, "");
+        let mon = serde_json::from_str::<Monitor>(&json).unwrap();
+        assert_eq!((mon.color_space, mon.sdr_white_level_nits), (None, None));
+    }
+
+    #[test]
+    fn luid_round_trips_as_text() {
+        let luid = Luid {
+            low_part: 0xD3A1,
+            high_part: -1,
+        };
+
+        assert_eq!(luid.to_string(), "FFFFFFFF:0000D3A1");
+        assert_eq!("FFFFFFFF:0000D3A1".parse(), Ok(luid));
+        assert_eq!(" 0xffffffff : 0xd3a1".parse(), Ok(luid));
+
+        for invalid in ["", "D3A1", "1:2:3", "x:1", "100000000:0"] {
+            assert_eq!(
+                invalid.parse::<Luid>(),
+                Err(InvalidLuid(invalid.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn adapter_preference_matches() {
+        let luid = Luid {
+            low_part: 0xD3A1,
+            high_part: 0,
+        };
+        let name = "NVIDIA GeForce RTX 4060 Laptop GPU";
+
+        let preference = AdapterPreference::from("00000000:0000D3A1");
+        assert_eq!(preference, AdapterPreference::Luid(luid));
+        assert!(preference.matches(luid, "anything"));
+        assert!(!preference.matches(
+            Luid {
+                low_part: 1,
+                ..luid
+            },
+            name
+        ));
+
+        let preference = AdapterPreference::from(" geforce ");
+        assert_eq!(preference, AdapterPreference::Name("geforce".to_owned()));
+        assert!(preference.matches(
+            Luid {
+                low_part: 1,
+                ..luid
+            },
+            name
+        ));
+        assert!(!preference.matches(luid, "Intel(R) UHD Graphics"));
+    }
+
+    #[test]
+    fn active_mode_is_in() {
+        let modes = [mode(1920, 1080, &[60, 120]), mode(1280, 720, &[60])];
+        let active = |width, height, refresh_rate| ActiveMode {
+            width,
+            height,
+            refresh_rate,
+        };
+
+        assert!(active(1920, 1080, 120).is_in(&modes));
+        assert!(active(1280, 720, 60).is_in(&modes));
+        assert!(!active(1280, 720, 120).is_in(&modes));
+        assert!(!active(1920, 1200, 60).is_in(&modes));
+        assert!(!active(1920, 1080, 60).is_in(&[]));
+    }
+
+    #[test]
+    fn validate_monitors_rejects_duplicate_ids() {
+        let mons = [
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+        ];
+
+        assert_eq!(
+            validate_monitors(&mons),
+            Err(vec![MonitorError::DuplicateMonitor(1)])
+        );
+    }
+
+    #[test]
+    fn monitor_events_diff() {
+        let old = [
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+            monitor(2, vec![mode(1920, 1080, &[60])]),
+        ];
+
+        let mut changed = monitor(1, vec![mode(1920, 1080, &[60])]);
+        changed.enabled = false;
+        let added = monitor(3, vec![mode(1280, 720, &[60])]);
+
+        let new = [old[2].clone(), changed.clone(), added.clone()];
+
+        assert_eq!(
+            monitor_events(&old, &new),
+            vec![
+                MonitorEvent::Removed(0),
+                MonitorEvent::Changed(changed),
+                MonitorEvent::Added(added),
+            ]
+        );
+        assert!(monitor_events(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn apply_resolve_and_report() {
+        let current = [
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+        ];
+
+        let updated = monitor(1, vec![mode(1280, 720, &[60])]);
+        let added = monitor(2, vec![mode(1280, 720, &[60])]);
+        let wanted = vec![updated.clone(), added.clone()];
+
+        let merged = ApplyMode::Merge.resolve(&current, wanted.clone());
+        assert_eq!(merged, vec![current[0].clone(), updated, added]);
+
+        let replaced = ApplyMode::Replace.resolve(&current, wanted.clone());
+        assert_eq!(replaced, wanted);
+
+        let failure = ApplyFailure {
+            id: 2,
+            code: ErrorCode::IddCx,
+            message: "Failed to create monitor 2".to_owned(),
+        };
+        let report = ApplyReport::new(&current, &replaced, vec![failure.clone()]);
+        assert_eq!(report.succeeded, vec![1, 0]);
+        assert_eq!(report.failed, vec![failure]);
+        assert!(!report.is_success());
+
+        assert!(ApplyReport::new(&current, &merged, Vec::new()).is_success());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_edid() {
+        let mut mon = monitor(0, vec![mode(1920, 1080, &[60])]);
+
+        let mut edid = vec![0; 2 * EDID_BLOCK_LEN];
+        edid[..8].copy_from_slice(&EDID_HEADER);
+        mon.edid = Some(edid.clone());
+        assert_eq!(mon.validate(), Ok(()));
+
+        for invalid in [
+            vec![],
+            edid[..EDID_BLOCK_LEN + 1].to_vec(),
+            vec![0; EDID_BLOCK_LEN],
+        ] {
+            let len = invalid.len();
+            mon.edid = Some(invalid);
+            assert_eq!(mon.validate(), Err(vec![MonitorError::InvalidEdid(0, len)]));
+        }
+    }
+
+    #[test]
+    fn validate_vrr_range() {
+        let mut mon = monitor(
+            0,
+            vec![mode(1920, 1080, &[60, 144]), mode(1280, 720, &[48])],
+        );
+
+        mon.vrr_range = Some((48, 144));
+        assert_eq!(mon.validate(), Ok(()));
+
+        for (min, max) in [(0, 60), (60, 60), (144, 48)] {
+            mon.vrr_range = Some((min, max));
+            assert_eq!(
+                mon.validate(),
+                Err(vec![MonitorError::InvalidVrrRange(0, min, max)])
+            );
+        }
+
+        for (min, max) in [(30, 144), (48, 165)] {
+            mon.vrr_range = Some((min, max));
+            assert_eq!(
+                mon.validate(),
+                Err(vec![MonitorError::VrrRangeOutsideModes(0, min, max)])
+            );
+        }
+
+        mon.vrr_range = Some((48, 120));
+        assert_eq!(
+            mon.validate(),
+            Err(vec![MonitorError::VrrMaxNotAMode(0, 120)])
+        );
+
+        mon.modes.clear();
+        mon.vrr_range = Some((48, 144));
+        assert_eq!(
+            mon.validate(),
+            Err(vec![MonitorError::VrrRangeOutsideModes(0, 48, 144)])
+        );
+    }
+
+    #[test]
+    fn validate_accepts_raw_edid() {
+        let mut mons = vec![
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+        ];
+
+        // broken on purpose, and with the serial of monitor 1
+        mons[0].edid = Some(vec![1, 2, 3]);
+        mons[0].serial = Some(1);
+        assert_eq!(
+            mons[0].validate(),
+            Err(vec![MonitorError::InvalidEdid(0, 3)])
+        );
+
+        mons[0].raw_edid = true;
+        assert_eq!(mons[0].raw_edid(), Some(&[1, 2, 3][..]));
+        assert_eq!(validate_monitors(&mons), Ok(()));
+
+        mons[1].edid = Some(vec![1, 2, 3]);
+        mons[1].raw_edid = true;
+        assert_eq!(
+            validate_monitors(&mons),
+            Err(vec![MonitorError::DuplicateRawEdid(0, 1)])
+        );
+
+        // without an EDID the flag does nothing
+        mons[1].edid = None;
+        assert_eq!(mons[1].raw_edid(), None);
+        assert_eq!(validate_monitors(&mons), Ok(()));
+    }
+
+    #[test]
+    fn validate_edid_identity() {
+        let mut mons = vec![
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+        ];
+        mons[0].manufacturer_id = Some("DEL".to_owned());
+        mons[0].serial = Some(1000);
+        assert_eq!(validate_monitors(&mons), Ok(()));
+
+        for invalid in ["", "DE", "DELL", "del", "D3L", "ÄBC"] {
+            mons[0].manufacturer_id = Some(invalid.to_owned());
+            assert_eq!(
+                validate_monitors(&mons),
+                Err(vec![MonitorError::InvalidManufacturerId(
+                    0,
+                    invalid.to_owned()
+                )])
+            );
+        }
+        mons[0].manufacturer_id = None;
+
+        // the serial of monitor 1 defaults to its id
+        mons[0].serial = Some(1);
+        assert_eq!(
+            validate_monitors(&mons),
+            Err(vec![MonitorError::DuplicateSerial(0, 1, 1)])
+        );
+    }
+
+    #[test]
+    fn parked_is_optional() {
+        let mon: Monitor =
+            serde_json::from_str(r#"{"id":0,"name":null,"enabled":true,"modes":[]}"#).unwrap();
+        assert!(!mon.parked);
+        assert!(mon.is_active());
+
+        let parked = Monitor {
+            parked: true,
+            ..mon
+        };
+        assert!(!parked.is_active());
+    }
+
+    #[test]
+    fn bandwidth_uses_most_demanding_mode() {
+        let mon = monitor(
+            0,
+            vec![mode(3840, 2160, &[60]), mode(1920, 1080, &[60, 240])],
+        );
+
+        // 3840x2160@60 and 1920x1080@240 have the same pixel rate
+        assert_eq!(mon.estimated_bandwidth(), 3840 * 2160 * 60 * 24);
+
+        let disabled = Monitor {
+            enabled: false,
+            ..mon
+        };
+        assert_eq!(disabled.estimated_bandwidth(), 0);
+    }
+
+    #[test]
+    fn envelope_id_is_optional() {
+        let plain = Envelope::new(None, ServerCommand::Request(RequestCommand::State));
+        let tagged = Envelope::new(Some(7), ServerCommand::Request(RequestCommand::State));
+
+        assert_eq!(serde_json::to_string(&plain).unwrap(), r#""State""#);
+        assert_eq!(
+            serde_json::to_string(&tagged).unwrap(),
+            r#"{"id":7,"State":null}"#
+        );
+
+        let parse = |s: &str| serde_json::from_str::<Envelope<ServerCommand>>(s).unwrap();
+        assert!(matches!(
+            parse(r#""State""#),
+            Envelope {
+                id: None,
+                command: ServerCommand::Request(RequestCommand::State)
+            }
+        ));
+        assert!(matches!(
+            parse(r#"{"id":7,"Remove":[1]}"#),
+            Envelope {
+                id: Some(7),
+                command: ServerCommand::Driver(DriverCommand::Remove(_))
+            }
+        ));
+
+        let reply = Envelope::new(Some(3), ClientCommand::Reply(ReplyCommand::MaxMonitors(4)));
+        let reply = serde_json::to_string(&reply).unwrap();
+        assert!(matches!(
+            serde_json::from_str::<Envelope<ClientCommand>>(&reply).unwrap(),
+            Envelope {
+                id: Some(3),
+                command: ClientCommand::Reply(ReplyCommand::MaxMonitors(4))
+            }
+        ));
+    }
+
+    #[test]
+    fn envelope_tags_unit_variants() {
+        let requests = [
+            RequestCommand::State,
+            RequestCommand::MaxMonitors,
+            RequestCommand::Subscribe,
+            RequestCommand::Capabilities,
+            RequestCommand::QueryState,
+            RequestCommand::DriverInfo,
+            RequestCommand::DriverStatus,
+            RequestCommand::ListClients,
+            RequestCommand::BootDefaults,
+            RequestCommand::IpcStats,
+        ];
+
+        for request in requests {
+            let name = serde_json::to_value(&request).unwrap();
+            let tagged = Envelope::new(Some(7), ServerCommand::Request(request));
+            let json = serde_json::to_string(&tagged).unwrap();
+            assert_eq!(json, format!(r#"{{"id":7,{name}:null}}"#));
+
+            let parsed = Envelope::<ServerCommand>::parse(json.as_bytes()).unwrap();
+            assert_eq!(parsed.id, Some(7));
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+
+        let reply = Envelope::new(Some(3), ClientCommand::Reply(ReplyCommand::Subscribed));
+        let json = serde_json::to_string(&reply).unwrap();
+        assert_eq!(json, r#"{"id":3,"Subscribed":null}"#);
+        assert!(matches!(
+            Envelope::<ClientCommand>::parse(json.as_bytes()).unwrap(),
+            Envelope {
+                id: Some(3),
+                command: ClientCommand::Reply(ReplyCommand::Subscribed)
+            }
+        ));
+    }
+
+    #[test]
+    fn overlapping_positions() {
+        let mut mons = [
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+            monitor(2, vec![mode(1280, 720, &[60])]),
+        ];
+        mons[0].position = Some((0, 0));
+        mons[1].position = Some((1920, 0));
+        mons[2].position = Some((1919, 1079));
+
+        assert_eq!(
+            validate_monitors(&mons),
+            Err(vec![
+                MonitorError::OverlappingPositions(0, 2),
+                MonitorError::OverlappingPositions(1, 2),
+            ])
+        );
+
+        // monitors which are not attached don't take up space
+        mons[2].parked = true;
+        assert_eq!(validate_monitors(&mons), Ok(()));
+
+        mons[1].position = Some((MAX_POSITION + 1, 0));
+        assert_eq!(
+            validate_monitors(&mons),
+            Err(vec![MonitorError::InvalidPosition(1, MAX_POSITION + 1, 0)])
+        );
+    }
+
+    #[test]
+    fn portrait_monitors_are_taller_than_wide() {
+        let mut mons = [
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+        ];
+        mons[0].position = Some((0, 0));
+        mons[1].position = Some((0, 1080));
+        assert_eq!(mons[0].desktop_rect(), Some((0, 0, 1920, 1080)));
+        assert_eq!(validate_monitors(&mons), Ok(()));
+
+        for orientation in [Orientation::Portrait, Orientation::PortraitFlipped] {
+            mons[0].orientation = Some(orientation);
+            assert_eq!(mons[0].desktop_rect(), Some((0, 0, 1080, 1920)));
+            assert_eq!(
+                validate_monitors(&mons),
+                Err(vec![MonitorError::OverlappingPositions(0, 1)])
+            );
+        }
+
+        mons[0].orientation = Some(Orientation::LandscapeFlipped);
+        assert_eq!(mons[0].desktop_rect(), Some((0, 0, 1920, 1080)));
+        assert_eq!(Orientation::LandscapeFlipped.degrees(), 180);
+    }
+
+    #[test]
+    fn single_primary() {
+        let mut mons = [
+            monitor(0, vec![mode(1920, 1080, &[60])]),
+            monitor(1, vec![mode(1920, 1080, &[60])]),
+        ];
+        mons[0].primary = true;
+        mons[0].position = Some((0, 0));
+        mons[1].primary = true;
+        mons[1].position = Some((1920, 0));
+
+        assert_eq!(
+            validate_monitors(&mons),
+            Err(vec![
+                MonitorError::MultiplePrimary(0, 1),
+                MonitorError::InvalidPrimaryPosition(1, 1920, 0),
+            ])
+        );
+
+        // only the attached monitor counts
+        mons[1].position = None;
+        mons[1].enabled = false;
+        assert_eq!(validate_monitors(&mons), Ok(()));
+    }
+
+    #[test]
+    fn error_reply_roundtrip() {
+        let reply = Envelope::new(
+            Some(9),
+            ClientCommand::Reply(ReplyCommand::Error {
+                code: ErrorCode::MonitorNotFound,
+                message: "Monitor 4 not found".to_owned(),
+                source_id: Some(9),
+            }),
+        );
+        let reply = serde_json::to_string(&reply).unwrap();
+        assert_eq!(
+            reply,
+            r#"{"id":9,"Error":{"code":3,"message":"Monitor 4 not found","source_id":9}}"#
+        );
+
+        let Envelope {
+            id: Some(9),
+            command: ClientCommand::Reply(reply),
+        } = serde_json::from_str::<Envelope<ClientCommand>>(&reply).unwrap()
+        else {
+            panic!("not a tagged reply");
+        };
+        let error = IpcError::from_reply(&reply).unwrap();
+        assert_eq!(error.code, ErrorCode::MonitorNotFound);
+        assert_eq!(error.source_id, Some(9));
+
+        // codes from newer drivers still parse
+        let reply = r#"{"Error":{"code":400,"message":"","source_id":null}}"#;
+        let reply = serde_json::from_str::<ReplyCommand>(reply).unwrap();
+        assert_eq!(
+            IpcError::from_reply(&reply).unwrap().code,
+            ErrorCode::Unknown(400)
+        );
+    }
+
+    #[test]
+    fn capabilities_supports() {
+        let caps = Capabilities::with_max_monitors(4);
+        assert!(caps.supports(1920, 1080, 60));
+        assert!(caps.supports(MAX_DIMEN, 1, 1));
+        assert!(!caps.supports(MAX_DIMEN + 1, 1080, 60));
+        assert!(!caps.supports(0, 1080, 60));
+        assert!(!caps.supports(1920, 1080, 0));
+
+        // limits added by newer drivers are ignored
+        let caps =
+            r#"{"max_monitors":4,"max_width":3840,"max_height":2160,"max_modes":8,"hdr":true}"#;
+        let caps = serde_json::from_str::<Capabilities>(caps).unwrap();
+        assert!(caps.supports(3840, 2160, 144));
+        assert!(!caps.supports(7680, 4320, 60));
+    }
+
+    #[test]
+    fn driver_info_compat() {
+        // replies of drivers before the profile and wdf version were added
+        let info = r#"{"version":"0.3.0","commit":"abc","build_date":"2024-07-26","protocol_version":2,"iddcx_version":4}"#;
+        let info = serde_json::from_str::<DriverInfo>(info).unwrap();
+        assert_eq!(info.version, "0.3.0");
+        assert_eq!(info.iddcx_version, Some(4));
+        assert_eq!(info.profile, "");
+        assert_eq!(info.wdf_version, None);
+
+        // fields added by newer drivers are ignored
+        let info = r#"{"version":"0.4.0","protocol_version":3,"arch":"arm64"}"#;
+        let info = serde_json::from_str::<DriverInfo>(info).unwrap();
+        assert_eq!(info.version, "0.4.0");
+        assert_eq!(info.commit, "");
+        assert_eq!(info.protocol_version, 3);
+    }
+
+    #[test]
+    fn init_failure_hints() {
+        let failure = |step, status: u32| InitFailure::new(step, status as i32);
+
+        let mismatch = failure(InitStep::ClientConfig, 0xC000_0225);
+        assert!(mismatch.hint().unwrap().contains("Update Windows"));
+        assert!(DriverStatus::Failed(mismatch)
+            .to_string()
+            .starts_with("The driver failed to initialize: IddCxDeviceInitConfig failed with 0xC0000225. IddCx version mismatch"));
+
+        // not found only means an old IddCx for the IddCx calls
+        assert_eq!(failure(InitStep::DeviceCreate, 0xC000_0225).hint(), None);
+
+        let inf = failure(InitStep::DeviceInitialize, 0xC000_0010);
+        assert!(inf.hint().unwrap().contains("VirtualDisplayDriver.inf"));
+
+        let unknown = failure(InitStep::DeviceCreate, 0xC000_0001);
+        assert_eq!(unknown.hint(), None);
+        assert_eq!(
+            unknown.to_string(),
+            "WdfDeviceCreate failed with 0xC0000001"
+        );
+    }
+
+    #[test]
+    fn parse_rejects_malformed_messages() {
+        let parse = |s: &[u8]| {
+            Envelope::<ServerCommand>::parse(s)
+                .map(|_| ())
+                .map_err(|e| e.id)
+        };
+
+        assert_eq!(parse(br#"{"id":5,"Bogus":[1]}"#), Err(Some(5)));
+        assert_eq!(parse(br#"{"id":5,"Remove":"x"}"#), Err(Some(5)));
+        assert!(parse(br#"{"id":5,"id":6,"Remove":[1]}"#).is_err());
+        assert_eq!(parse(br#"{"Remove":[1],"Remove":[2]}"#), Err(None));
+        assert_eq!(parse(br#"{"id":-1,"Remove":[1]}"#), Err(None));
+        assert_eq!(parse(br#"{"id":5,"Remove":[1]"#), Err(None));
+        assert_eq!(parse(b"\xff\xfe"), Err(None));
+        assert_eq!(parse(b""), Err(None));
+
+        // too deep for the recursion limit of serde_json
+        let deep = format!(
+            r#"{{"id":5,"Remove":{}{}}}"#,
+            "[".repeat(100_000),
+            "]".repeat(100_000)
+        );
+        assert_eq!(parse(deep.as_bytes()), Err(None));
+
+        assert_eq!(parse(br#"{"id":5,"Remove":[1]}"#), Ok(()));
+    }
+
+    #[test]
+    fn parse_reports_why_the_command_failed() {
+        let message = |s: &[u8]| {
+            Envelope::<ServerCommand>::parse(s)
+                .map(|_| ())
+                .unwrap_err()
+                .source
+                .to_string()
+        };
+
+        assert_eq!(
+            message(br#"{"id":5,"Bogus":[1]}"#),
+            "unknown command `Bogus`"
+        );
+        assert_eq!(message(br#"{"Bogus":[1]}"#), "unknown command `Bogus`");
+        assert!(message(br#"{"id":5,"Remove":"x"}"#).starts_with("invalid type: string"));
+        assert!(message(br#"{"id":5,"QueryStats":{"id":"x"}}"#).starts_with("invalid type"));
+
+        // not about the command, the error of the envelope is kept
+        assert!(message(br#"{"id":5,"Remove":[1]"#).contains("EOF"));
+        assert!(!message(br#"{"id":-1,"Remove":[1]}"#).is_empty());
+    }
+
+    #[test]
+    fn parse_never_panics() {
+        // xorshift, so the inputs are random but the same on every run
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut below = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+
+        let mut mon = monitor(1, vec![mode(1920, 1080, &[60, 144])]);
+        mon.name = Some("fuzz".to_owned());
+        mon.position = Some((-1920, 0));
+        mon.edid = Some(vec![0; EDID_BLOCK_LEN]);
+
+        let valid = [
+            serde_json::to_vec(&Envelope::new(
+                Some(1),
+                ServerCommand::Driver(DriverCommand::Notify(vec![mon.clone()])),
+            )),
+            serde_json::to_vec(&ServerCommand::Request(RequestCommand::Hello {
+                protocol: PROTOCOL_VERSION,
+                client_name: "fuzz".to_owned(),
+            })),
+            serde_json::to_vec(&Envelope::new(
+                Some(2),
+                ClientCommand::Reply(ReplyCommand::State(vec![mon])),
+            )),
+            serde_json::to_vec(&ClientCommand::Reply(ReplyCommand::Error {
+                code: ErrorCode::IddCx,
+                message: "fuzz".to_owned(),
+                source_id: Some(3),
+            })),
+        ]
+        .map(Result::unwrap);
+
+        let interesting = b"{}[]\":,0-9.eE\\nullid";
+
+        for _ in 0..20_000 {
+            let mut msg = valid[below(valid.len())].clone();
+
+            for _ in 0..=below(4) {
+                let pos = below(msg.len());
+                match below(5) {
+                    0 => msg[pos] = interesting[below(interesting.len())],
+                    1 => msg[pos] = below(256) as u8,
+                    2 => msg.truncate(pos),
+                    3 => msg.insert(pos, interesting[below(interesting.len())]),
+                    _ => {
+                        // repeat a part, which duplicates fields and nests values
+                        let end = pos + below(msg.len() - pos + 1);
+                        let part = msg[pos..end].to_vec();
+                        msg.splice(pos..pos, part);
+                    }
+                }
+
+                if msg.is_empty() {
+                    break;
+                }
+            }
+
+            // accepted or not, both sides must survive it
+            _ = Envelope::<ServerCommand>::parse(&msg);
+            _ = Envelope::<ClientCommand>::parse(&msg);
+        }
+    }
+}
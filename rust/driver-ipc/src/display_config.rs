@@ -0,0 +1,971 @@
+//! Arranging attached virtual monitors on the desktop.
+//!
+//! The driver runs outside of any user session, so it can't change the
+//! desktop layout. Instead, the requested [Monitor::position]s,
+//! [orientations](Monitor::orientation) and the [primary](Monitor::primary)
+//! monitor are applied here, through the display config APIs of the current
+//! user session.
+//!
+//! Virtual monitors are recognized by their adapter's hardware ID, and told
+//! apart by the serial number of their EDID, see [Monitor::edid_serial].
+//!
+//! [clone_physical] uses the same APIs to describe a physical display as a
+//! [Monitor], and [resolve_display] finds the display Windows shows a
+//! virtual monitor as.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use joinery::JoinableIterator;
+use thiserror::Error;
+use windows::core::HSTRING;
+use windows::Win32::{
+    Devices::Display::{
+        DisplayConfigGetDeviceInfo, GetDisplayConfigBufferSizes, QueryDisplayConfig,
+        SetDisplayConfig, DISPLAYCONFIG_ADAPTER_NAME, DISPLAYCONFIG_DEVICE_INFO_GET_ADAPTER_NAME,
+        DISPLAYCONFIG_DEVICE_INFO_GET_SDR_WHITE_LEVEL, DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME,
+        DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME, DISPLAYCONFIG_DEVICE_INFO_HEADER,
+        DISPLAYCONFIG_MODE_INFO, DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE, DISPLAYCONFIG_PATH_INFO,
+        DISPLAYCONFIG_ROTATION, DISPLAYCONFIG_ROTATION_IDENTITY, DISPLAYCONFIG_ROTATION_ROTATE180,
+        DISPLAYCONFIG_ROTATION_ROTATE270, DISPLAYCONFIG_ROTATION_ROTATE90,
+        DISPLAYCONFIG_SDR_WHITE_LEVEL, DISPLAYCONFIG_SOURCE_DEVICE_NAME, DISPLAYCONFIG_SOURCE_MODE,
+        DISPLAYCONFIG_TARGET_DEVICE_NAME, QDC_ONLY_ACTIVE_PATHS, SDC_ALLOW_CHANGES, SDC_APPLY,
+        SDC_SAVE_TO_DATABASE, SDC_USE_SUPPLIED_DISPLAY_CONFIG,
+    },
+    Foundation::{ERROR_INSUFFICIENT_BUFFER, LUID, POINTL, WIN32_ERROR},
+    Graphics::Gdi::{
+        EnumDisplaySettingsW, DEVMODEW, ENUM_CURRENT_SETTINGS, ENUM_DISPLAY_SETTINGS_MODE,
+    },
+};
+use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+use crate::{
+    validate_monitors, Dimen, Id, Luid, Mode, Monitor, MonitorError, Orientation, OsTarget,
+    RefreshRate, MAX_DIMEN, MAX_MODES,
+};
+
+/// Hardware ID the driver's adapter is installed with.
+const HARDWARE_ID: &str = r"Root\VirtualDisplayDriver";
+const ENUM_KEY: &str = r"SYSTEM\CurrentControlSet\Enum";
+/// First wait between two looks at the display config while waiting for
+/// monitors, doubled after every look up to [MAX_RETRY_INTERVAL].
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Move every active monitor with a position to that position, rotate every
+/// one with an [orientation](Monitor::orientation), and make the
+/// [primary](Monitor::primary) monitor the primary display.
+///
+/// The driver attaches monitors asynchronously after a notify, and Windows
+/// takes a moment to enumerate them, so this retries with a growing interval
+/// for up to `timeout` until all of them show up and the new layout is
+/// accepted. Monitors without a position are left where Windows put them,
+/// relative to the primary display, and so are monitors with a
+/// [raw EDID](Monitor::raw_edid), as displays are found by their EDID serial.
+///
+/// The driver keeps positions and orientations with the monitors it saves, so
+/// after a reboot, calling this with the restored monitors brings the layout
+/// back.
+///
+/// Returns an error if the positions are invalid, or if a positioned or
+/// rotated monitor would overlap any other display. Nothing is changed in that
+/// case.
+pub fn apply_positions(monitors: &[Monitor], timeout: Duration) -> Result<(), PositionError> {
+    validate_monitors(monitors).map_err(PositionError::Invalid)?;
+
+    let wanted = monitors
+        .iter()
+        .filter(|m| m.is_active() && m.raw_edid().is_none())
+        .filter(|m| m.position.is_some() || m.primary || m.orientation.is_some())
+        .map(|m| Wanted {
+            id: m.id,
+            serial: m.edid_serial(),
+            position: m.position,
+            primary: m.primary,
+            orientation: m.orientation,
+        })
+        .collect::<Vec<_>>();
+
+    if wanted.is_empty() {
+        return Ok(());
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut interval = RETRY_INTERVAL;
+    loop {
+        let config = DisplayConfig::query().map_err(PositionError::Query)?;
+
+        let result = match wanted
+            .iter()
+            .find(|wanted| config.source_of(wanted.serial).is_none())
+        {
+            Some(wanted) => Err(PositionError::NotAttached(wanted.id)),
+            None => arrange(config, &wanted),
+        };
+
+        // a monitor still being enumerated shows up missing, or makes
+        // Windows reject the layout
+        match result {
+            Err(PositionError::NotAttached(_) | PositionError::Set(_))
+                if Instant::now() < deadline =>
+            {
+                thread::sleep(interval);
+                interval = (interval * 2).min(MAX_RETRY_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Rotation of the active monitors whose [orientation](Monitor::orientation)
+/// Windows can be asked about
+///
+/// Monitors which aren't part of the desktop, and monitors with a
+/// [raw EDID](Monitor::raw_edid), which can't be found, are left out.
+pub fn active_orientations(monitors: &[Monitor]) -> windows::core::Result<Vec<(Id, Orientation)>> {
+    let config = DisplayConfig::query()?;
+
+    let orientations = monitors
+        .iter()
+        .filter(|m| m.raw_edid().is_none())
+        .filter_map(|m| {
+            let path = config.path_of(m.edid_serial())?;
+            Some((m.id, orientation(config.paths[path].targetInfo.rotation)?))
+        })
+        .collect();
+
+    Ok(orientations)
+}
+
+/// SDR white level in nits Windows renders the active monitors with
+///
+/// Like [active_orientations], monitors which aren't part of the desktop or
+/// have a [raw EDID](Monitor::raw_edid) are left out.
+pub fn active_sdr_white_levels(monitors: &[Monitor]) -> windows::core::Result<Vec<(Id, u16)>> {
+    let config = DisplayConfig::query()?;
+
+    let levels = monitors
+        .iter()
+        .filter(|m| m.raw_edid().is_none())
+        .filter_map(|m| {
+            let path = config.path_of(m.edid_serial())?;
+            Some((m.id, sdr_white_level(&config.paths[path])?))
+        })
+        .collect();
+
+    Ok(levels)
+}
+
+/// Where Windows shows a virtual monitor, see [resolve_display]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayPath {
+    pub id: Id,
+    /// GDI name of the display, like `\\.\DISPLAY5`, as taken by
+    /// `EnumDisplaySettings` or `CreateDC`
+    pub gdi_name: String,
+    pub target: OsTarget,
+    /// Desktop area of the display, the rect `GetMonitorInfo` reports for its
+    /// `HMONITOR`
+    pub rect: DisplayRect,
+}
+
+/// Area of the virtual screen, right and bottom are exclusive like in a `RECT`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DisplayRect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// Find the display showing the virtual monitor `id`, which Windows created
+/// display target `target` for.
+///
+/// Windows takes a moment to enumerate a monitor after it arrived, so this
+/// retries with a growing interval for up to `timeout`. Returns `None` if the
+/// monitor isn't part of the desktop by then, e.g. because it is disabled in
+/// the display settings.
+///
+/// The target is in the monitor's
+/// [MonitorState::os_target](crate::MonitorState::os_target), see
+/// [Client::resolve_display](crate::Client::resolve_display) for a version
+/// which looks it up.
+#[must_use]
+pub fn resolve_display(id: Id, target: OsTarget, timeout: Duration) -> Option<DisplayPath> {
+    resolve_in(&SystemPaths, id, target, timeout)
+}
+
+fn resolve_in(
+    paths: &impl ActivePaths,
+    id: Id,
+    target: OsTarget,
+    timeout: Duration,
+) -> Option<DisplayPath> {
+    let deadline = Instant::now() + timeout;
+    let mut interval = RETRY_INTERVAL;
+
+    loop {
+        // a failed query is retried like a missing display, the config can
+        // change while it is read
+        if let Ok(paths) = paths.active_paths() {
+            if let Some(path) = find_display(&paths, id, target) {
+                return Some(path);
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+
+        thread::sleep(interval.min(deadline - now));
+        interval = (interval * 2).min(MAX_RETRY_INTERVAL);
+    }
+}
+
+/// The path of `paths` showing `target`, if Windows finished setting it up
+fn find_display(paths: &[ActivePath], id: Id, target: OsTarget) -> Option<DisplayPath> {
+    let path = paths.iter().find(|path| path.target == target)?;
+
+    Some(DisplayPath {
+        id,
+        gdi_name: path.gdi_name.clone()?,
+        target,
+        rect: path.rect?,
+    })
+}
+
+/// An active display path, with what [resolve_display] needs to know of it
+#[derive(Debug, Clone)]
+struct ActivePath {
+    target: OsTarget,
+    // missing while Windows is still setting the path up
+    gdi_name: Option<String>,
+    rect: Option<DisplayRect>,
+}
+
+/// Source of the active display paths, so finding a display can be tested
+/// without any
+trait ActivePaths {
+    fn active_paths(&self) -> windows::core::Result<Vec<ActivePath>>;
+}
+
+/// The paths `QueryDisplayConfig` reports
+struct SystemPaths;
+
+impl ActivePaths for SystemPaths {
+    fn active_paths(&self) -> windows::core::Result<Vec<ActivePath>> {
+        let config = DisplayConfig::query()?;
+
+        let paths = config
+            .paths
+            .iter()
+            .map(|path| ActivePath {
+                target: OsTarget {
+                    adapter: Luid {
+                        low_part: path.targetInfo.adapterId.LowPart,
+                        high_part: path.targetInfo.adapterId.HighPart,
+                    },
+                    target_id: path.targetInfo.id,
+                },
+                gdi_name: source_name(path),
+                rect: config.source_rect(path),
+            })
+            .collect();
+
+        Ok(paths)
+    }
+}
+
+/// What [apply_positions] changes of a monitor
+struct Wanted {
+    id: Id,
+    // EDID serial, which finds the monitor in the display config
+    serial: u32,
+    position: Option<(i32, i32)>,
+    primary: bool,
+    orientation: Option<Orientation>,
+}
+
+/// Rotate and move the monitors of `wanted` in `config` and apply it, every
+/// monitor must be attached
+fn arrange(mut config: DisplayConfig, wanted: &[Wanted]) -> Result<(), PositionError> {
+    let mut moved = Vec::with_capacity(wanted.len());
+
+    // rotating changes the size of the desktop area, which the positions and
+    // the overlap checks need
+    for wanted in wanted {
+        let Some(orientation) = wanted.orientation else {
+            continue;
+        };
+
+        // checked by the caller
+        let path = config.path_of(wanted.serial).unwrap();
+        if config.set_orientation(path, orientation) {
+            moved.push((wanted.id, config.source_of(wanted.serial).unwrap()));
+        }
+    }
+
+    // the primary display is the one at the origin, so move the desktop
+    // under it first, and positions are relative to the new primary
+    if let Some(wanted) = wanted.iter().find(|wanted| wanted.primary) {
+        let index = config.source_of(wanted.serial).unwrap();
+        let origin = config.source_mode(index).position;
+        config.translate(-origin.x, -origin.y);
+    }
+
+    for wanted in wanted {
+        let Some((x, y)) = wanted.position else {
+            continue;
+        };
+
+        let index = config.source_of(wanted.serial).unwrap();
+        config.set_position(index, x, y);
+        moved.push((wanted.id, index));
+    }
+
+    for &(id, index) in &moved {
+        let overlapping = config.overlapping(index);
+        if !overlapping.is_empty() {
+            return Err(PositionError::Overlap(id, overlapping.len()));
+        }
+    }
+
+    config.apply().map_err(PositionError::Set)
+}
+
+/// Describe the physical display `device_name` as a monitor with ID `id`.
+///
+/// `device_name` is the GDI name of the display, like `\\.\DISPLAY1`. The
+/// monitor gets the display's friendly name, its EDID and every mode it
+/// supports, with the current mode first. Modes a virtual monitor can't have
+/// are left out, and so is an EDID the driver can't use.
+///
+/// The returned monitor is enabled and has no position, so it can be added to
+/// a [DriverClient](crate::DriverClient) as is.
+pub fn clone_physical(device_name: &str, id: Id) -> Result<Monitor, CloneError> {
+    let config = DisplayConfig::query().map_err(CloneError::Query)?;
+
+    let path = config
+        .paths
+        .iter()
+        .find(|path| source_name(path).is_some_and(|name| name.eq_ignore_ascii_case(device_name)))
+        .ok_or_else(|| CloneError::NotFound(device_name.to_owned()))?;
+
+    let target = target_name(path.targetInfo.adapterId, path.targetInfo.id);
+    let name = target
+        .as_ref()
+        .map(|target| from_wide(&target.monitorFriendlyDeviceName))
+        .filter(|name| !name.is_empty());
+    let edid = target.and_then(|target| read_edid(&from_wide(&target.monitorDevicePath)));
+
+    let modes = display_modes(device_name);
+    if modes.is_empty() {
+        return Err(CloneError::NoModes(device_name.to_owned()));
+    }
+
+    let mut monitor = Monitor {
+        id,
+        name,
+        modes,
+        edid,
+        ..Default::default()
+    };
+
+    // the driver generates its own EDID instead
+    if let Err(errors) = monitor.validate() {
+        if errors
+            .iter()
+            .any(|e| matches!(e, MonitorError::InvalidEdid(..)))
+        {
+            monitor.edid = None;
+        }
+    }
+
+    Ok(monitor)
+}
+
+/// Modes supported by display `device_name`, with the current mode first
+fn display_modes(device_name: &str) -> Vec<Mode> {
+    let device_name = HSTRING::from(device_name);
+
+    let mode_at = |index| {
+        let mut mode = DEVMODEW {
+            #[allow(clippy::cast_possible_truncation)]
+            dmSize: size_of::<DEVMODEW>() as u16,
+            ..Default::default()
+        };
+
+        unsafe { EnumDisplaySettingsW(&device_name, index, &mut mode) }
+            .as_bool()
+            .then_some((mode.dmPelsWidth, mode.dmPelsHeight, mode.dmDisplayFrequency))
+    };
+
+    let current = mode_at(ENUM_CURRENT_SETTINGS);
+    let all = (0..).map_while(|i| mode_at(ENUM_DISPLAY_SETTINGS_MODE(i)));
+
+    group_modes(current.into_iter().chain(all))
+}
+
+/// Group `(width, height, refresh rate)` combinations into modes
+///
+/// Duplicates and combinations a virtual monitor can't have are dropped, and
+/// at most [MAX_MODES] combinations are kept.
+fn group_modes(combinations: impl Iterator<Item = (Dimen, Dimen, RefreshRate)>) -> Vec<Mode> {
+    let mut modes: Vec<Mode> = Vec::new();
+    let mut count = 0;
+
+    for (width, height, refresh_rate) in combinations {
+        // 0 and 1 stand for the hardware default refresh rate
+        if count == MAX_MODES
+            || refresh_rate <= 1
+            || !(1..=MAX_DIMEN).contains(&width)
+            || !(1..=MAX_DIMEN).contains(&height)
+        {
+            continue;
+        }
+
+        match modes
+            .iter_mut()
+            .find(|m| m.width == width && m.height == height)
+        {
+            Some(mode) if mode.refresh_rates.contains(&refresh_rate) => continue,
+            Some(mode) => mode.refresh_rates.push(refresh_rate),
+            None => modes.push(Mode {
+                width,
+                height,
+                refresh_rates: vec![refresh_rate],
+                timing: None,
+            }),
+        }
+
+        count += 1;
+    }
+
+    modes
+}
+
+/// Active display paths and their modes, as returned by `QueryDisplayConfig`
+struct DisplayConfig {
+    paths: Vec<DISPLAYCONFIG_PATH_INFO>,
+    modes: Vec<DISPLAYCONFIG_MODE_INFO>,
+    // EDID serial of the monitor shown by each path, if it is one of ours
+    monitor_serials: Vec<Option<u32>>,
+}
+
+impl DisplayConfig {
+    fn query() -> windows::core::Result<Self> {
+        let (paths, modes) = loop {
+            let mut path_count = 0;
+            let mut mode_count = 0;
+            unsafe {
+                GetDisplayConfigBufferSizes(QDC_ONLY_ACTIVE_PATHS, &mut path_count, &mut mode_count)
+            }
+            .ok()?;
+
+            let mut paths = vec![DISPLAYCONFIG_PATH_INFO::default(); path_count as usize];
+            let mut modes = vec![DISPLAYCONFIG_MODE_INFO::default(); mode_count as usize];
+
+            let status = unsafe {
+                QueryDisplayConfig(
+                    QDC_ONLY_ACTIVE_PATHS,
+                    &mut path_count,
+                    paths.as_mut_ptr(),
+                    &mut mode_count,
+                    modes.as_mut_ptr(),
+                    None,
+                )
+            };
+
+            // a display was attached between both calls
+            if status == ERROR_INSUFFICIENT_BUFFER {
+                continue;
+            }
+            status.ok()?;
+
+            paths.truncate(path_count as usize);
+            modes.truncate(mode_count as usize);
+            break (paths, modes);
+        };
+
+        let monitor_serials = paths.iter().map(monitor_serial).collect();
+
+        Ok(Self {
+            paths,
+            modes,
+            monitor_serials,
+        })
+    }
+
+    /// Index of the path showing the virtual monitor with EDID serial `serial`
+    fn path_of(&self, serial: u32) -> Option<usize> {
+        self.monitor_serials
+            .iter()
+            .position(|&monitor| monitor == Some(serial))
+    }
+
+    /// Index of the source mode shown on the virtual monitor with EDID serial `serial`
+    fn source_of(&self, serial: u32) -> Option<usize> {
+        let path = &self.paths[self.path_of(serial)?];
+
+        let index = unsafe { path.sourceInfo.Anonymous.modeInfoIdx } as usize;
+
+        self.modes
+            .get(index)
+            .filter(|mode| mode.infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE)
+            .map(|_| index)
+    }
+
+    fn source_mode(&self, index: usize) -> DISPLAYCONFIG_SOURCE_MODE {
+        unsafe { self.modes[index].Anonymous.sourceMode }
+    }
+
+    /// Desktop area of the source shown on `path`
+    fn source_rect(&self, path: &DISPLAYCONFIG_PATH_INFO) -> Option<DisplayRect> {
+        let index = unsafe { path.sourceInfo.Anonymous.modeInfoIdx } as usize;
+
+        self.modes
+            .get(index)
+            .filter(|mode| mode.infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE)?;
+
+        let source = self.source_mode(index);
+        Some(DisplayRect {
+            left: source.position.x,
+            top: source.position.y,
+            right: source.position.x + i32::try_from(source.width).ok()?,
+            bottom: source.position.y + i32::try_from(source.height).ok()?,
+        })
+    }
+
+    fn set_position(&mut self, index: usize, x: i32, y: i32) {
+        let mut source = self.source_mode(index);
+        source.position = POINTL { x, y };
+        self.modes[index].Anonymous.sourceMode = source;
+    }
+
+    /// Rotate the target of path `index`, returns whether that changed it
+    ///
+    /// The source mode is the desktop area, which is taller than wide on a
+    /// portrait monitor, so it turns along.
+    fn set_orientation(&mut self, index: usize, orientation: Orientation) -> bool {
+        let target = &mut self.paths[index].targetInfo;
+        let was_portrait = self::orientation(target.rotation).is_some_and(Orientation::is_portrait);
+
+        let rotation = rotation(orientation);
+        if target.rotation == rotation {
+            return false;
+        }
+        target.rotation = rotation;
+
+        let source = unsafe { self.paths[index].sourceInfo.Anonymous.modeInfoIdx } as usize;
+        if was_portrait != orientation.is_portrait()
+            && self
+                .modes
+                .get(source)
+                .is_some_and(|mode| mode.infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE)
+        {
+            let mut mode = self.source_mode(source);
+            (mode.width, mode.height) = (mode.height, mode.width);
+            self.modes[source].Anonymous.sourceMode = mode;
+        }
+
+        true
+    }
+
+    /// Move every source by `dx`, `dy`
+    fn translate(&mut self, dx: i32, dy: i32) {
+        for index in 0..self.modes.len() {
+            if self.modes[index].infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE {
+                let POINTL { x, y } = self.source_mode(index).position;
+                self.set_position(index, x + dx, y + dy);
+            }
+        }
+    }
+
+    /// Other sources whose desktop area intersects the one of source `index`
+    fn overlapping(&self, index: usize) -> Vec<usize> {
+        let rect = |source: DISPLAYCONFIG_SOURCE_MODE| {
+            let (x, y) = (i64::from(source.position.x), i64::from(source.position.y));
+            (
+                x,
+                y,
+                x + i64::from(source.width),
+                y + i64::from(source.height),
+            )
+        };
+
+        let this = rect(self.source_mode(index));
+
+        self.modes
+            .iter()
+            .enumerate()
+            .filter(|&(i, mode)| i != index && mode.infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE)
+            .filter(|&(i, _)| {
+                let other = rect(self.source_mode(i));
+                this.0 < other.2 && other.0 < this.2 && this.1 < other.3 && other.1 < this.3
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn apply(&self) -> windows::core::Result<()> {
+        let status = unsafe {
+            SetDisplayConfig(
+                Some(&self.paths),
+                Some(&self.modes),
+                SDC_APPLY
+                    | SDC_USE_SUPPLIED_DISPLAY_CONFIG
+                    | SDC_SAVE_TO_DATABASE
+                    | SDC_ALLOW_CHANGES,
+            )
+        };
+
+        #[allow(clippy::cast_sign_loss)]
+        let status = WIN32_ERROR(status as u32);
+        status.ok()
+    }
+}
+
+fn rotation(orientation: Orientation) -> DISPLAYCONFIG_ROTATION {
+    match orientation {
+        Orientation::Landscape => DISPLAYCONFIG_ROTATION_IDENTITY,
+        Orientation::Portrait => DISPLAYCONFIG_ROTATION_ROTATE90,
+        Orientation::LandscapeFlipped => DISPLAYCONFIG_ROTATION_ROTATE180,
+        Orientation::PortraitFlipped => DISPLAYCONFIG_ROTATION_ROTATE270,
+    }
+}
+
+/// `None` for rotations Windows doesn't set on active paths
+fn orientation(rotation: DISPLAYCONFIG_ROTATION) -> Option<Orientation> {
+    [
+        Orientation::Landscape,
+        Orientation::Portrait,
+        Orientation::LandscapeFlipped,
+        Orientation::PortraitFlipped,
+    ]
+    .into_iter()
+    .find(|&orientation| self::rotation(orientation) == rotation)
+}
+
+/// EDID serial of the virtual monitor shown on `path`, `None` for other displays
+fn monitor_serial(path: &DISPLAYCONFIG_PATH_INFO) -> Option<u32> {
+    let adapter = adapter_path(path.targetInfo.adapterId)?;
+    let hardware_ids = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(format!(r"{ENUM_KEY}\{}", instance_id(&adapter)?))
+        .ok()?
+        .get_value::<Vec<String>, _>("HardwareID")
+        .ok()?;
+
+    if !hardware_ids
+        .iter()
+        .any(|id| id.eq_ignore_ascii_case(HARDWARE_ID))
+    {
+        return None;
+    }
+
+    let target = target_name(path.targetInfo.adapterId, path.targetInfo.id)?;
+    let edid = read_edid(&from_wide(&target.monitorDevicePath))?;
+
+    // the serial number is stored little endian at offset 12
+    let serial = edid.get(12..16)?;
+    Some(u32::from_le_bytes(serial.try_into().ok()?))
+}
+
+/// EDID of the monitor with device interface path `monitor_path`
+fn read_edid(monitor_path: &str) -> Option<Vec<u8>> {
+    let edid = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(format!(
+            r"{ENUM_KEY}\{}\Device Parameters",
+            instance_id(monitor_path)?
+        ))
+        .ok()?
+        .get_raw_value("EDID")
+        .ok()?;
+
+    Some(edid.bytes)
+}
+
+/// GDI name of the source shown on `path`, like `\\.\DISPLAY1`
+fn source_name(path: &DISPLAYCONFIG_PATH_INFO) -> Option<String> {
+    let mut name = DISPLAYCONFIG_SOURCE_DEVICE_NAME {
+        header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+            r#type: DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME,
+            #[allow(clippy::cast_possible_truncation)]
+            size: size_of::<DISPLAYCONFIG_SOURCE_DEVICE_NAME>() as u32,
+            adapterId: path.sourceInfo.adapterId,
+            id: path.sourceInfo.id,
+        },
+        ..Default::default()
+    };
+
+    let status = unsafe { DisplayConfigGetDeviceInfo(&mut name.header) };
+    (status == 0).then(|| from_wide(&name.viewGdiDeviceName))
+}
+
+fn adapter_path(adapter_id: LUID) -> Option<String> {
+    let mut name = DISPLAYCONFIG_ADAPTER_NAME {
+        header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+            r#type: DISPLAYCONFIG_DEVICE_INFO_GET_ADAPTER_NAME,
+            #[allow(clippy::cast_possible_truncation)]
+            size: size_of::<DISPLAYCONFIG_ADAPTER_NAME>() as u32,
+            adapterId: adapter_id,
+            id: 0,
+        },
+        ..Default::default()
+    };
+
+    let status = unsafe { DisplayConfigGetDeviceInfo(&mut name.header) };
+    (status == 0).then(|| from_wide(&name.adapterDevicePath))
+}
+
+fn target_name(adapter_id: LUID, target_id: u32) -> Option<DISPLAYCONFIG_TARGET_DEVICE_NAME> {
+    let mut name = DISPLAYCONFIG_TARGET_DEVICE_NAME {
+        header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+            r#type: DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME,
+            #[allow(clippy::cast_possible_truncation)]
+            size: size_of::<DISPLAYCONFIG_TARGET_DEVICE_NAME>() as u32,
+            adapterId: adapter_id,
+            id: target_id,
+        },
+        ..Default::default()
+    };
+
+    let status = unsafe { DisplayConfigGetDeviceInfo(&mut name.header) };
+    (status == 0).then_some(name)
+}
+
+/// SDR white level of the target of `path` in nits
+fn sdr_white_level(path: &DISPLAYCONFIG_PATH_INFO) -> Option<u16> {
+    let mut level = DISPLAYCONFIG_SDR_WHITE_LEVEL {
+        header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+            r#type: DISPLAYCONFIG_DEVICE_INFO_GET_SDR_WHITE_LEVEL,
+            #[allow(clippy::cast_possible_truncation)]
+            size: size_of::<DISPLAYCONFIG_SDR_WHITE_LEVEL>() as u32,
+            adapterId: path.targetInfo.adapterId,
+            id: path.targetInfo.id,
+        },
+        ..Default::default()
+    };
+
+    let status = unsafe { DisplayConfigGetDeviceInfo(&mut level.header) };
+    // reported relative to 80 nits, which is 1000
+    (status == 0)
+        .then(|| u16::try_from(u64::from(level.SDRWhiteLevel) * 80 / 1000).unwrap_or(u16::MAX))
+}
+
+fn from_wide(s: &[u16]) -> String {
+    let len = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+    String::from_utf16_lossy(&s[..len])
+}
+
+/// Turn a device interface path like `\\?\DISPLAY#ABC1234#1&2&0&UID0#{guid}`
+/// into the device instance ID `DISPLAY\ABC1234\1&2&0&UID0`
+fn instance_id(device_path: &str) -> Option<String> {
+    let path = device_path.strip_prefix(r"\\?\")?;
+    let (instance, _interface) = path.rsplit_once('#')?;
+    Some(instance.replace('#', r"\"))
+}
+
+/// Error returned from [apply_positions].
+#[derive(Debug, Error)]
+pub enum PositionError {
+    #[error("Invalid monitors: {}", .0.iter().join_with(", "))]
+    Invalid(Vec<MonitorError>),
+    #[error("Monitor {0} was not attached in time")]
+    NotAttached(Id),
+    #[error("Monitor {0} would overlap {1} other display(s) at its position")]
+    Overlap(Id, usize),
+    #[error("Failed to query display config: {0}")]
+    Query(windows::core::Error),
+    #[error("Failed to set display config: {0}")]
+    Set(windows::core::Error),
+}
+
+/// Error returned from [clone_physical].
+#[derive(Debug, Error)]
+pub enum CloneError {
+    #[error("No active display named {0}")]
+    NotFound(String),
+    #[error("Display {0} reported no usable modes")]
+    NoModes(String),
+    #[error("Failed to query display config: {0}")]
+    Query(windows::core::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    const TARGET: OsTarget = OsTarget {
+        adapter: Luid {
+            low_part: 0x1d0,
+            high_part: 0,
+        },
+        target_id: 7,
+    };
+
+    /// Paths of a system which shows `paths` after `enumerating` queries
+    struct FakePaths {
+        paths: Vec<ActivePath>,
+        enumerating: usize,
+        queries: Cell<usize>,
+    }
+
+    impl ActivePaths for FakePaths {
+        fn active_paths(&self) -> windows::core::Result<Vec<ActivePath>> {
+            let queries = self.queries.get();
+            self.queries.set(queries + 1);
+
+            if queries < self.enumerating {
+                return Ok(Vec::new());
+            }
+
+            Ok(self.paths.clone())
+        }
+    }
+
+    fn path(target: OsTarget, name: &str) -> ActivePath {
+        ActivePath {
+            target,
+            gdi_name: Some(name.to_owned()),
+            rect: Some(DisplayRect {
+                left: 1920,
+                top: 0,
+                right: 3840,
+                bottom: 1080,
+            }),
+        }
+    }
+
+    #[test]
+    fn resolve_matches_the_target() {
+        let other = OsTarget {
+            target_id: 8,
+            ..TARGET
+        };
+        let paths = FakePaths {
+            paths: vec![path(other, r"\\.\DISPLAY1"), path(TARGET, r"\\.\DISPLAY2")],
+            enumerating: 0,
+            queries: Cell::new(0),
+        };
+
+        let display = resolve_in(&paths, 3, TARGET, Duration::ZERO).unwrap();
+        assert_eq!(display.id, 3);
+        assert_eq!(display.gdi_name, r"\\.\DISPLAY2");
+        assert_eq!(display.target, TARGET);
+        assert_eq!(display.rect.right, 3840);
+    }
+
+    #[test]
+    fn resolve_waits_for_enumeration() {
+        let paths = FakePaths {
+            paths: vec![path(TARGET, r"\\.\DISPLAY2")],
+            enumerating: 2,
+            queries: Cell::new(0),
+        };
+
+        assert!(resolve_in(&paths, 3, TARGET, Duration::from_secs(5)).is_some());
+        assert_eq!(paths.queries.get(), 3);
+    }
+
+    #[test]
+    fn resolve_gives_up_after_timeout() {
+        let paths = FakePaths {
+            paths: vec![path(TARGET, r"\\.\DISPLAY2")],
+            enumerating: usize::MAX,
+            queries: Cell::new(0),
+        };
+
+        assert_eq!(
+            resolve_in(&paths, 3, TARGET, Duration::from_millis(100)),
+            None
+        );
+        assert!(paths.queries.get() > 1);
+    }
+
+    #[test]
+    fn resolve_skips_paths_being_set_up() {
+        let mut unnamed = path(TARGET, "");
+        unnamed.gdi_name = None;
+        let mut no_source = path(TARGET, r"\\.\DISPLAY2");
+        no_source.rect = None;
+
+        for path in [unnamed, no_source] {
+            assert_eq!(find_display(&[path], 3, TARGET), None);
+        }
+    }
+
+    #[test]
+    fn device_path_to_instance_id() {
+        assert_eq!(
+            instance_id(
+                r"\\?\DISPLAY#MTT1337#5&2e0e8d4a&0&UID256#{e6f07b5f-ee97-4a90-b076-33f57bf4eaa7}"
+            )
+            .as_deref(),
+            Some(r"DISPLAY\MTT1337\5&2e0e8d4a&0&UID256")
+        );
+        assert_eq!(
+            instance_id(r"\\?\ROOT#DISPLAY#0000#{5b45201d-f2f2-4f3b-85bb-30ff1f953599}").as_deref(),
+            Some(r"ROOT\DISPLAY\0000")
+        );
+        assert_eq!(instance_id("DISPLAY1"), None);
+    }
+
+    #[test]
+    fn rotations_match_orientations() {
+        for orientation in [
+            Orientation::Landscape,
+            Orientation::Portrait,
+            Orientation::LandscapeFlipped,
+            Orientation::PortraitFlipped,
+        ] {
+            assert_eq!(super::orientation(rotation(orientation)), Some(orientation));
+        }
+
+        assert_eq!(
+            rotation(Orientation::Portrait),
+            DISPLAYCONFIG_ROTATION_ROTATE90
+        );
+        assert_eq!(super::orientation(DISPLAYCONFIG_ROTATION(0)), None);
+    }
+
+    #[test]
+    fn group_modes_merges_refresh_rates() {
+        let modes = group_modes(
+            [
+                (2560, 1440, 144),
+                (3840, 2160, 60),
+                (2560, 1440, 60),
+                (2560, 1440, 144),
+                (1920, 1080, 1),
+                (0, 1080, 60),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(
+            modes,
+            vec![
+                Mode {
+                    width: 2560,
+                    height: 1440,
+                    refresh_rates: vec![144, 60],
+                    timing: None,
+                },
+                Mode {
+                    width: 3840,
+                    height: 2160,
+                    refresh_rates: vec![60],
+                    timing: None,
+                },
+            ]
+        );
+
+        let many = (1..=MAX_MODES as u32 + 10).map(|rr| (1920, 1080, rr + 1));
+        assert_eq!(group_modes(many)[0].refresh_rates.len(), MAX_MODES);
+    }
+}
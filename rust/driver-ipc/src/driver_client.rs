@@ -1,6 +1,20 @@
-use std::collections::HashSet;
-
-use tokio::{sync::watch, task};
+use std::{
+    collections::HashSet,
+    panic,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{watch, Mutex},
+    task,
+    time::{sleep, Instant},
+};
 use tokio_stream::{Stream, StreamExt};
 
 use crate::*;
@@ -13,13 +27,42 @@ use crate::*;
 /// [DriverClient::persist]. To synchronize this object with the driver, you
 /// must call [DriverClient::refresh_state]. The state will not be updated
 /// automatically.
-#[derive(Debug)]
+///
+/// ## Concurrency
+///
+/// The client is `Clone`, `Send` and `Sync`, and all methods take `&self`, so
+/// it can be shared between threads and tasks without an outer lock.
+///
+/// - Clones share the connection, the client state and a state waiting in
+///   [DriverClient::notify_debounced]. A change made through one clone is
+///   seen by all of them. Use [DriverClient::duplicate] for a copy with its
+///   own state.
+/// - The client state is behind a lock, which is only held while a method
+///   reads or changes it, and never while waiting for the driver. Every
+///   method sees a consistent state, but a sequence of calls is not atomic:
+///   another clone may change the state in between. Change a monitor in one
+///   step with [DriverClient::find_monitor_mut] to avoid lost updates.
+/// - Requests are tagged, so any number of clones can wait for replies at the
+///   same time, and each gets its own. Streams from
+///   [DriverClient::receive_events] get every event, independent of requests
+///   and of other streams.
+#[derive(Debug, Clone)]
 pub struct DriverClient {
     client: Client,
     state_rx: watch::Receiver<Vec<Monitor>>,
-    state: Vec<Monitor>,
+    // shared with all clones
+    state: Arc<RwLock<Vec<Monitor>>>,
+    capabilities: Capabilities,
+    // state waiting to be sent by DriverClient::notify_debounced
+    //
+    // Locked while sending, so a debounced send can't overtake a newer notify
+    pending: Arc<Mutex<Option<Vec<Monitor>>>>,
+    // bits per second, NO_BANDWIDTH_BUDGET if unlimited
+    bandwidth_budget: Arc<AtomicU64>,
 }
 
+const NO_BANDWIDTH_BUDGET: u64 = u64::MAX;
+
 impl DriverClient {
     /// Connect to driver on pipe with default name.
     ///
@@ -35,6 +78,7 @@ impl DriverClient {
         let client = Client::connect_to(name).await?;
 
         let current_state = client.request_state().await?;
+        let capabilities = client.request_capabilities().await?;
 
         let (state_tx, state_rx) = watch::channel(current_state.clone());
 
@@ -54,10 +98,138 @@ impl DriverClient {
         Ok(Self {
             client,
             state_rx,
-            state: current_state,
+            state: Arc::new(RwLock::new(current_state)),
+            capabilities,
+            pending: Arc::default(),
+            bandwidth_budget: Arc::new(AtomicU64::new(NO_BANDWIDTH_BUDGET)),
         })
     }
 
+    fn read_state(&self) -> RwLockReadGuard<'_, Vec<Monitor>> {
+        self.state.read().unwrap()
+    }
+
+    fn write_state(&self) -> RwLockWriteGuard<'_, Vec<Monitor>> {
+        self.state.write().unwrap()
+    }
+
+    /// Maximum number of monitors the driver can have enabled at the same
+    /// time.
+    ///
+    /// Disabled and parked monitors do not count towards this limit.
+    pub fn max_monitors(&self) -> u32 {
+        self.capabilities.max_monitors
+    }
+
+    /// Limit on the [estimated bandwidth](DriverClient::estimated_bandwidth)
+    /// of all active monitors, in bits per second, checked by
+    /// [DriverClient::set_monitors].
+    ///
+    /// The driver has no such limit, but the GPU runs out of resources at some
+    /// point, and monitors then fail to attach without telling why. There is
+    /// no budget by default. It is shared with clones of this client.
+    pub fn set_bandwidth_budget(&self, budget: Option<u64>) {
+        self.bandwidth_budget
+            .store(budget.unwrap_or(NO_BANDWIDTH_BUDGET), Ordering::Relaxed);
+    }
+
+    /// The budget set with [DriverClient::set_bandwidth_budget].
+    pub fn bandwidth_budget(&self) -> Option<u64> {
+        Some(self.bandwidth_budget.load(Ordering::Relaxed))
+            .filter(|&budget| budget != NO_BANDWIDTH_BUDGET)
+    }
+
+    /// Approximate bandwidth of the active monitors in the client state, in
+    /// bits per second, see [Monitor::estimated_bandwidth].
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn estimated_bandwidth(&self) -> u64 {
+        self.read_state()
+            .iter()
+            .map(Monitor::estimated_bandwidth)
+            .sum()
+    }
+
+    /// Limits the driver enforces on monitors and modes.
+    ///
+    /// Use [Capabilities::supports] to check a mode before adding it.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Version of the driver, see [Client::driver_version].
+    pub fn driver_version(&self) -> Option<&str> {
+        self.client.driver_version()
+    }
+
+    /// Request which build of the driver is running, see
+    /// [Client::version].
+    pub async fn version(&self) -> Result<DriverInfo, error::RequestError> {
+        self.client.version().await
+    }
+
+    /// Request whether the driver finished initializing IddCx, see
+    /// [Client::driver_status].
+    pub async fn driver_status(&self) -> Result<DriverStatus, error::RequestError> {
+        self.client.driver_status().await
+    }
+
+    /// Request the open connections of the driver's pipe, see
+    /// [Client::list_clients].
+    pub async fn list_clients(&self) -> Result<Vec<ClientInfo>, error::RequestError> {
+        self.client.list_clients().await
+    }
+
+    /// Request records of the driver's log, see [Client::logs].
+    pub async fn logs(
+        &self,
+        min_level: LogLevel,
+        since_seq: u64,
+    ) -> Result<(Vec<LogRecord>, u64), error::RequestError> {
+        self.client.logs(min_level, since_seq).await
+    }
+
+    /// Set the most verbose level the driver logs at, see
+    /// [Client::set_log_level].
+    pub async fn set_log_level(&self, level: LogLevel) -> Result<(), error::RequestError> {
+        self.client.set_log_level(level).await
+    }
+
+    /// Set how the adapter exposes monitors from the next driver start on,
+    /// see [Client::set_adapter_config].
+    pub async fn set_adapter_config(
+        &self,
+        config: &AdapterConfig,
+    ) -> Result<(), error::RequestError> {
+        self.client.set_adapter_config(config).await
+    }
+
+    /// Run a monitor in `mode` alone for `ttl`, then let the driver bring back
+    /// its modes from before, see [Client::override_mode].
+    ///
+    /// Note: This bypasses the client state, which keeps the modes from
+    /// before. Call [DriverClient::refresh_state] to see the override.
+    pub async fn override_mode(
+        &self,
+        id: Id,
+        mode: &Mode,
+        ttl: Duration,
+    ) -> Result<(), error::RequestError> {
+        self.client.override_mode(id, mode, ttl).await
+    }
+
+    /// End an override of [DriverClient::override_mode] now, see
+    /// [Client::revert_mode].
+    pub async fn revert_mode(&self, id: Id) -> Result<(), error::RequestError> {
+        self.client.revert_mode(id).await
+    }
+
+    /// Make the driver read its settings again, see [Client::reload_settings].
+    pub async fn reload_settings(&self) -> Result<SettingsReload, error::RequestError> {
+        self.client.reload_settings().await
+    }
+
     /// Get the ID of a monitor using a query.
     ///
     /// ## Query syntax
@@ -82,7 +254,7 @@ impl DriverClient {
     pub fn find_id(&self, query: &str) -> Option<Id> {
         let id = query.parse::<Id>();
 
-        for monitor in self.monitors().iter() {
+        for monitor in self.read_state().iter() {
             if let Some(name) = monitor.name.as_deref() {
                 if name == query {
                     return Some(monitor.id);
@@ -100,10 +272,36 @@ impl DriverClient {
     }
 
     /// Manually synchronize with the driver.
-    pub fn refresh_state(&mut self) -> &[Monitor] {
-        self.state = self.state_rx.borrow().clone();
+    ///
+    /// Returns a copy of the new client state.
+    pub fn refresh_state(&self) -> Vec<Monitor> {
+        let state = self.state_rx.borrow().clone();
+        self.write_state().clone_from(&state);
 
-        &self.state
+        state
+    }
+
+    /// Synchronize a single monitor with the driver, without transferring the
+    /// others.
+    ///
+    /// Replaces the monitor in the client state, adds it if it is new and
+    /// removes it if the driver has no monitor with `id`. Returns a copy of
+    /// the new monitor, see [Client::request_monitor].
+    pub async fn refresh_one(&self, id: Id) -> Result<Option<Monitor>, error::RequestError> {
+        let monitor = self.client.request_monitor(id).await?;
+
+        let mut state = self.write_state();
+        let index = state.iter().position(|m| m.id == id);
+        match (&monitor, index) {
+            (Some(monitor), Some(index)) => state[index].clone_from(monitor),
+            (Some(monitor), None) => state.push(monitor.clone()),
+            (None, Some(index)) => {
+                state.remove(index);
+            }
+            (None, None) => (),
+        }
+
+        Ok(monitor)
     }
 
     /// Returns a stream of continuous events from the driver.
@@ -112,32 +310,78 @@ impl DriverClient {
     /// of who changed its state. This means, if it is changed by another
     /// process, this stream will still be updated.
     ///
-    /// Note: If multiple copies of this client exist (using
+    /// Note: If multiple copies of this client exist (clones, or using
     /// [DriverClient::duplicate]), the returned stream will only be closed
     /// after all copies are dropped.
     pub fn receive_events(&self) -> impl Stream<Item = Result<EventCommand, error::ReceiveError>> {
         self.client.receive_events()
     }
 
-    /// Get the current monitor state stored inside this client.
+    /// Get a copy of the current monitor state stored inside this client.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn monitors(&self) -> &[Monitor] {
-        &self.state
+    pub fn monitors(&self) -> Vec<Monitor> {
+        self.read_state().clone()
     }
 
     /// Replace all monitors.
     ///
+    /// Returns an error if the monitors contain duplicates, if more monitors
+    /// are active than [DriverClient::max_monitors] allows, or if the active
+    /// monitors exceed the [bandwidth budget](DriverClient::set_bandwidth_budget).
+    ///
     /// Note: This does not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
-    pub fn set_monitors(&mut self, monitors: &[Monitor]) -> Result<(), error::DuplicateError> {
+    pub fn set_monitors(&self, monitors: &[Monitor]) -> Result<(), error::SetMonitorsError> {
         mons_have_duplicates(monitors)?;
 
-        self.state = monitors.to_owned();
+        let active = monitors.iter().filter(|mon| mon.is_active()).count();
+        if active > self.max_monitors() as usize {
+            return Err(error::SetMonitorsError::TooManyMonitors(
+                active,
+                self.max_monitors(),
+            ));
+        }
+
+        if let Some(budget) = self.bandwidth_budget() {
+            let total = monitors.iter().map(Monitor::estimated_bandwidth).sum();
+            if total > budget {
+                let mut usage = monitors
+                    .iter()
+                    .map(|mon| (mon.id, mon.estimated_bandwidth()))
+                    .filter(|&(_, bandwidth)| bandwidth > 0)
+                    .collect::<Vec<_>>();
+                usage.sort_by(|a, b| b.1.cmp(&a.1));
+
+                return Err(error::SetMonitorsError::OverBudget {
+                    total,
+                    budget,
+                    monitors: usage,
+                });
+            }
+        }
+
+        *self.write_state() = monitors.to_owned();
         Ok(())
     }
 
+    /// Ask the driver whether it would accept `monitors`, without applying
+    /// them.
+    ///
+    /// This runs the same checks the driver does when receiving
+    /// [DriverClient::notify], including the driver's monitor limit. Neither
+    /// the client state nor the driver state is changed.
+    pub async fn validate_monitors(
+        &self,
+        monitors: &[Monitor],
+    ) -> Result<(), error::ValidateError> {
+        self.client
+            .validate(monitors)
+            .await?
+            .map_err(error::ValidateError::Invalid)
+    }
+
     /// Replace an existing monitor. The monitor is identified by its ID.
     ///
     /// Returns an error if the monitor does not exist.
@@ -147,8 +391,8 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn replace_monitor(&mut self, monitor: Monitor) -> Result<(), error::MonNotFound> {
-        match self.state.iter_mut().find(|m| m.id == monitor.id) {
+    pub fn replace_monitor(&self, monitor: Monitor) -> Result<(), error::MonNotFound> {
+        match self.write_state().iter_mut().find(|m| m.id == monitor.id) {
             Some(m) => {
                 *m = monitor;
                 Ok(())
@@ -161,23 +405,208 @@ impl DriverClient {
     ///
     /// State changes of the client are not automatically sent to the driver.
     /// You must manually call this method to send changes to the driver.
-    pub async fn notify(&mut self) -> Result<(), error::SendError> {
-        self.client.notify(&self.state).await
+    pub async fn notify(&self) -> Result<(), error::SendError> {
+        let mut pending = self.pending.lock().await;
+        *pending = None;
+
+        let state = self.monitors();
+        self.client.notify(&state).await
+    }
+
+    /// Send the current client state to the driver after `interval`,
+    /// coalescing the calls in between.
+    ///
+    /// Meant for interactive editing, like a slider calling this on every
+    /// change. The first call starts the interval, and further calls within it
+    /// only replace the state to send, so the driver receives at most one
+    /// state per interval, and always the latest one.
+    ///
+    /// A state still waiting is dropped by [DriverClient::notify] and the
+    /// other notify methods, which send a newer state right away, and sent
+    /// first by [DriverClient::persist_now]. Failures to send are only
+    /// logged.
+    pub async fn notify_debounced(&self, interval: Duration) {
+        let mut pending = self.pending.lock().await;
+        let waiting = pending.replace(self.monitors()).is_some();
+
+        if waiting {
+            return;
+        }
+
+        let client = self.client.clone();
+        let pending = self.pending.clone();
+
+        task::spawn(async move {
+            sleep(interval).await;
+
+            let mut pending = pending.lock().await;
+            if let Some(state) = pending.take() {
+                if let Err(e) = client.notify(&state).await {
+                    error!("Failed to send debounced state: {e}");
+                }
+            }
+        });
+    }
+
+    /// Send the current client state to the driver, and let the driver save it
+    /// if `persistence` is set.
+    ///
+    /// A saved state is restored by the driver the next time it is started.
+    pub async fn notify_with(&self, persistence: bool) -> Result<(), error::SendError> {
+        let mut pending = self.pending.lock().await;
+        *pending = None;
+
+        let state = self.monitors();
+        self.client.notify_with(&state, persistence).await
+    }
+
+    /// Send the current client state to the driver and wait until it was
+    /// applied, saving it if `persistence` is set.
+    ///
+    /// Unlike [DriverClient::notify_with], failures on the driver side are
+    /// returned, see [Client::execute].
+    pub async fn notify_checked(&self, persistence: bool) -> Result<(), error::RequestError> {
+        let command = DriverCommand::WithPersistence {
+            command: Box::new(DriverCommand::Notify(self.monitors())),
+            persistence,
+        };
+
+        let mut pending = self.pending.lock().await;
+        *pending = None;
+
+        self.client.execute(&command).await
+    }
+
+    /// Send the client state like [DriverClient::notify_checked], then wait
+    /// until Windows attached every active monitor.
+    ///
+    /// A monitor counts as attached once it has an
+    /// [active mode](MonitorState::active_mode), so it can be used right away,
+    /// e.g. to set a wallpaper on it. Polls the driver until `timeout` passes.
+    pub async fn apply_and_wait(&self, timeout: Duration) -> Result<(), error::ApplyWaitError> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+        let deadline = Instant::now() + timeout;
+        self.notify_checked(false)
+            .await
+            .map_err(error::ApplyWaitError::Apply)?;
+
+        let ids = self
+            .read_state()
+            .iter()
+            .filter(|monitor| monitor.is_active())
+            .map(|monitor| monitor.id)
+            .collect::<Vec<_>>();
+
+        while !ids.is_empty() {
+            let states = self
+                .client
+                .state()
+                .await
+                .map_err(error::ApplyWaitError::Request)?;
+
+            let detached = ids.iter().copied().find(|&id| {
+                !states
+                    .iter()
+                    .any(|state| state.id == id && state.active_mode.is_some())
+            });
+
+            let Some(id) = detached else {
+                break;
+            };
+
+            if Instant::now() >= deadline {
+                return Err(error::ApplyWaitError::NotAttached(id));
+            }
+
+            sleep(POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
+    /// Return the driver and this client to the state of a fresh install.
+    ///
+    /// Resets the driver with [Client::reset], clears the client state, and
+    /// deletes the monitors saved with [DriverClient::persist]. A state still
+    /// waiting in [DriverClient::notify_debounced] is dropped.
+    pub async fn reset(&self) -> Result<(), error::ResetError> {
+        let mut pending = self.pending.lock().await;
+        *pending = None;
+
+        self.client.reset().await?;
+        self.write_state().clear();
+
+        Client::clear_persisted()?;
+
+        Ok(())
+    }
+
+    /// Make the driver save its current state.
+    ///
+    /// This saves the state of the driver, not the state of this client, apart
+    /// from a state still waiting in [DriverClient::notify_debounced]. Call
+    /// [DriverClient::notify_with] to send and save the client state at once.
+    pub async fn persist_now(&self) -> Result<(), error::SendError> {
+        // the driver must have the debounced state before saving
+        let mut pending = self.pending.lock().await;
+        if let Some(state) = pending.take() {
+            self.client.notify(&state).await?;
+        }
+
+        self.client.persist_now().await
+    }
+
+    /// Move the monitors of the client state to their requested
+    /// [Monitor::position], and make the [Monitor::primary] one the primary
+    /// display.
+    ///
+    /// Call this after [DriverClient::notify]. The driver attaches monitors
+    /// asynchronously, so this waits up to `timeout` for them to show up. See
+    /// [display_config::apply_positions] for details.
+    pub async fn apply_positions(&self, timeout: Duration) -> Result<(), error::PositionError> {
+        let monitors = self.monitors();
+
+        task::spawn_blocking(move || display_config::apply_positions(&monitors, timeout))
+            .await
+            .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
     }
 
-    /// Find the monitor with the given ID.
+    /// Request the mode Windows committed for the monitor with the given ID.
+    ///
+    /// This is asked from the driver, and may differ from the requested modes
+    /// in the client state, see [ActiveMode::is_in]. `None` while the monitor
+    /// isn't part of the desktop. Fails with
+    /// [error::RequestError::Unsupported] if the driver is too old to report
+    /// it.
+    pub async fn active_mode(&self, id: Id) -> Result<Option<ActiveMode>, error::ActiveModeError> {
+        let state = self
+            .client
+            .state()
+            .await?
+            .into_iter()
+            .find(|state| state.id == id)
+            .ok_or(error::ActiveModeError::MonNotFound(id))?;
+
+        Ok(state.active_mode)
+    }
+
+    /// Find the monitor with the given ID, and return a copy of it.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor(&self, id: Id) -> Option<&Monitor> {
-        self.state.iter().find(|monitor| monitor.id == id)
+    pub fn find_monitor(&self, id: Id) -> Option<Monitor> {
+        self.read_state()
+            .iter()
+            .find(|monitor| monitor.id == id)
+            .cloned()
     }
 
-    /// Find the monitor matched by the given query.
+    /// Find the monitor matched by the given query, and return a copy of it.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_query(&self, query: &str) -> Option<&Monitor> {
+    pub fn find_monitor_query(&self, query: &str) -> Option<Monitor> {
         let id = self.find_id(query)?;
         self.find_monitor(id)
     }
@@ -186,37 +615,26 @@ impl DriverClient {
     ///
     /// Find a monitor by ID and call `cb` with a mutable reference to it.
     ///
+    /// The client state stays locked while `cb` runs, so the change can't
+    /// interleave with changes of other clones. Don't call other methods of
+    /// this client from `cb`, they would deadlock.
+    ///
     /// Note: Any changes do not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_mut<R>(&mut self, id: Id, cb: impl FnOnce(&mut Monitor) -> R) -> Option<R> {
-        let monitor = self.state.iter_mut().find(|monitor| monitor.id == id)?;
+    pub fn find_monitor_mut<R>(&self, id: Id, cb: impl FnOnce(&mut Monitor) -> R) -> Option<R> {
+        let mut state = self.write_state();
+        let monitor = state.iter_mut().find(|monitor| monitor.id == id)?;
 
         let r = cb(monitor);
 
-        mons_have_duplicates(&self.state).ok()?;
+        mons_have_duplicates(&state).ok()?;
 
         Some(r)
     }
 
-    /// Find a monitor by ID and return a mutable reference to it.
-    ///
-    /// Does not check for duplicates (since this is not easy when returning a
-    /// mut reference) Caller agrees they will make sure there are no duplicates.
-    ///
-    /// Despite the "unchecked" part of this name, this is a safe method.
-    ///
-    /// Note: Any changes do not affect the driver. Manually call
-    /// [DriverClient::notify] to send these changes to the driver.
-    ///
-    /// Note: Client state might be stale. To synchronize with the driver,
-    /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_mut_unchecked(&mut self, id: Id) -> Option<&mut Monitor> {
-        self.state.iter_mut().find(|monitor| monitor.id == id)
-    }
-
     /// Find the monitor matched by the given query and call `cb` with a mutable
     /// reference to it.
     ///
@@ -226,7 +644,7 @@ impl DriverClient {
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
     pub fn find_monitor_mut_query<R>(
-        &mut self,
+        &self,
         query: &str,
         cb: impl FnOnce(&mut Monitor) -> R,
     ) -> Option<R> {
@@ -234,32 +652,44 @@ impl DriverClient {
         self.find_monitor_mut(id, cb)
     }
 
-    /// Find the monitor matching the given query.
+    /// Write client state to the registry for current user.
     ///
-    /// Does not check for duplicates (since this is not easy when returning a
-    /// mut reference) Caller agrees they will make sure there are no duplicates.
+    /// Next time the driver is started, it will load this state from the
+    /// registry. This might be after a reboot or a driver restart.
+    pub fn persist(&self) -> Result<(), error::PersistError> {
+        Client::persist(&self.read_state())
+    }
+
+    /// Replace the client state with the monitors written by
+    /// [DriverClient::persist].
     ///
-    /// Despite the "unchecked" part of this name, this is a safe method.
+    /// Lists written by older versions are migrated and written back, see
+    /// [Client::load_persisted]. Fails like [DriverClient::set_monitors].
     ///
-    /// Note: Any changes do not affect the driver. Manually call
+    /// Note: This does not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
+    pub fn load_persisted(&self) -> Result<(), error::SetMonitorsError> {
+        let loaded = Client::load_persisted();
+        self.set_monitors(&loaded.monitors)
+    }
+
+    /// Describe the physical display `device_name` (like `\\.\DISPLAY1`) as
+    /// a new monitor, with the same modes and EDID.
+    ///
+    /// The monitor gets a free ID, but is not added. Pass it to
+    /// [DriverClient::add] and call [DriverClient::notify] to create it. See
+    /// [display_config::clone_physical] for details.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_mut_query_unchecked(&mut self, query: &str) -> Option<&mut Monitor> {
-        let id = self.find_id(query)?;
-        self.find_monitor_mut_unchecked(id)
+    pub fn clone_physical(&self, device_name: &str) -> Result<Monitor, error::CloneError> {
+        let id = self.new_id(None).expect("failed to get a new ID");
+        display_config::clone_physical(device_name, id)
     }
 
-    /// Write client state to the registry for current user.
+    /// Get the closest available free ID, or `preferred_id` if it is given.
     ///
-    /// Next time the driver is started, it will load this state from the
-    /// registry. This might be after a reboot or a driver restart.
-    pub fn persist(&self) -> Result<(), error::PersistError> {
-        Client::persist(&self.state)
-    }
-
-    /// Get the closest available free ID.
+    /// Returns `None` if `preferred_id` is taken already.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
@@ -268,13 +698,13 @@ impl DriverClient {
     /// [DriverClient::notify].
     pub fn new_id(&self, preferred_id: Option<Id>) -> Option<Id> {
         let existing_ids = self
-            .state
+            .read_state()
             .iter()
             .map(|monitor| monitor.id)
             .collect::<HashSet<_>>();
 
         if let Some(id) = preferred_id {
-            if !existing_ids.contains(&id) {
+            if existing_ids.contains(&id) {
                 return None;
             }
 
@@ -297,8 +727,33 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn remove(&mut self, ids: &[Id]) {
-        self.state.retain(|mon| !ids.contains(&mon.id));
+    pub fn remove(&self, ids: &[Id]) {
+        self.write_state().retain(|mon| !ids.contains(&mon.id));
+    }
+
+    /// Remove all monitors tagged with `tag`, see [Monitor::tags].
+    ///
+    /// A monitor is removed if any of its tags is `tag`, regardless of its
+    /// other tags. Returns the IDs of the removed monitors, which is empty if
+    /// no monitor has the tag.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn remove_by_tag(&self, tag: &str) -> Vec<Id> {
+        let mut removed = Vec::new();
+        self.write_state().retain(|mon| {
+            let tagged = mon.has_tag(tag);
+            if tagged {
+                removed.push(mon.id);
+            }
+
+            !tagged
+        });
+
+        removed
     }
 
     /// Remove all monitors matched by the given queries.
@@ -310,10 +765,7 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn remove_query(
-        &mut self,
-        queries: &[impl AsRef<str>],
-    ) -> Result<(), error::QueryNotFound> {
+    pub fn remove_query(&self, queries: &[impl AsRef<str>]) -> Result<(), error::QueryNotFound> {
         let mut ids = Vec::new();
         for id in queries {
             if let Some(id) = self.find_id(id.as_ref()) {
@@ -332,8 +784,8 @@ impl DriverClient {
     ///
     /// Note: This does not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
-    pub fn remove_all(&mut self) {
-        self.state.clear();
+    pub fn remove_all(&self) {
+        self.write_state().clear();
     }
 
     /// Add a new monitor.
@@ -347,17 +799,109 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn add(&mut self, monitor: Monitor) -> Result<(), error::DuplicateError> {
-        if self.state.iter().any(|mon| mon.id == monitor.id) {
+    pub fn add(&self, monitor: Monitor) -> Result<(), error::DuplicateError> {
+        let mut state = self.write_state();
+        if state.iter().any(|mon| mon.id == monitor.id) {
             return Err(error::DuplicateError::Monitor(monitor.id));
         }
         mon_has_duplicates(&monitor)?;
 
-        self.state.push(monitor);
+        state.push(monitor);
 
         Ok(())
     }
 
+    /// Make sure a monitor named like `spec` exists, with the modes and
+    /// enabled state of `spec`, and return its ID.
+    ///
+    /// The name is the key: the first monitor with the same name gets the
+    /// modes and enabled state of `spec`, everything else about it is kept.
+    /// Without one, `spec` is added under the ID of `spec` if that is free,
+    /// or else the closest free ID. Calling this again with the same `spec`
+    /// changes nothing, so it suits desired state tooling.
+    ///
+    /// Returns an error if `spec` has no name, or it is invalid like for
+    /// [DriverClient::add].
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn ensure_monitor(&self, mut spec: Monitor) -> Result<Id, error::EnsureMonitorError> {
+        let Some(name) = spec.name.as_deref() else {
+            return Err(error::EnsureMonitorError::NoName);
+        };
+        mon_has_duplicates(&spec)?;
+
+        let mut state = self.write_state();
+        if let Some(monitor) = state
+            .iter_mut()
+            .find(|mon| mon.name.as_deref() == Some(name))
+        {
+            monitor.modes = spec.modes;
+            monitor.enabled = spec.enabled;
+            return Ok(monitor.id);
+        }
+
+        if state.iter().any(|mon| mon.id == spec.id) {
+            #[allow(clippy::maybe_infinite_iter)]
+            let free = (0..)
+                .find(|id| state.iter().all(|mon| mon.id != *id))
+                .expect("failed to get a new ID");
+            spec.id = free;
+        }
+
+        let id = spec.id;
+        state.push(spec);
+        Ok(id)
+    }
+
+    /// Add a copy of the monitor with the given ID, and return the ID of the
+    /// copy.
+    ///
+    /// The copy has the modes, EDID, color and other settings of the
+    /// original, and is named "<name> (copy)". It gets the first free ID,
+    /// starts out disabled, and has no position, primary flag or serial of
+    /// its own, so it doesn't collide with the original once enabled. A raw
+    /// EDID is copied as it is, so it must be changed before both monitors
+    /// can be sent to the driver, see [Monitor::raw_edid].
+    ///
+    /// Returns an error if the monitor does not exist.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn duplicate_monitor(&self, id: Id) -> Result<Id, error::MonNotFound> {
+        let mut state = self.write_state();
+
+        let original = state
+            .iter()
+            .find(|mon| mon.id == id)
+            .ok_or(error::MonNotFound(id))?;
+
+        #[allow(clippy::maybe_infinite_iter)]
+        let free = (0..)
+            .find(|id| state.iter().all(|mon| mon.id != *id))
+            .expect("failed to get a new ID");
+
+        let copy = Monitor {
+            id: free,
+            name: original.name.as_ref().map(|name| format!("{name} (copy)")),
+            enabled: false,
+            parked: false,
+            position: None,
+            primary: false,
+            serial: None,
+            ..original.clone()
+        };
+
+        state.push(copy);
+        Ok(free)
+    }
+
     /// Set enabled state of all monitors with the given IDs.
     ///
     /// Silently skips incorrect IDs.
@@ -367,8 +911,8 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn set_enabled(&mut self, ids: &[Id], enabled: bool) {
-        for mon in self.state.iter_mut() {
+    pub fn set_enabled(&self, ids: &[Id], enabled: bool) {
+        for mon in self.write_state().iter_mut() {
             if ids.contains(&mon.id) {
                 mon.enabled = enabled;
                 continue;
@@ -376,6 +920,30 @@ impl DriverClient {
         }
     }
 
+    /// Set enabled state of all monitors tagged with `tag`, see
+    /// [Monitor::tags].
+    ///
+    /// A monitor is included if any of its tags is `tag`, so a monitor in
+    /// two groups follows whichever group was set last. Returns the IDs of
+    /// the tagged monitors, which is empty if no monitor has the tag.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn set_enabled_by_tag(&self, tag: &str, enabled: bool) -> Vec<Id> {
+        let mut tagged = Vec::new();
+        for mon in self.write_state().iter_mut() {
+            if mon.has_tag(tag) {
+                mon.enabled = enabled;
+                tagged.push(mon.id);
+            }
+        }
+
+        tagged
+    }
+
     /// Set enabled state of all monitors matched by the given queries.
     ///
     /// Returns an error if a query does not match any monitor.
@@ -386,7 +954,7 @@ impl DriverClient {
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
     pub fn set_enabled_query(
-        &mut self,
+        &self,
         queries: &[impl AsRef<str>],
         enabled: bool,
     ) -> Result<(), error::QueryNotFound> {
@@ -404,6 +972,105 @@ impl DriverClient {
         Ok(())
     }
 
+    /// Set enabled state of all monitors matching `pred`, and send only
+    /// those monitors to the driver in one update.
+    ///
+    /// The other monitors of the driver are kept as they are, so unlike
+    /// [DriverClient::notify] this doesn't send unrelated changes of the
+    /// client state. Monitors which already have the enabled state are
+    /// skipped, and nothing is sent if no monitor changes.
+    ///
+    /// Returns the outcome per monitor, see [Client::merge_monitors]. The
+    /// client state keeps the change even if sending it fails. Drivers
+    /// without apply support return [error::RequestError::Unsupported].
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub async fn set_enabled_where<F: Fn(&Monitor) -> bool>(
+        &self,
+        pred: F,
+        enabled: bool,
+    ) -> Result<ApplyReport, error::RequestError> {
+        let changed = self.toggle_where(pred, enabled);
+        if changed.is_empty() {
+            return Ok(ApplyReport::default());
+        }
+
+        // a debounced state still waiting would undo the toggle once sent
+        let mut pending = self.pending.lock().await;
+        if let Some(state) = pending.as_mut() {
+            for mon in state.iter_mut() {
+                if changed.iter().any(|c| c.id == mon.id) {
+                    mon.enabled = enabled;
+                }
+            }
+        }
+
+        self.client.merge_monitors(&changed).await
+    }
+
+    // the state part of set_enabled_where, the lock must not be held while sending
+    fn toggle_where(&self, pred: impl Fn(&Monitor) -> bool, enabled: bool) -> Vec<Monitor> {
+        let mut state = self.write_state();
+
+        let mut changed = Vec::new();
+        for mon in state.iter_mut() {
+            if mon.enabled != enabled && pred(mon) {
+                mon.enabled = enabled;
+                changed.push(mon.clone());
+            }
+        }
+
+        changed
+    }
+
+    /// Park the monitor with the given ID.
+    ///
+    /// A parked monitor keeps its configuration and stays in the driver's
+    /// list, but is detached from the OS until it is unparked. Its enabled
+    /// state is kept as well. Unlike removing and re-adding a monitor,
+    /// unparking reattaches it on the same connector, so Windows restores its
+    /// layout.
+    ///
+    /// Returns an error if the monitor does not exist.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn park(&self, id: Id) -> Result<(), error::MonNotFound> {
+        self.set_parked(id, true)
+    }
+
+    /// Unpark the monitor with the given ID.
+    ///
+    /// The monitor is reattached if it is enabled. The driver rejects this if
+    /// it would exceed [DriverClient::max_monitors].
+    ///
+    /// Returns an error if the monitor does not exist.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn unpark(&self, id: Id) -> Result<(), error::MonNotFound> {
+        self.set_parked(id, false)
+    }
+
+    fn set_parked(&self, id: Id, parked: bool) -> Result<(), error::MonNotFound> {
+        let mut state = self.write_state();
+        let mon = state
+            .iter_mut()
+            .find(|mon| mon.id == id)
+            .ok_or(error::MonNotFound(id))?;
+
+        mon.parked = parked;
+
+        Ok(())
+    }
+
     /// Add a mode to the monitor with the given ID.
     ///
     /// Returns an error if the monitor does not exist, or if the mode already
@@ -415,8 +1082,9 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn add_mode(&mut self, id: Id, mode: Mode) -> Result<(), error::AddModeError> {
-        let Some(mon) = self.state.iter_mut().find(|mon| mon.id == id) else {
+    pub fn add_mode(&self, id: Id, mode: Mode) -> Result<(), error::AddModeError> {
+        let mut state = self.write_state();
+        let Some(mon) = state.iter_mut().find(|mon| mon.id == id) else {
             return Err(error::AddModeError::MonNotFound(id));
         };
 
@@ -465,11 +1133,7 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn add_mode_query(
-        &mut self,
-        query: &str,
-        mode: Mode,
-    ) -> Result<(), error::AddModeQueryError> {
+    pub fn add_mode_query(&self, query: &str, mode: Mode) -> Result<(), error::AddModeQueryError> {
         let id = self
             .find_id(query)
             .ok_or_else(|| error::AddModeQueryError::QueryNotFound(query.to_owned()))?;
@@ -490,6 +1154,90 @@ impl DriverClient {
         Ok(())
     }
 
+    /// Switch a monitor to another refresh rate of an existing resolution,
+    /// and send the state to the driver.
+    ///
+    /// The refresh rate is added to the mode if missing, as long as the
+    /// driver supports it and the monitor has room for another mode. The mode
+    /// becomes the preferred one, which Windows picks for the monitor.
+    /// Returns an error naming the available resolutions if the monitor has
+    /// no mode with `width` and `height`.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub async fn set_refresh_rate(
+        &self,
+        id: Id,
+        width: Dimen,
+        height: Dimen,
+        refresh_rate: RefreshRate,
+    ) -> Result<(), error::SetRefreshRateError> {
+        self.prefer_refresh_rate(id, width, height, refresh_rate)?;
+        self.notify().await?;
+
+        Ok(())
+    }
+
+    // the state part of set_refresh_rate, the lock must not be held while notifying
+    fn prefer_refresh_rate(
+        &self,
+        id: Id,
+        width: Dimen,
+        height: Dimen,
+        refresh_rate: RefreshRate,
+    ) -> Result<(), error::SetRefreshRateError> {
+        let capabilities = self.capabilities;
+        let mut state = self.write_state();
+        let Some(mon) = state.iter_mut().find(|mon| mon.id == id) else {
+            return Err(error::SetRefreshRateError::MonNotFound(id));
+        };
+
+        let Some(index) = mon
+            .modes
+            .iter()
+            .position(|mode| mode.width == width && mode.height == height)
+        else {
+            let available = mon.modes.iter().map(|mode| (mode.width, mode.height));
+            return Err(error::SetRefreshRateError::ModeNotFound(
+                id,
+                width,
+                height,
+                available.collect(),
+            ));
+        };
+
+        if !mon.modes[index].refresh_rates.contains(&refresh_rate) {
+            if !capabilities.supports(width, height, refresh_rate) {
+                return Err(error::SetRefreshRateError::Unsupported(
+                    id,
+                    width,
+                    height,
+                    refresh_rate,
+                ));
+            }
+
+            let mode_count = mon
+                .modes
+                .iter()
+                .map(|mode| mode.refresh_rates.len())
+                .sum::<usize>();
+            if mode_count >= capabilities.max_modes {
+                return Err(error::SetRefreshRateError::TooManyModes(
+                    id,
+                    capabilities.max_modes,
+                ));
+            }
+        }
+
+        // the preferred mode is the first mode, with its first refresh rate
+        let mut mode = mon.modes.remove(index);
+        mode.refresh_rates.retain(|&rr| rr != refresh_rate);
+        mode.refresh_rates.insert(0, refresh_rate);
+        mon.modes.insert(0, mode);
+
+        Ok(())
+    }
+
     /// Remove a mode from the monitor with the given ID.
     ///
     /// Returns an error if the monitor does not exist. If the mode does not
@@ -500,12 +1248,9 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn remove_mode(
-        &mut self,
-        id: Id,
-        resolution: (u32, u32),
-    ) -> Result<(), error::MonNotFound> {
-        let Some(mon) = self.state.iter_mut().find(|mon| mon.id == id) else {
+    pub fn remove_mode(&self, id: Id, resolution: (u32, u32)) -> Result<(), error::MonNotFound> {
+        let mut state = self.write_state();
+        let Some(mon) = state.iter_mut().find(|mon| mon.id == id) else {
             return Err(error::MonNotFound(id));
         };
 
@@ -526,7 +1271,7 @@ impl DriverClient {
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
     pub fn remove_mode_query(
-        &mut self,
+        &self,
         query: &str,
         resolution: (u32, u32),
     ) -> Result<(), error::QueryNotFound> {
@@ -540,9 +1285,45 @@ impl DriverClient {
         Ok(())
     }
 
+    /// Take a copy of the client state, to go back to it later with
+    /// [DriverClient::restore].
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            monitors: self.monitors(),
+        }
+    }
+
+    /// Make `snapshot` the state of the client and the driver, in one pass.
+    ///
+    /// The snapshot replaces the complete driver state, see
+    /// [Client::set_monitors]. If the driver rejects it, neither state is
+    /// changed. Otherwise the client state becomes the snapshot, even if some
+    /// monitors failed to attach, as the driver keeps them in its state as
+    /// well. A state still waiting in [DriverClient::notify_debounced] is
+    /// dropped, it would undo the restore once sent.
+    ///
+    /// Drivers without apply support return
+    /// [error::RequestError::Unsupported].
+    pub async fn restore(
+        &self,
+        snapshot: StateSnapshot,
+    ) -> Result<ApplyReport, error::RequestError> {
+        let mut pending = self.pending.lock().await;
+        *pending = None;
+
+        let report = self.client.set_monitors(&snapshot.monitors).await?;
+        *self.write_state() = snapshot.monitors;
+
+        Ok(report)
+    }
+
     /// Returns a copy of this client with it's own independent state.
     ///
-    /// Changes to one client will not affect the other.
+    /// Changes to one client will not affect the other. Unlike a clone, which
+    /// shares the state, see [DriverClient#concurrency].
     ///
     /// Note: Event receivers created with [DriverClient::receive_events] will
     /// only be closed after all copies are dropped, regardless of which client
@@ -551,11 +1332,41 @@ impl DriverClient {
         Self {
             client: self.client.clone(),
             state_rx: self.state_rx.clone(),
-            state: self.state.clone(),
+            state: Arc::new(RwLock::new(self.monitors())),
+            capabilities: self.capabilities,
+            pending: Arc::default(),
+            bandwidth_budget: Arc::new(AtomicU64::new(
+                self.bandwidth_budget.load(Ordering::Relaxed),
+            )),
         }
     }
 }
 
+/// The client state at one point in time, see [DriverClient::snapshot].
+///
+/// Serializes as the list of its monitors, so an undo history can be saved
+/// and restored after the application restarts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StateSnapshot {
+    monitors: Vec<Monitor>,
+}
+
+impl StateSnapshot {
+    pub fn monitors(&self) -> &[Monitor] {
+        &self.monitors
+    }
+}
+
+/// A state made elsewhere, e.g. read from a
+/// [config file](crate::config_file::ConfigFile), to apply with
+/// [DriverClient::restore].
+impl From<Vec<Monitor>> for StateSnapshot {
+    fn from(monitors: Vec<Monitor>) -> Self {
+        Self { monitors }
+    }
+}
+
 fn mons_have_duplicates(monitors: &[Monitor]) -> Result<(), error::DuplicateError> {
     let mut monitor_iter = monitors.iter();
     while let Some(monitor) = monitor_iter.next() {
@@ -610,6 +1421,8 @@ fn mode_has_duplicates(mode: &Mode, id: Id) -> Result<(), error::DuplicateError>
 pub mod error {
     use super::*;
     pub use crate::client::error::*;
+    pub use crate::display_config::{CloneError, PositionError};
+    use joinery::JoinableIterator;
     use thiserror::Error;
 
     #[derive(Debug, Error)]
@@ -622,6 +1435,46 @@ pub mod error {
         RefreshRate(u32, u32, u32, Id),
     }
 
+    /// Error returned from [DriverClient::ensure_monitor].
+    #[derive(Debug, Error)]
+    pub enum EnsureMonitorError {
+        #[error("Monitor has no name to match it by")]
+        NoName,
+        #[error(transparent)]
+        Duplicate(#[from] DuplicateError),
+    }
+
+    /// Error returned from [DriverClient::set_monitors].
+    #[derive(Debug, Error)]
+    pub enum SetMonitorsError {
+        #[error(transparent)]
+        Duplicate(#[from] DuplicateError),
+        #[error("Requested {0} enabled monitors, but the driver supports up to {1} monitors")]
+        TooManyMonitors(usize, u32),
+        #[error(
+            "Active monitors need about {} Mbit/s, over the budget of {} Mbit/s ({})",
+            .total / 1_000_000,
+            .budget / 1_000_000,
+            .monitors.iter().map(|(id, bandwidth)| format!("monitor {id}: {} Mbit/s", bandwidth / 1_000_000)).join_with(", ")
+        )]
+        OverBudget {
+            /// Bits per second of all active monitors
+            total: u64,
+            budget: u64,
+            /// Bits per second of each active monitor, largest first
+            monitors: Vec<(Id, u64)>,
+        },
+    }
+
+    /// Error returned from [DriverClient::validate_monitors].
+    #[derive(Debug, Error)]
+    pub enum ValidateError {
+        #[error("Driver rejected monitors: {}", .0.iter().join_with(", "))]
+        Invalid(Vec<MonitorError>),
+        #[error("Failed to validate monitors: {0}")]
+        Request(#[from] RequestError),
+    }
+
     #[derive(Debug, Error)]
     #[error("Query not found: {0}")]
     pub struct QueryNotFound(pub String);
@@ -630,6 +1483,35 @@ pub mod error {
     #[error("Monitor not found: {0}")]
     pub struct MonNotFound(pub Id);
 
+    /// Error returned from [DriverClient::active_mode].
+    #[derive(Debug, Error)]
+    pub enum ActiveModeError {
+        #[error("Monitor not found: {0}")]
+        MonNotFound(Id),
+        #[error("Failed to request state: {0}")]
+        Request(#[from] RequestError),
+    }
+
+    /// Error returned from [DriverClient::apply_and_wait].
+    #[derive(Debug, Error)]
+    pub enum ApplyWaitError {
+        #[error("Failed to apply monitors: {0}")]
+        Apply(RequestError),
+        #[error("Failed to request state: {0}")]
+        Request(RequestError),
+        #[error("Monitor {0} was not attached in time")]
+        NotAttached(Id),
+    }
+
+    /// Error returned from [DriverClient::reset].
+    #[derive(Debug, Error)]
+    pub enum ResetError {
+        #[error("Failed to reset driver: {0}")]
+        Request(#[from] RequestError),
+        #[error("Failed to delete saved monitors: {0}")]
+        Persist(#[from] PersistError),
+    }
+
     /// Error returned from [DriverClient::add_mode].
     #[derive(Debug, Error)]
     pub enum AddModeError {
@@ -652,6 +1534,24 @@ pub mod error {
         DupRefreshRate(u32, u32, u32, Id),
     }
 
+    /// Error returned from [DriverClient::set_refresh_rate].
+    #[derive(Debug, Error)]
+    pub enum SetRefreshRateError {
+        #[error("Monitor not found: {0}")]
+        MonNotFound(Id),
+        #[error(
+            "No mode {1}x{2} on monitor {0}, available: {available}",
+            available = .3.iter().map(|(w, h)| format!("{w}x{h}")).join_with(", ")
+        )]
+        ModeNotFound(Id, Dimen, Dimen, Vec<(Dimen, Dimen)>),
+        #[error("Refresh rate {3} on mode {1}x{2} on monitor {0} is not supported by the driver")]
+        Unsupported(Id, Dimen, Dimen, RefreshRate),
+        #[error("Monitor {0} already has the maximum of {1} modes")]
+        TooManyModes(Id, usize),
+        #[error("Failed to send state: {0}")]
+        Send(#[from] SendError),
+    }
+
     /// Error returned from [DriverClient::new] and [DriverClient::new_with].
     #[derive(Debug, Error)]
     pub enum InitError {
@@ -661,3 +1561,527 @@ pub mod error {
         RequestState(#[from] RequestError),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockServer, MOCK_DRIVER_VERSION, MOCK_MAX_MONITORS};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn new_with_reads_state_and_capabilities() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-new_with_reads_state_and_capabilities";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        server.check_next(|cmd| {
+            assert!(matches!(cmd, ServerCommand::Request(RequestCommand::State)));
+        });
+
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        assert!(client.monitors().is_empty());
+        assert_eq!(
+            client.capabilities(),
+            Capabilities::with_max_monitors(MOCK_MAX_MONITORS)
+        );
+        assert_eq!(client.driver_version(), Some(MOCK_DRIVER_VERSION));
+
+        // the connection keeps working after the startup requests
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        client.add(monitor).unwrap();
+        let (result, ()) = tokio::join!(client.notify_checked(false), server.pump());
+        result.expect("Failed to notify");
+        assert_eq!(server.state(), client.monitors());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn notify_debounced_sends_latest_state() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-notify_debounced_sends_latest_state";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            // state and capabilities
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        for id in 0..3 {
+            let monitor = MonitorBuilder::new(id)
+                .mode(1920, 1080, &[60])
+                .build()
+                .unwrap();
+            client.add(monitor).unwrap();
+            client.notify_debounced(Duration::from_millis(50)).await;
+        }
+
+        // all calls fell into one interval, so only the last state is sent
+        server.pump().await;
+        assert_eq!(server.state(), client.monitors());
+
+        let extra = tokio::time::timeout(Duration::from_millis(200), server.pump()).await;
+        assert!(extra.is_err(), "Debounced state was sent twice");
+
+        // a notify replaces the waiting state
+        client.remove(&[0]);
+        client.notify_debounced(Duration::from_millis(50)).await;
+        client.remove(&[1]);
+        let (result, ()) = tokio::join!(client.notify(), server.pump());
+        result.expect("Failed to notify");
+        assert_eq!(server.state(), client.monitors());
+
+        let extra = tokio::time::timeout(Duration::from_millis(200), server.pump()).await;
+        assert!(extra.is_err(), "Debounced state was sent after notify");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn apply_and_wait_times_out() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-apply_and_wait_times_out";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        let monitor = MonitorBuilder::new(4)
+            .mode(1920, 1080, &[60])
+            .enabled(false)
+            .build()
+            .unwrap();
+        client.add(monitor).unwrap();
+
+        // nothing to wait for
+        let (result, ()) = tokio::join!(client.apply_and_wait(Duration::ZERO), server.pump());
+        result.expect("Failed to apply");
+        assert_eq!(server.state(), client.monitors());
+
+        // the mock never attaches a monitor
+        client.set_enabled(&[4], true);
+        let (result, ()) = tokio::join!(client.apply_and_wait(Duration::ZERO), async {
+            // notify and one state query
+            server.pump().await;
+            server.pump().await;
+        });
+        assert!(matches!(result, Err(error::ApplyWaitError::NotAttached(4))));
+        assert_eq!(server.state(), client.monitors());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn set_refresh_rate_prefers_mode() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-set_refresh_rate_prefers_mode";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        let monitor = MonitorBuilder::new(1)
+            .mode(2560, 1440, &[60])
+            .mode(1920, 1080, &[60, 75])
+            .build()
+            .unwrap();
+        client.add(monitor).unwrap();
+
+        let (result, ()) = tokio::join!(client.set_refresh_rate(1, 1920, 1080, 144), server.pump());
+        result.expect("Failed to set refresh rate");
+        assert_eq!(server.state(), client.monitors());
+
+        let monitors = client.monitors();
+        let modes = &monitors[0].modes;
+        assert_eq!((modes[0].width, modes[0].height), (1920, 1080));
+        assert_eq!(modes[0].refresh_rates, [144, 60, 75]);
+        assert_eq!(modes[1].refresh_rates, [60]);
+
+        let err = client.set_refresh_rate(1, 1280, 720, 60).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No mode 1280x720 on monitor 1, available: 1920x1080, 2560x1440"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn set_enabled_where_sends_matches() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-set_enabled_where_sends_matches";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        for (id, refresh_rate, enabled) in [(0, 60, true), (1, 144, false), (2, 144, true)] {
+            let monitor = MonitorBuilder::new(id)
+                .mode(1920, 1080, &[refresh_rate])
+                .enabled(enabled)
+                .build()
+                .unwrap();
+            client.add(monitor).unwrap();
+        }
+        let (result, ()) = tokio::join!(client.notify(), server.pump());
+        result.expect("Failed to notify");
+
+        let fast = |mon: &Monitor| mon.modes.iter().any(|m| m.refresh_rates.contains(&144));
+
+        // only the monitor which changes is sent
+        server.check_next(|cmd| {
+            let ServerCommand::Driver(DriverCommand::Apply { monitors, mode }) = cmd else {
+                panic!("Expected apply, got {cmd:?}");
+            };
+            assert_eq!(mode, ApplyMode::Merge);
+            assert_eq!(monitors.iter().map(|m| m.id).collect::<Vec<_>>(), [2]);
+        });
+        let (report, ()) = tokio::join!(client.set_enabled_where(fast, false), server.pump());
+        assert_eq!(report.expect("Failed to apply").succeeded, [2]);
+        assert_eq!(server.state(), client.monitors());
+        assert!(client.monitors().iter().all(|m| m.enabled == (m.id == 0)));
+
+        // nothing left to change
+        let report = client.set_enabled_where(fast, false).await;
+        assert_eq!(report.expect("Failed to apply"), ApplyReport::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn restore_applies_snapshot() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-restore_applies_snapshot";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        client.add(MonitorBuilder::new(0).build().unwrap()).unwrap();
+        let (result, ()) = tokio::join!(client.notify(), server.pump());
+        result.expect("Failed to notify");
+
+        let snapshot = client.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(json, serde_json::to_string(&client.monitors()).unwrap());
+        assert_eq!(
+            serde_json::from_str::<StateSnapshot>(&json).unwrap(),
+            snapshot
+        );
+
+        client.add(MonitorBuilder::new(1).build().unwrap()).unwrap();
+        let (result, ()) = tokio::join!(client.notify(), server.pump());
+        result.expect("Failed to notify");
+
+        server.check_next(|cmd| {
+            let ServerCommand::Driver(DriverCommand::Apply { monitors, mode }) = cmd else {
+                panic!("Expected apply, got {cmd:?}");
+            };
+            assert_eq!(mode, ApplyMode::Replace);
+            assert_eq!(monitors.iter().map(|m| m.id).collect::<Vec<_>>(), [0]);
+        });
+        let (report, ()) = tokio::join!(client.restore(snapshot.clone()), server.pump());
+        report.expect("Failed to restore");
+        assert_eq!(client.monitors(), snapshot.monitors());
+        assert_eq!(server.state(), client.monitors());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn refresh_one_updates_single_monitor() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-refresh_one_updates_single_monitor";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        let driver = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        client.add(driver.clone()).unwrap();
+        let (result, ()) = tokio::join!(client.notify(), server.pump());
+        result.expect("Failed to notify");
+
+        // local changes which never reached the driver
+        client.find_monitor_mut(0, |monitor| monitor.enabled = false);
+        client.add(MonitorBuilder::new(1).build().unwrap()).unwrap();
+
+        let (monitor, ()) = tokio::join!(client.refresh_one(0), server.pump());
+        assert_eq!(monitor.unwrap(), Some(driver.clone()));
+
+        let (monitor, ()) = tokio::join!(client.refresh_one(1), server.pump());
+        assert_eq!(monitor.unwrap(), None);
+        assert_eq!(client.monitors(), [driver]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn set_monitors_checks_bandwidth_budget() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-set_monitors_checks_bandwidth_budget";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        let monitors = [
+            MonitorBuilder::new(0)
+                .mode(1920, 1080, &[60])
+                .build()
+                .unwrap(),
+            MonitorBuilder::new(1)
+                .mode(3840, 2160, &[144])
+                .build()
+                .unwrap(),
+            MonitorBuilder::new(2)
+                .mode(7680, 4320, &[60])
+                .enabled(false)
+                .build()
+                .unwrap(),
+        ];
+        let full_hd = 1920 * 1080 * 60 * BANDWIDTH_BITS_PER_PIXEL;
+        let uhd = 3840 * 2160 * 144 * BANDWIDTH_BITS_PER_PIXEL;
+
+        client.set_bandwidth_budget(Some(full_hd));
+        assert_eq!(client.bandwidth_budget(), Some(full_hd));
+
+        let result = client.set_monitors(&monitors);
+        let Err(error::SetMonitorsError::OverBudget {
+            total,
+            budget,
+            monitors: usage,
+        }) = result
+        else {
+            panic!("expected the budget to be exceeded, got {result:?}");
+        };
+        assert_eq!((total, budget), (full_hd + uhd, full_hd));
+        // the disabled monitor doesn't count
+        assert_eq!(usage, [(1, uhd), (0, full_hd)]);
+        assert!(client.monitors().is_empty());
+
+        client.set_bandwidth_budget(None);
+        client.set_monitors(&monitors).unwrap();
+        assert_eq!(client.estimated_bandwidth(), full_hd + uhd);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn duplicate_monitor_copies_config() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-duplicate_monitor_copies_config";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        // a wide gamut monitor with adaptive sync, as tuned for HDR content
+        let tuned = MonitorBuilder::new(0)
+            .name("HDR")
+            .mode(3840, 2160, &[60, 120])
+            .preferred_mode(3840, 2160, 120)
+            .position(1920, 0)
+            .primary(true)
+            .serial(42)
+            // BT.2020 primaries
+            .color(ColorCalibration {
+                gamma: 2.4,
+                red: (0.708, 0.292),
+                green: (0.170, 0.797),
+                blue: (0.131, 0.046),
+                ..ColorCalibration::SRGB
+            })
+            .vrr_range(48, 120)
+            .audio(true)
+            .build()
+            .unwrap();
+        client.add(tuned.clone()).unwrap();
+        client.add(MonitorBuilder::new(1).build().unwrap()).unwrap();
+
+        let id = client.duplicate_monitor(0).unwrap();
+        assert_eq!(id, 2);
+
+        let copy = client.find_monitor(id).unwrap();
+        assert_eq!(copy.name.as_deref(), Some("HDR (copy)"));
+        assert!(!copy.enabled && !copy.primary);
+        assert_eq!(copy.position, None);
+        assert_eq!(copy.edid_serial(), 2);
+        assert_eq!(copy.modes, tuned.modes);
+        assert_eq!(copy.color, tuned.color);
+        assert_eq!(copy.vrr_range, tuned.vrr_range);
+        assert!(copy.audio);
+
+        // the original is untouched, and both can go to the driver
+        assert_eq!(client.find_monitor(0), Some(tuned));
+        assert!(validate_monitors(&client.monitors()).is_ok());
+
+        assert!(matches!(
+            client.duplicate_monitor(7),
+            Err(error::MonNotFound(7))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn new_id_takes_free_ids() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-new_id_takes_free_ids";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        client.add(MonitorBuilder::new(0).build().unwrap()).unwrap();
+
+        assert_eq!(client.new_id(None), Some(1));
+        assert_eq!(client.new_id(Some(5)), Some(5));
+        assert_eq!(client.new_id(Some(0)), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn tags_select_groups() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-tags_select_groups";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        for (id, tags) in [
+            (0, &["streaming"][..]),
+            (1, &["streaming", "testing"][..]),
+            (2, &["testing"][..]),
+            (3, &[][..]),
+        ] {
+            let monitor = tags
+                .iter()
+                .fold(MonitorBuilder::new(id), |builder, &tag| builder.tag(tag))
+                .build()
+                .unwrap();
+            client.add(monitor).unwrap();
+        }
+
+        // a monitor in both groups follows the group changed last
+        assert_eq!(client.set_enabled_by_tag("streaming", false), [0, 1]);
+        assert_eq!(client.set_enabled_by_tag("testing", true), [1, 2]);
+        assert!(client.monitors().iter().all(|m| m.enabled == (m.id != 0)));
+
+        assert!(client.set_enabled_by_tag("unknown", false).is_empty());
+
+        assert_eq!(client.remove_by_tag("testing"), [1, 2]);
+        assert_eq!(
+            client.monitors().iter().map(|m| m.id).collect::<Vec<_>>(),
+            [0, 3]
+        );
+        assert!(client.remove_by_tag("testing").is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn ensure_monitor_is_idempotent() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-ensure_monitor_is_idempotent";
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+
+        client
+            .add(MonitorBuilder::new(0).name("other").build().unwrap())
+            .unwrap();
+
+        let spec = MonitorBuilder::new(0)
+            .name("desk")
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+
+        // ID 0 is taken, so it is created under the next free one
+        assert_eq!(client.ensure_monitor(spec.clone()).unwrap(), 1);
+        let state = client.monitors();
+        assert_eq!(client.ensure_monitor(spec).unwrap(), 1);
+        assert_eq!(client.monitors(), state);
+
+        // only the modes and enabled state are updated
+        client.find_monitor_mut(1, |mon| mon.position = Some((1920, 0)));
+        let spec = MonitorBuilder::new(5)
+            .name("desk")
+            .enabled(false)
+            .mode(2560, 1440, &[144])
+            .build()
+            .unwrap();
+        assert_eq!(client.ensure_monitor(spec.clone()).unwrap(), 1);
+
+        let desk = client.find_monitor(1).unwrap();
+        assert_eq!(desk.modes, spec.modes);
+        assert!(!desk.enabled);
+        assert_eq!(desk.position, Some((1920, 0)));
+        assert_eq!(client.monitors().len(), 2);
+
+        let unnamed = MonitorBuilder::new(2).build().unwrap();
+        assert!(matches!(
+            client.ensure_monitor(unnamed),
+            Err(error::EnsureMonitorError::NoName)
+        ));
+
+        let (result, ()) = tokio::join!(client.notify(), server.pump());
+        result.expect("Failed to notify");
+        assert_eq!(server.state(), client.monitors());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn clones_share_state() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-test-clones_share_state";
+
+        fn assert_shareable<T: Clone + Send + Sync>() {}
+        assert_shareable::<DriverClient>();
+
+        let mut server = MockServer::new(PIPE_NAME);
+        let (client, ()) = tokio::join!(DriverClient::new_with(PIPE_NAME), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = client.expect("Failed to connect");
+        let duplicate = client.duplicate();
+
+        let clone = client.clone();
+        task::spawn(async move {
+            let monitor = MonitorBuilder::new(2)
+                .mode(1920, 1080, &[60])
+                .build()
+                .unwrap();
+            clone.add(monitor).unwrap();
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(client.find_monitor(2).map(|mon| mon.id), Some(2));
+        assert!(duplicate.monitors().is_empty());
+
+        // both clones wait for their own reply at the same time
+        let clone = client.clone();
+        let (a, b, ()) = tokio::join!(client.version(), clone.version(), async {
+            server.pump().await;
+            server.pump().await;
+        });
+        a.expect("Failed to request version");
+        b.expect("Failed to request version");
+
+        let (result, ()) = tokio::join!(clone.notify(), server.pump());
+        result.expect("Failed to notify");
+        assert_eq!(server.state(), client.monitors());
+    }
+}
@@ -0,0 +1,804 @@
+//! Reading EDIDs back into a [Monitor].
+//!
+//! [parse] understands the base block and CTA-861 extension blocks, which is
+//! what the EDIDs generated by the driver and those of nearly all physical
+//! monitors consist of. Timings come from the detailed timing descriptors,
+//! the standard and established timings, and the video data blocks of CTA
+//! extensions. Interlaced timings are left out, virtual monitors can't have
+//! them.
+//!
+//! [ColorCalibration::to_edid] and [CustomTiming::to_dtd] are also what the
+//! driver uses to write the colour characteristics and custom timings into
+//! the EDIDs it generates.
+
+use thiserror::Error;
+
+use crate::{
+    ColorCalibration, CustomTiming, Dimen, Id, Mode, Monitor, RefreshRate, EDID_BLOCK_LEN,
+    EDID_HEADER,
+};
+
+/// Offset of the gamma byte in the base block
+pub const GAMMA_OFFSET: usize = 23;
+/// Offset of the 10 chromaticity bytes in the base block
+pub const CHROMATICITY_OFFSET: usize = 25;
+// gamma byte value telling that the gamma is stored in an extension
+const GAMMA_IN_EXTENSION: u8 = 0xFF;
+
+// display descriptor tags
+const NAME_TAG: u8 = 0xFC;
+const SERIAL_TAG: u8 = 0xFF;
+/// Extension block tag of CTA-861
+pub const CTA_TAG: u8 = 0x02;
+/// Flag in byte 3 of a CTA extension telling that the sink supports basic
+/// audio
+pub const BASIC_AUDIO: u8 = 0x40;
+/// Data block tag of a CTA audio data block
+pub const AUDIO_DATA_BLOCK: u8 = 1;
+// data block tag of a CTA video data block
+const VIDEO_DATA_BLOCK: u8 = 2;
+/// Data block tag of a CTA vendor specific data block
+pub const VENDOR_DATA_BLOCK: u8 = 3;
+/// IEEE OUI of AMD, which identifies the FreeSync vendor specific data block,
+/// in the little endian order of the EDID
+pub const AMD_OUI: [u8; 3] = [0x1A, 0x00, 0x00];
+/// IEEE OUI of the HDMI Licensing vendor specific data block, which marks an
+/// HDMI sink
+pub const HDMI_OUI: [u8; 3] = [0x03, 0x0C, 0x00];
+/// Data block tag of a CTA extended data block, whose first payload byte is
+/// the extended tag
+pub const EXTENDED_DATA_BLOCK: u8 = 7;
+/// Extended tag of a CTA colorimetry data block
+pub const COLORIMETRY_DATA_BLOCK: u8 = 0x05;
+/// Flag in the second byte of a colorimetry data block telling that the sink
+/// supports DCI-P3
+pub const DCI_P3_COLORIMETRY: u8 = 0x80;
+/// Length of a detailed timing or display descriptor
+pub const DESCRIPTOR_LEN: usize = 18;
+
+/// Established timings, by bit from the top of bytes 35 to 37
+const ESTABLISHED: [Option<Timing>; 17] = [
+    Some(Timing::new(720, 400, 70)),
+    Some(Timing::new(720, 400, 88)),
+    Some(Timing::new(640, 480, 60)),
+    Some(Timing::new(640, 480, 67)),
+    Some(Timing::new(640, 480, 72)),
+    Some(Timing::new(640, 480, 75)),
+    Some(Timing::new(800, 600, 56)),
+    Some(Timing::new(800, 600, 60)),
+    Some(Timing::new(800, 600, 72)),
+    Some(Timing::new(800, 600, 75)),
+    Some(Timing::new(832, 624, 75)),
+    // 1024x768 at 87 Hz is interlaced
+    None,
+    Some(Timing::new(1024, 768, 60)),
+    Some(Timing::new(1024, 768, 70)),
+    Some(Timing::new(1024, 768, 75)),
+    Some(Timing::new(1280, 1024, 75)),
+    Some(Timing::new(1152, 870, 75)),
+];
+
+/// Progressive CTA video formats by VIC, rates of the 1000/1001 variants are
+/// rounded up
+const VICS: [(u8, Timing); 26] = [
+    (1, Timing::new(640, 480, 60)),
+    (2, Timing::new(720, 480, 60)),
+    (3, Timing::new(720, 480, 60)),
+    (4, Timing::new(1280, 720, 60)),
+    (16, Timing::new(1920, 1080, 60)),
+    (17, Timing::new(720, 576, 50)),
+    (18, Timing::new(720, 576, 50)),
+    (19, Timing::new(1280, 720, 50)),
+    (31, Timing::new(1920, 1080, 50)),
+    (32, Timing::new(1920, 1080, 24)),
+    (33, Timing::new(1920, 1080, 25)),
+    (34, Timing::new(1920, 1080, 30)),
+    (47, Timing::new(1280, 720, 120)),
+    (60, Timing::new(1280, 720, 24)),
+    (61, Timing::new(1280, 720, 25)),
+    (62, Timing::new(1280, 720, 30)),
+    (63, Timing::new(1920, 1080, 120)),
+    (64, Timing::new(1920, 1080, 100)),
+    (93, Timing::new(3840, 2160, 24)),
+    (94, Timing::new(3840, 2160, 25)),
+    (95, Timing::new(3840, 2160, 30)),
+    (96, Timing::new(3840, 2160, 50)),
+    (97, Timing::new(3840, 2160, 60)),
+    (117, Timing::new(3840, 2160, 100)),
+    (118, Timing::new(3840, 2160, 120)),
+    (119, Timing::new(3840, 2160, 120)),
+];
+
+/// A resolution at a single refresh rate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timing {
+    pub width: Dimen,
+    pub height: Dimen,
+    pub refresh_rate: RefreshRate,
+}
+
+impl Timing {
+    #[must_use]
+    pub const fn new(width: Dimen, height: Dimen, refresh_rate: RefreshRate) -> Self {
+        Self {
+            width,
+            height,
+            refresh_rate,
+        }
+    }
+}
+
+/// What [parse] read from an EDID
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedEdid {
+    /// Three letter PNP ID, `None` if it isn't made of letters
+    pub manufacturer_id: Option<String>,
+    pub product_code: u16,
+    /// Serial number of the header, see [Monitor::edid_serial]
+    pub serial: u32,
+    /// Text of the monitor name descriptor
+    pub name: Option<String>,
+    /// Text of the serial number descriptor
+    pub serial_text: Option<String>,
+    /// Width and height of the screen in centimeters, `None` for projectors
+    /// and EDIDs which don't tell
+    pub size_cm: Option<(u8, u8)>,
+    /// Gamma and chromaticities, `None` if the gamma is stored in an extension
+    pub color: Option<ColorCalibration>,
+    /// Supported timings without duplicates, the preferred one first
+    pub timings: Vec<Timing>,
+    /// Adaptive sync range of the AMD FreeSync data block, see
+    /// [Monitor::vrr_range]
+    pub vrr_range: Option<(u16, u16)>,
+    /// Whether a CTA extension claims basic audio or has an audio data block
+    pub audio: bool,
+}
+
+impl ParsedEdid {
+    /// The timings as modes, merged by resolution in the order of
+    /// [ParsedEdid::timings].
+    #[must_use]
+    pub fn modes(&self) -> Vec<Mode> {
+        let mut modes: Vec<Mode> = Vec::new();
+
+        for timing in &self.timings {
+            match modes
+                .iter_mut()
+                .find(|m| m.width == timing.width && m.height == timing.height)
+            {
+                Some(mode) => mode.refresh_rates.push(timing.refresh_rate),
+                None => modes.push(Mode {
+                    width: timing.width,
+                    height: timing.height,
+                    refresh_rates: vec![timing.refresh_rate],
+                    timing: None,
+                }),
+            }
+        }
+
+        modes
+    }
+
+    /// An enabled monitor with the name, modes, identity and colour
+    /// characteristics of the EDID.
+    ///
+    /// The EDID itself is not attached, the driver generates one from these. Physical monitors may support more modes than the driver
+    /// accepts, so check the result with [Monitor::validate].
+    #[must_use]
+    pub fn to_monitor(&self, id: Id) -> Monitor {
+        Monitor {
+            id,
+            name: self.name.clone(),
+            modes: self.modes(),
+            manufacturer_id: self.manufacturer_id.clone(),
+            product_code: self.product_code,
+            serial: (self.serial != 0).then_some(self.serial),
+            color: self.color,
+            vrr_range: self.vrr_range,
+            audio: self.audio,
+            ..Default::default()
+        }
+    }
+}
+
+/// Error returned from [parse]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum EdidError {
+    #[error("EDID of {0} bytes is not made of whole {len} byte blocks", len = EDID_BLOCK_LEN)]
+    InvalidLength(usize),
+    #[error("EDID does not start with the EDID header")]
+    InvalidHeader,
+    #[error("Checksum of EDID block {0} does not match")]
+    Checksum(usize),
+}
+
+/// Read the identity, name, size and timings of an EDID.
+pub fn parse(edid: &[u8]) -> Result<ParsedEdid, EdidError> {
+    if edid.is_empty() || edid.len() % EDID_BLOCK_LEN != 0 {
+        return Err(EdidError::InvalidLength(edid.len()));
+    }
+
+    if !edid.starts_with(&EDID_HEADER) {
+        return Err(EdidError::InvalidHeader);
+    }
+
+    for (i, block) in edid.chunks(EDID_BLOCK_LEN).enumerate() {
+        if block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(EdidError::Checksum(i));
+        }
+    }
+
+    let base = &edid[..EDID_BLOCK_LEN];
+    let mut parsed = ParsedEdid {
+        manufacturer_id: decode_pnp_id([base[8], base[9]]),
+        product_code: u16::from_le_bytes([base[10], base[11]]),
+        serial: u32::from_le_bytes([base[12], base[13], base[14], base[15]]),
+        name: None,
+        serial_text: None,
+        size_cm: (base[21] != 0 && base[22] != 0).then_some((base[21], base[22])),
+        color: ColorCalibration::from_edid(base),
+        timings: Vec::new(),
+        vrr_range: None,
+        audio: false,
+    };
+
+    // the first detailed timing is the preferred one, so they go first
+    for descriptor in base[54..126].chunks(DESCRIPTOR_LEN) {
+        match detailed_timing(descriptor) {
+            Some(timing) => add(&mut parsed.timings, timing),
+            None => read_text(descriptor, &mut parsed),
+        }
+    }
+
+    for extension in edid[EDID_BLOCK_LEN..].chunks(EDID_BLOCK_LEN) {
+        if extension[0] == CTA_TAG {
+            cta_extension(extension, &mut parsed);
+        }
+    }
+
+    let established = u32::from_be_bytes([base[35], base[36], base[37], 0]);
+    for (bit, timing) in ESTABLISHED.iter().enumerate() {
+        if let Some(timing) = timing.filter(|_| established & (1 << (31 - bit)) != 0) {
+            add(&mut parsed.timings, timing);
+        }
+    }
+
+    let revision = base[19];
+    for standard in base[38..54].chunks(2) {
+        if let Some(timing) = standard_timing(standard[0], standard[1], revision) {
+            add(&mut parsed.timings, timing);
+        }
+    }
+
+    Ok(parsed)
+}
+
+impl ColorCalibration {
+    /// The gamma byte and the chromaticity bytes of an EDID base block.
+    ///
+    /// Values out of range are clamped, check them with [Monitor::validate]
+    /// first.
+    #[must_use]
+    pub fn to_edid(&self) -> (u8, [u8; 10]) {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let gamma = ((self.gamma * 100.0).round() - 100.0).clamp(0.0, 254.0) as u8;
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let [rx, ry, gx, gy, bx, by, wx, wy] = [
+            self.red.0,
+            self.red.1,
+            self.green.0,
+            self.green.1,
+            self.blue.0,
+            self.blue.1,
+            self.white.0,
+            self.white.1,
+        ]
+        .map(|v| (v * 1024.0).round().clamp(0.0, 1023.0) as u16);
+
+        let low = |a: u16, b: u16, c: u16, d: u16| {
+            let bits = (a & 3) << 6 | (b & 3) << 4 | (c & 3) << 2 | (d & 3);
+            bits.to_le_bytes()[0]
+        };
+        let high = |v: u16| (v >> 2).to_le_bytes()[0];
+
+        (
+            gamma,
+            [
+                low(rx, ry, gx, gy),
+                low(bx, by, wx, wy),
+                high(rx),
+                high(ry),
+                high(gx),
+                high(gy),
+                high(bx),
+                high(by),
+                high(wx),
+                high(wy),
+            ],
+        )
+    }
+
+    /// Reads the gamma and chromaticities of an EDID base block, `None` if
+    /// the gamma is stored in an extension.
+    #[must_use]
+    pub fn from_edid(base: &[u8]) -> Option<Self> {
+        let gamma = base[GAMMA_OFFSET];
+        if gamma == GAMMA_IN_EXTENSION {
+            return None;
+        }
+
+        let c = &base[CHROMATICITY_OFFSET..CHROMATICITY_OFFSET + 10];
+        let value = |high: u8, low: u8, shift: u8| {
+            let bits = u16::from(high) << 2 | u16::from(low >> shift) & 3;
+            f32::from(bits) / 1024.0
+        };
+
+        Some(Self {
+            gamma: (f32::from(gamma) + 100.0) / 100.0,
+            red: (value(c[2], c[0], 6), value(c[3], c[0], 4)),
+            green: (value(c[4], c[0], 2), value(c[5], c[0], 0)),
+            blue: (value(c[6], c[1], 6), value(c[7], c[1], 4)),
+            white: (value(c[8], c[1], 2), value(c[9], c[1], 0)),
+        })
+    }
+}
+
+impl CustomTiming {
+    /// The detailed timing descriptor of a mode of `width` x `height` with
+    /// this timing.
+    ///
+    /// Values out of range are cut off, check them with [Monitor::validate]
+    /// first. The image size is left at 0, the base block tells the size.
+    #[must_use]
+    pub fn to_dtd(&self, width: Dimen, height: Dimen) -> [u8; DESCRIPTOR_LEN] {
+        let [clock_low, clock_high, ..] = (self.pixel_clock_khz / 10).to_le_bytes();
+        let low = |v: u32| v.to_le_bytes()[0];
+        let high = |v: u32| (v >> 8).to_le_bytes()[0] & 0x0F;
+        let (h_blank, v_blank) = (self.h_blank(), self.v_blank());
+        let (h_front, h_sync) = (u32::from(self.h_front_porch), u32::from(self.h_sync_width));
+        let (v_front, v_sync) = (u32::from(self.v_front_porch), u32::from(self.v_sync_width));
+
+        // digital separate sync, with the polarities in bits 2 and 1
+        let features =
+            0x18 | u8::from(self.v_sync_positive) << 2 | u8::from(self.h_sync_positive) << 1;
+
+        [
+            clock_low,
+            clock_high,
+            low(width),
+            low(h_blank),
+            high(width) << 4 | high(h_blank),
+            low(height),
+            low(v_blank),
+            high(height) << 4 | high(v_blank),
+            low(h_front),
+            low(h_sync),
+            low(v_front & 0x0F) << 4 | low(v_sync & 0x0F),
+            low(h_front >> 8 & 3) << 6
+                | low(h_sync >> 8 & 3) << 4
+                | low(v_front >> 4 & 3) << 2
+                | low(v_sync >> 4 & 3),
+            0,
+            0,
+            0,
+            0,
+            0,
+            features,
+        ]
+    }
+}
+
+fn add(timings: &mut Vec<Timing>, timing: Timing) {
+    if timing.refresh_rate > 0 && !timings.contains(&timing) {
+        timings.push(timing);
+    }
+}
+
+/// Unpacks a PNP ID of 5 bits per letter, big endian
+fn decode_pnp_id(id: [u8; 2]) -> Option<String> {
+    let id = u16::from_be_bytes(id);
+
+    [10, 5, 0]
+        .into_iter()
+        .map(|shift| match (id >> shift) & 0x1F {
+            #[allow(clippy::cast_possible_truncation)]
+            letter @ 1..=26 => Some(char::from(b'A' - 1 + letter as u8)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Timing of a detailed timing descriptor, `None` for display descriptors
+/// and interlaced timings
+fn detailed_timing(d: &[u8]) -> Option<Timing> {
+    let pixel_clock = u64::from(u16::from_le_bytes([d[0], d[1]])) * 10_000;
+    if pixel_clock == 0 || d[17] & 0x80 != 0 {
+        return None;
+    }
+
+    let width = u32::from(d[2]) | u32::from(d[4] & 0xF0) << 4;
+    let h_blank = u32::from(d[3]) | u32::from(d[4] & 0x0F) << 8;
+    let height = u32::from(d[5]) | u32::from(d[7] & 0xF0) << 4;
+    let v_blank = u32::from(d[6]) | u32::from(d[7] & 0x0F) << 8;
+
+    let total = u64::from(width + h_blank) * u64::from(height + v_blank);
+    if total == 0 {
+        return None;
+    }
+
+    let refresh_rate = RefreshRate::try_from((pixel_clock + total / 2) / total).ok()?;
+    Some(Timing::new(width, height, refresh_rate))
+}
+
+/// Reads the name or serial number of a display descriptor
+fn read_text(d: &[u8], parsed: &mut ParsedEdid) {
+    let target = match d[..5] {
+        [0, 0, 0, NAME_TAG, 0] => &mut parsed.name,
+        [0, 0, 0, SERIAL_TAG, 0] => &mut parsed.serial_text,
+        _ => return,
+    };
+
+    let text = d[5..]
+        .iter()
+        .take_while(|&&b| b != b'\n')
+        .map(|&b| char::from(b))
+        .collect::<String>();
+
+    *target = Some(text.trim_end().to_owned());
+}
+
+/// Timing of a 2 byte standard timing, `None` for unused slots
+fn standard_timing(b0: u8, b1: u8, revision: u8) -> Option<Timing> {
+    if (b0, b1) == (0x01, 0x01) || b0 == 0 {
+        return None;
+    }
+
+    let width = (u32::from(b0) + 31) * 8;
+    let height = match b1 >> 6 {
+        // 16:10 since EDID 1.3, 1:1 before
+        0 if revision >= 3 => width * 10 / 16,
+        0 => width,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+
+    Some(Timing::new(width, height, u32::from(b1 & 0x3F) + 60))
+}
+
+/// Adds the detailed timings, video data block timings, FreeSync range and
+/// audio support of a CTA extension
+fn cta_extension(block: &[u8], parsed: &mut ParsedEdid) {
+    let revision = block[1];
+    if block[3] & BASIC_AUDIO != 0 {
+        parsed.audio = true;
+    }
+
+    // start of the detailed timings, the data blocks come before
+    let dtd_start = usize::from(block[2]).min(EDID_BLOCK_LEN - 1);
+
+    if dtd_start >= 4 {
+        let mut data = &block[4..dtd_start];
+
+        while let Some((&header, rest)) = data.split_first() {
+            let len = usize::from(header & 0x1F).min(rest.len());
+            let (payload, rest) = rest.split_at(len);
+
+            if header >> 5 == VIDEO_DATA_BLOCK {
+                for &svd in payload {
+                    // since revision 3, the top bit of the low VICs marks native formats
+                    let vic = if revision >= 3 && (1..=64).contains(&(svd & 0x7F)) {
+                        svd & 0x7F
+                    } else {
+                        svd
+                    };
+
+                    if let Some((_, timing)) = VICS.iter().find(|(v, _)| *v == vic) {
+                        add(&mut parsed.timings, *timing);
+                    }
+                }
+            }
+
+            if header >> 5 == AUDIO_DATA_BLOCK && !payload.is_empty() {
+                parsed.audio = true;
+            }
+
+            // OUI, version, then the minimum and maximum rate
+            if header >> 5 == VENDOR_DATA_BLOCK && payload.len() >= 7 && payload[..3] == AMD_OUI {
+                parsed.vrr_range = Some((u16::from(payload[5]), u16::from(payload[6])));
+            }
+
+            data = rest;
+        }
+    }
+
+    if dtd_start != 0 {
+        for descriptor in block[dtd_start..EDID_BLOCK_LEN - 1].chunks_exact(DESCRIPTOR_LEN) {
+            match detailed_timing(descriptor) {
+                Some(timing) => add(&mut parsed.timings, timing),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // base block of the driver's generated EDID
+    const GENERATED: [u8; 128] = [
+        0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x0D, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xFF, 0x21, 0x01, 0x03, 0x80, 0x32, 0x1F, 0x78, 0x07, 0xEE, 0x95, 0xA3, 0x54, 0x4C,
+        0x99, 0x26, 0x0F, 0x50, 0x54, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x3A, 0x80, 0x18, 0x71, 0x38,
+        0x2D, 0x40, 0x58, 0x2C, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1E, 0x00, 0x00, 0x00,
+        0xFD, 0x00, 0x17, 0xF0, 0x0F, 0xFF, 0x0F, 0x00, 0x0A, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
+        0x00, 0x00, 0x00, 0xFC, 0x00, 0x56, 0x69, 0x72, 0x74, 0x75, 0x44, 0x69, 0x73, 0x70, 0x6C,
+        0x61, 0x79, 0x2B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x51,
+    ];
+
+    fn fix_checksum(block: &mut [u8]) {
+        let sum = block[..EDID_BLOCK_LEN - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        block[EDID_BLOCK_LEN - 1] = sum.wrapping_neg();
+    }
+
+    #[test]
+    fn parses_generated_edid() {
+        let parsed = parse(&GENERATED).unwrap();
+
+        assert_eq!(
+            parsed,
+            ParsedEdid {
+                manufacturer_id: Some("CHY".to_owned()),
+                product_code: 0,
+                serial: 0,
+                name: Some("VirtuDisplay+".to_owned()),
+                serial_text: None,
+                size_cm: Some((50, 31)),
+                color: parsed.color,
+                timings: vec![Timing::new(1920, 1080, 60)],
+                vrr_range: None,
+                audio: false,
+            }
+        );
+
+        // the built in EDID describes an sRGB display
+        let color = parsed.color.unwrap();
+        assert!((color.gamma - 2.2).abs() < f32::EPSILON);
+        for ((_, (x, y)), (_, (srgb_x, srgb_y))) in color
+            .primaries()
+            .into_iter()
+            .zip(ColorCalibration::SRGB.primaries())
+        {
+            assert!((x - srgb_x).abs() <= 1.0 / 1024.0, "{x} != {srgb_x}");
+            assert!((y - srgb_y).abs() <= 1.0 / 1024.0, "{y} != {srgb_y}");
+        }
+
+        let monitor = parsed.to_monitor(4);
+        assert_eq!(monitor.name.as_deref(), Some("VirtuDisplay+"));
+        assert_eq!(monitor.serial, None);
+        assert_eq!(monitor.edid_serial(), 4);
+        assert_eq!(monitor.validate(), Ok(()));
+    }
+
+    #[test]
+    fn parses_all_timing_sources() {
+        let mut edid = GENERATED.to_vec();
+        // established 640x480@60 and 1024x768@87i, which is left out
+        edid[35] = 0x20;
+        edid[36] = 0x10;
+        // standard 1280x1024@60 (5:4) and 1680x1050@75 (16:10)
+        edid[38..42].copy_from_slice(&[0x81, 0x80, 0xB3, 0x0F]);
+        // one extension
+        edid[126] = 1;
+        fix_checksum(&mut edid);
+
+        let mut cta = vec![0; EDID_BLOCK_LEN];
+        cta[..4].copy_from_slice(&[CTA_TAG, 3, 0, 0]);
+        // video data block with native 1080p60 (again), 2160p60 and an unknown VIC
+        let data_blocks = [0x43, 0x80 | 16, 97, 200];
+        cta[4..8].copy_from_slice(&data_blocks);
+        cta[2] = 8;
+        // 2560x1440@60 detailed timing
+        cta[8..26].copy_from_slice(&[
+            0x56, 0x5E, 0x00, 0xA0, 0xA0, 0xA0, 0x29, 0x50, 0x30, 0x20, 0x35, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x1A,
+        ]);
+        fix_checksum(&mut cta);
+        edid.extend(cta);
+
+        let parsed = parse(&edid).unwrap();
+        assert_eq!(
+            parsed.timings,
+            [
+                Timing::new(1920, 1080, 60),
+                Timing::new(3840, 2160, 60),
+                Timing::new(2560, 1440, 60),
+                Timing::new(640, 480, 60),
+                Timing::new(1280, 1024, 60),
+                Timing::new(1680, 1050, 75),
+            ]
+        );
+
+        assert_eq!(parsed.modes().len(), 6);
+    }
+
+    #[test]
+    fn parses_freesync_range() {
+        let mut edid = GENERATED.to_vec();
+        edid[126] = 1;
+        fix_checksum(&mut edid);
+
+        let mut cta = vec![0; EDID_BLOCK_LEN];
+        cta[..4].copy_from_slice(&[CTA_TAG, 3, 0, 0]);
+        // a vendor block of someone else, then AMD's with 48-144 Hz
+        let data_blocks = [
+            0x63, 0x03, 0x0C, 0x00, 0x68, 0x1A, 0x00, 0x00, 0x01, 0x01, 48, 144, 0x00,
+        ];
+        cta[4..17].copy_from_slice(&data_blocks);
+        cta[2] = 17;
+        fix_checksum(&mut cta);
+        edid.extend(cta);
+
+        let parsed = parse(&edid).unwrap();
+        assert_eq!(parsed.vrr_range, Some((48, 144)));
+        assert_eq!(parsed.to_monitor(0).vrr_range, Some((48, 144)));
+        assert!(!parsed.audio);
+    }
+
+    #[test]
+    fn parses_audio_support() {
+        let mut edid = GENERATED.to_vec();
+        edid[126] = 1;
+        fix_checksum(&mut edid);
+
+        let mut cta = vec![0; EDID_BLOCK_LEN];
+        cta[..4].copy_from_slice(&[CTA_TAG, 3, 4, 0]);
+        fix_checksum(&mut cta);
+        edid.extend(&cta);
+        assert!(!parse(&edid).unwrap().audio);
+
+        // the basic audio flag alone
+        cta[3] = BASIC_AUDIO;
+        fix_checksum(&mut cta);
+        edid[EDID_BLOCK_LEN..].copy_from_slice(&cta);
+        assert!(parse(&edid).unwrap().to_monitor(0).audio);
+
+        // an audio data block alone, with 2 channel PCM
+        cta[2..8].copy_from_slice(&[8, 0, 0x23, 0x09, 0x07, 0x07]);
+        fix_checksum(&mut cta);
+        edid[EDID_BLOCK_LEN..].copy_from_slice(&cta);
+        assert!(parse(&edid).unwrap().audio);
+    }
+
+    #[test]
+    fn merges_modes() {
+        let parsed = ParsedEdid {
+            manufacturer_id: None,
+            product_code: 0,
+            serial: 0,
+            name: None,
+            serial_text: None,
+            size_cm: None,
+            color: None,
+            timings: vec![
+                Timing::new(1920, 1080, 60),
+                Timing::new(1280, 720, 60),
+                Timing::new(1920, 1080, 120),
+            ],
+            vrr_range: None,
+            audio: false,
+        };
+
+        assert_eq!(
+            parsed.modes(),
+            [
+                Mode {
+                    width: 1920,
+                    height: 1080,
+                    refresh_rates: vec![60, 120],
+                    timing: None,
+                },
+                Mode {
+                    width: 1280,
+                    height: 720,
+                    refresh_rates: vec![60],
+                    timing: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn color_round_trips() {
+        let color = ColorCalibration {
+            gamma: 2.4,
+            red: (0.68, 0.32),
+            green: (0.265, 0.69),
+            blue: (0.15, 0.06),
+            white: (0.314, 0.351),
+        };
+
+        let mut edid = GENERATED;
+        let (gamma, chromaticity) = color.to_edid();
+        edid[GAMMA_OFFSET] = gamma;
+        edid[CHROMATICITY_OFFSET..CHROMATICITY_OFFSET + 10].copy_from_slice(&chromaticity);
+
+        let parsed = ColorCalibration::from_edid(&edid).unwrap();
+        assert!((parsed.gamma - 2.4).abs() < f32::EPSILON);
+        for ((name, (x, y)), (_, (expected_x, expected_y))) in
+            parsed.primaries().into_iter().zip(color.primaries())
+        {
+            // rounded to 10 bits
+            assert!((x - expected_x).abs() <= 0.5 / 1024.0, "{name} x");
+            assert!((y - expected_y).abs() <= 0.5 / 1024.0, "{name} y");
+        }
+
+        // the same bits, so nothing changes on the way back
+        assert_eq!(parsed.to_edid(), (gamma, chromaticity));
+
+        edid[GAMMA_OFFSET] = 0xFF;
+        assert_eq!(ColorCalibration::from_edid(&edid), None);
+    }
+
+    #[test]
+    fn writes_detailed_timings() {
+        // CTA-861 1080p60, the preferred timing of the generated EDID
+        let timing = CustomTiming {
+            pixel_clock_khz: 148_500,
+            h_front_porch: 88,
+            h_sync_width: 44,
+            h_back_porch: 148,
+            v_front_porch: 4,
+            v_sync_width: 5,
+            v_back_porch: 36,
+            h_sync_positive: true,
+            v_sync_positive: true,
+        };
+        assert_eq!(timing.to_dtd(1920, 1080), GENERATED[54..72]);
+
+        // the high bits of the porches and syncs go into byte 11
+        let timing = CustomTiming {
+            pixel_clock_khz: 241_500,
+            h_front_porch: 300,
+            h_sync_width: 260,
+            h_back_porch: 80,
+            v_front_porch: 20,
+            v_sync_width: 40,
+            v_back_porch: 6,
+            h_sync_positive: true,
+            v_sync_positive: false,
+        };
+        let mut edid = GENERATED;
+        edid[54..72].copy_from_slice(&timing.to_dtd(2560, 1440));
+        assert_eq!(edid[65], 0b0101_0110);
+        assert_eq!(edid[71], 0x1A);
+        fix_checksum(&mut edid);
+
+        let parsed = parse(&edid).unwrap();
+        assert_eq!(parsed.timings[0], Timing::new(2560, 1440, 50));
+    }
+
+    #[test]
+    fn rejects_broken_edids() {
+        assert_eq!(parse(&[]), Err(EdidError::InvalidLength(0)));
+        assert_eq!(parse(&GENERATED[..100]), Err(EdidError::InvalidLength(100)));
+        assert_eq!(parse(&[0; 128]), Err(EdidError::InvalidHeader));
+
+        let mut edid = GENERATED.to_vec();
+        edid[20] ^= 1;
+        assert_eq!(parse(&edid), Err(EdidError::Checksum(0)));
+
+        let mut edid = GENERATED.to_vec();
+        edid[126] = 1;
+        fix_checksum(&mut edid);
+        edid.push(CTA_TAG);
+        edid.extend([0; EDID_BLOCK_LEN - 1]);
+        assert_eq!(parse(&edid), Err(EdidError::Checksum(1)));
+    }
+}
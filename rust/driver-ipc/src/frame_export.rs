@@ -0,0 +1,924 @@
+//! Shared memory layout of exported frames, see
+//! [DriverCommand::SetFrameExport](crate::DriverCommand::SetFrameExport).
+//!
+//! An export named `{name}` consists of:
+//!
+//! - a control section `{name}`, holding the current generation and whether
+//!   the export was closed.
+//! - a frame section `{name}-{generation}` per generation. The driver creates
+//!   generation 1 with the first frame, and a new one with the next
+//!   generation whenever the resolution of the monitor changes. It never
+//!   touches the old one again.
+//! - an auto reset event `{name}-Event`, signaled after every frame, when the
+//!   generation changes and when the export is closed.
+//!
+//! A frame section starts with a header describing the frames, followed by
+//! [FRAME_SLOTS] slots used as a ring. Each slot is a small header with the
+//! sequence number and presentation time of its frame and the regions which
+//! changed since the previous frame, followed by the BGRA pixels,
+//! `stride * height` bytes. Frames are numbered from 1 on, across
+//! generations, and frame `n` goes into slot `n % FRAME_SLOTS`.
+//!
+//! The driver never waits for a consumer. It clears the sequence number of a
+//! slot while it writes into it, so a reader which was overtaken notices from
+//! the sequence number having changed, see [Frame::is_intact].
+//!
+//! [FrameReceiver] is the consumer side. Only one receiver should wait on an
+//! export at a time, since each signal of the event wakes a single waiter.
+
+use std::{
+    fmt,
+    ptr::NonNull,
+    slice,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use thiserror::Error;
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0, WAIT_TIMEOUT},
+        System::{
+            Memory::{
+                MapViewOfFile, OpenFileMappingW, UnmapViewOfFile, VirtualQuery, FILE_MAP_READ,
+                MEMORY_BASIC_INFORMATION, MEMORY_MAPPED_VIEW_ADDRESS,
+            },
+            Threading::{OpenEventW, WaitForSingleObject, INFINITE, SYNCHRONIZATION_SYNCHRONIZE},
+        },
+    },
+};
+
+/// Number of frames a frame section holds
+pub const FRAME_SLOTS: u32 = 3;
+/// Version of the section layout, a receiver refuses sections of other
+/// versions
+pub const LAYOUT_VERSION: u32 = 2;
+/// Most changed regions a slot holds, a frame with more is marked as changed
+/// entirely, see [Frame::dirty_rects]
+pub const MAX_DIRTY_RECTS: usize = 32;
+/// First bytes of every control and frame section
+const MAGIC: u64 = u64::from_le_bytes(*b"VDDFRAME");
+/// Headers are padded to this, so pixel rows start on a cache line
+const ALIGN: usize = 64;
+
+/// Name of the frame section of `generation`, see the [module](self) docs
+#[must_use]
+pub fn frames_name(name: &str, generation: u64) -> String {
+    format!("{name}-{generation}")
+}
+
+/// Name of the event of an export, see the [module](self) docs
+#[must_use]
+pub fn event_name(name: &str) -> String {
+    format!("{name}-Event")
+}
+
+/// Size and layout of the frames of one generation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub width: u32,
+    pub height: u32,
+    /// Bytes from the start of one pixel row to the next
+    pub stride: u32,
+}
+
+impl FrameFormat {
+    /// Bytes of pixel data of a frame
+    #[must_use]
+    pub fn data_len(self) -> usize {
+        self.stride as usize * self.height as usize
+    }
+
+    /// Bytes of a slot, header included
+    fn slot_len(self) -> usize {
+        (SLOT_HEADER_LEN + self.data_len()).next_multiple_of(ALIGN)
+    }
+
+    /// Bytes of a frame section holding frames of this format
+    #[must_use]
+    pub fn section_len(self) -> usize {
+        size_of::<SectionHeader>() + FRAME_SLOTS as usize * self.slot_len()
+    }
+}
+
+/// Region of a frame, in pixels, `right` and `bottom` exclusive
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FrameRect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// Bytes of a control section
+pub const CONTROL_LEN: usize = size_of::<ControlHeader>();
+
+#[repr(C)]
+struct ControlHeader {
+    magic: u64,
+    generation: AtomicU64,
+    closed: AtomicU64,
+    _reserved: [u64; 5],
+}
+
+#[repr(C)]
+struct SectionHeader {
+    magic: u64,
+    version: u32,
+    slots: u32,
+    generation: u64,
+    width: u32,
+    height: u32,
+    stride: u32,
+    _reserved: u32,
+    // sequence number of the newest complete frame, 0 before the first one
+    latest: AtomicU64,
+    _reserved2: [u64; 2],
+}
+
+#[repr(C)]
+struct SlotHeader {
+    // 0 while the slot is written
+    sequence: AtomicU64,
+    qpc: AtomicU64,
+    _reserved: [u64; 6],
+}
+
+// set when the whole frame has to be treated as changed
+const ENTIRE_FRAME: u32 = 1;
+
+/// Changed regions of a frame, stored in its slot right after the
+/// [SlotHeader]. Apart from it, since it isn't written atomically.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct DirtyRects {
+    count: u32,
+    flags: u32,
+    rects: [FrameRect; MAX_DIRTY_RECTS],
+    _reserved: [u32; 14],
+}
+
+impl DirtyRects {
+    /// `None` and more than [MAX_DIRTY_RECTS] regions are stored as the entire frame
+    fn new(rects: Option<&[FrameRect]>) -> Self {
+        let mut dirty = Self {
+            count: 0,
+            flags: ENTIRE_FRAME,
+            rects: [FrameRect::default(); MAX_DIRTY_RECTS],
+            _reserved: [0; 14],
+        };
+
+        if let Some(rects) = rects.filter(|rects| rects.len() <= MAX_DIRTY_RECTS) {
+            dirty.rects[..rects.len()].copy_from_slice(rects);
+            #[allow(clippy::cast_possible_truncation)]
+            let count = rects.len() as u32;
+            dirty.count = count;
+            dirty.flags = 0;
+        }
+
+        dirty
+    }
+
+    /// The stored regions, `None` for the entire frame
+    fn get(&self) -> Option<&[FrameRect]> {
+        if self.flags & ENTIRE_FRAME != 0 {
+            return None;
+        }
+
+        // written by another process, so it isn't trusted to fit
+        let count = (self.count as usize).min(MAX_DIRTY_RECTS);
+        Some(&self.rects[..count])
+    }
+}
+
+const _: () = assert!(size_of::<ControlHeader>() == ALIGN);
+const _: () = assert!(size_of::<SectionHeader>() == ALIGN);
+const _: () = assert!(size_of::<SlotHeader>() == ALIGN);
+const _: () = assert!(size_of::<DirtyRects>() % ALIGN == 0);
+
+/// Bytes in front of the pixels of a slot
+const SLOT_HEADER_LEN: usize = size_of::<SlotHeader>() + size_of::<DirtyRects>();
+
+/// Error of opening or reading an export.
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("No new frame within {0:?}")]
+    Timeout(Duration),
+    #[error("Frame export was disabled")]
+    Closed,
+    #[error("Shared memory has an unknown layout")]
+    Layout,
+    #[error("Failed to open shared memory: {0}")]
+    Os(#[from] windows::core::Error),
+}
+
+/// Writes the control section, used by the driver
+#[derive(Debug)]
+pub struct ControlWriter(NonNull<ControlHeader>);
+
+unsafe impl Send for ControlWriter {}
+
+impl ControlWriter {
+    /// Initializes the control section at `ptr` with generation 0, which has
+    /// no frame section.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to [CONTROL_LEN] writable bytes, aligned to 8, which
+    /// stay mapped as long as the writer exists.
+    #[must_use]
+    pub unsafe fn init(ptr: NonNull<u8>) -> Self {
+        let header = ptr.cast::<ControlHeader>();
+        unsafe {
+            header.write(ControlHeader {
+                magic: MAGIC,
+                generation: AtomicU64::new(0),
+                closed: AtomicU64::new(0),
+                _reserved: [0; 5],
+            });
+        }
+
+        Self(header)
+    }
+
+    /// Points receivers at the frame section of `generation`, which must be
+    /// initialized already.
+    pub fn set_generation(&self, generation: u64) {
+        self.header()
+            .generation
+            .store(generation, Ordering::Release);
+    }
+
+    /// Tells receivers the export ended
+    pub fn close(&self) {
+        self.header().closed.store(1, Ordering::Release);
+    }
+
+    fn header(&self) -> &ControlHeader {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+/// Writes frames into a frame section, used by the driver
+#[derive(Debug)]
+pub struct FrameWriter {
+    section: Section,
+}
+
+unsafe impl Send for FrameWriter {}
+
+impl FrameWriter {
+    /// Initializes the frame section of `generation` at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to [FrameFormat::section_len] writable bytes, aligned
+    /// to 8, which stay mapped as long as the writer exists.
+    #[must_use]
+    pub unsafe fn init(ptr: NonNull<u8>, generation: u64, format: FrameFormat) -> Self {
+        unsafe {
+            ptr.cast::<SectionHeader>().write(SectionHeader {
+                magic: MAGIC,
+                version: LAYOUT_VERSION,
+                slots: FRAME_SLOTS,
+                generation,
+                width: format.width,
+                height: format.height,
+                stride: format.stride,
+                _reserved: 0,
+                latest: AtomicU64::new(0),
+                _reserved2: [0; 2],
+            });
+        }
+
+        let section = Section {
+            ptr,
+            generation,
+            format,
+        };
+
+        for slot in 0..FRAME_SLOTS {
+            let header = section.slot(u64::from(slot)).cast::<SlotHeader>();
+            unsafe {
+                header.write(SlotHeader {
+                    sequence: AtomicU64::new(0),
+                    qpc: AtomicU64::new(0),
+                    _reserved: [0; 6],
+                });
+            }
+
+            let dirty = unsafe { header.cast::<u8>().add(size_of::<SlotHeader>()) };
+            unsafe { dirty.cast::<DirtyRects>().write(DirtyRects::new(None)) };
+        }
+
+        Self { section }
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.section.format
+    }
+
+    /// Writes frame `sequence`, presented at `qpc`, with `fill` writing the
+    /// pixels into the slot. `sequence` must be higher than the one of the
+    /// previous frame.
+    ///
+    /// `dirty` are the regions which changed since the previous frame, `None`
+    /// if they aren't known.
+    pub fn write(
+        &mut self,
+        sequence: u64,
+        qpc: u64,
+        dirty: Option<&[FrameRect]>,
+        fill: impl FnOnce(&mut [u8]),
+    ) {
+        debug_assert_ne!(sequence, 0, "frames are numbered from 1 on");
+
+        let slot = self.section.slot(sequence);
+        let header = unsafe { slot.cast::<SlotHeader>().as_ref() };
+
+        header.sequence.store(0, Ordering::Relaxed);
+        // the cleared sequence is visible before any pixel changes
+        fence(Ordering::Release);
+
+        let data = unsafe { slot.add(SLOT_HEADER_LEN) };
+        let data =
+            unsafe { slice::from_raw_parts_mut(data.as_ptr(), self.section.format.data_len()) };
+        fill(data);
+
+        // like the pixels, guarded by the sequence
+        let rects = unsafe { slot.add(size_of::<SlotHeader>()) };
+        unsafe { rects.cast::<DirtyRects>().write(DirtyRects::new(dirty)) };
+
+        header.qpc.store(qpc, Ordering::Relaxed);
+        header.sequence.store(sequence, Ordering::Release);
+        self.section
+            .header()
+            .latest
+            .store(sequence, Ordering::Release);
+    }
+}
+
+/// Reads frames out of a frame section
+#[derive(Debug)]
+struct FrameReader {
+    section: Section,
+}
+
+impl FrameReader {
+    /// Checks the frame section of `len` bytes at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes, aligned to 8, which stay
+    /// mapped as long as the reader exists.
+    unsafe fn new(ptr: NonNull<u8>, len: usize) -> Result<Self, FrameError> {
+        if len < size_of::<SectionHeader>() {
+            return Err(FrameError::Layout);
+        }
+
+        let header = unsafe { ptr.cast::<SectionHeader>().as_ref() };
+        if header.magic != MAGIC
+            || header.version != LAYOUT_VERSION
+            || header.slots != FRAME_SLOTS
+            || header.stride < header.width.saturating_mul(4)
+        {
+            return Err(FrameError::Layout);
+        }
+
+        let format = FrameFormat {
+            width: header.width,
+            height: header.height,
+            stride: header.stride,
+        };
+
+        // the header comes from another process, it must not point past the mapping
+        if format.section_len() > len {
+            return Err(FrameError::Layout);
+        }
+
+        Ok(Self {
+            section: Section {
+                ptr,
+                generation: header.generation,
+                format,
+            },
+        })
+    }
+
+    /// Sequence number of the newest complete frame, 0 if there is none yet
+    fn latest(&self) -> u64 {
+        self.section.header().latest.load(Ordering::Acquire)
+    }
+
+    /// Frame `sequence`, which may have been overwritten already
+    fn frame(&self, sequence: u64) -> Frame<'_> {
+        let slot = self.section.slot(sequence);
+        let header = unsafe { slot.cast::<SlotHeader>().as_ref() };
+
+        let qpc = header.qpc.load(Ordering::Relaxed);
+        // pairs with the release store of the writer, so the pixels are complete unless the
+        // sequence changed
+        let intact = header.sequence.load(Ordering::Acquire) == sequence;
+
+        let data = unsafe { slot.add(SLOT_HEADER_LEN) };
+        let data = unsafe { slice::from_raw_parts(data.as_ptr(), self.section.format.data_len()) };
+
+        let dirty = unsafe { slot.add(size_of::<SlotHeader>()) };
+        let dirty = unsafe { dirty.cast::<DirtyRects>().as_ref() };
+
+        Frame {
+            sequence,
+            generation: self.section.generation,
+            format: self.section.format,
+            qpc,
+            data,
+            dirty,
+            slot_sequence: &header.sequence,
+            intact,
+        }
+    }
+}
+
+/// Frame section at a known address
+#[derive(Debug)]
+struct Section {
+    ptr: NonNull<u8>,
+    generation: u64,
+    format: FrameFormat,
+}
+
+impl Section {
+    fn header(&self) -> &SectionHeader {
+        unsafe { self.ptr.cast::<SectionHeader>().as_ref() }
+    }
+
+    /// Start of the slot of frame `sequence`
+    fn slot(&self, sequence: u64) -> NonNull<u8> {
+        #[allow(clippy::cast_possible_truncation)]
+        let index = (sequence % u64::from(FRAME_SLOTS)) as usize;
+
+        unsafe {
+            self.ptr
+                .add(size_of::<SectionHeader>() + index * self.format.slot_len())
+        }
+    }
+}
+
+/// An exported frame, see [FrameReceiver::next_frame].
+///
+/// The pixels are read straight from shared memory, which the driver
+/// overwrites [FRAME_SLOTS] frames later without waiting. Check
+/// [Frame::is_intact] after using [Frame::data].
+pub struct Frame<'a> {
+    sequence: u64,
+    generation: u64,
+    format: FrameFormat,
+    qpc: u64,
+    data: &'a [u8],
+    dirty: &'a DirtyRects,
+    slot_sequence: &'a AtomicU64,
+    intact: bool,
+}
+
+// without the pixels, which are megabytes
+impl fmt::Debug for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("sequence", &self.sequence)
+            .field("generation", &self.generation)
+            .field("format", &self.format)
+            .field("qpc", &self.qpc)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Frame<'_> {
+    /// Number of the frame, counting up from 1 across generations.
+    ///
+    /// A gap to the previous frame means frames were missed.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Increases every time the resolution changed
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn width(&self) -> u32 {
+        self.format.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.format.height
+    }
+
+    /// Bytes from the start of one pixel row to the next
+    pub fn stride(&self) -> u32 {
+        self.format.stride
+    }
+
+    /// QueryPerformanceCounter value at which Windows presented the frame
+    pub fn qpc(&self) -> u64 {
+        self.qpc
+    }
+
+    /// BGRA pixels, [Frame::height] rows of [Frame::stride] bytes.
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    /// Regions which changed since frame `sequence - 1`, `None` if the whole
+    /// frame has to be treated as changed.
+    ///
+    /// That is the case when Windows didn't report the regions, when there
+    /// were more than [MAX_DIRTY_RECTS], and for the first frame of a
+    /// generation. A receiver which missed frames has to treat the whole frame
+    /// as changed as well. Like [Frame::data], this is only valid while the
+    /// frame [is intact](Frame::is_intact).
+    pub fn dirty_rects(&self) -> Option<&[FrameRect]> {
+        self.dirty.get()
+    }
+
+    /// Whether the driver left the frame alone so far, i.e. everything read
+    /// from [Frame::data] up to now belongs to this frame.
+    pub fn is_intact(&self) -> bool {
+        // reads of the pixels happen before reading the sequence again
+        fence(Ordering::Acquire);
+        self.intact && self.slot_sequence.load(Ordering::Relaxed) == self.sequence
+    }
+}
+
+/// Consumer side of a frame export.
+///
+/// ```no_run
+/// # use driver_ipc::{frame_export::FrameReceiver, Client};
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = Client::connect().await?;
+/// let name = client.set_frame_export(0, true).await?.unwrap();
+///
+/// let mut receiver = FrameReceiver::open(&name)?;
+/// loop {
+///     let frame = receiver.next_frame()?;
+///     let pixels = frame.data().to_vec();
+///     if frame.is_intact() {
+///         // use pixels
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct FrameReceiver {
+    name: String,
+    control: View,
+    event: Handle,
+    // mapping of the current generation, the reader points into it
+    frames: Option<(FrameReader, View)>,
+    // sequence number of the last frame returned
+    last: u64,
+}
+
+unsafe impl Send for FrameReceiver {}
+
+impl FrameReceiver {
+    /// Opens the export `name`, as returned when enabling it.
+    pub fn open(name: &str) -> Result<Self, FrameError> {
+        let control = View::open(name)?;
+        let header = unsafe { control.ptr.cast::<ControlHeader>().as_ref() };
+        if control.len < size_of::<ControlHeader>() || header.magic != MAGIC {
+            return Err(FrameError::Layout);
+        }
+
+        let event = unsafe {
+            OpenEventW(
+                SYNCHRONIZATION_SYNCHRONIZE,
+                false,
+                &HSTRING::from(event_name(name)),
+            )?
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            control,
+            event: Handle(event),
+            frames: None,
+            last: 0,
+        })
+    }
+
+    /// Blocks until there is a frame newer than the last one returned.
+    ///
+    /// Returns [FrameError::Closed] once the export was disabled or the
+    /// monitor went away.
+    pub fn next_frame(&mut self) -> Result<Frame<'_>, FrameError> {
+        self.next_frame_within(None)
+    }
+
+    /// [FrameReceiver::next_frame], but gives up with
+    /// [FrameError::Timeout] after `timeout`.
+    pub fn next_frame_timeout(&mut self, timeout: Duration) -> Result<Frame<'_>, FrameError> {
+        self.next_frame_within(Some(timeout))
+    }
+
+    fn next_frame_within(&mut self, timeout: Option<Duration>) -> Result<Frame<'_>, FrameError> {
+        let sequence = loop {
+            let latest = self.poll()?;
+            if latest > self.last {
+                break latest;
+            }
+
+            // the event is set by every change since the last wait, so none is missed
+            let millis = timeout.map_or(INFINITE, |timeout| {
+                u32::try_from(timeout.as_millis()).unwrap_or(INFINITE - 1)
+            });
+            let result = unsafe { WaitForSingleObject(self.event.0, millis) };
+
+            if result == WAIT_TIMEOUT {
+                return Err(FrameError::Timeout(timeout.unwrap_or_default()));
+            }
+            if result != WAIT_OBJECT_0 {
+                return Err(windows::core::Error::from_win32().into());
+            }
+        };
+
+        self.last = sequence;
+        let (reader, _) = self.frames.as_ref().expect("polled a frame section");
+        Ok(reader.frame(sequence))
+    }
+
+    /// Follows the control section, returns the sequence number of the
+    /// newest frame, 0 if there is none
+    fn poll(&mut self) -> Result<u64, FrameError> {
+        let control = unsafe { self.control.ptr.cast::<ControlHeader>().as_ref() };
+
+        if control.closed.load(Ordering::Acquire) != 0 {
+            return Err(FrameError::Closed);
+        }
+
+        let generation = control.generation.load(Ordering::Acquire);
+
+        // no frame yet
+        if generation == 0 {
+            return Ok(0);
+        }
+
+        let current = self
+            .frames
+            .as_ref()
+            .map(|(reader, _)| reader.section.generation);
+        if current != Some(generation) {
+            // the old section is unmapped only after the new one is open, so a failure
+            // leaves the receiver as it was
+            let view = View::open(&frames_name(&self.name, generation))?;
+            let reader = unsafe { FrameReader::new(view.ptr, view.len)? };
+            self.frames = Some((reader, view));
+        }
+
+        Ok(self
+            .frames
+            .as_ref()
+            .map_or(0, |(reader, _)| reader.latest()))
+    }
+}
+
+/// Read only view of a named section
+#[derive(Debug)]
+struct View {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl View {
+    fn open(name: &str) -> Result<Self, FrameError> {
+        let mapping = unsafe { OpenFileMappingW(FILE_MAP_READ.0, false, &HSTRING::from(name))? };
+        let mapping = Handle(mapping);
+
+        // the view keeps the section alive on its own
+        let address = unsafe { MapViewOfFile(mapping.0, FILE_MAP_READ, 0, 0, 0) };
+        let ptr = NonNull::new(address.Value.cast::<u8>())
+            .ok_or_else(windows::core::Error::from_win32)?;
+
+        // the size of the section, the headers in it are not trusted for that
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let written = unsafe {
+            VirtualQuery(
+                Some(ptr.as_ptr().cast_const().cast()),
+                &mut info,
+                size_of::<MEMORY_BASIC_INFORMATION>(),
+            )
+        };
+
+        let view = Self {
+            ptr,
+            len: info.RegionSize,
+        };
+
+        if written == 0 {
+            return Err(windows::core::Error::from_win32().into());
+        }
+
+        Ok(view)
+    }
+}
+
+impl Drop for View {
+    fn drop(&mut self) {
+        let address = MEMORY_MAPPED_VIEW_ADDRESS {
+            Value: self.ptr.as_ptr().cast(),
+        };
+        _ = unsafe { UnmapViewOfFile(address) };
+    }
+}
+
+#[derive(Debug)]
+struct Handle(HANDLE);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        _ = unsafe { CloseHandle(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FORMAT: FrameFormat = FrameFormat {
+        width: 3,
+        height: 2,
+        stride: 16,
+    };
+
+    // u64s keep the headers aligned
+    fn section(format: FrameFormat) -> Vec<u64> {
+        vec![0; format.section_len().div_ceil(8)]
+    }
+
+    fn ptr(buffer: &mut [u64]) -> NonNull<u8> {
+        NonNull::new(buffer.as_mut_ptr().cast()).unwrap()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buffer = section(FORMAT);
+        let len = buffer.len() * 8;
+
+        let ptr = ptr(&mut buffer);
+
+        let mut writer = unsafe { FrameWriter::init(ptr, 4, FORMAT) };
+        let reader = unsafe { FrameReader::new(ptr, len) }.unwrap();
+        assert_eq!(reader.latest(), 0);
+        assert_eq!(reader.section.format, FORMAT);
+
+        for sequence in 1..=5 {
+            #[allow(clippy::cast_possible_truncation)]
+            writer.write(sequence, sequence * 100, None, |data| {
+                data.fill(sequence as u8)
+            });
+
+            assert_eq!(reader.latest(), sequence);
+
+            let frame = reader.frame(sequence);
+            assert_eq!(frame.sequence(), sequence);
+            assert_eq!(frame.generation(), 4);
+            assert_eq!((frame.width(), frame.height(), frame.stride()), (3, 2, 16));
+            assert_eq!(frame.qpc(), sequence * 100);
+            assert_eq!(frame.data().len(), 32);
+            assert!(frame.data().iter().all(|&b| u64::from(b) == sequence));
+            assert!(frame.is_intact());
+        }
+    }
+
+    #[test]
+    fn overwritten_frame_is_not_intact() {
+        let mut buffer = section(FORMAT);
+        let len = buffer.len() * 8;
+
+        let ptr = ptr(&mut buffer);
+
+        let mut writer = unsafe { FrameWriter::init(ptr, 0, FORMAT) };
+        let reader = unsafe { FrameReader::new(ptr, len) }.unwrap();
+
+        writer.write(1, 0, None, |data| data.fill(1));
+        let frame = reader.frame(1);
+
+        // the slots in between don't touch it
+        writer.write(2, 0, None, |data| data.fill(2));
+        writer.write(3, 0, None, |data| data.fill(3));
+        assert!(frame.is_intact());
+
+        // a reader catching the slot mid write sees it cleared
+        writer.write(4, 0, None, |_| {
+            assert!(!reader.frame(1).is_intact());
+        });
+        assert!(!frame.is_intact());
+        assert_eq!(reader.frame(4).sequence(), 4);
+        assert!(reader.frame(4).is_intact());
+    }
+
+    fn rects(count: i32) -> Vec<FrameRect> {
+        (0..count)
+            .map(|i| FrameRect {
+                left: i,
+                top: i * 2,
+                right: i + 10,
+                bottom: i * 2 + 20,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dirty_rects_round_trip() {
+        for count in [0, 1, 5, MAX_DIRTY_RECTS] {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+            let rects = rects(count as i32);
+            let dirty = DirtyRects::new(Some(&rects));
+            assert_eq!(dirty.count as usize, count);
+            assert_eq!(dirty.flags, 0);
+            assert_eq!(dirty.get(), Some(rects.as_slice()));
+        }
+    }
+
+    #[test]
+    fn too_many_dirty_rects_are_the_entire_frame() {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+        let rects = rects(MAX_DIRTY_RECTS as i32 + 1);
+        let dirty = DirtyRects::new(Some(&rects));
+        assert_eq!(dirty.count, 0);
+        assert_eq!(dirty.flags, ENTIRE_FRAME);
+        assert_eq!(dirty.get(), None);
+
+        assert_eq!(DirtyRects::new(None).get(), None);
+
+        // a count past the end from a broken writer is cut off
+        let mut dirty = DirtyRects::new(Some(&[]));
+        dirty.count = u32::MAX;
+        assert_eq!(dirty.get().map(<[_]>::len), Some(MAX_DIRTY_RECTS));
+    }
+
+    #[test]
+    fn frames_carry_their_dirty_rects() {
+        let mut buffer = section(FORMAT);
+        let len = buffer.len() * 8;
+
+        let ptr = ptr(&mut buffer);
+
+        let mut writer = unsafe { FrameWriter::init(ptr, 1, FORMAT) };
+        let reader = unsafe { FrameReader::new(ptr, len) }.unwrap();
+
+        let rects = rects(2);
+        writer.write(1, 0, None, |data| data.fill(1));
+        writer.write(2, 0, Some(&rects), |data| data.fill(2));
+
+        // the pixels stay in place behind the regions
+        let frame = reader.frame(1);
+        assert_eq!(frame.dirty_rects(), None);
+        assert!(frame.data().iter().all(|&b| b == 1));
+
+        let frame = reader.frame(2);
+        assert_eq!(frame.dirty_rects(), Some(rects.as_slice()));
+        assert!(frame.data().iter().all(|&b| b == 2));
+
+        // reusing the slot replaces the regions
+        writer.write(5, 0, None, |data| data.fill(5));
+        assert_eq!(reader.frame(5).dirty_rects(), None);
+    }
+
+    #[test]
+    fn bad_sections_are_refused() {
+        let mut buffer = section(FORMAT);
+        let len = buffer.len() * 8;
+        let ptr = ptr(&mut buffer);
+
+        // not initialized
+        assert!(matches!(
+            unsafe { FrameReader::new(ptr, len) },
+            Err(FrameError::Layout)
+        ));
+
+        _ = unsafe { FrameWriter::init(ptr, 0, FORMAT) };
+        assert!(unsafe { FrameReader::new(ptr, len) }.is_ok());
+
+        // the header claims more than is mapped
+        assert!(matches!(
+            unsafe { FrameReader::new(ptr, len - 8) },
+            Err(FrameError::Layout)
+        ));
+    }
+
+    #[test]
+    fn slots_are_aligned() {
+        assert_eq!(FORMAT.slot_len(), 704);
+        assert_eq!(FORMAT.section_len(), 64 + 3 * 704);
+
+        let format = FrameFormat {
+            width: 1920,
+            height: 1080,
+            stride: 1920 * 4,
+        };
+        assert_eq!(format.slot_len() % ALIGN, 0);
+        assert_eq!(format.data_len(), 1920 * 1080 * 4);
+    }
+}
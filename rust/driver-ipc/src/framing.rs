@@ -0,0 +1,283 @@
+//! Message framing on the driver pipe.
+//!
+//! Every message is a 4 byte little endian length, followed by that many
+//! bytes of JSON. Frames larger than [MAX_FRAME_LEN] are refused on both ends,
+//! so a bad length can't make the reader allocate without bounds.
+//!
+//! Peers from before the framing terminate each JSON message with
+//! [LEGACY_EOF] instead. A [FrameDecoder] tells both apart by the first bytes
+//! of a connection, and replies should be written with the [Framing] it
+//! detected. This compatibility path will be removed in the next release.
+
+use thiserror::Error;
+
+/// Largest payload accepted in a single frame
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Byte terminating each message of the legacy format
+pub const LEGACY_EOF: u8 = 0x04;
+/// Size of the length prefix
+const HEADER_LEN: usize = 4;
+
+/// How messages are separated on a connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Framing {
+    /// Length prefixed frames
+    LengthPrefixed,
+    /// JSON terminated by [LEGACY_EOF]
+    Legacy,
+}
+
+impl Framing {
+    /// Wrap a serialized message into a frame.
+    pub fn encode(self, mut payload: Vec<u8>) -> Result<Vec<u8>, FrameTooLarge> {
+        if payload.len() > MAX_FRAME_LEN {
+            return Err(FrameTooLarge(payload.len()));
+        }
+
+        match self {
+            Self::LengthPrefixed => {
+                #[allow(clippy::cast_possible_truncation)]
+                let len = payload.len() as u32;
+
+                let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+                frame.extend(len.to_le_bytes());
+                frame.append(&mut payload);
+                Ok(frame)
+            }
+
+            Self::Legacy => {
+                payload.push(LEGACY_EOF);
+                Ok(payload)
+            }
+        }
+    }
+
+    /// Detect the framing from the first bytes received on a connection.
+    ///
+    /// Legacy messages are JSON objects, arrays or strings. JSON never
+    /// contains a NUL byte, while the highest byte of a length prefix is always
+    /// NUL because of [MAX_FRAME_LEN]. `None` if more bytes are needed.
+    fn detect(buf: &[u8]) -> Option<Self> {
+        match buf.first()? {
+            b'{' | b'[' | b'"' => match buf.get(HEADER_LEN - 1)? {
+                0 => Some(Self::LengthPrefixed),
+                _ => Some(Self::Legacy),
+            },
+
+            _ => Some(Self::LengthPrefixed),
+        }
+    }
+}
+
+/// Error for a frame longer than [MAX_FRAME_LEN]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Error)]
+#[error("Message of {0} bytes exceeds the maximum of {max} bytes", max = MAX_FRAME_LEN)]
+pub struct FrameTooLarge(pub usize);
+
+/// Splits received bytes back into messages.
+///
+/// Bytes can be pushed in chunks of any size, a message split over several
+/// reads is returned once it is complete.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    framing: Option<Framing>,
+}
+
+impl FrameDecoder {
+    /// Decoder detecting the framing from the first message.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decoder for a connection with a known framing.
+    pub fn with_framing(framing: Framing) -> Self {
+        Self {
+            buf: Vec::new(),
+            framing: Some(framing),
+        }
+    }
+
+    /// Framing of the connection, `None` until enough bytes were received to
+    /// tell.
+    pub fn framing(&self) -> Option<Framing> {
+        self.framing
+    }
+
+    /// Append received bytes.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next complete message out of the received bytes.
+    ///
+    /// Returns `Ok(None)` if more bytes are needed. After an error the stream
+    /// can't be resynchronized, and the connection should be closed.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameTooLarge> {
+        if self.framing.is_none() {
+            self.framing = Framing::detect(&self.buf);
+        }
+
+        match self.framing {
+            None => Ok(None),
+
+            Some(Framing::LengthPrefixed) => {
+                let Some(header) = self.buf.get(..HEADER_LEN) else {
+                    return Ok(None);
+                };
+
+                let len = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(FrameTooLarge(len));
+                }
+
+                if self.buf.len() < HEADER_LEN + len {
+                    return Ok(None);
+                }
+
+                let frame = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+                self.buf.drain(..HEADER_LEN + len);
+                Ok(Some(frame))
+            }
+
+            Some(Framing::Legacy) => {
+                let Some(pos) = self.buf.iter().position(|&b| b == LEGACY_EOF) else {
+                    // the terminator could be anywhere, but not past the limit
+                    if self.buf.len() > MAX_FRAME_LEN {
+                        return Err(FrameTooLarge(self.buf.len()));
+                    }
+
+                    return Ok(None);
+                };
+
+                let frame = self.buf[..pos].to_vec();
+                self.buf.drain(..=pos);
+                Ok(Some(frame))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        DriverCommand, Envelope, Mode, Monitor, RequestCommand, ServerCommand, EDID_BLOCK_LEN,
+    };
+
+    // xorshift, so the split points are random but the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn messages() -> Vec<Vec<u8>> {
+        let mut edid = vec![0; 4 * EDID_BLOCK_LEN];
+        edid[..8].copy_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+
+        let monitor = Monitor {
+            id: 1,
+            name: Some("big".to_owned()),
+            modes: (1..=60)
+                .map(|i| Mode {
+                    width: 640 + i * 16,
+                    height: 480 + i * 9,
+                    refresh_rates: vec![60, 120],
+                    timing: None,
+                })
+                .collect(),
+            edid: Some(edid),
+            ..Default::default()
+        };
+
+        vec![
+            br#""State""#.to_vec(),
+            br#"{"id":0,"Hello":{"protocol":2,"client_name":"test"}}"#.to_vec(),
+            serde_json::to_vec(&ServerCommand::Driver(DriverCommand::Notify(vec![monitor])))
+                .unwrap(),
+            br#"[]"#.to_vec(),
+            br#""RemoveAll""#.to_vec(),
+        ]
+    }
+
+    fn decode_in_chunks(framing: Framing, detect: bool, seed: u64) {
+        let messages = messages();
+        let stream = messages
+            .iter()
+            .flat_map(|m| framing.encode(m.clone()).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut rng = Rng(seed);
+        let mut decoder = if detect {
+            FrameDecoder::new()
+        } else {
+            FrameDecoder::with_framing(framing)
+        };
+
+        let mut decoded = Vec::new();
+        let mut rest = &stream[..];
+        while !rest.is_empty() {
+            // single bytes, small reads, and reads merging several messages
+            let len = match rng.below(3) {
+                0 => 1,
+                1 => 1 + rng.below(16),
+                _ => 1 + rng.below(4096),
+            }
+            .min(rest.len());
+
+            decoder.push(&rest[..len]);
+            rest = &rest[len..];
+
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+
+        assert_eq!(decoder.framing(), Some(framing));
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn split_and_merged_reads() {
+        for seed in 1..=200 {
+            decode_in_chunks(Framing::LengthPrefixed, true, seed);
+            decode_in_chunks(Framing::Legacy, true, seed);
+            decode_in_chunks(Framing::Legacy, false, seed);
+        }
+    }
+
+    #[test]
+    fn detects_framing() {
+        // a length whose lowest byte looks like the start of JSON
+        let frame = Framing::LengthPrefixed.encode(vec![b' '; 0x7B]).unwrap();
+        assert_eq!(Framing::detect(&frame), Some(Framing::LengthPrefixed));
+
+        let state = ServerCommand::Request(RequestCommand::State);
+        let tagged = serde_json::to_vec(&Envelope::new(Some(3), state)).unwrap();
+        assert_eq!(Framing::detect(&tagged), Some(Framing::Legacy));
+        assert_eq!(Framing::detect(br#""State""#), Some(Framing::Legacy));
+        assert_eq!(Framing::detect(b"{\""), None);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        assert_eq!(
+            Framing::LengthPrefixed.encode(vec![0; MAX_FRAME_LEN + 1]),
+            Err(FrameTooLarge(MAX_FRAME_LEN + 1))
+        );
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&u32::MAX.to_le_bytes());
+        assert_eq!(decoder.next_frame(), Err(FrameTooLarge(u32::MAX as usize)));
+
+        let mut decoder = FrameDecoder::with_framing(Framing::Legacy);
+        decoder.push(&vec![b' '; MAX_FRAME_LEN + 1]);
+        assert_eq!(decoder.next_frame(), Err(FrameTooLarge(MAX_FRAME_LEN + 1)));
+    }
+}
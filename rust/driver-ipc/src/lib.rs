@@ -1,13 +1,56 @@
+//! Types shared between the driver and its clients, and the clients talking
+//! to it.
+//!
+//! Only the types and their serialization build on every platform, e.g. to
+//! read the same JSON on a server which isn't running Windows. The pipe
+//! clients and everything else using Windows APIs need the `transport`
+//! feature, which is on by default, and only exist on Windows.
+
+#[cfg(all(windows, feature = "transport"))]
+pub mod bridge;
+mod builder;
+#[cfg(all(windows, feature = "transport"))]
 mod client;
+#[cfg(all(windows, feature = "transport"))]
+mod client_builder;
+pub mod config_file;
 mod core;
+#[cfg(all(windows, feature = "transport"))]
+pub mod display_config;
+#[cfg(all(windows, feature = "transport"))]
 mod driver_client;
+pub mod edid;
+#[cfg(all(windows, feature = "transport"))]
+pub mod frame_export;
+pub mod framing;
+pub mod metrics;
+#[cfg(all(windows, feature = "transport"))]
+pub mod persist;
+#[cfg(all(windows, feature = "transport"))]
 pub mod sync;
 
-pub use client::Client;
+pub use builder::{IdChoice, MonitorBuilder};
+/// Alias of [Client], to tell it apart from [sync::Client] in code using both.
+#[cfg(all(windows, feature = "transport"))]
+pub use client::Client as AsyncClient;
+#[cfg(all(windows, feature = "transport"))]
+pub use client::{error, Client, DEFAULT_REQUEST_TIMEOUT};
+#[cfg(all(windows, feature = "transport"))]
+pub use client_builder::ClientBuilder;
 pub use core::*;
-pub use driver_client::DriverClient;
+#[cfg(all(windows, feature = "transport"))]
+pub use driver_client::{DriverClient, StateSnapshot};
 
-#[cfg(test)]
-mod mock;
+/// In-process stand-in for the driver pipe, for tests of code built on this
+/// crate.
+#[cfg(all(windows, feature = "transport", any(test, feature = "mock")))]
+#[doc(hidden)]
+pub mod mock;
 
 pub static DEFAULT_PIPE_NAME: &str = "virtualdisplaydriver";
+
+/// Device interface class the driver publishes, so its device can be found
+/// without knowing the pipe name.
+#[cfg(all(windows, feature = "transport"))]
+pub const DEVICE_INTERFACE: windows::core::GUID =
+    windows::core::GUID::from_u128(0x5d4c_3f0e_8b2a_4e7d_9c61_2f8a_7b3e_1d90);
@@ -0,0 +1,402 @@
+//! Counters of the driver in the Prometheus text format, for monitoring
+//! dashboards.
+//!
+//! [Metrics::render] builds on every platform. With the `transport` feature,
+//! [collect] gathers the metrics from the driver, and the
+//! [bridge](crate::bridge) answers scrapes of `/metrics` over HTTP if it is
+//! given an address for them, see
+//! [BridgeOptions::metrics](crate::bridge::BridgeOptions::metrics).
+//!
+//! | Metric | Type | |
+//! |--------|------|-|
+//! | `vdd_monitors` | gauge | Monitors configured in the driver |
+//! | `vdd_monitors_attached` | gauge | Monitors attached to a connector |
+//! | `vdd_monitors_rendering` | gauge | Monitors Windows renders to |
+//! | `vdd_monitor_frames_total` | counter | Frames received, by monitor |
+//! | `vdd_monitor_frames_skipped_total` | counter | Frames dropped by the frame rate limit, by monitor |
+//! | `vdd_monitor_latency_seconds` | gauge | Average time to process a frame, by monitor |
+//! | `vdd_ipc_messages_total` | counter | Messages the pipe server received |
+//! | `vdd_ipc_errors_total` | counter | Error replies of the pipe server |
+//!
+//! The per monitor metrics carry the monitor ID in the `monitor` label. The
+//! frame counters start over when the monitor is attached again, the others
+//! when the driver restarts.
+
+use std::fmt::{self, Write as _};
+
+use crate::{IpcStats, MonitorState, Stats};
+
+/// Content type of [Metrics::render], the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Everything exported, as queried from the driver.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// The monitors of the driver, see [RequestCommand::QueryState](crate::RequestCommand::QueryState)
+    pub monitors: Vec<MonitorState>,
+    /// Frame counters of the monitors with a swap chain
+    pub stats: Vec<Stats>,
+    /// Message counters of the pipe server
+    pub ipc: IpcStats,
+}
+
+impl Metrics {
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out)
+            .expect("writing to a String doesn't fail");
+        out
+    }
+
+    fn write(&self, out: &mut String) -> fmt::Result {
+        let attached = self
+            .monitors
+            .iter()
+            .filter(|m| m.connector_index.is_some())
+            .count();
+        let rendering = self.monitors.iter().filter(|m| m.swap_chain).count();
+
+        header(
+            out,
+            "vdd_monitors",
+            "gauge",
+            "Monitors configured in the driver",
+        )?;
+        writeln!(out, "vdd_monitors {}", self.monitors.len())?;
+
+        header(
+            out,
+            "vdd_monitors_attached",
+            "gauge",
+            "Monitors attached to a connector",
+        )?;
+        writeln!(out, "vdd_monitors_attached {attached}")?;
+
+        header(
+            out,
+            "vdd_monitors_rendering",
+            "gauge",
+            "Monitors Windows renders to",
+        )?;
+        writeln!(out, "vdd_monitors_rendering {rendering}")?;
+
+        header(
+            out,
+            "vdd_monitor_frames_total",
+            "counter",
+            "Frames received since the monitor was attached",
+        )?;
+        for monitor in &self.monitors {
+            writeln!(
+                out,
+                "vdd_monitor_frames_total{{monitor=\"{}\"}} {}",
+                monitor.id, monitor.frames
+            )?;
+        }
+
+        header(
+            out,
+            "vdd_monitor_frames_skipped_total",
+            "counter",
+            "Frames dropped by the frame rate limit since the swap chain was assigned",
+        )?;
+        for stats in &self.stats {
+            writeln!(
+                out,
+                "vdd_monitor_frames_skipped_total{{monitor=\"{}\"}} {}",
+                stats.id, stats.frames_skipped
+            )?;
+        }
+
+        header(
+            out,
+            "vdd_monitor_latency_seconds",
+            "gauge",
+            "Average time from acquiring a frame to handing it back",
+        )?;
+        for stats in &self.stats {
+            #[allow(clippy::cast_precision_loss)]
+            let latency = stats.avg_latency_us as f64 / 1_000_000.0;
+            writeln!(
+                out,
+                "vdd_monitor_latency_seconds{{monitor=\"{}\"}} {latency}",
+                stats.id
+            )?;
+        }
+
+        header(
+            out,
+            "vdd_ipc_messages_total",
+            "counter",
+            "Messages the pipe server received from all clients",
+        )?;
+        writeln!(out, "vdd_ipc_messages_total {}", self.ipc.messages)?;
+
+        header(
+            out,
+            "vdd_ipc_errors_total",
+            "counter",
+            "Replies of the pipe server which reported an error",
+        )?;
+        writeln!(out, "vdd_ipc_errors_total {}", self.ipc.errors)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")
+}
+
+/// Query the metrics from the driver.
+///
+/// A monitor which loses its swap chain while this runs is left out of the
+/// frame counters. Returns
+/// [RequestError::Unsupported](crate::error::RequestError::Unsupported) if
+/// the driver is too old to report them.
+#[cfg(all(windows, feature = "transport"))]
+pub async fn collect(client: &crate::Client) -> Result<Metrics, crate::error::RequestError> {
+    use crate::{error::RequestError, ErrorCode, IpcError};
+
+    let monitors = client.state().await?;
+
+    let mut stats = Vec::new();
+    for monitor in monitors.iter().filter(|m| m.swap_chain) {
+        match client.stats(monitor.id).await {
+            Ok(Some(monitor_stats)) => stats.push(monitor_stats),
+            // removed in the meantime
+            Ok(None)
+            | Err(RequestError::Ipc(IpcError {
+                code: ErrorCode::MonitorNotFound,
+                ..
+            })) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let ipc = client.ipc_stats().await?;
+
+    Ok(Metrics {
+        monitors,
+        stats,
+        ipc,
+    })
+}
+
+/// Answer scrapes on `listener` until it fails, with the metrics of the
+/// driver on pipe `pipe_name`.
+///
+/// Every scrape connects to the driver on its own, so the endpoint keeps
+/// working across driver restarts. Scrapes while the driver can't be reached
+/// are answered with `503 Service Unavailable`.
+#[cfg(all(windows, feature = "transport"))]
+pub(crate) async fn serve_on(
+    listener: tokio::net::TcpListener,
+    pipe_name: std::sync::Arc<str>,
+) -> std::io::Result<()> {
+    use log::{info, warn};
+
+    if let Ok(addr) = listener.local_addr() {
+        info!("Metrics listening on http://{addr}/metrics");
+    }
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let pipe_name = pipe_name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::scrape(stream, &pipe_name).await {
+                warn!("Metrics request from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Just enough HTTP for a scraper, one request per connection.
+#[cfg(all(windows, feature = "transport"))]
+mod http {
+    use std::{io, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    use super::{collect, CONTENT_TYPE};
+    use crate::Client;
+
+    /// Time a scraper has to send its request.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Time to wait for the driver pipe.
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+    /// Longest request head, scrapers send far less.
+    const MAX_HEAD_LEN: usize = 8192;
+
+    pub async fn scrape(mut stream: TcpStream, pipe_name: &str) -> io::Result<()> {
+        let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        let response = match route(&head) {
+            Ok(()) => match metrics(pipe_name).await {
+                Ok(body) => response("200 OK", CONTENT_TYPE, &body),
+                Err(e) => response(
+                    "503 Service Unavailable",
+                    "text/plain; charset=utf-8",
+                    &format!("{e}\n"),
+                ),
+            },
+            Err(status) => response(status, "text/plain; charset=utf-8", &format!("{status}\n")),
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    async fn metrics(pipe_name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let client = Client::connect_to_timeout(pipe_name, CONNECT_TIMEOUT).await?;
+        Ok(collect(&client).await?.render())
+    }
+
+    /// Whether the request line asks for the metrics, or the status to answer
+    /// with.
+    pub(super) fn route(head: &str) -> Result<(), &'static str> {
+        let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+        let method = request_line.next().unwrap_or_default();
+        let target = request_line.next().unwrap_or_default();
+        let path = target.split('?').next().unwrap_or_default();
+
+        match (method, path) {
+            ("GET", "/metrics") => Ok(()),
+            (_, "/metrics") => Err("405 Method Not Allowed"),
+            _ => Err("404 Not Found"),
+        }
+    }
+
+    /// Read until the empty line ending the request head, the body is ignored.
+    async fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            if head.len() > MAX_HEAD_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request head too large",
+                ));
+            }
+
+            match stream.read(&mut buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                len => head.extend_from_slice(&buf[..len]),
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&head).into_owned())
+    }
+
+    fn response(status: &str, content_type: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Monitor, MonitorBuilder};
+
+    fn monitor(id: u32) -> Monitor {
+        MonitorBuilder::new(id)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut attached = MonitorState::new(&monitor(1));
+        attached.connector_index = Some(0);
+        attached.swap_chain = true;
+        attached.frames = 120;
+
+        let detached = MonitorState::new(&monitor(2));
+
+        let stats = Stats {
+            id: 1,
+            frames_skipped: 3,
+            avg_latency_us: 1500,
+            ..Stats::default()
+        };
+
+        let metrics = Metrics {
+            monitors: vec![attached, detached],
+            stats: vec![stats],
+            ipc: IpcStats::new(42, 2),
+        };
+
+        let text = metrics.render();
+        let samples = text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            samples,
+            [
+                "vdd_monitors 2",
+                "vdd_monitors_attached 1",
+                "vdd_monitors_rendering 1",
+                r#"vdd_monitor_frames_total{monitor="1"} 120"#,
+                r#"vdd_monitor_frames_total{monitor="2"} 0"#,
+                r#"vdd_monitor_frames_skipped_total{monitor="1"} 3"#,
+                r#"vdd_monitor_latency_seconds{monitor="1"} 0.0015"#,
+                "vdd_ipc_messages_total 42",
+                "vdd_ipc_errors_total 2",
+            ]
+        );
+
+        // every metric is described once, before its samples
+        for name in samples
+            .iter()
+            .map(|sample| sample.split(['{', ' ']).next().unwrap())
+        {
+            assert_eq!(text.matches(&format!("# TYPE {name} ")).count(), 1);
+            assert!(text.find(&format!("# HELP {name} ")) < text.find(&format!("\n{name}")));
+        }
+    }
+
+    #[cfg(all(windows, feature = "transport"))]
+    #[test]
+    fn routes_only_metrics() {
+        assert_eq!(http::route("GET /metrics HTTP/1.1\r\n\r\n"), Ok(()));
+        assert_eq!(http::route("GET /metrics?x=1 HTTP/1.1\r\n\r\n"), Ok(()));
+        assert_eq!(
+            http::route("POST /metrics HTTP/1.1\r\n\r\n"),
+            Err("405 Method Not Allowed")
+        );
+        assert_eq!(http::route("GET / HTTP/1.1\r\n\r\n"), Err("404 Not Found"));
+        assert_eq!(http::route(""), Err("404 Not Found"));
+    }
+
+    #[test]
+    fn renders_without_monitors() {
+        let text = Metrics::default().render();
+        assert!(text.contains("\nvdd_monitors 0\n"));
+        assert!(text.ends_with("vdd_ipc_errors_total 0\n"));
+    }
+}
@@ -7,20 +7,44 @@ use tokio::{
     task,
 };
 
-use crate::*;
+use crate::{
+    framing::{FrameDecoder, Framing},
+    *,
+};
 
-use self::client::EOF;
+pub const MOCK_MAX_MONITORS: u32 = 16;
+pub const MOCK_DRIVER_VERSION: &str = "0.0.0-mock";
 
 pub struct MockServer {
     server: Arc<named_pipe::NamedPipeServer>,
     state: Vec<Monitor>,
-    command_rx: broadcast::Receiver<ServerCommand>,
-    command_tx: broadcast::Sender<ServerCommand>,
+    persisted: Option<Vec<Monitor>>,
+    boot_defaults: Vec<Monitor>,
+    subscribed: bool,
+    // id of the last pumped command
+    last_request_id: Option<RequestId>,
+    // ids reserved by the client, the mock serves a single connection
+    reserved: Vec<Id>,
+    // modes of overridden monitors from before their override, the mock never reverts on its own
+    overridden: Vec<(Id, Vec<Mode>)>,
+    framing: Framing,
+    command_rx: broadcast::Receiver<Envelope<ServerCommand>>,
+    command_tx: broadcast::Sender<Envelope<ServerCommand>>,
     notify_closed: Arc<Notify>,
 }
 
 impl MockServer {
     pub fn new(name: &str) -> Self {
+        Self::with_handshake(name, true)
+    }
+
+    /// Mock of a driver from before the handshake and the length prefix,
+    /// which ignores hello
+    pub fn new_legacy(name: &str) -> Self {
+        Self::with_handshake(name, false)
+    }
+
+    fn with_handshake(name: &str, handshake: bool) -> Self {
         let server = named_pipe::ServerOptions::new()
             .access_inbound(true)
             .access_outbound(true)
@@ -33,6 +57,13 @@ impl MockServer {
 
         let (command_tx, command_rx) = broadcast::channel(64);
 
+        // drivers that know the handshake also know the length prefix
+        let framing = if handshake {
+            Framing::LengthPrefixed
+        } else {
+            Framing::Legacy
+        };
+
         {
             let server = server.clone();
             let command_tx = command_tx.clone();
@@ -46,27 +77,56 @@ impl MockServer {
 
                 server.connect().await.expect("Failed to connect to server");
 
+                let mut decoder = FrameDecoder::with_framing(framing);
+                let mut buf = vec![0; 4096];
+
                 loop {
-                    let mut buf = vec![];
-                    loop {
-                        let byte = tokio::select! {
+                    let frame = loop {
+                        if let Some(frame) = decoder.next_frame().expect("Frame too large") {
+                            break frame;
+                        }
+
+                        let read = tokio::select! {
                             _ = notify_closed.notified() => return,
-                            r = server.read_u8() => r,
+                            r = server.read(&mut buf) => r,
                         };
 
-                        let v = match byte {
-                            Ok(v) => v,
-                            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return, // Client disconnected
+                        match read {
+                            Ok(0) => return, // Client disconnected
+                            Ok(n) => decoder.push(&buf[..n]),
+                            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
                             Err(_) => continue,
-                        };
-                        if v == EOF {
-                            break;
                         }
-                        buf.push(v);
-                    }
+                    };
 
-                    let cmd = serde_json::from_slice::<ServerCommand>(&buf)
-                        .expect("Failed to deserialize request");
+                    // a legacy driver can't read the length prefixed hello, and skips it
+                    let cmd = match serde_json::from_slice::<Envelope<ServerCommand>>(&frame) {
+                        Ok(cmd) => cmd,
+                        Err(_) if !handshake => continue,
+                        Err(e) => panic!("Failed to deserialize request: {e}"),
+                    };
+
+                    // answered right away, the client waits for it while connecting
+                    if let ServerCommand::Request(RequestCommand::Hello { protocol, .. }) =
+                        cmd.command
+                    {
+                        if handshake {
+                            let reply = ReplyCommand::HelloReply {
+                                protocol: protocol.min(PROTOCOL_VERSION),
+                                driver_version: MOCK_DRIVER_VERSION.to_owned(),
+                            };
+                            let reply = serde_json::to_vec(&Envelope::new(cmd.id, reply))
+                                .expect("Failed to serialize reply");
+                            let reply = framing.encode(reply).unwrap();
+
+                            server
+                                .write_all(&reply)
+                                .await
+                                .expect("Failed to write reply");
+                        }
+
+                        continue;
+                    }
 
                     command_tx.send(cmd).expect("Failed to send command");
                 }
@@ -76,6 +136,13 @@ impl MockServer {
         Self {
             server,
             state: vec![],
+            persisted: None,
+            boot_defaults: Vec::new(),
+            subscribed: false,
+            last_request_id: None,
+            reserved: Vec::new(),
+            overridden: Vec::new(),
+            framing,
             command_rx,
             command_tx,
             notify_closed,
@@ -86,6 +153,21 @@ impl MockServer {
         &self.state
     }
 
+    /// State saved by the last persisting command, if any
+    pub fn persisted(&self) -> Option<&[Monitor]> {
+        self.persisted.as_deref()
+    }
+
+    /// Monitors set with the last boot defaults command
+    pub fn boot_defaults(&self) -> &[Monitor] {
+        &self.boot_defaults
+    }
+
+    /// Id the last pumped command was tagged with, `None` if it was untagged
+    pub fn last_request_id(&self) -> Option<RequestId> {
+        self.last_request_id
+    }
+
     pub fn check_next(&mut self, cb: impl FnOnce(ServerCommand) + Send + 'static) {
         let mut rx = self.command_tx.subscribe();
 
@@ -93,7 +175,7 @@ impl MockServer {
             loop {
                 match rx.recv().await {
                     Ok(cmd) => {
-                        cb(cmd);
+                        cb(cmd.command);
                         break;
                     }
                     Err(_) => continue,
@@ -111,41 +193,350 @@ impl MockServer {
                 .unwrap()
         };
 
-        let changed = match cmd {
+        let old_state = self.state.clone();
+
+        let Envelope { id, command } = cmd;
+        self.last_request_id = id;
+
+        let (reply, changed) = match command {
             ServerCommand::Request(RequestCommand::State) => {
-                let reply = ReplyCommand::State(self.state.clone());
-                let mut reply = serde_json::to_vec(&reply).unwrap();
-                reply.push(EOF);
+                (Some(ReplyCommand::State(self.state.clone())), false)
+            }
+            ServerCommand::Request(RequestCommand::Monitor { id: monitor }) => {
+                let monitor = self.state.iter().find(|m| m.id == monitor).cloned();
+                (Some(ReplyCommand::Monitor(monitor)), false)
+            }
+            ServerCommand::Request(RequestCommand::MaxMonitors) => {
+                (Some(ReplyCommand::MaxMonitors(MOCK_MAX_MONITORS)), false)
+            }
+            ServerCommand::Request(RequestCommand::Validate(monitors)) => {
+                let errors = validate_monitors(&monitors).err().unwrap_or_default();
+                (Some(ReplyCommand::Validated(errors)), false)
+            }
+            ServerCommand::Request(RequestCommand::Subscribe) => {
+                self.subscribed = true;
+                (Some(ReplyCommand::Subscribed), false)
+            }
+            ServerCommand::Request(RequestCommand::QueryState) => {
+                // the mock has no OS behind it, so nothing gets attached
+                let states = self.state.iter().map(MonitorState::new).collect();
+                (Some(ReplyCommand::QueryState(states)), false)
+            }
+            ServerCommand::Request(RequestCommand::QueryStats { id: monitor }) => {
+                // nothing renders to the mock, so there is never a swap chain
+                let reply = if self.state.iter().any(|m| m.id == monitor) {
+                    ReplyCommand::Stats(None)
+                } else {
+                    ReplyCommand::Error {
+                        code: ErrorCode::MonitorNotFound,
+                        message: format!("Monitor {monitor} not found"),
+                        source_id: id,
+                    }
+                };
+
+                (Some(reply), false)
+            }
+            ServerCommand::Request(RequestCommand::DriverInfo) => {
+                let info = DriverInfo::new(
+                    MOCK_DRIVER_VERSION,
+                    "mock",
+                    "1970-01-01",
+                    "debug",
+                    None,
+                    None,
+                );
+                (Some(ReplyCommand::DriverInfo(info)), false)
+            }
+            ServerCommand::Request(RequestCommand::DriverStatus) => {
+                (Some(ReplyCommand::DriverStatus(DriverStatus::Ready)), false)
+            }
+            ServerCommand::Request(RequestCommand::ListClients) => {
+                // the mock serves a single connection
+                let mut client = ClientInfo::new(1, 0);
+                client.protocol = Some(PROTOCOL_VERSION);
+                client.subscribed = self.subscribed;
+                client.current = true;
+                (Some(ReplyCommand::Clients(vec![client])), false)
+            }
+            ServerCommand::Request(RequestCommand::BootDefaults) => (
+                Some(ReplyCommand::BootDefaults(self.boot_defaults.clone())),
+                false,
+            ),
+            // the mock logs nothing
+            ServerCommand::Request(RequestCommand::Logs { since_seq, .. }) => (
+                Some(ReplyCommand::Logs {
+                    records: Vec::new(),
+                    next_seq: since_seq,
+                }),
+                false,
+            ),
+            // the mock doesn't count its messages
+            ServerCommand::Request(RequestCommand::IpcStats) => {
+                (Some(ReplyCommand::IpcStats(IpcStats::default())), false)
+            }
+            ServerCommand::Request(RequestCommand::Capabilities) => {
+                let capabilities = Capabilities::with_max_monitors(MOCK_MAX_MONITORS);
+                (Some(ReplyCommand::Capabilities(capabilities)), false)
+            }
+            ServerCommand::Request(RequestCommand::Hello { .. }) => {
+                unreachable!("hello is handled while reading")
+            }
+            ServerCommand::Driver(DriverCommand::ReserveId) => {
+                let reserved = (0..)
+                    .find(|id| {
+                        !self.reserved.contains(id) && !self.state.iter().any(|m| m.id == *id)
+                    })
+                    .unwrap();
+                self.reserved.push(reserved);
+
+                (id.map(|_| ReplyCommand::ReservedId(reserved)), false)
+            }
+            ServerCommand::Driver(DriverCommand::ReserveRequestedId { id: requested }) => {
+                // a single connection can't clash with other reservations
+                let reply = if self.state.iter().any(|m| m.id == requested) {
+                    ReplyCommand::Error {
+                        code: ErrorCode::IdInUse,
+                        message: format!("Monitor {requested} already exists"),
+                        source_id: id,
+                    }
+                } else {
+                    if !self.reserved.contains(&requested) {
+                        self.reserved.push(requested);
+                    }
+                    ReplyCommand::ReservedId(requested)
+                };
+
+                (id.map(|_| reply), false)
+            }
+            ServerCommand::Driver(DriverCommand::SetFrameExport {
+                id: monitor,
+                enabled,
+            }) => {
+                // the name is made up, nothing renders to the mock so there is no section behind it
+                let reply = if self.state.iter().any(|m| m.id == monitor) {
+                    ReplyCommand::FrameExport(
+                        enabled
+                            .then(|| format!(r"Local\VirtualDisplayDriver-Mock-Frames-{monitor}")),
+                    )
+                } else {
+                    ReplyCommand::Error {
+                        code: ErrorCode::MonitorNotFound,
+                        message: format!("Monitor {monitor} not found"),
+                        source_id: id,
+                    }
+                };
+
+                (id.map(|_| reply), false)
+            }
+            // the mock has no settings, so nothing changes
+            ServerCommand::Driver(DriverCommand::ReloadSettings) => (
+                id.map(|_| ReplyCommand::SettingsReloaded(SettingsReload::default())),
+                false,
+            ),
+            // only tagged commands are answered, like on the driver
+            ServerCommand::Driver(cmd) => match id.map(|id| self.check(&cmd, id)) {
+                Some(Some(error)) => (Some(error), false),
+                Some(None) => {
+                    let is_apply = matches!(cmd, DriverCommand::Apply { .. });
+                    let changed = self.apply(cmd);
+
+                    // the mock never fails to create a monitor
+                    let reply = if is_apply {
+                        ReplyCommand::Applied(ApplyReport::new(&old_state, &self.state, Vec::new()))
+                    } else {
+                        ReplyCommand::Done
+                    };
+
+                    (Some(reply), changed)
+                }
+                None => (None, self.apply(cmd)),
+            },
+        };
+
+        if let Some(reply) = reply {
+            // echo the request id, like the driver does
+            let reply = Envelope::new(id, reply);
+            let reply = serde_json::to_vec(&reply).unwrap();
+            let reply = self.framing.encode(reply).unwrap();
+
+            server
+                .write_all(&reply)
+                .await
+                .expect("Failed to write reply");
+        }
+
+        if changed {
+            let event = EventCommand::Changed(self.state.clone());
+            let event = serde_json::to_vec(&event).unwrap();
+            let event = self.framing.encode(event).unwrap();
+
+            server
+                .write_all(&event)
+                .await
+                .expect("Failed to write event");
+        }
+
+        if changed && self.subscribed {
+            for event in monitor_events(&old_state, &self.state) {
+                let event = EventCommand::from(event);
+                let event = serde_json::to_vec(&event).unwrap();
+                let event = self.framing.encode(event).unwrap();
 
                 server
-                    .write_all(&reply)
+                    .write_all(&event)
                     .await
-                    .expect("Failed to write reply");
-                false
+                    .expect("Failed to write event");
             }
-            ServerCommand::Driver(DriverCommand::Notify(monitors)) => {
+        }
+    }
+
+    /// Error reply for a tagged driver command the driver would refuse
+    fn check(&self, cmd: &DriverCommand, id: RequestId) -> Option<ReplyCommand> {
+        let (code, message) = match cmd {
+            DriverCommand::Notify(monitors) => {
+                let errors = validate_monitors(monitors).err()?;
+                (ErrorCode::InvalidMonitors, format!("{errors:?}"))
+            }
+            DriverCommand::Remove(ids) => {
+                let missing = ids
+                    .iter()
+                    .find(|&&id| !self.state.iter().any(|m| m.id == id))?;
+                (
+                    ErrorCode::MonitorNotFound,
+                    format!("Monitor {missing} not found"),
+                )
+            }
+            DriverCommand::Apply { monitors, mode } => {
+                let state = mode.resolve(&self.state, monitors.clone());
+                let errors = validate_monitors(&state).err()?;
+                (ErrorCode::InvalidMonitors, format!("{errors:?}"))
+            }
+            DriverCommand::SetBootDefaults { monitors } => {
+                let errors = validate_monitors(monitors).err()?;
+                (ErrorCode::InvalidMonitors, format!("{errors:?}"))
+            }
+            DriverCommand::SetEnabled { id: monitor, .. }
+            | DriverCommand::OverrideMode { id: monitor, .. } => {
+                if self.state.iter().any(|m| m.id == *monitor) {
+                    return None;
+                }
+
+                (
+                    ErrorCode::MonitorNotFound,
+                    format!("Monitor {monitor} not found"),
+                )
+            }
+            DriverCommand::WithPersistence { command, .. } => return self.check(command, id),
+            _ => return None,
+        };
+
+        Some(ReplyCommand::Error {
+            code,
+            message,
+            source_id: Some(id),
+        })
+    }
+
+    /// Apply a driver command, returning whether the state changed
+    fn apply(&mut self, cmd: DriverCommand) -> bool {
+        match cmd {
+            DriverCommand::Notify(monitors) => {
                 self.state = monitors;
                 true
             }
-            ServerCommand::Driver(DriverCommand::Remove(ids)) => {
+            DriverCommand::Remove(ids) => {
                 self.state.retain(|m| !ids.contains(&m.id));
                 true
             }
-            ServerCommand::Driver(DriverCommand::RemoveAll) => {
+            DriverCommand::RemoveAll => {
                 self.state.clear();
                 true
             }
-        };
+            DriverCommand::PersistNow => {
+                self.persisted = Some(self.state.clone());
+                false
+            }
+            DriverCommand::Apply { monitors, mode } => {
+                self.state = mode.resolve(&self.state, monitors);
+                true
+            }
+            DriverCommand::SetEnabled { id, enabled } => {
+                match self.state.iter_mut().find(|m| m.id == id) {
+                    Some(monitor) if monitor.enabled != enabled => {
+                        monitor.enabled = enabled;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            // the mock has no adapters, the monitors stay as they are
+            DriverCommand::SetPreferredAdapter(_) => false,
+            // answered while pumping, a reservation doesn't change the state
+            DriverCommand::ReserveId | DriverCommand::ReserveRequestedId { .. } => false,
+            // the mock attaches nothing, so sessions change nothing either
+            DriverCommand::SetUserSession { .. } => false,
+            // answered while pumping, exports don't change the state
+            DriverCommand::SetFrameExport { .. } | DriverCommand::ReloadSettings => false,
+            // the defaults are only used when the driver starts
+            DriverCommand::SetBootDefaults { monitors } => {
+                self.boot_defaults = monitors;
+                false
+            }
+            // the mock logs nothing
+            DriverCommand::SetLogLevel(_) => false,
+            // only used from the next start
+            DriverCommand::SetAdapterConfig(_) => false,
+            DriverCommand::OverrideMode { id, mode, .. } => {
+                let Some(monitor) = self.state.iter_mut().find(|m| m.id == id) else {
+                    return false;
+                };
 
-        if changed {
-            let event = EventCommand::Changed(self.state.clone());
-            let mut event = serde_json::to_vec(&event).unwrap();
-            event.push(EOF);
+                // a renewed override keeps the modes from before the first one
+                if !self
+                    .overridden
+                    .iter()
+                    .any(|(overridden, _)| *overridden == id)
+                {
+                    self.overridden.push((id, monitor.modes.clone()));
+                }
 
-            server
-                .write_all(&event)
-                .await
-                .expect("Failed to write event");
+                monitor.modes = vec![mode];
+                true
+            }
+            DriverCommand::RevertMode { id } => {
+                let Some(index) = self
+                    .overridden
+                    .iter()
+                    .position(|(overridden, _)| *overridden == id)
+                else {
+                    return false;
+                };
+
+                let (_, modes) = self.overridden.remove(index);
+                match self.state.iter_mut().find(|m| m.id == id) {
+                    Some(monitor) => {
+                        monitor.modes = modes;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            DriverCommand::Reset => {
+                self.state.clear();
+                self.persisted = None;
+                self.overridden.clear();
+                true
+            }
+            DriverCommand::WithPersistence {
+                command,
+                persistence,
+            } => {
+                let changed = self.apply(*command);
+                if persistence {
+                    self.persisted = Some(self.state.clone());
+                }
+                changed
+            }
         }
     }
 }
@@ -0,0 +1,592 @@
+//! Registry storage of the monitor list.
+//!
+//! Writes happen inside a registry transaction: the serialized list is first
+//! written to a staging value, checked, and then swapped into the primary
+//! value. The previous primary value is kept as a backup, which is used when
+//! the primary value fails to load.
+//!
+//! The stored list is wrapped in an object carrying [FORMAT_VERSION]. Lists
+//! written by older versions are migrated to the current format on load, see
+//! [load_and_upgrade] to also write them back.
+//!
+//! Next to the saved list, the key may hold boot defaults in the same format,
+//! which are only used while nothing was saved, see [load_boot].
+
+use std::{fmt, io};
+
+use joinery::JoinableIterator;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use winreg::{enums::KEY_READ, transaction::Transaction, RegKey};
+
+use crate::{validate_monitors, Monitor, MonitorError};
+
+/// Registry value holding the current monitor list.
+pub const DATA_VALUE: &str = "data";
+/// Registry value holding the last known good monitor list.
+pub const BACKUP_VALUE: &str = "data_backup";
+/// Registry value a new monitor list is written to before it is swapped in.
+pub const STAGING_VALUE: &str = "data_staging";
+/// Registry value holding the monitors to start with while none are saved.
+pub const DEFAULTS_VALUE: &str = "DefaultMonitors";
+
+/// Version of the stored format written by [save].
+///
+/// 1. A bare array of monitors, written before the format had a version.
+/// 2. `{"version": 2, "monitors": [...]}`.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Layout of the current format
+#[derive(Serialize, Deserialize)]
+struct Stored<M> {
+    version: u32,
+    monitors: M,
+}
+
+/// Where a loaded monitor list came from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The primary value.
+    Primary,
+    /// The backup value, because the primary value failed to load.
+    Backup,
+    /// Neither value could be loaded, so no monitors were restored.
+    Defaults,
+    /// Neither value could be loaded, so the boot defaults were used.
+    BootDefaults,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Primary => write!(f, "primary value \"{DATA_VALUE}\""),
+            Self::Backup => write!(f, "backup value \"{BACKUP_VALUE}\""),
+            Self::Defaults => write!(f, "defaults"),
+            Self::BootDefaults => write!(f, "boot defaults value \"{DEFAULTS_VALUE}\""),
+        }
+    }
+}
+
+/// Monitor list returned from [load].
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub monitors: Vec<Monitor>,
+    pub source: Source,
+    /// Format the list was stored in. Older than [FORMAT_VERSION] if it was
+    /// migrated while loading.
+    pub version: u32,
+}
+
+/// Atomically write `monitors` to `path` under `root`.
+///
+/// The key is created if it does not exist yet. If the current primary value
+/// is valid, it is moved to the backup value first. Nothing is changed if any
+/// step fails.
+pub fn save(root: &RegKey, path: &str, monitors: &[Monitor]) -> Result<(), PersistError> {
+    let data = serde_json::to_string(&Stored {
+        version: FORMAT_VERSION,
+        monitors,
+    })?;
+
+    let transaction = Transaction::new().map_err(PersistError::Transaction)?;
+    let (key, _) = root
+        .create_subkey_transacted(path, &transaction)
+        .map_err(PersistError::Open)?;
+
+    key.set_value(STAGING_VALUE, &data)
+        .map_err(PersistError::Set)?;
+
+    // make sure what ends up in the registry is exactly what was serialized
+    let staged = key
+        .get_value::<String, _>(STAGING_VALUE)
+        .map_err(PersistError::Set)?;
+    if staged != data {
+        return Err(PersistError::Verify);
+    }
+
+    // only keep a backup that can actually be restored
+    if let Ok(current) = key.get_value::<String, _>(DATA_VALUE) {
+        if parse(&current).is_ok() {
+            key.set_value(BACKUP_VALUE, &current)
+                .map_err(PersistError::Set)?;
+        }
+    }
+
+    key.set_value(DATA_VALUE, &staged)
+        .map_err(PersistError::Set)?;
+    key.delete_value(STAGING_VALUE).map_err(PersistError::Set)?;
+
+    transaction.commit().map_err(PersistError::Transaction)
+}
+
+/// Atomically delete the monitor list at `path` under `root`, including its
+/// backup.
+///
+/// Loading afterwards falls back to the boot defaults, or an empty list.
+/// Values which don't exist are skipped. Nothing is changed if any step
+/// fails.
+pub fn clear(root: &RegKey, path: &str) -> Result<(), PersistError> {
+    let transaction = Transaction::new().map_err(PersistError::Transaction)?;
+    let (key, _) = root
+        .create_subkey_transacted(path, &transaction)
+        .map_err(PersistError::Open)?;
+
+    for value in [DATA_VALUE, BACKUP_VALUE, STAGING_VALUE] {
+        match key.delete_value(value) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(PersistError::Set(e)),
+            _ => (),
+        }
+    }
+
+    transaction.commit().map_err(PersistError::Transaction)
+}
+
+/// Load the monitor list stored at `path` under `root`.
+///
+/// Tries the primary value, then the backup value, and finally falls back to
+/// an empty list. The reason every failed source was skipped is logged.
+pub fn load(root: &RegKey, path: &str) -> Loaded {
+    let key = match root.open_subkey_with_flags(path, KEY_READ) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!("Failed to open registry key {path}: {e}");
+            return Loaded {
+                monitors: Vec::new(),
+                source: Source::Defaults,
+                version: FORMAT_VERSION,
+            };
+        }
+    };
+
+    for (name, source) in [
+        (DATA_VALUE, Source::Primary),
+        (BACKUP_VALUE, Source::Backup),
+    ] {
+        let monitors = key
+            .get_value::<String, _>(name)
+            .map_err(LoadError::Read)
+            .and_then(|data| parse_versioned(&data));
+
+        match monitors {
+            Ok((monitors, version)) => {
+                return Loaded {
+                    monitors,
+                    source,
+                    version,
+                }
+            }
+            Err(e) => warn!("Skipping {source}: {e}"),
+        }
+    }
+
+    Loaded {
+        monitors: Vec::new(),
+        source: Source::Defaults,
+        version: FORMAT_VERSION,
+    }
+}
+
+/// [load], and write the list back if it was migrated from an older format.
+///
+/// Writing it back is best effort: if it fails, the reason is logged and the
+/// list is migrated again on the next load.
+pub fn load_and_upgrade(root: &RegKey, path: &str) -> Loaded {
+    let loaded = load(root, path);
+
+    if loaded.version < FORMAT_VERSION {
+        match save(root, path, &loaded.monitors) {
+            Ok(()) => info!(
+                "Upgraded stored monitors from format version {} to {FORMAT_VERSION}",
+                loaded.version
+            ),
+            Err(e) => warn!("Failed to write back upgraded monitors: {e}"),
+        }
+    }
+
+    loaded
+}
+
+/// Write `monitors` as the boot defaults at `path` under `root`.
+///
+/// The key is created if it does not exist yet. An empty list deletes the
+/// value, so nothing is plugged at driver start.
+pub fn save_defaults(root: &RegKey, path: &str, monitors: &[Monitor]) -> Result<(), PersistError> {
+    let (key, _) = root.create_subkey(path).map_err(PersistError::Open)?;
+
+    if monitors.is_empty() {
+        return match key.delete_value(DEFAULTS_VALUE) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(PersistError::Set(e)),
+            _ => Ok(()),
+        };
+    }
+
+    let data = serde_json::to_string(&Stored {
+        version: FORMAT_VERSION,
+        monitors,
+    })?;
+
+    key.set_value(DEFAULTS_VALUE, &data)
+        .map_err(PersistError::Set)
+}
+
+/// Load the boot defaults stored at `path` under `root`.
+///
+/// Returns an empty list if there are none.
+pub fn load_defaults(root: &RegKey, path: &str) -> Result<Vec<Monitor>, LoadError> {
+    read_defaults(root, path)
+        .map(|defaults| defaults.map(|(monitors, _)| monitors).unwrap_or_default())
+}
+
+/// The boot defaults and the format they were stored in, `None` if there are
+/// none
+fn read_defaults(root: &RegKey, path: &str) -> Result<Option<(Vec<Monitor>, u32)>, LoadError> {
+    let data = root
+        .open_subkey_with_flags(path, KEY_READ)
+        .and_then(|key| key.get_value::<String, _>(DEFAULTS_VALUE));
+
+    match data {
+        Ok(data) => parse_versioned(&data).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(LoadError::Read(e)),
+    }
+}
+
+/// [load_and_upgrade], falling back to the boot defaults if nothing could be
+/// loaded.
+///
+/// Saved monitors take precedence, even an empty list, so the defaults are
+/// only used until a list is saved. Defaults which fail to load are skipped,
+/// and the reason is logged.
+pub fn load_boot(root: &RegKey, path: &str) -> Loaded {
+    let loaded = load_and_upgrade(root, path);
+
+    let (monitors, version) = match read_defaults(root, path) {
+        Ok(Some(defaults)) => defaults,
+        Ok(None) => return loaded,
+        Err(e) => {
+            warn!("Skipping {}: {e}", Source::BootDefaults);
+            return loaded;
+        }
+    };
+
+    if loaded.source != Source::Defaults {
+        info!(
+            "Ignoring {} boot default monitor(s), the monitors of the {} take precedence",
+            monitors.len(),
+            loaded.source
+        );
+        return loaded;
+    }
+
+    Loaded {
+        monitors,
+        source: Source::BootDefaults,
+        version,
+    }
+}
+
+/// Deserialize and validate a stored monitor list.
+///
+/// Lists in an older format are migrated. Duplicate modes are merged, like the
+/// driver does before applying a list.
+pub fn parse(data: &str) -> Result<Vec<Monitor>, LoadError> {
+    parse_versioned(data).map(|(monitors, _)| monitors)
+}
+
+/// [parse], also returning the format version the list was stored in
+fn parse_versioned(data: &str) -> Result<(Vec<Monitor>, u32), LoadError> {
+    let value = serde_json::from_str::<Value>(data)?;
+    let (value, version) = migrate(value)?;
+
+    let Stored { mut monitors, .. } = serde_json::from_value::<Stored<Vec<Monitor>>>(value)?;
+
+    for monitor in &mut monitors {
+        monitor.dedup_modes();
+    }
+
+    validate_monitors(&monitors).map_err(LoadError::Invalid)?;
+
+    Ok((monitors, version))
+}
+
+/// Upgrade a stored value to the layout of [FORMAT_VERSION].
+///
+/// Returns the upgraded value, and the version it was stored in. Each step
+/// only upgrades by one version, so a new format only needs a step from the
+/// one before it.
+fn migrate(mut value: Value) -> Result<(Value, u32), LoadError> {
+    let version = match &value {
+        Value::Array(_) => 1,
+        Value::Object(object) => object
+            .get("version")
+            .and_then(Value::as_u64)
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or(LoadError::MissingVersion)?,
+        _ => return Err(LoadError::MissingVersion),
+    };
+
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    for from in version..FORMAT_VERSION {
+        value = match from {
+            // the bare list becomes the monitors of the envelope, fields added since then all
+            // have defaults
+            1 => json!({ "version": 2, "monitors": value }),
+            _ => unreachable!("no migration from format version {from}"),
+        };
+    }
+
+    Ok((value, version))
+}
+
+/// Error returned from [save] and [save_defaults].
+#[derive(Debug, Error)]
+pub enum PersistError {
+    #[error("Failed to open registry key: {0}")]
+    Open(io::Error),
+    #[error("Failed to set registry value: {0}")]
+    Set(io::Error),
+    #[error("Registry transaction failed: {0}")]
+    Transaction(io::Error),
+    #[error("Staged registry value does not match the serialized monitors")]
+    Verify,
+    #[error("Failed to serialize monitors: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+/// Reason a stored monitor list could not be loaded.
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("failed to read value: {0}")]
+    Read(io::Error),
+    #[error("failed to deserialize: {0}")]
+    Deserialize(#[from] serde_json::Error),
+    #[error("invalid monitors: {}", .0.iter().join_with(", "))]
+    Invalid(Vec<MonitorError>),
+    #[error("no format version")]
+    MissingVersion,
+    #[error("format version {0} is not supported (newest is {FORMAT_VERSION})")]
+    UnsupportedVersion(u32),
+}
+
+#[cfg(test)]
+mod test {
+    use winreg::enums::HKEY_CURRENT_USER;
+
+    use super::*;
+    use crate::Mode;
+
+    fn monitors() -> Vec<Monitor> {
+        vec![Monitor {
+            id: 0,
+            modes: vec![Mode {
+                width: 1920,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        }]
+    }
+
+    struct TestKey(&'static str);
+
+    impl TestKey {
+        fn new(path: &'static str) -> Self {
+            _ = RegKey::predef(HKEY_CURRENT_USER).delete_subkey_all(path);
+            Self(path)
+        }
+
+        fn open(&self) -> RegKey {
+            RegKey::predef(HKEY_CURRENT_USER)
+                .create_subkey(self.0)
+                .unwrap()
+                .0
+        }
+    }
+
+    impl Drop for TestKey {
+        fn drop(&mut self) {
+            _ = RegKey::predef(HKEY_CURRENT_USER).delete_subkey_all(self.0);
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let key = TestKey::new(r"SOFTWARE\VirtualDisplayDriver-test-save_and_load");
+        let root = RegKey::predef(HKEY_CURRENT_USER);
+
+        let loaded = load(&root, key.0);
+        assert_eq!(loaded.source, Source::Defaults);
+        assert!(loaded.monitors.is_empty());
+
+        save(&root, key.0, &[]).unwrap();
+        save(&root, key.0, &monitors()).unwrap();
+
+        let loaded = load(&root, key.0);
+        assert_eq!(loaded.source, Source::Primary);
+        assert_eq!(loaded.monitors, monitors());
+
+        let reg = key.open();
+        assert_eq!(
+            reg.get_value::<String, _>(BACKUP_VALUE).unwrap(),
+            r#"{"version":2,"monitors":[]}"#
+        );
+        assert!(reg.get_value::<String, _>(STAGING_VALUE).is_err());
+    }
+
+    #[test]
+    fn clear_drops_backup() {
+        let key = TestKey::new(r"SOFTWARE\VirtualDisplayDriver-test-clear_drops_backup");
+        let root = RegKey::predef(HKEY_CURRENT_USER);
+
+        // nothing stored yet
+        clear(&root, key.0).unwrap();
+
+        save(&root, key.0, &monitors()).unwrap();
+        save(&root, key.0, &monitors()).unwrap();
+        clear(&root, key.0).unwrap();
+
+        let loaded = load(&root, key.0);
+        assert_eq!(loaded.source, Source::Defaults);
+        assert!(loaded.monitors.is_empty());
+
+        let reg = key.open();
+        assert!(reg.get_value::<String, _>(DATA_VALUE).is_err());
+        assert!(reg.get_value::<String, _>(BACKUP_VALUE).is_err());
+    }
+
+    #[test]
+    fn corrupt_primary_uses_backup() {
+        let key = TestKey::new(r"SOFTWARE\VirtualDisplayDriver-test-corrupt_primary_uses_backup");
+        let root = RegKey::predef(HKEY_CURRENT_USER);
+
+        save(&root, key.0, &monitors()).unwrap();
+        save(&root, key.0, &monitors()).unwrap();
+        key.open().set_value(DATA_VALUE, &"[{\"id\":").unwrap();
+
+        let loaded = load(&root, key.0);
+        assert_eq!(loaded.source, Source::Backup);
+        assert_eq!(loaded.monitors, monitors());
+
+        // a corrupt primary must not overwrite the good backup
+        save(&root, key.0, &[]).unwrap();
+        let reg = key.open();
+        assert_eq!(
+            parse(&reg.get_value::<String, _>(BACKUP_VALUE).unwrap()).unwrap(),
+            monitors()
+        );
+    }
+
+    #[test]
+    fn invalid_everywhere_uses_defaults() {
+        let key =
+            TestKey::new(r"SOFTWARE\VirtualDisplayDriver-test-invalid_everywhere_uses_defaults");
+        let root = RegKey::predef(HKEY_CURRENT_USER);
+
+        let mut invalid = monitors();
+        invalid[0].modes[0].width = 0;
+        let invalid = serde_json::to_string(&invalid).unwrap();
+
+        let reg = key.open();
+        reg.set_value(DATA_VALUE, &invalid).unwrap();
+        reg.set_value(BACKUP_VALUE, &"not json").unwrap();
+
+        let loaded = load(&root, key.0);
+        assert_eq!(loaded.source, Source::Defaults);
+        assert!(loaded.monitors.is_empty());
+    }
+
+    #[test]
+    fn boot_defaults_only_without_saved_monitors() {
+        let key = TestKey::new(
+            r"SOFTWARE\VirtualDisplayDriver-test-boot_defaults_only_without_saved_monitors",
+        );
+        let root = RegKey::predef(HKEY_CURRENT_USER);
+
+        assert!(load_defaults(&root, key.0).unwrap().is_empty());
+        assert_eq!(load_boot(&root, key.0).source, Source::Defaults);
+
+        save_defaults(&root, key.0, &monitors()).unwrap();
+        assert_eq!(load_defaults(&root, key.0).unwrap(), monitors());
+
+        let loaded = load_boot(&root, key.0);
+        assert_eq!(loaded.source, Source::BootDefaults);
+        assert_eq!(loaded.monitors, monitors());
+
+        // even an empty saved list wins, and clearing it keeps the defaults
+        save(&root, key.0, &[]).unwrap();
+        let loaded = load_boot(&root, key.0);
+        assert_eq!(loaded.source, Source::Primary);
+        assert!(loaded.monitors.is_empty());
+
+        clear(&root, key.0).unwrap();
+        assert_eq!(load_boot(&root, key.0).source, Source::BootDefaults);
+
+        save_defaults(&root, key.0, &[]).unwrap();
+        assert!(key.open().get_value::<String, _>(DEFAULTS_VALUE).is_err());
+        assert_eq!(load_boot(&root, key.0).source, Source::Defaults);
+    }
+
+    // written before the format had a version, and before monitors could be parked, placed or
+    // get a custom EDID
+    const FORMAT_1_ORIGINAL: &str = r#"[{"id":0,"name":null,"enabled":true,"modes":[{"width":1920,"height":1080,"refresh_rates":[60]}]}]"#;
+    const FORMAT_1: &str = r#"[{"id":0,"name":null,"enabled":true,"parked":false,"position":null,"modes":[{"width":1920,"height":1080,"refresh_rates":[60]}],"edid":null}]"#;
+    const FORMAT_2: &str = r#"{"version":2,"monitors":[{"id":0,"name":null,"enabled":true,"parked":false,"position":null,"modes":[{"width":1920,"height":1080,"refresh_rates":[60]}],"edid":null}]}"#;
+
+    #[test]
+    fn parse_every_format() {
+        for (data, version) in [(FORMAT_1_ORIGINAL, 1), (FORMAT_1, 1), (FORMAT_2, 2)] {
+            let (loaded, stored) = parse_versioned(data).unwrap();
+            assert_eq!(loaded, monitors(), "format {version}");
+            assert_eq!(stored, version);
+        }
+
+        assert!(matches!(
+            parse(r#"{"version":3,"monitors":[]}"#),
+            Err(LoadError::UnsupportedVersion(3))
+        ));
+        assert!(matches!(
+            parse(r#"{"version":0,"monitors":[]}"#),
+            Err(LoadError::UnsupportedVersion(0))
+        ));
+        assert!(matches!(
+            parse(r#"{"monitors":[]}"#),
+            Err(LoadError::MissingVersion)
+        ));
+        assert!(parse(r#"{"version":1,"monitors":[]}"#).is_err());
+    }
+
+    #[test]
+    fn old_format_is_upgraded() {
+        let key = TestKey::new(r"SOFTWARE\VirtualDisplayDriver-test-old_format_is_upgraded");
+        let root = RegKey::predef(HKEY_CURRENT_USER);
+
+        key.open()
+            .set_value(DATA_VALUE, &FORMAT_1_ORIGINAL)
+            .unwrap();
+
+        let loaded = load_and_upgrade(&root, key.0);
+        assert_eq!(loaded.source, Source::Primary);
+        assert_eq!(loaded.version, 1);
+        assert_eq!(loaded.monitors, monitors());
+
+        let reg = key.open();
+        let data = reg.get_value::<String, _>(DATA_VALUE).unwrap();
+        assert_eq!(
+            parse_versioned(&data).unwrap(),
+            (monitors(), FORMAT_VERSION)
+        );
+        assert_eq!(
+            reg.get_value::<String, _>(BACKUP_VALUE).unwrap(),
+            FORMAT_1_ORIGINAL
+        );
+
+        let loaded = load(&root, key.0);
+        assert_eq!(loaded.version, FORMAT_VERSION);
+    }
+}
@@ -3,11 +3,13 @@ mod driver_client;
 
 use std::sync::LazyLock;
 
-pub use client::{Client, EventsSubscription};
+pub use client::{Client, Events, EventsCloser, EventsSubscription};
 pub use driver_client::DriverClient;
 
 use tokio::runtime::{Builder, Runtime};
 
+use crate::bridge::{self, BridgeError, BridgeOptions};
+
 static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
     Builder::new_multi_thread()
         .worker_threads(1)
@@ -15,3 +17,10 @@ static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
         .build()
         .unwrap()
 });
+
+/// Serve the driver pipe over TCP until the listener fails.
+///
+/// See [bridge::serve].
+pub fn serve_bridge(options: BridgeOptions) -> Result<(), BridgeError> {
+    RUNTIME.block_on(bridge::serve(options))
+}
@@ -1,10 +1,15 @@
-use std::{any::Any, panic, thread};
+use std::{any::Any, panic, pin::Pin, sync::Arc, thread, time::Duration};
 
-use tokio::sync::{mpsc, oneshot};
-use tokio_stream::StreamExt;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::{Stream, StreamExt};
 
 use super::RUNTIME;
-use crate::{client::error, Client as AsyncClient, EventCommand, Id, Monitor};
+use crate::{
+    client::error, display_config, AdapterConfig, AdapterPreference, ApplyReport, Capabilities,
+    Client as AsyncClient, ClientBuilder, ClientInfo, DriverCommand, DriverInfo, DriverStatus,
+    EventCommand, Id, IpcStats, LogLevel, LogRecord, Mode, Monitor, MonitorError, MonitorEvent,
+    MonitorState, SettingsReload, Stats,
+};
 
 /// Client for interacting with the Virtual Display Driver.
 ///
@@ -29,29 +34,247 @@ impl Client {
         Ok(Self(client))
     }
 
+    /// Connect to driver on pipe with default name, waiting up to `timeout`
+    /// for the pipe to become available.
+    ///
+    /// See [Client::connect_to_timeout].
+    pub fn connect_timeout(timeout: Duration) -> Result<Self, error::ConnectionError> {
+        let client = RUNTIME.block_on(AsyncClient::connect_timeout(timeout))?;
+        Ok(Self(client))
+    }
+
     /// Connect to driver on pipe with specified name.
     ///
     /// `name` is ONLY the {name} portion of \\.\pipe\{name}.
+    ///
+    /// Fails immediately if the driver is busy serving another connection,
+    /// use [Client::connect_to_timeout] to wait instead.
     pub fn connect_to(name: &str) -> Result<Self, error::ConnectionError> {
         let client = RUNTIME.block_on(AsyncClient::connect_to(name))?;
         Ok(Self(client))
     }
 
+    /// Connect to driver on pipe with specified name, waiting up to `timeout`
+    /// for the pipe to become available.
+    ///
+    /// `name` is ONLY the {name} portion of \\.\pipe\{name}.
+    ///
+    /// Returns [error::ConnectionError::Timeout] if the driver stayed busy.
+    /// Other errors, like a missing pipe, are returned immediately.
+    pub fn connect_to_timeout(
+        name: &str,
+        timeout: Duration,
+    ) -> Result<Self, error::ConnectionError> {
+        let client = RUNTIME.block_on(AsyncClient::connect_to_timeout(name, timeout))?;
+        Ok(Self(client))
+    }
+
+    /// Connect to driver with the settings of `builder`.
+    ///
+    /// See [ClientBuilder::connect].
+    pub fn connect_with(builder: &ClientBuilder) -> Result<Self, error::ConnectionError> {
+        let client = RUNTIME.block_on(builder.connect())?;
+        Ok(Self(client))
+    }
+
+    /// Version of the driver, as reported in the handshake.
+    ///
+    /// `None` if the driver is older than the handshake.
+    pub fn driver_version(&self) -> Option<&str> {
+        self.0.driver_version()
+    }
+
+    /// Protocol version agreed on with the driver, see
+    /// [crate::PROTOCOL_VERSION].
+    pub fn protocol_version(&self) -> u32 {
+        self.0.protocol_version()
+    }
+
+    /// Time requests wait for the driver to reply.
+    pub fn request_timeout(&self) -> Duration {
+        self.0.request_timeout()
+    }
+
+    /// Change how long requests wait for the driver to reply.
+    ///
+    /// Only affects this copy of the client, so a clone can be used for
+    /// requests with a different timeout.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.0.set_request_timeout(timeout);
+    }
+
     /// Send new state to the driver.
+    ///
+    /// The change is temporary, see [Client::notify_with].
     pub fn notify(&self, monitors: &[Monitor]) -> Result<(), error::SendError> {
         RUNTIME.block_on(self.0.notify(monitors))
     }
 
+    /// Send new state to the driver.
+    ///
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    pub fn notify_with(
+        &self,
+        monitors: &[Monitor],
+        persistence: bool,
+    ) -> Result<(), error::SendError> {
+        RUNTIME.block_on(self.0.notify_with(monitors, persistence))
+    }
+
     /// Remove all monitors with the specified IDs.
+    ///
+    /// The change is temporary, see [Client::remove_with].
     pub fn remove(&self, ids: &[Id]) -> Result<(), error::SendError> {
         RUNTIME.block_on(self.0.remove(ids))
     }
 
+    /// Remove all monitors with the specified IDs.
+    ///
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    pub fn remove_with(&self, ids: &[Id], persistence: bool) -> Result<(), error::SendError> {
+        RUNTIME.block_on(self.0.remove_with(ids, persistence))
+    }
+
     /// Remove all monitors.
+    ///
+    /// The change is temporary, see [Client::remove_all_with].
     pub fn remove_all(&self) -> Result<(), error::SendError> {
         RUNTIME.block_on(self.0.remove_all())
     }
 
+    /// Remove all monitors.
+    ///
+    /// If `persistence` is set, the driver saves the resulting state and
+    /// restores it the next time it is started.
+    pub fn remove_all_with(&self, persistence: bool) -> Result<(), error::SendError> {
+        RUNTIME.block_on(self.0.remove_all_with(persistence))
+    }
+
+    /// Make the driver save its current state.
+    ///
+    /// The driver restores this state the next time it is started.
+    pub fn persist_now(&self) -> Result<(), error::SendError> {
+        RUNTIME.block_on(self.0.persist_now())
+    }
+
+    /// Run a driver command and wait until the driver applied it.
+    ///
+    /// Failures on the driver side are returned as
+    /// [error::RequestError::Ipc], see [AsyncClient::execute].
+    pub fn execute(&self, command: &DriverCommand) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.execute(command))
+    }
+
+    /// Make `monitors` the complete state of the driver, in one pass.
+    ///
+    /// Returns the outcome per monitor, see [AsyncClient::set_monitors].
+    pub fn set_monitors(&self, monitors: &[Monitor]) -> Result<ApplyReport, error::RequestError> {
+        RUNTIME.block_on(self.0.set_monitors(monitors))
+    }
+
+    /// Add or update `monitors` in one pass, keeping the other monitors of
+    /// the driver as they are.
+    ///
+    /// Returns the outcome per monitor, see [AsyncClient::merge_monitors].
+    pub fn merge_monitors(&self, monitors: &[Monitor]) -> Result<ApplyReport, error::RequestError> {
+        RUNTIME.block_on(self.0.merge_monitors(monitors))
+    }
+
+    /// Return the driver to the state of a fresh install.
+    ///
+    /// See [AsyncClient::reset].
+    pub fn reset(&self) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.reset())
+    }
+
+    /// Enable or disable a monitor, keeping its configuration.
+    ///
+    /// The change is temporary, see [AsyncClient::set_enabled].
+    pub fn set_enabled(&self, id: Id, enabled: bool) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_enabled(id, enabled))
+    }
+
+    /// Enable or disable a monitor, keeping its configuration.
+    ///
+    /// See [AsyncClient::set_enabled_with].
+    pub fn set_enabled_with(
+        &self,
+        id: Id,
+        enabled: bool,
+        persistence: bool,
+    ) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_enabled_with(id, enabled, persistence))
+    }
+
+    /// Make the driver render on the adapter matching `preference`.
+    ///
+    /// See [AsyncClient::set_preferred_adapter].
+    pub fn set_preferred_adapter(
+        &self,
+        preference: Option<AdapterPreference>,
+    ) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_preferred_adapter(preference))
+    }
+
+    /// Tell the driver whether a user is logged on and the session is
+    /// unlocked.
+    ///
+    /// See [AsyncClient::set_user_session].
+    pub fn set_user_session(&self, active: bool) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_user_session(active))
+    }
+
+    /// Start or stop exporting the frames of a monitor into shared memory.
+    ///
+    /// See [AsyncClient::set_frame_export].
+    pub fn set_frame_export(
+        &self,
+        id: Id,
+        enabled: bool,
+    ) -> Result<Option<String>, error::RequestError> {
+        RUNTIME.block_on(self.0.set_frame_export(id, enabled))
+    }
+
+    /// Set the monitors the driver starts with while it has no saved
+    /// monitors.
+    ///
+    /// See [AsyncClient::set_boot_defaults].
+    pub fn set_boot_defaults(&self, monitors: &[Monitor]) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_boot_defaults(monitors))
+    }
+
+    /// See [AsyncClient::set_log_level].
+    pub fn set_log_level(&self, level: LogLevel) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_log_level(level))
+    }
+
+    /// See [AsyncClient::set_adapter_config].
+    pub fn set_adapter_config(&self, config: &AdapterConfig) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_adapter_config(config))
+    }
+
+    /// See [AsyncClient::override_mode].
+    pub fn override_mode(
+        &self,
+        id: Id,
+        mode: &Mode,
+        ttl: Duration,
+    ) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.override_mode(id, mode, ttl))
+    }
+
+    /// See [AsyncClient::revert_mode].
+    pub fn revert_mode(&self, id: Id) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.revert_mode(id))
+    }
+
+    /// See [AsyncClient::reload_settings].
+    pub fn reload_settings(&self) -> Result<SettingsReload, error::RequestError> {
+        RUNTIME.block_on(self.0.reload_settings())
+    }
+
     /// Block and receive the next driver event.
     ///
     /// Only new events after calling this method will be received.
@@ -65,6 +288,46 @@ impl Client {
         })
     }
 
+    /// Subscribe to per monitor events and iterate over them.
+    ///
+    /// Every call to `next` blocks until the next event arrives. The iterator
+    /// ends when the connection to the driver is closed.
+    ///
+    /// Only events after calling this method are received. See
+    /// [Client::subscribe] for which events are pushed.
+    pub fn receive_events(
+        &self,
+    ) -> Result<impl Iterator<Item = MonitorEvent>, error::RequestError> {
+        // listen before subscribing, so no event in between is lost
+        let mut stream = Box::pin(self.0.receive_events());
+
+        self.subscribe()?;
+
+        Ok(std::iter::from_fn(move || {
+            RUNTIME.block_on(async {
+                while let Some(event) = stream.next().await {
+                    match event.map(MonitorEvent::from_event) {
+                        Ok(Some(event)) => return Some(event),
+                        Ok(None) => continue,
+                        Err(_) => return None,
+                    }
+                }
+
+                None
+            })
+        }))
+    }
+
+    /// Iterate over the monitor states broadcast by the driver.
+    ///
+    /// Every call to `next` blocks until another client changes the state. See
+    /// [Events] for how to end the iterator.
+    ///
+    /// Only changes after calling this method are received.
+    pub fn events(&self) -> Events {
+        Events::new(self.0.receive_events())
+    }
+
     /// Add an event receiver to receive continuous events from the driver.
     ///
     /// Returns an object that can be used to cancel the subscription.
@@ -86,19 +349,247 @@ impl Client {
 
     /// Request the current state of the driver.
     ///
-    /// Returns [IpcError::Timeout] if the driver does not respond within 5
-    /// seconds.
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
     pub fn request_state(&self) -> Result<Vec<Monitor>, error::RequestError> {
         RUNTIME.block_on(self.0.request_state())
     }
 
+    /// Request a single monitor of the driver's state.
+    ///
+    /// See [AsyncClient::request_monitor].
+    pub fn request_monitor(&self, id: Id) -> Result<Option<Monitor>, error::RequestError> {
+        RUNTIME.block_on(self.0.request_monitor(id))
+    }
+
+    /// Request what the driver and Windows actually did with each monitor.
+    ///
+    /// See [AsyncClient::state].
+    pub fn state(&self) -> Result<Vec<MonitorState>, error::RequestError> {
+        RUNTIME.block_on(self.0.state())
+    }
+
+    /// Find the display Windows shows the monitor with ID `id` as.
+    ///
+    /// See [AsyncClient::resolve_display].
+    pub fn resolve_display(
+        &self,
+        id: Id,
+    ) -> Result<Option<display_config::DisplayPath>, error::RequestError> {
+        RUNTIME.block_on(self.0.resolve_display(id))
+    }
+
+    /// Request the frame counters of a monitor's swap chain.
+    ///
+    /// See [AsyncClient::stats].
+    pub fn stats(&self, id: Id) -> Result<Option<Stats>, error::RequestError> {
+        RUNTIME.block_on(self.0.stats(id))
+    }
+
+    /// Request which build of the driver is running.
+    ///
+    /// See [AsyncClient::version].
+    pub fn version(&self) -> Result<DriverInfo, error::RequestError> {
+        RUNTIME.block_on(self.0.version())
+    }
+
+    /// Request whether the driver finished initializing IddCx.
+    ///
+    /// See [AsyncClient::driver_status].
+    pub fn driver_status(&self) -> Result<DriverStatus, error::RequestError> {
+        RUNTIME.block_on(self.0.driver_status())
+    }
+
+    /// Reserve the lowest monitor ID which is neither used nor reserved.
+    ///
+    /// See [AsyncClient::new_id].
+    pub fn new_id(&self) -> Result<Id, error::RequestError> {
+        RUNTIME.block_on(self.0.new_id())
+    }
+
+    /// Reserve the monitor ID `id`, if it is neither used nor reserved.
+    ///
+    /// See [AsyncClient::reserve_id].
+    pub fn reserve_id(&self, id: Id) -> Result<Id, error::RequestError> {
+        RUNTIME.block_on(self.0.reserve_id(id))
+    }
+
+    /// Request the open connections of the driver's pipe.
+    ///
+    /// See [AsyncClient::list_clients].
+    pub fn list_clients(&self) -> Result<Vec<ClientInfo>, error::RequestError> {
+        RUNTIME.block_on(self.0.list_clients())
+    }
+
+    /// Request the message counters of the driver's pipe server.
+    ///
+    /// See [AsyncClient::ipc_stats].
+    pub fn ipc_stats(&self) -> Result<IpcStats, error::RequestError> {
+        RUNTIME.block_on(self.0.ipc_stats())
+    }
+
+    /// Request the monitors the driver starts with while it has no saved
+    /// monitors.
+    ///
+    /// See [AsyncClient::boot_defaults].
+    pub fn boot_defaults(&self) -> Result<Vec<Monitor>, error::RequestError> {
+        RUNTIME.block_on(self.0.boot_defaults())
+    }
+
+    /// See [AsyncClient::logs].
+    pub fn logs(
+        &self,
+        min_level: LogLevel,
+        since_seq: u64,
+    ) -> Result<(Vec<LogRecord>, u64), error::RequestError> {
+        RUNTIME.block_on(self.0.logs(min_level, since_seq))
+    }
+
+    /// Request the maximum number of monitors the driver can have enabled at
+    /// the same time.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub fn request_max_monitors(&self) -> Result<u32, error::RequestError> {
+        RUNTIME.block_on(self.0.request_max_monitors())
+    }
+
+    /// Request the limits the driver enforces on monitors and modes.
+    ///
+    /// Drivers too old to report them only report their monitor limit, the
+    /// other limits are then filled in from this crate, see
+    /// [Capabilities::with_max_monitors].
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub fn request_capabilities(&self) -> Result<Capabilities, error::RequestError> {
+        RUNTIME.block_on(self.0.request_capabilities())
+    }
+
+    /// Ask the driver whether it would accept `monitors`, without applying
+    /// them.
+    ///
+    /// The outer result is the request itself, the inner result is the outcome
+    /// of the validation.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub fn validate(
+        &self,
+        monitors: &[Monitor],
+    ) -> Result<Result<(), Vec<MonitorError>>, error::RequestError> {
+        RUNTIME.block_on(self.0.validate(monitors))
+    }
+
+    /// Ask the driver to push per monitor events to this connection.
+    ///
+    /// After this returns, received events also include
+    /// [EventCommand::MonitorAdded], [EventCommand::MonitorRemoved] and
+    /// [EventCommand::MonitorChanged] for every state change, no matter which
+    /// client (or the driver itself) caused it. The subscription lasts until
+    /// the connection is closed.
+    ///
+    /// Returns [IpcError::Timeout] if the driver does not respond within the
+    /// request timeout (5 seconds by default).
+    pub fn subscribe(&self) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.subscribe())
+    }
+
     /// Write `monitors` to the registry for current user.
     ///
     /// Next time the driver is started, it will load this state from the
     /// registry. This might be after a reboot or a driver restart.
+    ///
+    /// The write is atomic, see [crate::persist::save].
     pub fn persist(monitors: &[Monitor]) -> Result<(), error::PersistError> {
         AsyncClient::persist(monitors)
     }
+
+    /// Read the monitors written by [Client::persist].
+    ///
+    /// A list written by an older version is migrated, and written back in
+    /// the current format, see [crate::persist::load_and_upgrade]. If nothing
+    /// could be loaded, the list is empty.
+    pub fn load_persisted() -> crate::persist::Loaded {
+        AsyncClient::load_persisted()
+    }
+
+    /// Delete the monitors written by [Client::persist].
+    ///
+    /// See [AsyncClient::clear_persisted].
+    pub fn clear_persisted() -> Result<(), error::PersistError> {
+        AsyncClient::clear_persisted()
+    }
+}
+
+/// Blocking iterator over the monitor states broadcast by the driver.
+///
+/// Yields the new state on every change. It ends when the connection to the
+/// driver is closed, or when [EventsCloser::close] is called, which also wakes
+/// up a blocked `next` on another thread.
+pub struct Events {
+    stream: Pin<Box<dyn Stream<Item = Result<EventCommand, error::ReceiveError>> + Send>>,
+    closed_tx: Arc<watch::Sender<bool>>,
+    closed_rx: watch::Receiver<bool>,
+}
+
+impl Events {
+    pub(crate) fn new(
+        stream: impl Stream<Item = Result<EventCommand, error::ReceiveError>> + Send + 'static,
+    ) -> Self {
+        let (closed_tx, closed_rx) = watch::channel(false);
+
+        Self {
+            stream: Box::pin(stream),
+            closed_tx: Arc::new(closed_tx),
+            closed_rx,
+        }
+    }
+
+    /// Handle to end this iterator, which can be sent to another thread.
+    pub fn closer(&self) -> EventsCloser {
+        EventsCloser(self.closed_tx.clone())
+    }
+}
+
+impl Iterator for Events {
+    type Item = Vec<Monitor>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Self {
+            stream, closed_rx, ..
+        } = self;
+
+        RUNTIME.block_on(async {
+            loop {
+                tokio::select! {
+                    // closing wins over changes which are already waiting
+                    biased;
+
+                    _ = closed_rx.wait_for(|&closed| closed) => return None,
+
+                    event = stream.next() => match event {
+                        Some(Ok(EventCommand::Changed(monitors))) => return Some(monitors),
+                        // per monitor events of a subscribed connection
+                        Some(Ok(_)) => continue,
+                        Some(Err(_)) | None => return None,
+                    },
+                }
+            }
+        })
+    }
+}
+
+/// Ends an [Events] iterator, see [Events::closer].
+#[derive(Debug, Clone)]
+pub struct EventsCloser(Arc<watch::Sender<bool>>);
+
+impl EventsCloser {
+    /// End the iterator. A `next` call blocked on another thread returns
+    /// `None`.
+    pub fn close(&self) {
+        self.0.send_replace(true);
+    }
 }
 
 pub struct EventsSubscription {
@@ -388,4 +879,69 @@ mod test {
         );
         assert!(*shared_flag.lock().unwrap(), "Callback was not run");
     }
+
+    #[test]
+    fn receive_events_iterator() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-sync-receive_events_iterator";
+
+        let mut server = RUNTIME.block_on(async { MockServer::new(PIPE_NAME) });
+
+        let client = Client::connect_to(PIPE_NAME).unwrap();
+
+        let receiver = thread::spawn({
+            let client = client.clone();
+            move || {
+                client
+                    .receive_events()
+                    .map(|events| events.take(2).collect::<Vec<_>>())
+            }
+        });
+
+        // Subscribe request
+        RUNTIME.block_on(server.pump());
+
+        let mon = Monitor {
+            id: 0,
+            modes: vec![crate::Mode {
+                width: 1920,
+                height: 1080,
+                refresh_rates: vec![60],
+                timing: None,
+            }],
+            ..Default::default()
+        };
+
+        client.notify(&[mon.clone()]).unwrap();
+        RUNTIME.block_on(server.pump());
+
+        client.remove_all().unwrap();
+        RUNTIME.block_on(server.pump());
+
+        let events = receiver.join().unwrap().expect("Failed to subscribe");
+
+        assert_eq!(events, [MonitorEvent::Added(mon), MonitorEvent::Removed(0)]);
+    }
+
+    #[test]
+    fn events_iterator() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-sync-events_iterator";
+
+        let mut server = RUNTIME.block_on(async { MockServer::new(PIPE_NAME) });
+
+        let client = Client::connect_to(PIPE_NAME).unwrap();
+
+        let events = client.events();
+        let closer = events.closer();
+        let receiver = thread::spawn(move || events.collect::<Vec<_>>());
+
+        client.notify(&[]).unwrap();
+        RUNTIME.block_on(server.pump());
+        sleep(std::time::Duration::from_millis(100));
+
+        // unblocks the iterator waiting for the next change
+        closer.close();
+
+        let states = receiver.join().unwrap();
+        assert_eq!(states, [Vec::<Monitor>::new()]);
+    }
 }
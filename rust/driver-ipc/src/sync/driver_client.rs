@@ -1,6 +1,13 @@
-use super::{client::EventsSubscription, RUNTIME};
+use std::time::Duration;
+
+use super::{
+    client::{Events, EventsSubscription},
+    RUNTIME,
+};
 use crate::{
-    driver_client::error, DriverClient as AsyncDriverClient, EventCommand, Id, Mode, Monitor,
+    display_config, driver_client::error, ActiveMode, AdapterConfig, ApplyReport, Capabilities,
+    ClientInfo, Dimen, DriverClient as AsyncDriverClient, DriverInfo, DriverStatus, EventCommand,
+    Id, LogLevel, LogRecord, Mode, Monitor, RefreshRate, SettingsReload, StateSnapshot,
 };
 
 /// Abstraction layer over [Client].
@@ -11,7 +18,10 @@ use crate::{
 /// [DriverClient::persist]. To synchronize this object with the driver, you
 /// must call [DriverClient::refresh_state]. The state will not be updated
 /// automatically.
-#[derive(Debug)]
+///
+/// Like the async client, it is `Clone`, `Send` and `Sync`, and clones share
+/// their state, see [AsyncDriverClient#concurrency].
+#[derive(Debug, Clone)]
 pub struct DriverClient(AsyncDriverClient);
 
 impl DriverClient {
@@ -57,10 +67,27 @@ impl DriverClient {
     }
 
     /// Manually synchronize with the driver.
-    pub fn refresh_state(&mut self) -> &[Monitor] {
+    ///
+    /// Returns a copy of the new client state.
+    pub fn refresh_state(&self) -> Vec<Monitor> {
         self.0.refresh_state()
     }
 
+    /// Synchronize a single monitor with the driver, see
+    /// [AsyncDriverClient::refresh_one].
+    pub fn refresh_one(&self, id: Id) -> Result<Option<Monitor>, error::RequestError> {
+        RUNTIME.block_on(self.0.refresh_one(id))
+    }
+
+    /// Iterate over the driver state, blocking until it changes.
+    ///
+    /// Yields every state change, regardless of who caused it. See [Events]
+    /// for how to end the iterator. The state stored inside this client is
+    /// not updated by it, see [DriverClient::refresh_state].
+    pub fn events(&self) -> Events {
+        Events::new(self.0.receive_events())
+    }
+
     /// Add an event receiver to receive continuous events from the driver.
     ///
     /// This receiver will always reflect the real state of the driver,
@@ -74,7 +101,7 @@ impl DriverClient {
     /// consequence, all other library events will be delayed until the callback
     /// returns.
     ///
-    /// Note: If multiple copies of this client exist (using
+    /// Note: If multiple copies of this client exist (clones, or using
     /// [DriverClient::duplicate]), the returned stream will only be closed
     /// after all copies are dropped.
     pub fn add_event_receiver(
@@ -88,22 +115,133 @@ impl DriverClient {
         EventsSubscription::start_subscriber(cb, stream)
     }
 
-    /// Get the current monitor state stored inside this client.
+    /// Get a copy of the current monitor state stored inside this client.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn monitors(&self) -> &[Monitor] {
+    pub fn monitors(&self) -> Vec<Monitor> {
         self.0.monitors()
     }
 
+    /// Maximum number of monitors the driver can have enabled at the same
+    /// time.
+    ///
+    /// Disabled and parked monitors do not count towards this limit.
+    pub fn max_monitors(&self) -> u32 {
+        self.0.max_monitors()
+    }
+
+    /// See [AsyncDriverClient::set_bandwidth_budget] for details.
+    pub fn set_bandwidth_budget(&self, budget: Option<u64>) {
+        self.0.set_bandwidth_budget(budget);
+    }
+
+    /// See [AsyncDriverClient::bandwidth_budget] for details.
+    pub fn bandwidth_budget(&self) -> Option<u64> {
+        self.0.bandwidth_budget()
+    }
+
+    /// See [AsyncDriverClient::estimated_bandwidth] for details.
+    pub fn estimated_bandwidth(&self) -> u64 {
+        self.0.estimated_bandwidth()
+    }
+
+    /// Limits the driver enforces on monitors and modes.
+    ///
+    /// Use [Capabilities::supports] to check a mode before adding it.
+    pub fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
+    /// Version of the driver, as reported in the handshake.
+    ///
+    /// `None` if the driver is older than the handshake.
+    pub fn driver_version(&self) -> Option<&str> {
+        self.0.driver_version()
+    }
+
+    /// Request which build of the driver is running, see
+    /// [AsyncDriverClient::version].
+    pub fn version(&self) -> Result<DriverInfo, error::RequestError> {
+        RUNTIME.block_on(self.0.version())
+    }
+
+    /// Request whether the driver finished initializing IddCx, see
+    /// [AsyncDriverClient::driver_status].
+    pub fn driver_status(&self) -> Result<DriverStatus, error::RequestError> {
+        RUNTIME.block_on(self.0.driver_status())
+    }
+
+    /// Request the open connections of the driver's pipe, see
+    /// [AsyncDriverClient::list_clients].
+    pub fn list_clients(&self) -> Result<Vec<ClientInfo>, error::RequestError> {
+        RUNTIME.block_on(self.0.list_clients())
+    }
+
+    /// Request records of the driver's log, see [AsyncDriverClient::logs].
+    pub fn logs(
+        &self,
+        min_level: LogLevel,
+        since_seq: u64,
+    ) -> Result<(Vec<LogRecord>, u64), error::RequestError> {
+        RUNTIME.block_on(self.0.logs(min_level, since_seq))
+    }
+
+    /// Set the most verbose level the driver logs at, see
+    /// [AsyncDriverClient::set_log_level].
+    pub fn set_log_level(&self, level: LogLevel) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_log_level(level))
+    }
+
+    /// Set how the adapter exposes monitors from the next driver start on,
+    /// see [AsyncDriverClient::set_adapter_config].
+    pub fn set_adapter_config(&self, config: &AdapterConfig) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.set_adapter_config(config))
+    }
+
+    /// Run a monitor in `mode` alone for `ttl`, then let the driver bring back
+    /// its modes from before, see [AsyncDriverClient::override_mode].
+    pub fn override_mode(
+        &self,
+        id: Id,
+        mode: &Mode,
+        ttl: Duration,
+    ) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.override_mode(id, mode, ttl))
+    }
+
+    /// End an override now, see [AsyncDriverClient::revert_mode].
+    pub fn revert_mode(&self, id: Id) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.revert_mode(id))
+    }
+
+    /// Make the driver read its settings again, see
+    /// [AsyncDriverClient::reload_settings].
+    pub fn reload_settings(&self) -> Result<SettingsReload, error::RequestError> {
+        RUNTIME.block_on(self.0.reload_settings())
+    }
+
     /// Replace all monitors.
     ///
+    /// Returns an error if the monitors contain duplicates, or if more monitors
+    /// are active than [DriverClient::max_monitors] allows.
+    ///
     /// Note: This does not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
-    pub fn set_monitors(&mut self, monitors: &[Monitor]) -> Result<(), error::DuplicateError> {
+    pub fn set_monitors(&self, monitors: &[Monitor]) -> Result<(), error::SetMonitorsError> {
         self.0.set_monitors(monitors)
     }
 
+    /// Ask the driver whether it would accept `monitors`, without applying
+    /// them.
+    ///
+    /// This runs the same checks the driver does when receiving
+    /// [DriverClient::notify], including the driver's monitor limit. Neither
+    /// the client state nor the driver state is changed.
+    pub fn validate_monitors(&self, monitors: &[Monitor]) -> Result<(), error::ValidateError> {
+        RUNTIME.block_on(self.0.validate_monitors(monitors))
+    }
+
     /// Replace an existing monitor. The monitor is identified by its ID.
     ///
     /// Returns an error if the monitor does not exist.
@@ -113,7 +251,7 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn replace_monitor(&mut self, monitor: Monitor) -> Result<(), error::MonNotFound> {
+    pub fn replace_monitor(&self, monitor: Monitor) -> Result<(), error::MonNotFound> {
         self.0.replace_monitor(monitor)
     }
 
@@ -121,51 +259,112 @@ impl DriverClient {
     ///
     /// State changes of the client are not automatically sent to the driver.
     /// You must manually call this method to send changes to the driver.
-    pub fn notify(&mut self) -> Result<(), error::SendError> {
+    pub fn notify(&self) -> Result<(), error::SendError> {
         RUNTIME.block_on(self.0.notify())
     }
 
-    /// Find the monitor with the given ID.
+    /// Send the current client state to the driver after `interval`,
+    /// coalescing the calls in between.
     ///
-    /// Note: Client state might be stale. To synchronize with the driver,
-    /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor(&self, id: Id) -> Option<&Monitor> {
-        self.0.find_monitor(id)
+    /// See [AsyncDriverClient::notify_debounced].
+    pub fn notify_debounced(&self, interval: Duration) {
+        RUNTIME.block_on(self.0.notify_debounced(interval));
     }
 
-    /// Find the monitor matched by the given query.
+    /// Send the current client state to the driver, and let the driver save it
+    /// if `persistence` is set.
     ///
-    /// Note: Client state might be stale. To synchronize with the driver,
-    /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_query(&self, query: &str) -> Option<&Monitor> {
-        self.0.find_monitor_query(query)
+    /// A saved state is restored by the driver the next time it is started.
+    pub fn notify_with(&self, persistence: bool) -> Result<(), error::SendError> {
+        RUNTIME.block_on(self.0.notify_with(persistence))
     }
 
-    /// Find a monitor by ID and call `cb` with a mutable reference to it.
+    /// Send the current client state to the driver and wait until it was
+    /// applied, saving it if `persistence` is set.
     ///
-    /// Note: Any changes do not affect the driver. Manually call
-    /// [DriverClient::notify] to send these changes to the driver.
+    /// Unlike [DriverClient::notify_with], failures on the driver side are
+    /// returned, see [crate::Client::execute].
+    pub fn notify_checked(&self, persistence: bool) -> Result<(), error::RequestError> {
+        RUNTIME.block_on(self.0.notify_checked(persistence))
+    }
+
+    /// Make the driver save its current state.
+    ///
+    /// This saves the state of the driver, not the state of this client, apart
+    /// from a state still waiting in [DriverClient::notify_debounced]. Call
+    /// [DriverClient::notify_with] to send and save the client state at once.
+    pub fn persist_now(&self) -> Result<(), error::SendError> {
+        RUNTIME.block_on(self.0.persist_now())
+    }
+
+    /// Return the driver and this client to the state of a fresh install. See
+    /// [AsyncDriverClient::reset] for details.
+    pub fn reset(&self) -> Result<(), error::ResetError> {
+        RUNTIME.block_on(self.0.reset())
+    }
+
+    /// Send the client state, then block until Windows attached every active
+    /// monitor. See [AsyncDriverClient::apply_and_wait] for details.
+    pub fn apply_and_wait(&self, timeout: Duration) -> Result<(), error::ApplyWaitError> {
+        RUNTIME.block_on(self.0.apply_and_wait(timeout))
+    }
+
+    /// Move the monitors of the client state to their requested
+    /// [Monitor::position], and make the [Monitor::primary] one the primary
+    /// display.
+    ///
+    /// Call this after [DriverClient::notify]. The driver attaches monitors
+    /// asynchronously, so this waits up to `timeout` for them to show up. See
+    /// [display_config::apply_positions] for details.
+    pub fn apply_positions(&self, timeout: Duration) -> Result<(), error::PositionError> {
+        display_config::apply_positions(&self.0.monitors(), timeout)
+    }
+
+    /// Describe the physical display `device_name` (like `\\.\DISPLAY1`) as
+    /// a new monitor, with the same modes and EDID.
+    ///
+    /// The monitor gets a free ID, but is not added. Pass it to
+    /// [DriverClient::add] and call [DriverClient::notify] to create it. See
+    /// [display_config::clone_physical] for details.
+    pub fn clone_physical(&self, device_name: &str) -> Result<Monitor, error::CloneError> {
+        self.0.clone_physical(device_name)
+    }
+
+    /// Request the mode Windows committed for the monitor with the given ID.
+    ///
+    /// See [AsyncDriverClient::active_mode].
+    pub fn active_mode(&self, id: Id) -> Result<Option<ActiveMode>, error::ActiveModeError> {
+        RUNTIME.block_on(self.0.active_mode(id))
+    }
+
+    /// Find the monitor with the given ID, and return a copy of it.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_mut<R>(&mut self, id: Id, cb: impl FnOnce(&mut Monitor) -> R) -> Option<R> {
-        self.0.find_monitor_mut(id, cb)
+    pub fn find_monitor(&self, id: Id) -> Option<Monitor> {
+        self.0.find_monitor(id)
     }
 
-    /// Find a monitor by ID and return a mutable reference to it.
+    /// Find the monitor matched by the given query, and return a copy of it.
     ///
-    /// Does not check for duplicates (since this is not easy when returning a
-    /// mut reference) Caller agrees they will make sure there are no duplicates.
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn find_monitor_query(&self, query: &str) -> Option<Monitor> {
+        self.0.find_monitor_query(query)
+    }
+
+    /// Find a monitor by ID and call `cb` with a mutable reference to it.
     ///
-    /// Despite the "unchecked" part of this name, this is a safe method.
+    /// The client state stays locked while `cb` runs, see
+    /// [AsyncDriverClient::find_monitor_mut].
     ///
     /// Note: Any changes do not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_mut_unchecked(&mut self, id: Id) -> Option<&mut Monitor> {
-        self.0.find_monitor_mut_unchecked(id)
+    pub fn find_monitor_mut<R>(&self, id: Id, cb: impl FnOnce(&mut Monitor) -> R) -> Option<R> {
+        self.0.find_monitor_mut(id, cb)
     }
 
     /// Find the monitor matched by the given query and call `cb` with a mutable
@@ -177,29 +376,13 @@ impl DriverClient {
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
     pub fn find_monitor_mut_query<R>(
-        &mut self,
+        &self,
         query: &str,
         cb: impl FnOnce(&mut Monitor) -> R,
     ) -> Option<R> {
         self.0.find_monitor_mut_query(query, cb)
     }
 
-    /// Find the monitor matching the given query.
-    ///
-    /// Does not check for duplicates (since this is not easy when returning a
-    /// mut reference) Caller agrees they will make sure there are no duplicates.
-    ///
-    /// Despite the "unchecked" part of this name, this is a safe method.
-    ///
-    /// Note: Any changes do not affect the driver. Manually call
-    /// [DriverClient::notify] to send these changes to the driver.
-    ///
-    /// Note: Client state might be stale. To synchronize with the driver,
-    /// manually call [DriverClient::refresh_state].
-    pub fn find_monitor_mut_query_unchecked(&mut self, query: &str) -> Option<&mut Monitor> {
-        self.0.find_monitor_mut_query_unchecked(query)
-    }
-
     /// Write client state to the registry for current user.
     ///
     /// Next time the driver is started, it will load this state from the
@@ -208,7 +391,22 @@ impl DriverClient {
         self.0.persist()
     }
 
-    /// Get the closest available free ID.
+    /// Replace the client state with the monitors written by
+    /// [DriverClient::persist].
+    ///
+    /// Lists written by older versions are migrated and written back, see
+    /// [crate::sync::Client::load_persisted]. Fails like
+    /// [DriverClient::set_monitors].
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    pub fn load_persisted(&self) -> Result<(), error::SetMonitorsError> {
+        self.0.load_persisted()
+    }
+
+    /// Get the closest available free ID, or `preferred_id` if it is given.
+    ///
+    /// Returns `None` if `preferred_id` is taken already.
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
@@ -228,10 +426,19 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn remove(&mut self, ids: &[Id]) {
+    pub fn remove(&self, ids: &[Id]) {
         self.0.remove(ids)
     }
 
+    /// Remove all monitors tagged with `tag`, returns their IDs. See
+    /// [AsyncDriverClient::remove_by_tag].
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    pub fn remove_by_tag(&self, tag: &str) -> Vec<Id> {
+        self.0.remove_by_tag(tag)
+    }
+
     /// Remove all monitors matched by the given queries.
     ///
     /// Returns an error if a query does not match any monitor.
@@ -241,10 +448,7 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn remove_query(
-        &mut self,
-        queries: &[impl AsRef<str>],
-    ) -> Result<(), error::QueryNotFound> {
+    pub fn remove_query(&self, queries: &[impl AsRef<str>]) -> Result<(), error::QueryNotFound> {
         self.0.remove_query(queries)
     }
 
@@ -252,7 +456,7 @@ impl DriverClient {
     ///
     /// Note: This does not affect the driver. Manually call
     /// [DriverClient::notify] to send these changes to the driver.
-    pub fn remove_all(&mut self) {
+    pub fn remove_all(&self) {
         self.0.remove_all()
     }
 
@@ -267,10 +471,40 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn add(&mut self, monitor: Monitor) -> Result<(), error::DuplicateError> {
+    pub fn add(&self, monitor: Monitor) -> Result<(), error::DuplicateError> {
         self.0.add(monitor)
     }
 
+    /// Make sure a monitor named like `spec` exists, with the modes and
+    /// enabled state of `spec`, and return its ID.
+    ///
+    /// The name is the key: the first monitor with the same name gets the
+    /// modes and enabled state of `spec`, everything else about it is kept.
+    /// Without one, `spec` is added under the ID of `spec` if that is free,
+    /// or else the closest free ID. Calling this again with the same `spec`
+    /// changes nothing, so it suits desired state tooling.
+    ///
+    /// Returns an error if `spec` has no name, or it is invalid like for
+    /// [DriverClient::add].
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn ensure_monitor(&self, spec: Monitor) -> Result<Id, error::EnsureMonitorError> {
+        self.0.ensure_monitor(spec)
+    }
+
+    /// Add a copy of the monitor with the given ID, and return the ID of the
+    /// copy. See [AsyncDriverClient::duplicate_monitor] for details.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    pub fn duplicate_monitor(&self, id: Id) -> Result<Id, error::MonNotFound> {
+        self.0.duplicate_monitor(id)
+    }
+
     /// Set enabled state of all monitors with the given IDs.
     ///
     /// Silently skips incorrect IDs.
@@ -280,10 +514,19 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn set_enabled(&mut self, ids: &[Id], enabled: bool) {
+    pub fn set_enabled(&self, ids: &[Id], enabled: bool) {
         self.0.set_enabled(ids, enabled)
     }
 
+    /// Set enabled state of all monitors tagged with `tag`, returns their
+    /// IDs. See [AsyncDriverClient::set_enabled_by_tag].
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    pub fn set_enabled_by_tag(&self, tag: &str, enabled: bool) -> Vec<Id> {
+        self.0.set_enabled_by_tag(tag, enabled)
+    }
+
     /// Set enabled state of all monitors matched by the given queries.
     ///
     /// Returns an error if a query does not match any monitor.
@@ -294,13 +537,59 @@ impl DriverClient {
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
     pub fn set_enabled_query(
-        &mut self,
+        &self,
         queries: &[impl AsRef<str>],
         enabled: bool,
     ) -> Result<(), error::QueryNotFound> {
         self.0.set_enabled_query(queries, enabled)
     }
 
+    /// Set enabled state of all monitors matching `pred`, and send only
+    /// those monitors to the driver. See
+    /// [AsyncDriverClient::set_enabled_where] for details.
+    pub fn set_enabled_where<F: Fn(&Monitor) -> bool>(
+        &self,
+        pred: F,
+        enabled: bool,
+    ) -> Result<ApplyReport, error::RequestError> {
+        RUNTIME.block_on(self.0.set_enabled_where(pred, enabled))
+    }
+
+    /// Park the monitor with the given ID.
+    ///
+    /// A parked monitor keeps its configuration and stays in the driver's
+    /// list, but is detached from the OS until it is unparked. Its enabled
+    /// state is kept as well. Unlike removing and re-adding a monitor,
+    /// unparking reattaches it on the same connector, so Windows restores its
+    /// layout.
+    ///
+    /// Returns an error if the monitor does not exist.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn park(&self, id: Id) -> Result<(), error::MonNotFound> {
+        self.0.park(id)
+    }
+
+    /// Unpark the monitor with the given ID.
+    ///
+    /// The monitor is reattached if it is enabled. The driver rejects this if
+    /// it would exceed [DriverClient::max_monitors].
+    ///
+    /// Returns an error if the monitor does not exist.
+    ///
+    /// Note: This does not affect the driver. Manually call
+    /// [DriverClient::notify] to send these changes to the driver.
+    ///
+    /// Note: Client state might be stale. To synchronize with the driver,
+    /// manually call [DriverClient::refresh_state].
+    pub fn unpark(&self, id: Id) -> Result<(), error::MonNotFound> {
+        self.0.unpark(id)
+    }
+
     /// Add a mode to the monitor with the given ID.
     ///
     /// Returns an error if the monitor does not exist, or if the mode already
@@ -312,7 +601,7 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn add_mode(&mut self, id: Id, mode: Mode) -> Result<(), error::AddModeError> {
+    pub fn add_mode(&self, id: Id, mode: Mode) -> Result<(), error::AddModeError> {
         self.0.add_mode(id, mode)
     }
 
@@ -327,14 +616,23 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn add_mode_query(
-        &mut self,
-        query: &str,
-        mode: Mode,
-    ) -> Result<(), error::AddModeQueryError> {
+    pub fn add_mode_query(&self, query: &str, mode: Mode) -> Result<(), error::AddModeQueryError> {
         self.0.add_mode_query(query, mode)
     }
 
+    /// Switch a monitor to another refresh rate of an existing resolution,
+    /// and send the state to the driver. See
+    /// [AsyncDriverClient::set_refresh_rate] for details.
+    pub fn set_refresh_rate(
+        &self,
+        id: Id,
+        width: Dimen,
+        height: Dimen,
+        refresh_rate: RefreshRate,
+    ) -> Result<(), error::SetRefreshRateError> {
+        RUNTIME.block_on(self.0.set_refresh_rate(id, width, height, refresh_rate))
+    }
+
     /// Remove a mode from the monitor with the given ID.
     ///
     /// Returns an error if the monitor does not exist. If the mode does not
@@ -345,11 +643,7 @@ impl DriverClient {
     ///
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
-    pub fn remove_mode(
-        &mut self,
-        id: Id,
-        resolution: (u32, u32),
-    ) -> Result<(), error::MonNotFound> {
+    pub fn remove_mode(&self, id: Id, resolution: (u32, u32)) -> Result<(), error::MonNotFound> {
         self.0.remove_mode(id, resolution)
     }
 
@@ -364,16 +658,29 @@ impl DriverClient {
     /// Note: Client state might be stale. To synchronize with the driver,
     /// manually call [DriverClient::refresh_state].
     pub fn remove_mode_query(
-        &mut self,
+        &self,
         query: &str,
         resolution: (u32, u32),
     ) -> Result<(), error::QueryNotFound> {
         self.0.remove_mode_query(query, resolution)
     }
 
+    /// Take a copy of the client state, to go back to it later with
+    /// [DriverClient::restore].
+    pub fn snapshot(&self) -> StateSnapshot {
+        self.0.snapshot()
+    }
+
+    /// Make `snapshot` the state of the client and the driver, in one pass.
+    /// See [AsyncDriverClient::restore] for details.
+    pub fn restore(&self, snapshot: StateSnapshot) -> Result<ApplyReport, error::RequestError> {
+        RUNTIME.block_on(self.0.restore(snapshot))
+    }
+
     /// Returns a copy of this client with it's own independent state.
     ///
-    /// Changes to one client will not affect the other.
+    /// Changes to one client will not affect the other. Unlike a clone, which
+    /// shares the state.
     ///
     /// Note: Event receivers created with [DriverClient::add_event_receiver] will
     /// only be closed after all copies are dropped, regardless of which client
@@ -382,3 +689,39 @@ impl DriverClient {
         Self(self.0.duplicate())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::{mock::MockServer, MonitorBuilder};
+
+    #[test]
+    fn events_iterator() {
+        const PIPE_NAME: &str = "virtualdisplaydriver-sync-driver_client-events_iterator";
+
+        let mut server = RUNTIME.block_on(async { MockServer::new(PIPE_NAME) });
+
+        let connecting = thread::spawn(|| DriverClient::new_with(PIPE_NAME));
+        // state and capabilities
+        RUNTIME.block_on(async {
+            server.pump().await;
+            server.pump().await;
+        });
+        let client = connecting.join().unwrap().expect("Failed to connect");
+
+        let events = client.events();
+        let receiver = thread::spawn(move || events.take(1).collect::<Vec<_>>());
+
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+        client.add(monitor.clone()).unwrap();
+        client.notify().unwrap();
+        RUNTIME.block_on(server.pump());
+
+        assert_eq!(receiver.join().unwrap(), [vec![monitor]]);
+    }
+}
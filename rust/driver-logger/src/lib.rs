@@ -1,16 +1,18 @@
 #![allow(clippy::missing_errors_doc)]
 
+pub mod ring;
 mod win_debug;
 mod win_logger;
 
 use std::error::Error;
 
-use log::{Level, Log};
+use log::{Level, LevelFilter, Log};
 
 use crate::win_debug::WinDebugLogger;
 use crate::win_logger::WinLogger;
 
-// A logger which logs to multiple logger implementations
+// A logger which logs to multiple logger implementations, and keeps the latest records in the
+// ring, see `ring::read`
 pub struct DriverLogger {
     pub level: Level,
     win_debug: Option<WinDebugLogger>,
@@ -28,7 +30,10 @@ impl DriverLogger {
     }
 
     pub fn debug(&mut self) -> &mut Self {
-        self.win_debug = Some(WinDebugLogger { level: self.level });
+        // filtered here already, so it follows `set_level`
+        self.win_debug = Some(WinDebugLogger {
+            level: Level::Trace,
+        });
         self
     }
 
@@ -50,9 +55,15 @@ impl DriverLogger {
     }
 }
 
+/// Changes the level of the logger set up with [`DriverLogger::init`], e.g. to debug a running
+/// driver without restarting it
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
 impl Log for DriverLogger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= self.level
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &log::Record) {
@@ -60,6 +71,8 @@ impl Log for DriverLogger {
             return;
         }
 
+        ring::push(record);
+
         if let Some(debug) = self.win_debug.as_ref() {
             debug.log(record);
         }
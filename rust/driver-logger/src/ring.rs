@@ -0,0 +1,252 @@
+//! In memory copy of the latest log records, so clients of the driver can read its log without
+//! access to the event log.
+//!
+//! Logging never waits for a reader: records are sent through a channel, and only moved into the
+//! ring if its lock is free. Otherwise the next record or read moves them.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        LazyLock, Mutex, PoisonError, TryLockError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{Level, Record};
+
+/// Records kept in the ring, older ones are dropped
+pub const RING_CAPACITY: usize = 2000;
+/// Longest message kept in the ring in bytes, longer ones are cut
+pub const MAX_MESSAGE_LEN: usize = 2048;
+
+static RING: LazyLock<LogRing> = LazyLock::new(|| LogRing::new(RING_CAPACITY));
+
+/// A log record kept in the ring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RingRecord {
+    /// Position in the log, increasing by one per record
+    pub seq: u64,
+    pub level: Level,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub target: String,
+    pub message: String,
+}
+
+/// Records of the ring with a sequence number of at least `since_seq` and at least as severe as
+/// `min_level`, oldest first, see [`LogRing::read`]
+pub fn read(since_seq: u64, min_level: Level, max: usize) -> (Vec<RingRecord>, u64) {
+    RING.read(since_seq, min_level, max)
+}
+
+pub(crate) fn push(record: &Record) {
+    RING.push(record);
+}
+
+/// Record as sent through the channel, it gets its sequence number once it's in the ring
+struct Pending {
+    level: Level,
+    timestamp: u64,
+    target: String,
+    message: String,
+}
+
+struct Ring {
+    rx: Receiver<Pending>,
+    records: VecDeque<RingRecord>,
+    next_seq: u64,
+}
+
+impl Ring {
+    fn drain(&mut self, capacity: usize) {
+        while let Ok(pending) = self.rx.try_recv() {
+            if self.records.len() == capacity {
+                self.records.pop_front();
+            }
+
+            self.records.push_back(RingRecord {
+                seq: self.next_seq,
+                level: pending.level,
+                timestamp: pending.timestamp,
+                target: pending.target,
+                message: pending.message,
+            });
+            self.next_seq += 1;
+        }
+    }
+}
+
+/// Bounded ring of log records, see the module docs
+pub struct LogRing {
+    tx: Sender<Pending>,
+    ring: Mutex<Ring>,
+    capacity: usize,
+}
+
+impl LogRing {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let (tx, rx) = mpsc::channel();
+
+        Self {
+            tx,
+            ring: Mutex::new(Ring {
+                rx,
+                records: VecDeque::with_capacity(capacity),
+                next_seq: 0,
+            }),
+            capacity,
+        }
+    }
+
+    /// Adds a record, without waiting if the ring is being read
+    pub fn push(&self, record: &Record) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| {
+                u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+            });
+
+        let mut message = record.args().to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+
+        // the receiver lives as long as the ring
+        _ = self.tx.send(Pending {
+            level: record.level(),
+            timestamp,
+            target: record.target().to_owned(),
+            message,
+        });
+
+        // a reader moves it into the ring otherwise
+        let ring = match self.ring.try_lock() {
+            Ok(ring) => Some(ring),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        };
+
+        if let Some(mut ring) = ring {
+            ring.drain(self.capacity);
+        }
+    }
+
+    /// Up to `max` records with a sequence number of at least `since_seq` and at least as severe
+    /// as `min_level`, oldest first
+    ///
+    /// Also returns the sequence number to read from next time, which is past the returned
+    /// records and past all skipped ones. If records with a sequence number of at least
+    /// `since_seq` were dropped from the ring already, the first returned record is a later one.
+    pub fn read(&self, since_seq: u64, min_level: Level, max: usize) -> (Vec<RingRecord>, u64) {
+        let mut ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        ring.drain(self.capacity);
+
+        let mut records = Vec::new();
+        let mut next_seq = since_seq.max(ring.records.front().map_or(0, |r| r.seq));
+
+        for record in ring.records.iter().filter(|r| r.seq >= since_seq) {
+            if records.len() == max {
+                break;
+            }
+
+            next_seq = record.seq + 1;
+            if record.level <= min_level {
+                records.push(record.clone());
+            }
+        }
+
+        // everything was read, so the client continues after what exists now
+        if records.len() < max {
+            next_seq = next_seq.max(ring.next_seq);
+        }
+
+        (records, next_seq)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn log(ring: &LogRing, level: Level, message: &str) {
+        ring.push(
+            &Record::builder()
+                .level(level)
+                .target("test")
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn keeps_the_latest_records() {
+        let ring = LogRing::new(3);
+        for i in 0..5 {
+            log(&ring, Level::Info, &i.to_string());
+        }
+
+        let (records, next_seq) = ring.read(0, Level::Trace, usize::MAX);
+        let messages = records
+            .iter()
+            .map(|r| r.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["2", "3", "4"]);
+        assert_eq!(records[0].seq, 2);
+        assert_eq!(records[0].target, "test");
+        assert_eq!(next_seq, 5);
+
+        let (records, next_seq) = ring.read(next_seq, Level::Trace, usize::MAX);
+        assert!(records.is_empty());
+        assert_eq!(next_seq, 5);
+    }
+
+    #[test]
+    fn reads_incrementally() {
+        let ring = LogRing::new(10);
+        log(&ring, Level::Debug, "debug");
+        log(&ring, Level::Warn, "warn");
+        log(&ring, Level::Info, "info");
+        log(&ring, Level::Error, "error");
+
+        // filtered records are skipped, not returned later
+        let (records, next_seq) = ring.read(0, Level::Warn, 1);
+        assert_eq!(records[0].message, "warn");
+        assert_eq!(next_seq, 2);
+
+        let (records, next_seq) = ring.read(next_seq, Level::Warn, 1);
+        assert_eq!(records[0].message, "error");
+        assert_eq!(next_seq, 4);
+
+        let (records, next_seq) = ring.read(next_seq, Level::Warn, 1);
+        assert!(records.is_empty());
+        assert_eq!(next_seq, 4);
+    }
+
+    #[test]
+    fn logging_does_not_wait_for_readers() {
+        let ring = LogRing::new(10);
+
+        let held = ring.ring.lock().unwrap();
+        log(&ring, Level::Info, "while reading");
+        drop(held);
+
+        let (records, _) = ring.read(0, Level::Trace, usize::MAX);
+        assert_eq!(records[0].message, "while reading");
+        assert_eq!(records[0].seq, 0);
+    }
+
+    #[test]
+    fn long_messages_are_cut() {
+        let ring = LogRing::new(10);
+        log(&ring, Level::Info, &"é".repeat(MAX_MESSAGE_LEN));
+
+        let (records, _) = ring.read(0, Level::Trace, usize::MAX);
+        assert_eq!(records[0].message, "é".repeat(MAX_MESSAGE_LEN / 2));
+    }
+}
@@ -1,9 +1,6 @@
 use std::{ffi::OsString, io::ErrorKind, sync::mpsc, time::Duration};
 
-use driver_ipc::{
-    sync::{Client, DriverClient},
-    Monitor,
-};
+use driver_ipc::sync::{Client, DriverClient};
 use windows::Win32::{
     Foundation::{CloseHandle, HANDLE},
     Security::{ImpersonateLoggedOnUser, SE_TCB_NAME},
@@ -63,6 +60,9 @@ fn run_service(_arguments: &[OsString]) -> windows_service::Result<()> {
                     SessionChangeReason::SessionLogon
                     | SessionChangeReason::RemoteConnect
                     | SessionChangeReason::SessionUnlock => {
+                        // also after unlocking the same session, which detached them
+                        set_user_session(true);
+
                         // skip if this was already ran for a particular session
                         if latest_session == param.notification.session_id {
                             return ServiceControlHandlerResult::NoError;
@@ -75,11 +75,14 @@ fn run_service(_arguments: &[OsString]) -> windows_service::Result<()> {
                         }
                     }
 
+                    SessionChangeReason::SessionLock => set_user_session(false),
+
                     SessionChangeReason::SessionLogoff => {
                         let Ok(client) = Client::connect() else {
                             return ServiceControlHandlerResult::Other(0x3);
                         };
 
+                        _ = client.set_user_session(false);
                         _ = client.remove_all();
                     }
 
@@ -109,6 +112,11 @@ fn run_service(_arguments: &[OsString]) -> windows_service::Result<()> {
     })?;
 
     if let Ok(session) = get_current_session() {
+        // the service may start after logging on, e.g. right after installing it
+        if user_logged_on(session) {
+            set_user_session(true);
+        }
+
         latest_session = session;
         _ = notify(latest_session);
     }
@@ -140,25 +148,42 @@ fn get_current_session() -> Result<u32, ()> {
     }
 }
 
+/// Whether a user is logged on to the session, as opposed to its logon screen
+fn user_logged_on(session_id: u32) -> bool {
+    let mut token = HANDLE::default();
+    if unsafe { WTSQueryUserToken(session_id, &mut token).is_err() } {
+        return false;
+    }
+
+    _ = unsafe { CloseHandle(token) };
+
+    true
+}
+
+/// Attach or detach the user session monitors, see `SessionScope::User`
+fn set_user_session(active: bool) {
+    if let Ok(client) = Client::connect() {
+        // drivers from before session scopes fail this, they attach every monitor anyway
+        _ = client.set_user_session(active);
+    }
+}
+
 fn notify(session_id: u32) -> Result<(), ServiceControlHandlerResult> {
     impersonate_user(session_id, || {
         let hklm = RegKey::predef(HKEY_CURRENT_USER);
         let key = r"SOFTWARE\VirtualDisplayDriver";
 
-        let Ok(driver_settings) = hklm.open_subkey_with_flags(key, KEY_READ) else {
+        if hklm.open_subkey_with_flags(key, KEY_READ).is_err() {
             return Err(ServiceControlHandlerResult::NoError);
-        };
-
-        let monitors = driver_settings
-            .get_value::<String, _>("data")
-            .map(|data| serde_json::from_str::<Vec<Monitor>>(&data).unwrap_or_default())
-            .unwrap_or_default();
+        }
 
-        let Ok(mut client) = DriverClient::new() else {
+        let Ok(client) = DriverClient::new() else {
             return Err(ServiceControlHandlerResult::NoError);
         };
 
-        if client.set_monitors(&monitors).is_err() {
+        // falls back to the backup value if the primary one is corrupt, and upgrades lists
+        // saved by older versions
+        if client.load_persisted().is_err() {
             return Err(ServiceControlHandlerResult::NoError);
         }
 
@@ -0,0 +1,37 @@
+use std::{env, fs};
+
+use driver_ipc::{bridge::BridgeOptions, sync::serve_bridge};
+use eyre::{bail, Context as _};
+
+use crate::BridgeCommand;
+
+/// Environment variable with the secret, if there is no `--secret-file`.
+const SECRET_VAR: &str = "VDD_BRIDGE_SECRET";
+
+/// Serve the driver to remotes knowing the secret, until the process is
+/// stopped.
+pub fn bridge(command: &BridgeCommand) -> eyre::Result<()> {
+    let secret = match &command.secret_file {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read secret from {}", path.display()))?,
+        None => env::var(SECRET_VAR)
+            .with_context(|| format!("No secret, pass --secret-file or set {SECRET_VAR}"))?,
+    };
+
+    // files usually end with a newline, which is no part of the secret
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("The secret is empty");
+    }
+
+    println!("Serving the driver on {}, stop with Ctrl-C", command.bind);
+    if let Some(metrics) = command.metrics {
+        println!("Serving metrics on http://{metrics}/metrics");
+    }
+
+    let mut options = BridgeOptions::new(command.bind, secret);
+    options.metrics = command.metrics;
+    serve_bridge(options)?;
+
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::{fs, path::Path};
+
+use driver_ipc::{
+    config_file::{ConfigFile, Format, Parsed},
+    monitor_events,
+    sync::DriverClient,
+    validate_monitors, ApplyMode, Monitor, MonitorEvent,
+};
+use eyre::{bail, Context as _};
+use joinery::JoinableIterator;
+use lazy_format::lazy_format;
+use owo_colors::OwoColorize;
+
+use crate::{ExportCommand, FileFormat, GlobalOptions, ImportCommand};
+
+/// Format from the command line, otherwise by the file extension, otherwise
+/// JSON.
+fn format(format: Option<FileFormat>, path: Option<&Path>) -> Format {
+    format
+        .map(Format::from)
+        .or_else(|| path.and_then(Format::from_path))
+        .unwrap_or_default()
+}
+
+pub fn export(
+    client: &DriverClient,
+    opts: &GlobalOptions,
+    command: &ExportCommand,
+) -> eyre::Result<()> {
+    let monitors = client.monitors();
+    let count = monitors.len();
+
+    let format = format(command.format, command.file.as_deref());
+    let data = ConfigFile::new(monitors).to_string(format)?;
+
+    let Some(path) = &command.file else {
+        println!("{data}");
+        return Ok(());
+    };
+
+    fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    if opts.json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &count)?;
+    } else {
+        println!(
+            "Exported {} virtual monitors to {}.",
+            count.green(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn import(
+    client: &DriverClient,
+    opts: &GlobalOptions,
+    command: &ImportCommand,
+) -> eyre::Result<()> {
+    let data = fs::read_to_string(&command.file)
+        .with_context(|| format!("Failed to read {}", command.file.display()))?;
+
+    let format = format(command.format, Some(&command.file));
+    let Parsed {
+        config,
+        unknown_fields,
+    } = ConfigFile::parse(&data, format)
+        .with_context(|| format!("Failed to parse {}", command.file.display()))?;
+
+    // likely written by a newer version, the rest of the file still applies
+    for field in &unknown_fields {
+        eprintln!("{} ignoring unknown field `{field}`", "warning:".yellow());
+    }
+
+    // clap rejects both flags together
+    let mode = if command.merge && !command.replace {
+        ApplyMode::Merge
+    } else {
+        ApplyMode::Replace
+    };
+
+    let current = client.monitors();
+    let monitors = mode.resolve(&current, config.monitors);
+
+    if let Err(errors) = validate_monitors(&monitors) {
+        bail!(
+            "{} contains invalid monitors:\n{}",
+            command.file.display(),
+            errors.iter().join_with("\n")
+        );
+    }
+
+    let changes = monitor_events(&current, &monitors);
+
+    if !opts.json {
+        if changes.is_empty() {
+            println!("Nothing to change.");
+            return Ok(());
+        }
+
+        for change in &changes {
+            print_change(change);
+        }
+    }
+
+    // the resolved list is the complete new state, so it replaces the driver state
+    let report = client.restore(monitors.into())?;
+
+    if opts.json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &report)?;
+        return Ok(());
+    }
+
+    for warning in &report.warnings {
+        eprintln!(
+            "{} monitor {}: {}",
+            "warning:".yellow(),
+            warning.id,
+            warning.message
+        );
+    }
+
+    if report.is_success() {
+        println!("Imported {}.", command.file.display());
+    } else {
+        for failure in &report.failed {
+            eprintln!(
+                "{} monitor {}: {}",
+                "error:".red(),
+                failure.id,
+                failure.message
+            );
+        }
+
+        bail!("{} of the monitors failed to apply", report.failed.len());
+    }
+
+    Ok(())
+}
+
+fn print_change(change: &MonitorEvent) {
+    let describe = |monitor: &Monitor| {
+        let name = lazy_format!(match (&monitor.name) {
+            Some(name) => (" {}{name}{}", "[".dimmed(), "]".dimmed()),
+            None => "",
+        });
+        let modes = monitor
+            .modes
+            .iter()
+            .map(|mode| lazy_format!("{}x{}", mode.width, mode.height))
+            .join_with(", ");
+
+        format!("monitor {}{name} {modes}", monitor.id)
+    };
+
+    match change {
+        MonitorEvent::Added(monitor) => println!("{} {}", "+".green(), describe(monitor)),
+        MonitorEvent::Removed(id) => println!("{} monitor {id}", "-".red()),
+        MonitorEvent::Changed(monitor) => println!("{} {}", "~".yellow(), describe(monitor)),
+    }
+}
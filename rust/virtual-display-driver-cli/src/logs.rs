@@ -0,0 +1,90 @@
+use std::{
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use driver_ipc::{sync::DriverClient, LogLevel, LogRecord, MAX_LOG_RECORDS};
+use eyre::bail;
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use crate::{watch::timestamp, LogsCommand};
+
+/// Wait between two requests for new records while following the log.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// One line of `logs --json`.
+#[derive(Debug, Serialize)]
+struct RecordLine<'a> {
+    time: String,
+    #[serde(flatten)]
+    record: &'a LogRecord,
+}
+
+pub fn parse_level(s: &str) -> eyre::Result<LogLevel> {
+    let level = match s.to_ascii_lowercase().as_str() {
+        "error" => LogLevel::Error,
+        "warn" => LogLevel::Warn,
+        "info" => LogLevel::Info,
+        "debug" => LogLevel::Debug,
+        "trace" => LogLevel::Trace,
+        _ => bail!("invalid log level {s:?}, expected one of error, warn, info, debug or trace"),
+    };
+
+    Ok(level)
+}
+
+/// Print the records the driver still has, and with `--follow` the new ones
+/// until the process is stopped.
+pub fn logs(client: &DriverClient, json: bool, command: &LogsCommand) -> eyre::Result<()> {
+    if let Some(level) = command.set_level {
+        client.set_log_level(level)?;
+    }
+
+    let mut since_seq = 0;
+
+    loop {
+        let (records, next_seq) = client.logs(command.level, since_seq)?;
+        since_seq = next_seq;
+
+        for record in &records {
+            print_record(json, record)?;
+        }
+
+        // a full reply may be followed by more records right away
+        if records.len() < MAX_LOG_RECORDS {
+            if !command.follow {
+                return Ok(());
+            }
+
+            thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+}
+
+fn print_record(json: bool, record: &LogRecord) -> eyre::Result<()> {
+    let time = timestamp(UNIX_EPOCH + Duration::from_millis(record.timestamp));
+
+    if json {
+        println!("{}", serde_json::to_string(&RecordLine { time, record })?);
+        return Ok(());
+    }
+
+    // padded before coloring, the escape codes would count towards the width
+    let level = format!("{:<5}", record.level);
+    let level = match record.level {
+        LogLevel::Error => level.red().to_string(),
+        LogLevel::Warn => level.yellow().to_string(),
+        LogLevel::Info => level.green().to_string(),
+        _ => level.dimmed().to_string(),
+    };
+
+    println!(
+        "{} {level} {} {}",
+        time.dimmed(),
+        record.target.dimmed(),
+        record.message,
+    );
+
+    Ok(())
+}
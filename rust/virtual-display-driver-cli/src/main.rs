@@ -1,13 +1,22 @@
+mod bridge;
+mod config_file;
+mod logs;
 mod mode;
+mod watch;
 
-use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{CommandFactory as _, Parser};
 use eyre::{bail, eyre, Context as _};
 use joinery::JoinableIterator;
 use lazy_format::lazy_format;
 use owo_colors::OwoColorize;
 use serde::{Deserialize, Serialize};
 
-use driver_ipc::{sync::DriverClient, Id, Monitor};
+use driver_ipc::{
+    bridge::DEFAULT_BRIDGE_PORT, stable_id, sync::DriverClient, ColorSpace, DriverInfo,
+    DriverStatus, Id, Monitor, Orientation, SessionScope,
+};
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -15,7 +24,7 @@ struct Args {
     options: GlobalOptions,
 
     #[clap(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 
 #[derive(Debug, Parser)]
@@ -23,14 +32,23 @@ struct GlobalOptions {
     /// Format output as JSON.
     #[clap(short, long)]
     json: bool,
+
+    /// Print the version of this tool and of the running driver.
+    #[clap(short = 'V', long)]
+    version: bool,
 }
 
 #[derive(Debug, Parser)]
 enum Command {
     /// List currently connected virtual monitors.
     List,
+    /// Show which build of the driver is running, and whether it initialized.
+    Status,
     /// Add a new virtual monitor.
     Add(AddCommand),
+    /// Add a new virtual monitor with the modes and EDID of a physical
+    /// display.
+    Clone(CloneCommand),
     /// Add a new resolution/refresh rate mode to an existing virtual monitor.
     AddMode(AddModeCommand),
     /// Remove a resolution/refresh rate mode to an existing virtual monitor.
@@ -39,12 +57,75 @@ enum Command {
     Enable(EnableCommand),
     /// Disable a virtual monitor.
     Disable(DisableCommand),
+    /// Park a virtual monitor. It keeps its configuration, but is detached
+    /// from the system until it is unparked.
+    Park(ParkCommand),
+    /// Reattach a parked virtual monitor.
+    Unpark(UnparkCommand),
+    /// Limit how many frames per second of a virtual monitor reach apps
+    /// capturing it, or lift the limit. Applies right away, without
+    /// reattaching the monitor.
+    LimitFrameRate(LimitFrameRateCommand),
     /// Remove one or more virtual monitors.
     Remove(RemoveCommand),
     /// Remove all virtual monitors.
     RemoveAll,
+    /// Remove all virtual monitors and every saved configuration, like after
+    /// a fresh install.
+    Reset,
     /// Persist changes to current user
     Persist,
+    /// Write the virtual monitors, with their modes, names and EDIDs, to a
+    /// file to import elsewhere.
+    Export(ExportCommand),
+    /// Apply the virtual monitors of a file written by `export`.
+    Import(ImportCommand),
+    /// Print a line per monitor added, removed or changed, until stopped with
+    /// Ctrl-C. Reconnects when the driver restarts.
+    Watch,
+    /// Print the latest records of the driver's log.
+    Logs(LogsCommand),
+    /// Let other machines control the driver over TCP, until stopped with
+    /// Ctrl-C. Remotes speak the pipe protocol after sending the secret,
+    /// unencrypted, so only use it on trusted networks.
+    Bridge(BridgeCommand),
+}
+
+#[derive(Debug, Parser)]
+struct BridgeCommand {
+    /// Address to listen on, e.g. `0.0.0.0:23557` for every network.
+    #[clap(long, default_value_t = SocketAddr::from(([127, 0, 0, 1], DEFAULT_BRIDGE_PORT)))]
+    bind: SocketAddr,
+
+    /// File with the secret remotes have to send. Read from the
+    /// `VDD_BRIDGE_SECRET` environment variable otherwise, never pass it on
+    /// the command line.
+    #[clap(long)]
+    secret_file: Option<PathBuf>,
+
+    /// Also serve the driver's counters in the Prometheus text format on
+    /// `http://<address>/metrics`, e.g. `127.0.0.1:9477`. Anyone reaching
+    /// the address can read them, no secret needed.
+    #[clap(long)]
+    metrics: Option<SocketAddr>,
+}
+
+#[derive(Debug, Parser)]
+struct LogsCommand {
+    /// Keep printing new records as the driver logs them, until stopped with
+    /// Ctrl-C.
+    #[clap(short, long)]
+    follow: bool,
+
+    /// Least severe records to print: `error`, `warn`, `info`, `debug` or
+    /// `trace`.
+    #[clap(long, value_parser = logs::parse_level, default_value = "info")]
+    level: driver_ipc::LogLevel,
+
+    /// Change the most verbose level the driver logs at until it restarts,
+    /// records below it are not kept at all.
+    #[clap(long, value_parser = logs::parse_level)]
+    set_level: Option<driver_ipc::LogLevel>,
 }
 
 #[derive(Debug, Parser)]
@@ -58,6 +139,11 @@ struct AddCommand {
     #[clap(long)]
     id: Option<driver_ipc::Id>,
 
+    /// Derive the ID from the name, so the virtual monitor gets the same ID
+    /// every time it is re-added. Fails if another monitor has that ID.
+    #[clap(long, requires = "name", conflicts_with = "id")]
+    stable_id: bool,
+
     /// Optional label for the virtual monitor.
     #[clap(long)]
     name: Option<String>,
@@ -65,6 +151,97 @@ struct AddCommand {
     /// Set the virtual monitor to disabled on creation.
     #[clap(long)]
     disabled: bool,
+
+    #[clap(flatten)]
+    placement: PlacementArgs,
+
+    #[clap(flatten)]
+    edid: EdidArgs,
+
+    /// Only attach the virtual monitor while a user is logged on and the
+    /// session is unlocked, keeping the logon and lock screen off it. Needs
+    /// the user session service, which tells the driver about sessions.
+    #[clap(long)]
+    user_session: bool,
+
+    /// Brightness of SDR white in nits the virtual monitor's content is meant
+    /// for, between 80 and 480. Only kept for apps capturing the monitor.
+    #[clap(long)]
+    sdr_white_level: Option<u16>,
+
+    /// Most frames per second handed to apps capturing the virtual monitor,
+    /// the frames in between are skipped. Windows still renders at the
+    /// refresh rate of the mode.
+    #[clap(long)]
+    frame_rate_limit: Option<u32>,
+
+    /// Tag to group the virtual monitor by, can be given multiple times.
+    /// Example value: `streaming`.
+    #[clap(long)]
+    tag: Vec<String>,
+}
+
+// where the virtual monitor of `AddCommand` goes on the desktop
+#[derive(Debug, clap::Args)]
+struct PlacementArgs {
+    /// Desktop position of the top left corner of the virtual monitor.
+    /// Example values: `1920,0`, `-1280,0`.
+    #[clap(long, value_parser = parse_position, allow_hyphen_values = true)]
+    position: Option<(i32, i32)>,
+
+    /// Make the virtual monitor the primary display. Other displays keep their
+    /// place relative to it.
+    #[clap(long)]
+    primary: bool,
+
+    /// Rotate the desktop on the virtual monitor once it is attached.
+    #[clap(long, value_enum)]
+    orientation: Option<OrientationArg>,
+}
+
+// what the EDID of the virtual monitor of `AddCommand` reports
+#[derive(Debug, clap::Args)]
+struct EdidArgs {
+    /// Three letter manufacturer ID reported in the monitor's EDID.
+    /// Example value: `DEL`.
+    #[clap(long)]
+    manufacturer: Option<String>,
+
+    /// Product code reported in the monitor's EDID.
+    #[clap(long, default_value_t = 0)]
+    product_code: u16,
+
+    /// Serial number reported in the monitor's EDID. Defaults to the
+    /// monitor's ID, and must not conflict with another virtual monitor.
+    #[clap(long)]
+    serial: Option<u32>,
+
+    /// Adaptive sync range advertised in the monitor's EDID, within the
+    /// refresh rates of its modes. Only apps reading the EDID observe it.
+    /// Example value: `48-144`.
+    #[clap(long, value_parser = parse_vrr_range)]
+    vrr: Option<(u16, u16)>,
+
+    /// Advertise basic PCM audio in the monitor's EDID. No audio is routed
+    /// to the virtual monitor.
+    #[clap(long)]
+    audio: bool,
+
+    /// Color space the virtual monitor's EDID claims. Defaults to sRGB.
+    #[clap(long, value_enum)]
+    color_space: Option<ColorSpaceArg>,
+}
+
+#[derive(Debug, Parser)]
+struct CloneCommand {
+    /// Name of the physical display to clone, as shown by Windows.
+    /// Example value: `\\.\DISPLAY1`.
+    device: String,
+
+    /// Optional label for the virtual monitor. Defaults to the name of the
+    /// physical display.
+    #[clap(long)]
+    name: Option<String>,
 }
 
 #[derive(Debug, Parser)]
@@ -101,55 +278,338 @@ struct DisableCommand {
     id: String,
 }
 
+#[derive(Debug, Parser)]
+struct ParkCommand {
+    // The ID or name of the monitor to park.
+    id: String,
+}
+
+#[derive(Debug, Parser)]
+struct UnparkCommand {
+    // The ID or name of the monitor to unpark.
+    id: String,
+}
+
+#[derive(Debug, Parser)]
+struct LimitFrameRateCommand {
+    // The ID or name of the monitor to limit.
+    id: String,
+
+    /// Frames per second. Lifts the limit if omitted.
+    fps: Option<u32>,
+}
+
 #[derive(Debug, Parser)]
 struct RemoveCommand {
     // One or more monitor IDs or names to remove.
     id: Vec<String>,
 }
 
+#[derive(Debug, Parser)]
+struct ExportCommand {
+    /// File to write to. Prints to stdout if omitted.
+    #[clap(long)]
+    file: Option<PathBuf>,
+
+    /// Format of the file. Defaults to the file extension, otherwise JSON.
+    #[clap(long)]
+    format: Option<FileFormat>,
+}
+
+#[derive(Debug, Parser)]
+struct ImportCommand {
+    /// File to read from.
+    #[clap(long)]
+    file: PathBuf,
+
+    /// Remove virtual monitors which are not in the file. This is the
+    /// default.
+    #[clap(long, conflicts_with = "merge")]
+    replace: bool,
+
+    /// Keep virtual monitors which are not in the file, monitors in the file
+    /// replace those with the same ID.
+    #[clap(long)]
+    merge: bool,
+
+    /// Format of the file. Defaults to the file extension, otherwise JSON.
+    #[clap(long)]
+    format: Option<FileFormat>,
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+enum OrientationArg {
+    Landscape,
+    Portrait,
+    LandscapeFlipped,
+    PortraitFlipped,
+}
+
+impl From<OrientationArg> for Orientation {
+    fn from(orientation: OrientationArg) -> Self {
+        match orientation {
+            OrientationArg::Landscape => Self::Landscape,
+            OrientationArg::Portrait => Self::Portrait,
+            OrientationArg::LandscapeFlipped => Self::LandscapeFlipped,
+            OrientationArg::PortraitFlipped => Self::PortraitFlipped,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+enum ColorSpaceArg {
+    Srgb,
+    DciP3,
+    Bt2020,
+}
+
+impl From<ColorSpaceArg> for ColorSpace {
+    fn from(color_space: ColorSpaceArg) -> Self {
+        match color_space {
+            ColorSpaceArg::Srgb => Self::Srgb,
+            ColorSpaceArg::DciP3 => Self::DciP3,
+            ColorSpaceArg::Bt2020 => Self::Bt2020,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+enum FileFormat {
+    Json,
+    Toml,
+}
+
+impl From<FileFormat> for driver_ipc::config_file::Format {
+    fn from(format: FileFormat) -> Self {
+        match format {
+            FileFormat::Json => Self::Json,
+            FileFormat::Toml => Self::Toml,
+        }
+    }
+}
+
+/// Time to wait for the driver to attach a monitor before moving it.
+const POSITION_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn parse_position(s: &str) -> eyre::Result<(i32, i32)> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| eyre!("invalid position {s:?}, expected a string like \"1920,0\""))?;
+    let x = x
+        .parse()
+        .with_context(|| format!("invalid x coordinate in {s:?}, expected a number"))?;
+    let y = y
+        .parse()
+        .with_context(|| format!("invalid y coordinate in {s:?}, expected a number"))?;
+
+    Ok((x, y))
+}
+
+fn parse_vrr_range(s: &str) -> eyre::Result<(u16, u16)> {
+    let (min, max) = s
+        .split_once('-')
+        .ok_or_else(|| eyre!("invalid range {s:?}, expected a string like \"48-144\""))?;
+    let min = min
+        .parse()
+        .with_context(|| format!("invalid minimum refresh rate in {s:?}, expected a number"))?;
+    let max = max
+        .parse()
+        .with_context(|| format!("invalid maximum refresh rate in {s:?}, expected a number"))?;
+
+    Ok((min, max))
+}
+
 fn main() -> eyre::Result<()> {
     let Args { options, command } = Args::parse();
-    let mut client = DriverClient::new().context("Failed to connect to Virtual Display Driver; please ensure the driver is installed and working")?;
+
+    if options.version {
+        return version(&options);
+    }
+
+    let Some(command) = command else {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required",
+            )
+            .exit();
+    };
+
+    // waits for the driver itself, instead of failing when it is not running
+    if let Command::Watch = command {
+        return watch::watch(options.json);
+    }
+
+    // every remote gets its own connection to the driver
+    if let Command::Bridge(command) = &command {
+        return bridge::bridge(command);
+    }
+
+    let client = DriverClient::new().context("Failed to connect to Virtual Display Driver; please ensure the driver is installed and working")?;
 
     match command {
         Command::List => {
-            list(&mut client, &options)?;
+            list(&client, &options)?;
+        }
+        Command::Status => {
+            status(&client, &options)?;
         }
         Command::Add(command) => {
-            add(&mut client, &options, command)?;
+            add(&client, &options, command)?;
+        }
+        Command::Clone(command) => {
+            clone(&client, &options, command)?;
         }
         Command::AddMode(command) => {
-            add_mode(&mut client, &options, command)?;
+            add_mode(&client, &options, command)?;
         }
         Command::RemoveMode(command) => {
-            remove_mode(&mut client, &options, &command)?;
+            remove_mode(&client, &options, &command)?;
         }
         Command::Enable(command) => {
-            enable(&mut client, &options, &command)?;
+            enable(&client, &options, &command)?;
         }
         Command::Disable(command) => {
-            disable(&mut client, &options, &command)?;
+            disable(&client, &options, &command)?;
+        }
+        Command::Park(command) => {
+            park(&client, &options, &command)?;
+        }
+        Command::Unpark(command) => {
+            unpark(&client, &options, &command)?;
+        }
+        Command::LimitFrameRate(command) => {
+            limit_frame_rate(&client, &options, &command)?;
         }
         Command::Remove(command) => {
-            remove(&mut client, &options, &command)?;
+            remove(&client, &options, &command)?;
         }
         Command::RemoveAll => {
-            remove_all(&mut client, &options)?;
+            remove_all(&client, &options)?;
+        }
+        Command::Reset => {
+            reset(&client, &options)?;
         }
         Command::Persist => {
-            persist(&mut client)?;
+            persist(&client)?;
+        }
+        Command::Export(command) => {
+            config_file::export(&client, &options, &command)?;
         }
+        Command::Import(command) => {
+            config_file::import(&client, &options, &command)?;
+        }
+        Command::Logs(command) => {
+            logs::logs(&client, options.json, &command)?;
+        }
+        // handled above
+        Command::Watch | Command::Bridge(_) => unreachable!(),
     }
 
     Ok(())
 }
 
-fn persist(client: &mut DriverClient) -> eyre::Result<()> {
+fn persist(client: &DriverClient) -> eyre::Result<()> {
     client.persist()?;
     Ok(())
 }
 
-fn list(client: &mut DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
+fn version(opts: &GlobalOptions) -> eyre::Result<()> {
+    let cli_version = env!("CARGO_PKG_VERSION");
+
+    // the tool's own version is useful even without a working driver
+    if !opts.json {
+        println!("virtual-display-driver-cli {cli_version}");
+    }
+
+    let client = DriverClient::new().context("Failed to connect to Virtual Display Driver; please ensure the driver is installed and working")?;
+    let info = client.version()?;
+
+    if opts.json {
+        #[derive(Serialize)]
+        struct Versions<'a> {
+            cli: &'a str,
+            driver: &'a DriverInfo,
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(
+            &mut stdout,
+            &Versions {
+                cli: cli_version,
+                driver: &info,
+            },
+        )?;
+    } else {
+        println!();
+        print_driver_info(&info);
+    }
+
+    Ok(())
+}
+
+fn status(client: &DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
+    let info = client.version()?;
+    // drivers from before the status request can't tell
+    let driver_status = client.driver_status().ok();
+
+    if opts.json {
+        #[derive(Serialize)]
+        struct Status<'a> {
+            #[serde(flatten)]
+            driver: &'a DriverInfo,
+            status: Option<&'a DriverStatus>,
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(
+            &mut stdout,
+            &Status {
+                driver: &info,
+                status: driver_status.as_ref(),
+            },
+        )?;
+    } else {
+        print_driver_info(&info);
+
+        match driver_status {
+            Some(DriverStatus::Ready) => println!("{} {}", "Status:".dimmed(), "ready".green()),
+            Some(DriverStatus::Failed(failure)) => {
+                println!("{} {}", "Status:".dimmed(), failure.red());
+            }
+            Some(status) => println!("{} {status}", "Status:".dimmed()),
+            None => println!("{} unknown", "Status:".dimmed()),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_driver_info(info: &DriverInfo) {
+    let iddcx_version = lazy_format!(match (info.iddcx_version) {
+        Some(version) => ("{version:#06X}"),
+        None => "unknown",
+    });
+
+    let profile = if info.profile.is_empty() {
+        "unknown"
+    } else {
+        &info.profile
+    };
+
+    let wdf_version = info.wdf_version.as_deref().unwrap_or("unknown");
+
+    println!("{}", "Virtual Display Driver".underline());
+    println!("{} {}", "Version:".dimmed(), info.version.green());
+    println!("{} {}", "Commit:".dimmed(), info.commit);
+    println!("{} {}", "Built:".dimmed(), info.build_date);
+    println!("{} {profile}", "Profile:".dimmed());
+    println!("{} {}", "Protocol:".dimmed(), info.protocol_version);
+    println!("{} {iddcx_version}", "IddCx:".dimmed());
+    println!("{} {wdf_version}", "WDF:".dimmed());
+}
+
+fn list(client: &DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
     let monitors = client.monitors();
 
     if opts.json {
@@ -170,11 +630,46 @@ fn list(client: &mut DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
             else =>
                 (" {}", "(disabled)".red())
             );
+            let parked_label = lazy_format!(if monitor.parked =>
+                (" {}", "(parked)".yellow())
+            else => ""
+            );
+            let position_label = lazy_format!(match (monitor.position) {
+                Some((x, y)) => (" {} {x},{y}", "at".dimmed()),
+                None => "",
+            });
+            let primary_label = lazy_format!(if monitor.primary =>
+                (" {}", "(primary)".cyan())
+            else => ""
+            );
+            let vrr_label = lazy_format!(match (monitor.vrr_range) {
+                Some((min, max)) => (" {} {min}-{max} Hz", "VRR".dimmed()),
+                None => "",
+            });
+            let rotation = monitor
+                .orientation
+                .filter(|&orientation| orientation != Orientation::Landscape);
+            let orientation_label = lazy_format!(match (rotation) {
+                Some(orientation) => (" {} {}°", "rotated".dimmed(), orientation.degrees()),
+                None => "",
+            });
+            let limit_label = lazy_format!(match (monitor.frame_rate_limit) {
+                Some(fps) => (" {} {fps} fps", "limited to".dimmed()),
+                None => "",
+            });
             println!(
-                "Monitor {}{name_label}{disabled_label}:",
+                "Monitor {}{name_label}{disabled_label}{parked_label}{position_label}{primary_label}{vrr_label}{orientation_label}{limit_label}:",
                 monitor.id.green(),
             );
 
+            if !monitor.tags.is_empty() {
+                println!(
+                    "{} {}",
+                    "Tags:".dimmed(),
+                    monitor.tags.iter().join_with(", ")
+                );
+            }
+
             if monitor.modes.is_empty() {
                 println!("{} {}", "-".dimmed(), "No modes".red());
             } else {
@@ -203,27 +698,67 @@ fn list(client: &mut DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
     Ok(())
 }
 
-fn add(client: &mut DriverClient, opts: &GlobalOptions, command: AddCommand) -> eyre::Result<()> {
+fn add(client: &DriverClient, opts: &GlobalOptions, command: AddCommand) -> eyre::Result<()> {
     let modes = command
         .mode
         .into_iter()
         .map(driver_ipc::Mode::from)
         .collect::<Vec<_>>();
 
+    let requested_id = match &command.name {
+        Some(name) if command.stable_id => Some(stable_id(name)),
+        _ => command.id,
+    };
     let id = client
-        .new_id(command.id)
-        .ok_or_else(|| eyre!("Monitor {} already exists", command.id.unwrap()))?;
+        .new_id(requested_id)
+        .ok_or_else(|| eyre!("Monitor {} already exists", requested_id.unwrap()))?;
 
     let new_monitor = driver_ipc::Monitor {
         id,
         enabled: !command.disabled,
+        position: command.placement.position,
+        primary: command.placement.primary,
         name: command.name,
         modes,
+        manufacturer_id: command.edid.manufacturer,
+        product_code: command.edid.product_code,
+        serial: command.edid.serial,
+        vrr_range: command.edid.vrr,
+        audio: command.edid.audio,
+        session_scope: if command.user_session {
+            SessionScope::User
+        } else {
+            SessionScope::System
+        },
+        orientation: command.placement.orientation.map(Orientation::from),
+        tags: command.tag,
+        color_space: command.edid.color_space.map(ColorSpace::from),
+        sdr_white_level_nits: command.sdr_white_level,
+        frame_rate_limit: command.frame_rate_limit,
+        ..Default::default()
     };
 
+    if command.placement.primary {
+        let primary = client
+            .monitors()
+            .iter()
+            .filter(|monitor| monitor.primary)
+            .map(|monitor| monitor.id)
+            .collect::<Vec<_>>();
+
+        for id in primary {
+            client.find_monitor_mut(id, |monitor| monitor.primary = false);
+        }
+    }
+
     client.add(new_monitor)?;
     client.notify()?;
 
+    let placement = &command.placement;
+    if placement.position.is_some() || placement.primary || placement.orientation.is_some() {
+        client.apply_positions(POSITION_TIMEOUT)?;
+    }
+
     if opts.json {
         let mut stdout = std::io::stdout().lock();
         serde_json::to_writer_pretty(&mut stdout, &id)?;
@@ -241,8 +776,32 @@ fn add(client: &mut DriverClient, opts: &GlobalOptions, command: AddCommand) ->
     Ok(())
 }
 
+fn clone(client: &DriverClient, opts: &GlobalOptions, command: CloneCommand) -> eyre::Result<()> {
+    let mut new_monitor = client.clone_physical(&command.device)?;
+    if command.name.is_some() {
+        new_monitor.name = command.name;
+    }
+
+    let id = new_monitor.id;
+    client.add(new_monitor)?;
+    client.notify()?;
+
+    if opts.json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &id)?;
+    } else {
+        println!(
+            "Added virtual monitor with ID {} as a clone of {}.",
+            id.green(),
+            command.device
+        );
+    }
+
+    Ok(())
+}
+
 fn add_mode(
-    client: &mut DriverClient,
+    client: &DriverClient,
     opts: &GlobalOptions,
     command: AddModeCommand,
 ) -> eyre::Result<()> {
@@ -251,8 +810,9 @@ fn add_mode(
 
         let existing_modes = monitor.modes.iter().cloned().map(mode::Mode::from);
         let new_modes = mode::merge(existing_modes.chain(command.mode));
-        let new_modes: Vec<driver_ipc::Mode> =
+        let mut new_modes: Vec<driver_ipc::Mode> =
             new_modes.into_iter().map(driver_ipc::Mode::from).collect();
+        mode::keep_timings(&monitor.modes, &mut new_modes);
 
         monitor.modes.clone_from(&new_modes);
         (id, new_modes)
@@ -273,7 +833,7 @@ fn add_mode(
 }
 
 fn remove_mode(
-    client: &mut DriverClient,
+    client: &DriverClient,
     opts: &GlobalOptions,
     command: &RemoveModeCommand,
 ) -> eyre::Result<()> {
@@ -285,8 +845,9 @@ fn remove_mode(
 
                 let modes = monitor.modes.iter().cloned().map(mode::Mode::from);
                 let new_modes = mode::remove(modes, &command.mode)?;
-                let new_modes: Vec<driver_ipc::Mode> =
+                let mut new_modes: Vec<driver_ipc::Mode> =
                     new_modes.into_iter().map(driver_ipc::Mode::from).collect();
+                mode::keep_timings(&monitor.modes, &mut new_modes);
 
                 monitor.modes.clone_from(&new_modes);
                 eyre::Result::Ok((id, new_modes))
@@ -311,7 +872,7 @@ fn remove_mode(
 }
 
 fn enable(
-    client: &mut DriverClient,
+    client: &DriverClient,
     opts: &GlobalOptions,
     command: &EnableCommand,
 ) -> eyre::Result<()> {
@@ -336,7 +897,7 @@ fn enable(
 }
 
 fn disable(
-    client: &mut DriverClient,
+    client: &DriverClient,
     opts: &GlobalOptions,
     command: &DisableCommand,
 ) -> eyre::Result<()> {
@@ -360,8 +921,54 @@ fn disable(
     Ok(())
 }
 
+fn park(client: &DriverClient, opts: &GlobalOptions, command: &ParkCommand) -> eyre::Result<()> {
+    let outcome = set_parked(client, &command.id, true)?;
+
+    if opts.json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &outcome)?;
+    } else {
+        let footnote = if outcome.toggled {
+            ""
+        } else {
+            " (was already parked)"
+        };
+        println!(
+            "Parked virtual monitor with ID {}{footnote}.",
+            outcome.monitor.id.green()
+        );
+    }
+
+    Ok(())
+}
+
+fn unpark(
+    client: &DriverClient,
+    opts: &GlobalOptions,
+    command: &UnparkCommand,
+) -> eyre::Result<()> {
+    let outcome = set_parked(client, &command.id, false)?;
+
+    if opts.json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &outcome)?;
+    } else {
+        let footnote = if outcome.toggled {
+            ""
+        } else {
+            " (was not parked)"
+        };
+        println!(
+            "Unparked virtual monitor with ID {}{footnote}.",
+            outcome.monitor.id.green()
+        );
+    }
+
+    Ok(())
+}
+
 fn remove(
-    client: &mut DriverClient,
+    client: &DriverClient,
     opts: &GlobalOptions,
     command: &RemoveCommand,
 ) -> eyre::Result<()> {
@@ -380,7 +987,7 @@ fn remove(
     Ok(())
 }
 
-fn remove_all(client: &mut DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
+fn remove_all(client: &DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
     client.remove_all();
     client.notify()?;
 
@@ -394,15 +1001,60 @@ fn remove_all(client: &mut DriverClient, opts: &GlobalOptions) -> eyre::Result<(
     Ok(())
 }
 
+fn reset(client: &DriverClient, opts: &GlobalOptions) -> eyre::Result<()> {
+    client.reset()?;
+
+    if opts.json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &())?;
+    } else {
+        println!("Removed all virtual monitors and saved configurations.");
+    }
+
+    Ok(())
+}
+
+fn limit_frame_rate(
+    client: &DriverClient,
+    opts: &GlobalOptions,
+    command: &LimitFrameRateCommand,
+) -> eyre::Result<()> {
+    let monitor = client
+        .find_monitor_query(&command.id)
+        .ok_or(eyre!("Monitor matching `{}` not found", command.id))?;
+
+    client.find_monitor_mut(monitor.id, |monitor| {
+        monitor.frame_rate_limit = command.fps;
+    });
+    client.notify()?;
+
+    if opts.json {
+        let monitor = client.find_monitor(monitor.id);
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &monitor)?;
+    } else if let Some(fps) = command.fps {
+        println!(
+            "Limited virtual monitor with ID {} to {fps} fps.",
+            monitor.id.green()
+        );
+    } else {
+        println!(
+            "Lifted the frame rate limit of virtual monitor with ID {}.",
+            monitor.id.green()
+        );
+    }
+
+    Ok(())
+}
+
 fn set_enabled(
-    client: &mut DriverClient,
+    client: &DriverClient,
     query: &str,
     enabled: bool,
 ) -> eyre::Result<EnableDisableOutcome> {
     let monitor = client
         .find_monitor_query(query)
-        .ok_or(eyre!("Monitor matching `{query}` not found"))?
-        .clone();
+        .ok_or(eyre!("Monitor matching `{query}` not found"))?;
 
     client.set_enabled_query(&[query], enabled)?;
     client.notify()?;
@@ -415,6 +1067,32 @@ fn set_enabled(
     })
 }
 
+fn set_parked(client: &DriverClient, query: &str, parked: bool) -> eyre::Result<ParkOutcome> {
+    let monitor = client
+        .find_monitor_query(query)
+        .ok_or(eyre!("Monitor matching `{query}` not found"))?;
+
+    if parked {
+        client.park(monitor.id)?;
+    } else {
+        client.unpark(monitor.id)?;
+    }
+    client.notify()?;
+
+    let should_toggle = parked != monitor.parked;
+
+    Ok(ParkOutcome {
+        monitor,
+        toggled: should_toggle,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+struct ParkOutcome {
+    monitor: driver_ipc::Monitor,
+    toggled: bool,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 struct EnableDisableOutcome {
     monitor: driver_ipc::Monitor,
@@ -55,6 +55,7 @@ impl From<Mode> for driver_ipc::Mode {
             width: value.width,
             height: value.height,
             refresh_rates: value.refresh_rates.into_iter().collect(),
+            timing: None,
         }
     }
 }
@@ -137,6 +138,17 @@ pub fn merge(modes: impl IntoIterator<Item = Mode>) -> Vec<Mode> {
         .collect()
 }
 
+/// Copy the custom timings of `old` to the modes of `new` with the same
+/// resolution, which the conversion from [`Mode`] leaves out.
+pub fn keep_timings(old: &[driver_ipc::Mode], new: &mut [driver_ipc::Mode]) {
+    for mode in new {
+        mode.timing = old
+            .iter()
+            .find(|m| m.width == mode.width && m.height == mode.height)
+            .and_then(|m| m.timing);
+    }
+}
+
 /// Remove a mode from a list of modes. If `remove_mode` includes a refresh
 /// rate, then only that refresh rate will be removed from the mode; otherwise,
 /// the entire mode will be removed. Returns an error if no mode matches
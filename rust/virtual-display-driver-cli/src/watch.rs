@@ -0,0 +1,163 @@
+use std::{
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use driver_ipc::{
+    error::{ConnectionError, RequestError},
+    sync::Client,
+    Dimen, Id, Monitor, MonitorEvent,
+};
+use eyre::bail;
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+/// Wait before the first reconnect, doubled for every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// Longest wait between two reconnects.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// One line of `watch --json`.
+#[derive(Debug, Serialize)]
+struct EventLine<'a> {
+    time: String,
+    event: &'static str,
+    id: Id,
+    width: Option<Dimen>,
+    height: Option<Dimen>,
+    monitor: Option<&'a Monitor>,
+}
+
+impl<'a> EventLine<'a> {
+    fn new(event: &'a MonitorEvent) -> Self {
+        let (kind, monitor) = match event {
+            MonitorEvent::Added(monitor) => ("added", Some(monitor)),
+            MonitorEvent::Removed(_) => ("removed", None),
+            MonitorEvent::Changed(monitor) => ("changed", Some(monitor)),
+        };
+
+        // the preferred mode is the first one
+        let mode = monitor.and_then(|monitor| monitor.modes.first());
+
+        Self {
+            time: timestamp(SystemTime::now()),
+            event: kind,
+            id: event.id(),
+            width: mode.map(|mode| mode.width),
+            height: mode.map(|mode| mode.height),
+            monitor,
+        }
+    }
+}
+
+/// Print the monitor events of the driver until the process is stopped.
+///
+/// Reconnects with backoff when the driver goes away, e.g. because it
+/// restarted. Only errors which retrying can't fix are returned.
+pub fn watch(json: bool) -> eyre::Result<()> {
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let client = match Client::connect() {
+            Ok(client) => client,
+            Err(e @ (ConnectionError::AccessDenied | ConnectionError::Incompatible { .. })) => {
+                bail!(e);
+            }
+            Err(e) => {
+                status(json, &format!("Failed to connect: {e}"));
+                retry(&mut backoff);
+                continue;
+            }
+        };
+
+        // the events end when the client is dropped, so it is kept until then
+        let events = match client.receive_events() {
+            Ok(events) => events,
+            Err(e @ (RequestError::Ipc(_) | RequestError::Unsupported(_))) => {
+                bail!("The driver does not support monitor events: {e}");
+            }
+            Err(e) => {
+                status(json, &format!("Failed to subscribe: {e}"));
+                retry(&mut backoff);
+                continue;
+            }
+        };
+
+        status(json, "Watching monitor events");
+        backoff = INITIAL_BACKOFF;
+
+        for event in events {
+            print_event(json, &EventLine::new(&event))?;
+        }
+
+        status(json, "Driver disconnected");
+    }
+}
+
+fn retry(backoff: &mut Duration) {
+    thread::sleep(*backoff);
+    *backoff = (*backoff * 2).min(MAX_BACKOFF);
+}
+
+/// Connection changes go to stderr, so stdout only has the events.
+fn status(json: bool, message: &str) {
+    if json {
+        eprintln!("{message}");
+    } else {
+        eprintln!("{}", message.dimmed());
+    }
+}
+
+fn print_event(json: bool, line: &EventLine) -> eyre::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(line)?);
+        return Ok(());
+    }
+
+    let kind = match line.event {
+        "added" => line.event.green().to_string(),
+        "removed" => line.event.red().to_string(),
+        _ => line.event.yellow().to_string(),
+    };
+
+    match (line.width, line.height) {
+        (Some(width), Some(height)) => println!(
+            "{} {kind} monitor {} {}{}{}",
+            line.time.dimmed(),
+            line.id.green(),
+            width.green(),
+            "x".dimmed(),
+            height.green(),
+        ),
+        _ => println!("{} {kind} monitor {}", line.time.dimmed(), line.id.green()),
+    }
+
+    Ok(())
+}
+
+/// `time` as UTC in RFC 3339 format, with milliseconds.
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -1,6 +1,6 @@
 use std::error::Error;
 
-use vergen_gix::{Emitter, GixBuilder};
+use vergen_gix::{BuildBuilder, Emitter, GixBuilder};
 
 fn main() -> Result<(), Box<dyn Error>> {
     let mut winres = winres::WindowsResource::new();
@@ -33,8 +33,13 @@ LANGUAGE 0x9, 0x1
 
     println!("cargo::rerun-if-changed=build.rs");
 
+    // `debug` or `release`, reported to clients with the driver info
+    let profile = std::env::var("PROFILE")?;
+    println!("cargo::rustc-env=VDD_BUILD_PROFILE={profile}");
+
     // emit vergen build instructions
     Emitter::default()
+        .add_instructions(&BuildBuilder::default().build_date(true).build()?)?
         .add_instructions(&GixBuilder::all_git()?)?
         .emit()?;
 
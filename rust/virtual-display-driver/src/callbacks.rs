@@ -3,7 +3,9 @@ use std::{
     ptr::NonNull,
 };
 
+use driver_ipc::{ActiveMode, CustomTiming, InitStep};
 use log::error;
+use wdf_umdf::{iddcx_callback, logging, wdf_callback};
 use wdf_umdf_sys::{
     DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1,
     DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1, __BindgenBitfieldUnit,
@@ -12,57 +14,123 @@ use wdf_umdf_sys::{
     IDARG_IN_COMMITMODES, IDARG_IN_GETDEFAULTDESCRIPTIONMODES, IDARG_IN_PARSEMONITORDESCRIPTION,
     IDARG_IN_QUERYTARGETMODES, IDARG_IN_SETSWAPCHAIN, IDARG_OUT_GETDEFAULTDESCRIPTIONMODES,
     IDARG_OUT_PARSEMONITORDESCRIPTION, IDARG_OUT_QUERYTARGETMODES, IDDCX_ADAPTER__,
-    IDDCX_MONITOR_MODE, IDDCX_MONITOR_MODE_ORIGIN, IDDCX_MONITOR__, IDDCX_TARGET_MODE, NTSTATUS,
+    IDDCX_MONITOR_MODE, IDDCX_MONITOR_MODE_ORIGIN, IDDCX_MONITOR__, IDDCX_PATH_FLAGS,
+    IDDCX_TARGET_MODE, NTSTATUS, PFN_IDD_CX_ADAPTER_COMMIT_MODES, PFN_IDD_CX_ADAPTER_INIT_FINISHED,
+    PFN_IDD_CX_MONITOR_ASSIGN_SWAPCHAIN, PFN_IDD_CX_MONITOR_GET_DEFAULT_DESCRIPTION_MODES,
+    PFN_IDD_CX_MONITOR_QUERY_TARGET_MODES, PFN_IDD_CX_MONITOR_UNASSIGN_SWAPCHAIN,
+    PFN_IDD_CX_PARSE_MONITOR_DESCRIPTION, PFN_WDF_DEVICE_D0_ENTRY, PFN_WDF_DEVICE_D0_EXIT,
     WDFDEVICE, WDF_POWER_DEVICE_STATE,
 };
 
 use crate::{
     context::{DeviceContext, MonitorContext},
     edid::Edid,
-    ipc::{AdapterObject, FlattenModes, ADAPTER, MONITOR_MODES},
+    ipc::{self, AdapterObject, FlattenModes, ModeItem, ADAPTER, MONITOR_MODES},
+    shutdown::SHUTDOWN_TIMEOUT,
 };
 
-pub extern "C-unwind" fn adapter_init_finished(
-    adapter_object: *mut IDDCX_ADAPTER__,
-    _p_in_args: *const IDARG_IN_ADAPTER_INIT_FINISHED,
-) -> NTSTATUS {
-    let Some(adapter_ptr) = NonNull::new(adapter_object) else {
-        error!("Adapter ptr was null");
-        return NTSTATUS::STATUS_INVALID_ADDRESS;
-    };
+iddcx_callback! {
+    pub fn adapter_init_finished(
+        adapter_object: *mut IDDCX_ADAPTER__,
+        p_in_args: *const IDARG_IN_ADAPTER_INIT_FINISHED,
+    ) -> NTSTATUS as PFN_IDD_CX_ADAPTER_INIT_FINISHED {
+        let in_args = unsafe { &*p_in_args };
+        if !in_args.AdapterInitStatus.is_success() {
+            ipc::init_failed(InitStep::AdapterInitFinished, in_args.AdapterInitStatus);
+            return in_args.AdapterInitStatus;
+        }
 
-    // store adapter object for listener to use
-    if ADAPTER.set(AdapterObject(adapter_ptr)).is_err() {
-        error!("Failed to set adapter");
-        return NTSTATUS::STATUS_ADAPTER_HARDWARE_ERROR;
-    }
+        let Some(adapter_ptr) = NonNull::new(adapter_object) else {
+            error!("Adapter ptr was null");
+            ipc::init_failed(InitStep::AdapterInitFinished, NTSTATUS::STATUS_INVALID_ADDRESS);
+            return NTSTATUS::STATUS_INVALID_ADDRESS;
+        };
 
-    DeviceContext::finish_init();
+        // store adapter object for listener to use
+        if ADAPTER.set(AdapterObject(adapter_ptr)).is_err() {
+            error!("Failed to set adapter");
+            return NTSTATUS::STATUS_ADAPTER_HARDWARE_ERROR;
+        }
 
-    NTSTATUS::STATUS_SUCCESS
+        DeviceContext::finish_init();
+
+        NTSTATUS::STATUS_SUCCESS
+    }
 }
 
-pub extern "C-unwind" fn device_d0_entry(
-    device: WDFDEVICE,
-    _previous_state: WDF_POWER_DEVICE_STATE,
-) -> NTSTATUS {
-    let status: NTSTATUS = unsafe {
-        DeviceContext::get_mut(device.cast(), |context| {
-            if let Err(e) = context.init_adapter() {
-                error!("Failed to init adapter: {e:?}");
+wdf_callback! {
+    pub fn device_d0_entry(
+        device: WDFDEVICE,
+        _previous_state: WDF_POWER_DEVICE_STATE,
+    ) -> NTSTATUS as PFN_WDF_DEVICE_D0_ENTRY {
+        logging::device_start();
+
+        // the adapter is initialized once, later entries are resumes from a sleep state
+        if ADAPTER.get().is_some() {
+            let stopped = ipc::resume_monitors();
+
+            // their swap chains are gone, so they don't keep the device from idling anymore
+            unsafe {
+                let _ = DeviceContext::get_mut(device.cast(), |context| {
+                    for id in stopped {
+                        context.streaming_stopped(id);
+                    }
+                });
             }
-        })
-        .into()
-    };
 
-    if !status.is_success() {
-        return status;
+            return NTSTATUS::STATUS_SUCCESS;
+        }
+
+        let status: NTSTATUS = unsafe {
+            DeviceContext::get_mut(device.cast(), |context| {
+                if let Err(e) = context.init_adapter() {
+                    error!("Failed to init adapter: {e:?}");
+                    ipc::init_failed(InitStep::AdapterInit, e.status());
+                }
+            })
+            .into()
+        };
+
+        if !status.is_success() {
+            return status;
+        }
+
+        NTSTATUS::STATUS_SUCCESS
     }
+}
 
-    NTSTATUS::STATUS_SUCCESS
+wdf_callback! {
+    pub fn device_d0_exit(
+        _device: WDFDEVICE,
+        target_state: WDF_POWER_DEVICE_STATE,
+    ) -> NTSTATUS as PFN_WDF_DEVICE_D0_EXIT {
+        // the device is being disabled or removed, it won't come back to D0 in this host process
+        let last = target_state == WDF_POWER_DEVICE_STATE::WdfPowerDeviceD3Final;
+        logging::device_stop(last);
+
+        if last {
+            ipc::teardown(SHUTDOWN_TIMEOUT);
+        } else {
+            // the D3D devices may not survive the sleep, new ones are created on D0 entry
+            ipc::suspend_monitors();
+        }
+
+        NTSTATUS::STATUS_SUCCESS
+    }
 }
 
-fn display_info(width: u32, height: u32, refresh_rate: u32) -> DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
+fn display_info(mode: ModeItem) -> DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
+    let ModeItem {
+        width,
+        height,
+        refresh_rate,
+        timing,
+    } = mode;
+
+    if let Some(timing) = timing {
+        return timed_signal_info(width, height, &timing, 0);
+    }
+
     let clock_rate = refresh_rate * (height + 4) * (height + 4) + 1000;
 
     DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
@@ -100,221 +168,443 @@ fn display_info(width: u32, height: u32, refresh_rate: u32) -> DISPLAYCONFIG_VID
     }
 }
 
-pub extern "C-unwind" fn parse_monitor_description(
-    p_in_args: *const IDARG_IN_PARSEMONITORDESCRIPTION,
-    p_out_args: *mut IDARG_OUT_PARSEMONITORDESCRIPTION,
-) -> NTSTATUS {
-    let in_args = unsafe { &*p_in_args };
-    let out_args = unsafe { &mut *p_out_args };
+/// Signal info with the exact totals and pixel rate of a custom timing
+fn timed_signal_info(
+    width: u32,
+    height: u32,
+    timing: &CustomTiming,
+    v_sync_freq_divider: u32,
+) -> DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
+    let h_total = width + timing.h_blank();
+    let v_total = height + timing.v_blank();
+    // validated to be at most 655 MHz
+    let pixel_rate = timing.pixel_clock_khz * 1000;
 
-    let Ok(monitors) = MONITOR_MODES.lock() else {
-        error!("MONITOR_MODES mutex poisoned");
-        return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
-    };
+    DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
+        pixelRate: u64::from(pixel_rate),
+        hSyncFreq: DISPLAYCONFIG_RATIONAL {
+            Numerator: pixel_rate,
+            Denominator: h_total,
+        },
+        vSyncFreq: DISPLAYCONFIG_RATIONAL {
+            Numerator: pixel_rate,
+            Denominator: h_total * v_total,
+        },
+        activeSize: DISPLAYCONFIG_2DREGION {
+            cx: width,
+            cy: height,
+        },
+        totalSize: DISPLAYCONFIG_2DREGION {
+            cx: h_total,
+            cy: v_total,
+        },
+        __bindgen_anon_1: DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1 {
+            AdditionalSignalInfo: unsafe {
+                mem::transmute::<
+                    __BindgenBitfieldUnit<[u8; 4]>,
+                    DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1,
+                >(
+                    DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1::new_bitfield_1(
+                        255,
+                        v_sync_freq_divider,
+                        0,
+                    ),
+                )
+            },
+        },
+        scanLineOrdering:
+            DISPLAYCONFIG_SCANLINE_ORDERING::DISPLAYCONFIG_SCANLINE_ORDERING_PROGRESSIVE,
+    }
+}
 
-    let edid = unsafe {
-        std::slice::from_raw_parts(
-            in_args.MonitorDescription.pData as *const u8,
-            in_args.MonitorDescription.DataSize as usize,
-        )
-    };
+iddcx_callback! {
+    pub fn parse_monitor_description(
+        p_in_args: *const IDARG_IN_PARSEMONITORDESCRIPTION,
+        p_out_args: *mut IDARG_OUT_PARSEMONITORDESCRIPTION,
+    ) -> NTSTATUS as PFN_IDD_CX_PARSE_MONITOR_DESCRIPTION {
+        let in_args = unsafe { &*p_in_args };
+        let out_args = unsafe { &mut *p_out_args };
+
+        let Ok(monitors) = MONITOR_MODES.lock() else {
+            error!("MONITOR_MODES mutex poisoned");
+            return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
+        };
 
-    let monitor_index = Edid::get_serial(edid);
-    let Ok(monitor_index) = monitor_index else {
-        error!(
-            "We got an edid {} bytes long, but this is incorrect",
-            edid.len()
-        );
-        return NTSTATUS::STATUS_INVALID_VIEW_SIZE;
-    };
+        let edid = unsafe {
+            std::slice::from_raw_parts(
+                in_args.MonitorDescription.pData as *const u8,
+                in_args.MonitorDescription.DataSize as usize,
+            )
+        };
 
-    let Some(monitor) = monitors.iter().find(|&m| m.data.id == monitor_index) else {
-        error!("Failed to find monitor id {monitor_index}");
-        return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
-    };
+        // raw edids may be broken in any way, so they are matched as a whole before reading the
+        // serial
+        let raw = monitors.iter().find(|&m| m.data.raw_edid() == Some(edid));
 
-    let number_of_modes: u32 = monitor
-        .data
-        .modes
-        .iter()
-        .map(|m| u32::try_from(m.refresh_rates.len()).expect("Cannot use > u32::MAX refresh rates"))
-        .sum();
-
-    out_args.MonitorModeBufferOutputCount = number_of_modes;
-    if in_args.MonitorModeBufferInputCount < number_of_modes {
-        // Return success if there was no buffer, since the caller was only asking for a count of modes
-        return if in_args.MonitorModeBufferInputCount > 0 {
-            NTSTATUS::STATUS_BUFFER_TOO_SMALL
+        let monitor = if let Some(monitor) = raw {
+            monitor
         } else {
-            NTSTATUS::STATUS_SUCCESS
+            let serial = Edid::get_serial(edid);
+            let Ok(serial) = serial else {
+                error!(
+                    "We got an edid {} bytes long, but this is incorrect",
+                    edid.len()
+                );
+                return NTSTATUS::STATUS_INVALID_VIEW_SIZE;
+            };
+
+            let monitor = monitors
+                .iter()
+                .find(|&m| m.data.raw_edid().is_none() && m.data.edid_serial() == serial);
+
+            let Some(monitor) = monitor else {
+                error!("Failed to find monitor with edid serial {serial}");
+                return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
+            };
+
+            monitor
         };
-    }
 
-    let monitor_modes = unsafe {
-        std::slice::from_raw_parts_mut(
-            in_args
-                .pMonitorModes
-                .cast::<MaybeUninit<IDDCX_MONITOR_MODE>>(),
-            number_of_modes as usize,
-        )
-    };
+        let number_of_modes: u32 = monitor
+            .data
+            .modes
+            .iter()
+            .map(|m| {
+                u32::try_from(m.refresh_rates.len()).expect("Cannot use > u32::MAX refresh rates")
+            })
+            .sum();
+
+        out_args.MonitorModeBufferOutputCount = number_of_modes;
+        if in_args.MonitorModeBufferInputCount < number_of_modes {
+            // Return success if there was no buffer, since the caller was only asking for a count of
+            // modes
+            return if in_args.MonitorModeBufferInputCount > 0 {
+                NTSTATUS::STATUS_BUFFER_TOO_SMALL
+            } else {
+                NTSTATUS::STATUS_SUCCESS
+            };
+        }
 
-    for (mode, out_mode) in monitor.data.modes.flatten().zip(monitor_modes.iter_mut()) {
-        out_mode.write(IDDCX_MONITOR_MODE {
-            #[allow(clippy::cast_possible_truncation)]
-            Size: mem::size_of::<IDDCX_MONITOR_MODE>() as u32,
-            Origin: IDDCX_MONITOR_MODE_ORIGIN::IDDCX_MONITOR_MODE_ORIGIN_MONITORDESCRIPTOR,
-            MonitorVideoSignalInfo: display_info(mode.width, mode.height, mode.refresh_rate),
-        });
-    }
+        let monitor_modes = unsafe {
+            std::slice::from_raw_parts_mut(
+                in_args
+                    .pMonitorModes
+                    .cast::<MaybeUninit<IDDCX_MONITOR_MODE>>(),
+                number_of_modes as usize,
+            )
+        };
+
+        for (mode, out_mode) in monitor.data.modes.flatten().zip(monitor_modes.iter_mut()) {
+            out_mode.write(IDDCX_MONITOR_MODE {
+                #[allow(clippy::cast_possible_truncation)]
+                Size: mem::size_of::<IDDCX_MONITOR_MODE>() as u32,
+                Origin: IDDCX_MONITOR_MODE_ORIGIN::IDDCX_MONITOR_MODE_ORIGIN_MONITORDESCRIPTOR,
+                MonitorVideoSignalInfo: display_info(mode),
+            });
+        }
 
-    // Set the preferred mode as represented in the EDID
-    out_args.PreferredMonitorModeIdx = 0;
+        // Set the preferred mode as represented in the EDID
+        out_args.PreferredMonitorModeIdx = 0;
 
-    NTSTATUS::STATUS_SUCCESS
+        NTSTATUS::STATUS_SUCCESS
+    }
 }
 
-pub extern "C-unwind" fn monitor_get_default_modes(
-    _monitor_object: *mut IDDCX_MONITOR__,
-    _p_in_args: *const IDARG_IN_GETDEFAULTDESCRIPTIONMODES,
-    _p_out_args: *mut IDARG_OUT_GETDEFAULTDESCRIPTIONMODES,
-) -> NTSTATUS {
-    NTSTATUS::STATUS_NOT_IMPLEMENTED
+iddcx_callback! {
+    pub fn monitor_get_default_modes(
+        monitor_object: *mut IDDCX_MONITOR__,
+        p_in_args: *const IDARG_IN_GETDEFAULTDESCRIPTIONMODES,
+        p_out_args: *mut IDARG_OUT_GETDEFAULTDESCRIPTIONMODES,
+    ) -> NTSTATUS as PFN_IDD_CX_MONITOR_GET_DEFAULT_DESCRIPTION_MODES {
+        // only called for monitors created without an edid, which store their modes instead
+        let mut modes = Vec::new();
+        let result = unsafe {
+            MonitorContext::get(monitor_object.cast(), |context| {
+                modes = context.default_modes().to_vec();
+            })
+        };
+
+        if let Err(e) = result {
+            error!("Failed to get default modes of {monitor_object:?}: {e:?}");
+            return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
+        }
+
+        let number_of_modes: u32 = modes
+            .iter()
+            .map(|m| {
+                u32::try_from(m.refresh_rates.len()).expect("Cannot use > u32::MAX refresh rates")
+            })
+            .sum();
+
+        let in_args = unsafe { &*p_in_args };
+        let out_args = unsafe { &mut *p_out_args };
+
+        out_args.DefaultMonitorModeBufferOutputCount = number_of_modes;
+        if in_args.DefaultMonitorModeBufferInputCount < number_of_modes {
+            // Return success if there was no buffer, since the caller was only asking for a count of
+            // modes
+            return if in_args.DefaultMonitorModeBufferInputCount > 0 {
+                NTSTATUS::STATUS_BUFFER_TOO_SMALL
+            } else {
+                NTSTATUS::STATUS_SUCCESS
+            };
+        }
+
+        let monitor_modes = unsafe {
+            std::slice::from_raw_parts_mut(
+                in_args
+                    .pDefaultMonitorModes
+                    .cast::<MaybeUninit<IDDCX_MONITOR_MODE>>(),
+                number_of_modes as usize,
+            )
+        };
+
+        for (mode, out_mode) in modes.flatten().zip(monitor_modes.iter_mut()) {
+            out_mode.write(IDDCX_MONITOR_MODE {
+                #[allow(clippy::cast_possible_truncation)]
+                Size: mem::size_of::<IDDCX_MONITOR_MODE>() as u32,
+                Origin: IDDCX_MONITOR_MODE_ORIGIN::IDDCX_MONITOR_MODE_ORIGIN_DRIVER,
+                MonitorVideoSignalInfo: display_info(mode),
+            });
+        }
+
+        // the first mode is the preferred one, like for monitors with an edid
+        out_args.PreferredMonitorModeIdx = 0;
+
+        NTSTATUS::STATUS_SUCCESS
+    }
 }
 
-pub fn target_mode(width: u32, height: u32, refresh_rate: u32) -> IDDCX_TARGET_MODE {
+pub fn target_mode(mode: ModeItem) -> IDDCX_TARGET_MODE {
+    let ModeItem {
+        width,
+        height,
+        refresh_rate,
+        timing,
+    } = mode;
     let total_size = DISPLAYCONFIG_2DREGION {
         cx: width,
         cy: height,
     };
 
+    let signal_info = match timing {
+        Some(timing) => timed_signal_info(width, height, &timing, 1),
+        None => DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
+            pixelRate: u64::from(refresh_rate) * u64::from(width) * u64::from(height),
+            hSyncFreq: DISPLAYCONFIG_RATIONAL {
+                Numerator: refresh_rate * height,
+                Denominator: 1,
+            },
+            vSyncFreq: DISPLAYCONFIG_RATIONAL {
+                Numerator: refresh_rate,
+                Denominator: 1,
+            },
+            totalSize: total_size,
+            activeSize: total_size,
+            scanLineOrdering:
+                DISPLAYCONFIG_SCANLINE_ORDERING::DISPLAYCONFIG_SCANLINE_ORDERING_PROGRESSIVE,
+            __bindgen_anon_1: DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1 {
+                AdditionalSignalInfo: unsafe {
+                    mem::transmute::<
+                        __BindgenBitfieldUnit<[u8; 4]>,
+                        DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1,
+                    >(
+                        DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1::new_bitfield_1(
+                            255, 1, 0,
+                        ),
+                    )
+                },
+            },
+        },
+    };
+
     IDDCX_TARGET_MODE {
         #[allow(clippy::cast_possible_truncation)]
         Size: mem::size_of::<IDDCX_TARGET_MODE>() as u32,
 
         TargetVideoSignalInfo: DISPLAYCONFIG_TARGET_MODE {
-            targetVideoSignalInfo: DISPLAYCONFIG_VIDEO_SIGNAL_INFO {
-                pixelRate: u64::from(refresh_rate) * u64::from(width) * u64::from(height),
-                hSyncFreq: DISPLAYCONFIG_RATIONAL {
-                    Numerator: refresh_rate * height,
-                    Denominator: 1,
-                },
-                vSyncFreq: DISPLAYCONFIG_RATIONAL {
-                    Numerator: refresh_rate,
-                    Denominator: 1,
-                },
-                totalSize: total_size,
-                activeSize: total_size,
-                scanLineOrdering:
-                    DISPLAYCONFIG_SCANLINE_ORDERING::DISPLAYCONFIG_SCANLINE_ORDERING_PROGRESSIVE,
-                __bindgen_anon_1: DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1 {
-                    AdditionalSignalInfo: unsafe {
-                        mem::transmute::<__BindgenBitfieldUnit<[u8; 4]>, DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1>(
-                            DISPLAYCONFIG_VIDEO_SIGNAL_INFO__bindgen_ty_1__bindgen_ty_1::new_bitfield_1(
-                                255, 1, 0,
-                            ),
-                        )
-                    },
-                },
-            },
+            targetVideoSignalInfo: signal_info,
         },
 
         ..Default::default()
     }
 }
 
-pub extern "C-unwind" fn monitor_query_modes(
-    monitor_object: *mut IDDCX_MONITOR__,
-    p_in_args: *const IDARG_IN_QUERYTARGETMODES,
-    p_out_args: *mut IDARG_OUT_QUERYTARGETMODES,
-) -> NTSTATUS {
-    // find out which monitor this belongs too
+iddcx_callback! {
+    pub fn monitor_query_modes(
+        monitor_object: *mut IDDCX_MONITOR__,
+        p_in_args: *const IDARG_IN_QUERYTARGETMODES,
+        p_out_args: *mut IDARG_OUT_QUERYTARGETMODES,
+    ) -> NTSTATUS as PFN_IDD_CX_MONITOR_QUERY_TARGET_MODES {
+        // find out which monitor this belongs too
+
+        let Ok(monitors) = MONITOR_MODES.lock() else {
+            error!("MONITOR_MODES mutex poisoned");
+            return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
+        };
 
-    let Ok(monitors) = MONITOR_MODES.lock() else {
-        error!("MONITOR_MODES mutex poisoned");
-        return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
-    };
+        // we have stored the monitor object per id, so we should be able to compare pointers
+        let Some(monitor) = monitors
+            .iter()
+            .find(|&m| m.object.is_some_and(|p| p.as_ptr() == monitor_object))
+        else {
+            error!("Failed to find monitor object in cache for {monitor_object:?}");
+            return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
+        };
 
-    // we have stored the monitor object per id, so we should be able to compare pointers
-    let Some(monitor) = monitors
-        .iter()
-        .find(|&m| m.object.is_some_and(|p| p.as_ptr() == monitor_object))
-    else {
-        error!("Failed to find monitor object in cache for {monitor_object:?}");
-        return NTSTATUS::STATUS_DRIVER_INTERNAL_ERROR;
-    };
+        let number_of_modes = monitor
+            .data
+            .modes
+            .iter()
+            .map(|m| u32::try_from(m.refresh_rates.len()).expect("Cannot use > u32::MAX modes"))
+            .sum();
+
+        // Create a set of modes supported for frame processing and scan-out. These are typically not based on the
+        // monitor's descriptor and instead are based on the static processing capability of the device. The OS will
+        // report the available set of modes for a given output as the intersection of monitor modes with target modes.
+
+        let out_args = unsafe { &mut *p_out_args };
+        out_args.TargetModeBufferOutputCount = number_of_modes;
+
+        let in_args = unsafe { &*p_in_args };
+
+        if in_args.TargetModeBufferInputCount >= number_of_modes {
+            let out_target_modes = unsafe {
+                std::slice::from_raw_parts_mut(
+                    in_args
+                        .pTargetModes
+                        .cast::<MaybeUninit<IDDCX_TARGET_MODE>>(),
+                    number_of_modes as usize,
+                )
+            };
 
-    let number_of_modes = monitor
-        .data
-        .modes
-        .iter()
-        .map(|m| u32::try_from(m.refresh_rates.len()).expect("Cannot use > u32::MAX modes"))
-        .sum();
+            for (mode, out_target) in monitor
+                .data
+                .modes
+                .flatten()
+                .zip(out_target_modes.iter_mut())
+            {
+                let target_mode = target_mode(mode);
 
-    // Create a set of modes supported for frame processing and scan-out. These are typically not based on the
-    // monitor's descriptor and instead are based on the static processing capability of the device. The OS will
-    // report the available set of modes for a given output as the intersection of monitor modes with target modes.
+                out_target.write(target_mode);
+            }
+        }
 
-    let out_args = unsafe { &mut *p_out_args };
-    out_args.TargetModeBufferOutputCount = number_of_modes;
+        NTSTATUS::STATUS_SUCCESS
+    }
+}
 
-    let in_args = unsafe { &*p_in_args };
+iddcx_callback! {
+    pub fn adapter_commit_modes(
+        _adapter_object: *mut IDDCX_ADAPTER__,
+        p_in_args: *const IDARG_IN_COMMITMODES,
+    ) -> NTSTATUS as PFN_IDD_CX_ADAPTER_COMMIT_MODES {
+        let p_in_args = unsafe { &*p_in_args };
 
-    if in_args.TargetModeBufferInputCount >= number_of_modes {
-        let out_target_modes = unsafe {
-            std::slice::from_raw_parts_mut(
-                in_args
-                    .pTargetModes
-                    .cast::<MaybeUninit<IDDCX_TARGET_MODE>>(),
-                number_of_modes as usize,
-            )
+        let paths = if p_in_args.pPaths.is_null() {
+            &[][..]
+        } else {
+            unsafe { std::slice::from_raw_parts(p_in_args.pPaths, p_in_args.PathCount as usize) }
         };
 
-        for (mode, out_target) in monitor
-            .data
-            .modes
-            .flatten()
-            .zip(out_target_modes.iter_mut())
-        {
-            let target_mode = target_mode(mode.width, mode.height, mode.refresh_rate);
+        // remember what windows picked, so clients can compare it to the requested modes
+        for path in paths {
+            let active = path.Flags.0 & IDDCX_PATH_FLAGS::IDDCX_PATH_FLAGS_ACTIVE.0 != 0;
+            let mode = active.then(|| active_mode(&path.TargetVideoSignalInfo));
+
+            let result = unsafe {
+                MonitorContext::get_mut(path.MonitorObject.cast(), |context| {
+                    context.set_committed_mode(mode);
+                })
+            };
 
-            out_target.write(target_mode);
+            if let Err(e) = result {
+                error!("Failed to record committed mode: {e:?}");
+            }
         }
-    }
 
-    NTSTATUS::STATUS_SUCCESS
+        NTSTATUS::STATUS_SUCCESS
+    }
 }
 
-pub extern "C-unwind" fn adapter_commit_modes(
-    _adapter_object: *mut IDDCX_ADAPTER__,
-    _p_in_args: *const IDARG_IN_COMMITMODES,
-) -> NTSTATUS {
-    NTSTATUS::STATUS_SUCCESS
+fn active_mode(info: &DISPLAYCONFIG_VIDEO_SIGNAL_INFO) -> ActiveMode {
+    let DISPLAYCONFIG_RATIONAL {
+        Numerator: numerator,
+        Denominator: denominator,
+    } = info.vSyncFreq;
+
+    // rounded, the signal info of the modes reported in `display_info` is slightly above the rate
+    let refresh_rate = numerator
+        .checked_add(denominator / 2)
+        .and_then(|n| n.checked_div(denominator))
+        .unwrap_or_default();
+
+    ActiveMode {
+        width: info.activeSize.cx,
+        height: info.activeSize.cy,
+        refresh_rate,
+    }
 }
 
-pub extern "C-unwind" fn assign_swap_chain(
-    monitor_object: *mut IDDCX_MONITOR__,
-    p_in_args: *const IDARG_IN_SETSWAPCHAIN,
-) -> NTSTATUS {
-    let p_in_args = unsafe { &*p_in_args };
-
-    unsafe {
-        MonitorContext::get_mut(monitor_object.cast(), |context| {
-            context.assign_swap_chain(
-                p_in_args.hSwapChain,
-                p_in_args.RenderAdapterLuid,
-                p_in_args.hNextSurfaceAvailable,
-            );
-        })
-        .into()
+iddcx_callback! {
+    pub fn assign_swap_chain(
+        monitor_object: *mut IDDCX_MONITOR__,
+        p_in_args: *const IDARG_IN_SETSWAPCHAIN,
+    ) -> NTSTATUS as PFN_IDD_CX_MONITOR_ASSIGN_SWAPCHAIN {
+        let p_in_args = unsafe { &*p_in_args };
+
+        let mut streaming = None;
+        let status: NTSTATUS = unsafe {
+            MonitorContext::get_mut(monitor_object.cast(), |context| {
+                let running = context.assign_swap_chain(
+                    p_in_args.hSwapChain,
+                    p_in_args.RenderAdapterLuid,
+                    p_in_args.hNextSurfaceAvailable,
+                );
+
+                streaming = Some((context.wdf_device(), context.id(), running));
+            })
+            .into()
+        };
+
+        // the monitor context is unlocked first, the device context is never locked the other way
+        if let Some((device, id, running)) = streaming {
+            unsafe {
+                let _ = DeviceContext::get_mut(device.cast(), |context| {
+                    if running {
+                        context.streaming_started(id);
+                    } else {
+                        context.streaming_stopped(id);
+                    }
+                });
+            }
+        }
+
+        status
     }
 }
 
-pub extern "C-unwind" fn unassign_swap_chain(monitor_object: *mut IDDCX_MONITOR__) -> NTSTATUS {
-    unsafe {
-        MonitorContext::get_mut(monitor_object.cast(), |context| {
-            context.unassign_swap_chain();
-        })
-        .into()
+iddcx_callback! {
+    pub fn unassign_swap_chain(
+        monitor_object: *mut IDDCX_MONITOR__,
+    ) -> NTSTATUS as PFN_IDD_CX_MONITOR_UNASSIGN_SWAPCHAIN {
+        let mut streaming = None;
+        let status: NTSTATUS = unsafe {
+            MonitorContext::get_mut(monitor_object.cast(), |context| {
+                context.unassign_swap_chain();
+                streaming = Some((context.wdf_device(), context.id()));
+            })
+            .into()
+        };
+
+        if let Some((device, id)) = streaming {
+            unsafe {
+                let _ = DeviceContext::get_mut(device.cast(), |context| {
+                    context.streaming_stopped(id);
+                });
+            }
+        }
+
+        status
     }
 }
@@ -1,14 +1,21 @@
 use std::{
+    collections::HashSet,
     mem::{self, size_of},
     num::{ParseIntError, TryFromIntError},
-    ptr::{addr_of_mut, NonNull},
+    ptr::{self, addr_of_mut, NonNull},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::anyhow;
+use driver_ipc::{ActiveMode, AdapterConfig, Id, Luid, Mode, OsTarget, Stats};
 use log::{error, warn};
 use wdf_umdf::{
-    IddCxAdapterInitAsync, IddCxError, IddCxMonitorArrival, IddCxMonitorCreate,
-    IddCxMonitorSetupHardwareCursor, WdfError, WdfObjectDelete, WDF_DECLARE_CONTEXT_TYPE,
+    logging, IddCxAdapterInitAsync, IddCxError, IddCxMonitorArrival, IddCxMonitorCreate,
+    IddCxMonitorSetupHardwareCursor, WdfDeviceResumeIdle, WdfDeviceStopIdle, WdfError,
+    WdfObjectDelete, WDF_DECLARE_CONTEXT_TYPE,
 };
 use wdf_umdf_sys::{
     DISPLAYCONFIG_VIDEO_OUTPUT_TECHNOLOGY, HANDLE, IDARG_IN_ADAPTER_INIT, IDARG_IN_MONITORCREATE,
@@ -27,16 +34,17 @@ use windows::{
 use crate::{
     direct_3d_device::Direct3DDevice,
     edid::Edid,
-    ipc::{startup, MONITOR_MODES},
-    swap_chain_processor::SwapChainProcessor,
+    ipc::{init_succeeded, restore, startup, MONITOR_MODES},
+    render_adapter,
+    settings::{self, ADAPTER_CONFIG, MAX_MONITORS},
+    swap_chain_processor::{FrameRateLimit, FrameStats, SwapChainProcessor},
 };
 
-// Maximum amount of monitors that can be connected
-pub const MAX_MONITORS: u8 = 16;
-
 pub struct DeviceContext {
     device: WDFDEVICE,
     adapter: Option<IDDCX_ADAPTER>,
+    // monitors with a swap chain, the device is kept from idling while there are any
+    streaming: Streaming,
 }
 
 // SAFETY: Raw ptr is managed by external library
@@ -47,7 +55,45 @@ unsafe impl Sync for DeviceContext {}
 #[allow(unused)]
 pub struct MonitorContext {
     device: IDDCX_MONITOR,
+    // device the monitor was created on, to keep it from idling while frames flow
+    wdf_device: WDFDEVICE,
+    id: Id,
     swap_chain_processor: Option<SwapChainProcessor>,
+    // mode of the last commit which had the monitor's path active
+    committed_mode: Option<ActiveMode>,
+    // adapter of the swap chain, only set while one is assigned
+    render_adapter: Option<Luid>,
+    // frames processed since the monitor was created, shared with the swap chain processor
+    frames: Arc<AtomicU64>,
+    // counters of the current swap chain, only set while one is assigned
+    stats: Option<Arc<FrameStats>>,
+    // shared with the monitor's state, which updates it in place
+    frame_rate_limit: Arc<FrameRateLimit>,
+    // kept while the device is suspended, so processing can resume on it
+    swap_chain: Option<AssignedSwapChain>,
+    // the device left D0, frames are processed again once it's back
+    suspended: bool,
+    // target windows created for the monitor, set once it arrived
+    os_target: Option<OsTarget>,
+    // modes reported to IddCx for a monitor created without an edid
+    default_modes: Vec<Mode>,
+}
+
+/// What a monitor is described to the OS with when it is created
+enum MonitorDescription {
+    Edid(Vec<u8>),
+    /// No description at all, IddCx asks for `modes` through `monitor_get_default_modes` instead
+    EdidLess {
+        modes: Vec<Mode>,
+    },
+}
+
+/// A swap chain IddCx assigned to a monitor
+#[derive(Clone, Copy)]
+struct AssignedSwapChain {
+    handle: IDDCX_SWAPCHAIN,
+    render_adapter: windows::Win32::Foundation::LUID,
+    new_frame_event: HANDLE,
 }
 
 // SAFETY: Raw ptr is managed by external library
@@ -75,11 +121,55 @@ pub enum ContextError {
     Other(#[from] anyhow::Error),
 }
 
+impl ContextError {
+    /// Status to report for this error
+    pub fn status(&self) -> NTSTATUS {
+        match self {
+            Self::Ntstatus(status) => *status,
+            Self::IddCx(e) => (*e).into(),
+            Self::Wdf(e) => (*e).into(),
+            // a HRESULT_FROM_NT keeps the status it wraps
+            Self::Win(e) => NTSTATUS::try_from(e.code()).unwrap_or(NTSTATUS::STATUS_UNSUCCESSFUL),
+            _ => NTSTATUS::STATUS_UNSUCCESSFUL,
+        }
+    }
+}
+
 impl DeviceContext {
     pub fn new(device: WDFDEVICE) -> Self {
         Self {
             device,
             adapter: None,
+            streaming: Streaming::default(),
+        }
+    }
+
+    pub fn device(&self) -> WDFDEVICE {
+        self.device
+    }
+
+    /// A swap chain was assigned to monitor `id`, the first one keeps the device in D0
+    pub fn streaming_started(&mut self, id: Id) {
+        if !self.streaming.start(id) {
+            return;
+        }
+
+        // called from an IddCx callback, so the device is in D0 already and there's no need to wait
+        if let Err(e) = unsafe { WdfDeviceStopIdle(self.device, false) } {
+            error!("Failed to stop idle: {e:?}");
+            // not counted, so the last stop doesn't resume idle without a matching stop
+            self.streaming.stop(id);
+        }
+    }
+
+    /// The swap chain of monitor `id` went away, the last one lets the device idle again
+    pub fn streaming_stopped(&mut self, id: Id) {
+        if !self.streaming.stop(id) {
+            return;
+        }
+
+        if let Err(e) = unsafe { WdfDeviceResumeIdle(self.device) } {
+            error!("Failed to resume idle: {e:?}");
         }
     }
 
@@ -98,7 +188,7 @@ impl DeviceContext {
             #[allow(clippy::cast_possible_truncation)]
             Size: size_of::<IDDCX_ADAPTER_CAPS>() as u32,
 
-            MaxMonitorsSupported: u32::from(MAX_MONITORS),
+            MaxMonitorsSupported: *MAX_MONITORS,
 
             EndPointDiagnostics: IDDCX_ENDPOINT_DIAGNOSTIC_INFO {
                 #[allow(clippy::cast_possible_truncation)]
@@ -131,15 +221,33 @@ impl DeviceContext {
 
         self.adapter = Some(adapter_init_out.AdapterObject);
 
-        unsafe { self.clone_into(adapter_init_out.AdapterObject as WDFOBJECT)? };
+        unsafe {
+            Self::clone_into(
+                self.device as WDFOBJECT,
+                adapter_init_out.AdapterObject as WDFOBJECT,
+            )?
+        };
 
         Ok(())
     }
 
     pub fn finish_init() -> NTSTATUS {
+        // before any monitor arrives, so the swap chains start out on the preferred adapter
+        if let Err(e) = render_adapter::apply() {
+            error!("Failed to set the preferred render adapter: {e:?}");
+        }
+
+        // bring back the monitors saved before the last driver stop
+        restore();
+
+        init_succeeded();
+
         // start the socket listener to listen for messages from the client
         startup();
 
+        // apply settings changed while the driver runs, see `settings::reload`
+        settings::watch();
+
         NTSTATUS::STATUS_SUCCESS
     }
 
@@ -147,8 +255,63 @@ impl DeviceContext {
         let mut attr =
             WDF_OBJECT_ATTRIBUTES::init_context_type(unsafe { MonitorContext::get_type_info() });
 
-        // use the edid serial number to represent the monitor index for later identification
-        let mut edid = Edid::generate_with(index);
+        let (connector_index, mut description, (width, height)) = {
+            let lock = MONITOR_MODES
+                .lock()
+                .map_err(|_| anyhow!("Failed to lock mutex"))?;
+
+            let monitor = lock.iter().find(|m| m.data.id == index);
+
+            // the edid serial number identifies the monitor later on, it is unique between monitors.
+            // raw edids are passed on untouched, they are unique by their bytes instead
+            let description = match monitor.map(|m| &m.data) {
+                // static connectors look like the ports of a graphics card, nothing identifies them
+                Some(data) if matches!(*ADAPTER_CONFIG, AdapterConfig::Static { .. }) => {
+                    MonitorDescription::EdidLess {
+                        modes: data.modes.clone(),
+                    }
+                }
+                Some(data) => MonitorDescription::Edid(match data.edid.as_deref() {
+                    Some(edid) if data.raw_edid => edid.to_vec(),
+                    Some(edid) => Edid::with_serial(edid, data.edid_serial()),
+                    None => Edid::generate_for(data),
+                }),
+                None => MonitorDescription::Edid(Edid::generate_with(index)),
+            };
+
+            // the connector index must be below MaxMonitorsSupported, so it can't be the monitor id
+            let max_monitors = *MAX_MONITORS;
+
+            let in_use = |connector| {
+                lock.iter()
+                    .any(|m| m.object.is_some() && m.connector_index == Some(connector))
+            };
+
+            // a monitor coming back from being parked or disabled gets its old connector if it is
+            // still free, so windows restores the layout it remembered for it
+            let previous = monitor
+                .and_then(|m| m.connector_index)
+                .filter(|&connector| !in_use(connector));
+
+            let connector_index = previous
+                .or_else(|| (0..max_monitors).find(|&connector| !in_use(connector)))
+                .ok_or(anyhow!(
+                    "All {max_monitors} connectors are in use, cannot create monitor {index}"
+                ))?;
+
+            // only for the arrival event, a monitor without modes is created with the default ones
+            let preferred = monitor
+                .and_then(|m| m.data.modes.first())
+                .map_or((0, 0), |mode| (mode.width, mode.height));
+
+            (connector_index, description, preferred)
+        };
+
+        let (data_size, data) = match &mut description {
+            #[allow(clippy::cast_possible_truncation)]
+            MonitorDescription::Edid(edid) => (edid.len() as u32, edid.as_mut_ptr().cast()),
+            MonitorDescription::EdidLess { .. } => (0, ptr::null_mut()),
+        };
 
         let mut monitor_info = IDDCX_MONITOR_INFO {
             #[allow(clippy::cast_possible_truncation)]
@@ -162,17 +325,15 @@ impl DeviceContext {
             MonitorType:
                 DISPLAYCONFIG_VIDEO_OUTPUT_TECHNOLOGY::DISPLAYCONFIG_OUTPUT_TECHNOLOGY_HDMI,
 
-            ConnectorIndex: index,
+            ConnectorIndex: connector_index,
             MonitorDescription: IDDCX_MONITOR_DESCRIPTION {
                 #[allow(clippy::cast_possible_truncation)]
                 Size: size_of::<IDDCX_MONITOR_DESCRIPTION>() as u32,
 
                 Type: IDDCX_MONITOR_DESCRIPTION_TYPE::IDDCX_MONITOR_DESCRIPTION_TYPE_EDID,
 
-                #[allow(clippy::cast_possible_truncation)]
-                DataSize: edid.len() as u32,
-
-                pData: edid.as_mut_ptr().cast(),
+                DataSize: data_size,
+                pData: data,
             },
         };
 
@@ -191,6 +352,7 @@ impl DeviceContext {
         };
 
         // store monitor object for later
+        let mut frame_rate_limit = Arc::default();
         {
             let mut lock = MONITOR_MODES
                 .lock()
@@ -198,16 +360,23 @@ impl DeviceContext {
 
             for monitor in &mut *lock {
                 if monitor.data.id == index {
+                    frame_rate_limit = monitor.frame_rate_limit.clone();
                     monitor.object = Some(
                         NonNull::new(monitor_create_out.MonitorObject)
                             .ok_or(anyhow!("MonitorObject was null"))?,
                     );
+                    monitor.connector_index = Some(connector_index);
                 }
             }
         }
 
         unsafe {
-            let context = MonitorContext::new(monitor_create_out.MonitorObject);
+            let mut context =
+                MonitorContext::new(monitor_create_out.MonitorObject, self.device, index);
+            if let MonitorDescription::EdidLess { modes } = description {
+                context.default_modes = modes;
+            }
+            context.frame_rate_limit = frame_rate_limit;
             context.init(monitor_create_out.MonitorObject as WDFOBJECT)?;
         }
 
@@ -219,26 +388,99 @@ impl DeviceContext {
             IddCxMonitorArrival(monitor_create_out.MonitorObject, &mut arrival_out)?;
         }
 
+        let os_target = OsTarget {
+            adapter: Luid {
+                low_part: arrival_out.OsAdapterLuid.LowPart,
+                high_part: arrival_out.OsAdapterLuid.HighPart,
+            },
+            target_id: arrival_out.OsTargetId,
+        };
+
+        let result = unsafe {
+            MonitorContext::get_mut(monitor_create_out.MonitorObject.cast(), |context| {
+                context.os_target = Some(os_target);
+            })
+        };
+
+        if let Err(e) = result {
+            error!("Failed to store display target of monitor {index}: {e:?}");
+        }
+
+        logging::monitor_arrival(index, connector_index, width, height);
+
         Ok(())
     }
 }
 
 impl MonitorContext {
-    pub fn new(device: IDDCX_MONITOR) -> Self {
+    pub fn new(device: IDDCX_MONITOR, wdf_device: WDFDEVICE, id: Id) -> Self {
         Self {
             device,
+            wdf_device,
+            id,
             swap_chain_processor: None,
+            committed_mode: None,
+            render_adapter: None,
+            frames: Arc::new(AtomicU64::new(0)),
+            stats: None,
+            frame_rate_limit: Arc::default(),
+            swap_chain: None,
+            suspended: false,
+            os_target: None,
+            default_modes: Vec::new(),
         }
     }
 
+    /// Records the mode windows committed, `None` if the monitor's path became inactive
+    pub fn set_committed_mode(&mut self, mode: Option<ActiveMode>) {
+        self.committed_mode = mode;
+    }
+
+    pub fn committed_mode(&self) -> Option<ActiveMode> {
+        self.committed_mode
+    }
+
+    /// Adapter rendering into the swap chain, `None` if no swap chain is attached
+    pub fn render_adapter(&self) -> Option<Luid> {
+        self.render_adapter
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Target windows created for the monitor, `None` until it arrived
+    pub fn os_target(&self) -> Option<OsTarget> {
+        self.os_target
+    }
+
+    /// Modes of a monitor created without an edid, empty otherwise
+    pub fn default_modes(&self) -> &[Mode] {
+        &self.default_modes
+    }
+
+    /// Counters of the swap chain, `None` if no swap chain is attached
+    pub fn stats(&self, id: Id) -> Option<Stats> {
+        self.stats.as_ref().map(|stats| stats.snapshot(id))
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn wdf_device(&self) -> WDFDEVICE {
+        self.wdf_device
+    }
+
+    /// Starts processing frames of the swap chain, returns whether it is running
     pub fn assign_swap_chain(
         &mut self,
         swap_chain: IDDCX_SWAPCHAIN,
         render_adapter: LUID,
         new_frame_event: HANDLE,
-    ) {
-        // drop processing thread
-        drop(self.swap_chain_processor.take());
+    ) -> bool {
+        // drop processing thread, its counters go with it
+        self.release_swap_chain();
 
         // transmute would work, but one less unsafe block, so why not
         let luid = windows::Win32::Foundation::LUID {
@@ -246,28 +488,139 @@ impl MonitorContext {
             HighPart: render_adapter.HighPart,
         };
 
-        let device = Direct3DDevice::init(luid);
+        self.swap_chain = Some(AssignedSwapChain {
+            handle: swap_chain,
+            render_adapter: luid,
+            new_frame_event,
+        });
+
+        if !self.start_processing() {
+            return false;
+        }
 
-        if let Ok(device) = device {
-            let mut processor = SwapChainProcessor::new();
+        let luid = Luid {
+            low_part: luid.LowPart,
+            high_part: luid.HighPart,
+        };
 
-            processor.run(swap_chain, device, new_frame_event);
+        if let Some(preferred) = render_adapter::preferred().filter(|&p| p != luid) {
+            warn!("Swap chain is rendered on adapter {luid} instead of the preferred {preferred}");
+        }
 
-            self.swap_chain_processor = Some(processor);
+        self.render_adapter = Some(luid);
+        logging::swap_chain_assigned(self.id, luid.low_part, luid.high_part);
 
-            self.setup_hw_cursor();
-        } else {
-            // It's important to delete the swap-chain if D3D initialization fails, so that the OS knows to generate a new
-            // swap-chain and try again.
+        self.setup_hw_cursor();
 
-            unsafe {
-                let _ = WdfObjectDelete(swap_chain.cast());
-            }
-        }
+        true
     }
 
     pub fn unassign_swap_chain(&mut self) {
-        self.swap_chain_processor.take();
+        self.release_swap_chain();
+        self.render_adapter = None;
+
+        logging::swap_chain_unassigned(self.id);
+    }
+
+    /// The device leaves D0, so frame processing stops and the D3D device is released
+    ///
+    /// The swap chain is kept, and processed again on [MonitorContext::resume]
+    pub fn suspend(&mut self) {
+        self.suspended = true;
+
+        let Some(processor) = self.swap_chain_processor.take() else {
+            return;
+        };
+
+        // processing ended on its own before, and deleted the swap chain
+        if !processor.suspend() {
+            self.swap_chain = None;
+            self.render_adapter = None;
+            self.stats = None;
+        }
+    }
+
+    /// The device is back in D0, frames of a kept swap chain are processed on a new D3D device
+    ///
+    /// Returns whether frames are processed
+    pub fn resume(&mut self) -> bool {
+        let suspended = mem::take(&mut self.suspended);
+
+        if suspended && self.swap_chain_processor.is_none() && !self.start_processing() {
+            self.render_adapter = None;
+        }
+
+        self.swap_chain_processor.is_some()
+    }
+
+    /// Processes the frames of the assigned swap chain on a new D3D device, returns whether it
+    /// is running
+    ///
+    /// The counters of the swap chain are kept if it was processed before. If no device can be
+    /// created, the swap chain is deleted
+    fn start_processing(&mut self) -> bool {
+        let Some(swap_chain) = self.swap_chain else {
+            return false;
+        };
+
+        let device = match Direct3DDevice::init(swap_chain.render_adapter) {
+            Ok(device) => device,
+            Err(e) => {
+                if e.is_device_removed() {
+                    warn!("Render adapter of monitor {} went away: {e:?}", self.id);
+                } else {
+                    error!(
+                        "Failed to create a D3D device for monitor {}: {e:?}",
+                        self.id
+                    );
+                }
+
+                // It's important to delete the swap-chain if D3D initialization fails, so that the OS knows to generate a new
+                // swap-chain and try again.
+                self.swap_chain = None;
+                self.stats = None;
+
+                unsafe {
+                    let _ = WdfObjectDelete(swap_chain.handle.cast());
+                }
+
+                return false;
+            }
+        };
+
+        let stats = self
+            .stats
+            .get_or_insert_with(|| Arc::new(FrameStats::new()))
+            .clone();
+
+        let mut processor = SwapChainProcessor::new(self.frame_rate_limit.clone());
+        processor.run(
+            self.id,
+            swap_chain.handle,
+            device,
+            swap_chain.new_frame_event,
+            self.frames.clone(),
+            stats,
+        );
+
+        self.swap_chain_processor = Some(processor);
+
+        true
+    }
+
+    /// Stops processing the swap chain, which is deleted either way
+    fn release_swap_chain(&mut self) {
+        let swap_chain = self.swap_chain.take();
+        self.stats = None;
+
+        // a running processor deletes its swap chain once it ends
+        if let Some(processor) = self.swap_chain_processor.take() {
+            drop(processor);
+        } else if let Some(swap_chain) = swap_chain {
+            unsafe {
+                let _ = WdfObjectDelete(swap_chain.handle.cast());
+            }
+        }
     }
 
     pub fn setup_hw_cursor(&mut self) {
@@ -306,3 +659,45 @@ impl MonitorContext {
         }
     }
 }
+
+/// Monitors with a swap chain attached
+///
+/// IddCx may assign a new swap chain without unassigning the old one first, so monitors are
+/// tracked by id instead of counting calls.
+#[derive(Debug, Default)]
+struct Streaming(HashSet<Id>);
+
+impl Streaming {
+    /// Returns whether this is the first streaming monitor
+    fn start(&mut self, id: Id) -> bool {
+        self.0.insert(id) && self.0.len() == 1
+    }
+
+    /// Returns whether this was the last streaming monitor
+    fn stop(&mut self, id: Id) -> bool {
+        self.0.remove(&id) && self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streaming_counts_monitors() {
+        let mut streaming = Streaming::default();
+
+        assert!(streaming.start(1));
+        assert!(!streaming.start(2));
+        // a new swap chain for a monitor which still has one
+        assert!(!streaming.start(1));
+
+        assert!(!streaming.stop(1));
+        // already stopped
+        assert!(!streaming.stop(1));
+        assert!(streaming.stop(2));
+
+        assert!(!streaming.stop(3));
+        assert!(streaming.start(3));
+    }
+}
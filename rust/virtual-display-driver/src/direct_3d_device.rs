@@ -1,3 +1,5 @@
+use driver_ipc::{AdapterPreference, Luid};
+use log::info;
 use windows::{
     core::Error,
     Win32::{
@@ -10,7 +12,10 @@ use windows::{
                 D3D11_CREATE_DEVICE_PREVENT_ALTERING_LAYER_SETTINGS_FROM_REGISTRY,
                 D3D11_CREATE_DEVICE_SINGLETHREADED, D3D11_SDK_VERSION,
             },
-            Dxgi::{CreateDXGIFactory2, IDXGIAdapter1, IDXGIFactory5, DXGI_CREATE_FACTORY_FLAGS},
+            Dxgi::{
+                CreateDXGIFactory2, IDXGIAdapter1, IDXGIFactory5, DXGI_ADAPTER_FLAG_SOFTWARE,
+                DXGI_CREATE_FACTORY_FLAGS, DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_NOT_FOUND,
+            },
         },
     },
 };
@@ -23,6 +28,17 @@ pub enum Direct3DError {
     Other(&'static str),
 }
 
+impl Direct3DError {
+    /// Whether the adapter went away, e.g. because the GPU was reset or unplugged. Its LUID isn't
+    /// found anymore then, or the device creation fails
+    pub fn is_device_removed(&self) -> bool {
+        matches!(
+            self,
+            Self::Win32(e) if e.code() == DXGI_ERROR_DEVICE_REMOVED || e.code() == DXGI_ERROR_NOT_FOUND
+        )
+    }
+}
+
 impl From<&'static str> for Direct3DError {
     fn from(value: &'static str) -> Self {
         Direct3DError::Other(value)
@@ -35,7 +51,8 @@ pub struct Direct3DDevice {
     _dxgi_factory: IDXGIFactory5,
     _adapter: IDXGIAdapter1,
     pub device: ID3D11Device,
-    _device_context: ID3D11DeviceContext,
+    // single threaded, only the swap chain processor may use it
+    pub device_context: ID3D11DeviceContext,
 }
 
 impl Direct3DDevice {
@@ -71,7 +88,48 @@ impl Direct3DDevice {
             _dxgi_factory: dxgi_factory,
             _adapter: adapter,
             device,
-            _device_context: device_context,
+            device_context,
         })
     }
 }
+
+/// Finds the first hardware adapter matching `preference`
+pub fn find_adapter(preference: &AdapterPreference) -> Result<Option<Luid>, Direct3DError> {
+    let dxgi_factory =
+        unsafe { CreateDXGIFactory2::<IDXGIFactory5>(DXGI_CREATE_FACTORY_FLAGS(0))? };
+
+    for index in 0.. {
+        let adapter = match unsafe { dxgi_factory.EnumAdapters1(index) } {
+            Ok(adapter) => adapter,
+            Err(e) if e.code() == DXGI_ERROR_NOT_FOUND => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let desc = unsafe { adapter.GetDesc1()? };
+
+        // the software rasterizer can't render for IddCx
+        #[allow(clippy::cast_sign_loss)]
+        if desc.Flags & DXGI_ADAPTER_FLAG_SOFTWARE.0 as u32 != 0 {
+            continue;
+        }
+
+        let len = desc
+            .Description
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(desc.Description.len());
+        let description = String::from_utf16_lossy(&desc.Description[..len]);
+
+        let luid = Luid {
+            low_part: desc.AdapterLuid.LowPart,
+            high_part: desc.AdapterLuid.HighPart,
+        };
+
+        if preference.matches(luid, &description) {
+            info!("Preferred adapter is {description} ({luid})");
+            return Ok(Some(luid));
+        }
+    }
+
+    Ok(None)
+}
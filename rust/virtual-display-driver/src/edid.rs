@@ -1,6 +1,14 @@
 use std::{array::TryFromSliceError, ops::Deref};
 
 use bytemuck::{Pod, Zeroable};
+use driver_ipc::{
+    edid::{
+        AMD_OUI, AUDIO_DATA_BLOCK, BASIC_AUDIO, CHROMATICITY_OFFSET, COLORIMETRY_DATA_BLOCK,
+        CTA_TAG, DCI_P3_COLORIMETRY, DESCRIPTOR_LEN, EXTENDED_DATA_BLOCK, GAMMA_OFFSET, HDMI_OUI,
+        VENDOR_DATA_BLOCK,
+    },
+    ColorCalibration, ColorSpace, Monitor,
+};
 
 const _EDID: [u8; 128] = [
     0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x0D, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -15,6 +23,26 @@ const _EDID: [u8; 128] = [
 
 const EDID_LEN: usize = _EDID.len();
 
+// display descriptor slots of the base block, the first two hold the timing and range limits
+const TIMING_DESCRIPTOR_OFFSET: usize = 54;
+// maximum pixel clock of the range limits descriptor, in 10 MHz
+const MAX_PIXEL_CLOCK_OFFSET: usize = 81;
+const NAME_DESCRIPTOR_OFFSET: usize = 90;
+const SERIAL_DESCRIPTOR_OFFSET: usize = 108;
+const NAME_DESCRIPTOR_TAG: u8 = 0xFC;
+const SERIAL_DESCRIPTOR_TAG: u8 = 0xFF;
+// characters a text descriptor holds
+const DESCRIPTOR_TEXT_LEN: usize = 13;
+// feature support byte, and its bit declaring sRGB the default color space
+const FEATURES_OFFSET: usize = 24;
+const SRGB_FEATURE: u8 = 0x04;
+// byte holding the number of extension blocks
+const EXTENSION_COUNT_OFFSET: usize = 126;
+// CTA-861 revision of the extension block written
+const CTA_REVISION: u8 = 3;
+// short audio descriptor of 2 channel LPCM at 32/44.1/48 kHz with 16/20/24 bits
+const PCM_AUDIO_DESCRIPTOR: [u8; 3] = [0x09, 0x07, 0x07];
+
 static EDID: AlignedEdid<EDID_LEN> = AlignedEdid {
     data: _EDID,
     _align: [],
@@ -59,6 +87,7 @@ pub struct Edid {
 }
 
 impl Edid {
+    /// Generates the EDID of a monitor without a custom one
     pub fn generate_with(serial: u32) -> Vec<u8> {
         // change serial number in the header
         let mut header = *EDID;
@@ -67,8 +96,77 @@ impl Edid {
         header.generate()
     }
 
+    /// Generates the EDID of a monitor without a custom one, carrying the identity set on it
+    ///
+    /// An invalid manufacturer ID keeps the built in one. The name and serial are also written as
+    /// monitor name (0xFC) and serial (0xFF) descriptors, which show up in monitor info tools.
+    /// Those hold 13 ASCII characters, so longer names are cut off and other characters become `?`.
+    /// Without a color calibration or color space, the built in sRGB characteristics are kept, see
+    /// [`Monitor::color_calibration`]. A custom timing of
+    /// the preferred mode replaces the built in 1080p60 one, see [`write_timing`]. An adaptive sync
+    /// range or audio adds a CTA-861 extension block, see [`cta_extension`]
+    pub fn generate_for(monitor: &Monitor) -> Vec<u8> {
+        // change the identity in the header
+        let mut header = *EDID;
+        header.serial_number = monitor.edid_serial();
+        header.product_code = monitor.product_code;
+
+        if let Some(manufacturer_id) = monitor.manufacturer_id.as_deref().and_then(encode_pnp_id) {
+            header.manufacturer_id = manufacturer_id;
+        }
+
+        let mut edid = header.generate();
+
+        if let Some(name) = &monitor.name {
+            write_text_descriptor(&mut edid, NAME_DESCRIPTOR_OFFSET, NAME_DESCRIPTOR_TAG, name);
+        }
+
+        if let Some(serial) = monitor.serial {
+            let serial = serial.to_string();
+            write_text_descriptor(
+                &mut edid,
+                SERIAL_DESCRIPTOR_OFFSET,
+                SERIAL_DESCRIPTOR_TAG,
+                &serial,
+            );
+        }
+
+        if monitor.color.is_some() || monitor.color_space.is_some() {
+            write_color(&mut edid, &monitor.color_calibration());
+        }
+
+        write_timing(&mut edid, monitor);
+
+        if let Some(extension) = cta_extension(monitor) {
+            edid[EXTENSION_COUNT_OFFSET] = 1;
+            edid.extend(extension);
+        }
+
+        Self::gen_checksum(&mut edid);
+
+        edid
+    }
+
+    /// Takes a custom EDID and replaces its serial number
+    ///
+    /// Extension blocks are kept as they are. `edid` must be at least one block long
+    pub fn with_serial(edid: &[u8], serial: u32) -> Vec<u8> {
+        let mut edid = edid.to_vec();
+
+        let mut header: Self = bytemuck::pod_read_unaligned(&edid[..EDID_SIZE]);
+        header.serial_number = serial;
+        edid[..EDID_SIZE].copy_from_slice(bytemuck::bytes_of(&header));
+
+        // only the base block checksum covers the header
+        Self::gen_checksum(&mut edid);
+
+        edid
+    }
+
     pub fn get_serial(edid: &[u8]) -> Result<u32, TryFromSliceError> {
-        let edid = AlignedEdid::<EDID_LEN>::new(edid)?;
+        // custom EDIDs may have extension blocks after the base block
+        let base = edid.get(..EDID_LEN).unwrap_or(edid);
+        let edid = AlignedEdid::<EDID_LEN>::new(base)?;
         Ok(edid.serial_number)
     }
 
@@ -103,3 +201,444 @@ impl Edid {
         data[127] = checksum;
     }
 }
+
+/// Packs a PNP ID like "DEL" into 5 bits per letter, big endian
+fn encode_pnp_id(id: &str) -> Option<[u8; 2]> {
+    let &[a, b, c] = id.as_bytes() else {
+        return None;
+    };
+
+    let mut packed = 0u16;
+    for letter in [a, b, c] {
+        if !letter.is_ascii_uppercase() {
+            return None;
+        }
+
+        packed = (packed << 5) | u16::from(letter - b'A' + 1);
+    }
+
+    Some(packed.to_be_bytes())
+}
+
+/// Writes the gamma and chromaticities, and only claims sRGB if they are the sRGB ones
+fn write_color(edid: &mut [u8], color: &ColorCalibration) {
+    let (gamma, chromaticity) = color.to_edid();
+
+    edid[GAMMA_OFFSET] = gamma;
+    edid[CHROMATICITY_OFFSET..CHROMATICITY_OFFSET + chromaticity.len()]
+        .copy_from_slice(&chromaticity);
+
+    if *color == ColorCalibration::SRGB {
+        edid[FEATURES_OFFSET] |= SRGB_FEATURE;
+    } else {
+        edid[FEATURES_OFFSET] &= !SRGB_FEATURE;
+    }
+}
+
+/// Writes the custom timing of the preferred mode as the preferred detailed timing
+///
+/// The maximum pixel clock of the range limits is raised to cover the custom timings of all
+/// modes, clocks above 2.55 GHz don't fit it and are capped
+fn write_timing(edid: &mut [u8], monitor: &Monitor) {
+    if let Some((mode, timing)) = monitor
+        .modes
+        .first()
+        .and_then(|mode| Some((mode, mode.timing?)))
+    {
+        edid[TIMING_DESCRIPTOR_OFFSET..TIMING_DESCRIPTOR_OFFSET + DESCRIPTOR_LEN]
+            .copy_from_slice(&timing.to_dtd(mode.width, mode.height));
+    }
+
+    let max_clock = monitor
+        .modes
+        .iter()
+        .filter_map(|mode| mode.timing)
+        .map(|timing| timing.pixel_clock_khz.div_ceil(10_000))
+        .max()
+        .map_or(0, |clock| u8::try_from(clock).unwrap_or(u8::MAX));
+
+    let range_limit = &mut edid[MAX_PIXEL_CLOCK_OFFSET];
+    *range_limit = (*range_limit).max(max_clock);
+}
+
+/// A CTA-861 extension block, if the monitor has anything that needs one
+///
+/// Audio adds the basic audio flag, an audio data block with 2 channel PCM and an HDMI vendor
+/// specific data block, as sinks with audio are expected to be HDMI ones. An adaptive sync range
+/// adds an AMD FreeSync vendor specific data block, rates above 255 Hz don't fit it and are capped.
+/// The DCI-P3 color space adds a colorimetry data block claiming it
+fn cta_extension(monitor: &Monitor) -> Option<[u8; EDID_LEN]> {
+    let mut flags = 0;
+    let mut data = Vec::new();
+
+    if monitor.audio {
+        flags |= BASIC_AUDIO;
+        push_data_block(&mut data, AUDIO_DATA_BLOCK, &PCM_AUDIO_DESCRIPTOR);
+        // physical address 1.0.0.0
+        let mut hdmi = [0; 5];
+        hdmi[..3].copy_from_slice(&HDMI_OUI);
+        hdmi[3] = 0x10;
+        push_data_block(&mut data, VENDOR_DATA_BLOCK, &hdmi);
+    }
+
+    if let Some((min, max)) = monitor.vrr_range {
+        let min = u8::try_from(min).unwrap_or(u8::MAX);
+        let max = u8::try_from(max).unwrap_or(u8::MAX);
+
+        // OUI, version 1.1, the range, and no further features
+        let mut freesync = [0; 8];
+        freesync[..3].copy_from_slice(&AMD_OUI);
+        freesync[3..].copy_from_slice(&[0x01, 0x01, min, max, 0x00]);
+        push_data_block(&mut data, VENDOR_DATA_BLOCK, &freesync);
+    }
+
+    if monitor.color_space == Some(ColorSpace::DciP3) {
+        push_data_block(
+            &mut data,
+            EXTENDED_DATA_BLOCK,
+            &[COLORIMETRY_DATA_BLOCK, 0x00, DCI_P3_COLORIMETRY],
+        );
+    }
+
+    if data.is_empty() {
+        return None;
+    }
+
+    let mut block = [0; EDID_LEN];
+    // the detailed timings would start after the data blocks, there are none
+    #[allow(clippy::cast_possible_truncation)]
+    let dtd_start = (4 + data.len()) as u8;
+    block[..4].copy_from_slice(&[CTA_TAG, CTA_REVISION, dtd_start, flags]);
+    block[4..4 + data.len()].copy_from_slice(&data);
+
+    let sum = block[..EDID_LEN - 1]
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b));
+    block[EDID_LEN - 1] = sum.wrapping_neg();
+
+    Some(block)
+}
+
+/// Appends a CTA data block, its payload must be shorter than 32 bytes
+fn push_data_block(data: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    #[allow(clippy::cast_possible_truncation)]
+    data.push((tag << 5) | payload.len() as u8);
+    data.extend_from_slice(payload);
+}
+
+/// Writes an 18 byte display descriptor holding text, like the monitor name
+///
+/// The text is cut to 13 characters, shorter text is terminated with a line feed and padded with
+/// spaces as the EDID spec requires
+fn write_text_descriptor(edid: &mut [u8], offset: usize, tag: u8, text: &str) {
+    let mut data = [b' '; DESCRIPTOR_TEXT_LEN];
+
+    let mut len = 0;
+    for (byte, c) in data.iter_mut().zip(text.chars()) {
+        *byte = u8::try_from(c)
+            .ok()
+            .filter(|b| b.is_ascii_graphic() || *b == b' ')
+            .unwrap_or(b'?');
+
+        len += 1;
+    }
+
+    if len < DESCRIPTOR_TEXT_LEN {
+        data[len] = b'\n';
+    }
+
+    let descriptor = &mut edid[offset..offset + 18];
+    descriptor[..5].copy_from_slice(&[0x00, 0x00, 0x00, tag, 0x00]);
+    descriptor[5..].copy_from_slice(&data);
+}
+
+#[cfg(test)]
+mod test {
+    use driver_ipc::{
+        edid::{self, ParsedEdid},
+        CustomTiming, MonitorBuilder,
+    };
+
+    use super::*;
+
+    // Golden outputs. Any change to these is a change of what Windows sees, so update them only
+    // on purpose
+
+    const DEFAULT: [u8; 128] = [
+        0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x0D, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xFF, 0x21, 0x01, 0x03, 0x80, 0x32, 0x1F, 0x78, 0x07, 0xEE, 0x95, 0xA3, 0x54, 0x4C,
+        0x99, 0x26, 0x0F, 0x50, 0x54, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x3A, 0x80, 0x18, 0x71, 0x38,
+        0x2D, 0x40, 0x58, 0x2C, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1E, 0x00, 0x00, 0x00,
+        0xFD, 0x00, 0x17, 0xF0, 0x0F, 0xFF, 0x0F, 0x00, 0x0A, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
+        0x00, 0x00, 0x00, 0xFC, 0x00, 0x56, 0x69, 0x72, 0x74, 0x75, 0x44, 0x69, 0x73, 0x70, 0x6C,
+        0x61, 0x79, 0x2B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x51,
+    ];
+    const IDENTITY: [u8; 128] = [
+        0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x10, 0xAC, 0x21, 0x43, 0x40, 0xE2, 0x01,
+        0x00, 0xFF, 0x21, 0x01, 0x03, 0x80, 0x32, 0x1F, 0x78, 0x07, 0xEE, 0x95, 0xA3, 0x54, 0x4C,
+        0x99, 0x26, 0x0F, 0x50, 0x54, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x3A, 0x80, 0x18, 0x71, 0x38,
+        0x2D, 0x40, 0x58, 0x2C, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1E, 0x00, 0x00, 0x00,
+        0xFD, 0x00, 0x17, 0xF0, 0x0F, 0xFF, 0x0F, 0x00, 0x0A, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
+        0x00, 0x00, 0x00, 0xFC, 0x00, 0x44, 0x65, 0x73, 0x6B, 0x0A, 0x20, 0x20, 0x20, 0x20, 0x20,
+        0x20, 0x20, 0x20, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x0A,
+        0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x00, 0xC0,
+    ];
+    const LONG_NAME: [u8; 128] = [
+        0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x0D, 0x19, 0x00, 0x00, 0x07, 0x00, 0x00,
+        0x00, 0xFF, 0x21, 0x01, 0x03, 0x80, 0x32, 0x1F, 0x78, 0x07, 0xEE, 0x95, 0xA3, 0x54, 0x4C,
+        0x99, 0x26, 0x0F, 0x50, 0x54, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x3A, 0x80, 0x18, 0x71, 0x38,
+        0x2D, 0x40, 0x58, 0x2C, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1E, 0x00, 0x00, 0x00,
+        0xFD, 0x00, 0x17, 0xF0, 0x0F, 0xFF, 0x0F, 0x00, 0x0A, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
+        0x00, 0x00, 0x00, 0xFC, 0x00, 0x56, 0x69, 0x72, 0x74, 0x75, 0x3F, 0x6C, 0x20, 0x44, 0x69,
+        0x73, 0x70, 0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x84,
+    ];
+
+    /// Checks the structure of `edid` and reads it back
+    fn parse(edid: &[u8]) -> ParsedEdid {
+        assert_eq!(usize::from(edid[126]), edid.len() / EDID_LEN - 1);
+        edid::parse(edid).unwrap()
+    }
+
+    #[test]
+    fn default_edid() {
+        let edid = Edid::generate_with(0);
+        assert_eq!(edid, DEFAULT);
+        let parsed = parse(&edid);
+        assert_eq!(parsed.manufacturer_id.as_deref(), Some("CHY"));
+        assert_eq!(parsed.product_code, 0);
+        assert_eq!(parsed.serial, 0);
+        assert_eq!(parsed.name.as_deref(), Some("VirtuDisplay+"));
+        assert_eq!(parsed.serial_text, None);
+
+        // a monitor without identity gets the same EDID as before
+        let monitor = MonitorBuilder::new(0).build().unwrap();
+        assert_eq!(Edid::generate_for(&monitor), DEFAULT);
+    }
+
+    #[test]
+    fn identity_edid() {
+        let monitor = MonitorBuilder::new(3)
+            .name("Desk")
+            .manufacturer_id("DEL")
+            .product_code(0x4321)
+            .serial(123_456)
+            .build()
+            .unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid, IDENTITY);
+        let parsed = parse(&edid);
+        assert_eq!(parsed.manufacturer_id.as_deref(), Some("DEL"));
+        assert_eq!(parsed.product_code, 0x4321);
+        assert_eq!(parsed.serial, 123_456);
+        assert_eq!(parsed.name.as_deref(), Some("Desk"));
+        assert_eq!(parsed.serial_text.as_deref(), Some("123456"));
+        assert_eq!(Edid::get_serial(&edid).unwrap(), monitor.edid_serial());
+    }
+
+    #[test]
+    fn long_name_edid() {
+        let monitor = MonitorBuilder::new(7)
+            .name("Virtu\u{e4}l Display Pro")
+            .manufacturer_id("bad")
+            .build();
+        assert!(
+            monitor.is_err(),
+            "invalid IDs are rejected before the driver"
+        );
+
+        let mut monitor = MonitorBuilder::new(7)
+            .name("Virtu\u{e4}l Display Pro")
+            .build()
+            .unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid, LONG_NAME);
+        assert_eq!(parse(&edid).name.as_deref(), Some("Virtu?l Displ"));
+
+        // older clients can still send an invalid ID, the built in one is kept
+        monitor.manufacturer_id = Some("bad".to_owned());
+        assert_eq!(Edid::generate_for(&monitor), LONG_NAME);
+    }
+
+    #[test]
+    fn color_edid() {
+        let color = ColorCalibration {
+            gamma: 2.4,
+            red: (0.68, 0.32),
+            green: (0.265, 0.69),
+            blue: (0.15, 0.06),
+            white: (0.3127, 0.329),
+        };
+        let monitor = MonitorBuilder::new(0).color(color).build().unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid[GAMMA_OFFSET], 140);
+        assert_eq!(edid[FEATURES_OFFSET] & SRGB_FEATURE, 0);
+        // only the color characteristics and the checksum change
+        let changed = (0..EDID_LEN)
+            .filter(|&i| edid[i] != DEFAULT[i])
+            .collect::<Vec<_>>();
+        assert!(changed
+            .iter()
+            .all(|i| (GAMMA_OFFSET..35).contains(i) || *i == 127));
+
+        let parsed = parse(&edid).color.unwrap();
+        assert!((parsed.red.0 - 0.68).abs() <= 0.5 / 1024.0);
+        assert!((parsed.green.1 - 0.69).abs() <= 0.5 / 1024.0);
+
+        // explicitly sRGB keeps claiming it
+        let monitor = MonitorBuilder::new(0)
+            .color(ColorCalibration::SRGB)
+            .build()
+            .unwrap();
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid[FEATURES_OFFSET], DEFAULT[FEATURES_OFFSET]);
+        assert_eq!(edid[GAMMA_OFFSET], DEFAULT[GAMMA_OFFSET]);
+    }
+
+    #[test]
+    fn color_space_edid() {
+        let monitor = MonitorBuilder::new(0)
+            .color_space(ColorSpace::DciP3)
+            .build()
+            .unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid.len(), 2 * EDID_LEN);
+        assert_eq!(edid[FEATURES_OFFSET] & SRGB_FEATURE, 0);
+        assert_eq!(edid[GAMMA_OFFSET], DEFAULT[GAMMA_OFFSET]);
+        let extension = &edid[EDID_LEN..];
+        assert_eq!(extension[..4], [CTA_TAG, CTA_REVISION, 8, 0]);
+        assert_eq!(extension[4..8], [0xE3, 0x05, 0x00, 0x80]);
+
+        let parsed = parse(&edid).color.unwrap();
+        assert!((parsed.red.0 - 0.68).abs() <= 0.5 / 1024.0);
+        assert!((parsed.white.0 - 0.3127).abs() <= 0.5 / 1024.0);
+
+        // sRGB is what the built in EDID claims already
+        let monitor = MonitorBuilder::new(0)
+            .color_space(ColorSpace::Srgb)
+            .build()
+            .unwrap();
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid.len(), EDID_LEN);
+        assert_eq!(edid[FEATURES_OFFSET], DEFAULT[FEATURES_OFFSET]);
+    }
+
+    #[test]
+    fn vrr_edid() {
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[144, 48])
+            .vrr_range(48, 144)
+            .build()
+            .unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid.len(), 2 * EDID_LEN);
+        assert_eq!(edid[EDID_LEN..EDID_LEN + 4], [CTA_TAG, CTA_REVISION, 13, 0]);
+
+        let parsed = parse(&edid);
+        assert_eq!(parsed.vrr_range, Some((48, 144)));
+        assert_eq!(parsed.name.as_deref(), Some("VirtuDisplay+"));
+        assert_eq!(Edid::get_serial(&edid).unwrap(), 0);
+
+        // the base block only differs in the extension count and checksum
+        let changed = (0..EDID_LEN)
+            .filter(|&i| edid[i] != DEFAULT[i])
+            .collect::<Vec<_>>();
+        assert_eq!(changed, [EXTENSION_COUNT_OFFSET, 127]);
+    }
+
+    #[test]
+    fn audio_edid() {
+        let monitor = MonitorBuilder::new(0).audio(true).build().unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(edid.len(), 2 * EDID_LEN);
+        let extension = &edid[EDID_LEN..];
+        assert_eq!(extension[..4], [CTA_TAG, CTA_REVISION, 14, BASIC_AUDIO]);
+        assert_eq!(extension[4..8], [0x23, 0x09, 0x07, 0x07]);
+        assert_eq!(extension[8..11], [0x65, 0x03, 0x0C]);
+
+        let parsed = parse(&edid);
+        assert!(parsed.audio);
+        assert_eq!(parsed.vrr_range, None);
+
+        // both share the one extension
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[144, 48])
+            .vrr_range(48, 144)
+            .audio(true)
+            .build()
+            .unwrap();
+        let parsed = parse(&Edid::generate_for(&monitor));
+        assert!(parsed.audio);
+        assert_eq!(parsed.vrr_range, Some((48, 144)));
+    }
+
+    #[test]
+    fn timing_edid() {
+        // CVT reduced blanking 2560x1440 at 120 Hz
+        let timing = CustomTiming {
+            pixel_clock_khz: 497_760,
+            h_front_porch: 48,
+            h_sync_width: 32,
+            h_back_porch: 80,
+            v_front_porch: 3,
+            v_sync_width: 5,
+            v_back_porch: 77,
+            h_sync_positive: true,
+            v_sync_positive: false,
+        };
+        let monitor = MonitorBuilder::new(0)
+            .timed_mode(2560, 1440, timing)
+            .mode(1920, 1080, &[60])
+            .build()
+            .unwrap();
+
+        let edid = Edid::generate_for(&monitor);
+        assert_eq!(
+            edid[TIMING_DESCRIPTOR_OFFSET..TIMING_DESCRIPTOR_OFFSET + DESCRIPTOR_LEN],
+            timing.to_dtd(2560, 1440)
+        );
+        assert_eq!(edid[MAX_PIXEL_CLOCK_OFFSET], 50);
+
+        let parsed = parse(&edid);
+        assert_eq!(parsed.timings[0], edid::Timing::new(2560, 1440, 120));
+
+        // only the preferred mode's timing is written, but the clock limit covers all
+        let monitor = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[60])
+            .timed_mode(2560, 1440, timing)
+            .build()
+            .unwrap();
+        let edid = Edid::generate_for(&monitor);
+        let changed = (0..EDID_LEN)
+            .filter(|&i| edid[i] != DEFAULT[i])
+            .collect::<Vec<_>>();
+        assert_eq!(changed, [MAX_PIXEL_CLOCK_OFFSET, 127]);
+    }
+
+    #[test]
+    fn custom_edid_serial() {
+        let mut custom = IDENTITY.to_vec();
+        custom[126] = 1;
+        custom.extend((0..EDID_LEN).map(|i| u8::try_from(i).unwrap()));
+        let extension_sum = custom[EDID_LEN..2 * EDID_LEN - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        custom[2 * EDID_LEN - 1] = extension_sum.wrapping_neg();
+        Edid::gen_checksum(&mut custom);
+
+        let edid = Edid::with_serial(&custom, 99);
+        assert_eq!(parse(&edid).serial, 99);
+        assert_eq!(edid[EDID_LEN..], custom[EDID_LEN..]);
+        assert_eq!(Edid::get_serial(&edid).unwrap(), 99);
+    }
+}
@@ -1,187 +1,267 @@
 use std::time::{Duration, Instant};
 
+use driver_ipc::{InitStep, DEVICE_INTERFACE};
 use driver_logger::DriverLogger;
-use log::{error, info, Level};
+use log::{error, info, warn, Level};
 use wdf_umdf::{
-    IddCxDeviceInitConfig, IddCxDeviceInitialize, WdfDeviceCreate,
-    WdfDeviceInitSetPnpPowerEventCallbacks, WdfDeviceSetFailed, WdfDriverCreate,
+    logging, wdf_callback, IddCxDeviceInitConfig, IddCxDeviceInitialize, SoftwareDeviceInit,
+    WdfDeviceCreate, WdfDeviceCreateDeviceInterface, WdfDeviceInitAssignName,
+    WdfDeviceInitSetCharacteristics, WdfDeviceInitSetPnpPowerEventCallbacks, WdfDeviceSetFailed,
+    WdfDriverCreate,
 };
 use wdf_umdf_sys::{
-    IDD_CX_CLIENT_CONFIG, NTSTATUS, WDFDEVICE_INIT, WDFDRIVER__, WDFOBJECT,
-    WDF_DEVICE_FAILED_ACTION, WDF_DRIVER_CONFIG, WDF_OBJECT_ATTRIBUTES,
-    WDF_PNPPOWER_EVENT_CALLBACKS, _DRIVER_OBJECT, _UNICODE_STRING,
+    IDD_CX_CLIENT_CONFIG, NTSTATUS, PDRIVER_INITIALIZE, PFN_WDF_DRIVER_DEVICE_ADD,
+    PFN_WDF_DRIVER_UNLOAD, PFN_WDF_OBJECT_CONTEXT_CLEANUP, WDFDEVICE_INIT, WDFDRIVER, WDFDRIVER__,
+    WDFOBJECT, WDF_DEVICE_FAILED_ACTION, WDF_DRIVER_CONFIG, WDF_OBJECT_ATTRIBUTES,
+    WDF_PNPPOWER_EVENT_CALLBACKS, _DRIVER_OBJECT, _GUID, _UNICODE_STRING,
 };
 
 use crate::callbacks::{
     adapter_commit_modes, adapter_init_finished, assign_swap_chain, device_d0_entry,
-    monitor_get_default_modes, monitor_query_modes, parse_monitor_description, unassign_swap_chain,
+    device_d0_exit, monitor_get_default_modes, monitor_query_modes, parse_monitor_description,
+    unassign_swap_chain,
 };
-use crate::{context::DeviceContext, helpers::Sendable};
+use crate::{
+    context::DeviceContext,
+    helpers::Sendable,
+    ipc,
+    settings::LOG_LEVEL,
+    shutdown::{SHUTDOWN, SHUTDOWN_TIMEOUT},
+};
+
+/// Name of the device object, to find it in tools like WinObj
+const DEVICE_NAME: &str = r"\Device\VirtualDisplayCtl";
+/// Apply the device's security to opens of names below the device as well
+const FILE_DEVICE_SECURE_OPEN: u32 = 0x0000_0100;
 
 //
 // Our driver's entry point
 // See windows::Wdk::System::SystemServices::DRIVER_INITIALIZE
 //
-#[no_mangle]
-extern "C-unwind" fn DriverEntry(
-    driver_object: *mut _DRIVER_OBJECT,
-    registry_path: *mut _UNICODE_STRING,
-) -> NTSTATUS {
-    // During system bootup, `RegisterEventSourceW` fails and causes the driver to not bootup
-    // Pretty unfortunate, therefore, we will run this on a thread until it succeeds and let the rest of
-    // the driver start. I know this is suboptimal considering it's our main code to catch panics.
-    //
-    // It always starts immediately when the computer is already booted up.
-    // If you have a better solution, please by all means open an issue report
-    let init_log = || {
-        let mut logger = DriverLogger::new(if cfg!(debug_assertions) {
-            Level::Debug
-        } else {
-            Level::Info
-        });
-
-        if cfg!(debug_assertions) {
-            logger.debug();
-        } else if logger.name("VirtualDisplayDriver").is_err() {
-            return NTSTATUS::STATUS_UNSUCCESSFUL;
+wdf_callback! {
+    #[no_mangle]
+    fn DriverEntry(
+        driver_object: *mut _DRIVER_OBJECT,
+        registry_path: *mut _UNICODE_STRING,
+    ) -> NTSTATUS as PDRIVER_INITIALIZE {
+        // SAFETY: Unregistered in driver_unload, or below if the driver fails to load
+        unsafe {
+            logging::register();
         }
 
-        let status = logger
-            .init()
-            .map_err(|_| NTSTATUS::STATUS_FAILED_DRIVER_ENTRY)
-            .into();
-
-        if status == NTSTATUS::STATUS_SUCCESS {
-            info!(
-                "Initialized Virtual Display Driver v{} @ {}",
-                env!("CARGO_PKG_VERSION"),
-                env!("VERGEN_GIT_SHA")
-            );
-        }
+        // During system bootup, `RegisterEventSourceW` fails and causes the driver to not bootup
+        // Pretty unfortunate, therefore, we will run this on a thread until it succeeds and let the rest of
+        // the driver start. I know this is suboptimal considering it's our main code to catch panics.
+        //
+        // It always starts immediately when the computer is already booted up.
+        // If you have a better solution, please by all means open an issue report
+        let init_log = || {
+            let mut logger = DriverLogger::new(if cfg!(debug_assertions) {
+                Level::Debug
+            } else {
+                Level::Info
+            });
+
+            if cfg!(feature = "debug-log") {
+                logger.debug();
+            } else if logger.name("VirtualDisplayDriver").is_err() {
+                return NTSTATUS::STATUS_UNSUCCESSFUL;
+            }
 
-        status
-    };
-
-    let status = init_log();
-
-    if !status.is_success() {
-        // Okay, let's try another method then
-        let device = unsafe { Sendable::new(driver_object) };
-
-        std::thread::spawn(move || {
-            #[allow(clippy::redundant_locals)]
-            let device = device;
-            let time_waited = Instant::now();
-            // 5 minutes
-            let timeout_duration = Duration::from_secs(60 * 5);
-            // in ms
-            let sleep_for = 500;
-
-            loop {
-                let status = init_log();
-                std::thread::sleep(Duration::from_millis(sleep_for));
-
-                // if it succeeds, great. if it didn't conclude after 5 minutes
-                // Surely a users system is booted up before then?
-                let timedout = time_waited.elapsed() >= timeout_duration;
-                if status.is_success() || timedout {
-                    if timedout {
-                        // Service took too long to start. Unfortunately, there is no way to log this failure
-                        unsafe {
-                            _ = WdfDeviceSetFailed(
-                                device.cast(),
-                                WDF_DEVICE_FAILED_ACTION::WdfDeviceFailedNoRestart,
+            let status = logger
+                .init()
+                .map_err(|_| NTSTATUS::STATUS_FAILED_DRIVER_ENTRY)
+                .into();
+
+            if status == NTSTATUS::STATUS_SUCCESS {
+                driver_logger::set_level(*LOG_LEVEL);
+
+                info!(
+                    "Initialized Virtual Display Driver v{} @ {}",
+                    env!("CARGO_PKG_VERSION"),
+                    env!("VERGEN_GIT_SHA")
+                );
+            }
+
+            status
+        };
+
+        let status = init_log();
+
+        if !status.is_success() {
+            // Okay, let's try another method then
+            let device = unsafe { Sendable::new(driver_object) };
+
+            std::thread::spawn(move || {
+                #[allow(clippy::redundant_locals)]
+                let device = device;
+                let time_waited = Instant::now();
+                // 5 minutes
+                let timeout_duration = Duration::from_secs(60 * 5);
+                // in ms
+                let sleep_for = 500;
+
+                loop {
+                    let status = init_log();
+                    std::thread::sleep(Duration::from_millis(sleep_for));
+
+                    // if it succeeds, great. if it didn't conclude after 5 minutes
+                    // Surely a users system is booted up before then?
+                    let timedout = time_waited.elapsed() >= timeout_duration;
+                    if status.is_success() || timedout {
+                        if timedout {
+                            // Service took too long to start. Unfortunately, there is no way to log this failure
+                            unsafe {
+                                _ = WdfDeviceSetFailed(
+                                    device.cast(),
+                                    WDF_DEVICE_FAILED_ACTION::WdfDeviceFailedNoRestart,
+                                );
+                            }
+                        } else {
+                            info!(
+                                "Service took {} seconds to start",
+                                time_waited.elapsed().as_secs()
                             );
                         }
-                    } else {
-                        info!(
-                            "Service took {} seconds to start",
-                            time_waited.elapsed().as_secs()
-                        );
-                    }
 
-                    break;
+                        break;
+                    }
                 }
-            }
-        });
-    }
+            });
+        }
 
-    // set the panic hook to capture and log panics
-    crate::panic::set_hook();
+        // set the panic hook to capture and log panics
+        crate::panic::set_hook();
 
-    let mut attributes = WDF_OBJECT_ATTRIBUTES::init();
+        let mut attributes = WDF_OBJECT_ATTRIBUTES::init();
 
-    let mut config = WDF_DRIVER_CONFIG::init(Some(driver_add));
+        let mut config = WDF_DRIVER_CONFIG::init(Some(driver_add)).unload(driver_unload);
 
-    unsafe {
-        WdfDriverCreate(
-            driver_object,
-            registry_path,
-            Some(&mut attributes),
-            &mut config,
-            None,
-        )
+        let status: NTSTATUS = unsafe {
+            WdfDriverCreate(
+                driver_object,
+                registry_path,
+                Some(&mut attributes),
+                &mut config,
+                None,
+            )
+        }
+        .into();
+
+        // the dll is unloaded without calling driver_unload
+        if !status.is_success() {
+            logging::unregister();
+        }
+
+        status
     }
-    .into()
 }
 
-extern "C-unwind" fn driver_add(
-    _driver: *mut WDFDRIVER__,
-    mut init: *mut WDFDEVICE_INIT,
-) -> NTSTATUS {
-    let mut callbacks = WDF_PNPPOWER_EVENT_CALLBACKS::init();
+wdf_callback! {
+    fn driver_add(
+        _driver: *mut WDFDRIVER__,
+        init: *mut WDFDEVICE_INIT,
+    ) -> NTSTATUS as PFN_WDF_DRIVER_DEVICE_ADD {
+        let mut init = init;
+        logging::device_add();
 
-    callbacks.EvtDeviceD0Entry = Some(device_d0_entry);
+        let mut callbacks = WDF_PNPPOWER_EVENT_CALLBACKS::init()
+            .d0_entry(device_d0_entry)
+            .d0_exit(device_d0_exit);
 
-    unsafe {
-        _ = WdfDeviceInitSetPnpPowerEventCallbacks(init, &mut callbacks);
-    }
+        unsafe {
+            _ = WdfDeviceInitSetPnpPowerEventCallbacks(init, &mut callbacks);
+        }
 
-    let Some(mut config) = IDD_CX_CLIENT_CONFIG::init() else {
-        error!("Failed to create IDD_CX_CLIENT_CONFIG");
-        return NTSTATUS::STATUS_NOT_FOUND;
-    };
-
-    config.EvtIddCxAdapterInitFinished = Some(adapter_init_finished);
-
-    config.EvtIddCxParseMonitorDescription = Some(parse_monitor_description);
-    config.EvtIddCxMonitorGetDefaultDescriptionModes = Some(monitor_get_default_modes);
-    config.EvtIddCxMonitorQueryTargetModes = Some(monitor_query_modes);
-    config.EvtIddCxAdapterCommitModes = Some(adapter_commit_modes);
-    config.EvtIddCxMonitorAssignSwapChain = Some(assign_swap_chain);
-    config.EvtIddCxMonitorUnassignSwapChain = Some(unassign_swap_chain);
-
-    let init_data = unsafe { &mut *init };
-    let status = unsafe { IddCxDeviceInitConfig(init_data, &config) };
-    if let Err(e) = status {
-        error!("Failed to init iddcx config: {e:?}");
-        return e.into();
-    }
+        // only a debugging aid, the device works without a name
+        let status = unsafe { WdfDeviceInitAssignName(init, Some(DEVICE_NAME)) };
+        if let Err(e) = status {
+            warn!("Failed to name device {DEVICE_NAME}: {e:?}");
+        } else {
+            unsafe {
+                _ = WdfDeviceInitSetCharacteristics(init, FILE_DEVICE_SECURE_OPEN, true);
+            }
+        }
 
-    let mut attributes =
-        WDF_OBJECT_ATTRIBUTES::init_context_type(unsafe { DeviceContext::get_type_info() });
+        // these are the framework defaults, the device still works if they couldn't be set
+        if let Err(e) = unsafe { SoftwareDeviceInit::configure(init) } {
+            warn!("Failed to set up device: {e}");
+        }
 
-    attributes.EvtCleanupCallback = Some(event_cleanup);
+        let Some(mut config) = IDD_CX_CLIENT_CONFIG::init() else {
+            error!("Failed to create IDD_CX_CLIENT_CONFIG");
+            ipc::init_failed(InitStep::ClientConfig, NTSTATUS::STATUS_NOT_FOUND);
+            return NTSTATUS::STATUS_NOT_FOUND;
+        };
+
+        config.EvtIddCxAdapterInitFinished = Some(adapter_init_finished);
+
+        config.EvtIddCxParseMonitorDescription = Some(parse_monitor_description);
+        config.EvtIddCxMonitorGetDefaultDescriptionModes = Some(monitor_get_default_modes);
+        config.EvtIddCxMonitorQueryTargetModes = Some(monitor_query_modes);
+        config.EvtIddCxAdapterCommitModes = Some(adapter_commit_modes);
+        config.EvtIddCxMonitorAssignSwapChain = Some(assign_swap_chain);
+        config.EvtIddCxMonitorUnassignSwapChain = Some(unassign_swap_chain);
+
+        let init_data = unsafe { &mut *init };
+        let status = unsafe { IddCxDeviceInitConfig(init_data, &config) };
+        if let Err(e) = status {
+            error!("Failed to init iddcx config: {e:?}");
+            ipc::init_failed(InitStep::ClientConfig, e.into());
+            return e.into();
+        }
 
-    let mut device = std::ptr::null_mut();
+        let mut attributes =
+            WDF_OBJECT_ATTRIBUTES::init_context_type(unsafe { DeviceContext::get_type_info() });
 
-    let status = unsafe { WdfDeviceCreate(&mut init, Some(&mut attributes), &mut device) };
-    if let Err(e) = status {
-        error!("Failed to create device: {e:?}");
-        return e.into();
-    }
+        attributes.EvtCleanupCallback = Some(event_cleanup);
+
+        let mut device = std::ptr::null_mut();
 
-    let status = unsafe { IddCxDeviceInitialize(device) };
-    if let Err(e) = status {
-        error!("Failed to init iddcx device: {e:?}");
-        return e.into();
+        let status = unsafe { WdfDeviceCreate(&mut init, Some(&mut attributes), &mut device) };
+        if let Err(e) = status {
+            error!("Failed to create device: {e:?}");
+            ipc::init_failed(InitStep::DeviceCreate, e.into());
+            return e.into();
+        }
+
+        // clients find the device by the interface, the pipe works without it
+        let interface = _GUID {
+            Data1: DEVICE_INTERFACE.data1,
+            Data2: DEVICE_INTERFACE.data2,
+            Data3: DEVICE_INTERFACE.data3,
+            Data4: DEVICE_INTERFACE.data4,
+        };
+        if let Err(e) = unsafe { WdfDeviceCreateDeviceInterface(device, &interface, None) } {
+            warn!("Failed to publish the device interface: {e:?}");
+        }
+
+        let status = unsafe { IddCxDeviceInitialize(device) };
+        if let Err(e) = status {
+            error!("Failed to init iddcx device: {e:?}");
+            ipc::init_failed(InitStep::DeviceInitialize, e.into());
+            return e.into();
+        }
+
+        let context = DeviceContext::new(device);
+
+        unsafe { context.init(device as WDFOBJECT).into() }
     }
+}
 
-    let context = DeviceContext::new(device);
+wdf_callback! {
+    fn driver_unload(_driver: WDFDRIVER) as PFN_WDF_DRIVER_UNLOAD {
+        // the devices are gone already, this only catches threads started without one reaching D0
+        // exit
+        SHUTDOWN.shutdown(SHUTDOWN_TIMEOUT);
 
-    unsafe { context.init(device as WDFOBJECT).into() }
+        info!("Driver unloaded");
+        logging::unregister();
+    }
 }
 
-unsafe extern "C-unwind" fn event_cleanup(wdf_object: WDFOBJECT) {
-    _ = unsafe { DeviceContext::drop(wdf_object) };
+wdf_callback! {
+    fn event_cleanup(wdf_object: WDFOBJECT) as PFN_WDF_OBJECT_CONTEXT_CLEANUP {
+        _ = unsafe { DeviceContext::drop(wdf_object) };
+    }
 }
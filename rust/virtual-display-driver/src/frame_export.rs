@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    mem::size_of,
+    ptr::{self, NonNull},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use driver_ipc::{
+    frame_export::{
+        event_name, frames_name, ControlWriter, FrameFormat, FrameRect, FrameWriter, CONTROL_LEN,
+    },
+    Id,
+};
+use log::{debug, info};
+use windows::{
+    core::{Error, Result, HSTRING},
+    Win32::{
+        Foundation::{
+            CloseHandle, GetLastError, ERROR_ALREADY_EXISTS, HANDLE, INVALID_HANDLE_VALUE,
+        },
+        Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES},
+        System::{
+            Memory::{
+                CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_WRITE,
+                MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+            },
+            Threading::{CreateEventW, SetEvent},
+        },
+    },
+};
+
+use crate::{helpers::Sendable, ipc::pipe_security_descriptor};
+
+// Running exports by monitor id, see `DriverCommand::SetFrameExport`
+static EXPORTS: LazyLock<Mutex<HashMap<Id, Arc<Mutex<Export>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Whoever may control the driver may read its frames, so the sections get the access of the pipe
+static SECURITY: LazyLock<Sendable<PSECURITY_DESCRIPTOR>> =
+    LazyLock::new(|| unsafe { Sendable::new(pipe_security_descriptor()) });
+
+/// Starts exporting the frames of monitor `id`, returns the name of the export
+///
+/// An export which is running already is kept, and its name returned again
+pub fn enable(id: Id) -> Result<String> {
+    let mut exports = EXPORTS.lock().unwrap();
+
+    if let Some(export) = exports.get(&id) {
+        return Ok(export.lock().unwrap().name.clone());
+    }
+
+    // a random part, so other processes can't guess the name and squat it before it exists
+    let key = RandomState::new().hash_one(id);
+    let name = format!(r"Global\VirtualDisplayDriver-Frames-{id}-{key:016x}");
+
+    let export = Export::new(name.clone())?;
+    exports.insert(id, Arc::new(Mutex::new(export)));
+
+    info!("Exporting frames of monitor {id} to {name}");
+    Ok(name)
+}
+
+/// Ends the export of monitor `id`, returns whether there was one
+///
+/// Receivers are told right away, the sections go away once the swap chain processor and all
+/// receivers let go of them
+pub fn disable(id: Id) -> bool {
+    let removed = EXPORTS.lock().unwrap().remove(&id).is_some();
+    if removed {
+        info!("Stopped exporting frames of monitor {id}");
+    }
+
+    removed
+}
+
+/// Export of monitor `id`, if it is enabled
+pub fn get(id: Id) -> Option<Arc<Mutex<Export>>> {
+    EXPORTS.lock().unwrap().get(&id).cloned()
+}
+
+/// Shared memory of one monitor's frames, see `driver_ipc::frame_export` for the layout
+pub struct Export {
+    name: String,
+    control: ControlWriter,
+    _control_section: Section,
+    // writer of the current generation, `None` before the first frame
+    frames: Option<(FrameWriter, Section)>,
+    generation: u64,
+    sequence: u64,
+    event: Event,
+}
+
+impl Export {
+    fn new(name: String) -> Result<Self> {
+        let control_section = Section::create(&name, CONTROL_LEN)?;
+        let control = unsafe { ControlWriter::init(control_section.ptr()) };
+        let event = Event::create(&event_name(&name))?;
+
+        Ok(Self {
+            name,
+            control,
+            _control_section: control_section,
+            frames: None,
+            generation: 0,
+            sequence: 0,
+            event,
+        })
+    }
+
+    /// Format of the frames in the current section, `None` before the first frame
+    pub fn format(&self) -> Option<FrameFormat> {
+        self.frames.as_ref().map(|(writer, _)| writer.format())
+    }
+
+    /// Writes the next frame, presented at `qpc`, with `fill` writing its pixels and `dirty` the
+    /// regions which changed since the previous frame, if known
+    ///
+    /// A frame of another format than the previous one goes into a new section with the next
+    /// generation, and counts as changed entirely
+    pub fn write(
+        &mut self,
+        format: FrameFormat,
+        qpc: u64,
+        mut dirty: Option<&[FrameRect]>,
+        fill: impl FnOnce(&mut [u8]),
+    ) -> Result<()> {
+        if self.format() != Some(format) {
+            dirty = None;
+
+            let generation = self.generation + 1;
+            let section =
+                Section::create(&frames_name(&self.name, generation), format.section_len())?;
+            let writer = unsafe { FrameWriter::init(section.ptr(), generation, format) };
+
+            // the old section stays mapped by receivers which didn't switch over yet
+            self.frames = Some((writer, section));
+            self.generation = generation;
+            self.control.set_generation(generation);
+
+            debug!(
+                "Frame export {} is at generation {generation}, {}x{}",
+                self.name, format.width, format.height
+            );
+        }
+
+        let (writer, _) = self.frames.as_mut().expect("created above");
+
+        self.sequence += 1;
+        writer.write(self.sequence, qpc, dirty, fill);
+        self.event.set();
+
+        Ok(())
+    }
+}
+
+impl Drop for Export {
+    fn drop(&mut self) {
+        self.control.close();
+        self.event.set();
+    }
+}
+
+fn security_attributes() -> SECURITY_ATTRIBUTES {
+    let sd: &PSECURITY_DESCRIPTOR = &SECURITY;
+
+    SECURITY_ATTRIBUTES {
+        #[allow(clippy::cast_possible_truncation)]
+        nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: sd.0,
+        bInheritHandle: false.into(),
+    }
+}
+
+/// Named section created by the driver, mapped writable
+struct Section {
+    handle: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+}
+
+unsafe impl Send for Section {}
+
+impl Section {
+    fn create(name: &str, len: usize) -> Result<Self> {
+        let len = len as u64;
+
+        let sa = security_attributes();
+        #[allow(clippy::cast_possible_truncation)]
+        let handle = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                Some(ptr::from_ref(&sa)),
+                PAGE_READWRITE,
+                (len >> 32) as u32,
+                len as u32,
+                &HSTRING::from(name),
+            )?
+        };
+
+        // a section made by someone else can't be trusted with the frames
+        if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+            _ = unsafe { CloseHandle(handle) };
+            return Err(ERROR_ALREADY_EXISTS.to_hresult().into());
+        }
+
+        let view = unsafe { MapViewOfFile(handle, FILE_MAP_WRITE, 0, 0, 0) };
+        if view.Value.is_null() {
+            let e = Error::from_win32();
+            _ = unsafe { CloseHandle(handle) };
+            return Err(e);
+        }
+
+        Ok(Self { handle, view })
+    }
+
+    fn ptr(&self) -> NonNull<u8> {
+        NonNull::new(self.view.Value.cast()).expect("checked on creation")
+    }
+}
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        _ = unsafe { UnmapViewOfFile(self.view) };
+        _ = unsafe { CloseHandle(self.handle) };
+    }
+}
+
+/// Named auto reset event a receiver waits on
+struct Event(HANDLE);
+
+unsafe impl Send for Event {}
+
+impl Event {
+    fn create(name: &str) -> Result<Self> {
+        let sa = security_attributes();
+        let handle =
+            unsafe { CreateEventW(Some(ptr::from_ref(&sa)), false, false, &HSTRING::from(name))? };
+        Ok(Self(handle))
+    }
+
+    fn set(&self) {
+        _ = unsafe { SetEvent(self.0) };
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        _ = unsafe { CloseHandle(self.0) };
+    }
+}
@@ -1,160 +1,1726 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     mem::size_of,
-    ptr::{addr_of_mut, NonNull},
-    sync::{LazyLock, Mutex, OnceLock},
+    os::windows::io::AsRawHandle,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, LazyLock, Mutex, Once, OnceLock, PoisonError,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use driver_ipc::{
+    framing::{FrameDecoder, Framing},
+    monitor_events,
+    persist::{self, Loaded},
+    validate_monitors, AdapterConfig, ApplyFailure, ApplyReport, ApplyWarning, Capabilities,
+    ClientInfo, CustomTiming, Dimen, DriverCommand, DriverInfo, DriverStatus, Envelope, ErrorCode,
+    EventCommand, Id, InitFailure, InitStep, IpcStats, LogLevel, LogRecord, Mode, Monitor,
+    MonitorBuilder, MonitorError, MonitorEvent, MonitorState, RefreshRate, ReplyCommand,
+    RequestCommand, RequestId, ServerCommand, SessionScope, Stats, LEGACY_PROTOCOL_VERSION,
+    MAX_LOG_RECORDS, PROTOCOL_VERSION,
+};
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt as _},
+    net::windows::named_pipe::{NamedPipeServer, ServerOptions},
+    sync::{
+        broadcast::{self, error::RecvError, Sender},
+        mpsc::{self, UnboundedSender},
+        oneshot, OwnedSemaphorePermit, Semaphore,
+    },
+    task,
+};
+use wdf_umdf::{
+    logging, IddCxGetVersion, IddCxMonitorDeparture, Timer, WdfDriverRetrieveVersionString,
+    WdfError, WdfGetDriver, WdfObjectDelete, WdfStringCreate, WdfStringGetUnicodeString,
+};
+use wdf_umdf_sys::{
+    IDARG_OUT_GETVERSION, IDDCX_ADAPTER__, IDDCX_MONITOR__, NTSTATUS, UNICODE_STRING,
+};
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::HANDLE,
+        Security::{
+            Authorization::{
+                ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+            },
+            PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+        },
+        System::Pipes::GetNamedPipeClientProcessId,
+    },
 };
+use winreg::RegKey;
+
+use crate::{
+    context::{DeviceContext, MonitorContext},
+    frame_export, render_adapter,
+    settings::{
+        self, default_static_modes, PipeAccess, ADAPTER_CONFIG, MAX_MONITORS, MAX_PIPE_CLIENTS,
+        PIPE_ACCESS, SETTINGS_KEY, STATIC_CONNECTORS_VALUE, STATIC_MODES_VALUE,
+    },
+    shutdown::{LIFECYCLE, SHUTDOWN},
+    swap_chain_processor::FrameRateLimit,
+};
+
+pub static ADAPTER: OnceLock<AdapterObject> = OnceLock::new();
+pub static MONITOR_MODES: LazyLock<Mutex<Vec<MonitorObject>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+// Event writers of all connections which sent a subscribe request, by connection id
+static SUBSCRIBERS: LazyLock<Mutex<HashMap<usize, UnboundedSender<EventCommand>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Open connections by connection id, answered to `RequestCommand::ListClients`
+static CLIENTS: LazyLock<Mutex<BTreeMap<usize, ClientInfo>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+// Monitor ids handed out by `DriverCommand::ReserveId`, with the connection id they belong to.
+// Never saved, they only live as long as their connection
+static RESERVATIONS: LazyLock<Mutex<HashMap<Id, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Whether a user is logged on and the console session is unlocked, see
+// `DriverCommand::SetUserSession`
+static USER_SESSION: AtomicBool = AtomicBool::new(false);
+// How far IddCx initialization got, answered to `RequestCommand::DriverStatus`
+static DRIVER_STATUS: Mutex<DriverStatus> = Mutex::new(DriverStatus::Starting);
+// Monitors running in the mode of a `DriverCommand::OverrideMode`, never saved
+static OVERRIDES: LazyLock<Mutex<HashMap<Id, ModeOverride>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Timers ending the overrides, one for every monitor id which was overridden. They are deleted
+// together with the device
+static OVERRIDE_TIMERS: LazyLock<Mutex<HashMap<Id, Timer>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Queue of the pipe server, for the commands the driver runs on its own
+static QUEUE: OnceLock<CommandQueue> = OnceLock::new();
+// Messages received and error replies sent on all connections, answered to
+// `RequestCommand::IpcStats`
+static IPC_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static IPC_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct AdapterObject(pub NonNull<IDDCX_ADAPTER__>);
+unsafe impl Sync for AdapterObject {}
+unsafe impl Send for AdapterObject {}
+
+#[derive(Debug)]
+pub struct MonitorObject {
+    pub object: Option<NonNull<IDDCX_MONITOR__>>,
+    // connector the monitor was created on, only meaningful while `object` is set
+    pub connector_index: Option<u32>,
+    pub data: Monitor,
+    // limit of `data`, shared with the swap chain processor so changing it needs no reattach
+    pub frame_rate_limit: Arc<FrameRateLimit>,
+}
+unsafe impl Sync for MonitorObject {}
+unsafe impl Send for MonitorObject {}
+
+/// A monitor running in the mode of `DriverCommand::OverrideMode`
+#[derive(Debug)]
+struct ModeOverride {
+    // modes from before the first override, which come back when it ends
+    saved: Vec<Mode>,
+    // modes while overridden, the override ended if a command changed them since
+    overridden: Vec<Mode>,
+    // renewing the override moves this
+    until: Instant,
+}
+
+const BUFFER_SIZE: u32 = 4096;
+// bytes of a malformed message which are logged
+const MALFORMED_PREFIX_LEN: usize = 64;
+// how long a command waits for the device context, which the frame thread may briefly hold
+const CONTEXT_LOCK_TIMEOUT: Duration = Duration::from_millis(20);
+// connection id of the commands the driver queues itself, connections are counted from 1
+const DRIVER_CONNECTION: usize = 0;
+// whether Windows drives our monitors at a variable refresh rate. No IddCx version in wdf-umdf-sys
+// has adaptive sync for indirect displays, so a vrr range only reaches the EDID
+const ADAPTIVE_SYNC: bool = false;
+
+// message processor
+//
+// `protocol` is the version agreed on for this connection, `None` until the first message, and
+// `pid` the process id of the client
+async fn process_message(
+    id: usize,
+    pid: u32,
+    server: &mut NamedPipeServer,
+    queue: &CommandQueue,
+    events_tx: &UnboundedSender<EventCommand>,
+    protocol: &mut Option<u32>,
+    decoder: &mut FrameDecoder,
+) -> Result<(), ()> {
+    // process each complete message in the buffer
+    loop {
+        let frame = match decoder.next_frame() {
+            Ok(Some(frame)) => {
+                IPC_MESSAGES.fetch_add(1, Ordering::Relaxed);
+                frame
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // the stream can't be resynchronized after a bad length
+                error!("Closing connection {id}: {e}");
+                return Err(());
+            }
+        };
+
+        // replies are written in the framing the client uses
+        let framing = decoder
+            .framing()
+            .expect("framing is known once a message was decoded");
+
+        // anyone can open the pipe, so this must handle garbage without panicking
+        let parsed = Envelope::<ServerCommand>::parse(&frame);
+
+        // a connection which doesn't start with hello is from a client older than the handshake
+        let is_hello = matches!(
+            parsed,
+            Ok(Envelope {
+                command: ServerCommand::Request(RequestCommand::Hello { .. }),
+                ..
+            })
+        );
+        if !is_hello && protocol.is_none() {
+            *protocol = Some(LEGACY_PROTOCOL_VERSION);
+            update_client(id, |client| client.protocol = *protocol);
+        }
+
+        // replies carry the id of the message they answer, if it had one
+        let Envelope {
+            id: request_id,
+            command,
+        } = match parsed {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                let prefix =
+                    String::from_utf8_lossy(&frame[..frame.len().min(MALFORMED_PREFIX_LEN)]);
+                debug!("Connection {id} sent a malformed message ({e}), starting with {prefix:?}");
+
+                // answered even without an id, so a client with a bug learns why nothing
+                // happens, but older clients don't know the reply
+                if protocol.is_some_and(|protocol| protocol >= PROTOCOL_VERSION) {
+                    let reply = ReplyCommand::Error {
+                        code: ErrorCode::ParseError,
+                        message: e.source.to_string(),
+                        source_id: e.id,
+                    };
+                    if send_reply(server, framing, e.id, &reply).await.is_err() {
+                        return Err(());
+                    }
+                }
+
+                // the frame is complete, so the next one can still be read
+                continue;
+            }
+        };
+
+        logging::ipc_command(id as u64, pid, command_name(&command));
+
+        // held until the command is answered, so the monitors don't depart while it runs. The
+        // handshake still works, so the client learns why its commands fail
+        let in_flight = LIFECYCLE.enter();
+        if in_flight.is_none() && !is_hello {
+            debug!("Connection {id} sent a command while the driver stops");
+
+            if let Some(request_id) = error_reply_id(*protocol, request_id) {
+                let reply = stopping().into_reply(request_id);
+                if send_reply(server, framing, Some(request_id), &reply)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            continue;
+        }
+
+        match command {
+            // driver commands
+            ServerCommand::Driver(cmd) => {
+                // the queue only goes away when the driver shuts down
+                let Some(result) = queue.run(id, cmd).await else {
+                    return Err(());
+                };
+
+                // older clients are answered like before error replies existed
+                let reply = match (error_reply_id(*protocol, request_id), result) {
+                    (Some(_), Ok(reply)) => Some(reply),
+                    (Some(request_id), Err(e)) => Some(e.into_reply(request_id)),
+                    (None, Err(CommandError::Invalid(errors))) => {
+                        Some(ReplyCommand::InvalidMonitors(errors))
+                    }
+                    (None, _) => None,
+                };
+
+                if let Some(reply) = reply {
+                    if send_reply(server, framing, request_id, &reply)
+                        .await
+                        .is_err()
+                    {
+                        return Err(());
+                    }
+                }
+            }
+
+            // request commands
+            ServerCommand::Request(RequestCommand::State) => {
+                let command = {
+                    let lock = MONITOR_MODES.lock().unwrap();
+                    let monitors = lock.iter().map(|m| m.data.clone()).collect();
+                    ReplyCommand::State(monitors)
+                };
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::Monitor { id: monitor }) => {
+                let command = {
+                    let lock = MONITOR_MODES.lock().unwrap();
+                    let monitor = lock.iter().find(|m| m.data.id == monitor);
+                    ReplyCommand::Monitor(monitor.map(|m| m.data.clone()))
+                };
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::QueryState) => {
+                let command = ReplyCommand::QueryState(query_state());
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::DriverInfo) => {
+                let command = ReplyCommand::DriverInfo(driver_info());
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::DriverStatus) => {
+                let command = ReplyCommand::DriverStatus(driver_status());
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::ListClients) => {
+                let command = ReplyCommand::Clients(list_clients(id));
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::QueryStats { id: monitor }) => {
+                let reply = match (error_reply_id(*protocol, request_id), query_stats(monitor)) {
+                    (_, Ok(stats)) => Some(ReplyCommand::Stats(stats)),
+                    (Some(request_id), Err(e)) => Some(e.into_reply(request_id)),
+                    (None, Err(_)) => None,
+                };
+
+                if let Some(reply) = reply {
+                    if send_reply(server, framing, request_id, &reply)
+                        .await
+                        .is_err()
+                    {
+                        return Err(());
+                    }
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::BootDefaults) => {
+                let reply = match (error_reply_id(*protocol, request_id), boot_defaults()) {
+                    (_, Ok(monitors)) => Some(ReplyCommand::BootDefaults(monitors)),
+                    (Some(request_id), Err(e)) => Some(e.into_reply(request_id)),
+                    (None, Err(_)) => None,
+                };
+
+                if let Some(reply) = reply {
+                    if send_reply(server, framing, request_id, &reply)
+                        .await
+                        .is_err()
+                    {
+                        return Err(());
+                    }
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::Logs {
+                min_level,
+                since_seq,
+            }) => {
+                let command = logs(min_level, since_seq);
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::IpcStats) => {
+                let command = ReplyCommand::IpcStats(IpcStats::new(
+                    IPC_MESSAGES.load(Ordering::Relaxed),
+                    IPC_ERRORS.load(Ordering::Relaxed),
+                ));
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::Validate(mut monitors)) => {
+                let errors = check_monitors(&mut monitors).err().unwrap_or_default();
+                let command = ReplyCommand::Validated(errors);
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::MaxMonitors) => {
+                let command = ReplyCommand::MaxMonitors(*MAX_MONITORS);
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::Capabilities) => {
+                // modes are checked with the limits of driver_ipc, only the monitor limit is configurable
+                let mut capabilities = Capabilities::with_max_monitors(*MAX_MONITORS);
+                capabilities.adaptive_sync = ADAPTIVE_SYNC;
+                let command = ReplyCommand::Capabilities(capabilities);
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::Subscribe) => {
+                // register before replying, so the client can't miss a change after seeing the reply
+                SUBSCRIBERS.lock().unwrap().insert(id, events_tx.clone());
+
+                if send_reply(server, framing, request_id, &ReplyCommand::Subscribed)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            ServerCommand::Request(RequestCommand::Hello {
+                protocol: client_protocol,
+                client_name,
+            }) => {
+                // a hello after the first message doesn't change the protocol anymore
+                let agreed = *protocol.get_or_insert(client_protocol.min(PROTOCOL_VERSION));
+                info!("Client {client_name} connected, using protocol version {agreed}");
+                update_client(id, |client| {
+                    client.name = Some(client_name);
+                    client.protocol = Some(agreed);
+                });
+
+                let command = ReplyCommand::HelloReply {
+                    protocol: agreed,
+                    driver_version: env!("CARGO_PKG_VERSION").to_owned(),
+                };
+
+                if send_reply(server, framing, request_id, &command)
+                    .await
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+
+            // Everything else is an invalid command
+            _ => {
+                if let Some(request_id) = error_reply_id(*protocol, request_id) {
+                    let error = CommandError::Failed(
+                        ErrorCode::InvalidCommand,
+                        "Unsupported command".into(),
+                    );
+                    if send_reply(
+                        server,
+                        framing,
+                        Some(request_id),
+                        &error.into_reply(request_id),
+                    )
+                    .await
+                    .is_err()
+                    {
+                        return Err(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Name of a message for the ETW event, without its payload
+fn command_name(command: &ServerCommand) -> &'static str {
+    match command {
+        ServerCommand::Driver(cmd) => driver_command_name(cmd),
+        ServerCommand::Request(RequestCommand::State) => "State",
+        ServerCommand::Request(RequestCommand::Monitor { .. }) => "Monitor",
+        ServerCommand::Request(RequestCommand::MaxMonitors) => "MaxMonitors",
+        ServerCommand::Request(RequestCommand::Validate(_)) => "Validate",
+        ServerCommand::Request(RequestCommand::Subscribe) => "Subscribe",
+        ServerCommand::Request(RequestCommand::Hello { .. }) => "Hello",
+        ServerCommand::Request(RequestCommand::Capabilities) => "Capabilities",
+        ServerCommand::Request(RequestCommand::QueryState) => "QueryState",
+        ServerCommand::Request(RequestCommand::QueryStats { .. }) => "QueryStats",
+        ServerCommand::Request(RequestCommand::DriverInfo) => "DriverInfo",
+        ServerCommand::Request(RequestCommand::DriverStatus) => "DriverStatus",
+        ServerCommand::Request(RequestCommand::ListClients) => "ListClients",
+        ServerCommand::Request(RequestCommand::BootDefaults) => "BootDefaults",
+        ServerCommand::Request(RequestCommand::Logs { .. }) => "Logs",
+        ServerCommand::Request(RequestCommand::IpcStats) => "IpcStats",
+        _ => "Unknown",
+    }
+}
+
+fn driver_command_name(cmd: &DriverCommand) -> &'static str {
+    match cmd {
+        DriverCommand::Notify(_) => "Notify",
+        DriverCommand::Remove(_) => "Remove",
+        DriverCommand::RemoveAll => "RemoveAll",
+        DriverCommand::PersistNow => "PersistNow",
+        // the persistence flag isn't interesting for tracing
+        DriverCommand::WithPersistence { command, .. } => driver_command_name(command),
+        DriverCommand::Apply { .. } => "Apply",
+        DriverCommand::SetPreferredAdapter(_) => "SetPreferredAdapter",
+        DriverCommand::SetEnabled { .. } => "SetEnabled",
+        DriverCommand::Reset => "Reset",
+        DriverCommand::ReserveId => "ReserveId",
+        DriverCommand::ReserveRequestedId { .. } => "ReserveRequestedId",
+        DriverCommand::SetUserSession { .. } => "SetUserSession",
+        DriverCommand::SetFrameExport { .. } => "SetFrameExport",
+        DriverCommand::SetBootDefaults { .. } => "SetBootDefaults",
+        DriverCommand::SetLogLevel(_) => "SetLogLevel",
+        DriverCommand::SetAdapterConfig(_) => "SetAdapterConfig",
+        DriverCommand::OverrideMode { .. } => "OverrideMode",
+        DriverCommand::RevertMode { .. } => "RevertMode",
+        DriverCommand::ReloadSettings => "ReloadSettings",
+        _ => "Unknown",
+    }
+}
+
+/// Process id of the client connected to `server`, 0 if it can't be queried
+fn client_process_id(server: &NamedPipeServer) -> u32 {
+    let mut pid = 0;
+    let handle = HANDLE(server.as_raw_handle());
+
+    if let Err(e) = unsafe { GetNamedPipeClientProcessId(handle, &mut pid) } {
+        debug!("Failed to get client process id: {e:?}");
+    }
+
+    pid
+}
+
+/// Id to send an error reply to, if the message had one and the client understands error replies
+fn error_reply_id(protocol: Option<u32>, request_id: Option<RequestId>) -> Option<RequestId> {
+    request_id.filter(|_| protocol.is_some_and(|protocol| protocol >= PROTOCOL_VERSION))
+}
+
+/// Why a driver command failed
+#[derive(Debug)]
+enum CommandError {
+    /// The monitors failed validation, nothing was applied
+    Invalid(Vec<MonitorError>),
+    /// The command was applied, but not everything succeeded
+    Failed(ErrorCode, String),
+}
+
+impl CommandError {
+    /// Collects the failures of one step into a single error
+    fn from_failures(code: ErrorCode, failures: Vec<String>) -> Result<(), Self> {
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Self::Failed(code, failures.join(", ")))
+        }
+    }
+
+    fn into_reply(self, source_id: RequestId) -> ReplyCommand {
+        let (code, message) = match self {
+            Self::Invalid(errors) => (
+                // the limit gets its own code, so clients can tell it from a list they got wrong
+                if errors
+                    .iter()
+                    .all(|e| matches!(e, MonitorError::TooManyMonitors(..)))
+                {
+                    ErrorCode::MonitorLimitReached
+                } else {
+                    ErrorCode::InvalidMonitors
+                },
+                errors
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            Self::Failed(code, message) => (code, message),
+        };
+
+        ReplyCommand::Error {
+            code,
+            message,
+            source_id: Some(source_id),
+        }
+    }
+}
+
+/// The device goes away, see [LIFECYCLE]
+fn stopping() -> CommandError {
+    CommandError::Failed(ErrorCode::DriverStopping, "Driver stopping".to_owned())
+}
+
+/// A driver command waiting in the `CommandQueue`, with where to send its result
+#[derive(Debug)]
+struct QueuedCommand {
+    id: usize,
+    command: DriverCommand,
+    reply_tx: oneshot::Sender<Result<ReplyCommand, CommandError>>,
+}
+
+/// Runs driver commands one at a time, in the order they were queued
+///
+/// Every connection queues its commands here, so commands of concurrent clients can't interleave
+/// their monitor arrivals and departures, and the state broadcast after each command is the one
+/// it applied
+#[derive(Debug, Clone)]
+struct CommandQueue(UnboundedSender<QueuedCommand>);
+
+impl CommandQueue {
+    /// Spawns the task running the commands with `run`
+    ///
+    /// The task ends once all clones of the queue are dropped
+    fn start(
+        mut run: impl FnMut(usize, DriverCommand) -> Result<ReplyCommand, CommandError> + Send + 'static,
+    ) -> Self {
+        let (queue_tx, mut queue_rx) = mpsc::unbounded_channel::<QueuedCommand>();
+
+        // IddCx calls block, so a running command occupies one runtime worker, like it did when
+        // connections ran them themselves
+        task::spawn(async move {
+            while let Some(QueuedCommand {
+                id,
+                command,
+                reply_tx,
+            }) = queue_rx.recv().await
+            {
+                // commands queued by the driver itself have no connection checking this
+                let result = match LIFECYCLE.enter() {
+                    Some(_in_flight) => run(id, command),
+                    None => Err(stopping()),
+                };
+                // the connection may have closed while waiting, the command was applied anyway
+                _ = reply_tx.send(result);
+            }
+        });
+
+        Self(queue_tx)
+    }
+
+    /// Queues a command of connection `id` and waits until it ran
+    ///
+    /// `None` if the queue stopped, which only happens when the driver shuts down
+    async fn run(
+        &self,
+        id: usize,
+        command: DriverCommand,
+    ) -> Option<Result<ReplyCommand, CommandError>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.0
+            .send(QueuedCommand {
+                id,
+                command,
+                reply_tx,
+            })
+            .ok()?;
+
+        reply_rx.await.ok()
+    }
+
+    /// Queues a command of connection `id` without waiting for it
+    fn queue(&self, id: usize, command: DriverCommand) {
+        // nobody waits for the result
+        let (reply_tx, _) = oneshot::channel();
+
+        // the queue only stops when the driver shuts down
+        _ = self.0.send(QueuedCommand {
+            id,
+            command,
+            reply_tx,
+        });
+    }
+}
+
+/// Removes the event subscription of a connection when its handler ends
+///
+/// Also runs when the handler is dropped on shutdown, so no sender of a closed pipe is left behind
+struct Subscription(usize);
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // a poisoned lock must not turn into a panic while unwinding
+        if let Ok(mut subscribers) = SUBSCRIBERS.lock() {
+            subscribers.remove(&self.0);
+        }
+    }
+}
+
+/// Lists a connection in [CLIENTS] until it is dropped, then releases its [RESERVATIONS]
+struct Registration(usize);
+
+impl Registration {
+    fn new(id: usize, pid: u32) -> Self {
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        let mut client = ClientInfo::new(id as u64, connected_at);
+        client.pid = Some(pid).filter(|&pid| pid != 0);
+
+        CLIENTS.lock().unwrap().insert(id, client);
+
+        Self(id)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // a poisoned lock must not turn into a panic while unwinding
+        if let Ok(mut clients) = CLIENTS.lock() {
+            clients.remove(&self.0);
+        }
+
+        // a monitor created with a reserved id keeps the id in use on its own
+        if let Ok(mut reservations) = RESERVATIONS.lock() {
+            reservations.retain(|_, owner| *owner != self.0);
+        }
+    }
+}
+
+fn update_client(id: usize, update: impl FnOnce(&mut ClientInfo)) {
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&id) {
+        update(client);
+    }
+}
+
+/// The open connections, as seen by connection `current`
+fn list_clients(current: usize) -> Vec<ClientInfo> {
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+
+    CLIENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, client)| {
+            let mut client = client.clone();
+            client.subscribed = subscribers.contains_key(id);
+            client.current = *id == current;
+            client
+        })
+        .collect()
+}
+
+/// Records of the driver's log from `since_seq` on, see [`driver_logger::ring::read`]
+///
+/// With messages of at most 2 KiB, a full reply stays well below the frame limit
+fn logs(min_level: LogLevel, since_seq: u64) -> ReplyCommand {
+    let (records, next_seq) =
+        driver_logger::ring::read(since_seq, min_level.into(), MAX_LOG_RECORDS);
+
+    let records = records
+        .into_iter()
+        .map(|r| LogRecord {
+            seq: r.seq,
+            level: r.level.into(),
+            timestamp: r.timestamp,
+            target: r.target,
+            message: r.message,
+        })
+        .collect();
+
+    ReplyCommand::Logs { records, next_seq }
+}
+
+/// Serialize and write a pushed event to the client
+///
+/// Serialization failures are logged and ignored, only pipe errors are returned
+async fn send_event(
+    server: &mut NamedPipeServer,
+    framing: Framing,
+    event: &EventCommand,
+) -> Result<(), ()> {
+    let data = match serde_json::to_vec(event).map(|data| framing.encode(data)) {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => {
+            error!("Command::Event - {e}");
+            return Ok(());
+        }
+        Err(_) => {
+            error!("Command::Event - failed to serialize event");
+            return Ok(());
+        }
+    };
+
+    server.write_all(&data).await.map_err(|_| ())
+}
+
+/// Pushes the changes between `old` and `new` to all subscribed connections, and ends the frame
+/// exports of removed monitors
+fn publish(old: &[Monitor], new: &[Monitor]) {
+    let events = monitor_events(old, new);
+    if events.is_empty() {
+        return;
+    }
+
+    for event in &events {
+        if let MonitorEvent::Removed(id) = event {
+            frame_export::disable(*id);
+        }
+    }
+
+    // a closed channel means the connection task already ended
+    SUBSCRIBERS.lock().unwrap().retain(|_, events_tx| {
+        events
+            .iter()
+            .all(|event| events_tx.send(event.clone().into()).is_ok())
+    });
+}
+
+/// Copies the monitor data out of the driver state
+fn snapshot(monitors: &[MonitorObject]) -> Vec<Monitor> {
+    monitors.iter().map(|m| m.data.clone()).collect()
+}
+
+/// Collects what the OS did with each monitor
+fn query_state() -> Vec<MonitorState> {
+    // monitors only depart while this is locked, so their contexts stay alive while reading them
+    let lock = MONITOR_MODES.lock().unwrap();
+
+    lock.iter()
+        .map(|monitor| {
+            let mut state = MonitorState::new(&monitor.data);
+
+            let Some(object) = monitor.object else {
+                return state;
+            };
+
+            state.connector_index = monitor.connector_index;
+
+            let result = unsafe {
+                MonitorContext::get(object.as_ptr().cast(), |context| {
+                    state.active_mode = context.committed_mode();
+                    state.render_adapter = context.render_adapter();
+                    state.swap_chain = state.render_adapter.is_some();
+                    state.frames = context.frames();
+                    state.os_target = context.os_target();
+                })
+            };
+
+            if let Err(e) = result {
+                error!("Failed to read state of monitor {}: {e:?}", monitor.data.id);
+            }
+
+            state
+        })
+        .collect()
+}
+
+/// Build of this driver, and the IddCx and WDF versions it runs on
+fn driver_info() -> DriverInfo {
+    let mut version = IDARG_OUT_GETVERSION::default();
+    // failures are traced by the wrapper, the version is only informational
+    let iddcx_version = unsafe { IddCxGetVersion(&mut version) }
+        .ok()
+        .map(|_| version.IddCxVersion);
+
+    DriverInfo::new(
+        env!("CARGO_PKG_VERSION"),
+        env!("VERGEN_GIT_SHA"),
+        env!("VERGEN_BUILD_DATE"),
+        env!("VDD_BUILD_PROFILE"),
+        iddcx_version,
+        wdf_version(),
+    )
+}
+
+/// Version string of the WDF runtime, `None` if it couldn't be retrieved
+fn wdf_version() -> Option<String> {
+    let mut string = std::ptr::null_mut();
+    // failures are traced by the wrappers
+    unsafe { WdfStringCreate(None, None, &mut string) }.ok()?;
+
+    // SAFETY: the driver is created before the server is started
+    let driver = unsafe { WdfGetDriver() };
+
+    let version = unsafe { WdfDriverRetrieveVersionString(driver, string) }
+        .ok()
+        .and_then(|_| {
+            let mut unicode = UNICODE_STRING::default();
+            unsafe { WdfStringGetUnicodeString(string, &mut unicode) }.ok()?;
+
+            if unicode.Buffer.is_null() {
+                return None;
+            }
+
+            // SAFETY: the buffer is owned by the string and holds `Length` bytes
+            let buffer = unsafe {
+                std::slice::from_raw_parts(unicode.Buffer, usize::from(unicode.Length) / 2)
+            };
+
+            Some(String::from_utf16_lossy(buffer))
+        });
+
+    _ = unsafe { WdfObjectDelete(string.cast()) };
+
+    version
+}
+
+/// Frame counters of a monitor, `None` while Windows has no swap chain attached to it
+fn query_stats(id: Id) -> Result<Option<Stats>, CommandError> {
+    // monitors only depart while this is locked, so the context stays alive while reading it
+    let lock = MONITOR_MODES.lock().unwrap();
+
+    let Some(monitor) = lock.iter().find(|monitor| monitor.data.id == id) else {
+        return Err(CommandError::Failed(
+            ErrorCode::MonitorNotFound,
+            format!("Monitor {id} not found"),
+        ));
+    };
+
+    let Some(object) = monitor.object else {
+        return Ok(None);
+    };
+
+    let mut stats = None;
+    let result = unsafe {
+        MonitorContext::get(object.as_ptr().cast(), |context| {
+            stats = context.stats(id);
+        })
+    };
+
+    if let Err(e) = result {
+        error!("Failed to read stats of monitor {id}: {e:?}");
+    }
+
+    Ok(stats)
+}
+
+/// Runs a driver command and broadcasts the resulting state to the other clients
+///
+/// Returns the reply for a tagged command, or why the command was rejected or which parts of it
+/// failed
+fn run_command(
+    id: usize,
+    tx: &Sender<(usize, Vec<Monitor>)>,
+    cmd: DriverCommand,
+) -> Result<ReplyCommand, CommandError> {
+    // the pipe server also runs when initializing failed, tell clients why nothing works
+    if ADAPTER.get().is_none() {
+        return Err(CommandError::Failed(
+            ErrorCode::IddCx,
+            driver_status().to_string(),
+        ));
+    }
+
+    // static connectors keep their monitors for as long as the driver runs
+    if matches!(*ADAPTER_CONFIG, AdapterConfig::Static { .. }) && changes_monitors(&cmd) {
+        return Err(CommandError::Failed(
+            ErrorCode::InvalidCommand,
+            "The adapter has static connectors, its monitors can't be changed".into(),
+        ));
+    }
+
+    match cmd {
+        DriverCommand::Notify(mut monitors) => {
+            if let Err(errors) = check_monitors(&mut monitors) {
+                for e in &errors {
+                    warn!("notify(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            check_reservations(id, &monitors)?;
+
+            let result = notify(monitors.clone());
+            _ = tx.send((id, monitors));
+            result.map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::Apply { monitors, mode } => {
+            let current = snapshot(&MONITOR_MODES.lock().unwrap());
+
+            let mut monitors = mode.resolve(&current, monitors);
+            if let Err(errors) = check_monitors(&mut monitors) {
+                for e in &errors {
+                    warn!("apply(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            check_reservations(id, &monitors)?;
+
+            // failures are reported per monitor instead of failing the whole command
+            let failed = apply_state(monitors.clone());
+            let mut report = ApplyReport::new(&current, &monitors, failed);
+            if !ADAPTIVE_SYNC {
+                report.warnings = dropped_adaptive_sync(&current, &monitors, &report);
+            }
+
+            _ = tx.send((id, monitors));
+            Ok(ReplyCommand::Applied(report))
+        }
+
+        DriverCommand::SetEnabled {
+            id: monitor_id,
+            enabled,
+        } => {
+            let mut monitors = snapshot(&MONITOR_MODES.lock().unwrap());
+
+            let Some(monitor) = monitors.iter_mut().find(|m| m.id == monitor_id) else {
+                return Err(CommandError::Failed(
+                    ErrorCode::MonitorNotFound,
+                    format!("Monitor {monitor_id} not found"),
+                ));
+            };
+
+            if monitor.enabled == enabled {
+                return Ok(ReplyCommand::Done);
+            }
+
+            // the monitor stays in the state either way, `notify` only departs or arrives it
+            monitor.enabled = enabled;
+            if let Err(errors) = check_monitors(&mut monitors) {
+                for e in &errors {
+                    warn!("set_enabled(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            let result = notify(monitors.clone());
+            _ = tx.send((id, monitors));
+            result.map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::Remove(ids) => {
+            let result = remove(&ids);
+
+            let lock = MONITOR_MODES.lock().unwrap();
+            let monitors = lock.iter().map(|m| m.data.clone()).collect();
+            _ = tx.send((id, monitors));
+            result.map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::RemoveAll => {
+            let result = remove_all();
+            _ = tx.send((id, Vec::new()));
+            result.map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::SetPreferredAdapter(preference) => render_adapter::set(preference)
+            .map(|()| ReplyCommand::Done)
+            .map_err(|e| {
+                error!("Failed to set the render adapter: {e:?}");
+                CommandError::Failed(
+                    ErrorCode::IddCx,
+                    format!("Failed to set the render adapter: {e}"),
+                )
+            }),
+
+        DriverCommand::PersistNow => save().map(|()| ReplyCommand::Done),
+
+        DriverCommand::SetUserSession { active } => {
+            if USER_SESSION.swap(active, Ordering::Relaxed) == active {
+                return Ok(ReplyCommand::Done);
+            }
+
+            info!("User session {}", if active { "started" } else { "ended" });
+
+            // the state stays the same, only which of its monitors are attached changes
+            let monitors = snapshot(&MONITOR_MODES.lock().unwrap());
+            notify(monitors).map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::ReserveId => {
+            // commands run one at a time, so no monitor can appear between looking and reserving
+            let lock = MONITOR_MODES.lock().unwrap();
+            let mut reservations = RESERVATIONS.lock().unwrap();
+
+            let reserved = (0..)
+                .find(|monitor_id| {
+                    !reservations.contains_key(monitor_id)
+                        && !lock.iter().any(|m| m.data.id == *monitor_id)
+                })
+                .expect("every monitor id is taken");
+
+            reservations.insert(reserved, id);
+            Ok(ReplyCommand::ReservedId(reserved))
+        }
+
+        DriverCommand::ReserveRequestedId { id: requested } => {
+            let lock = MONITOR_MODES.lock().unwrap();
+            let mut reservations = RESERVATIONS.lock().unwrap();
+
+            if lock.iter().any(|m| m.data.id == requested) {
+                return Err(CommandError::Failed(
+                    ErrorCode::IdInUse,
+                    format!("Monitor {requested} already exists"),
+                ));
+            }
+
+            if reservations
+                .get(&requested)
+                .is_some_and(|&owner| owner != id)
+            {
+                return Err(CommandError::Failed(
+                    ErrorCode::IdReserved,
+                    format!("Monitor ID {requested} is reserved by another client"),
+                ));
+            }
+
+            reservations.insert(requested, id);
+            Ok(ReplyCommand::ReservedId(requested))
+        }
+
+        DriverCommand::SetFrameExport {
+            id: monitor_id,
+            enabled,
+        } => {
+            if !enabled {
+                frame_export::disable(monitor_id);
+                return Ok(ReplyCommand::FrameExport(None));
+            }
+
+            // commands run one at a time, so the monitor can't go away before the export exists
+            if !MONITOR_MODES
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|m| m.data.id == monitor_id)
+            {
+                return Err(CommandError::Failed(
+                    ErrorCode::MonitorNotFound,
+                    format!("Monitor {monitor_id} not found"),
+                ));
+            }
+
+            frame_export::enable(monitor_id)
+                .map(|name| ReplyCommand::FrameExport(Some(name)))
+                .map_err(|e| {
+                    error!("Failed to export frames of monitor {monitor_id}: {e:?}");
+                    CommandError::Failed(
+                        ErrorCode::IddCx,
+                        format!("Failed to export frames of monitor {monitor_id}: {e}"),
+                    )
+                })
+        }
+
+        DriverCommand::SetBootDefaults { mut monitors } => {
+            if let Err(errors) = check_monitors(&mut monitors) {
+                for e in &errors {
+                    warn!("set_boot_defaults(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            // only read on the next start, the current monitors stay
+            save_boot_defaults(&monitors).map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::SetLogLevel(level) => {
+            driver_logger::set_level(log::Level::from(level).to_level_filter());
+            info!("Log level set to {level}");
+
+            Ok(ReplyCommand::Done)
+        }
+
+        // the same as when the settings key changes, see `settings::watch`
+        DriverCommand::ReloadSettings => Ok(ReplyCommand::SettingsReloaded(settings::reload())),
+
+        DriverCommand::SetAdapterConfig(config) => {
+            if let AdapterConfig::Static { connectors, .. } = config {
+                let max_monitors = *MAX_MONITORS;
+                if connectors == 0 || connectors > max_monitors {
+                    return Err(CommandError::Failed(
+                        ErrorCode::InvalidCommand,
+                        format!("Static connectors must be between 1 and {max_monitors}"),
+                    ));
+                }
+            }
+
+            if let Err(errors) = static_monitors(&config) {
+                for e in &errors {
+                    warn!("set_adapter_config(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            // only read on the next start, the current monitors stay
+            save_adapter_config(&config).map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::OverrideMode {
+            id: monitor_id,
+            mode,
+            ttl_ms,
+        } => {
+            let ttl = Duration::from_millis(ttl_ms);
+            let Some(until) = Instant::now().checked_add(ttl).filter(|_| ttl_ms > 0) else {
+                return Err(CommandError::Failed(
+                    ErrorCode::InvalidCommand,
+                    format!("The override can't last {ttl_ms} ms"),
+                ));
+            };
+
+            let mut monitors = snapshot(&MONITOR_MODES.lock().unwrap());
+
+            let Some(monitor) = monitors.iter_mut().find(|m| m.id == monitor_id) else {
+                return Err(CommandError::Failed(
+                    ErrorCode::MonitorNotFound,
+                    format!("Monitor {monitor_id} not found"),
+                ));
+            };
+
+            // a renewed override keeps the modes from before the first one
+            let saved = match OVERRIDES.lock().unwrap().get(&monitor_id) {
+                Some(renewed) if renewed.overridden == monitor.modes => renewed.saved.clone(),
+                _ => monitor.modes.clone(),
+            };
+            monitor.modes = vec![mode];
+
+            if let Err(errors) = check_monitors(&mut monitors) {
+                for e in &errors {
+                    warn!("override_mode(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            let overridden = monitors
+                .iter()
+                .find(|m| m.id == monitor_id)
+                .map(|m| m.modes.clone())
+                .unwrap_or_default();
+
+            // started before applying, so the monitor isn't stuck in the mode if it fails. The
+            // revert it queues runs after this command either way
+            override_timer(monitor_id)?.start(ttl).map_err(|e| {
+                error!("Failed to start the override timer of monitor {monitor_id}: {e:?}");
+                CommandError::Failed(
+                    ErrorCode::IddCx,
+                    format!("Failed to start the override timer: {e}"),
+                )
+            })?;
+
+            OVERRIDES.lock().unwrap().insert(
+                monitor_id,
+                ModeOverride {
+                    saved,
+                    overridden,
+                    until,
+                },
+            );
+
+            let result = notify(monitors.clone());
+            _ = tx.send((id, monitors));
+            result.map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::RevertMode { id: monitor_id } => {
+            let Some(ended) = OVERRIDES.lock().unwrap().remove(&monitor_id) else {
+                return Ok(ReplyCommand::Done);
+            };
+
+            let timer = OVERRIDE_TIMERS.lock().unwrap().get(&monitor_id).copied();
+            if let Some(timer) = timer {
+                // the revert queued by the timer may be older than a renewal, then it's started
+                // again for the rest of the ttl
+                let left = ended.until.saturating_duration_since(Instant::now());
+                if id == DRIVER_CONNECTION && !left.is_zero() && timer.start(left).is_ok() {
+                    OVERRIDES.lock().unwrap().insert(monitor_id, ended);
+                    return Ok(ReplyCommand::Done);
+                }
+
+                // a revert it queues anyway finds nothing to revert
+                _ = timer.stop(false);
+            }
+
+            let mut monitors = snapshot(&MONITOR_MODES.lock().unwrap());
+
+            // a command changed the modes since, they are kept
+            let Some(monitor) = monitors
+                .iter_mut()
+                .find(|m| m.id == monitor_id && m.modes == ended.overridden)
+            else {
+                return Ok(ReplyCommand::Done);
+            };
+
+            monitor.modes = ended.saved;
+            info!("Reverted the mode override of monitor {monitor_id}");
+
+            // the saved modes were fine before, but the other monitors may have changed since
+            if let Err(errors) = check_monitors(&mut monitors) {
+                for e in &errors {
+                    warn!("revert_mode(): {e}");
+                }
+
+                return Err(CommandError::Invalid(errors));
+            }
+
+            let result = notify(monitors.clone());
+            _ = tx.send((id, monitors));
+            result.map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::Reset => {
+            // nothing is removed unless the saved monitors are gone too
+            clear_saved()?;
+
+            let result = remove_all();
+            _ = tx.send((id, Vec::new()));
+
+            let adapter = render_adapter::reset().map_err(|e| {
+                error!("Failed to reset the render adapter: {e:?}");
+                CommandError::Failed(
+                    ErrorCode::IddCx,
+                    format!("Failed to reset the render adapter: {e}"),
+                )
+            });
+
+            result.and(adapter).map(|()| ReplyCommand::Done)
+        }
+
+        DriverCommand::WithPersistence {
+            command,
+            persistence,
+        } => {
+            let result = run_command(id, tx, *command);
+
+            // a partially failed command still changed the state, so it is saved anyway
+            if persistence && !matches!(result, Err(CommandError::Invalid(_))) {
+                let saved = save();
+                return result.and_then(|reply| saved.map(|()| reply));
+            }
+
+            result
+        }
+
+        _ => Err(CommandError::Failed(
+            ErrorCode::InvalidCommand,
+            "Unsupported driver command".into(),
+        )),
+    }
+}
+
+/// Serialize and write a reply to the client, tagged with the id of the request it answers
+///
+/// Serialization failures are logged and ignored, only pipe errors are returned
+async fn send_reply(
+    server: &mut NamedPipeServer,
+    framing: Framing,
+    request_id: Option<RequestId>,
+    command: &ReplyCommand,
+) -> Result<(), ()> {
+    if matches!(
+        command,
+        ReplyCommand::Error { .. } | ReplyCommand::InvalidMonitors(_)
+    ) {
+        IPC_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a reply which can't be sent leaves the client waiting for it, so the
+    // connection is dropped instead. The client then fails all of its pending
+    // requests rather than running into their timeouts
+    let data = serde_json::to_vec(&Envelope::new(request_id, command));
+    let data = match data.map(|data| framing.encode(data)) {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => {
+            error!("Command::Request - {e}");
+            return Err(());
+        }
+        Err(e) => {
+            error!("Command::Request - failed to serialize reply: {e}");
+            return Err(());
+        }
+    };
+
+    // a server error means we should completely stop trying
+    server.write_all(&data).await.map_err(|_| ())
+}
+
+/// Restores the monitors saved by the last persisting command, or the boot defaults if none were
+/// saved
+///
+/// Must be called after the adapter was stored in `ADAPTER`
+pub fn restore() {
+    // the saved monitors stay untouched, they are restored once the adapter is dynamic again
+    if matches!(*ADAPTER_CONFIG, AdapterConfig::Static { .. }) {
+        match static_monitors(&ADAPTER_CONFIG) {
+            Ok(monitors) => _ = notify(monitors),
+            Err(errors) => {
+                for e in &errors {
+                    warn!("restore(): {e}");
+                }
+            }
+        }
+
+        return;
+    }
+
+    let (root, key) = saved_monitors_key();
+    // lists saved by older driver versions are written back in the current format
+    let Loaded {
+        mut monitors,
+        source,
+        ..
+    } = persist::load_boot(&root, key);
+
+    info!("Loaded {} monitor(s) from {source}", monitors.len());
+
+    if let Err(errors) = check_monitors(&mut monitors) {
+        for e in &errors {
+            warn!("restore(): {e}");
+        }
+
+        return;
+    }
+
+    // nobody to reply to, the failures were already logged
+    _ = notify(monitors);
+}
+
+/// The device leaves D0 for a sleep state, frames aren't processed until [resume_monitors]
+pub fn suspend_monitors() {
+    // monitors only depart while this is locked, so their contexts stay alive
+    for object in MONITOR_MODES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|m| m.object)
+    {
+        let result = unsafe { MonitorContext::get_mut(object.as_ptr().cast(), |c| c.suspend()) };
+        if let Err(e) = result {
+            error!("Failed to suspend monitor: {e:?}");
+        }
+    }
+
+    info!("Suspended frame processing");
+    broadcast(&EventCommand::Suspended);
+}
+
+/// The device is back in D0, frames are processed again
+///
+/// Returns the monitors which don't process frames, either without a swap chain or because it was
+/// lost, e.g. with its render adapter. The OS assigns a new one then
+pub fn resume_monitors() -> Vec<Id> {
+    let mut stopped = Vec::new();
+
+    for object in MONITOR_MODES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|m| m.object)
+    {
+        let result = unsafe {
+            MonitorContext::get_mut(object.as_ptr().cast(), |context| {
+                if !context.resume() {
+                    stopped.push(context.id());
+                }
+            })
+        };
+
+        if let Err(e) = result {
+            error!("Failed to resume monitor: {e:?}");
+        }
+    }
 
-use driver_ipc::{
-    Dimen, DriverCommand, EventCommand, Mode, Monitor, RefreshRate, ReplyCommand, RequestCommand,
-    ServerCommand,
-};
-use log::{error, warn};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt as _},
-    net::windows::named_pipe::{NamedPipeServer, ServerOptions},
-    sync::broadcast::{self, error::RecvError, Sender},
-    task,
-};
-use wdf_umdf::IddCxMonitorDeparture;
-use wdf_umdf_sys::{IDDCX_ADAPTER__, IDDCX_MONITOR__};
-use windows::Win32::{
-    Security::{
-        InitializeSecurityDescriptor, SetSecurityDescriptorDacl, PSECURITY_DESCRIPTOR,
-        SECURITY_ATTRIBUTES, SECURITY_DESCRIPTOR,
-    },
-    System::SystemServices::SECURITY_DESCRIPTOR_REVISION1,
-};
+    info!("Resumed frame processing");
+    broadcast(&EventCommand::Resumed);
 
-use crate::context::DeviceContext;
+    stopped
+}
 
-pub static ADAPTER: OnceLock<AdapterObject> = OnceLock::new();
-pub static MONITOR_MODES: LazyLock<Mutex<Vec<MonitorObject>>> =
-    LazyLock::new(|| Mutex::new(Vec::new()));
+/// Sends `event` to all subscribed connections
+fn broadcast(event: &EventCommand) {
+    // a closed channel means the connection task already ended
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|_, events_tx| events_tx.send(event.clone()).is_ok());
+}
 
-#[derive(Debug)]
-pub struct AdapterObject(pub NonNull<IDDCX_ADAPTER__>);
-unsafe impl Sync for AdapterObject {}
-unsafe impl Send for AdapterObject {}
+/// Saves the current monitor state, so it's restored on the next driver start
+fn save() -> Result<(), CommandError> {
+    let monitors = {
+        let lock = MONITOR_MODES.lock().unwrap();
+        let overrides = OVERRIDES.lock().unwrap();
 
-#[derive(Debug)]
-pub struct MonitorObject {
-    pub object: Option<NonNull<IDDCX_MONITOR__>>,
-    pub data: Monitor,
+        lock.iter()
+            .map(|m| {
+                let mut monitor = m.data.clone();
+
+                // overrides are temporary, the modes they revert to are saved
+                if let Some(overridden) = overrides
+                    .get(&monitor.id)
+                    .filter(|o| o.overridden == monitor.modes)
+                {
+                    monitor.modes.clone_from(&overridden.saved);
+                }
+
+                monitor
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let (root, key) = saved_monitors_key();
+    match persist::save(&root, key, &monitors) {
+        Ok(()) => {
+            info!("Saved {} monitor(s)", monitors.len());
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to save monitors: {e}");
+            Err(CommandError::Failed(
+                ErrorCode::Registry,
+                format!("Failed to save monitors: {e}"),
+            ))
+        }
+    }
 }
-unsafe impl Sync for MonitorObject {}
-unsafe impl Send for MonitorObject {}
 
-const BUFFER_SIZE: u32 = 4096;
-// EOT
-const EOF: char = '\x04';
+/// Stores the monitors the next driver start begins with if none are saved, an empty list removes
+/// them
+fn save_boot_defaults(monitors: &[Monitor]) -> Result<(), CommandError> {
+    let (root, key) = saved_monitors_key();
+    persist::save_defaults(&root, key, monitors).map_err(|e| {
+        error!("Failed to save boot defaults: {e}");
+        CommandError::Failed(
+            ErrorCode::Registry,
+            format!("Failed to save boot defaults: {e}"),
+        )
+    })?;
+
+    info!("Saved {} boot default monitor(s)", monitors.len());
+    Ok(())
+}
 
-// message processor
-async fn process_message(
-    id: usize,
-    server: &mut NamedPipeServer,
-    tx: &Sender<(usize, Vec<Monitor>)>,
-    buf: &[u8],
-    iter: impl Iterator<Item = usize>,
-) -> Result<(), ()> {
-    // process each message in the buffer
-    let mut start = 0;
-    for eidx in iter {
-        let sidx = start;
-        start = eidx + 1;
+/// The boot defaults, empty if there are none
+fn boot_defaults() -> Result<Vec<Monitor>, CommandError> {
+    let (root, key) = saved_monitors_key();
+    persist::load_defaults(&root, key).map_err(|e| {
+        error!("Failed to load boot defaults: {e}");
+        CommandError::Failed(
+            ErrorCode::Registry,
+            format!("Failed to load boot defaults: {e}"),
+        )
+    })
+}
 
-        let Ok(msg) = std::str::from_utf8(&buf[sidx..eidx]) else {
-            continue;
-        };
+/// Stores the adapter config the next driver start uses, see `ADAPTER_CONFIG`
+fn save_adapter_config(config: &AdapterConfig) -> Result<(), CommandError> {
+    fn delete(key: &RegKey, name: &str) -> std::io::Result<()> {
+        match key.delete_value(name) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
 
-        let Ok(command) = serde_json::from_str::<ServerCommand>(msg) else {
-            continue;
-        };
+    let (root, key) = saved_monitors_key();
+    let result = root.create_subkey(key).and_then(|(key, _)| match config {
+        AdapterConfig::Dynamic => {
+            delete(&key, STATIC_CONNECTORS_VALUE)?;
+            delete(&key, STATIC_MODES_VALUE)
+        }
 
-        match command {
-            // driver commands
-            ServerCommand::Driver(cmd) => match cmd {
-                DriverCommand::Notify(monitors) => {
-                    notify(monitors.clone());
-                    _ = tx.send((id, monitors));
-                }
+        AdapterConfig::Static { connectors, modes } => {
+            key.set_value(STATIC_CONNECTORS_VALUE, connectors)?;
+            if modes.is_empty() {
+                delete(&key, STATIC_MODES_VALUE)
+            } else {
+                key.set_value(STATIC_MODES_VALUE, &serde_json::to_string(modes)?)
+            }
+        }
+    });
 
-                DriverCommand::Remove(ids) => {
-                    remove(&ids);
+    result.map_err(|e| {
+        error!("Failed to save adapter config: {e}");
+        CommandError::Failed(
+            ErrorCode::Registry,
+            format!("Failed to save adapter config: {e}"),
+        )
+    })?;
 
-                    let lock = MONITOR_MODES.lock().unwrap();
-                    let monitors = lock.iter().map(|m| m.data.clone()).collect();
-                    _ = tx.send((id, monitors));
-                }
+    info!("Saved adapter config {config:?}");
+    Ok(())
+}
 
-                DriverCommand::RemoveAll => {
-                    remove_all();
-                    _ = tx.send((id, Vec::new()));
-                }
+/// Deletes the saved monitors, so the next driver start begins without any, or with the boot
+/// defaults
+fn clear_saved() -> Result<(), CommandError> {
+    let (root, key) = saved_monitors_key();
+    persist::clear(&root, key).map_err(|e| {
+        error!("Failed to delete saved monitors: {e}");
+        CommandError::Failed(
+            ErrorCode::Registry,
+            format!("Failed to delete saved monitors: {e}"),
+        )
+    })?;
+
+    info!("Deleted saved monitors");
+    Ok(())
+}
 
-                _ => (),
-            },
+// Key the monitors are saved under
+#[cfg(not(test))]
+pub fn saved_monitors_key() -> (RegKey, &'static str) {
+    (
+        RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE),
+        SETTINGS_KEY,
+    )
+}
 
-            // request commands
-            ServerCommand::Request(RequestCommand::State) => {
-                let mut data = {
-                    let lock = MONITOR_MODES.lock().unwrap();
-                    let monitors = lock.iter().map(|m| m.data.clone()).collect();
-                    let command = ReplyCommand::State(monitors);
+// The tests save their monitors under the current user, so they neither need administrator rights
+// nor touch the monitors of an installed driver
+#[cfg(test)]
+pub fn saved_monitors_key() -> (RegKey, &'static str) {
+    (
+        RegKey::predef(winreg::enums::HKEY_CURRENT_USER),
+        r"SOFTWARE\VirtualDisplayDriver\Test",
+    )
+}
 
-                    let Ok(serialized) = serde_json::to_string(&command) else {
-                        error!("Command::Request - failed to serialize reply");
-                        break;
-                    };
+// Converts an SDDL string into a security descriptor
+//
+// The descriptor is allocated by the system and never freed, the pipe server uses it for as long as
+// the driver runs
+fn security_descriptor(sddl: &str) -> windows::core::Result<PSECURITY_DESCRIPTOR> {
+    let mut sd = PSECURITY_DESCRIPTOR::default();
 
-                    serialized
-                };
+    unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &HSTRING::from(sddl),
+            SDDL_REVISION_1,
+            &mut sd,
+            None,
+        )?;
+    }
 
-                data.push(EOF);
+    Ok(sd)
+}
 
-                if server.write_all(data.as_bytes()).await.is_err() {
-                    // a server error means we should completely stop trying
-                    return Err(());
-                }
-            }
+// Security descriptor for the pipe according to the `PipeAccess` setting, also used for the frame
+// exports
+//
+// A broken custom SDDL must not open the pipe up, so it falls back to administrators only
+pub fn pipe_security_descriptor() -> PSECURITY_DESCRIPTOR {
+    let sddl = PIPE_ACCESS.sddl();
 
-            // Everything else is an invalid command
-            _ => (),
+    match security_descriptor(sddl) {
+        Ok(sd) => sd,
+
+        Err(e) => {
+            error!("Invalid PipeAccess SDDL {sddl:?}: {e}, restricting the pipe to administrators");
+            security_descriptor(PipeAccess::Administrators.sddl()).unwrap()
         }
     }
+}
 
-    Ok(())
+/// Records that IddCx is initialized and monitors can be added
+pub fn init_succeeded() {
+    *DRIVER_STATUS.lock().unwrap_or_else(PoisonError::into_inner) = DriverStatus::Ready;
+    LIFECYCLE.start();
 }
 
-#[allow(clippy::too_many_lines)]
-pub fn startup() {
-    thread::spawn(move || {
-        // These security attributes will allow anyone access, so local account does not need admin privileges to use it
+/// Records that initializing failed at `step`
+///
+/// Starts the pipe server regardless, so clients can ask why with `RequestCommand::DriverStatus`
+pub fn init_failed(step: InitStep, status: NTSTATUS) {
+    let failure = InitFailure::new(step, status.0);
+    error!("{failure}");
 
-        let mut sd = SECURITY_DESCRIPTOR::default();
+    *DRIVER_STATUS.lock().unwrap_or_else(PoisonError::into_inner) = DriverStatus::Failed(failure);
 
-        unsafe {
-            InitializeSecurityDescriptor(
-                PSECURITY_DESCRIPTOR(addr_of_mut!(sd).cast()),
-                SECURITY_DESCRIPTOR_REVISION1,
-            )
-            .unwrap();
-        }
+    startup();
+}
 
-        unsafe {
-            SetSecurityDescriptorDacl(
-                PSECURITY_DESCRIPTOR(addr_of_mut!(sd).cast()),
-                true,
-                None,
-                false,
-            )
-            .unwrap();
-        }
+fn driver_status() -> DriverStatus {
+    DRIVER_STATUS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Starts the pipe server, which runs until [SHUTDOWN] is signaled
+///
+/// Every client gets its own pipe instance and handler, up to [MAX_PIPE_CLIENTS] at the same time.
+/// Only the first call starts it, initializing may fail after it already runs
+pub fn startup() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| start_pipe_server(driver_ipc::DEFAULT_PIPE_NAME));
+}
 
+/// Starts the pipe server on `\\.\pipe\<pipe_name>`, see [startup]
+pub fn start_pipe_server(pipe_name: &'static str) {
+    let pipe_path = format!(r"\\.\pipe\{pipe_name}");
+
+    let thread = thread::spawn(move || {
         let mut sa = SECURITY_ATTRIBUTES {
             #[allow(clippy::cast_possible_truncation)]
             nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
-            lpSecurityDescriptor: addr_of_mut!(sd).cast(),
+            lpSecurityDescriptor: pipe_security_descriptor().0,
             bInheritHandle: false.into(),
         };
 
@@ -162,9 +1728,25 @@ pub fn startup() {
         let pipe_server = async {
             let (tx, _rx) = broadcast::channel(1);
 
+            // every state change goes through here, see `CommandQueue`
+            let queue = {
+                let tx = tx.clone();
+                CommandQueue::start(move |id, cmd| run_command(id, &tx, cmd))
+            };
+            _ = QUEUE.set(queue.clone());
+
+            let max_clients = *MAX_PIPE_CLIENTS as usize;
+            // one permit per pipe instance, including the one waiting for the next client
+            let instances = Arc::new(Semaphore::new(max_clients));
+
             let mut id = 0usize;
 
             loop {
+                // the semaphore is never closed
+                let Ok(permit) = instances.clone().acquire_owned().await else {
+                    break;
+                };
+
                 let mut server = unsafe {
                     ServerOptions::new()
                         .access_inbound(true)
@@ -172,9 +1754,11 @@ pub fn startup() {
                         .reject_remote_clients(true)
                         .in_buffer_size(BUFFER_SIZE)
                         .out_buffer_size(BUFFER_SIZE)
-                        // default is unlimited instances
+                        // while all instances are connected, further clients get ERROR_PIPE_BUSY
+                        // and can wait for one to close
+                        .max_instances(max_clients)
                         .create_with_security_attributes_raw(
-                            r"\\.\pipe\virtualdisplaydriver",
+                            &pipe_path,
                             std::ptr::from_mut::<SECURITY_ATTRIBUTES>(&mut sa).cast(),
                         )
                         .unwrap()
@@ -185,126 +1769,259 @@ pub fn startup() {
                 }
 
                 id += 1;
-
-                let mut msg_buf: Vec<u8> = Vec::with_capacity(BUFFER_SIZE as usize);
-                let mut buf = vec![0; BUFFER_SIZE as usize];
-                let tx = tx.clone();
-                let mut rx = tx.subscribe();
-
-                task::spawn(async move {
-                    loop {
-                        tokio::select! {
-                            val = server.read(&mut buf) =>  {
-                                match val {
-                                    // 0 = no more data to read
-                                    // or break on err
-                                    Ok(0) | Err(_) => break,
-
-                                    Ok(size) => msg_buf.extend(&buf[..size]),
-                                }
-
-                                // get all eof boundary positions
-                                let eof_iter = msg_buf.iter().enumerate().filter_map(|(i, &byte)| {
-                                    if byte == EOF as u8 {
-                                        Some(i)
-                                    } else {
-                                        None
-                                    }
-                                });
-
-                                if process_message(id, &mut server, &tx, &msg_buf, eof_iter.clone()).await.is_err() {
-                                    break;
-                                }
-
-                                // remove processed messages from buffer
-                                // we can exploit the fact that these are sequential
-                                // so just get the last index and chop off everything before that
-                                if let Some(last) = eof_iter.last() {
-                                    // remove everything up to and including the last EOF
-                                    msg_buf.drain(..=last);
-                                }
-                            },
-
-                            val = rx.recv() => {
-                                let command = match val {
-                                    // ignore if this value was sent for the current client (current client doesn't need notification)
-                                    Ok((client_id, _)) if client_id == id => continue,
-
-                                    Ok((_, data)) => EventCommand::Changed(data),
-
-                                    Err(RecvError::Lagged(_)) => continue,
-
-                                    // closed
-                                    Err(_) => break
-                                };
-
-                                let Ok(mut serialized) = serde_json::to_string(&command) else {
-                                    error!("Command::Request - failed to serialize reply");
-                                    break;
-                                };
-
-                                serialized.push(EOF);
-
-                                if server.write_all(serialized.as_bytes()).await.is_err() {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                });
+                task::spawn(handle_connection(
+                    id,
+                    server,
+                    permit,
+                    tx.clone(),
+                    queue.clone(),
+                ));
             }
         };
 
-        tokio::runtime::Builder::new_multi_thread()
+        let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
-            .expect("Failed building the Runtime")
-            .block_on(pipe_server);
+            .expect("Failed building the Runtime");
+
+        runtime.block_on(async {
+            tokio::select! {
+                _ = pipe_server => {}
+                () = SHUTDOWN.signaled() => {}
+            }
+        });
+
+        // drops the connection tasks, which closes their pipes
+        runtime.shutdown_timeout(Duration::from_millis(500));
     });
+
+    SHUTDOWN.register("pipe server", thread);
 }
 
-/// used to check the validity of a Vec<Monitor>
-/// the validity invariants are:
-/// 1. unique monitor ids
-/// 2. unique monitor modes (width+height must be unique per array element)
-/// 3. unique refresh rates per monitor mode
-fn has_duplicates(monitors: &[Monitor]) -> bool {
-    let mut monitor_iter = monitors.iter();
-    while let Some(monitor) = monitor_iter.next() {
-        let duplicate_id = monitor_iter.clone().any(|b| monitor.id == b.id);
-        if duplicate_id {
-            warn!("Found duplicate monitor id {}", monitor.id);
-            return true;
-        }
-
-        let mut mode_iter = monitor.modes.iter();
-        while let Some(mode) = mode_iter.next() {
-            let duplicate_mode = mode_iter
-                .clone()
-                .any(|m| mode.height == m.height && mode.width == m.width);
-            if duplicate_mode {
-                warn!(
-                    "Found duplicate mode {}x{} on monitor {}",
-                    mode.width, mode.height, monitor.id
-                );
-                return true;
-            }
-
-            let mut refresh_iter = mode.refresh_rates.iter().copied();
-            while let Some(rr) = refresh_iter.next() {
-                let duplicate_rr = refresh_iter.clone().any(|r| rr == r);
-                if duplicate_rr {
-                    warn!(
-                        "Found duplicate refresh rate {rr} on mode {}x{} for monitor {}",
-                        mode.width, mode.height, monitor.id
-                    );
-                    return true;
+/// Serves a connected client until it disconnects
+///
+/// Holds `permit` for as long as the pipe instance is open, and pushes the state changes of other
+/// connections to it
+async fn handle_connection(
+    id: usize,
+    mut server: NamedPipeServer,
+    _permit: OwnedSemaphorePermit,
+    tx: Sender<(usize, Vec<Monitor>)>,
+    queue: CommandQueue,
+) {
+    // also unregisters when this is dropped on shutdown
+    let _subscription = Subscription(id);
+
+    let pid = client_process_id(&server);
+    // also unlisted when this is dropped on shutdown
+    let _registration = Registration::new(id, pid);
+
+    // detects legacy clients which terminate messages instead of prefixing their length
+    let mut decoder = FrameDecoder::new();
+    let mut buf = vec![0; BUFFER_SIZE as usize];
+    let mut rx = tx.subscribe();
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let mut protocol = None;
+
+    loop {
+        tokio::select! {
+            val = server.read(&mut buf) =>  {
+                match val {
+                    // 0 = no more data to read
+                    // or break on err
+                    Ok(0) | Err(_) => break,
+
+                    Ok(size) => decoder.push(&buf[..size]),
+                }
+
+                if process_message(id, pid, &mut server, &queue, &events_tx, &mut protocol, &mut decoder).await.is_err() {
+                    break;
+                }
+            },
+
+            val = rx.recv() => {
+                let command = match val {
+                    // ignore if this value was sent for the current client (current client doesn't need notification)
+                    Ok((client_id, _)) if client_id == id => continue,
+
+                    Ok((_, data)) => EventCommand::Changed(data),
+
+                    Err(RecvError::Lagged(_)) => continue,
+
+                    // closed
+                    Err(_) => break
+                };
+
+                // the client hasn't sent anything yet, so it can't be told apart from a
+                // legacy one. It gets the current state with its first request anyway
+                let Some(framing) = decoder.framing() else {
+                    continue;
+                };
+
+                if send_event(&mut server, framing, &command).await.is_err() {
+                    break;
+                }
+            }
+
+            Some(event) = events_rx.recv() => {
+                // only subscribed connections get events, so a message was received already
+                let framing = decoder.framing().unwrap_or(Framing::LengthPrefixed);
+
+                if send_event(&mut server, framing, &event).await.is_err() {
+                    break;
                 }
             }
         }
     }
 
-    false
+    // pipe is gone (possibly in the middle of a write), `_subscription` stops pushing events to it
+}
+
+/// The monitors attached to the static connectors of `config`, none for a dynamic adapter
+///
+/// Each connector gets the monitor with its index as id
+fn static_monitors(config: &AdapterConfig) -> Result<Vec<Monitor>, Vec<MonitorError>> {
+    let AdapterConfig::Static { connectors, modes } = config else {
+        return Ok(Vec::new());
+    };
+
+    let modes = if modes.is_empty() {
+        default_static_modes()
+    } else {
+        modes.clone()
+    };
+
+    let mut monitors = (0..*connectors)
+        .map(|id| MonitorBuilder::new(id).modes(modes.clone()).build())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    check_monitors(&mut monitors)?;
+    Ok(monitors)
+}
+
+/// Whether `cmd` adds, changes or removes monitors, or overwrites the saved ones
+fn changes_monitors(cmd: &DriverCommand) -> bool {
+    match cmd {
+        DriverCommand::WithPersistence { command, .. } => changes_monitors(command),
+        DriverCommand::Notify(_)
+        | DriverCommand::Apply { .. }
+        | DriverCommand::SetEnabled { .. }
+        | DriverCommand::Remove(_)
+        | DriverCommand::RemoveAll
+        | DriverCommand::PersistNow
+        | DriverCommand::Reset
+        | DriverCommand::OverrideMode { .. } => true,
+        _ => false,
+    }
+}
+
+/// Checks that a monitor list is valid and that it fits within the driver limits
+///
+/// Duplicate modes are harmless to fix up, so they are merged first. Everything else is rejected
+fn check_monitors(monitors: &mut [Monitor]) -> Result<(), Vec<MonitorError>> {
+    for monitor in monitors.iter_mut() {
+        monitor.dedup_modes();
+    }
+
+    let mut errors = validate_monitors(monitors).err().unwrap_or_default();
+
+    let max_monitors = *MAX_MONITORS;
+    let active = monitors.iter().filter(|m| m.is_active()).count();
+    if active > max_monitors as usize {
+        errors.push(MonitorError::TooManyMonitors(active, max_monitors));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Warnings for the applied monitors whose adaptive sync range is only advertised in the EDID
+fn dropped_adaptive_sync(
+    old: &[Monitor],
+    new: &[Monitor],
+    report: &ApplyReport,
+) -> Vec<ApplyWarning> {
+    monitor_events(old, new)
+        .into_iter()
+        .filter_map(|event| match event {
+            MonitorEvent::Added(monitor) | MonitorEvent::Changed(monitor) => Some(monitor),
+            MonitorEvent::Removed(_) => None,
+        })
+        .filter(|monitor| monitor.vrr_range.is_some() && report.succeeded.contains(&monitor.id))
+        .map(|monitor| {
+            warn!("apply(): monitor {} runs without adaptive sync", monitor.id);
+
+            ApplyWarning {
+                id: monitor.id,
+                message: "Adaptive sync is not supported by this version of Windows, the monitor \
+                          runs at the fixed refresh rate of its mode"
+                    .into(),
+            }
+        })
+        .collect()
+}
+
+/// Rejects new monitors with an id another connection than `connection` reserved
+///
+/// Monitors which already exist are part of every full state, so only new ones are checked
+fn check_reservations(connection: usize, monitors: &[Monitor]) -> Result<(), CommandError> {
+    let lock = MONITOR_MODES.lock().unwrap();
+    let reservations = RESERVATIONS.lock().unwrap();
+
+    let taken = monitors
+        .iter()
+        .filter(|monitor| !lock.iter().any(|m| m.data.id == monitor.id))
+        .filter(|monitor| {
+            reservations
+                .get(&monitor.id)
+                .is_some_and(|&owner| owner != connection)
+        })
+        .map(|monitor| format!("Monitor ID {} is reserved by another client", monitor.id))
+        .collect();
+
+    CommandError::from_failures(ErrorCode::IdReserved, taken)
+}
+
+/// The timer reverting the override of monitor `id`, created on its first override
+fn override_timer(id: Id) -> Result<Timer, CommandError> {
+    let mut timers = OVERRIDE_TIMERS.lock().unwrap();
+    if let Some(&timer) = timers.get(&id) {
+        return Ok(timer);
+    }
+
+    let failed = |e: WdfError| {
+        error!("Failed to create the override timer of monitor {id}: {e:?}");
+        CommandError::Failed(
+            ErrorCode::IddCx,
+            format!("Failed to create the override timer: {e}"),
+        )
+    };
+
+    let adapter = ADAPTER.get().unwrap().0.as_ptr();
+    let mut device = None;
+    unsafe {
+        DeviceContext::get_timeout(adapter.cast(), CONTEXT_LOCK_TIMEOUT, |context| {
+            device = Some(context.device());
+        })
+    }
+    .map_err(failed)?;
+    let device = device.expect("the context was read");
+
+    // runs in order with the commands, a client could have changed the monitor since
+    let revert = move || {
+        if let Some(queue) = QUEUE.get() {
+            queue.queue(DRIVER_CONNECTION, DriverCommand::RevertMode { id });
+        }
+    };
+
+    // SAFETY: the device lives as long as the driver, and with it the timer
+    let timer = unsafe { Timer::create(device.cast(), revert) }.map_err(failed)?;
+    timers.insert(id, timer);
+
+    Ok(timer)
 }
 
 /// Notifies driver of new system monitor state
@@ -314,18 +2031,30 @@ fn has_duplicates(monitors: &[Monitor]) -> bool {
 /// Note that updated monitors causes a detach, update, and reattach. (Required for windows to see the changes)
 ///
 /// Only detaches/reattaches if required
-/// e.g. only a monitor name update would not detach/arrive a monitor
-fn notify(monitors: Vec<Monitor>) {
-    // Duplicated id's will not cause any issue, however duplicated resolutions/refresh rates are possible
-    // They should all be unique anyways. So warn + noop if the sender sent incorrect data
-    if has_duplicates(&monitors) {
-        warn!("notify(): Duplicate data was detected; update aborted");
-        return;
-    }
+/// e.g. only a monitor name update of a monitor with a custom EDID would not detach/arrive a monitor
+///
+/// `monitors` must already have passed `check_monitors`
+///
+/// Failing IddCx calls don't stop the update, they are collected into the returned error
+fn notify(monitors: Vec<Monitor>) -> Result<(), CommandError> {
+    let failures = apply_state(monitors)
+        .into_iter()
+        .map(|failure| failure.message)
+        .collect();
+
+    CommandError::from_failures(ErrorCode::IddCx, failures)
+}
 
+/// Brings the driver to the new monitor state in one pass, see `notify`
+///
+/// Returns the monitors for which an IddCx call failed, the others are applied regardless
+fn apply_state(monitors: Vec<Monitor>) -> Vec<ApplyFailure> {
     let adapter = ADAPTER.get().unwrap().0.as_ptr();
 
+    let mut failures = Vec::new();
+
     let mut lock = MONITOR_MODES.lock().unwrap();
+    let old_state = snapshot(&lock);
 
     // Remove monitors from internal list which are missing from the provided list
 
@@ -336,11 +2065,10 @@ fn notify(monitors: Vec<Monitor>) {
         // if it doesn't exist, then add to removal list
         if !found {
             // monitor not found in monitors list, so schedule to remove it
-            if let Some(mut obj) = mon.object.take() {
+            if let Some(obj) = mon.object.take() {
                 // remove any monitors scheduled for removal
-                let obj = unsafe { obj.as_mut() };
-                if let Err(e) = unsafe { IddCxMonitorDeparture(obj) } {
-                    error!("Failed to remove monitor: {e:?}");
+                if let Err(e) = depart(id, obj) {
+                    failures.push(iddcx_failure(id, e));
                 }
             }
         }
@@ -358,36 +2086,50 @@ fn notify(monitors: Vec<Monitor>) {
             let cur_mon = lock.iter_mut().find(|mon| mon.data.id == id);
 
             if let Some(mon) = cur_mon {
-                let modes_changed = mon.data.modes != monitor.modes;
+                // the EDID is only read on arrival, so changing it needs a reattach as well
+                let modes_changed = mon.data.modes != monitor.modes
+                    || mon.data.edid != monitor.edid
+                    || mon.data.raw_edid != monitor.raw_edid
+                    || mon.data.manufacturer_id != monitor.manufacturer_id
+                    || mon.data.product_code != monitor.product_code
+                    || mon.data.edid_serial() != monitor.edid_serial()
+                    // the name and color are part of the generated EDID
+                    || (monitor.edid.is_none()
+                        && (mon.data.name != monitor.name
+                            || mon.data.color != monitor.color
+                            || mon.data.color_space != monitor.color_space));
+                let active = attached(&monitor);
 
                 #[allow(clippy::nonminimal_bool)]
                 {
                     should_arrive =
-                        // previously was disabled, and it was just enabled
-                        (!mon.data.enabled && monitor.enabled) ||
-                        // OR monitor is enabled and the display modes changed
-                        (monitor.enabled && modes_changed) ||
-                        // OR monitor is enabled and the monitor was disconnected
-                        (monitor.enabled && mon.object.is_none());
-                }
-
-                // should only detach if modes changed, or if state is false
-                if modes_changed || !monitor.enabled {
-                    if let Some(mut obj) = mon.object.take() {
-                        let obj = unsafe { obj.as_mut() };
-                        if let Err(e) = unsafe { IddCxMonitorDeparture(obj) } {
-                            error!("Failed to remove monitor: {e:?}");
+                        // previously was disabled, parked or out of its session, and it just became active
+                        (!attached(&mon.data) && active) ||
+                        // OR monitor is active and the display modes changed
+                        (active && modes_changed) ||
+                        // OR monitor is active and the monitor was disconnected
+                        (active && mon.object.is_none());
+                }
+
+                // should only detach if modes changed, or if it is disabled, parked or out of its session
+                if modes_changed || !active {
+                    if let Some(obj) = mon.object.take() {
+                        if let Err(e) = depart(id, obj) {
+                            failures.push(iddcx_failure(id, e));
                         }
                     }
                 }
 
                 // update monitor data
+                mon.frame_rate_limit.set(monitor.frame_rate_limit);
                 mon.data = monitor;
             } else {
-                should_arrive = monitor.enabled;
+                should_arrive = attached(&monitor);
 
                 lock.push(MonitorObject {
                     object: None,
+                    connector_index: None,
+                    frame_rate_limit: Arc::new(FrameRateLimit::new(monitor.frame_rate_limit)),
                     data: monitor,
                 });
             }
@@ -396,48 +2138,146 @@ fn notify(monitors: Vec<Monitor>) {
         })
         .collect::<Vec<_>>();
 
+    publish(&old_state, &snapshot(&lock));
+
     // context.create_monitor locks again, so this avoids deadlock
     drop(lock);
 
+    let arriving = should_arrive
+        .into_iter()
+        .filter_map(|(id, arrive)| arrive.then_some(id))
+        .collect::<Vec<_>>();
+
     let cb = |context: &mut DeviceContext| {
         // arrive any monitors that need arriving
-        for (id, arrive) in should_arrive {
-            if arrive {
-                if let Err(e) = context.create_monitor(id) {
-                    error!("Failed to create monitor: {e:?}");
-                }
+        for &id in &arriving {
+            if let Err(e) = context.create_monitor(id) {
+                error!("Failed to create monitor: {e:?}");
+                failures.push(iddcx_failure(
+                    id,
+                    format!("Failed to create monitor {id}: {e}"),
+                ));
             }
         }
     };
 
-    unsafe {
-        DeviceContext::get_mut(adapter.cast(), cb).unwrap();
+    let result =
+        unsafe { DeviceContext::get_mut_timeout(adapter.cast(), CONTEXT_LOCK_TIMEOUT, cb) };
+    match result {
+        Ok(()) => (),
+        // they stay detached, the next change of state arrives them
+        Err(WdfError::LockTimeout) => {
+            for id in arriving {
+                warn!("Timed out waiting for the device context to create monitor {id}");
+                failures.push(iddcx_failure(
+                    id,
+                    format!("Failed to create monitor {id}: the device is busy, try again"),
+                ));
+            }
+        }
+        // this runs on the command queue, a panic would stop it for every client
+        Err(e) => {
+            error!("Failed to get device context: {e}");
+            for id in arriving {
+                failures.push(iddcx_failure(
+                    id,
+                    format!("Failed to create monitor {id}: no device context: {e}"),
+                ));
+            }
+        }
     }
+
+    failures
 }
 
-fn remove_all() {
-    let mut lock = MONITOR_MODES.lock().unwrap();
+/// Whether `monitor` is attached to the OS in the current session, see [SessionScope]
+fn attached(monitor: &Monitor) -> bool {
+    monitor.is_active()
+        && (monitor.session_scope == SessionScope::System || USER_SESSION.load(Ordering::Relaxed))
+}
 
-    for monitor in lock.drain(..) {
-        if let Some(mut monitor_object) = monitor.object {
-            let obj = unsafe { monitor_object.as_mut() };
-            if let Err(e) = unsafe { IddCxMonitorDeparture(obj) } {
-                error!("Failed to remove monitor: {e:?}");
-            }
+fn iddcx_failure(id: Id, message: String) -> ApplyFailure {
+    ApplyFailure {
+        id,
+        code: ErrorCode::IddCx,
+        message,
+    }
+}
+
+/// Detaches a monitor from the OS
+fn depart(id: Id, mut object: NonNull<IDDCX_MONITOR__>) -> Result<(), String> {
+    let obj = unsafe { object.as_mut() };
+    unsafe { IddCxMonitorDeparture(obj) }
+        .map(|_| logging::monitor_departure(id))
+        .map_err(|e| {
+            error!("Failed to remove monitor: {e:?}");
+            format!("Failed to remove monitor {id}: {e}")
+        })
+}
+
+/// Tears the driver down as the device goes away
+///
+/// First new commands are rejected and the ones in flight finish, then the monitors depart, and
+/// only then the pipe server and the other threads stop. So no client command runs into a monitor
+/// departing under it. Waits up to `timeout` for each of the two waits
+pub fn teardown(timeout: Duration) {
+    stop_monitors(timeout);
+    SHUTDOWN.shutdown(timeout);
+    LIFECYCLE.stop();
+
+    info!("Driver stopped");
+}
+
+/// The part of [teardown] before the threads stop: drains the commands and departs all monitors
+///
+/// Unlike [remove_all], the monitors stay in the list and what is saved is kept
+pub fn stop_monitors(timeout: Duration) {
+    if !LIFECYCLE.drain(timeout) {
+        warn!("Commands still running after {timeout:?}, departing the monitors anyway");
+    }
+
+    let mut lock = MONITOR_MODES.lock().unwrap_or_else(PoisonError::into_inner);
+
+    for monitor in lock.iter_mut() {
+        if let Some(object) = monitor.object.take() {
+            monitor.connector_index = None;
+            // the failure is logged, the device goes away regardless
+            _ = depart(monitor.data.id, object);
         }
     }
 }
 
-fn remove(ids: &[u32]) {
+fn remove_all() -> Result<(), CommandError> {
+    let mut lock = MONITOR_MODES.lock().unwrap();
+    let old_state = snapshot(&lock);
+
+    let failures = lock
+        .drain(..)
+        .filter_map(|monitor| depart(monitor.data.id, monitor.object?).err())
+        .collect();
+
+    publish(&old_state, &[]);
+
+    CommandError::from_failures(ErrorCode::IddCx, failures)
+}
+
+fn remove(ids: &[u32]) -> Result<(), CommandError> {
     let mut lock = MONITOR_MODES.lock().unwrap();
+    let old_state = snapshot(&lock);
+
+    let mut failures = Vec::new();
+    let mut missing = Vec::new();
 
     for &id in ids {
+        let mut found = false;
+
         lock.retain_mut(|monitor| {
             if id == monitor.data.id {
-                if let Some(mut monitor_object) = monitor.object.take() {
-                    let obj = unsafe { monitor_object.as_mut() };
-                    if let Err(e) = unsafe { IddCxMonitorDeparture(obj) } {
-                        error!("Failed to remove monitor: {e:?}");
+                found = true;
+
+                if let Some(monitor_object) = monitor.object.take() {
+                    if let Err(e) = depart(id, monitor_object) {
+                        failures.push(e);
                     }
                 }
 
@@ -446,7 +2286,16 @@ fn remove(ids: &[u32]) {
                 true
             }
         });
+
+        if !found {
+            missing.push(format!("Monitor {id} not found"));
+        }
     }
+
+    publish(&old_state, &snapshot(&lock));
+
+    CommandError::from_failures(ErrorCode::IddCx, failures)?;
+    CommandError::from_failures(ErrorCode::MonitorNotFound, missing)
 }
 
 pub trait FlattenModes {
@@ -458,6 +2307,7 @@ pub struct ModeItem {
     pub width: Dimen,
     pub height: Dimen,
     pub refresh_rate: RefreshRate,
+    pub timing: Option<CustomTiming>,
 }
 
 /// Takes a slice of modes and creates a flattened structure that can be iterated over
@@ -468,7 +2318,80 @@ impl FlattenModes for Vec<Mode> {
                 width: m.width,
                 height: m.height,
                 refresh_rate: rr,
+                timing: m.timing,
             })
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use driver_ipc::MonitorBuilder;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn queue_serializes_concurrent_clients() {
+        const CLIENTS: usize = 4;
+        const ROUNDS: usize = 25;
+
+        // stands in for MONITOR_MODES, with every state in the order it was applied
+        let state = Arc::new(Mutex::new(Vec::<Monitor>::new()));
+        let applied = Arc::new(Mutex::new(Vec::new()));
+
+        let queue = {
+            let state = state.clone();
+            let applied = applied.clone();
+
+            CommandQueue::start(move |_, command| {
+                let mut state = state.try_lock().expect("commands ran at the same time");
+
+                match command {
+                    DriverCommand::Notify(monitors) => *state = monitors,
+                    DriverCommand::Remove(ids) => state.retain(|m| !ids.contains(&m.id)),
+                    _ => unreachable!(),
+                }
+
+                // widen the window for a second command to sneak in
+                thread::sleep(Duration::from_millis(1));
+
+                applied.lock().unwrap().push(state.clone());
+                Ok(ReplyCommand::Done)
+            })
+        };
+
+        let clients = (0..CLIENTS)
+            .map(|client| {
+                let queue = queue.clone();
+
+                task::spawn(async move {
+                    #[allow(clippy::cast_possible_truncation)]
+                    let id = client as Id;
+
+                    for round in 0..ROUNDS {
+                        let command = if round % 2 == 0 {
+                            let monitor = MonitorBuilder::new(id)
+                                .mode(1920, 1080, &[60])
+                                .build()
+                                .unwrap();
+                            DriverCommand::Notify(vec![monitor])
+                        } else {
+                            DriverCommand::Remove(vec![id])
+                        };
+
+                        let reply = queue.run(client, command).await;
+                        assert!(matches!(reply, Some(Ok(ReplyCommand::Done))));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        let applied = applied.lock().unwrap();
+        assert_eq!(applied.len(), CLIENTS * ROUNDS);
+        assert_eq!(*state.lock().unwrap(), *applied.last().unwrap());
+    }
+}
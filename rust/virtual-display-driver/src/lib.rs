@@ -5,9 +5,15 @@ mod context;
 mod direct_3d_device;
 mod edid;
 mod entry;
+mod frame_export;
 mod ipc;
 mod panic;
+mod render_adapter;
+mod settings;
+mod shutdown;
 mod swap_chain_processor;
+#[cfg(test)]
+mod testing;
 
 use wdf_umdf_sys::{NTSTATUS, PUNICODE_STRING, PVOID};
 
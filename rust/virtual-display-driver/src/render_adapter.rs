@@ -0,0 +1,85 @@
+use std::sync::{LazyLock, Mutex};
+
+use driver_ipc::{AdapterPreference, Luid};
+use log::warn;
+use wdf_umdf::{IddCxAdapterSetRenderAdapter, IddCxError};
+use wdf_umdf_sys::{IDARG_IN_ADAPTERSETRENDERADAPTER, LUID};
+
+use crate::{
+    direct_3d_device::find_adapter,
+    ipc::ADAPTER,
+    settings::{self, PREFERRED_ADAPTER},
+};
+
+/// The adapter preference, and the adapter it matched when it was last applied
+struct Preferred {
+    preference: Option<AdapterPreference>,
+    luid: Option<Luid>,
+}
+
+// starts out with the driver setting, clients can replace it until the setting changes
+static PREFERRED: LazyLock<Mutex<Preferred>> = LazyLock::new(|| {
+    Mutex::new(Preferred {
+        preference: PREFERRED_ADAPTER.clone(),
+        luid: None,
+    })
+});
+
+/// Replaces the adapter preference and applies it, see `apply`
+pub fn set(preference: Option<AdapterPreference>) -> Result<(), IddCxError> {
+    PREFERRED.lock().unwrap().preference = preference;
+    apply()
+}
+
+/// Goes back to the preference from the driver settings, see `set`
+pub fn reset() -> Result<(), IddCxError> {
+    set(settings::preferred_adapter())
+}
+
+/// Asks IddCx to render on the preferred adapter, Windows then moves the swap chains over
+///
+/// Without a preference, or if no adapter matches it, IddCx keeps the adapter it picked. Must be
+/// called after the adapter was stored in `ADAPTER`
+pub fn apply() -> Result<(), IddCxError> {
+    let preference = {
+        let mut preferred = PREFERRED.lock().unwrap();
+        preferred.luid = None;
+        preferred.preference.clone()
+    };
+
+    let Some(preference) = preference else {
+        return Ok(());
+    };
+
+    let luid = match find_adapter(&preference) {
+        Ok(Some(luid)) => luid,
+        Ok(None) => {
+            warn!("No adapter matches {preference:?}, keeping the adapter picked by IddCx");
+            return Ok(());
+        }
+        Err(e) => {
+            warn!("Failed to enumerate adapters, keeping the adapter picked by IddCx: {e:?}");
+            return Ok(());
+        }
+    };
+
+    let args = IDARG_IN_ADAPTERSETRENDERADAPTER {
+        PreferredRenderAdapter: LUID {
+            LowPart: luid.low_part,
+            HighPart: luid.high_part,
+        },
+    };
+
+    let adapter = ADAPTER.get().unwrap().0.as_ptr();
+    // not locked, in case IddCx reassigns the swap chains right away
+    unsafe { IddCxAdapterSetRenderAdapter(adapter, &args)? };
+
+    PREFERRED.lock().unwrap().luid = Some(luid);
+
+    Ok(())
+}
+
+/// Adapter matching the preference, if there is one
+pub fn preferred() -> Option<Luid> {
+    PREFERRED.lock().unwrap().luid
+}
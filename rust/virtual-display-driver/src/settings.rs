@@ -0,0 +1,709 @@
+use std::{
+    str::FromStr,
+    sync::{LazyLock, Mutex, Once},
+    thread,
+};
+
+use driver_ipc::{AdapterConfig, AdapterPreference, Mode, SettingsReload};
+use log::{error, info, warn, LevelFilter};
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0, WAIT_TIMEOUT},
+        System::{
+            Registry::{RegNotifyChangeKeyValue, HKEY, REG_NOTIFY_CHANGE_LAST_SET},
+            Threading::{CreateEventW, WaitForSingleObject},
+        },
+    },
+};
+use winreg::{
+    enums::{HKEY_LOCAL_MACHINE, KEY_READ},
+    RegKey,
+};
+
+use crate::{render_adapter, shutdown::SHUTDOWN};
+
+// Registry key (under HKEY_LOCAL_MACHINE) holding driver wide settings and the persisted monitors
+// The settings are read on driver start, and again whenever the key changes, see `watch`. Only
+// `LogLevel` and `PreferredAdapter` are applied right away, the others on the next driver start
+pub const SETTINGS_KEY: &str = r"SOFTWARE\VirtualDisplayDriver";
+
+// Names of the values holding the settings
+pub const LOG_LEVEL_VALUE: &str = "LogLevel";
+pub const MAX_MONITORS_VALUE: &str = "MaxMonitors";
+pub const MAX_PIPE_CLIENTS_VALUE: &str = "MaxPipeClients";
+pub const PIPE_ACCESS_VALUE: &str = "PipeAccess";
+pub const PREFERRED_ADAPTER_VALUE: &str = "PreferredAdapter";
+pub const STATIC_CONNECTORS_VALUE: &str = "StaticConnectors";
+pub const STATIC_MODES_VALUE: &str = "StaticModes";
+
+// How often (in ms) the settings watcher checks for the driver shutting down
+const WATCH_POLL_MS: u32 = 250;
+
+/// Driver wide settings, as read from the settings key
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub log_level: LevelFilter,
+    pub max_monitors: u32,
+    pub max_pipe_clients: u32,
+    pub pipe_access: PipeAccess,
+    pub preferred_adapter: Option<AdapterPreference>,
+    pub adapter_config: AdapterConfig,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            log_level: default_log_level(),
+            max_monitors: DEFAULT_MAX_MONITORS,
+            max_pipe_clients: DEFAULT_MAX_PIPE_CLIENTS,
+            pipe_access: PipeAccess::Everyone,
+            preferred_adapter: None,
+            adapter_config: AdapterConfig::Dynamic,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings, falling back to the default of every value which is missing or invalid
+    ///
+    /// Also returns why values were not used, so the caller decides whether to log them
+    pub fn read(values: &impl SettingValues) -> (Self, Vec<String>) {
+        let mut warnings = Vec::new();
+
+        let log_level = read_log_level(values, &mut warnings);
+        let max_monitors = read_max_monitors(values, &mut warnings);
+        let max_pipe_clients = read_max_pipe_clients(values, &mut warnings);
+        let adapter_config = read_adapter_config(values, max_monitors, &mut warnings);
+
+        let settings = Self {
+            log_level,
+            max_monitors,
+            max_pipe_clients,
+            pipe_access: read_pipe_access(values),
+            preferred_adapter: read_preferred_adapter(values),
+            adapter_config,
+        };
+
+        (settings, warnings)
+    }
+
+    /// Names of the values which differ from `other`, split into those the driver applies while
+    /// running and those which need a driver restart
+    pub fn changes(&self, other: &Self) -> (Vec<&'static str>, Vec<&'static str>) {
+        fn static_connectors(config: &AdapterConfig) -> (u32, &[Mode]) {
+            match config {
+                AdapterConfig::Dynamic => (0, &[]),
+                AdapterConfig::Static { connectors, modes } => (*connectors, modes),
+            }
+        }
+
+        let mut live = Vec::new();
+        let mut restart = Vec::new();
+
+        if self.log_level != other.log_level {
+            live.push(LOG_LEVEL_VALUE);
+        }
+
+        if self.preferred_adapter != other.preferred_adapter {
+            live.push(PREFERRED_ADAPTER_VALUE);
+        }
+
+        if self.max_monitors != other.max_monitors {
+            restart.push(MAX_MONITORS_VALUE);
+        }
+
+        if self.max_pipe_clients != other.max_pipe_clients {
+            restart.push(MAX_PIPE_CLIENTS_VALUE);
+        }
+
+        if self.pipe_access != other.pipe_access {
+            restart.push(PIPE_ACCESS_VALUE);
+        }
+
+        let (connectors, modes) = static_connectors(&self.adapter_config);
+        let (other_connectors, other_modes) = static_connectors(&other.adapter_config);
+
+        if connectors != other_connectors {
+            restart.push(STATIC_CONNECTORS_VALUE);
+        }
+
+        if modes != other_modes {
+            restart.push(STATIC_MODES_VALUE);
+        }
+
+        (live, restart)
+    }
+}
+
+/// Source of the setting values, the settings key or a fake one in tests
+pub trait SettingValues {
+    fn dword(&self, name: &str) -> Option<u32>;
+    fn string(&self, name: &str) -> Option<String>;
+}
+
+/// The settings key, values can't be read if it doesn't exist
+struct Registry(Option<RegKey>);
+
+impl Registry {
+    fn open() -> Self {
+        Self(
+            RegKey::predef(HKEY_LOCAL_MACHINE)
+                .open_subkey_with_flags(SETTINGS_KEY, KEY_READ)
+                .ok(),
+        )
+    }
+}
+
+impl SettingValues for Registry {
+    fn dword(&self, name: &str) -> Option<u32> {
+        self.0.as_ref()?.get_value::<u32, _>(name).ok()
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        self.0.as_ref()?.get_value::<String, _>(name).ok()
+    }
+}
+
+// Settings the driver started with, every static below is taken from these
+static STARTUP: LazyLock<Settings> = LazyLock::new(|| {
+    let (settings, warnings) = Settings::read(&Registry::open());
+    for warning in &warnings {
+        warn!("{warning}");
+    }
+
+    let defaults = Settings::default();
+    let (live, restart) = settings.changes(&defaults);
+    for name in live.into_iter().chain(restart) {
+        info!("Using {name} from registry");
+    }
+
+    info!("Using PipeAccess {:?}", settings.pipe_access);
+
+    *LAST_READ.lock().unwrap() = Some((settings.clone(), warnings));
+    settings
+});
+
+// Settings of the latest reload and why values were not used, see `reload`
+static LAST_READ: Mutex<Option<(Settings, Vec<String>)>> = Mutex::new(None);
+
+/// Level of the driver log when nothing is configured
+pub fn default_log_level() -> LevelFilter {
+    if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
+
+/// Least severe records the driver logs
+///
+/// Configured through the `LogLevel` string value, one of `Off`, `Error`, `Warn`, `Info`, `Debug`
+/// and `Trace`. Applied right away when it changes
+pub static LOG_LEVEL: LazyLock<LevelFilter> = LazyLock::new(|| STARTUP.log_level);
+
+fn read_log_level(values: &impl SettingValues, warnings: &mut Vec<String>) -> LevelFilter {
+    match values.string(LOG_LEVEL_VALUE).as_deref().map(str::trim) {
+        None | Some("") => default_log_level(),
+        Some(level) => LevelFilter::from_str(level).unwrap_or_else(|_| {
+            let default = default_log_level();
+            warnings.push(format!(
+                "Invalid LogLevel {level:?}, using default of {default}"
+            ));
+            default
+        }),
+    }
+}
+
+// Amount of monitors that can be connected at the same time when nothing is configured
+pub const DEFAULT_MAX_MONITORS: u32 = 32;
+// IddCx does not document a hard limit, so keep the configurable value within reason
+pub const MAX_MONITORS_LIMIT: u32 = 128;
+
+/// Maximum amount of monitors that can be connected at the same time
+///
+/// Configured through the `MaxMonitors` DWORD value. Connector indices are allocated
+/// from `0..MAX_MONITORS`, independent of the monitor id
+pub static MAX_MONITORS: LazyLock<u32> = LazyLock::new(|| STARTUP.max_monitors);
+
+fn read_max_monitors(values: &impl SettingValues, warnings: &mut Vec<String>) -> u32 {
+    match values.dword(MAX_MONITORS_VALUE) {
+        Some(0) => {
+            warnings.push(format!(
+                "MaxMonitors must be at least 1, using default of {DEFAULT_MAX_MONITORS}"
+            ));
+            DEFAULT_MAX_MONITORS
+        }
+
+        Some(max) if max > MAX_MONITORS_LIMIT => {
+            warnings.push(format!(
+                "MaxMonitors {max} is above the limit, using {MAX_MONITORS_LIMIT}"
+            ));
+            MAX_MONITORS_LIMIT
+        }
+
+        Some(max) => max,
+        None => DEFAULT_MAX_MONITORS,
+    }
+}
+
+// Clients that can be connected to the control pipe at the same time when nothing is configured
+pub const DEFAULT_MAX_PIPE_CLIENTS: u32 = 8;
+// Windows allows at most 255 instances of a pipe, and 255 itself means unlimited
+pub const MAX_PIPE_CLIENTS_LIMIT: u32 = 254;
+
+/// Maximum amount of clients that can be connected to the control pipe at the same time
+///
+/// Configured through the `MaxPipeClients` DWORD value. Further clients wait until a connection
+/// closes
+pub static MAX_PIPE_CLIENTS: LazyLock<u32> = LazyLock::new(|| STARTUP.max_pipe_clients);
+
+fn read_max_pipe_clients(values: &impl SettingValues, warnings: &mut Vec<String>) -> u32 {
+    match values.dword(MAX_PIPE_CLIENTS_VALUE) {
+        Some(0) => {
+            warnings.push(format!(
+                "MaxPipeClients must be at least 1, using default of {DEFAULT_MAX_PIPE_CLIENTS}"
+            ));
+            DEFAULT_MAX_PIPE_CLIENTS
+        }
+
+        Some(max) if max > MAX_PIPE_CLIENTS_LIMIT => {
+            warnings.push(format!(
+                "MaxPipeClients {max} is above the limit, using {MAX_PIPE_CLIENTS_LIMIT}"
+            ));
+            MAX_PIPE_CLIENTS_LIMIT
+        }
+
+        Some(max) => max,
+        None => DEFAULT_MAX_PIPE_CLIENTS,
+    }
+}
+
+/// Who may open the control pipe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipeAccess {
+    /// Any local account, the default
+    Everyone,
+    /// Elevated processes and services
+    Administrators,
+    /// Users logged on interactively, and everything `Administrators` allows
+    InteractiveUsers,
+    /// Custom SDDL string
+    Custom(String),
+}
+
+impl PipeAccess {
+    /// Security descriptor of the pipe in SDDL form
+    ///
+    /// The pipe owner keeps full access, otherwise the driver couldn't create further pipe
+    /// instances once the first one is restricted
+    pub fn sddl(&self) -> &str {
+        match self {
+            Self::Everyone => "D:NO_ACCESS_CONTROL",
+            Self::Administrators => "D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)",
+            Self::InteractiveUsers => "D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)(A;;GRGW;;;IU)",
+            Self::Custom(sddl) => sddl,
+        }
+    }
+}
+
+/// Access control applied to the control pipe
+///
+/// Configured through the `PipeAccess` string value, one of `Everyone`, `Administrators` and
+/// `InteractiveUsers`. Any other value is used as an SDDL string
+pub static PIPE_ACCESS: LazyLock<PipeAccess> = LazyLock::new(|| STARTUP.pipe_access.clone());
+
+fn read_pipe_access(values: &impl SettingValues) -> PipeAccess {
+    match values.string(PIPE_ACCESS_VALUE).as_deref().map(str::trim) {
+        None | Some("" | "Everyone") => PipeAccess::Everyone,
+        Some("Administrators") => PipeAccess::Administrators,
+        Some("InteractiveUsers") => PipeAccess::InteractiveUsers,
+        Some(sddl) => PipeAccess::Custom(sddl.to_owned()),
+    }
+}
+
+/// Adapter to render on, instead of the one IddCx picks
+///
+/// Configured through the `PreferredAdapter` string value, either a LUID like `00000000:0000D3A1`
+/// or part of the adapter description like `NVIDIA`. Clients can replace it until the value changes
+/// or the driver restarts, see `preferred_adapter` for the current value
+pub static PREFERRED_ADAPTER: LazyLock<Option<AdapterPreference>> =
+    LazyLock::new(|| STARTUP.preferred_adapter.clone());
+
+/// The preferred adapter of the latest reload, see `PREFERRED_ADAPTER`
+pub fn preferred_adapter() -> Option<AdapterPreference> {
+    LazyLock::force(&STARTUP);
+
+    LAST_READ
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|(settings, _)| settings.preferred_adapter.clone())
+}
+
+fn read_preferred_adapter(values: &impl SettingValues) -> Option<AdapterPreference> {
+    values
+        .string(PREFERRED_ADAPTER_VALUE)
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| AdapterPreference::from(value.as_str()))
+}
+
+/// How the adapter exposes monitors
+///
+/// Configured through the `StaticConnectors` DWORD value, the amount of connectors with a monitor
+/// attached from the start, and the `StaticModes` string value, the JSON list of modes they offer.
+/// Without static connectors, monitors arrive and depart as clients add and remove them
+pub static ADAPTER_CONFIG: LazyLock<AdapterConfig> =
+    LazyLock::new(|| STARTUP.adapter_config.clone());
+
+fn read_adapter_config(
+    values: &impl SettingValues,
+    max_monitors: u32,
+    warnings: &mut Vec<String>,
+) -> AdapterConfig {
+    let connectors = match values.dword(STATIC_CONNECTORS_VALUE) {
+        None | Some(0) => return AdapterConfig::Dynamic,
+
+        Some(connectors) if connectors > max_monitors => {
+            warnings.push(format!(
+                "StaticConnectors {connectors} is above MaxMonitors, using {max_monitors}"
+            ));
+            max_monitors
+        }
+
+        Some(connectors) => connectors,
+    };
+
+    let modes = match values
+        .string(STATIC_MODES_VALUE)
+        .filter(|value| !value.trim().is_empty())
+    {
+        None => Vec::new(),
+        Some(value) => serde_json::from_str::<Vec<Mode>>(&value).unwrap_or_else(|e| {
+            warnings.push(format!("Invalid StaticModes, using the default modes: {e}"));
+            Vec::new()
+        }),
+    };
+
+    AdapterConfig::Static { connectors, modes }
+}
+
+/// Modes of static connectors without `StaticModes`, the first one is preferred
+pub fn default_static_modes() -> Vec<Mode> {
+    [(1920, 1080), (2560, 1440), (3840, 2160), (1280, 720)]
+        .into_iter()
+        .map(|(width, height)| Mode {
+            width,
+            height,
+            refresh_rates: vec![60],
+            timing: None,
+        })
+        .collect()
+}
+
+/// Reads the settings again and applies the ones which can change while the driver runs
+///
+/// Reports which values changed since the last reload and were applied, and which differ from the
+/// settings the driver started with, so they need a restart. Runs whenever the settings key
+/// changes, see `watch`, and for `DriverCommand::ReloadSettings`
+pub fn reload() -> SettingsReload {
+    LazyLock::force(&STARTUP);
+
+    let (settings, warnings) = Settings::read(&Registry::open());
+
+    let reload = {
+        let mut last_read = LAST_READ.lock().unwrap();
+        let (last, last_warnings) = last_read.as_ref().expect("set with STARTUP");
+
+        // the monitors are saved in the same key, so most reloads change nothing
+        if warnings != *last_warnings {
+            for warning in &warnings {
+                warn!("{warning}");
+            }
+        }
+
+        let reload = compare(&STARTUP, last, &settings);
+        *last_read = Some((settings.clone(), warnings));
+        reload
+    };
+
+    for name in &reload.applied {
+        match name.as_str() {
+            LOG_LEVEL_VALUE => {
+                driver_logger::set_level(settings.log_level);
+                info!("Applied LogLevel {}", settings.log_level);
+            }
+
+            PREFERRED_ADAPTER_VALUE => {
+                info!("Applying PreferredAdapter {:?}", settings.preferred_adapter);
+                if let Err(e) = render_adapter::set(settings.preferred_adapter.clone()) {
+                    error!("Failed to set the preferred render adapter: {e:?}");
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    reload
+}
+
+// The live values which changed since `last`, and the others which differ from `startup`. Values
+// only logged when they change, not on every reload
+fn compare(startup: &Settings, last: &Settings, settings: &Settings) -> SettingsReload {
+    let (applied, newly_changed) = last.changes(settings);
+    let (_, restart_required) = startup.changes(settings);
+
+    for name in newly_changed {
+        if restart_required.contains(&name) {
+            info!("{name} changed, it takes effect on the next driver start");
+        } else {
+            info!("{name} changed back to the value the driver runs with");
+        }
+    }
+
+    SettingsReload::new(
+        applied.into_iter().map(str::to_owned).collect(),
+        restart_required.into_iter().map(str::to_owned).collect(),
+    )
+}
+
+/// Reloads the settings whenever a value in the settings key changes, until the driver shuts down
+///
+/// Only the first call starts watching
+pub fn watch() {
+    static STARTED: Once = Once::new();
+
+    STARTED.call_once(|| {
+        let thread = thread::spawn(|| {
+            if let Err(e) = watch_key() {
+                error!("Stopped watching the settings, changes need a driver restart: {e}");
+            }
+        });
+
+        SHUTDOWN.register("settings watcher", thread);
+    });
+}
+
+fn watch_key() -> anyhow::Result<()> {
+    struct Event(HANDLE);
+
+    impl Drop for Event {
+        fn drop(&mut self) {
+            _ = unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    // created if nothing was saved yet, so there is a key to watch
+    let (key, _) =
+        RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey_with_flags(SETTINGS_KEY, KEY_READ)?;
+    let hkey = HKEY(key.raw_handle() as *mut _);
+
+    let event = Event(unsafe { CreateEventW(None, false, false, PCWSTR::null())? });
+
+    // notifications fire once, and are armed again before reading so no change is missed
+    let arm = || unsafe {
+        RegNotifyChangeKeyValue(hkey, false, REG_NOTIFY_CHANGE_LAST_SET, event.0, true).ok()
+    };
+
+    arm()?;
+
+    loop {
+        let result = unsafe { WaitForSingleObject(event.0, WATCH_POLL_MS) };
+
+        if SHUTDOWN.is_signaled() {
+            return Ok(());
+        }
+
+        match result {
+            WAIT_OBJECT_0 => {
+                arm()?;
+                reload();
+            }
+
+            WAIT_TIMEOUT => (),
+            _ => return Err(windows::core::Error::from_win32().into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // Values set like in the registry, a DWORD or a string
+    #[derive(Default)]
+    struct Values(HashMap<&'static str, Result<u32, &'static str>>);
+
+    impl Values {
+        fn dword(mut self, name: &'static str, value: u32) -> Self {
+            self.0.insert(name, Ok(value));
+            self
+        }
+
+        fn string(mut self, name: &'static str, value: &'static str) -> Self {
+            self.0.insert(name, Err(value));
+            self
+        }
+    }
+
+    impl SettingValues for Values {
+        fn dword(&self, name: &str) -> Option<u32> {
+            self.0.get(name)?.ok()
+        }
+
+        fn string(&self, name: &str) -> Option<String> {
+            self.0.get(name)?.err().map(str::to_owned)
+        }
+    }
+
+    #[test]
+    fn read_defaults() {
+        let (settings, warnings) = Settings::read(&Values::default());
+        assert_eq!(settings, Settings::default());
+        assert!(warnings.is_empty());
+
+        // empty strings count as unset
+        let values = Values::default()
+            .string(LOG_LEVEL_VALUE, " ")
+            .string(PIPE_ACCESS_VALUE, "")
+            .string(PREFERRED_ADAPTER_VALUE, "")
+            .dword(STATIC_CONNECTORS_VALUE, 0);
+        let (settings, warnings) = Settings::read(&values);
+        assert_eq!(settings, Settings::default());
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn read_values() {
+        let values = Values::default()
+            .string(LOG_LEVEL_VALUE, "trace")
+            .dword(MAX_MONITORS_VALUE, 4)
+            .dword(MAX_PIPE_CLIENTS_VALUE, 2)
+            .string(PIPE_ACCESS_VALUE, "Administrators")
+            .string(PREFERRED_ADAPTER_VALUE, " nvidia ")
+            .dword(STATIC_CONNECTORS_VALUE, 2)
+            .string(
+                STATIC_MODES_VALUE,
+                r#"[{"width":800,"height":600,"refresh_rates":[60]}]"#,
+            );
+
+        let (settings, warnings) = Settings::read(&values);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(
+            settings,
+            Settings {
+                log_level: LevelFilter::Trace,
+                max_monitors: 4,
+                max_pipe_clients: 2,
+                pipe_access: PipeAccess::Administrators,
+                preferred_adapter: Some(AdapterPreference::Name("nvidia".to_owned())),
+                adapter_config: AdapterConfig::Static {
+                    connectors: 2,
+                    modes: vec![Mode {
+                        width: 800,
+                        height: 600,
+                        refresh_rates: vec![60],
+                        timing: None,
+                    }],
+                },
+            }
+        );
+
+        let values = Values::default().string(PIPE_ACCESS_VALUE, "D:P(A;;GA;;;SY)");
+        let (settings, _) = Settings::read(&values);
+        assert_eq!(
+            settings.pipe_access,
+            PipeAccess::Custom("D:P(A;;GA;;;SY)".to_owned())
+        );
+    }
+
+    #[test]
+    fn read_invalid_values() {
+        let values = Values::default()
+            .string(LOG_LEVEL_VALUE, "loud")
+            .dword(MAX_MONITORS_VALUE, 0)
+            .dword(MAX_PIPE_CLIENTS_VALUE, 255)
+            .dword(STATIC_CONNECTORS_VALUE, 5)
+            .string(STATIC_MODES_VALUE, "not json");
+
+        let (settings, warnings) = Settings::read(&values);
+        assert_eq!(settings.log_level, default_log_level());
+        assert_eq!(settings.max_monitors, DEFAULT_MAX_MONITORS);
+        assert_eq!(settings.max_pipe_clients, MAX_PIPE_CLIENTS_LIMIT);
+        assert_eq!(
+            settings.adapter_config,
+            AdapterConfig::Static {
+                connectors: 5,
+                modes: Vec::new()
+            }
+        );
+        assert_eq!(warnings.len(), 4, "{warnings:?}");
+
+        // static connectors are limited by the monitors in the same read
+        let values = Values::default()
+            .dword(MAX_MONITORS_VALUE, 2)
+            .dword(STATIC_CONNECTORS_VALUE, 3);
+        let (settings, warnings) = Settings::read(&values);
+        assert_eq!(
+            settings.adapter_config,
+            AdapterConfig::Static {
+                connectors: 2,
+                modes: Vec::new()
+            }
+        );
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn changes_split_live_and_restart() {
+        let startup = Settings::default();
+        let (settings, _) = Settings::read(
+            &Values::default()
+                .string(LOG_LEVEL_VALUE, "error")
+                .string(PREFERRED_ADAPTER_VALUE, "intel")
+                .dword(MAX_MONITORS_VALUE, 8)
+                .string(PIPE_ACCESS_VALUE, "InteractiveUsers")
+                .dword(STATIC_CONNECTORS_VALUE, 1),
+        );
+
+        let (live, restart) = startup.changes(&settings);
+        assert_eq!(live, [LOG_LEVEL_VALUE, PREFERRED_ADAPTER_VALUE]);
+        assert_eq!(
+            restart,
+            [
+                MAX_MONITORS_VALUE,
+                PIPE_ACCESS_VALUE,
+                STATIC_CONNECTORS_VALUE
+            ]
+        );
+
+        assert_eq!(settings.changes(&settings), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn compare_applies_once_and_keeps_restart_pending() {
+        let startup = Settings::default();
+        let (settings, _) = Settings::read(
+            &Values::default()
+                .string(LOG_LEVEL_VALUE, "warn")
+                .dword(MAX_PIPE_CLIENTS_VALUE, 1),
+        );
+
+        let reload = compare(&startup, &startup, &settings);
+        assert_eq!(reload.applied, [LOG_LEVEL_VALUE]);
+        assert_eq!(reload.restart_required, [MAX_PIPE_CLIENTS_VALUE]);
+
+        // nothing to apply again, but the restart is still needed
+        let reload = compare(&startup, &settings, &settings);
+        assert!(reload.applied.is_empty());
+        assert_eq!(reload.restart_required, [MAX_PIPE_CLIENTS_VALUE]);
+
+        // back to the values the driver started with
+        let reload = compare(&startup, &settings, &startup);
+        assert_eq!(reload.applied, [LOG_LEVEL_VALUE]);
+        assert!(reload.restart_required.is_empty());
+    }
+}
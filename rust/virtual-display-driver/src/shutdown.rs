@@ -0,0 +1,280 @@
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, LazyLock, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::sync::Notify;
+
+/// How long the driver waits for its threads when the device goes away
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Signaled when the device is removed or the driver unloads
+///
+/// Like the adapter and the monitor list, this is shared by the whole host process, as the pipe
+/// server is started before the device context can be reached from the adapter
+pub static SHUTDOWN: LazyLock<ShutdownToken> = LazyLock::new(ShutdownToken::default);
+
+/// Where the device is in its life, see [Lifecycle]
+///
+/// Process wide for the same reason as [SHUTDOWN]
+pub static LIFECYCLE: Lifecycle = Lifecycle::new();
+
+/// Tells the threads of the driver to stop, and joins them
+#[derive(Clone, Default)]
+pub struct ShutdownToken(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    signaled: AtomicBool,
+    notify: Notify,
+    threads: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+}
+
+impl ShutdownToken {
+    pub fn is_signaled(&self) -> bool {
+        self.0.signaled.load(Ordering::Acquire)
+    }
+
+    /// Completes once the token is signaled
+    pub async fn signaled(&self) {
+        let notified = self.0.notify.notified();
+        tokio::pin!(notified);
+
+        // register before checking, so a signal in between isn't missed
+        notified.as_mut().enable();
+        if self.is_signaled() {
+            return;
+        }
+
+        notified.await;
+    }
+
+    /// Hands over a thread which stops on its own once the token is signaled, to join it on
+    /// shutdown
+    pub fn register(&self, name: &'static str, thread: JoinHandle<()>) {
+        self.0.threads.lock().unwrap().push((name, thread));
+    }
+
+    pub fn signal(&self) {
+        if !self.0.signaled.swap(true, Ordering::AcqRel) {
+            self.0.notify.notify_waiters();
+        }
+    }
+
+    /// Signals the token and waits up to `timeout` for all registered threads
+    ///
+    /// Threads still running afterwards are left behind, so a stuck thread can't hold up the
+    /// removal of the device. Calling this again only waits for threads registered since
+    pub fn shutdown(&self, timeout: Duration) {
+        self.signal();
+
+        let threads = mem::take(&mut *self.0.threads.lock().unwrap());
+        let deadline = Instant::now() + timeout;
+
+        for (name, thread) in threads {
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            if thread.is_finished() {
+                _ = thread.join();
+                info!("Stopped {name} thread");
+            } else {
+                warn!("The {name} thread did not stop within {timeout:?}, leaving it behind");
+            }
+        }
+    }
+}
+
+/// Stage of the device, it only moves forward
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    /// IddCx is initializing, commands run already so a failed init can be asked about
+    Starting,
+    Running,
+    /// The device goes away, commands are rejected while the ones in flight finish
+    Draining,
+    /// The monitors departed and the threads stopped
+    Stopped,
+}
+
+/// Keeps work touching the monitors away from their teardown
+///
+/// Work takes an [InFlight] with [Lifecycle::enter] while it runs, [Lifecycle::drain] stops new
+/// work and waits for it. Monitors depart only after that, so nothing reaches a departed monitor
+pub struct Lifecycle {
+    // the state and the number of `InFlight` alive
+    state: Mutex<(State, usize)>,
+    idle: Condvar,
+}
+
+impl Lifecycle {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new((State::Starting, 0)),
+            idle: Condvar::new(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.lock().0
+    }
+
+    /// IddCx finished initializing
+    pub fn start(&self) {
+        let mut state = self.lock();
+        if state.0 == State::Starting {
+            state.0 = State::Running;
+        }
+    }
+
+    /// Registers work until the returned guard is dropped, `None` once the device goes away
+    pub fn enter(&self) -> Option<InFlight<'_>> {
+        let mut state = self.lock();
+        if matches!(state.0, State::Draining | State::Stopped) {
+            return None;
+        }
+
+        state.1 += 1;
+        Some(InFlight(self))
+    }
+
+    /// Rejects new work and waits up to `timeout` for the work in flight
+    ///
+    /// Returns whether everything finished. Like [ShutdownToken::shutdown], a stuck command can't
+    /// hold up the removal of the device
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let mut state = self.lock();
+        if state.0 != State::Stopped {
+            state.0 = State::Draining;
+        }
+
+        let (state, _) = self
+            .idle
+            .wait_timeout_while(state, timeout, |(_, in_flight)| *in_flight > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        state.1 == 0
+    }
+
+    /// The teardown finished
+    pub fn stop(&self) {
+        self.lock().0 = State::Stopped;
+    }
+
+    /// Back to [State::Starting], tests tear down the driver they keep using
+    #[cfg(test)]
+    pub fn reset(&self) {
+        self.lock().0 = State::Starting;
+    }
+
+    // a panicking command must not keep the device from going away
+    fn lock(&self) -> MutexGuard<'_, (State, usize)> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Work registered with [Lifecycle::enter]
+#[must_use]
+pub struct InFlight<'a>(&'a Lifecycle);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.1 -= 1;
+
+        if state.1 == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Stands in for a monitor context, which must not be used once departed
+    #[derive(Default)]
+    struct Canary(AtomicBool);
+
+    impl Canary {
+        fn touch(&self) {
+            assert!(!self.0.load(Ordering::SeqCst), "used after drop");
+        }
+
+        fn drop_now(&self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn rejects_work_once_draining() {
+        let lifecycle = Lifecycle::new();
+        assert_eq!(lifecycle.state(), State::Starting);
+        assert!(lifecycle.enter().is_some());
+
+        lifecycle.start();
+        assert_eq!(lifecycle.state(), State::Running);
+
+        assert!(lifecycle.drain(Duration::ZERO));
+        assert_eq!(lifecycle.state(), State::Draining);
+        assert!(lifecycle.enter().is_none());
+
+        lifecycle.stop();
+        lifecycle.start();
+        assert_eq!(lifecycle.state(), State::Stopped);
+        assert!(lifecycle.enter().is_none());
+    }
+
+    #[test]
+    fn drain_times_out_on_stuck_work() {
+        let lifecycle = Lifecycle::new();
+        let in_flight = lifecycle.enter().unwrap();
+
+        assert!(!lifecycle.drain(Duration::from_millis(10)));
+
+        drop(in_flight);
+        assert!(lifecycle.drain(Duration::ZERO));
+    }
+
+    #[test]
+    fn drain_waits_for_work_in_flight() {
+        static LIFECYCLE: Lifecycle = Lifecycle::new();
+        LIFECYCLE.start();
+
+        let canary = Arc::new(Canary::default());
+
+        let workers = (0..8)
+            .map(|_| {
+                let canary = canary.clone();
+                thread::spawn(move || {
+                    let mut ran = 0;
+                    while let Some(_in_flight) = LIFECYCLE.enter() {
+                        canary.touch();
+                        thread::yield_now();
+                        canary.touch();
+                        ran += 1;
+                    }
+                    ran
+                })
+            })
+            .collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(20));
+
+        // like the device going away: drain, then depart the monitors
+        assert!(LIFECYCLE.drain(SHUTDOWN_TIMEOUT));
+        canary.drop_now();
+        LIFECYCLE.stop();
+
+        for worker in workers {
+            assert!(worker.join().unwrap() > 0);
+        }
+    }
+}
@@ -1,58 +1,454 @@
 use std::{
+    ffi::c_void,
+    slice,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use log::{debug, error};
+use driver_ipc::{
+    frame_export::{FrameFormat, FrameRect, MAX_DIRTY_RECTS},
+    Id, Stats,
+};
+use log::{debug, error, warn};
 use wdf_umdf::{
-    IddCxSwapChainFinishedProcessingFrame, IddCxSwapChainReleaseAndAcquireBuffer,
-    IddCxSwapChainSetDevice, WdfObjectDelete,
+    IddCxSwapChainFinishedProcessingFrame, IddCxSwapChainGetDirtyRects,
+    IddCxSwapChainGetMoveRegions, IddCxSwapChainReleaseAndAcquireBuffer, IddCxSwapChainSetDevice,
+    WdfObjectDelete,
 };
 use wdf_umdf_sys::{
-    HANDLE, IDARG_IN_SWAPCHAINSETDEVICE, IDARG_OUT_RELEASEANDACQUIREBUFFER, IDDCX_SWAPCHAIN,
-    NTSTATUS, WAIT_TIMEOUT, WDFOBJECT,
+    HANDLE, IDARG_IN_GETDIRTYRECTS, IDARG_IN_GETMOVEREGIONS, IDARG_IN_SWAPCHAINSETDEVICE,
+    IDARG_OUT_GETDIRTYRECTS, IDARG_OUT_GETMOVEREGIONS, IDARG_OUT_RELEASEANDACQUIREBUFFER,
+    IDDCX_METADATA, IDDCX_MOVEREGION, IDDCX_SWAPCHAIN, NTSTATUS, RECT, WAIT_TIMEOUT, WDFOBJECT,
 };
 use windows::{
     core::{w, Interface},
     Win32::{
         Foundation::HANDLE as WHANDLE,
-        Graphics::Dxgi::IDXGIDevice,
-        System::Threading::{
-            AvRevertMmThreadCharacteristics, AvSetMmThreadCharacteristicsW, WaitForSingleObject,
+        Graphics::{
+            Direct3D11::{
+                ID3D11Texture2D, D3D11_CPU_ACCESS_READ, D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ,
+                D3D11_TEXTURE2D_DESC, D3D11_USAGE_STAGING,
+            },
+            Dxgi::{Common::DXGI_FORMAT_B8G8R8A8_UNORM, IDXGIDevice, IDXGIResource},
+        },
+        System::{
+            Performance::{QueryPerformanceCounter, QueryPerformanceFrequency},
+            Threading::{
+                AvRevertMmThreadCharacteristics, AvSetMmThreadCharacteristicsW, WaitForSingleObject,
+            },
         },
     },
 };
 
-use crate::{direct_3d_device::Direct3DDevice, helpers::Sendable};
+use crate::{
+    direct_3d_device::Direct3DDevice,
+    frame_export::{self, Export},
+    helpers::Sendable,
+    shutdown::SHUTDOWN,
+};
+
+/// Frame counters of one swap chain, updated by its processing thread and read over IPC
+///
+/// These are only statistics, so relaxed atomics are enough, and a reader may see a frame counted
+/// in one counter but not yet in another. Every swap chain gets new counters, which is how they
+/// start over
+#[derive(Debug)]
+pub struct FrameStats {
+    started: Instant,
+    acquired: AtomicU64,
+    completed: AtomicU64,
+    // sum of the latencies of all completed frames
+    latency_total_us: AtomicU64,
+    last_present_qpc: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            acquired: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            latency_total_us: AtomicU64::new(0),
+            last_present_qpc: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    fn acquired(&self, present_qpc: u64) {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.last_present_qpc.store(present_qpc, Ordering::Relaxed);
+    }
+
+    /// Counts a frame handed back to the OS `latency` after it was acquired
+    fn completed(&self, latency: Duration) {
+        let latency_us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+
+        self.latency_total_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a completed frame which didn't reach consumers because of the frame rate limit
+    fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, id: Id) -> Stats {
+        let completed = self.completed.load(Ordering::Relaxed);
+        let latency_total_us = self.latency_total_us.load(Ordering::Relaxed);
+
+        let mut stats = Stats::default();
+        stats.id = id;
+        stats.frames_acquired = self.acquired.load(Ordering::Relaxed);
+        stats.frames_completed = completed;
+        stats.avg_latency_us = average(latency_total_us, completed);
+        stats.last_present_qpc = self.last_present_qpc.load(Ordering::Relaxed);
+        stats.uptime_ms = u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        stats.frames_skipped = self.skipped.load(Ordering::Relaxed);
+        stats
+    }
+}
+
+/// Mean of `count` values summing up to `total`, 0 if there are none
+fn average(total: u64, count: u64) -> u64 {
+    total.checked_div(count).unwrap_or(0)
+}
+
+/// Most frames per second of a monitor which reach consumers, shared by its state and the swap
+/// chain processor
+///
+/// Changed in place, so a new limit applies from the next frame, without a new swap chain
+#[derive(Debug, Default)]
+pub struct FrameRateLimit(AtomicU32);
+
+impl FrameRateLimit {
+    pub fn new(fps: Option<u32>) -> Self {
+        Self(AtomicU32::new(fps.unwrap_or(0)))
+    }
+
+    pub fn set(&self, fps: Option<u32>) {
+        self.0.store(fps.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u32> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            fps => Some(fps),
+        }
+    }
+}
+
+/// Time source frames are paced with, the performance counter or a fake one in tests
+trait Clock {
+    /// Current time in ticks
+    fn now(&self) -> u64;
+
+    /// Ticks per second
+    fn frequency(&self) -> u64;
+}
+
+/// The performance counter, which the present times of the frames are in as well
+struct Qpc {
+    frequency: u64,
+}
+
+impl Qpc {
+    fn new() -> Self {
+        let mut frequency = 0;
+        // can't fail since Windows XP
+        _ = unsafe { QueryPerformanceFrequency(&mut frequency) };
+
+        Self {
+            frequency: u64::try_from(frequency).unwrap_or(0),
+        }
+    }
+}
+
+impl Clock for Qpc {
+    fn now(&self) -> u64 {
+        let mut now = 0;
+        _ = unsafe { QueryPerformanceCounter(&mut now) };
+        u64::try_from(now).unwrap_or(0)
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+/// Picks the frames which reach consumers under a [`FrameRateLimit`]
+struct Pacer<'a, C> {
+    clock: C,
+    limit: &'a FrameRateLimit,
+    // time from which the next frame reaches consumers
+    next: u64,
+}
+
+impl<'a, C: Clock> Pacer<'a, C> {
+    fn new(clock: C, limit: &'a FrameRateLimit) -> Self {
+        Self {
+            clock,
+            limit,
+            next: 0,
+        }
+    }
+
+    /// Whether a frame acquired now reaches consumers
+    fn admit(&mut self) -> bool {
+        let Some(fps) = self.limit.get() else {
+            return true;
+        };
+
+        let interval = self.clock.frequency() / u64::from(fps);
+        let now = self.clock.now();
+
+        // frames up to a quarter interval early count as on time, so jitter of the presents
+        // doesn't halve the rate of a limit dividing the refresh rate
+        if now.saturating_add(interval / 4) < self.next {
+            return false;
+        }
+
+        // the next slot keeps to the grid of the limit, unless frames paused for longer than an
+        // interval, which would let a burst through
+        self.next = if now > self.next.saturating_add(interval) {
+            now.saturating_add(interval)
+        } else {
+            self.next.saturating_add(interval)
+        };
+
+        true
+    }
+}
+
+/// Why frame processing of a swap chain ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exit {
+    /// The swap chain was replaced or unassigned, or the device is going away
+    Terminated = 1,
+    /// The device left D0, the swap chain is kept to process it again on a new D3D device
+    Suspended = 2,
+    /// The swap chain was abandoned, e.g. because its render adapter went away
+    Lost = 3,
+}
+
+/// Request to end processing, checked between frames, so a frame in flight is always handed back
+/// first
+#[derive(Debug, Default)]
+struct Stop(AtomicU8);
+
+impl Stop {
+    /// The first request wins, a terminated processor doesn't become a suspended one
+    fn request(&self, exit: Exit) {
+        _ = self
+            .0
+            .compare_exchange(0, exit as u8, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    fn requested(&self) -> Option<Exit> {
+        match self.0.load(Ordering::Relaxed) {
+            1 => Some(Exit::Terminated),
+            2 => Some(Exit::Suspended),
+            _ if SHUTDOWN.is_signaled() => Some(Exit::Terminated),
+            _ => None,
+        }
+    }
+}
+
+/// Result of trying to acquire the next frame of a swap chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Acquired {
+    /// A frame is held until [`FrameSource::finish`], presented at the given QPC time
+    Frame(u64),
+    /// No new frame yet
+    Pending,
+    /// The swap chain can't be used anymore
+    Lost,
+}
+
+/// Where [`process_frames`] gets its frames from, the swap chain or a stub in tests
+trait FrameSource {
+    fn acquire(&mut self) -> Acquired;
+
+    /// Waits a bit for a new frame, returns `false` if waiting failed
+    fn wait(&mut self) -> bool;
+
+    /// Processes the acquired frame and hands it back, returns `false` if the swap chain was lost
+    ///
+    /// A frame which isn't `deliver`ed is only handed back, without reaching consumers
+    fn finish(&mut self, deliver: bool) -> bool;
+}
+
+/// Processes the frames of `source` until a stop is requested or the swap chain is lost
+///
+/// Frames the `pacer` doesn't admit are handed back right away
+fn process_frames(
+    source: &mut impl FrameSource,
+    stop: &Stop,
+    frames: &AtomicU64,
+    stats: &FrameStats,
+    pacer: &mut Pacer<'_, impl Clock>,
+) -> Exit {
+    loop {
+        match source.acquire() {
+            Acquired::Pending => {
+                let waited = source.wait();
+
+                // thread requested an end, or the device is going away
+                if let Some(exit) = stop.requested() {
+                    return exit;
+                }
+
+                // The wait was cancelled or something unexpected happened
+                if !waited {
+                    return Exit::Lost;
+                }
+            }
+            Acquired::Frame(present_qpc) => {
+                let acquired_at = Instant::now();
+                stats.acquired(present_qpc);
+
+                let deliver = pacer.admit();
+                if !source.finish(deliver) {
+                    return Exit::Lost;
+                }
+
+                frames.fetch_add(1, Ordering::Relaxed);
+                stats.completed(acquired_at.elapsed());
+                if !deliver {
+                    stats.skipped();
+                }
+
+                // frames may keep coming without a pending acquire in between
+                if let Some(exit) = stop.requested() {
+                    return exit;
+                }
+            }
+            // The swap-chain was likely abandoned (e.g. DXGI_ERROR_ACCESS_LOST), so exit the processing loop
+            Acquired::Lost => return Exit::Lost,
+        }
+    }
+}
+
+/// Frames of a swap chain, copied with the D3D device of the processor
+struct SwapChainFrames<'a> {
+    id: Id,
+    swap_chain: IDDCX_SWAPCHAIN,
+    device: &'a Direct3DDevice,
+    available_buffer_event: HANDLE,
+    buffer: IDARG_OUT_RELEASEANDACQUIREBUFFER,
+    // only created once a frame is exported
+    staging: Option<Staging>,
+    // the changed regions are relative to the previous frame, so they only mean something to
+    // a receiver which got that one as well
+    exported_previous: bool,
+}
+
+impl FrameSource for SwapChainFrames<'_> {
+    fn acquire(&mut self) -> Acquired {
+        self.buffer = IDARG_OUT_RELEASEANDACQUIREBUFFER::default();
+        let hr: NTSTATUS = unsafe {
+            IddCxSwapChainReleaseAndAcquireBuffer(self.swap_chain, &mut self.buffer).into()
+        };
+
+        #[allow(clippy::items_after_statements)]
+        const E_PENDING: u32 = 0x8000_000A;
+        if u32::from(hr) == E_PENDING {
+            Acquired::Pending
+        } else if hr.is_success() {
+            Acquired::Frame(self.buffer.MetaData.PresentDisplayQPC)
+        } else {
+            Acquired::Lost
+        }
+    }
+
+    fn wait(&mut self) -> bool {
+        let wait_result =
+            unsafe { WaitForSingleObject(WHANDLE(self.available_buffer_event.cast()), 16).0 };
+
+        // WAIT_OBJECT_0 | WAIT_TIMEOUT, so there may be a new buffer to acquire
+        matches!(wait_result, 0 | WAIT_TIMEOUT)
+    }
+
+    fn finish(&mut self, deliver: bool) -> bool {
+        let metadata = &self.buffer.MetaData;
+
+        // the copy only has to be queued before the surface is handed back, reading it
+        // waits for the GPU, so that happens afterwards
+        let export = frame_export::get(self.id).filter(|_| deliver).filter(|_| {
+            let surface = metadata.pSurface.cast::<c_void>();
+            Staging::copy(self.device, surface, &mut self.staging)
+        });
+
+        // the metadata can only be read while the buffer is held
+        let dirty = export
+            .as_ref()
+            .filter(|_| self.exported_previous)
+            .and_then(|_| changed_rects(self.swap_chain, metadata));
+
+        // This is the most performance-critical section of code in an IddCx driver. It's important that whatever
+        // is done with the acquired surface be finished as quickly as possible.
+        let hr = unsafe { IddCxSwapChainFinishedProcessingFrame(self.swap_chain) };
+
+        if hr.is_err() {
+            return false;
+        }
+
+        self.exported_previous = match (export, &self.staging) {
+            (Some(export), Some(staging)) => staging.export(
+                self.device,
+                &export,
+                metadata.PresentDisplayQPC,
+                dirty.as_deref(),
+            ),
+            _ => false,
+        };
+
+        true
+    }
+}
 
 pub struct SwapChainProcessor {
-    terminate: Arc<AtomicBool>,
-    thread: Option<JoinHandle<()>>,
+    stop: Arc<Stop>,
+    thread: Option<JoinHandle<Exit>>,
+    frame_rate_limit: Arc<FrameRateLimit>,
 }
 
 unsafe impl Send for SwapChainProcessor {}
 unsafe impl Sync for SwapChainProcessor {}
 
 impl SwapChainProcessor {
-    pub fn new() -> Self {
+    /// A processor handing frames to consumers at most as often as `frame_rate_limit` allows
+    pub fn new(frame_rate_limit: Arc<FrameRateLimit>) -> Self {
         Self {
-            terminate: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(Stop::default()),
             thread: None,
+            frame_rate_limit,
         }
     }
 
+    /// Processes the frames of `swap_chain` on a new thread, until the processor is dropped or
+    /// [suspended](Self::suspend)
+    ///
+    /// The swap chain is deleted once processing ends, unless it was suspended
     pub fn run(
         &mut self,
+        id: Id,
         swap_chain: IDDCX_SWAPCHAIN,
         device: Direct3DDevice,
         available_buffer_event: HANDLE,
+        frames: Arc<AtomicU64>,
+        stats: Arc<FrameStats>,
     ) {
         let available_buffer_event = unsafe { Sendable::new(available_buffer_event) };
         let swap_chain = unsafe { Sendable::new(swap_chain) };
-        let terminate = self.terminate.clone();
+        let stop = self.stop.clone();
+        let frame_rate_limit = self.frame_rate_limit.clone();
 
         let join_handle = thread::spawn(move || {
             // It is very important to prioritize this thread by making use of the Multimedia Scheduler Service.
@@ -61,15 +457,28 @@ impl SwapChainProcessor {
             let res = unsafe { AvSetMmThreadCharacteristicsW(w!("Distribution"), &mut av_task) };
             let Ok(av_handle) = res else {
                 error!("Failed to prioritize thread: {res:?}");
-                return;
+                return Exit::Lost;
             };
 
-            Self::run_core(*swap_chain, &device, *available_buffer_event, &terminate);
+            let exit = Self::run_core(
+                id,
+                *swap_chain,
+                &device,
+                *available_buffer_event,
+                &stop,
+                &frames,
+                &stats,
+                &frame_rate_limit,
+            );
 
-            let res = unsafe { WdfObjectDelete(*swap_chain as WDFOBJECT) };
-            if let Err(e) = res {
-                error!("Failed to delete wdf object: {e:?}");
-                return;
+            // the D3D device is released with the thread, a suspended swap chain gets a new one
+            // once the device is back in D0
+            if exit != Exit::Suspended {
+                let res = unsafe { WdfObjectDelete(*swap_chain as WDFOBJECT) };
+                if let Err(e) = res {
+                    error!("Failed to delete wdf object: {e:?}");
+                    return exit;
+                }
             }
 
             // Revert the thread to normal once it's done
@@ -77,21 +486,41 @@ impl SwapChainProcessor {
             if let Err(e) = res {
                 error!("Failed to revert prioritize thread: {e:?}");
             }
+
+            exit
         });
 
         self.thread = Some(join_handle);
     }
 
+    /// Stops processing after the frame in flight and releases the D3D device, keeping the swap
+    /// chain to process it again with [run](Self::run)
+    ///
+    /// Returns whether the swap chain was kept, it's gone if processing ended before
+    pub fn suspend(mut self) -> bool {
+        let Some(handle) = self.thread.take() else {
+            return false;
+        };
+
+        self.stop.request(Exit::Suspended);
+        matches!(handle.join(), Ok(Exit::Suspended))
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn run_core(
+        id: Id,
         swap_chain: IDDCX_SWAPCHAIN,
         device: &Direct3DDevice,
         available_buffer_event: HANDLE,
-        terminate: &AtomicBool,
-    ) {
+        stop: &Stop,
+        frames: &AtomicU64,
+        stats: &FrameStats,
+        frame_rate_limit: &FrameRateLimit,
+    ) -> Exit {
         let dxgi_device = device.device.cast::<IDXGIDevice>();
         let Ok(dxgi_device) = dxgi_device else {
             error!("Failed to cast ID3D11Device to IDXGIDevice: {dxgi_device:?}");
-            return;
+            return Exit::Lost;
         };
 
         let set_device = IDARG_IN_SWAPCHAINSETDEVICE {
@@ -101,47 +530,202 @@ impl SwapChainProcessor {
         let res = unsafe { IddCxSwapChainSetDevice(swap_chain, &set_device) };
         if res.is_err() {
             debug!("Failed to set swapchain device: {res:?}");
-            return;
+            return Exit::Lost;
         }
 
-        loop {
-            let mut buffer = IDARG_OUT_RELEASEANDACQUIREBUFFER::default();
-            let hr: NTSTATUS =
-                unsafe { IddCxSwapChainReleaseAndAcquireBuffer(swap_chain, &mut buffer).into() };
+        let mut source = SwapChainFrames {
+            id,
+            swap_chain,
+            device,
+            available_buffer_event,
+            buffer: IDARG_OUT_RELEASEANDACQUIREBUFFER::default(),
+            staging: None,
+            exported_previous: false,
+        };
+
+        let mut pacer = Pacer::new(Qpc::new(), frame_rate_limit);
+        process_frames(&mut source, stop, frames, stats, &mut pacer)
+    }
+}
 
-            #[allow(clippy::items_after_statements)]
-            const E_PENDING: u32 = 0x8000_000A;
-            if u32::from(hr) == E_PENDING {
-                let wait_result =
-                    unsafe { WaitForSingleObject(WHANDLE(available_buffer_event.cast()), 16).0 };
+/// Regions which changed in the acquired frame, `None` if the whole frame has to be treated as
+/// changed
+///
+/// A moved region counts with its destination, since receivers get whole frames anyway
+fn changed_rects(swap_chain: IDDCX_SWAPCHAIN, metadata: &IDDCX_METADATA) -> Option<Vec<FrameRect>> {
+    let dirty_count = metadata.DirtyRectCount;
+    let move_count = metadata.MoveRegionCount;
 
-                // thread requested an end
-                let should_terminate = terminate.load(Ordering::Relaxed);
-                if should_terminate {
-                    break;
-                }
+    // the OS didn't tell, or there are more than the export holds
+    let total = dirty_count as usize + move_count as usize;
+    if total == 0 || total > MAX_DIRTY_RECTS {
+        return None;
+    }
 
-                // WAIT_OBJECT_0 | WAIT_TIMEOUT
-                if matches!(wait_result, 0 | WAIT_TIMEOUT) {
-                    // We have a new buffer, so try the AcquireBuffer again
-                    continue;
-                }
+    let mut dirty = vec![RECT::default(); dirty_count as usize];
+    if dirty_count > 0 {
+        let in_args = IDARG_IN_GETDIRTYRECTS {
+            DirtyRectInCount: dirty_count,
+            pDirtyRects: dirty.as_mut_ptr(),
+        };
+        let mut out_args = IDARG_OUT_GETDIRTYRECTS::default();
+        unsafe { IddCxSwapChainGetDirtyRects(swap_chain, &in_args, &mut out_args) }.ok()?;
+        dirty.truncate(out_args.DirtyRectOutCount as usize);
+    }
 
-                // The wait was cancelled or something unexpected happened
-                break;
-            } else if hr.is_success() {
-                // This is the most performance-critical section of code in an IddCx driver. It's important that whatever
-                // is done with the acquired surface be finished as quickly as possible.
-                let hr = unsafe { IddCxSwapChainFinishedProcessingFrame(swap_chain) };
-
-                if hr.is_err() {
-                    break;
+    let mut moves = vec![IDDCX_MOVEREGION::default(); move_count as usize];
+    if move_count > 0 {
+        let in_args = IDARG_IN_GETMOVEREGIONS {
+            MoveRegionInCount: move_count,
+            pMoveRegions: moves.as_mut_ptr(),
+        };
+        let mut out_args = IDARG_OUT_GETMOVEREGIONS::default();
+        unsafe { IddCxSwapChainGetMoveRegions(swap_chain, &in_args, &mut out_args) }.ok()?;
+        moves.truncate(out_args.MoveRegionOutCount as usize);
+    }
+
+    let rects = dirty
+        .iter()
+        .chain(moves.iter().map(|region| &region.DestRect))
+        .map(|rect| FrameRect {
+            left: rect.left,
+            top: rect.top,
+            right: rect.right,
+            bottom: rect.bottom,
+        })
+        .collect();
+
+    Some(rects)
+}
+
+/// CPU readable copy of the surface, for the frame export of the monitor
+struct Staging {
+    texture: ID3D11Texture2D,
+    desc: D3D11_TEXTURE2D_DESC,
+}
+
+impl Staging {
+    /// Queues a copy of `surface` into `staging`, which is recreated when the surface changed size
+    ///
+    /// Returns whether there is a copy to export
+    fn copy(device: &Direct3DDevice, surface: *mut c_void, staging: &mut Option<Self>) -> bool {
+        let Some(surface) = (unsafe { IDXGIResource::from_raw_borrowed(&surface) }) else {
+            return false;
+        };
+
+        let texture = match surface.cast::<ID3D11Texture2D>() {
+            Ok(texture) => texture,
+            Err(e) => {
+                error!("Failed to cast the surface to ID3D11Texture2D: {e:?}");
+                return false;
+            }
+        };
+
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { texture.GetDesc(&mut desc) };
+
+        // the export promises BGRA pixels, other formats are skipped instead of converted
+        if desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM {
+            debug!("Not exporting a frame of format {:?}", desc.Format);
+            return false;
+        }
+
+        let reusable = staging.as_ref().is_some_and(|staging| {
+            (staging.desc.Width, staging.desc.Height) == (desc.Width, desc.Height)
+        });
+
+        if !reusable {
+            *staging = None;
+
+            #[allow(clippy::cast_sign_loss)]
+            let staging_desc = D3D11_TEXTURE2D_DESC {
+                MipLevels: 1,
+                ArraySize: 1,
+                Usage: D3D11_USAGE_STAGING,
+                BindFlags: 0,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ.0 as u32,
+                MiscFlags: 0,
+                ..desc
+            };
+
+            let mut texture = None;
+            let res = unsafe {
+                device
+                    .device
+                    .CreateTexture2D(&staging_desc, None, Some(&mut texture))
+            };
+            match (res, texture) {
+                (Ok(()), Some(texture)) => {
+                    *staging = Some(Self {
+                        texture,
+                        desc: staging_desc,
+                    });
+                }
+                (res, _) => {
+                    error!("Failed to create the staging texture: {res:?}");
+                    return false;
                 }
-            } else {
-                // The swap-chain was likely abandoned (e.g. DXGI_ERROR_ACCESS_LOST), so exit the processing loop
-                break;
             }
         }
+
+        let Some(staging) = staging else {
+            return false;
+        };
+
+        unsafe {
+            device
+                .device_context
+                .CopyResource(&staging.texture, &texture)
+        };
+        true
+    }
+
+    /// Reads back the copy and writes it into `export`, with `dirty` as the regions which changed
+    ///
+    /// Returns whether the frame was exported
+    fn export(
+        &self,
+        device: &Direct3DDevice,
+        export: &Mutex<Export>,
+        qpc: u64,
+        dirty: Option<&[FrameRect]>,
+    ) -> bool {
+        let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+        let res = unsafe {
+            device
+                .device_context
+                .Map(&self.texture, 0, D3D11_MAP_READ, 0, Some(&mut mapped))
+        };
+        if let Err(e) = res {
+            error!("Failed to map the staging texture: {e:?}");
+            return false;
+        }
+
+        let format = FrameFormat {
+            width: self.desc.Width,
+            height: self.desc.Height,
+            stride: self.desc.Width * 4,
+        };
+
+        let row_len = format.stride as usize;
+        let pitch = mapped.RowPitch as usize;
+        // the last row isn't padded to the pitch
+        let len = pitch * (format.height as usize).saturating_sub(1) + row_len;
+        let pixels = unsafe { slice::from_raw_parts(mapped.pData.cast::<u8>(), len) };
+
+        let res = export.lock().unwrap().write(format, qpc, dirty, |data| {
+            for (dst, src) in data.chunks_exact_mut(row_len).zip(pixels.chunks(pitch)) {
+                dst.copy_from_slice(&src[..row_len]);
+            }
+        });
+
+        unsafe { device.device_context.Unmap(&self.texture, 0) };
+
+        if let Err(e) = &res {
+            warn!("Failed to export a frame: {e:?}");
+        }
+
+        res.is_ok()
     }
 }
 
@@ -149,10 +733,266 @@ impl Drop for SwapChainProcessor {
     fn drop(&mut self) {
         if let Some(handle) = self.thread.take() {
             // send signal to end thread
-            self.terminate.store(true, Ordering::Relaxed);
+            self.stop.request(Exit::Terminated);
 
             // wait until thread is finished
             _ = handle.join();
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, collections::VecDeque};
+
+    use super::*;
+
+    static NO_LIMIT: FrameRateLimit = FrameRateLimit(AtomicU32::new(0));
+
+    /// Ticks 600 times a second, so 60 Hz frames are 10 ticks apart. Every look at it moves it on
+    /// by `step`
+    #[derive(Default)]
+    struct FakeClock {
+        now: Cell<u64>,
+        step: u64,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            let now = self.now.get();
+            self.now.set(now + self.step);
+            now
+        }
+
+        fn frequency(&self) -> u64 {
+            600
+        }
+    }
+
+    fn unpaced() -> Pacer<'static, FakeClock> {
+        Pacer::new(FakeClock::default(), &NO_LIMIT)
+    }
+
+    /// The times out of `times` at which frames are admitted
+    fn admitted(pacer: &mut Pacer<'_, FakeClock>, times: &[u64]) -> Vec<u64> {
+        times
+            .iter()
+            .copied()
+            .filter(|&time| {
+                pacer.clock.now.set(time);
+                pacer.admit()
+            })
+            .collect()
+    }
+
+    /// Hands out scripted frames, and keeps waiting for more once they're gone
+    struct StubFrames<'a> {
+        frames: VecDeque<Acquired>,
+        finished: u32,
+        delivered: u32,
+        waits: u32,
+        // called while a frame is held, with the frames finished before it
+        on_finish: &'a dyn Fn(u32) -> bool,
+    }
+
+    impl<'a> StubFrames<'a> {
+        fn new(frames: &[Acquired], on_finish: &'a dyn Fn(u32) -> bool) -> Self {
+            Self {
+                frames: frames.iter().copied().collect(),
+                finished: 0,
+                delivered: 0,
+                waits: 0,
+                on_finish,
+            }
+        }
+    }
+
+    impl FrameSource for StubFrames<'_> {
+        fn acquire(&mut self) -> Acquired {
+            self.frames.pop_front().unwrap_or(Acquired::Pending)
+        }
+
+        // fails eventually, so a test missing its stop ends instead of hanging
+        fn wait(&mut self) -> bool {
+            self.waits += 1;
+            self.waits < 100
+        }
+
+        fn finish(&mut self, deliver: bool) -> bool {
+            let kept = (self.on_finish)(self.finished);
+            self.finished += 1;
+            self.delivered += u32::from(deliver);
+            kept
+        }
+    }
+
+    #[test]
+    fn suspends_after_the_frame_in_flight() {
+        let stop = Stop::default();
+        let (frames, stats) = (AtomicU64::new(0), FrameStats::new());
+
+        // the device leaves D0 while the first frame is processed
+        let on_finish = |finished| {
+            if finished == 0 {
+                stop.request(Exit::Suspended);
+            }
+            true
+        };
+        let mut source = StubFrames::new(
+            &[Acquired::Frame(1), Acquired::Frame(2), Acquired::Frame(3)],
+            &on_finish,
+        );
+
+        let exit = process_frames(&mut source, &stop, &frames, &stats, &mut unpaced());
+        assert_eq!(exit, Exit::Suspended);
+
+        // the frame was handed back, and no other one acquired
+        assert_eq!(source.finished, 1);
+        assert_eq!(source.frames.len(), 2);
+        assert_eq!(frames.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.snapshot(0).frames_completed, 1);
+    }
+
+    #[test]
+    fn stops_while_waiting_for_frames() {
+        let stop = Stop::default();
+        let (frames, stats) = (AtomicU64::new(0), FrameStats::new());
+        let on_finish = |_| true;
+
+        // without a stop, only a failed wait ends it
+        let mut source = StubFrames::new(&[Acquired::Frame(1)], &on_finish);
+        assert_eq!(
+            process_frames(&mut source, &stop, &frames, &stats, &mut unpaced()),
+            Exit::Lost
+        );
+        assert_eq!(source.finished, 1);
+        assert_eq!(source.waits, 100);
+
+        // a suspension doesn't turn a terminated processor into one to resume
+        stop.request(Exit::Terminated);
+        stop.request(Exit::Suspended);
+
+        let mut source = StubFrames::new(&[], &on_finish);
+        assert_eq!(
+            process_frames(&mut source, &stop, &frames, &stats, &mut unpaced()),
+            Exit::Terminated
+        );
+        assert_eq!(source.waits, 1);
+    }
+
+    #[test]
+    fn ends_when_the_swap_chain_is_lost() {
+        let stop = Stop::default();
+        let (frames, stats) = (AtomicU64::new(0), FrameStats::new());
+        let on_finish = |finished| finished == 0;
+
+        let mut source = StubFrames::new(&[Acquired::Frame(1), Acquired::Lost], &on_finish);
+        assert_eq!(
+            process_frames(&mut source, &stop, &frames, &stats, &mut unpaced()),
+            Exit::Lost
+        );
+        assert_eq!(frames.load(Ordering::Relaxed), 1);
+
+        // handing a frame back failed
+        let mut source = StubFrames::new(&[Acquired::Frame(1), Acquired::Frame(2)], &on_finish);
+        assert_eq!(
+            process_frames(&mut source, &stop, &frames, &stats, &mut unpaced()),
+            Exit::Lost
+        );
+        assert_eq!(source.finished, 2);
+        assert_eq!(frames.load(Ordering::Relaxed), 2);
+        // acquired, but never completed
+        assert_eq!(stats.snapshot(0).frames_acquired, 3);
+        assert_eq!(stats.snapshot(0).frames_completed, 2);
+    }
+
+    #[test]
+    fn limits_frames_reaching_consumers() {
+        let stop = Stop::default();
+        let (frames, stats) = (AtomicU64::new(0), FrameStats::new());
+        let limit = FrameRateLimit::new(Some(30));
+        let clock = FakeClock {
+            step: 10,
+            ..FakeClock::default()
+        };
+        let mut pacer = Pacer::new(clock, &limit);
+
+        // the monitor runs at 60 Hz, every other frame is handed back right away
+        let on_finish = |_| true;
+        let mut source = StubFrames::new(&[Acquired::Frame(1); 6], &on_finish);
+        assert_eq!(
+            process_frames(&mut source, &stop, &frames, &stats, &mut pacer),
+            Exit::Lost
+        );
+        assert_eq!(source.finished, 6);
+        assert_eq!(source.delivered, 3);
+        assert_eq!(frames.load(Ordering::Relaxed), 6);
+
+        let snapshot = stats.snapshot(0);
+        assert_eq!(snapshot.frames_completed, 6);
+        assert_eq!(snapshot.frames_skipped, 3);
+
+        // a new limit applies to the next frame, on the same swap chain
+        limit.set(None);
+        let mut source = StubFrames::new(&[Acquired::Frame(1); 4], &on_finish);
+        process_frames(&mut source, &stop, &frames, &stats, &mut pacer);
+        assert_eq!(source.delivered, 4);
+        assert_eq!(stats.snapshot(0).frames_skipped, 3);
+    }
+
+    #[test]
+    fn paces_on_the_grid_of_the_limit() {
+        let limit = FrameRateLimit::new(Some(30));
+        let mut pacer = Pacer::new(FakeClock::default(), &limit);
+
+        // presents jitter by a tick or two around 60 Hz, which still keeps every other frame
+        assert_eq!(
+            admitted(&mut pacer, &[0, 11, 19, 31, 39, 50, 61, 70]),
+            [0, 19, 39, 61]
+        );
+
+        // after a pause, frames don't catch up in a burst
+        assert_eq!(admitted(&mut pacer, &[500, 510, 520, 530]), [500, 520]);
+
+        // a limit above the refresh rate lets every frame through
+        limit.set(Some(120));
+        assert_eq!(
+            admitted(&mut pacer, &[600, 610, 620, 630]),
+            [600, 610, 620, 630]
+        );
+
+        limit.set(Some(20));
+        assert_eq!(
+            admitted(&mut pacer, &[700, 710, 720, 730, 740, 750, 760]),
+            [700, 730, 760]
+        );
+    }
+
+    #[test]
+    fn averages_latency_of_completed_frames() {
+        let stats = FrameStats::new();
+        assert_eq!(stats.snapshot(0).avg_latency_us, 0);
+
+        for latency in [100, 200, 600] {
+            stats.acquired(latency);
+            stats.completed(Duration::from_micros(latency));
+        }
+        // still being processed, so not part of the average
+        stats.acquired(700);
+
+        let snapshot = stats.snapshot(3);
+        assert_eq!(snapshot.id, 3);
+        assert_eq!(snapshot.frames_acquired, 4);
+        assert_eq!(snapshot.frames_completed, 3);
+        assert_eq!(snapshot.avg_latency_us, 300);
+        assert_eq!(snapshot.last_present_qpc, 700);
+    }
+
+    #[test]
+    fn average_rounds_down() {
+        assert_eq!(average(0, 0), 0);
+        assert_eq!(average(10, 0), 0);
+        assert_eq!(average(10, 4), 2);
+        assert_eq!(average(u64::MAX, 1), u64::MAX);
+    }
+}
@@ -0,0 +1,859 @@
+//! Runs the driver against the fake frameworks of `wdf_umdf::testing`, with the pipe server in the
+//! test process, so the monitor lifecycle can be tested through a real client.
+//!
+//! The driver state is global like in the driver host, so it is set up once and reset by every
+//! [Harness::start]. IddCx calls are recorded by the fakes, see [Harness::calls].
+
+use std::{ptr::NonNull, sync::Once, thread, time::Duration};
+
+use driver_ipc::{persist, Client};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use wdf_umdf::testing::{self, Fakes, IddCxCall};
+use wdf_umdf_sys::WDFDEVICE;
+
+use crate::{
+    context::DeviceContext,
+    ipc::{self, AdapterObject, ADAPTER, MONITOR_MODES},
+    shutdown::LIFECYCLE,
+};
+
+const PIPE_NAME: &str = "virtualdisplaydriver-test-driver";
+
+/// How often [Harness::client] tries to connect while the pipe server is starting
+const CONNECT_ATTEMPTS: u32 = 100;
+
+/// The driver with its fakes, tests using it run one after another
+pub struct Harness {
+    _fakes: Fakes,
+}
+
+impl Harness {
+    /// Starts the driver on first use, and resets it to no monitors and none saved
+    pub fn start() -> Self {
+        static STARTED: Once = Once::new();
+
+        let fakes = testing::install();
+        STARTED.call_once(init);
+
+        // the monitors of the last test are dropped without departing, like in a new driver host
+        MONITOR_MODES.lock().unwrap().clear();
+
+        // a test may have torn the driver down, its pipe server keeps running for the next ones
+        LIFECYCLE.reset();
+        LIFECYCLE.start();
+
+        let (root, key) = ipc::saved_monitors_key();
+        persist::clear(&root, key).unwrap();
+        persist::save_defaults(&root, key, &[]).unwrap();
+
+        _ = testing::take_calls();
+
+        Self { _fakes: fakes }
+    }
+
+    /// Connects a client to the pipe server
+    pub async fn client(&self) -> Client {
+        for _ in 0..CONNECT_ATTEMPTS {
+            if let Ok(client) = Client::connect_to(PIPE_NAME).await {
+                return client;
+            }
+
+            // the pipe server thread didn't create its pipe yet
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Failed to connect to {PIPE_NAME}");
+    }
+
+    /// Opens the pipe without a client, to send what a client wouldn't
+    pub async fn pipe(&self) -> NamedPipeClient {
+        let path = format!(r"\\.\pipe\{PIPE_NAME}");
+
+        for _ in 0..CONNECT_ATTEMPTS {
+            if let Ok(pipe) = ClientOptions::new().open(&path) {
+                return pipe;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Failed to open {path}");
+    }
+
+    /// The IddCx calls made since the last time, oldest first
+    pub fn calls(&self) -> Vec<IddCxCall> {
+        testing::take_calls()
+    }
+
+    /// Starts the driver again, which restores the saved monitors
+    pub fn restart(&self) {
+        MONITOR_MODES.lock().unwrap().clear();
+        ipc::restore();
+    }
+}
+
+// What `device_d0_entry` and `adapter_init_finished` do, the fake adapter is ready right away
+fn init() {
+    let device: WDFDEVICE = testing::fake_object().cast();
+
+    unsafe { DeviceContext::new(device).init(device.cast()) }.unwrap();
+    unsafe { DeviceContext::get_mut(device.cast(), |context| context.init_adapter().unwrap()) }
+        .unwrap();
+
+    let adapter = testing::take_calls()
+        .into_iter()
+        .find_map(|call| match call {
+            IddCxCall::AdapterInitAsync { adapter } => Some(adapter),
+            _ => None,
+        })
+        .unwrap();
+
+    ADAPTER
+        .set(AdapterObject(NonNull::new(adapter as *mut _).unwrap()))
+        .unwrap();
+
+    ipc::init_succeeded();
+    ipc::start_pipe_server(PIPE_NAME);
+}
+
+mod test {
+    use std::collections::HashSet;
+
+    use driver_ipc::{
+        error::RequestError,
+        framing::{FrameDecoder, Framing},
+        stable_id, AdapterConfig, ClientCommand, DriverCommand, Envelope, ErrorCode, EventCommand,
+        Id, Mode, Monitor, MonitorBuilder, RefreshRate, ReplyCommand, RequestCommand,
+        ServerCommand, SessionScope, PROTOCOL_VERSION,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        task,
+    };
+
+    use super::*;
+    use crate::{
+        settings::MAX_MONITORS,
+        shutdown::{State, SHUTDOWN_TIMEOUT},
+    };
+
+    fn monitor(id: Id, refresh_rate: RefreshRate) -> Monitor {
+        MonitorBuilder::new(id)
+            .mode(1920, 1080, &[refresh_rate])
+            .build()
+            .unwrap()
+    }
+
+    /// The monitor created and arrived by `calls`, panics if they are anything else
+    fn arrived(calls: &[IddCxCall], connector: u32) -> usize {
+        let [IddCxCall::MonitorCreate {
+            monitor,
+            connector_index,
+            edid,
+            ..
+        }, IddCxCall::MonitorArrival { monitor: arrived }] = calls
+        else {
+            panic!("expected a monitor to be created and arrived, got {calls:?}");
+        };
+
+        assert_eq!(*connector_index, connector);
+        assert_eq!(arrived, monitor);
+        assert!(!edid.is_empty());
+
+        *monitor
+    }
+
+    async fn send(pipe: &mut NamedPipeClient, message: &[u8]) {
+        let frame = Framing::LengthPrefixed.encode(message.to_vec()).unwrap();
+        pipe.write_all(&frame).await.unwrap();
+    }
+
+    async fn receive(
+        pipe: &mut NamedPipeClient,
+        decoder: &mut FrameDecoder,
+    ) -> Envelope<ClientCommand> {
+        loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                return Envelope::parse(&frame).unwrap();
+            }
+
+            let mut buf = [0; 4096];
+            let len = pipe.read(&mut buf).await.unwrap();
+            assert_ne!(len, 0, "the driver closed the connection");
+            decoder.push(&buf[..len]);
+        }
+    }
+
+    fn error_code(result: Result<(), RequestError>) -> ErrorCode {
+        match result {
+            Err(RequestError::Ipc(e)) => e.code,
+            other => panic!("expected the driver to reject the command, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_and_remove() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60)]))
+            .await
+            .unwrap();
+        let handle = arrived(&driver.calls(), 0);
+
+        client
+            .execute(&DriverCommand::Remove(vec![0]))
+            .await
+            .unwrap();
+        assert_eq!(
+            driver.calls(),
+            [IddCxCall::MonitorDeparture { monitor: handle }]
+        );
+    }
+
+    #[tokio::test]
+    async fn add_duplicate_id() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        let result = client
+            .execute(&DriverCommand::Notify(vec![
+                monitor(1, 60),
+                monitor(1, 144),
+            ]))
+            .await;
+
+        assert_eq!(error_code(result), ErrorCode::InvalidMonitors);
+        assert!(driver.calls().is_empty());
+        assert!(client.request_state().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn add_beyond_limit() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        let monitors = (0..=*MAX_MONITORS).map(|id| monitor(id, 60)).collect();
+        let result = client.execute(&DriverCommand::Notify(monitors)).await;
+
+        assert_eq!(error_code(result), ErrorCode::MonitorLimitReached);
+        assert!(driver.calls().is_empty());
+        assert!(client.request_state().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_missing() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60)]))
+            .await
+            .unwrap();
+        arrived(&driver.calls(), 0);
+
+        let result = client.execute(&DriverCommand::Remove(vec![9])).await;
+
+        assert_eq!(error_code(result), ErrorCode::MonitorNotFound);
+        assert!(driver.calls().is_empty());
+        let monitors = client.request_state().await.unwrap();
+        assert_eq!(monitors.iter().map(|m| m.id).collect::<Vec<_>>(), [0]);
+    }
+
+    // IddCx reads the modes on arrival, so changing them replugs the monitor on its connector
+    #[tokio::test]
+    async fn mode_update() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60)]))
+            .await
+            .unwrap();
+        let old = arrived(&driver.calls(), 0);
+
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 144)]))
+            .await
+            .unwrap();
+
+        let calls = driver.calls();
+        assert_eq!(calls[0], IddCxCall::MonitorDeparture { monitor: old });
+        let new = arrived(&calls[1..], 0);
+        assert_ne!(new, old);
+    }
+
+    // clients find the display of a monitor by the target IddCx reported on arrival
+    #[tokio::test]
+    async fn arrival_target_is_reported() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60), monitor(1, 60)]))
+            .await
+            .unwrap();
+
+        let states = client.state().await.unwrap();
+        let targets = states
+            .iter()
+            .map(|state| state.os_target.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(targets.len(), 2);
+        assert!(targets
+            .iter()
+            .all(|target| target.adapter.low_part == testing::OS_ADAPTER_LUID));
+        assert_ne!(targets[0].target_id, targets[1].target_id);
+    }
+
+    #[tokio::test]
+    async fn persistence_reload() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        let command = DriverCommand::WithPersistence {
+            command: Box::new(DriverCommand::Notify(vec![monitor(3, 60)])),
+            persistence: true,
+        };
+        client.execute(&command).await.unwrap();
+        arrived(&driver.calls(), 0);
+
+        driver.restart();
+
+        arrived(&driver.calls(), 0);
+        assert_eq!(client.request_state().await.unwrap(), [monitor(3, 60)]);
+    }
+
+    #[tokio::test]
+    async fn boot_defaults_without_saved_monitors() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client.set_boot_defaults(&[monitor(4, 60)]).await.unwrap();
+        assert!(driver.calls().is_empty());
+        assert_eq!(client.boot_defaults().await.unwrap(), [monitor(4, 60)]);
+
+        driver.restart();
+        arrived(&driver.calls(), 0);
+        assert_eq!(client.request_state().await.unwrap(), [monitor(4, 60)]);
+
+        // saved monitors win over the defaults
+        client
+            .execute(&DriverCommand::WithPersistence {
+                command: Box::new(DriverCommand::Notify(vec![monitor(5, 60)])),
+                persistence: true,
+            })
+            .await
+            .unwrap();
+        _ = driver.calls();
+
+        driver.restart();
+        arrived(&driver.calls(), 0);
+        assert_eq!(client.request_state().await.unwrap(), [monitor(5, 60)]);
+
+        let result = client
+            .set_boot_defaults(&[monitor(6, 60), monitor(6, 144)])
+            .await;
+        assert_eq!(error_code(result), ErrorCode::InvalidMonitors);
+        assert_eq!(client.boot_defaults().await.unwrap(), [monitor(4, 60)]);
+    }
+
+    #[tokio::test]
+    async fn adapter_config_for_next_start() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client
+            .execute(&DriverCommand::WithPersistence {
+                command: Box::new(DriverCommand::Notify(vec![monitor(5, 60)])),
+                persistence: true,
+            })
+            .await
+            .unwrap();
+        _ = driver.calls();
+
+        let (root, key) = ipc::saved_monitors_key();
+        let key = root.open_subkey(key).unwrap();
+
+        let config = AdapterConfig::Static {
+            connectors: 2,
+            modes: Vec::new(),
+        };
+        client.set_adapter_config(&config).await.unwrap();
+        assert!(driver.calls().is_empty());
+        assert_eq!(key.get_value::<u32, _>("StaticConnectors").unwrap(), 2);
+        assert!(key.get_value::<String, _>("StaticModes").is_err());
+
+        for config in [
+            AdapterConfig::Static {
+                connectors: 0,
+                modes: Vec::new(),
+            },
+            AdapterConfig::Static {
+                connectors: 1,
+                modes: vec![Mode {
+                    width: 0,
+                    height: 1080,
+                    refresh_rates: vec![60],
+                    timing: None,
+                }],
+            },
+        ] {
+            assert!(client.set_adapter_config(&config).await.is_err());
+        }
+        assert_eq!(key.get_value::<u32, _>("StaticConnectors").unwrap(), 2);
+
+        // the saved monitors are left alone
+        driver.restart();
+        arrived(&driver.calls(), 0);
+        assert_eq!(client.request_state().await.unwrap(), [monitor(5, 60)]);
+
+        client
+            .set_adapter_config(&AdapterConfig::Dynamic)
+            .await
+            .unwrap();
+        assert!(key.get_value::<u32, _>("StaticConnectors").is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_message() {
+        let driver = Harness::start();
+        let mut pipe = driver.pipe().await;
+        let mut decoder = FrameDecoder::with_framing(Framing::LengthPrefixed);
+
+        let hello = ServerCommand::Request(RequestCommand::Hello {
+            protocol: PROTOCOL_VERSION,
+            client_name: "test".to_owned(),
+        });
+        send(&mut pipe, &serde_json::to_vec(&hello).unwrap()).await;
+        let reply = receive(&mut pipe, &mut decoder).await;
+        assert!(matches!(
+            reply.command,
+            ClientCommand::Reply(ReplyCommand::HelloReply { .. })
+        ));
+
+        // the reason is the one of the command, not of the envelope around it
+        for (message, id, reason) in [
+            (&b"\x00\xff{garbage"[..], None, None),
+            (
+                &br#"{"id":7,"Bogus":[1]}"#[..],
+                Some(7),
+                Some("unknown command `Bogus`"),
+            ),
+        ] {
+            send(&mut pipe, message).await;
+
+            let reply = receive(&mut pipe, &mut decoder).await;
+            assert_eq!(reply.id, id);
+            let ClientCommand::Reply(ReplyCommand::Error {
+                code,
+                message,
+                source_id,
+            }) = &reply.command
+            else {
+                panic!("expected an error reply, got {reply:?}");
+            };
+            assert_eq!(*code, ErrorCode::ParseError);
+            assert_eq!(*source_id, id);
+            assert!(!message.is_empty());
+            if let Some(reason) = reason {
+                assert_eq!(message, reason);
+            }
+        }
+
+        // the connection is still usable
+        let state = Envelope::new(Some(8), ServerCommand::Request(RequestCommand::State));
+        send(&mut pipe, &serde_json::to_vec(&state).unwrap()).await;
+        let reply = receive(&mut pipe, &mut decoder).await;
+        assert_eq!(reply.id, Some(8));
+        assert!(matches!(
+            reply.command,
+            ClientCommand::Reply(ReplyCommand::State(_))
+        ));
+
+        // and so is the driver
+        let client = driver.client().await;
+        assert!(client.request_state().await.unwrap().is_empty());
+        assert!(driver.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sleep_is_pushed_to_subscribers() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60)]))
+            .await
+            .unwrap();
+        arrived(&driver.calls(), 0);
+
+        let mut pipe = driver.pipe().await;
+        let mut decoder = FrameDecoder::with_framing(Framing::LengthPrefixed);
+
+        let hello = ServerCommand::Request(RequestCommand::Hello {
+            protocol: PROTOCOL_VERSION,
+            client_name: "test".to_owned(),
+        });
+        send(&mut pipe, &serde_json::to_vec(&hello).unwrap()).await;
+        receive(&mut pipe, &mut decoder).await;
+
+        let subscribe = ServerCommand::Request(RequestCommand::Subscribe);
+        send(&mut pipe, &serde_json::to_vec(&subscribe).unwrap()).await;
+        let reply = receive(&mut pipe, &mut decoder).await;
+        assert!(matches!(
+            reply.command,
+            ClientCommand::Reply(ReplyCommand::Subscribed)
+        ));
+
+        ipc::suspend_monitors();
+        let event = receive(&mut pipe, &mut decoder).await;
+        assert!(matches!(
+            event.command,
+            ClientCommand::Event(EventCommand::Suspended)
+        ));
+
+        // the monitor never got a swap chain, so there's nothing to process
+        assert_eq!(ipc::resume_monitors(), [0]);
+        let event = receive(&mut pipe, &mut decoder).await;
+        assert!(matches!(
+            event.command,
+            ClientCommand::Event(EventCommand::Resumed)
+        ));
+
+        // the monitors stay attached while the device sleeps
+        assert!(driver.calls().is_empty());
+        let monitors = client.request_state().await.unwrap();
+        assert_eq!(monitors.iter().map(|m| m.id).collect::<Vec<_>>(), [0]);
+    }
+
+    #[tokio::test]
+    async fn list_clients() {
+        let driver = Harness::start();
+        let watcher = driver.client().await;
+        watcher.subscribe().await.unwrap();
+        let client = driver.client().await;
+
+        let clients = client.list_clients().await.unwrap();
+
+        let [current] = &clients.iter().filter(|c| c.current).collect::<Vec<_>>()[..] else {
+            panic!("expected one current connection, got {clients:?}");
+        };
+        assert_eq!(current.pid, Some(std::process::id()));
+        assert!(current.name.is_some());
+        assert_eq!(current.protocol, Some(PROTOCOL_VERSION));
+        assert!(!current.subscribed);
+
+        // connections of earlier tests may still be closing, but none subscribed
+        let watcher_id = clients
+            .iter()
+            .find(|c| c.subscribed)
+            .expect("the watcher is listed")
+            .id;
+        assert!(watcher_id < current.id);
+
+        drop(watcher);
+        for _ in 0..CONNECT_ATTEMPTS {
+            let clients = client.list_clients().await.unwrap();
+            if clients.iter().all(|c| c.id != watcher_id) {
+                return;
+            }
+
+            // the connection task notices the closed pipe on its next read
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("connection {watcher_id} is still listed after it closed");
+    }
+
+    #[tokio::test]
+    async fn adaptive_sync_is_dropped() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        assert!(!client.request_capabilities().await.unwrap().adaptive_sync);
+
+        let vrr = MonitorBuilder::new(0)
+            .mode(1920, 1080, &[48, 144])
+            .vrr_range(48, 144)
+            .build()
+            .unwrap();
+        let report = client.set_monitors(&[vrr, monitor(1, 60)]).await.unwrap();
+
+        // the monitor is plugged anyway, only with a fixed refresh rate
+        assert!(report.is_success());
+        assert_eq!(
+            report.warnings.iter().map(|w| w.id).collect::<Vec<_>>(),
+            [0]
+        );
+        assert_eq!(client.request_state().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn user_session_monitors() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+        client.set_user_session(false).await.unwrap();
+
+        let user = MonitorBuilder::new(1)
+            .mode(1920, 1080, &[60])
+            .session_scope(SessionScope::User)
+            .build()
+            .unwrap();
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60), user.clone()]))
+            .await
+            .unwrap();
+
+        // on the logon screen only the system monitor is attached
+        arrived(&driver.calls(), 0);
+
+        client.set_user_session(true).await.unwrap();
+        let handle = arrived(&driver.calls(), 1);
+
+        // locking detaches it again, but it stays in the state
+        client.set_user_session(false).await.unwrap();
+        assert_eq!(
+            driver.calls(),
+            [IddCxCall::MonitorDeparture { monitor: handle }]
+        );
+        assert_eq!(
+            client.request_state().await.unwrap(),
+            [monitor(0, 60), user]
+        );
+
+        client.set_user_session(false).await.unwrap();
+        assert!(driver.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reserved_ids() {
+        let driver = Harness::start();
+        let first = driver.client().await;
+        let second = driver.client().await;
+
+        assert_eq!(first.new_id().await.unwrap(), 0);
+        assert_eq!(second.new_id().await.unwrap(), 1);
+
+        let result = second
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60)]))
+            .await;
+        assert_eq!(error_code(result), ErrorCode::IdReserved);
+        assert!(driver.calls().is_empty());
+
+        second
+            .execute(&DriverCommand::Notify(vec![monitor(1, 60)]))
+            .await
+            .unwrap();
+        arrived(&driver.calls(), 0);
+
+        // monitor 1 exists now, so the full state may include it
+        first
+            .execute(&DriverCommand::Notify(vec![monitor(1, 60), monitor(0, 60)]))
+            .await
+            .unwrap();
+        arrived(&driver.calls(), 1);
+
+        assert_eq!(first.new_id().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn frame_rate_limit_without_reattach() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        client
+            .execute(&DriverCommand::Notify(vec![monitor(0, 60)]))
+            .await
+            .unwrap();
+        arrived(&driver.calls(), 0);
+
+        let limited = Monitor {
+            frame_rate_limit: Some(30),
+            ..monitor(0, 60)
+        };
+        client
+            .execute(&DriverCommand::Notify(vec![limited.clone()]))
+            .await
+            .unwrap();
+
+        // the swap chain processor picks it up from the shared limit
+        assert!(driver.calls().is_empty());
+        assert_eq!(client.request_state().await.unwrap(), [limited]);
+        assert_eq!(
+            MONITOR_MODES.lock().unwrap()[0].frame_rate_limit.get(),
+            Some(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn requested_ids() {
+        let driver = Harness::start();
+        let first = driver.client().await;
+        let second = driver.client().await;
+
+        let id = stable_id("capture");
+        assert_eq!(first.reserve_id(id).await.unwrap(), id);
+        // asking again for an own reservation is fine
+        assert_eq!(first.reserve_id(id).await.unwrap(), id);
+        let result = second.reserve_id(id).await.map(drop);
+        assert_eq!(error_code(result), ErrorCode::IdReserved);
+
+        first
+            .execute(&DriverCommand::Notify(vec![monitor(id, 60)]))
+            .await
+            .unwrap();
+        arrived(&driver.calls(), 0);
+
+        for client in [&first, &second] {
+            let result = client.reserve_id(id).await.map(drop);
+            assert_eq!(error_code(result), ErrorCode::IdInUse);
+        }
+    }
+
+    #[tokio::test]
+    async fn mode_override_reverts() {
+        const REVERT_ATTEMPTS: u32 = 100;
+
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        let original = monitor(0, 60);
+        client
+            .execute(&DriverCommand::Notify(vec![original.clone()]))
+            .await
+            .unwrap();
+        let old = arrived(&driver.calls(), 0);
+
+        let mode = Mode {
+            width: 800,
+            height: 600,
+            refresh_rates: vec![60],
+            timing: None,
+        };
+        client
+            .override_mode(0, &mode, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let calls = driver.calls();
+        assert_eq!(calls[0], IddCxCall::MonitorDeparture { monitor: old });
+        arrived(&calls[1..], 0);
+
+        // renewing keeps the modes from before the first override
+        client
+            .override_mode(0, &mode, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(driver.calls().is_empty());
+        assert_eq!(
+            client.request_state().await.unwrap()[0].modes,
+            [mode.clone()]
+        );
+
+        let mut reverted = false;
+        for _ in 0..REVERT_ATTEMPTS {
+            if client.request_state().await.unwrap() == [original.clone()] {
+                reverted = true;
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(reverted, "the override was not reverted");
+
+        // nothing is left to revert
+        client.revert_mode(0).await.unwrap();
+        assert_eq!(client.request_state().await.unwrap(), [original]);
+
+        let result = client.override_mode(1, &mode, Duration::from_secs(1)).await;
+        assert_eq!(error_code(result), ErrorCode::MonitorNotFound);
+    }
+
+    #[tokio::test]
+    async fn reload_settings_without_changes() {
+        let driver = Harness::start();
+        let client = driver.client().await;
+
+        // the settings are the ones the driver started with, nothing to apply
+        let reload = client.reload_settings().await.unwrap();
+        assert!(reload.applied.is_empty());
+        assert!(reload.restart_required.is_empty());
+        assert!(driver.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn commands_during_teardown() {
+        const CLIENTS: u32 = 4;
+
+        let driver = Harness::start();
+
+        // every client adds and removes its own monitor until the driver rejects it
+        let mut clients = Vec::new();
+        for id in 0..CLIENTS {
+            let client = driver.client().await;
+
+            clients.push(task::spawn(async move {
+                for applied in 0usize.. {
+                    let command = if applied % 2 == 0 {
+                        DriverCommand::Notify(vec![monitor(id, 60)])
+                    } else {
+                        DriverCommand::Remove(vec![id])
+                    };
+
+                    let result = client.execute(&command).await;
+                    if result.is_err() {
+                        assert_eq!(error_code(result), ErrorCode::DriverStopping);
+                        return applied;
+                    }
+                }
+
+                unreachable!()
+            }));
+        }
+
+        // what `device_d0_exit` does before stopping the threads, while the clients are busy
+        task::spawn_blocking(|| {
+            thread::sleep(Duration::from_millis(50));
+            ipc::stop_monitors(SHUTDOWN_TIMEOUT);
+        })
+        .await
+        .unwrap();
+
+        for client in clients {
+            assert!(client.await.unwrap() > 0);
+        }
+        assert_eq!(LIFECYCLE.state(), State::Draining);
+
+        // the fake monitors are the canaries of their contexts, nothing touches them once departed
+        let mut attached = HashSet::new();
+        let mut departed = HashSet::new();
+        for call in driver.calls() {
+            match call {
+                IddCxCall::MonitorCreate { monitor, .. }
+                | IddCxCall::MonitorArrival { monitor } => {
+                    assert!(
+                        !departed.contains(&monitor),
+                        "monitor {monitor:#x} used after departing"
+                    );
+                    attached.insert(monitor);
+                }
+                IddCxCall::MonitorDeparture { monitor } => {
+                    assert!(
+                        departed.insert(monitor),
+                        "monitor {monitor:#x} departed twice"
+                    );
+                    attached.remove(&monitor);
+                }
+                IddCxCall::AdapterInitAsync { .. } => {}
+            }
+        }
+
+        assert!(attached.is_empty(), "monitors left attached: {attached:?}");
+        assert!(MONITOR_MODES
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|m| m.object.is_none()));
+    }
+}
@@ -152,8 +152,35 @@ impl WDF_DRIVER_CONFIG {
 
         config
     }
+
+    /// Called before the driver is unloaded, after all of its devices were removed
+    #[must_use]
+    pub fn unload(mut self, cb: EvtDriverUnload) -> Self {
+        self.EvtDriverUnload = Some(cb);
+        self
+    }
 }
 
+/// `EvtDriverUnload`
+pub type EvtDriverUnload = unsafe extern "C-unwind" fn(Driver: WDFDRIVER);
+
+/// `EvtDeviceD0Entry` / `EvtDeviceD0Exit`
+pub type EvtDevicePowerState =
+    unsafe extern "C-unwind" fn(Device: WDFDEVICE, State: WDF_POWER_DEVICE_STATE) -> NTSTATUS;
+/// `EvtDevicePrepareHardware`
+pub type EvtDevicePrepareHardware = unsafe extern "C-unwind" fn(
+    Device: WDFDEVICE,
+    ResourcesRaw: WDFCMRESLIST,
+    ResourcesTranslated: WDFCMRESLIST,
+) -> NTSTATUS;
+/// `EvtDeviceReleaseHardware`
+pub type EvtDeviceReleaseHardware =
+    unsafe extern "C-unwind" fn(Device: WDFDEVICE, ResourcesTranslated: WDFCMRESLIST) -> NTSTATUS;
+/// `EvtDeviceSelfManagedIoInit` / `Suspend` / `Restart`, and `EvtDeviceArmWakeFromS0` / `Sx`
+pub type EvtDeviceStatus = unsafe extern "C-unwind" fn(Device: WDFDEVICE) -> NTSTATUS;
+/// `EvtDeviceSelfManagedIoCleanup` / `Flush`, and the wake disarm / triggered callbacks
+pub type EvtDeviceNotify = unsafe extern "C-unwind" fn(Device: WDFDEVICE);
+
 impl WDF_PNPPOWER_EVENT_CALLBACKS {
     /// Initializes the [`WDF_PNPPOWER_EVENT_CALLBACKS`] structure
     /// <https://github.com/microsoft/Windows-Driver-Frameworks/blob/a94b8c30dad524352fab90872aefc83920b98e56/src/publicinc/wdf/umdf/2.33/wdfdevice.h#L1278/>
@@ -165,6 +192,235 @@ impl WDF_PNPPOWER_EVENT_CALLBACKS {
 
         callbacks
     }
+
+    /// Called when the device enters D0, with the state it came from
+    #[must_use]
+    pub fn d0_entry(mut self, cb: EvtDevicePowerState) -> Self {
+        self.EvtDeviceD0Entry = Some(cb);
+        self
+    }
+
+    /// Called when the device leaves D0, with the state it goes to
+    #[must_use]
+    pub fn d0_exit(mut self, cb: EvtDevicePowerState) -> Self {
+        self.EvtDeviceD0Exit = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn prepare_hardware(mut self, cb: EvtDevicePrepareHardware) -> Self {
+        self.EvtDevicePrepareHardware = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn release_hardware(mut self, cb: EvtDeviceReleaseHardware) -> Self {
+        self.EvtDeviceReleaseHardware = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn self_managed_io_init(mut self, cb: EvtDeviceStatus) -> Self {
+        self.EvtDeviceSelfManagedIoInit = Some(cb);
+        self
+    }
+
+    /// Called before the device leaves D0, e.g. when the system goes to sleep
+    #[must_use]
+    pub fn self_managed_io_suspend(mut self, cb: EvtDeviceStatus) -> Self {
+        self.EvtDeviceSelfManagedIoSuspend = Some(cb);
+        self
+    }
+
+    /// Called after the device returned to D0, e.g. when the system wakes up
+    #[must_use]
+    pub fn self_managed_io_restart(mut self, cb: EvtDeviceStatus) -> Self {
+        self.EvtDeviceSelfManagedIoRestart = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn self_managed_io_flush(mut self, cb: EvtDeviceNotify) -> Self {
+        self.EvtDeviceSelfManagedIoFlush = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn self_managed_io_cleanup(mut self, cb: EvtDeviceNotify) -> Self {
+        self.EvtDeviceSelfManagedIoCleanup = Some(cb);
+        self
+    }
+}
+
+impl WDF_POWER_POLICY_EVENT_CALLBACKS {
+    /// Initializes the [`WDF_POWER_POLICY_EVENT_CALLBACKS`] structure
+    /// <https://github.com/microsoft/Windows-Driver-Frameworks/blob/a94b8c30dad524352fab90872aefc83920b98e56/src/publicinc/wdf/umdf/2.33/wdfdevice.h/>
+    #[must_use]
+    pub fn init() -> Self {
+        // SAFETY: All fields are zero-able
+        let mut callbacks: Self = unsafe { core::mem::zeroed() };
+        callbacks.Size = WDF_STRUCTURE_SIZE!(Self);
+
+        callbacks
+    }
+
+    #[must_use]
+    pub fn arm_wake_from_s0(mut self, cb: EvtDeviceStatus) -> Self {
+        self.EvtDeviceArmWakeFromS0 = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn disarm_wake_from_s0(mut self, cb: EvtDeviceNotify) -> Self {
+        self.EvtDeviceDisarmWakeFromS0 = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn wake_from_s0_triggered(mut self, cb: EvtDeviceNotify) -> Self {
+        self.EvtDeviceWakeFromS0Triggered = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn arm_wake_from_sx(mut self, cb: EvtDeviceStatus) -> Self {
+        self.EvtDeviceArmWakeFromSx = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn disarm_wake_from_sx(mut self, cb: EvtDeviceNotify) -> Self {
+        self.EvtDeviceDisarmWakeFromSx = Some(cb);
+        self
+    }
+
+    #[must_use]
+    pub fn wake_from_sx_triggered(mut self, cb: EvtDeviceNotify) -> Self {
+        self.EvtDeviceWakeFromSxTriggered = Some(cb);
+        self
+    }
+}
+
+impl WDF_WORKITEM_CONFIG {
+    /// Initializes the [`WDF_WORKITEM_CONFIG`] structure
+    /// <https://github.com/microsoft/Windows-Driver-Frameworks/blob/a94b8c30dad524352fab90872aefc83920b98e56/src/publicinc/wdf/umdf/2.33/wdfworkitem.h/>
+    ///
+    /// Sets
+    /// - `AutomaticSerialization` to `TRUE`
+    #[must_use]
+    pub fn init(EvtWorkItemFunc: PFN_WDF_WORKITEM) -> Self {
+        // SAFETY: All fields are zero-able
+        let mut config: Self = unsafe { core::mem::zeroed() };
+
+        config.Size = WDF_STRUCTURE_SIZE!(Self);
+        config.EvtWorkItemFunc = EvtWorkItemFunc;
+        config.AutomaticSerialization = true.into();
+
+        config
+    }
+}
+
+impl WDF_TIMER_CONFIG {
+    /// Initializes the [`WDF_TIMER_CONFIG`] structure for a one-shot timer
+    /// <https://github.com/microsoft/Windows-Driver-Frameworks/blob/a94b8c30dad524352fab90872aefc83920b98e56/src/publicinc/wdf/umdf/2.33/wdftimer.h/>
+    ///
+    /// Sets
+    /// - `Period` to `0`
+    /// - `AutomaticSerialization` to `TRUE`
+    /// - `TolerableDelay` to `0`
+    #[must_use]
+    pub fn init(EvtTimerFunc: PFN_WDF_TIMER) -> Self {
+        // SAFETY: All fields are zero-able
+        let mut config: Self = unsafe { core::mem::zeroed() };
+
+        config.Size = WDF_STRUCTURE_SIZE!(Self);
+        config.EvtTimerFunc = EvtTimerFunc;
+        config.AutomaticSerialization = true.into();
+
+        config
+    }
+}
+
+/// `EvtIoDeviceControl`
+pub type EvtIoDeviceControl = unsafe extern "C-unwind" fn(
+    Queue: WDFQUEUE,
+    Request: WDFREQUEST,
+    OutputBufferLength: usize,
+    InputBufferLength: usize,
+    IoControlCode: ULONG,
+);
+/// `EvtIoStop`
+pub type EvtIoStop =
+    unsafe extern "C-unwind" fn(Queue: WDFQUEUE, Request: WDFREQUEST, ActionFlags: ULONG);
+
+impl WDF_IO_QUEUE_CONFIG {
+    /// Initializes the [`WDF_IO_QUEUE_CONFIG`] structure
+    /// <https://github.com/microsoft/Windows-Driver-Frameworks/blob/a94b8c30dad524352fab90872aefc83920b98e56/src/publicinc/wdf/umdf/2.33/wdfio.h#L483/>
+    ///
+    /// Sets
+    /// - `PowerManaged` to [`WDF_TRI_STATE::WdfUseDefault`]
+    /// - `NumberOfPresentedRequests` to unlimited, for
+    ///   [`WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel`]
+    #[must_use]
+    pub fn init(DispatchType: WDF_IO_QUEUE_DISPATCH_TYPE) -> Self {
+        // SAFETY: All fields are zero-able
+        let mut config: Self = unsafe { core::mem::zeroed() };
+
+        config.Size = WDF_STRUCTURE_SIZE!(Self);
+        config.PowerManaged = WDF_TRI_STATE::WdfUseDefault;
+        config.DispatchType = DispatchType;
+
+        if DispatchType == WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel {
+            config.Settings.Parallel.NumberOfPresentedRequests = ULONG::MAX;
+        }
+
+        config
+    }
+
+    /// Like [`WDF_IO_QUEUE_CONFIG::init`], for the queue which receives all
+    /// requests not forwarded to another queue
+    /// <https://github.com/microsoft/Windows-Driver-Frameworks/blob/a94b8c30dad524352fab90872aefc83920b98e56/src/publicinc/wdf/umdf/2.33/wdfio.h#L510/>
+    #[must_use]
+    pub fn init_default_queue(DispatchType: WDF_IO_QUEUE_DISPATCH_TYPE) -> Self {
+        let mut config = Self::init(DispatchType);
+        config.DefaultQueue = true.into();
+
+        config
+    }
+
+    /// Dispatch requests in parallel, with at most `max_presented` of them
+    /// handed to the driver at the same time. `None` doesn't limit them
+    #[must_use]
+    pub fn parallel(mut self, max_presented: Option<ULONG>) -> Self {
+        self.DispatchType = WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel;
+        self.Settings.Parallel.NumberOfPresentedRequests = max_presented.unwrap_or(ULONG::MAX);
+        self
+    }
+
+    /// Whether the queue stops dispatching while the device leaves D0
+    #[must_use]
+    pub fn power_managed(mut self, power_managed: bool) -> Self {
+        self.PowerManaged = if power_managed {
+            WDF_TRI_STATE::WdfTrue
+        } else {
+            WDF_TRI_STATE::WdfFalse
+        };
+        self
+    }
+
+    #[must_use]
+    pub fn device_control(mut self, cb: EvtIoDeviceControl) -> Self {
+        self.EvtIoDeviceControl = Some(cb);
+        self
+    }
+
+    /// Called for every request the driver owns when the queue stops, e.g.
+    /// because the device leaves D0
+    #[must_use]
+    pub fn stop(mut self, cb: EvtIoStop) -> Self {
+        self.EvtIoStop = Some(cb);
+        self
+    }
 }
 
 /// If this returns None, the struct is NOT available to be used
@@ -1,5 +1,7 @@
 use std::fmt::{self, Display};
 
+use windows_result::{Error, HRESULT};
+
 /// A NTSTATUS wrapper that gives information on the value
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -41,6 +43,20 @@ impl NTSTATUS {
     }
 }
 
+impl NTSTATUS {
+    /// The bit `HRESULT_FROM_NT` sets to mark an HRESULT as a wrapped NTSTATUS
+    pub const FACILITY_NT_BIT: u32 = 0x1000_0000;
+
+    /// `Ok` if this is a success status, so it can be `?`ed like a `windows::core::Result`
+    pub fn ok(self) -> Result<(), NTSTATUS> {
+        if self.is_success() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
 impl std::error::Error for NTSTATUS {}
 
 impl Display for NTSTATUS {
@@ -80,6 +96,38 @@ impl From<NTSTATUS> for i32 {
     }
 }
 
+/// `HRESULT_FROM_NT`
+impl From<NTSTATUS> for HRESULT {
+    fn from(value: NTSTATUS) -> Self {
+        let value = u32::from(value) | NTSTATUS::FACILITY_NT_BIT;
+        Self(bytemuck::cast(value))
+    }
+}
+
+impl From<NTSTATUS> for Error {
+    fn from(value: NTSTATUS) -> Self {
+        HRESULT::from(value).into()
+    }
+}
+
+/// The reverse of `HRESULT_FROM_NT`. `S_OK` is `STATUS_SUCCESS`, any other
+/// HRESULT without the NT facility bit is returned as the error
+impl TryFrom<HRESULT> for NTSTATUS {
+    type Error = HRESULT;
+
+    fn try_from(value: HRESULT) -> Result<Self, Self::Error> {
+        let bits = bytemuck::cast::<_, u32>(value.0);
+
+        if bits == 0 {
+            Ok(Self::STATUS_SUCCESS)
+        } else if bits & Self::FACILITY_NT_BIT != 0 {
+            Ok(Self::from(bits & !Self::FACILITY_NT_BIT))
+        } else {
+            Err(value)
+        }
+    }
+}
+
 impl<R: Into<NTSTATUS>, E: Into<NTSTATUS>> From<Result<R, E>> for NTSTATUS {
     fn from(value: Result<R, E>) -> Self {
         match value {
@@ -2787,3 +2835,41 @@ impl NTSTATUS {
     pub const STATUS_XML_ENCODING_MISMATCH: NTSTATUS = NTSTATUS(-1_072_365_535_i32);
     pub const STATUS_XML_PARSE_ERROR: NTSTATUS = NTSTATUS(-1_073_700_733_i32);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 0x80070005, HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED)
+    const E_ACCESSDENIED: HRESULT = HRESULT(-2_147_024_891);
+
+    #[test]
+    fn success_round_trips() {
+        assert_eq!(NTSTATUS::STATUS_SUCCESS.ok(), Ok(()));
+        assert_eq!(NTSTATUS::try_from(HRESULT(0)), Ok(NTSTATUS::STATUS_SUCCESS));
+
+        let hr = HRESULT::from(NTSTATUS::STATUS_SUCCESS);
+        assert!(hr.is_ok());
+        assert_eq!(NTSTATUS::try_from(hr), Ok(NTSTATUS::STATUS_SUCCESS));
+    }
+
+    #[test]
+    fn nt_error_round_trips() {
+        let status = NTSTATUS::STATUS_ACCESS_DENIED;
+        assert_eq!(status.ok(), Err(status));
+
+        let hr = HRESULT::from(status);
+        assert_eq!(bytemuck::cast::<_, u32>(hr.0), 0xD000_0022);
+        assert!(hr.is_err());
+        assert_eq!(NTSTATUS::try_from(hr), Ok(status));
+
+        let error = Error::from(status);
+        assert_eq!(error.code(), hr);
+        assert_eq!(NTSTATUS::try_from(error.code()), Ok(status));
+    }
+
+    #[test]
+    fn win32_hresult_is_no_ntstatus() {
+        assert_eq!(NTSTATUS::try_from(E_ACCESSDENIED), Err(E_ACCESSDENIED));
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    fmt::{self, Debug},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use wdf_umdf_sys::WDFOBJECT;
+
+use crate::{context_lock, context_lock::RwLock, WdfError};
+
+/// Handle to the data of a `WDF_DECLARE_CONTEXT_TYPE` context, made by its
+/// `init_cached` or `cached`
+///
+/// The `get`/`get_mut` functions of the context look the context up through
+/// `WdfObjectGetTypedContextWorker` on every access. This handle does that
+/// once and keeps a weak pointer to the data, so accessing it is only the
+/// lock. It has the same accessors, which don't need `unsafe`: once the
+/// context is dropped, they fail with [`WdfError::UpgradeFailed`] instead
+/// of touching freed memory.
+pub struct CachedContext<T> {
+    object: WDFOBJECT,
+    data: Weak<RwLock<T>>,
+}
+
+// SAFETY: The object handle is only handed out, never dereferenced. The data
+// is behind a lock, like the context itself
+unsafe impl<T: Send + Sync> Send for CachedContext<T> {}
+// SAFETY: See above
+unsafe impl<T: Send + Sync> Sync for CachedContext<T> {}
+
+impl<T> CachedContext<T> {
+    #[doc(hidden)]
+    pub fn new(object: WDFOBJECT, data: &Arc<RwLock<T>>) -> Self {
+        Self {
+            object,
+            data: Arc::downgrade(data),
+        }
+    }
+
+    /// The object the context belongs to
+    #[must_use]
+    pub fn object(&self) -> WDFOBJECT {
+        self.object
+    }
+
+    fn upgrade(&self) -> Result<Arc<RwLock<T>>, WdfError> {
+        self.data.upgrade().ok_or(WdfError::UpgradeFailed)
+    }
+
+    /// Borrow the context immutably
+    /// Returns with error and won't call cb if it failed to lock
+    pub fn get(&self, cb: impl FnOnce(&T)) -> Result<(), WdfError> {
+        let data = self.upgrade()?;
+        let guard = context_lock::read(&data)?;
+
+        cb(&guard);
+
+        Ok(())
+    }
+
+    /// Borrow the context mutably
+    /// Returns with error and won't call cb if it failed to lock
+    pub fn get_mut(&self, cb: impl FnOnce(&mut T)) -> Result<(), WdfError> {
+        let data = self.upgrade()?;
+        let mut guard = context_lock::write(&data)?;
+
+        cb(&mut guard);
+
+        Ok(())
+    }
+
+    /// Try to borrow the context immutably. Immediately returns if it's locked
+    pub fn try_get(&self, cb: impl FnOnce(&T)) -> Result<(), WdfError> {
+        let data = self.upgrade()?;
+        let guard = context_lock::try_read(&data)?;
+
+        cb(&guard);
+
+        Ok(())
+    }
+
+    /// Try to borrow the context mutably. Immediately returns if it's locked
+    pub fn try_get_mut(&self, cb: impl FnOnce(&mut T)) -> Result<(), WdfError> {
+        let data = self.upgrade()?;
+        let mut guard = context_lock::try_write(&data)?;
+
+        cb(&mut guard);
+
+        Ok(())
+    }
+
+    /// Borrow the context immutably, waiting up to `timeout` for the lock
+    pub fn get_timeout(&self, timeout: Duration, cb: impl FnOnce(&T)) -> Result<(), WdfError> {
+        let data = self.upgrade()?;
+        let guard = context_lock::read_timeout(&data, timeout)?;
+
+        cb(&guard);
+
+        Ok(())
+    }
+
+    /// Borrow the context mutably, waiting up to `timeout` for the lock
+    pub fn get_mut_timeout(
+        &self,
+        timeout: Duration,
+        cb: impl FnOnce(&mut T),
+    ) -> Result<(), WdfError> {
+        let data = self.upgrade()?;
+        let mut guard = context_lock::write_timeout(&data, timeout)?;
+
+        cb(&mut guard);
+
+        Ok(())
+    }
+}
+
+impl<T> Clone for CachedContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            object: self.object,
+            data: self.data.clone(),
+        }
+    }
+}
+
+impl<T> Debug for CachedContext<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedContext")
+            .field("object", &self.object)
+            .field("alive", &(self.data.strong_count() > 0))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fails_once_dropped() {
+        let data = Arc::new(RwLock::new(1));
+        let cached = CachedContext::new(std::ptr::null_mut(), &data);
+
+        cached.get_mut(|n| *n += 1).unwrap();
+        cached.clone().get(|n| assert_eq!(*n, 2)).unwrap();
+
+        drop(data);
+        assert!(matches!(
+            cached.get(|_| panic!("called after drop")),
+            Err(WdfError::UpgradeFailed)
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+//! Callbacks which don't unwind into the framework
+//!
+//! A panic unwinding out of a callback into WDF or IddCx is undefined behavior. Callbacks defined
+//! with [wdf_callback!](crate::wdf_callback) or [iddcx_callback!](crate::iddcx_callback) catch it
+//! and return [PanicDefault::panic_default] instead.
+
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+};
+
+use wdf_umdf_sys::NTSTATUS;
+
+use crate::logging;
+
+/// Value a callback returns to the framework after its body panicked
+pub trait PanicDefault {
+    fn panic_default() -> Self;
+}
+
+impl PanicDefault for NTSTATUS {
+    fn panic_default() -> Self {
+        Self::STATUS_UNSUCCESSFUL
+    }
+}
+
+impl PanicDefault for () {
+    fn panic_default() -> Self {}
+}
+
+/// Runs the body of the callback `name`, used by the callback macros
+#[doc(hidden)]
+pub fn guard<R: PanicDefault, F: FnOnce() -> R>(name: &str, body: F) -> R {
+    // the body only sees its arguments, and the framework never sees a half finished call
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => value,
+        Err(payload) => {
+            logging::callback_panicked(name, payload_message(payload.as_ref()));
+            R::panic_default()
+        }
+    }
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// Defines a WDF callback whose panics are caught, see the [module docs](crate::callback)
+///
+/// The callback is checked to match the `PFN_` type after `as`, and gets its `extern "C-unwind"`
+/// ABI. Arguments must be plain names.
+///
+/// ```ignore
+/// wdf_callback! {
+///     /// Called when the device enters D0
+///     pub fn device_d0_entry(
+///         device: WDFDEVICE,
+///         previous_state: WDF_POWER_DEVICE_STATE,
+///     ) -> NTSTATUS as PFN_WDF_DEVICE_D0_ENTRY {
+///         NTSTATUS::STATUS_SUCCESS
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! wdf_callback {
+    (
+        $(#[$meta:meta])*
+        $vis:vis fn $name:ident($($arg:ident: $arg_ty:ty),* $(,)?) -> $ret:ty as $pfn:ty $body:block
+    ) => {
+        $(#[$meta])*
+        $vis extern "C-unwind" fn $name($($arg: $arg_ty),*) -> $ret {
+            $crate::callback::guard::<$ret, _>(stringify!($name), move || $body)
+        }
+
+        const _: $pfn = ::std::option::Option::Some($name);
+    };
+
+    (
+        $(#[$meta:meta])*
+        $vis:vis fn $name:ident($($arg:ident: $arg_ty:ty),* $(,)?) as $pfn:ty $body:block
+    ) => {
+        $(#[$meta])*
+        $vis extern "C-unwind" fn $name($($arg: $arg_ty),*) {
+            $crate::callback::guard::<(), _>(stringify!($name), move || $body);
+        }
+
+        const _: $pfn = ::std::option::Option::Some($name);
+    };
+}
+
+/// Defines an IddCx callback whose panics are caught, the same as
+/// [wdf_callback!](crate::wdf_callback)
+#[macro_export]
+macro_rules! iddcx_callback {
+    ($($callback:tt)*) => {
+        $crate::wdf_callback! { $($callback)* }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+
+    use wdf_umdf_sys::{
+        PFN_WDF_DEVICE_D0_EXIT, PFN_WDF_OBJECT_CONTEXT_CLEANUP, WDFDEVICE, WDFOBJECT,
+        WDF_POWER_DEVICE_STATE,
+    };
+
+    use super::*;
+
+    crate::wdf_callback! {
+        fn panicking_d0_exit(
+            _device: WDFDEVICE,
+            _target_state: WDF_POWER_DEVICE_STATE,
+        ) -> NTSTATUS as PFN_WDF_DEVICE_D0_EXIT {
+            panic!("callback failed");
+        }
+    }
+
+    crate::wdf_callback! {
+        fn panicking_cleanup(_object: WDFOBJECT) as PFN_WDF_OBJECT_CONTEXT_CLEANUP {
+            panic!("cleanup failed");
+        }
+    }
+
+    crate::wdf_callback! {
+        fn succeeding_d0_exit(
+            _device: WDFDEVICE,
+            _target_state: WDF_POWER_DEVICE_STATE,
+        ) -> NTSTATUS as PFN_WDF_DEVICE_D0_EXIT {
+            NTSTATUS::STATUS_SUCCESS
+        }
+    }
+
+    #[test]
+    fn panic_returns_unsuccessful() {
+        let state = WDF_POWER_DEVICE_STATE::WdfPowerDeviceD3Final;
+
+        assert_eq!(
+            panicking_d0_exit(ptr::null_mut(), state),
+            NTSTATUS::STATUS_UNSUCCESSFUL
+        );
+        assert_eq!(
+            succeeding_d0_exit(ptr::null_mut(), state),
+            NTSTATUS::STATUS_SUCCESS
+        );
+
+        // returns instead of aborting
+        panicking_cleanup(ptr::null_mut());
+    }
+
+    #[test]
+    fn reads_panic_message() {
+        let payload = panic::catch_unwind(|| panic!("failed {}", 1)).unwrap_err();
+        assert_eq!(payload_message(payload.as_ref()), "failed 1");
+    }
+}
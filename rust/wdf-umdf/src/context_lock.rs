@@ -0,0 +1,277 @@
+//! The lock guarding the data of a `WDF_DECLARE_CONTEXT_TYPE` context.
+//!
+//! This is the std `RwLock` by default. The `parking-lot-context` feature
+//! swaps in `parking_lot::RwLock`, which doesn't poison and holds up better
+//! when the frame and IPC threads contend for the device context. Locking
+//! goes through the functions here, so the macro and the accessors it
+//! generates are the same with both.
+//!
+//! A std lock is poisoned once a thread panics while holding it. The data of
+//! a context is still usable then, and failing every later access would
+//! silently disable whatever the context belongs to, e.g. the whole monitor.
+//! So the functions here take the lock anyway, clear the poison and write a
+//! `ContextLockPoisoned` event, see [`logging`](crate::logging).
+
+use std::time::Duration;
+#[cfg(not(feature = "parking-lot-context"))]
+use std::{
+    any::type_name,
+    sync::{PoisonError, TryLockError},
+    thread,
+    time::Instant,
+};
+
+use crate::WdfError;
+
+/// Attempts spent spinning before waiting for a lock starts sleeping
+#[cfg(not(feature = "parking-lot-context"))]
+const SPINS: u32 = 64;
+/// Longest sleep between attempts while waiting for a lock
+#[cfg(not(feature = "parking-lot-context"))]
+const MAX_NAP: Duration = Duration::from_micros(100);
+
+#[cfg(not(feature = "parking-lot-context"))]
+pub use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "parking-lot-context")]
+pub use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Lock for reading, recovering the lock if it is poisoned.
+#[cfg(not(feature = "parking-lot-context"))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn read<T>(lock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, WdfError> {
+    Ok(lock.read().unwrap_or_else(|e| recover(lock, e)))
+}
+
+/// Lock for writing, recovering the lock if it is poisoned.
+#[cfg(not(feature = "parking-lot-context"))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn write<T>(lock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, WdfError> {
+    Ok(lock.write().unwrap_or_else(|e| recover(lock, e)))
+}
+
+/// Lock for reading without blocking, failing if the lock is held.
+#[cfg(not(feature = "parking-lot-context"))]
+pub fn try_read<T>(lock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, WdfError> {
+    try_lock(lock, lock.try_read()).ok_or(WdfError::LockFailed)
+}
+
+/// Lock for writing without blocking, failing if the lock is held.
+#[cfg(not(feature = "parking-lot-context"))]
+pub fn try_write<T>(lock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, WdfError> {
+    try_lock(lock, lock.try_write()).ok_or(WdfError::LockFailed)
+}
+
+/// Lock for reading, waiting up to `timeout` for it.
+///
+/// The std lock can't wait with a timeout, so this spins and then naps
+/// between attempts.
+#[cfg(not(feature = "parking-lot-context"))]
+pub fn read_timeout<T>(
+    lock: &RwLock<T>,
+    timeout: Duration,
+) -> Result<RwLockReadGuard<'_, T>, WdfError> {
+    wait_for(timeout, || try_lock(lock, lock.try_read()))
+}
+
+/// Lock for writing, waiting up to `timeout` for it.
+///
+/// The std lock can't wait with a timeout, so this spins and then naps
+/// between attempts.
+#[cfg(not(feature = "parking-lot-context"))]
+pub fn write_timeout<T>(
+    lock: &RwLock<T>,
+    timeout: Duration,
+) -> Result<RwLockWriteGuard<'_, T>, WdfError> {
+    wait_for(timeout, || try_lock(lock, lock.try_write()))
+}
+
+/// The guard of a poisoned lock, after clearing the poison
+#[cfg(not(feature = "parking-lot-context"))]
+fn recover<T, G>(lock: &RwLock<T>, e: PoisonError<G>) -> G {
+    crate::logging::context_lock_poisoned(type_name::<T>());
+    lock.clear_poison();
+    e.into_inner()
+}
+
+/// The guard of a non blocking attempt, `None` if the lock is held
+#[cfg(not(feature = "parking-lot-context"))]
+fn try_lock<T, G>(lock: &RwLock<T>, result: Result<G, TryLockError<G>>) -> Option<G> {
+    match result {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(recover(lock, e)),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+#[cfg(not(feature = "parking-lot-context"))]
+fn wait_for<G>(timeout: Duration, mut try_lock: impl FnMut() -> Option<G>) -> Result<G, WdfError> {
+    let deadline = Instant::now() + timeout;
+    let mut attempt = 0u32;
+
+    loop {
+        if let Some(guard) = try_lock() {
+            return Ok(guard);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(WdfError::LockTimeout);
+        }
+
+        if attempt < SPINS {
+            attempt += 1;
+            std::hint::spin_loop();
+        } else {
+            thread::sleep(MAX_NAP.min(deadline - now));
+        }
+    }
+}
+
+/// Lock for reading, this never fails.
+#[cfg(feature = "parking-lot-context")]
+#[allow(clippy::unnecessary_wraps)]
+pub fn read<T>(lock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, WdfError> {
+    Ok(lock.read())
+}
+
+/// Lock for writing, this never fails.
+#[cfg(feature = "parking-lot-context")]
+#[allow(clippy::unnecessary_wraps)]
+pub fn write<T>(lock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, WdfError> {
+    Ok(lock.write())
+}
+
+/// Lock for reading without blocking, failing if the lock is held.
+#[cfg(feature = "parking-lot-context")]
+pub fn try_read<T>(lock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, WdfError> {
+    lock.try_read().ok_or(WdfError::LockFailed)
+}
+
+/// Lock for writing without blocking, failing if the lock is held.
+#[cfg(feature = "parking-lot-context")]
+pub fn try_write<T>(lock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, WdfError> {
+    lock.try_write().ok_or(WdfError::LockFailed)
+}
+
+/// Lock for reading, waiting up to `timeout` for it.
+#[cfg(feature = "parking-lot-context")]
+pub fn read_timeout<T>(
+    lock: &RwLock<T>,
+    timeout: Duration,
+) -> Result<RwLockReadGuard<'_, T>, WdfError> {
+    lock.try_read_for(timeout).ok_or(WdfError::LockTimeout)
+}
+
+/// Lock for writing, waiting up to `timeout` for it.
+#[cfg(feature = "parking-lot-context")]
+pub fn write_timeout<T>(
+    lock: &RwLock<T>,
+    timeout: Duration,
+) -> Result<RwLockWriteGuard<'_, T>, WdfError> {
+    lock.try_write_for(timeout).ok_or(WdfError::LockTimeout)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    /// Holds a write lock on another thread for `hold`, returning once it is taken
+    fn hold_write(lock: &Arc<RwLock<u32>>, hold: Duration) -> thread::JoinHandle<()> {
+        let (taken_tx, taken_rx) = mpsc::channel();
+        let lock = Arc::clone(lock);
+
+        let writer = thread::spawn(move || {
+            let mut guard = write(&lock).unwrap();
+            taken_tx.send(()).unwrap();
+            thread::sleep(hold);
+            *guard += 1;
+        });
+
+        taken_rx.recv().unwrap();
+        writer
+    }
+
+    #[test]
+    fn waits_for_a_short_writer() {
+        let lock = Arc::new(RwLock::new(0));
+
+        let writer = hold_write(&lock, Duration::from_millis(20));
+        assert_eq!(*read_timeout(&lock, Duration::from_secs(5)).unwrap(), 1);
+        writer.join().unwrap();
+
+        let writer = hold_write(&lock, Duration::from_millis(20));
+        *write_timeout(&lock, Duration::from_secs(5)).unwrap() += 1;
+        writer.join().unwrap();
+        assert_eq!(*read(&lock).unwrap(), 3);
+    }
+
+    #[test]
+    fn times_out_on_a_long_writer() {
+        let lock = Arc::new(RwLock::new(0));
+        let writer = hold_write(&lock, Duration::from_millis(500));
+
+        let start = Instant::now();
+        assert!(matches!(
+            read_timeout(&lock, Duration::from_millis(20)),
+            Err(WdfError::LockTimeout)
+        ));
+        assert!(matches!(
+            write_timeout(&lock, Duration::from_millis(20)),
+            Err(WdfError::LockTimeout)
+        ));
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(40), "{waited:?}");
+        assert!(waited < Duration::from_millis(500), "{waited:?}");
+
+        writer.join().unwrap();
+        assert_eq!(*read_timeout(&lock, Duration::ZERO).unwrap(), 1);
+    }
+
+    #[cfg(not(feature = "parking-lot-context"))]
+    #[test]
+    fn recovers_a_poisoned_lock() {
+        let lock = Arc::new(RwLock::new(0));
+
+        let poisoner = Arc::clone(&lock);
+        let panicked = thread::spawn(move || {
+            let mut guard = write(&poisoner).unwrap();
+            *guard += 1;
+            panic!("poisoning the lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(lock.is_poisoned());
+
+        assert_eq!(*read(&lock).unwrap(), 1);
+        assert!(!lock.is_poisoned());
+
+        // every way to lock gets through a poisoned lock
+        let locks: [fn(&RwLock<u32>); 4] = [
+            |lock| *try_write(lock).unwrap() += 1,
+            |lock| *write_timeout(lock, Duration::ZERO).unwrap() += 1,
+            |lock| assert!(*try_read(lock).unwrap() > 0),
+            |lock| assert!(*read_timeout(lock, Duration::ZERO).unwrap() > 0),
+        ];
+        for lock_once in locks {
+            let poisoner = Arc::clone(&lock);
+            _ = thread::spawn(move || {
+                let _guard = write(&poisoner).unwrap();
+                panic!("poisoning the lock");
+            })
+            .join();
+            assert!(lock.is_poisoned());
+
+            lock_once(&lock);
+            assert!(!lock.is_poisoned());
+        }
+
+        assert_eq!(*read(&lock).unwrap(), 3);
+    }
+}
@@ -0,0 +1,80 @@
+//! Setup of the device object for the root-enumerated software device.
+//!
+//! The calls must all happen before `WdfDeviceCreate`. `WdfDeviceInitSetPowerNotPageable` is
+//! KMDF only, a UMDF driver runs in a user mode host where nothing is paged in on the power path.
+
+use wdf_umdf_sys::{PWDFDEVICE_INIT, WDF_DEVICE_IO_TYPE};
+
+use crate::{
+    WdfDeviceInitSetDeviceType, WdfDeviceInitSetExclusive, WdfDeviceInitSetIoType, WdfError,
+};
+
+/// `FILE_DEVICE_UNKNOWN`, for devices which fit no other type
+pub const FILE_DEVICE_UNKNOWN: u32 = 0x0000_0022;
+
+/// A call of [`SoftwareDeviceInit::apply`] failed
+#[derive(Debug, Copy, Clone, thiserror::Error)]
+#[error("{call} failed: {source}")]
+pub struct DeviceInitError {
+    /// Name of the framework function that failed
+    pub call: &'static str,
+    pub source: WdfError,
+}
+
+/// Device object settings of the virtual display's software device
+///
+/// The [`Default`] is what the virtual display needs, apply it with
+/// [`SoftwareDeviceInit::configure`].
+#[derive(Debug, Copy, Clone)]
+pub struct SoftwareDeviceInit {
+    /// `FILE_DEVICE_*` type of the device object
+    pub device_type: u32,
+    /// Whether only one handle to the device can be open at a time
+    pub exclusive: bool,
+    pub io_type: WDF_DEVICE_IO_TYPE,
+}
+
+impl Default for SoftwareDeviceInit {
+    fn default() -> Self {
+        Self {
+            device_type: FILE_DEVICE_UNKNOWN,
+            // every client opens its own handle
+            exclusive: false,
+            io_type: WDF_DEVICE_IO_TYPE::WdfDeviceIoBuffered,
+        }
+    }
+}
+
+impl SoftwareDeviceInit {
+    /// Apply the [`Default`] settings to `device_init`.
+    ///
+    /// # Safety
+    ///
+    /// `device_init` must be valid, and `WdfDeviceCreate` not yet called with it.
+    pub unsafe fn configure(device_init: PWDFDEVICE_INIT) -> Result<(), DeviceInitError> {
+        // SAFETY: Upheld by the caller
+        unsafe { Self::default().apply(device_init) }
+    }
+
+    /// Apply these settings to `device_init`, stopping at the first call that
+    /// fails.
+    ///
+    /// # Safety
+    ///
+    /// `device_init` must be valid, and `WdfDeviceCreate` not yet called with it.
+    pub unsafe fn apply(&self, device_init: PWDFDEVICE_INIT) -> Result<(), DeviceInitError> {
+        let failed = |call| move |source| DeviceInitError { call, source };
+
+        // SAFETY: Upheld by the caller
+        unsafe { WdfDeviceInitSetDeviceType(device_init, self.device_type) }
+            .map_err(failed("WdfDeviceInitSetDeviceType"))?;
+        // SAFETY: Upheld by the caller
+        unsafe { WdfDeviceInitSetExclusive(device_init, self.exclusive) }
+            .map_err(failed("WdfDeviceInitSetExclusive"))?;
+        // SAFETY: Upheld by the caller
+        unsafe { WdfDeviceInitSetIoType(device_init, self.io_type) }
+            .map_err(failed("WdfDeviceInitSetIoType"))?;
+
+        Ok(())
+    }
+}
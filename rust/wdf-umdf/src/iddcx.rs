@@ -1,11 +1,11 @@
 #![allow(non_snake_case)]
 #![allow(clippy::missing_errors_doc)]
 
-use std::sync::OnceLock;
-
 use wdf_umdf_sys::{
-    IDARG_IN_ADAPTER_INIT, IDARG_IN_MONITORCREATE, IDARG_IN_QUERY_HWCURSOR,
+    IDARG_IN_ADAPTERSETRENDERADAPTER, IDARG_IN_ADAPTER_INIT, IDARG_IN_GETDIRTYRECTS,
+    IDARG_IN_GETMOVEREGIONS, IDARG_IN_MONITORCREATE, IDARG_IN_QUERY_HWCURSOR,
     IDARG_IN_SETUP_HWCURSOR, IDARG_IN_SWAPCHAINSETDEVICE, IDARG_OUT_ADAPTER_INIT,
+    IDARG_OUT_GETDIRTYRECTS, IDARG_OUT_GETMOVEREGIONS, IDARG_OUT_GETVERSION,
     IDARG_OUT_MONITORARRIVAL, IDARG_OUT_MONITORCREATE, IDARG_OUT_QUERY_HWCURSOR,
     IDARG_OUT_RELEASEANDACQUIREBUFFER, IDDCX_ADAPTER, IDDCX_MONITOR, IDDCX_SWAPCHAIN,
     IDD_CX_CLIENT_CONFIG, NTSTATUS, WDFDEVICE, WDFDEVICE_INIT,
@@ -51,40 +51,56 @@ macro_rules! IddCxCall {
     };
 
     ($other_is_error:expr, $name:ident ( $($args:expr),* )) => {{
-        static CACHED_FN: OnceLock<
-            Result<
-                ::paste::paste!(::wdf_umdf_sys::[<PFN_ $name:upper>]),
-                IddCxError
-            >
-        > = OnceLock::new();
-
-        let f = CACHED_FN.get_or_init(|| {
-            ::paste::paste! {
-                const FN_INDEX: usize = ::wdf_umdf_sys::IDDFUNCENUM::[<$name TableIndex>].0 as usize;
-
-                // validate that wdf function can be used
-                let is_available = ::wdf_umdf_sys::IddCxIsFunctionAvailable!($name);
-
-                if is_available {
-                    // SAFETY: Only immutable accesses are done to this
-                    //         The underlying array is Copy, so we call as_ptr() directly on it inside block
-                    let fn_table = unsafe { ::wdf_umdf_sys::IddFunctions.as_ptr() };
-
-                    // SAFETY: Ensured that this is present by if condition from `WdfIsFunctionAvailable!`
-                    let f = unsafe {
-                        fn_table.add(FN_INDEX)
-                            .cast::<::wdf_umdf_sys::[<PFN_ $name:upper>]>()
-                    };
-
-                    // SAFETY: Ensured that this is present by if condition from `IddIsFunctionAvailable!`
-                    let f = unsafe { f.read() };
-
-                    Ok(f)
-                } else {
-                    Err($crate::IddCxError::IddCxFunctionNotAvailable(concat!(stringify!($name), " is not available")))
+        #[cfg(not(feature = "testing"))]
+        let f = {
+            static CACHED_FN: ::std::sync::OnceLock<
+                Result<
+                    ::paste::paste!(::wdf_umdf_sys::[<PFN_ $name:upper>]),
+                    IddCxError
+                >
+            > = ::std::sync::OnceLock::new();
+
+            let f = CACHED_FN.get_or_init(|| {
+                ::paste::paste! {
+                    const FN_INDEX: usize = ::wdf_umdf_sys::IDDFUNCENUM::[<$name TableIndex>].0 as usize;
+
+                    // validate that wdf function can be used
+                    let is_available = ::wdf_umdf_sys::IddCxIsFunctionAvailable!($name);
+
+                    if is_available {
+                        // SAFETY: Only immutable accesses are done to this
+                        //         The underlying array is Copy, so we call as_ptr() directly on it inside block
+                        let fn_table = unsafe { ::wdf_umdf_sys::IddFunctions.as_ptr() };
+
+                        // SAFETY: Ensured that this is present by if condition from `WdfIsFunctionAvailable!`
+                        let f = unsafe {
+                            fn_table.add(FN_INDEX)
+                                .cast::<::wdf_umdf_sys::[<PFN_ $name:upper>]>()
+                        };
+
+                        // SAFETY: Ensured that this is present by if condition from `IddIsFunctionAvailable!`
+                        let f = unsafe { f.read() };
+
+                        Ok(f)
+                    } else {
+                        Err($crate::IddCxError::IddCxFunctionNotAvailable(concat!(stringify!($name), " is not available")))
+                    }
                 }
-            }
-        }).clone()?;
+            }).clone()
+        };
+
+        // the fake table is filled in by the tests, so it isn't cached
+        #[cfg(feature = "testing")]
+        let f = ::paste::paste! {
+            $crate::testing::iddcx_function::<::wdf_umdf_sys::[<PFN_ $name:upper>]>(
+                ::wdf_umdf_sys::IDDFUNCENUM::[<$name TableIndex>].0 as usize
+            )
+            .ok_or($crate::IddCxError::IddCxFunctionNotAvailable(concat!(stringify!($name), " is not available")))
+        };
+
+        let f = f.inspect_err(|e| {
+            $crate::logging::call_failed(stringify!($name), (*e).into());
+        })?;
 
         // SAFETY: Above: If it's Ok, then it's guaranteed to be Some(fn)
         let f = unsafe { f.unwrap_unchecked() };
@@ -96,7 +112,9 @@ macro_rules! IddCxCall {
         let result = unsafe { f(globals, $($args),*) };
 
         if $crate::is_nt_error(&result, $other_is_error) {
-            Err(result.into())
+            let error: $crate::IddCxError = result.into();
+            $crate::logging::call_failed(stringify!($name), error.into());
+            Err(error)
         } else {
             Ok(result.into())
         }
@@ -152,6 +170,24 @@ pub unsafe fn IddCxAdapterInitAsync(
     }
 }
 
+/// # Safety
+///
+/// None. User is responsible for safety.
+#[rustfmt::skip]
+pub unsafe fn IddCxAdapterSetRenderAdapter(
+    // in
+    AdapterObject: IDDCX_ADAPTER,
+    // in
+    pInArgs: &IDARG_IN_ADAPTERSETRENDERADAPTER
+) -> Result<NTSTATUS, IddCxError> {
+    IddCxCall!(
+        IddCxAdapterSetRenderAdapter(
+            AdapterObject,
+            pInArgs
+        )
+    )
+}
+
 /// # Safety
 ///
 /// None. User is responsible for safety.
@@ -245,6 +281,48 @@ pub unsafe fn IddCxSwapChainFinishedProcessingFrame(
     )
 }
 
+/// # Safety
+///
+/// None. User is responsible for safety.
+#[rustfmt::skip]
+pub unsafe fn IddCxSwapChainGetDirtyRects(
+    // in
+    SwapChainObject: IDDCX_SWAPCHAIN,
+    // in
+    pInArgs: &IDARG_IN_GETDIRTYRECTS,
+    // out
+    pOutArgs: &mut IDARG_OUT_GETDIRTYRECTS
+) -> Result<NTSTATUS, IddCxError> {
+    IddCxCall!(
+        IddCxSwapChainGetDirtyRects(
+            SwapChainObject,
+            pInArgs,
+            pOutArgs
+        )
+    )
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+#[rustfmt::skip]
+pub unsafe fn IddCxSwapChainGetMoveRegions(
+    // in
+    SwapChainObject: IDDCX_SWAPCHAIN,
+    // in
+    pInArgs: &IDARG_IN_GETMOVEREGIONS,
+    // out
+    pOutArgs: &mut IDARG_OUT_GETMOVEREGIONS
+) -> Result<NTSTATUS, IddCxError> {
+    IddCxCall!(
+        IddCxSwapChainGetMoveRegions(
+            SwapChainObject,
+            pInArgs,
+            pOutArgs
+        )
+    )
+}
+
 /// # Safety
 ///
 /// None. User is responsible for safety.
@@ -298,3 +376,18 @@ pub unsafe fn IddCxMonitorQueryHardwareCursor(
         )
     )
 }
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+#[rustfmt::skip]
+pub unsafe fn IddCxGetVersion(
+    // out
+    pOutArgs: &mut IDARG_OUT_GETVERSION
+) -> Result<NTSTATUS, IddCxError> {
+    IddCxCall!(
+        IddCxGetVersion(
+            pOutArgs
+        )
+    )
+}
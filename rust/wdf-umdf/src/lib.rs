@@ -1,10 +1,20 @@
+mod cached_context;
+pub mod callback;
+pub mod context_lock;
+mod device_init;
 mod iddcx;
+pub mod logging;
 mod wdf;
 
+#[cfg(feature = "testing")]
+pub mod testing;
+
 use std::any::Any;
 
 pub use paste::paste;
 
+pub use cached_context::CachedContext;
+pub use device_init::{DeviceInitError, SoftwareDeviceInit, FILE_DEVICE_UNKNOWN};
 pub use iddcx::*;
 pub use wdf::*;
 pub use wdf_umdf_sys;
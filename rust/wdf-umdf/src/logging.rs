@@ -0,0 +1,141 @@
+//! ETW provider shared by the wrappers and the driver
+//!
+//! Events are written with TraceLogging, so they are self describing and need no manifest. The
+//! provider id is derived from its name, so a trace session can enable it as
+//! `*VirtualDisplayDriver`, e.g. with `wpr -start VirtualDisplayDriver.wprp` followed by
+//! `wpr -stop vdd.etl` and `tracerpt vdd.etl`.
+
+use tracelogging as tlg;
+use wdf_umdf_sys::NTSTATUS;
+
+tlg::define_provider!(PROVIDER, "VirtualDisplayDriver");
+
+// returned by IddCxSwapChainReleaseAndAcquireBuffer while no new frame is ready, so it's polled
+// for every frame and is no failure
+const E_PENDING: i32 = i32::from_ne_bytes(0x8000_000A_u32.to_ne_bytes());
+
+/// Registers the provider with ETW, events written before this are dropped
+///
+/// # Safety
+///
+/// [unregister] must be called before the driver dll is unloaded.
+pub unsafe fn register() {
+    // not fatal, the driver works the same without events
+    // SAFETY: The caller unregisters before unloading
+    _ = unsafe { PROVIDER.register() };
+}
+
+pub fn unregister() {
+    _ = PROVIDER.unregister();
+}
+
+/// A wdf or IddCx function failed, or is not available
+pub fn call_failed(function: &str, status: NTSTATUS) {
+    if status.0 == E_PENDING {
+        return;
+    }
+
+    tlg::write_event!(
+        PROVIDER,
+        "CallFailed",
+        level(Error),
+        str8("Function", function),
+        ntstatus("Status", &status.0),
+    );
+}
+
+/// A callback panicked, and returned an error to the framework instead of unwinding into it
+pub fn callback_panicked(callback: &str, message: &str) {
+    tlg::write_event!(
+        PROVIDER,
+        "CallbackPanicked",
+        level(Critical),
+        str8("Callback", callback),
+        str8("Message", message),
+    );
+}
+
+/// The lock of a context of type `context` was poisoned by a panic while it was held, and is used
+/// anyway
+pub fn context_lock_poisoned(context: &str) {
+    tlg::write_event!(
+        PROVIDER,
+        "ContextLockPoisoned",
+        level(Critical),
+        str8("Context", context),
+    );
+}
+
+pub fn device_add() {
+    tlg::write_event!(PROVIDER, "DeviceAdd", level(Informational));
+}
+
+/// The device entered D0
+pub fn device_start() {
+    tlg::write_event!(PROVIDER, "DeviceStart", level(Informational));
+}
+
+/// The device left D0, `last` if it is being disabled or removed
+pub fn device_stop(last: bool) {
+    tlg::write_event!(
+        PROVIDER,
+        "DeviceStop",
+        level(Informational),
+        bool8("Last", &last),
+    );
+}
+
+/// A monitor was reported to the OS, `width` and `height` are those of its preferred mode
+pub fn monitor_arrival(id: u32, connector_index: u32, width: u32, height: u32) {
+    tlg::write_event!(
+        PROVIDER,
+        "MonitorArrival",
+        level(Informational),
+        u32("Id", &id),
+        u32("ConnectorIndex", &connector_index),
+        u32("Width", &width),
+        u32("Height", &height),
+    );
+}
+
+pub fn monitor_departure(id: u32) {
+    tlg::write_event!(
+        PROVIDER,
+        "MonitorDeparture",
+        level(Informational),
+        u32("Id", &id),
+    );
+}
+
+/// The OS assigned a swap chain rendered on the adapter with the given LUID
+pub fn swap_chain_assigned(id: u32, adapter_low: u32, adapter_high: i32) {
+    tlg::write_event!(
+        PROVIDER,
+        "SwapChainAssigned",
+        level(Informational),
+        u32("Id", &id),
+        u32("AdapterLowPart", &adapter_low),
+        i32("AdapterHighPart", &adapter_high),
+    );
+}
+
+pub fn swap_chain_unassigned(id: u32) {
+    tlg::write_event!(
+        PROVIDER,
+        "SwapChainUnassigned",
+        level(Informational),
+        u32("Id", &id),
+    );
+}
+
+/// A message was received on the pipe, `pid` is 0 if the client process is unknown
+pub fn ipc_command(connection: u64, pid: u32, command: &str) {
+    tlg::write_event!(
+        PROVIDER,
+        "IpcCommand",
+        level(Verbose),
+        u64("Connection", &connection),
+        u32("ClientPid", &pid),
+        str8("Command", command),
+    );
+}
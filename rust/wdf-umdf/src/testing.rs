@@ -0,0 +1,495 @@
+//! Fake WDF and IddCx function tables, to run driver code without the frameworks.
+//!
+//! With the `testing` feature, the wrappers look their function up here instead of in the tables
+//! the frameworks fill in when the driver loads. [`install`] registers fakes for the object context
+//! and the adapter and monitor lifecycle, which hand out fake handles and record the IddCx calls
+//! made with them, and for timers, which expire on a thread of their own. Other functions fail with `FunctionNotAvailable` until a test registers them
+//! with [`set_wdf_function`] or [`set_iddcx_function`].
+//!
+//! The tables and the recorded calls are shared by the whole process, like the real ones, so tests
+//! using them hold the [`Fakes`] guard, which runs them one after another.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::c_void,
+    mem, ptr, slice,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        LazyLock, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use wdf_umdf_sys::{
+    BOOLEAN, IDARG_IN_ADAPTER_INIT, IDARG_IN_MONITORCREATE, IDARG_OUT_ADAPTER_INIT,
+    IDARG_OUT_GETVERSION, IDARG_OUT_MONITORARRIVAL, IDARG_OUT_MONITORCREATE, IDDCX_MONITOR,
+    IDDFUNCENUM, NTSTATUS, PCWDF_OBJECT_CONTEXT_TYPE_INFO, PFN_WDF_TIMER, PWDF_OBJECT_ATTRIBUTES,
+    PWDF_TIMER_CONFIG, WDFFUNCENUM, WDFOBJECT, WDFTIMER,
+};
+
+/// IddCx version the fake `IddCxGetVersion` reports, IddCx 1.9
+pub const IDDCX_VERSION: u32 = 0x1900;
+/// Low part of the adapter LUID the fake `IddCxMonitorArrival` reports, the high part is 0
+pub const OS_ADAPTER_LUID: u32 = 0x1d0;
+
+// function pointers by their index in the WDF or IddCx function table
+static WDF_FUNCTIONS: LazyLock<Mutex<HashMap<usize, usize>>> = LazyLock::new(Mutex::default);
+static IDDCX_FUNCTIONS: LazyLock<Mutex<HashMap<usize, usize>>> = LazyLock::new(Mutex::default);
+
+// addresses of the objects made by `fake_object`, everything else is no fake handle
+static OBJECTS: LazyLock<Mutex<HashSet<usize>>> = LazyLock::new(Mutex::default);
+static CALLS: Mutex<Vec<IddCxCall>> = Mutex::new(Vec::new());
+// timers made by `timer_create`, by handle
+static TIMERS: LazyLock<Mutex<HashMap<usize, FakeTimer>>> = LazyLock::new(Mutex::default);
+static SERIAL: Mutex<()> = Mutex::new(());
+// target ids handed out by `monitor_arrival`, unique for the whole process like handles
+static NEXT_TARGET_ID: AtomicU32 = AtomicU32::new(1);
+
+/// An IddCx call made through the fakes of [`install`]
+///
+/// Handles are the addresses of the fake objects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IddCxCall {
+    AdapterInitAsync {
+        adapter: usize,
+    },
+    MonitorCreate {
+        adapter: usize,
+        monitor: usize,
+        connector_index: u32,
+        edid: Vec<u8>,
+    },
+    MonitorArrival {
+        monitor: usize,
+    },
+    MonitorDeparture {
+        monitor: usize,
+    },
+}
+
+/// Keeps other tests from using the fakes, see [`install`]
+pub struct Fakes {
+    _serial: MutexGuard<'static, ()>,
+}
+
+/// Register the built-in fakes, waiting until no other test uses them.
+///
+/// Calls recorded before are dropped. Objects made before stay valid, and so do functions
+/// registered by other tests, as the driver keeps handles around for as long as the process runs.
+#[must_use]
+pub fn install() -> Fakes {
+    let serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let wdf: [(WDFFUNCENUM, *const ()); 5] = [
+        (
+            WDFFUNCENUM::WdfObjectGetTypedContextWorkerTableIndex,
+            object_get_typed_context_worker as *const (),
+        ),
+        (
+            WDFFUNCENUM::WdfObjectDeleteTableIndex,
+            object_delete as *const (),
+        ),
+        (
+            WDFFUNCENUM::WdfTimerCreateTableIndex,
+            timer_create as *const (),
+        ),
+        (
+            WDFFUNCENUM::WdfTimerStartTableIndex,
+            timer_start as *const (),
+        ),
+        (WDFFUNCENUM::WdfTimerStopTableIndex, timer_stop as *const ()),
+    ];
+    for (index, f) in wdf {
+        // SAFETY: The fake has the signature of the function it stands in for
+        unsafe { set_wdf_function(index, f) };
+    }
+
+    let iddcx: [(IDDFUNCENUM, *const ()); 5] = [
+        (
+            IDDFUNCENUM::IddCxAdapterInitAsyncTableIndex,
+            adapter_init_async as *const (),
+        ),
+        (
+            IDDFUNCENUM::IddCxMonitorCreateTableIndex,
+            monitor_create as *const (),
+        ),
+        (
+            IDDFUNCENUM::IddCxMonitorArrivalTableIndex,
+            monitor_arrival as *const (),
+        ),
+        (
+            IDDFUNCENUM::IddCxMonitorDepartureTableIndex,
+            monitor_departure as *const (),
+        ),
+        (
+            IDDFUNCENUM::IddCxGetVersionTableIndex,
+            get_version as *const (),
+        ),
+    ];
+    for (index, f) in iddcx {
+        // SAFETY: The fake has the signature of the function it stands in for
+        unsafe { set_iddcx_function(index, f) };
+    }
+
+    lock(&CALLS).clear();
+
+    Fakes { _serial: serial }
+}
+
+/// Make `f` the WDF function at `index`.
+///
+/// # Safety
+///
+/// `f` must be an `extern "C"` function with the signature of the function at `index`, taking the
+/// driver globals first.
+pub unsafe fn set_wdf_function(index: WDFFUNCENUM, f: *const ()) {
+    lock(&WDF_FUNCTIONS).insert(table_index(index.0), f as usize);
+}
+
+/// Make `f` the IddCx function at `index`.
+///
+/// # Safety
+///
+/// `f` must be an `extern "C"` function with the signature of the function at `index`, taking the
+/// driver globals first.
+pub unsafe fn set_iddcx_function(index: IDDFUNCENUM, f: *const ()) {
+    lock(&IDDCX_FUNCTIONS).insert(table_index(index.0), f as usize);
+}
+
+/// The IddCx calls recorded since the last time, oldest first
+#[must_use]
+pub fn take_calls() -> Vec<IddCxCall> {
+    mem::take(&mut *lock(&CALLS))
+}
+
+/// A new fake object handle, which can carry contexts like a framework object.
+///
+/// It lives until the process exits.
+#[must_use]
+pub fn fake_object() -> WDFOBJECT {
+    let object = Box::into_raw(Box::new(FakeObject::default()));
+    lock(&OBJECTS).insert(object as usize);
+
+    object.cast()
+}
+
+#[doc(hidden)]
+pub fn wdf_function<F: Copy>(index: usize) -> Option<F> {
+    let f = lock(&WDF_FUNCTIONS).get(&index).copied()?;
+
+    // SAFETY: `F` is the function pointer type of the function at `index`, see `set_wdf_function`
+    Some(unsafe { as_function(f) })
+}
+
+#[doc(hidden)]
+pub fn iddcx_function<F: Copy>(index: usize) -> Option<F> {
+    let f = lock(&IDDCX_FUNCTIONS).get(&index).copied()?;
+
+    // SAFETY: `F` is the function pointer type of the function at `index`, see `set_iddcx_function`
+    Some(unsafe { as_function(f) })
+}
+
+/// # Safety
+///
+/// `f` must be a function of type `F`
+unsafe fn as_function<F: Copy>(f: usize) -> F {
+    assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>());
+
+    // SAFETY: Same size, the caller upholds the type
+    unsafe { mem::transmute_copy(&f) }
+}
+
+// table indices are never negative, see `WdfCall!`
+fn table_index<I: TryInto<usize>>(index: I) -> usize {
+    index.try_into().unwrap_or(usize::MAX)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn record(call: IddCxCall) {
+    lock(&CALLS).push(call);
+}
+
+#[derive(Default)]
+struct FakeObject {
+    // zeroed memory of each context type, by `UniqueType`
+    contexts: Mutex<HashMap<usize, usize>>,
+}
+
+impl FakeObject {
+    /// The object behind `handle`, `None` if it is no fake object
+    fn get(handle: WDFOBJECT) -> Option<&'static Self> {
+        if !lock(&OBJECTS).contains(&(handle as usize)) {
+            return None;
+        }
+
+        // SAFETY: Made by `fake_object`, and never freed
+        Some(unsafe { &*handle.cast::<Self>() })
+    }
+
+    fn context(&self, unique_type: usize, size: usize) -> *mut c_void {
+        let mut contexts = lock(&self.contexts);
+
+        let context = contexts.entry(unique_type).or_insert_with(|| {
+            // u64s, so the context is aligned for the pointers it holds
+            let memory = vec![0u64; size.div_ceil(mem::size_of::<u64>())].leak();
+            memory.as_mut_ptr() as usize
+        });
+
+        *context as *mut c_void
+    }
+}
+
+struct FakeTimer {
+    expired: PFN_WDF_TIMER,
+    // bumped by every start and stop, so a thread only fires the start it was spawned for
+    generation: u64,
+    queued: bool,
+}
+
+// WdfObjectGetTypedContextWorker, contexts are allocated on first use instead of on creation
+unsafe extern "C" fn object_get_typed_context_worker(
+    _globals: *mut c_void,
+    handle: WDFOBJECT,
+    type_info: PCWDF_OBJECT_CONTEXT_TYPE_INFO,
+) -> *mut c_void {
+    let Some(object) = FakeObject::get(handle) else {
+        return ptr::null_mut();
+    };
+
+    // SAFETY: WDF requires valid type info
+    let type_info = unsafe { &*type_info };
+    object.context(type_info.UniqueType as usize, type_info.ContextSize)
+}
+
+// WdfObjectDelete, fake objects live until the process exits
+unsafe extern "C" fn object_delete(_globals: *mut c_void, _object: WDFOBJECT) {}
+
+// WdfTimerCreate, the timer is a fake object so it can carry a context
+unsafe extern "C" fn timer_create(
+    _globals: *mut c_void,
+    config: PWDF_TIMER_CONFIG,
+    _attributes: PWDF_OBJECT_ATTRIBUTES,
+    timer: *mut WDFTIMER,
+) -> NTSTATUS {
+    let object = fake_object();
+
+    // SAFETY: WDF requires a valid config
+    let expired = unsafe { (*config).EvtTimerFunc };
+    lock(&TIMERS).insert(
+        object as usize,
+        FakeTimer {
+            expired,
+            generation: 0,
+            queued: false,
+        },
+    );
+
+    // SAFETY: WDF requires a valid out pointer
+    unsafe { *timer = object.cast() };
+
+    NTSTATUS::STATUS_SUCCESS
+}
+
+// WdfTimerStart, every start spawns a thread which sleeps until the timer is due. Absolute due
+// times aren't faked, they expire right away
+unsafe extern "C" fn timer_start(_globals: *mut c_void, timer: WDFTIMER, due_time: i64) -> BOOLEAN {
+    let handle = timer as usize;
+
+    let mut timers = lock(&TIMERS);
+    let Some(fake) = timers.get_mut(&handle) else {
+        return 0;
+    };
+
+    let queued = mem::replace(&mut fake.queued, true);
+    fake.generation += 1;
+    let generation = fake.generation;
+
+    let due = u64::try_from(due_time.saturating_neg()).unwrap_or(0);
+    let due = Duration::from_nanos(due.saturating_mul(100));
+
+    thread::spawn(move || {
+        thread::sleep(due);
+
+        let expired = {
+            let mut timers = lock(&TIMERS);
+            let Some(fake) = timers
+                .get_mut(&handle)
+                .filter(|fake| fake.queued && fake.generation == generation)
+            else {
+                return;
+            };
+
+            fake.queued = false;
+            fake.expired
+        };
+
+        if let Some(expired) = expired {
+            // SAFETY: The callback was handed to `timer_create` for this timer
+            unsafe { expired(handle as WDFTIMER) };
+        }
+    });
+
+    queued.into()
+}
+
+// WdfTimerStop, a callback which already started is not waited for
+unsafe extern "C" fn timer_stop(_globals: *mut c_void, timer: WDFTIMER, _wait: BOOLEAN) -> BOOLEAN {
+    let mut timers = lock(&TIMERS);
+    let Some(fake) = timers.get_mut(&(timer as usize)) else {
+        return 0;
+    };
+
+    fake.generation += 1;
+    mem::replace(&mut fake.queued, false).into()
+}
+
+// IddCxAdapterInitAsync, the adapter is ready right away and `EvtIddCxAdapterInitFinished` is not
+// called
+unsafe extern "C" fn adapter_init_async(
+    _globals: *mut c_void,
+    _in_args: *const IDARG_IN_ADAPTER_INIT,
+    out_args: *mut IDARG_OUT_ADAPTER_INIT,
+) -> NTSTATUS {
+    let adapter = fake_object();
+    record(IddCxCall::AdapterInitAsync {
+        adapter: adapter as usize,
+    });
+
+    // SAFETY: IddCx requires valid out args
+    unsafe { (*out_args).AdapterObject = adapter.cast() };
+
+    NTSTATUS::STATUS_SUCCESS
+}
+
+// IddCxMonitorCreate
+unsafe extern "C" fn monitor_create(
+    _globals: *mut c_void,
+    adapter: *mut c_void,
+    in_args: *const IDARG_IN_MONITORCREATE,
+    out_args: *mut IDARG_OUT_MONITORCREATE,
+) -> NTSTATUS {
+    // SAFETY: IddCx requires valid in args
+    let in_args = unsafe { &*in_args };
+    // SAFETY: And valid monitor info in them
+    let info = unsafe { &*in_args.pMonitorInfo };
+    let description = &info.MonitorDescription;
+
+    let edid = if description.pData.is_null() {
+        Vec::new()
+    } else {
+        // SAFETY: IddCx requires `DataSize` bytes at `pData`
+        unsafe {
+            slice::from_raw_parts(
+                description.pData.cast::<u8>(),
+                description.DataSize as usize,
+            )
+        }
+        .to_vec()
+    };
+
+    let monitor = fake_object();
+    record(IddCxCall::MonitorCreate {
+        adapter: adapter as usize,
+        monitor: monitor as usize,
+        connector_index: info.ConnectorIndex,
+        edid,
+    });
+
+    // SAFETY: IddCx requires valid out args
+    unsafe { (*out_args).MonitorObject = monitor.cast() };
+
+    NTSTATUS::STATUS_SUCCESS
+}
+
+// IddCxMonitorArrival
+unsafe extern "C" fn monitor_arrival(
+    _globals: *mut c_void,
+    monitor: IDDCX_MONITOR,
+    out_args: *mut IDARG_OUT_MONITORARRIVAL,
+) -> NTSTATUS {
+    record(IddCxCall::MonitorArrival {
+        monitor: monitor as usize,
+    });
+
+    // SAFETY: IddCx requires valid out args
+    let out_args = unsafe { &mut *out_args };
+    out_args.OsAdapterLuid.LowPart = OS_ADAPTER_LUID;
+    out_args.OsAdapterLuid.HighPart = 0;
+    out_args.OsTargetId = NEXT_TARGET_ID.fetch_add(1, Ordering::Relaxed);
+
+    NTSTATUS::STATUS_SUCCESS
+}
+
+// IddCxMonitorDeparture
+unsafe extern "C" fn monitor_departure(_globals: *mut c_void, monitor: IDDCX_MONITOR) -> NTSTATUS {
+    record(IddCxCall::MonitorDeparture {
+        monitor: monitor as usize,
+    });
+
+    NTSTATUS::STATUS_SUCCESS
+}
+
+// IddCxGetVersion
+unsafe extern "C" fn get_version(
+    _globals: *mut c_void,
+    out_args: *mut IDARG_OUT_GETVERSION,
+) -> NTSTATUS {
+    // SAFETY: IddCx requires valid out args
+    unsafe { (*out_args).IddCxVersion = IDDCX_VERSION };
+
+    NTSTATUS::STATUS_SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    use crate::{IddCxMonitorDeparture, Timer, WdfObjectGetTypedContextWorker};
+
+    #[test]
+    fn fakes_record_calls() {
+        let _fakes = install();
+
+        let monitor = fake_object();
+        unsafe { IddCxMonitorDeparture(monitor.cast()) }.unwrap();
+        assert_eq!(
+            take_calls(),
+            [IddCxCall::MonitorDeparture {
+                monitor: monitor as usize
+            }]
+        );
+        assert!(take_calls().is_empty());
+
+        // not a fake object
+        let mut other = 0u8;
+        let context = unsafe {
+            WdfObjectGetTypedContextWorker(ptr::from_mut(&mut other).cast(), ptr::null())
+        };
+        assert!(matches!(context, Ok(context) if context.is_null()));
+    }
+
+    #[test]
+    fn fake_timers_expire_once() {
+        let _fakes = install();
+
+        let (expired_tx, expired) = mpsc::channel();
+        let timer =
+            unsafe { Timer::create(fake_object(), move || expired_tx.send(()).unwrap()) }.unwrap();
+
+        assert!(!timer.start(Duration::from_secs(60)).unwrap());
+        // starting again replaces the due time
+        assert!(timer.start(Duration::from_millis(10)).unwrap());
+        expired.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(expired.recv_timeout(Duration::from_millis(50)).is_err());
+
+        assert!(!timer.start(Duration::from_millis(10)).unwrap());
+        assert!(timer.stop(false).unwrap());
+        assert!(expired.recv_timeout(Duration::from_millis(50)).is_err());
+        assert!(!timer.stop(false).unwrap());
+    }
+}
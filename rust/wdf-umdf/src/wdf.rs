@@ -2,15 +2,26 @@
 #![allow(clippy::missing_errors_doc)]
 
 use std::ffi::c_void;
-use std::sync::OnceLock;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::time::Duration;
 
 use wdf_umdf_sys::{
-    DEVPROPTYPE, NTSTATUS, PCUNICODE_STRING, PCWDF_OBJECT_CONTEXT_TYPE_INFO, PDRIVER_OBJECT,
-    POOL_TYPE, PWDFDEVICE_INIT, PWDF_DRIVER_CONFIG, PWDF_OBJECT_ATTRIBUTES, WDFDEVICE, WDFDRIVER,
-    WDFMEMORY, WDFOBJECT, WDF_DEVICE_FAILED_ACTION, WDF_NO_HANDLE, WDF_NO_OBJECT_ATTRIBUTES,
-    WDF_OBJECT_ATTRIBUTES, _WDF_DEVICE_PROPERTY_DATA, _WDF_PNPPOWER_EVENT_CALLBACKS,
+    BOOLEAN, DEVPROPTYPE, NTSTATUS, PCUNICODE_STRING, PCWDF_OBJECT_CONTEXT_TYPE_INFO,
+    PDRIVER_OBJECT, PFN_WDF_OBJECT_CONTEXT_CLEANUP, PFN_WDF_TIMER, PFN_WDF_WORKITEM, POOL_TYPE,
+    PWDFDEVICE_INIT, PWDF_DRIVER_CONFIG, PWDF_OBJECT_ATTRIBUTES, UNICODE_STRING, WDFDEVICE,
+    WDFDRIVER, WDFMEMORY, WDFOBJECT, WDFQUEUE, WDFREQUEST, WDFSPINLOCK, WDFSTRING, WDFTIMER,
+    WDFWAITLOCK, WDFWORKITEM, WDF_DEVICE_FAILED_ACTION, WDF_DEVICE_IO_TYPE, WDF_IO_QUEUE_CONFIG,
+    WDF_NO_HANDLE, WDF_NO_OBJECT_ATTRIBUTES, WDF_OBJECT_ATTRIBUTES,
+    WDF_POWER_POLICY_EVENT_CALLBACKS, WDF_TIMER_CONFIG, WDF_WORKITEM_CONFIG, _GUID,
+    _WDF_DEVICE_PROPERTY_DATA, _WDF_PNPPOWER_EVENT_CALLBACKS,
 };
 
+use timer_context::TimerContext;
+use windows_result::HRESULT;
+use work_item_context::WorkItemContext;
+
 #[derive(Copy, Clone, Debug, thiserror::Error)]
 pub enum WdfError {
     #[error("{0}")]
@@ -21,6 +32,17 @@ pub enum WdfError {
     UpgradeFailed,
     #[error("Failed to lock")]
     LockFailed,
+    #[error("Timed out waiting for the lock")]
+    LockTimeout,
+    #[error("Context is not initialized")]
+    NotInitialized,
+    #[error("Context is already initialized")]
+    AlreadyInitialized,
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    /// An HRESULT which doesn't wrap an NTSTATUS, e.g. one of a D3D call
+    #[error("HRESULT 0x{:08X}", .0 .0)]
+    Hresult(HRESULT),
     #[error("Unknown")]
     Unknown,
     // this is required for success status for ()
@@ -40,6 +62,19 @@ impl From<*mut c_void> for WdfError {
     }
 }
 
+impl From<WDFDRIVER> for WdfError {
+    fn from(_: WDFDRIVER) -> Self {
+        Self::Unknown
+    }
+}
+
+// a BOOLEAN result is never an error, this is only required by the macros
+impl From<BOOLEAN> for WdfError {
+    fn from(_: BOOLEAN) -> Self {
+        Self::Unknown
+    }
+}
+
 impl From<WdfError> for NTSTATUS {
     fn from(value: WdfError) -> Self {
         #[allow(clippy::enum_glob_use)]
@@ -49,6 +84,11 @@ impl From<WdfError> for NTSTATUS {
             CallFailed(status) => status,
             UpgradeFailed => Self::STATUS_INVALID_HANDLE,
             LockFailed => Self::STATUS_WAS_LOCKED,
+            LockTimeout => Self::STATUS_IO_TIMEOUT,
+            NotInitialized => Self::STATUS_INVALID_DEVICE_STATE,
+            AlreadyInitialized => Self::STATUS_ALREADY_INITIALIZED,
+            InvalidArgument(_) => Self::STATUS_INVALID_PARAMETER,
+            Hresult(_) => Self::STATUS_UNSUCCESSFUL,
             Unknown => Self::STATUS_DRIVER_INTERNAL_ERROR,
             _Success => 0.into(),
         }
@@ -61,46 +101,84 @@ impl From<NTSTATUS> for WdfError {
     }
 }
 
+/// HRESULTs made by `HRESULT_FROM_NT` become the NTSTATUS they wrap
+impl From<HRESULT> for WdfError {
+    fn from(value: HRESULT) -> Self {
+        NTSTATUS::try_from(value).map_or(WdfError::Hresult(value), WdfError::CallFailed)
+    }
+}
+
+impl From<windows_result::Error> for WdfError {
+    fn from(value: windows_result::Error) -> Self {
+        value.code().into()
+    }
+}
+
+impl From<WdfError> for windows_result::Error {
+    fn from(value: WdfError) -> Self {
+        match value {
+            WdfError::Hresult(hr) => hr.into(),
+            other => NTSTATUS::from(other).into(),
+        }
+    }
+}
+
 macro_rules! WdfCall {
     ($name:ident ( $($args:expr),* )) => {
         WdfCall!(false, $name($($args),*))
     };
 
     ($other_is_error:expr, $name:ident ( $($args:expr),* )) => {{
-        static CACHED_FN: OnceLock<
-            Result<
-                ::paste::paste!(::wdf_umdf_sys::[<PFN_ $name:upper>]),
-                WdfError
-            >
-        > = OnceLock::new();
-
-        let f = CACHED_FN.get_or_init(|| {
-            ::paste::paste! {
-                const FN_INDEX: usize = ::wdf_umdf_sys::WDFFUNCENUM::[<$name TableIndex>].0 as usize;
-
-                // validate that wdf function can be used
-                let is_available = ::wdf_umdf_sys::WdfIsFunctionAvailable!($name);
-
-                if is_available {
-                    // SAFETY: Only immutable accesses are done to this
-                    let fn_table = unsafe { ::wdf_umdf_sys::WdfFunctions_02031 };
-
-                    // SAFETY: Read-only, initialized by the time we use it, and checked to be in bounds
-                    let f = unsafe {
-                        fn_table
-                        .add(FN_INDEX)
-                        .cast::<::wdf_umdf_sys::[<PFN_ $name:upper>]>()
-                    };
+        #[cfg(not(feature = "testing"))]
+        let f = {
+            static CACHED_FN: ::std::sync::OnceLock<
+                Result<
+                    ::paste::paste!(::wdf_umdf_sys::[<PFN_ $name:upper>]),
+                    WdfError
+                >
+            > = ::std::sync::OnceLock::new();
+
+            let f = CACHED_FN.get_or_init(|| {
+                ::paste::paste! {
+                    const FN_INDEX: usize = ::wdf_umdf_sys::WDFFUNCENUM::[<$name TableIndex>].0 as usize;
+
+                    // validate that wdf function can be used
+                    let is_available = ::wdf_umdf_sys::WdfIsFunctionAvailable!($name);
+
+                    if is_available {
+                        // SAFETY: Only immutable accesses are done to this
+                        let fn_table = unsafe { ::wdf_umdf_sys::WdfFunctions_02031 };
+
+                        // SAFETY: Read-only, initialized by the time we use it, and checked to be in bounds
+                        let f = unsafe {
+                            fn_table
+                            .add(FN_INDEX)
+                            .cast::<::wdf_umdf_sys::[<PFN_ $name:upper>]>()
+                        };
 
-                    // SAFETY: Ensured that this is present by if condition from `WdfIsFunctionAvailable!`
-                    let f = unsafe { f.read() };
+                        // SAFETY: Ensured that this is present by if condition from `WdfIsFunctionAvailable!`
+                        let f = unsafe { f.read() };
 
-                    Ok(f)
-                } else {
-                    Err($crate::WdfError::WdfFunctionNotAvailable(concat!(stringify!($name), " is not available")))
+                        Ok(f)
+                    } else {
+                        Err($crate::WdfError::WdfFunctionNotAvailable(concat!(stringify!($name), " is not available")))
+                    }
                 }
-            }
-        }).clone()?;
+            }).clone()
+        };
+
+        // the fake table is filled in by the tests, so it isn't cached
+        #[cfg(feature = "testing")]
+        let f = ::paste::paste! {
+            $crate::testing::wdf_function::<::wdf_umdf_sys::[<PFN_ $name:upper>]>(
+                ::wdf_umdf_sys::WDFFUNCENUM::[<$name TableIndex>].0 as usize
+            )
+            .ok_or($crate::WdfError::WdfFunctionNotAvailable(concat!(stringify!($name), " is not available")))
+        };
+
+        let f = f.inspect_err(|e| {
+            $crate::logging::call_failed(stringify!($name), (*e).into());
+        })?;
 
         // SAFETY: Above: If it's Ok, then it's guaranteed to be Some(fn)
         let f = unsafe { f.unwrap_unchecked() };
@@ -112,7 +190,9 @@ macro_rules! WdfCall {
         let result = unsafe { f(globals, $($args),*) };
 
         if $crate::is_nt_error(&result, $other_is_error) {
-            Err(result.into())
+            let error: $crate::WdfError = result.into();
+            $crate::logging::call_failed(stringify!($name), error.into());
+            Err(error)
         } else {
             Ok(result.into())
         }
@@ -121,9 +201,20 @@ macro_rules! WdfCall {
 }
 
 /// Unlike the official `WDF_DECLARE_CONTEXT_TYPE` macro, you only need to declare this on the actual data struct want to use
-/// Safety is maintained through a `RwLock` of the underlying data
+/// Safety is maintained through a `RwLock` of the underlying data, see [`context_lock`](crate::context_lock)
+///
+/// This generates associated fns `init`/`get`/`get_timeout`/`drop`/`get_type_info` (and their `_mut`/`try_`
+/// variants) on your `$context_type` with the same visibility. `try_init` refuses to overwrite a context
+/// which holds data already, for callbacks which may run more than once for the same object. `init_cached`/`cached` hand out a
+/// [`CachedContext`](crate::CachedContext) for hot paths, which skips the context lookup. `with`/`with_mut`
+/// return the result of their closure, so nothing derived from the guard outlives it, prefer them when
+/// a framework call follows
 ///
-/// This generates associated fns `init`/`get`/`drop`/`get_type_info` on your `$context_type` with the same visibility
+/// Any object created with the context type in its attributes can carry it. For requests, set the
+/// attributes with [`WdfDeviceInitSetRequestAttributes`], `init` the context once the request
+/// arrives, e.g. in `EvtIoDeviceControl`, and `drop` it in the request's cleanup callback. For the
+/// driver object, pass the attributes to [`WdfDriverCreate`], and find the driver later through
+/// [`WdfGetDriver`] or [`WdfDeviceGetDriver`]
 ///
 /// Example:
 /// ```rust
@@ -145,6 +236,33 @@ macro_rules! WdfCall {
 /// // elsewhere
 /// let mutable_access = IndirectDeviceContext::get_mut(device).unwrap();
 /// ```
+///
+/// Driver wide state, which lives as long as the driver object:
+/// ```ignore
+/// pub struct DriverSettings {
+///     max_monitors: u32,
+/// }
+///
+/// WDF_DECLARE_CONTEXT_TYPE!(pub DriverSettings);
+///
+/// // in DriverEntry
+/// let mut attributes =
+///     WDF_OBJECT_ATTRIBUTES::init_context_type(unsafe { DriverSettings::get_type_info() });
+/// attributes.EvtCleanupCallback = Some(driver_cleanup);
+///
+/// let mut driver = std::ptr::null_mut();
+/// unsafe {
+///     WdfDriverCreate(driver_object, registry_path, Some(&mut attributes), &mut config, Some(&mut driver))?;
+///     DriverSettings { max_monitors: 16 }.init(driver.cast())?;
+/// }
+///
+/// // in EvtDeviceAdd, or anywhere else with a device
+/// let driver = unsafe { WdfDeviceGetDriver(device) }?;
+/// unsafe { DriverSettings::get(driver.cast(), |settings| info!("{}", settings.max_monitors)) }?;
+///
+/// // in driver_cleanup, the EvtCleanupCallback
+/// _ = unsafe { DriverSettings::drop(driver.cast()) };
+/// ```
 #[macro_export]
 macro_rules! WDF_DECLARE_CONTEXT_TYPE {
     ($sv:vis $context_type:ident) => {
@@ -187,13 +305,18 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
 
                 /// Allows us to keep ONE main Arc allocation while handing out weak pointers to the rest of the clones.
                 /// In this way, we can drop the allocation by dropping 1 arc, while letting others still access it
+                ///
+                /// WDF zeroes the context memory of new objects, which is `Empty` with this layout, so it's
+                /// known whether a context was initialized yet
+                #[repr(u8)]
                 enum ArcPointer<T> {
-                    Strong(::std::sync::Arc<T>),
-                    Weak(::std::sync::Weak<T>)
+                    Empty = 0,
+                    Strong(::std::sync::Arc<T>) = 1,
+                    Weak(::std::sync::Weak<T>) = 2,
                 }
 
                 #[repr(transparent)]
-                struct [<WdfObject $context_type>](ArcPointer<::std::sync::RwLock<$context_type>>);
+                struct [<WdfObject $context_type>](ArcPointer<$crate::context_lock::RwLock<$context_type>>);
 
                 impl $context_type {
                     /// Initialize and place context into internal WdfObject
@@ -202,36 +325,102 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                     /// - handle must be a fresh unused object with no data in its context already
                     /// - context type must already have been set up for handle
                     /// - Must be set only once regardless of the object. For all other objects, use clone_into()
+                    ///
+                    /// See `try_init` for callbacks which may run more than once for the same object
                     $sv unsafe fn init(
                         self,
                         handle: $crate::wdf_umdf_sys::WDFOBJECT,
                     ) -> ::std::result::Result<(), $crate::WdfError> {
+                        self.init_cached(handle).map(|_| ())
+                    }
+
+                    /// Same as `init`, but fails with `WdfError::AlreadyInitialized` instead of
+                    /// overwriting a context which holds data already, from `init`, `try_init` or
+                    /// `clone_into`
+                    ///
+                    /// To replace the data, `drop` the context first
+                    ///
+                    /// SAFETY:
+                    /// - context type must already have been set up for handle
+                    /// - The context must not be initialized or dropped on another thread at the same time
+                    /// - Must be set only once regardless of the object. For all other objects, use clone_into()
+                    $sv unsafe fn try_init(
+                        self,
+                        handle: $crate::wdf_umdf_sys::WDFOBJECT,
+                    ) -> ::std::result::Result<(), $crate::WdfError> {
+                        let context = unsafe {
+                            $crate::WdfObjectGetTypedContextWorker(handle, [<_WDF_ $context_type _TYPE_INFO>].cell.get())?
+                        } as *mut [<WdfObject $context_type>];
+
+                        // SAFETY: The memory is zeroed by WDF or was written by us, both are a valid ArcPointer
+                        if !::std::matches!(&(*context).0, ArcPointer::Empty) {
+                            return ::std::result::Result::Err($crate::WdfError::AlreadyInitialized);
+                        }
+
+                        self.init(handle)
+                    }
+
+                    /// Same as `init`, also returning a `CachedContext` to access the context
+                    /// without looking it up every time
+                    ///
+                    /// SAFETY:
+                    /// - Same as `init`
+                    $sv unsafe fn init_cached(
+                        self,
+                        handle: $crate::wdf_umdf_sys::WDFOBJECT,
+                    ) -> ::std::result::Result<$crate::CachedContext<$context_type>, $crate::WdfError> {
                         let context = unsafe {
                             $crate::WdfObjectGetTypedContextWorker(handle, [<_WDF_ $context_type _TYPE_INFO>].cell.get())?
                         } as *mut ::std::mem::MaybeUninit<[<WdfObject $context_type>]>;
 
                         let context = &mut *context;
 
+                        let data = ::std::sync::Arc::new($crate::context_lock::RwLock::new(self));
+                        let cached = $crate::CachedContext::new(handle, &data);
+
                         // Write to the memory location, making the data in it init
-                        context.write(
-                            [<WdfObject $context_type>](
-                                ArcPointer::Strong(::std::sync::Arc::new(::std::sync::RwLock::new(self)))
-                            )
-                        );
+                        context.write([<WdfObject $context_type>](ArcPointer::Strong(data)));
 
-                        Ok(())
+                        Ok(cached)
+                    }
+
+                    /// Look the context up once, for a `CachedContext` to access it through
+                    ///
+                    /// SAFETY:
+                    /// - Must have initialized WdfObject first
+                    /// - Data must not have been dropped
+                    /// - Object must not have been destroyed
+                    $sv unsafe fn cached(
+                        handle: $crate::wdf_umdf_sys::WDFOBJECT,
+                    ) -> ::std::result::Result<$crate::CachedContext<$context_type>, $crate::WdfError> {
+                        let context = unsafe {
+                            $crate::WdfObjectGetTypedContextWorker(handle,
+                                // SAFETY: Reading is always fine, since user cannot obtain mutable reference
+                                (&*[<_WDF_ $context_type _TYPE_INFO>].cell.get()).UniqueType
+                            )?
+                        } as *mut [<WdfObject $context_type>];
+
+                        let context = &*context;
+
+                        let data = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
+                            ArcPointer::Strong(a) => a.clone(),
+                            ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?,
+                        };
+
+                        Ok($crate::CachedContext::new(handle, &data))
                     }
 
-                    /// Initialize handle's context and clone a Weak pointer to self context into it.
+                    /// Initialize handle's context and clone a Weak pointer to the context of `from` into it.
                     /// Internally, these are Arc's, so they will always point to the same data.
                     /// When the main Arc drops, none of these may access memory any longer
                     ///
                     /// SAFETY:
                     /// - handle must be a fresh unused object with no data in its context already
-                    /// - to_handle must have set context_type for this type via WDF_OBJECT_ATTRIBUTES when it was created
-                    /// - to_handle must be a valid T
+                    /// - handle must have set context_type for this type via WDF_OBJECT_ATTRIBUTES when it was created
+                    /// - from must have an initialized context of this type
                     $sv unsafe fn clone_into(
-                        &self,
+                        from: $crate::wdf_umdf_sys::WDFOBJECT,
                         handle: $crate::wdf_umdf_sys::WDFOBJECT
                     ) -> ::std::result::Result<(), $crate::WdfError> {
                         let context = unsafe {
@@ -241,10 +430,11 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                         let context = &mut *context;
 
                         let from_context = unsafe {
-                            $crate::WdfObjectGetTypedContextWorker(self.device as *mut _, [<_WDF_ $context_type _TYPE_INFO>].cell.get())?
+                            $crate::WdfObjectGetTypedContextWorker(from, [<_WDF_ $context_type _TYPE_INFO>].cell.get())?
                         } as *mut [<WdfObject $context_type>];
 
                         let from_context = match &(*from_context).0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
                             ArcPointer::Strong(a) => a.clone(),
                             ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
                         };
@@ -267,7 +457,8 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                     /// - Therefore, init for the context must already have been done on this handle
                     /// - No other mutable/non-mutable refs can exist to data when this is called, or it will alias
                     ///
-                    /// This may overwrite data in the handle's context memory, it is UB to read it after drop (e.g. get*)
+                    /// The context is empty afterwards, so get* fails with `WdfError::NotInitialized`
+                    /// and `try_init` may initialize it again
                     $sv unsafe fn drop(
                         handle: $crate::wdf_umdf_sys::WDFOBJECT,
                     ) -> ::std::result::Result<(), $crate::WdfError> {
@@ -276,8 +467,11 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                             [<_WDF_ $context_type _TYPE_INFO>].cell.get(),
                         )? as *mut [<WdfObject $context_type>];
 
-                        // drop the memory
-                        ::std::ptr::drop_in_place(context);
+                        // drop the memory, leaving an empty context behind
+                        ::std::mem::drop(::std::ptr::replace(
+                            context,
+                            [<WdfObject $context_type>](ArcPointer::Empty),
+                        ));
 
                         Ok(())
                     }
@@ -306,11 +500,12 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                         let context = &*context;
 
                         let context = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
                             ArcPointer::Strong(a) => a.clone(),
                             ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
                         };
 
-                        let guard = context.read().map_err(|_| $crate::WdfError::LockFailed)?;
+                        let guard = $crate::context_lock::read(&context)?;
 
                         cb(&*guard);
 
@@ -341,17 +536,72 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                         let context = &*context;
 
                         let context = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
                             ArcPointer::Strong(a) => a.clone(),
                             ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
                         };
 
-                        let mut guard = context.write().map_err(|_| $crate::WdfError::LockFailed)?;
+                        let mut guard = $crate::context_lock::write(&context)?;
 
                         cb(&mut *guard);
 
                         Ok(())
                     }
 
+                    /// Run `cb` with the context borrowed immutably, and return what it returns
+                    /// Returns `None` without calling cb if the context can't be borrowed, see `get`
+                    ///
+                    /// The lock is released once cb returns, so take what's needed out of the context
+                    /// and make framework calls, which may call back into the driver, afterwards
+                    ///
+                    /// SAFETY:
+                    /// - Must have initialized WdfObject first
+                    /// - Data must not have been dropped
+                    /// - Object must not have been destroyed
+                    $sv unsafe fn with<R, F>(
+                        handle: *mut $crate::wdf_umdf_sys::WDFDEVICE__,
+                        cb: F
+                    ) -> ::std::option::Option<R>
+                    where
+                        F: ::std::ops::FnOnce(&$context_type) -> R
+                    {
+                        let mut result = ::std::option::Option::None;
+
+                        // SAFETY: The caller upholds the same requirements
+                        _ = unsafe {
+                            Self::get(handle, |context| result = ::std::option::Option::Some(cb(context)))
+                        };
+
+                        result
+                    }
+
+                    /// Run `cb` with the context borrowed mutably, and return what it returns
+                    /// Returns `None` without calling cb if the context can't be borrowed, see `get_mut`
+                    ///
+                    /// The lock is released once cb returns, so take what's needed out of the context
+                    /// and make framework calls, which may call back into the driver, afterwards
+                    ///
+                    /// SAFETY:
+                    /// - Must have initialized WdfObject first
+                    /// - Data must not have been dropped
+                    /// - Object must not have been destroyed
+                    $sv unsafe fn with_mut<R, F>(
+                        handle: *mut $crate::wdf_umdf_sys::WDFDEVICE__,
+                        cb: F
+                    ) -> ::std::option::Option<R>
+                    where
+                        F: ::std::ops::FnOnce(&mut $context_type) -> R
+                    {
+                        let mut result = ::std::option::Option::None;
+
+                        // SAFETY: The caller upholds the same requirements
+                        _ = unsafe {
+                            Self::get_mut(handle, |context| result = ::std::option::Option::Some(cb(context)))
+                        };
+
+                        result
+                    }
+
                                         /// Borrow the context immutably
                     /// Function returns with error and won't call cb if it failed to lock
                     ///
@@ -376,11 +626,12 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                         let context = &*context;
 
                         let context = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
                             ArcPointer::Strong(a) => a.clone(),
                             ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
                         };
 
-                        let guard = context.try_read().map_err(|_| $crate::WdfError::LockFailed)?;
+                        let guard = $crate::context_lock::try_read(&context)?;
 
                         cb(&*guard);
 
@@ -411,11 +662,90 @@ macro_rules! WDF_DECLARE_CONTEXT_TYPE {
                         let context = &*context;
 
                         let context = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
+                            ArcPointer::Strong(a) => a.clone(),
+                            ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
+                        };
+
+                        let mut guard = $crate::context_lock::try_write(&context)?;
+
+                        cb(&mut *guard);
+
+                        Ok(())
+                    }
+
+                    /// Borrow the context immutably, waiting up to `timeout` for the lock
+                    /// Function returns with error and won't call cb if it failed to lock,
+                    /// `WdfError::LockTimeout` if it timed out, and `WdfError::UpgradeFailed` if the
+                    /// context is gone
+                    ///
+                    /// SAFETY:
+                    /// - Must have initialized WdfObject first
+                    /// - Data must not have been dropped
+                    /// - Object must not have been destroyed
+                    $sv unsafe fn get_timeout<F>(
+                        handle: *mut $crate::wdf_umdf_sys::WDFDEVICE__,
+                        timeout: ::std::time::Duration,
+                        cb: F
+                    ) -> ::std::result::Result<(), $crate::WdfError>
+                    where
+                        F: ::std::ops::FnOnce(&$context_type)
+                    {
+                        let context = unsafe {
+                            $crate::WdfObjectGetTypedContextWorker(handle as *mut _,
+                                // SAFETY: Reading is always fine, since user cannot obtain mutable reference
+                                (&*[<_WDF_ $context_type _TYPE_INFO>].cell.get()).UniqueType
+                            )?
+                        } as *mut [<WdfObject $context_type>];
+
+                        let context = &*context;
+
+                        let context = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
+                            ArcPointer::Strong(a) => a.clone(),
+                            ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
+                        };
+
+                        let guard = $crate::context_lock::read_timeout(&context, timeout)?;
+
+                        cb(&*guard);
+
+                        Ok(())
+                    }
+
+                    /// Borrow the context mutably, waiting up to `timeout` for the lock
+                    /// Function returns with error and won't call cb if it failed to lock,
+                    /// `WdfError::LockTimeout` if it timed out, and `WdfError::UpgradeFailed` if the
+                    /// context is gone
+                    ///
+                    /// SAFETY:
+                    /// - Must have initialized WdfObject first
+                    /// - Data must not have been dropped
+                    /// - Object must not have been destroyed
+                    $sv unsafe fn get_mut_timeout<F>(
+                        handle: *mut $crate::wdf_umdf_sys::WDFDEVICE__,
+                        timeout: ::std::time::Duration,
+                        cb: F
+                    ) -> ::std::result::Result<(), $crate::WdfError>
+                    where
+                        F: ::std::ops::FnOnce(&mut $context_type)
+                    {
+                        let context = unsafe {
+                            $crate::WdfObjectGetTypedContextWorker(handle as *mut _,
+                                // SAFETY: Reading is always fine, since user cannot obtain mutable reference
+                                (&*[<_WDF_ $context_type _TYPE_INFO>].cell.get()).UniqueType
+                            )?
+                        } as *mut [<WdfObject $context_type>];
+
+                        let context = &*context;
+
+                        let context = match &context.0 {
+                            ArcPointer::Empty => return ::std::result::Result::Err($crate::WdfError::NotInitialized),
                             ArcPointer::Strong(a) => a.clone(),
                             ArcPointer::Weak(a) => a.upgrade().ok_or($crate::WdfError::UpgradeFailed)?.clone(),
                         };
 
-                        let mut guard = context.try_write().map_err(|_| $crate::WdfError::LockFailed)?;
+                        let mut guard = $crate::context_lock::write_timeout(&context, timeout)?;
 
                         cb(&mut *guard);
 
@@ -443,7 +773,7 @@ pub unsafe fn WdfDriverCreate(
     // in
     RegistryPath: PCUNICODE_STRING,
     // in, optional
-    DriverAttributes: Option<PWDF_OBJECT_ATTRIBUTES>,
+    DriverAttributes: Option<&mut WDF_OBJECT_ATTRIBUTES>,
     // in
     DriverConfig: PWDF_DRIVER_CONFIG,
     // out, optional
@@ -453,7 +783,7 @@ pub unsafe fn WdfDriverCreate(
         WdfDriverCreate(
             DriverObject,
             RegistryPath,
-            DriverAttributes.unwrap_or(WDF_NO_OBJECT_ATTRIBUTES!()),
+            DriverAttributes.map_or(WDF_NO_OBJECT_ATTRIBUTES!(), std::ptr::from_mut),
             DriverConfig,
             Driver
                 .map(std::ptr::from_mut)
@@ -462,6 +792,97 @@ pub unsafe fn WdfDriverCreate(
     }
 }
 
+/// The driver object created by `WdfDriverCreate`, like the `WdfGetDriver`
+/// macro.
+///
+/// # Safety
+///
+/// Must only be called after `WdfDriverCreate` succeeded.
+#[must_use]
+pub unsafe fn WdfGetDriver() -> WDFDRIVER {
+    // SAFETY: Pointer to globals is always immutable
+    let globals = unsafe { ::wdf_umdf_sys::WdfDriverGlobals };
+
+    // SAFETY: Caller guarantees the framework initialized the globals
+    unsafe { (*globals).Driver }
+}
+
+/// The driver object `Device` belongs to.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceGetDriver(
+    // in
+    Device: WDFDEVICE,
+) -> Result<WDFDRIVER, WdfError> {
+    WdfCall! {
+        WdfDeviceGetDriver(
+            Device
+        )
+    }
+}
+
+/// Write the version of the WDF runtime the driver is bound to into
+/// `String`, which must have been created with `WdfStringCreate`.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDriverRetrieveVersionString(
+    // in
+    Driver: WDFDRIVER,
+    // in
+    String: WDFSTRING,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfDriverRetrieveVersionString(
+            Driver,
+            String
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfStringCreate(
+    // in, optional
+    UnicodeString: Option<PCUNICODE_STRING>,
+    // in, optional
+    StringAttributes: Option<PWDF_OBJECT_ATTRIBUTES>,
+    // out
+    String: &mut WDFSTRING,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfStringCreate(
+            UnicodeString.unwrap_or(std::ptr::null()),
+            StringAttributes.unwrap_or(WDF_NO_OBJECT_ATTRIBUTES!()),
+            String
+        )
+    }
+}
+
+/// The buffer of `UnicodeString` is owned by `String`, and only valid
+/// until it's deleted.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfStringGetUnicodeString(
+    // in
+    String: WDFSTRING,
+    // out
+    UnicodeString: &mut UNICODE_STRING,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfStringGetUnicodeString(
+            String,
+            UnicodeString
+        )
+    }
+}
+
 /// # Safety
 ///
 /// None. User is responsible for safety.
@@ -502,49 +923,392 @@ pub unsafe fn WdfDeviceInitSetPnpPowerEventCallbacks(
 /// # Safety
 ///
 /// None. User is responsible for safety.
-pub unsafe fn WdfObjectGetTypedContextWorker(
+pub unsafe fn WdfDeviceInitSetPowerPolicyEventCallbacks(
     // in
-    Handle: WDFOBJECT,
+    DeviceInit: PWDFDEVICE_INIT,
     // in
-    TypeInfo: PCWDF_OBJECT_CONTEXT_TYPE_INFO,
-) -> Result<*mut c_void, WdfError> {
+    PowerPolicyEventCallbacks: &mut WDF_POWER_POLICY_EVENT_CALLBACKS,
+) -> Result<(), WdfError> {
     WdfCall! {
-        WdfObjectGetTypedContextWorker(
-            Handle,
-            TypeInfo
+        WdfDeviceInitSetPowerPolicyEventCallbacks(
+            DeviceInit,
+            PowerPolicyEventCallbacks
         )
     }
 }
 
+/// Must be called before `WdfDeviceCreate`. Only the power policy owner
+/// receives the callbacks set with [`WdfDeviceInitSetPowerPolicyEventCallbacks`].
+///
 /// # Safety
 ///
 /// None. User is responsible for safety.
-pub unsafe fn WdfObjectDelete(
+pub unsafe fn WdfDeviceInitSetPowerPolicyOwnership(
     // in
-    Object: WDFOBJECT,
+    DeviceInit: PWDFDEVICE_INIT,
+    // in
+    IsPowerPolicyOwner: bool,
 ) -> Result<(), WdfError> {
     WdfCall! {
-        WdfObjectDelete(
-            Object
+        WdfDeviceInitSetPowerPolicyOwnership(
+            DeviceInit,
+            u8::from(IsPowerPolicyOwner)
         )
     }
 }
 
+/// Name the device object, like `\Device\VirtualDisplayCtl`. `None`
+/// removes a previously assigned name.
+///
+/// Must be called before `WdfDeviceCreate`. The framework copies the name.
+/// Names with a NUL character, or too long for a `UNICODE_STRING`, are
+/// rejected with [`WdfError::InvalidArgument`] without calling the framework.
+///
 /// # Safety
 ///
 /// None. User is responsible for safety.
-pub unsafe fn WdfDeviceSetFailed(
-    // in
-    Device: WDFDEVICE,
+pub unsafe fn WdfDeviceInitAssignName(
     // in
-    FailedAction: WDF_DEVICE_FAILED_ACTION,
-) -> Result<(), WdfError> {
-    WdfCall! {
-        WdfDeviceSetFailed(
-            Device,
-            FailedAction
-        )
-    }
+    DeviceInit: PWDFDEVICE_INIT,
+    // in, optional
+    DeviceName: Option<&str>,
+) -> Result<NTSTATUS, WdfError> {
+    let Some(name) = DeviceName else {
+        return WdfCall! {
+            WdfDeviceInitAssignName(
+                DeviceInit,
+                std::ptr::null()
+            )
+        };
+    };
+
+    if name.is_empty() {
+        return Err(WdfError::InvalidArgument("device name is empty"));
+    }
+
+    if name.contains('\0') {
+        return Err(WdfError::InvalidArgument(
+            "device name contains a NUL character",
+        ));
+    }
+
+    let mut buffer = name.encode_utf16().collect::<Vec<_>>();
+    // length is in bytes, and doesn't include a terminator
+    let Ok(len) = u16::try_from(buffer.len() * 2) else {
+        return Err(WdfError::InvalidArgument("device name is too long"));
+    };
+
+    let unicode = UNICODE_STRING {
+        Length: len,
+        MaximumLength: len,
+        Buffer: buffer.as_mut_ptr(),
+    };
+
+    WdfCall! {
+        WdfDeviceInitAssignName(
+            DeviceInit,
+            &unicode
+        )
+    }
+}
+
+/// Set the `FILE_*` device characteristics, like `FILE_DEVICE_SECURE_OPEN`.
+///
+/// With `OrInValues`, they are added to the characteristics already set,
+/// otherwise they replace them. Must be called before `WdfDeviceCreate`.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceInitSetCharacteristics(
+    // in
+    DeviceInit: PWDFDEVICE_INIT,
+    // in
+    DeviceCharacteristics: u32,
+    // in
+    OrInValues: bool,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceInitSetCharacteristics(
+            DeviceInit,
+            DeviceCharacteristics,
+            u8::from(OrInValues)
+        )
+    }
+}
+
+/// Set the `FILE_DEVICE_*` type of the device object, `FILE_DEVICE_UNKNOWN`
+/// if not set. Must be called before `WdfDeviceCreate`.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceInitSetDeviceType(
+    // in
+    DeviceInit: PWDFDEVICE_INIT,
+    // in
+    DeviceType: u32,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceInitSetDeviceType(
+            DeviceInit,
+            DeviceType
+        )
+    }
+}
+
+/// Whether only one handle to the device can be open at a time, off if
+/// not set. Must be called before `WdfDeviceCreate`.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceInitSetExclusive(
+    // in
+    DeviceInit: PWDFDEVICE_INIT,
+    // in
+    IsExclusive: bool,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceInitSetExclusive(
+            DeviceInit,
+            u8::from(IsExclusive)
+        )
+    }
+}
+
+/// How the buffers of read and write requests are passed to the driver,
+/// buffered if not set. Must be called before `WdfDeviceCreate`.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceInitSetIoType(
+    // in
+    DeviceInit: PWDFDEVICE_INIT,
+    // in
+    IoType: WDF_DEVICE_IO_TYPE,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceInitSetIoType(
+            DeviceInit,
+            IoType
+        )
+    }
+}
+
+/// Attributes of every request the framework creates for the device, like
+/// a context type to carry per-request state. Must be called before
+/// `WdfDeviceCreate`.
+///
+/// The context of a request is only initialized once the driver calls its
+/// `init`, and must be dropped from the `EvtCleanupCallback` of these
+/// attributes.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceInitSetRequestAttributes(
+    // in
+    DeviceInit: PWDFDEVICE_INIT,
+    // in
+    RequestAttributes: &mut WDF_OBJECT_ATTRIBUTES,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceInitSetRequestAttributes(
+            DeviceInit,
+            RequestAttributes
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfObjectGetTypedContextWorker(
+    // in
+    Handle: WDFOBJECT,
+    // in
+    TypeInfo: PCWDF_OBJECT_CONTEXT_TYPE_INFO,
+) -> Result<*mut c_void, WdfError> {
+    WdfCall! {
+        WdfObjectGetTypedContextWorker(
+            Handle,
+            TypeInfo
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfObjectDelete(
+    // in
+    Object: WDFOBJECT,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfObjectDelete(
+            Object
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceSetFailed(
+    // in
+    Device: WDFDEVICE,
+    // in
+    FailedAction: WDF_DEVICE_FAILED_ACTION,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceSetFailed(
+            Device,
+            FailedAction
+        )
+    }
+}
+
+/// Keep the device in D0 until the matching [`WdfDeviceResumeIdle`]. Calls
+/// are counted, the device may idle again once every call was matched.
+///
+/// Without `WaitForD0`, returns `STATUS_PENDING` if the device is still on
+/// its way back to D0.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceStopIdle(
+    // in
+    Device: WDFDEVICE,
+    // in
+    WaitForD0: bool,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfDeviceStopIdleNoTrack(
+            Device,
+            u8::from(WaitForD0)
+        )
+    }
+}
+
+/// Match a successful [`WdfDeviceStopIdle`].
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceResumeIdle(
+    // in
+    Device: WDFDEVICE,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfDeviceResumeIdleNoTrack(
+            Device
+        )
+    }
+}
+
+/// Publish an instance of the device interface class `InterfaceClassGUID`,
+/// which clients find the device by. `ReferenceString` tells several
+/// instances of the same class apart.
+///
+/// The interface is enabled once the device starts, and disabled when it is
+/// removed. Reference strings with a NUL character, or too long for a
+/// `UNICODE_STRING`, are rejected with [`WdfError::InvalidArgument`] without
+/// calling the framework.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceCreateDeviceInterface(
+    // in
+    Device: WDFDEVICE,
+    // in
+    InterfaceClassGUID: &_GUID,
+    // in, optional
+    ReferenceString: Option<&str>,
+) -> Result<(), WdfError> {
+    let mut buffer = reference_string(ReferenceString)?;
+    let reference = buffer.as_deref_mut().map(unicode_string);
+
+    let status: Result<NTSTATUS, WdfError> = WdfCall! {
+        WdfDeviceCreateDeviceInterface(
+            Device,
+            InterfaceClassGUID,
+            reference.as_ref().map_or(std::ptr::null(), std::ptr::from_ref)
+        )
+    };
+
+    status.map(|_| ())
+}
+
+/// Enable or disable an interface published with
+/// [`WdfDeviceCreateDeviceInterface`], e.g. while the device can't serve
+/// clients.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfDeviceSetDeviceInterfaceState(
+    // in
+    Device: WDFDEVICE,
+    // in
+    InterfaceClassGUID: &_GUID,
+    // in, optional
+    ReferenceString: Option<&str>,
+    // in
+    IsInterfaceEnabled: bool,
+) -> Result<(), WdfError> {
+    let mut buffer = reference_string(ReferenceString)?;
+    let reference = buffer.as_deref_mut().map(unicode_string);
+
+    WdfCall! {
+        WdfDeviceSetDeviceInterfaceState(
+            Device,
+            InterfaceClassGUID,
+            reference.as_ref().map_or(std::ptr::null(), std::ptr::from_ref),
+            u8::from(IsInterfaceEnabled)
+        )
+    }
+}
+
+/// UTF-16 copy of an interface reference string, checked to fit a
+/// `UNICODE_STRING`
+fn reference_string(reference: Option<&str>) -> Result<Option<Vec<u16>>, WdfError> {
+    let Some(reference) = reference else {
+        return Ok(None);
+    };
+
+    if reference.is_empty() {
+        return Err(WdfError::InvalidArgument("reference string is empty"));
+    }
+
+    if reference.contains('\0') {
+        return Err(WdfError::InvalidArgument(
+            "reference string contains a NUL character",
+        ));
+    }
+
+    let buffer = reference.encode_utf16().collect::<Vec<_>>();
+    if u16::try_from(buffer.len() * 2).is_err() {
+        return Err(WdfError::InvalidArgument("reference string is too long"));
+    }
+
+    Ok(Some(buffer))
+}
+
+/// `UNICODE_STRING` pointing into `buffer`, which must fit one
+fn unicode_string(buffer: &mut [u16]) -> UNICODE_STRING {
+    // length is in bytes, and doesn't include a terminator
+    #[allow(clippy::cast_possible_truncation)]
+    let len = (buffer.len() * 2) as u16;
+
+    UNICODE_STRING {
+        Length: len,
+        MaximumLength: len,
+        Buffer: buffer.as_mut_ptr(),
+    }
 }
 
 /// # Safety
@@ -576,6 +1340,35 @@ pub unsafe fn WdfDeviceAllocAndQueryPropertyEx(
     }
 }
 
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfMemoryCreate(
+    // in, optional
+    Attributes: Option<&mut WDF_OBJECT_ATTRIBUTES>,
+    // in
+    PoolType: POOL_TYPE,
+    // in, optional
+    PoolTag: u32,
+    // in
+    BufferSize: usize,
+    // out
+    Memory: &mut WDFMEMORY,
+    // out, optional
+    Buffer: Option<&mut *mut c_void>,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfMemoryCreate(
+            Attributes.map_or(WDF_NO_OBJECT_ATTRIBUTES!(), std::ptr::from_mut),
+            PoolType,
+            PoolTag,
+            BufferSize,
+            Memory,
+            Buffer.map_or(std::ptr::null_mut(), std::ptr::from_mut)
+        )
+    }
+}
+
 /// # Safety
 ///
 /// None. User is responsible for safety.
@@ -592,3 +1385,996 @@ pub unsafe fn WdfMemoryGetBuffer(
         )
     }
 }
+
+/// Create an I/O queue of `Device`, configured with
+/// [`WDF_IO_QUEUE_CONFIG::init`] or [`WDF_IO_QUEUE_CONFIG::init_default_queue`].
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfIoQueueCreate(
+    // in
+    Device: WDFDEVICE,
+    // in
+    Config: &mut WDF_IO_QUEUE_CONFIG,
+    // in, optional
+    QueueAttributes: Option<&mut WDF_OBJECT_ATTRIBUTES>,
+    // out, optional
+    Queue: Option<&mut WDFQUEUE>,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfIoQueueCreate(
+            Device,
+            Config,
+            QueueAttributes.map_or(WDF_NO_OBJECT_ATTRIBUTES!(), std::ptr::from_mut),
+            Queue.map_or(WDF_NO_HANDLE!(), std::ptr::from_mut)
+        )
+    }
+}
+
+/// Complete `Request` with `Status`, and `Information` as the number of
+/// bytes written to its output buffer.
+///
+/// The request, and its context, must not be used afterwards.
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfRequestCompleteWithInformation(
+    // in
+    Request: WDFREQUEST,
+    // in
+    Status: NTSTATUS,
+    // in
+    Information: usize,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfRequestCompleteWithInformation(
+            Request,
+            Status,
+            Information as _
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfWorkItemCreate(
+    // in
+    Config: &mut WDF_WORKITEM_CONFIG,
+    // in
+    Attributes: &mut WDF_OBJECT_ATTRIBUTES,
+    // out
+    WorkItem: &mut WDFWORKITEM,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfWorkItemCreate(
+            Config,
+            Attributes,
+            WorkItem
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfWorkItemEnqueue(
+    // in
+    WorkItem: WDFWORKITEM,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfWorkItemEnqueue(
+            WorkItem
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfWorkItemFlush(
+    // in
+    WorkItem: WDFWORKITEM,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfWorkItemFlush(
+            WorkItem
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfTimerCreate(
+    // in
+    Config: &mut WDF_TIMER_CONFIG,
+    // in
+    Attributes: &mut WDF_OBJECT_ATTRIBUTES,
+    // out
+    Timer: &mut WDFTIMER,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfTimerCreate(
+            Config,
+            Attributes,
+            Timer
+        )
+    }
+}
+
+/// `DueTime` is negative for a time relative to now, in 100 ns units. Returns
+/// whether the timer was already queued
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfTimerStart(
+    // in
+    Timer: WDFTIMER,
+    // in
+    DueTime: i64,
+) -> Result<BOOLEAN, WdfError> {
+    WdfCall! {
+        WdfTimerStart(
+            Timer,
+            DueTime
+        )
+    }
+}
+
+/// Returns whether the timer was queued
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfTimerStop(
+    // in
+    Timer: WDFTIMER,
+    // in
+    Wait: bool,
+) -> Result<BOOLEAN, WdfError> {
+    WdfCall! {
+        WdfTimerStop(
+            Timer,
+            Wait.into()
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfSpinLockCreate(
+    // in, optional
+    SpinLockAttributes: Option<&mut WDF_OBJECT_ATTRIBUTES>,
+    // out
+    SpinLock: &mut WDFSPINLOCK,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfSpinLockCreate(
+            SpinLockAttributes.map_or(WDF_NO_OBJECT_ATTRIBUTES!(), std::ptr::from_mut),
+            SpinLock
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfSpinLockAcquire(
+    // in
+    SpinLock: WDFSPINLOCK,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfSpinLockAcquire(
+            SpinLock
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfSpinLockRelease(
+    // in
+    SpinLock: WDFSPINLOCK,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfSpinLockRelease(
+            SpinLock
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfWaitLockCreate(
+    // in, optional
+    LockAttributes: Option<&mut WDF_OBJECT_ATTRIBUTES>,
+    // out
+    Lock: &mut WDFWAITLOCK,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfWaitLockCreate(
+            LockAttributes.map_or(WDF_NO_OBJECT_ATTRIBUTES!(), std::ptr::from_mut),
+            Lock
+        )
+    }
+}
+
+/// Returns `STATUS_TIMEOUT` (which is not an error) if the lock could not be
+/// acquired within `Timeout`
+///
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfWaitLockAcquire(
+    // in
+    Lock: WDFWAITLOCK,
+    // in, optional
+    Timeout: Option<&mut i64>,
+) -> Result<NTSTATUS, WdfError> {
+    WdfCall! {
+        WdfWaitLockAcquire(
+            Lock,
+            Timeout.map_or(std::ptr::null_mut(), std::ptr::from_mut)
+        )
+    }
+}
+
+/// # Safety
+///
+/// None. User is responsible for safety.
+pub unsafe fn WdfWaitLockRelease(
+    // in
+    Lock: WDFWAITLOCK,
+) -> Result<(), WdfError> {
+    WdfCall! {
+        WdfWaitLockRelease(
+            Lock
+        )
+    }
+}
+
+/// A framework spin lock
+///
+/// IRQL: In KMDF, acquiring raises the IRQL to `DISPATCH_LEVEL` until the lock
+/// is released. UMDF has no IRQL, but follows the same contract: while the lock
+/// is held, do not block, wait, sleep, or acquire a [WaitLock]. Keep the locked
+/// region short. Acquiring the same spin lock twice on one thread deadlocks
+#[derive(Debug)]
+pub struct SpinLock(WDFSPINLOCK);
+
+// SAFETY: Spin lock handles may be used from any thread
+unsafe impl Send for SpinLock {}
+unsafe impl Sync for SpinLock {}
+
+impl SpinLock {
+    /// Create a spin lock which is deleted together with `parent`
+    ///
+    /// # Safety
+    ///
+    /// - `parent` must be a valid framework object
+    /// - The returned lock must not be used after `parent` was deleted
+    pub unsafe fn create(parent: WDFOBJECT) -> Result<Self, WdfError> {
+        let mut attributes = WDF_OBJECT_ATTRIBUTES::init();
+        attributes.ParentObject = parent;
+
+        let mut lock = std::ptr::null_mut();
+
+        // SAFETY: Caller guarantees `parent` is valid
+        unsafe { WdfSpinLockCreate(Some(&mut attributes), &mut lock) }?;
+
+        Ok(Self(lock))
+    }
+
+    /// Acquire the lock, spinning until it is available
+    ///
+    /// The lock is released when the returned guard is dropped
+    pub fn acquire(&self) -> Result<SpinLockGuard<'_>, WdfError> {
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        unsafe { WdfSpinLockAcquire(self.0) }?;
+
+        Ok(SpinLockGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+/// Releases the [SpinLock] when dropped
+///
+/// Must be dropped on the thread that acquired the lock
+#[derive(Debug)]
+pub struct SpinLockGuard<'a> {
+    lock: &'a SpinLock,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for SpinLockGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: Lock was acquired by this guard, and the handle outlives it
+        _ = unsafe { WdfSpinLockRelease(self.lock.0) };
+    }
+}
+
+/// A framework wait lock
+///
+/// IRQL: In KMDF, a wait lock may only be acquired at `PASSIVE_LEVEL` (or at
+/// `DISPATCH_LEVEL` with a zero timeout, see [WaitLock::try_acquire]), and
+/// never while holding a [SpinLock]. In UMDF there is no IRQL, but holding a
+/// [SpinLock] while waiting is still a bug. Unlike spin locks, the holder may
+/// block. Wait locks are not recursive
+#[derive(Debug)]
+pub struct WaitLock(WDFWAITLOCK);
+
+// SAFETY: Wait lock handles may be used from any thread
+unsafe impl Send for WaitLock {}
+unsafe impl Sync for WaitLock {}
+
+impl WaitLock {
+    /// Create a wait lock which is deleted together with `parent`
+    ///
+    /// # Safety
+    ///
+    /// - `parent` must be a valid framework object
+    /// - The returned lock must not be used after `parent` was deleted
+    pub unsafe fn create(parent: WDFOBJECT) -> Result<Self, WdfError> {
+        let mut attributes = WDF_OBJECT_ATTRIBUTES::init();
+        attributes.ParentObject = parent;
+
+        let mut lock = std::ptr::null_mut();
+
+        // SAFETY: Caller guarantees `parent` is valid
+        unsafe { WdfWaitLockCreate(Some(&mut attributes), &mut lock) }?;
+
+        Ok(Self(lock))
+    }
+
+    /// Acquire the lock, waiting as long as needed
+    ///
+    /// The lock is released when the returned guard is dropped
+    pub fn acquire(&self) -> Result<WaitLockGuard<'_>, WdfError> {
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        unsafe { WdfWaitLockAcquire(self.0, None) }?;
+
+        Ok(self.guard())
+    }
+
+    /// Acquire the lock, giving up after `timeout`
+    ///
+    /// Returns `None` if the lock was not available in time. A zero `timeout`
+    /// only tries once, without waiting
+    pub fn try_acquire(&self, timeout: Duration) -> Result<Option<WaitLockGuard<'_>>, WdfError> {
+        // negative values are relative, in units of 100ns
+        let mut timeout = i64::try_from(timeout.as_nanos() / 100).map_or(i64::MIN, |t| -t);
+
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        let status = unsafe { WdfWaitLockAcquire(self.0, Some(&mut timeout)) }?;
+
+        if status == NTSTATUS::STATUS_TIMEOUT {
+            Ok(None)
+        } else {
+            Ok(Some(self.guard()))
+        }
+    }
+
+    fn guard(&self) -> WaitLockGuard<'_> {
+        WaitLockGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Releases the [WaitLock] when dropped
+#[derive(Debug)]
+pub struct WaitLockGuard<'a> {
+    lock: &'a WaitLock,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for WaitLockGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: Lock was acquired by this guard, and the handle outlives it
+        _ = unsafe { WdfWaitLockRelease(self.lock.0) };
+    }
+}
+
+/// Pool tag of the memory objects made by [Memory], UMDF ignores it
+const MEMORY_POOL_TAG: u32 = u32::from_le_bytes(*b"VDDm");
+
+/// A framework memory object, for handing blobs to and from the frameworks
+///
+/// Owns the object, which is deleted when dropped, and derefs to its buffer
+#[derive(Debug)]
+pub struct Memory {
+    memory: WDFMEMORY,
+    buffer: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: Memory handles may be used from any thread, and the buffer is only reached through the
+// borrow rules
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+impl Memory {
+    /// Allocate a zeroed buffer of `len` bytes, which must not be 0
+    pub fn new(len: usize) -> Result<Self, WdfError> {
+        if len == 0 {
+            return Err(WdfError::InvalidArgument("memory objects can't be empty"));
+        }
+
+        let mut memory = std::ptr::null_mut();
+        let mut buffer = std::ptr::null_mut();
+
+        // SAFETY: Without attributes the object belongs to the driver, and is deleted on drop
+        unsafe {
+            WdfMemoryCreate(
+                None,
+                POOL_TYPE::NonPagedPool,
+                MEMORY_POOL_TAG,
+                len,
+                &mut memory,
+                Some(&mut buffer),
+            )
+        }?;
+
+        let Some(buffer) = NonNull::new(buffer.cast::<u8>()) else {
+            // SAFETY: Object was just created, and never handed out
+            _ = unsafe { WdfObjectDelete(memory.cast()) };
+            return Err(WdfError::Unknown);
+        };
+
+        // the frameworks don't promise a zeroed buffer
+        // SAFETY: Buffer is `len` bytes, owned by the new object
+        unsafe { buffer.write_bytes(0, len) };
+
+        Ok(Self {
+            memory,
+            buffer,
+            len,
+        })
+    }
+
+    /// Allocate a buffer holding a copy of `data`
+    pub fn from_slice(data: &[u8]) -> Result<Self, WdfError> {
+        let mut memory = Self::new(data.len())?;
+        memory.copy_from_slice(data);
+        Ok(memory)
+    }
+
+    /// Take ownership of `memory`, e.g. one returned by
+    /// [`WdfDeviceAllocAndQueryPropertyEx`]
+    ///
+    /// # Safety
+    ///
+    /// - `memory` must be a valid memory object, which nobody else deletes
+    /// - Nobody else may access its buffer while the returned value exists
+    pub unsafe fn from_raw(memory: WDFMEMORY) -> Result<Self, WdfError> {
+        let mut len = 0;
+
+        // SAFETY: Caller guarantees `memory` is valid
+        let buffer = unsafe { WdfMemoryGetBuffer(memory, Some(&mut len)) }?;
+
+        let Some(buffer) = NonNull::new(buffer.cast::<u8>()) else {
+            return Err(WdfError::InvalidArgument("memory object has no buffer"));
+        };
+
+        Ok(Self {
+            memory,
+            buffer,
+            len,
+        })
+    }
+
+    /// The memory object, which stays owned by `self`
+    #[must_use]
+    pub fn as_raw(&self) -> WDFMEMORY {
+        self.memory
+    }
+
+    /// Give up ownership of the memory object, which the caller has to delete
+    #[must_use]
+    pub fn into_raw(self) -> WDFMEMORY {
+        let memory = self.memory;
+        std::mem::forget(self);
+        memory
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: Buffer is `len` bytes, and lives as long as the object `self` owns
+        unsafe { std::slice::from_raw_parts(self.buffer.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: Same as for `deref`, and `&mut self` makes the access exclusive
+        unsafe { std::slice::from_raw_parts_mut(self.buffer.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        // SAFETY: The object is owned by `self`, so nobody else deletes it
+        _ = unsafe { WdfObjectDelete(self.memory.cast()) };
+    }
+}
+
+/// A work item running a Rust closure on a WDF worker thread
+///
+/// Use this to move blocking work out of callbacks which must return quickly.
+/// The work item is deleted together with its parent object
+#[derive(Debug, Copy, Clone)]
+pub struct WorkItem(WDFWORKITEM);
+
+// SAFETY: Work item handles may be enqueued and flushed from any thread
+unsafe impl Send for WorkItem {}
+unsafe impl Sync for WorkItem {}
+
+impl WorkItem {
+    /// Create a work item which calls `work` every time it runs
+    ///
+    /// `work` is responsible for its own synchronization, it is not serialized
+    /// with the callbacks of `parent`
+    ///
+    /// # Safety
+    ///
+    /// - `parent` must be a valid device or queue object
+    /// - The returned work item must not be used after `parent` was deleted
+    pub unsafe fn create(
+        parent: WDFOBJECT,
+        work: impl FnMut() + Send + 'static,
+    ) -> Result<Self, WdfError> {
+        let mut config = WDF_WORKITEM_CONFIG::init(Some(work_item_callback));
+        config.AutomaticSerialization = false.into();
+
+        // SAFETY: Type info is never mutated
+        let type_info = unsafe { WorkItemContext::get_type_info() };
+        let mut attributes = WDF_OBJECT_ATTRIBUTES::init_context_type(type_info);
+        attributes.ParentObject = parent;
+        attributes.EvtCleanupCallback = Some(work_item_cleanup);
+
+        let mut work_item = std::ptr::null_mut();
+
+        // SAFETY: Caller guarantees `parent` is valid
+        unsafe { WdfWorkItemCreate(&mut config, &mut attributes, &mut work_item) }?;
+
+        let context = WorkItemContext {
+            work: Box::new(work),
+        };
+
+        // SAFETY: Work item was just created with this context type, and it cannot run before it is enqueued
+        if let Err(e) = unsafe { context.init(work_item.cast()) } {
+            // init only fails when the context can't be looked up, in which case cleanup can't
+            // find it to drop either, so deleting is fine
+            // SAFETY: Work item was never handed out
+            _ = unsafe { WdfObjectDelete(work_item.cast()) };
+            return Err(e);
+        }
+
+        Ok(Self(work_item))
+    }
+
+    /// Queue the work item to run on a worker thread
+    ///
+    /// Does nothing if the work item is already queued
+    pub fn enqueue(&self) -> Result<(), WdfError> {
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        unsafe { WdfWorkItemEnqueue(self.0) }
+    }
+
+    /// Block until the work item finished, if it is currently queued or running
+    ///
+    /// Must not be called from within the work item itself
+    pub fn flush(&self) -> Result<(), WdfError> {
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        unsafe { WdfWorkItemFlush(self.0) }
+    }
+}
+
+crate::wdf_callback! {
+    fn work_item_callback(work_item: WDFWORKITEM) as PFN_WDF_WORKITEM {
+        // SAFETY: Context was initialized in `WorkItem::create`, and is only dropped in cleanup
+        _ = unsafe { WorkItemContext::get_mut(work_item.cast(), |context| (context.work)()) };
+    }
+}
+
+crate::wdf_callback! {
+    fn work_item_cleanup(work_item: WDFOBJECT) as PFN_WDF_OBJECT_CONTEXT_CLEANUP {
+        // SAFETY: Called once, after the work item can no longer run
+        _ = unsafe { WorkItemContext::drop(work_item) };
+    }
+}
+
+// The macro expansion is linted as local code, so its internals need the same allowances
+// a downstream crate gets for free
+#[allow(
+    dead_code,
+    unsafe_op_in_unsafe_fn,
+    clippy::pedantic,
+    clippy::multiple_unsafe_ops_per_block
+)]
+mod work_item_context {
+    pub struct WorkItemContext {
+        pub work: Box<dyn FnMut() + Send>,
+    }
+
+    // SAFETY: `work` is only called behind the context lock
+    unsafe impl Send for WorkItemContext {}
+    unsafe impl Sync for WorkItemContext {}
+
+    crate::WDF_DECLARE_CONTEXT_TYPE!(pub WorkItemContext);
+}
+
+/// A one-shot timer running a Rust closure on a WDF worker thread when it expires
+///
+/// The timer is deleted together with its parent object
+#[derive(Debug, Copy, Clone)]
+pub struct Timer(WDFTIMER);
+
+// SAFETY: Timer handles may be started and stopped from any thread
+unsafe impl Send for Timer {}
+unsafe impl Sync for Timer {}
+
+impl Timer {
+    /// Create a stopped timer which calls `expired` every time it expires
+    ///
+    /// `expired` is responsible for its own synchronization, it is not
+    /// serialized with the callbacks of `parent`
+    ///
+    /// # Safety
+    ///
+    /// - `parent` must be a valid device or queue object
+    /// - The returned timer must not be used after `parent` was deleted
+    pub unsafe fn create(
+        parent: WDFOBJECT,
+        expired: impl FnMut() + Send + 'static,
+    ) -> Result<Self, WdfError> {
+        let mut config = WDF_TIMER_CONFIG::init(Some(timer_callback));
+        config.AutomaticSerialization = false.into();
+
+        // SAFETY: Type info is never mutated
+        let type_info = unsafe { TimerContext::get_type_info() };
+        let mut attributes = WDF_OBJECT_ATTRIBUTES::init_context_type(type_info);
+        attributes.ParentObject = parent;
+        attributes.EvtCleanupCallback = Some(timer_cleanup);
+
+        let mut timer = std::ptr::null_mut();
+
+        // SAFETY: Caller guarantees `parent` is valid
+        unsafe { WdfTimerCreate(&mut config, &mut attributes, &mut timer) }?;
+
+        let context = TimerContext {
+            expired: Box::new(expired),
+        };
+
+        // SAFETY: Timer was just created with this context type, and it cannot expire before it is started
+        if let Err(e) = unsafe { context.init(timer.cast()) } {
+            // same as for a work item, cleanup can't find the context either
+            // SAFETY: Timer was never handed out
+            _ = unsafe { WdfObjectDelete(timer.cast()) };
+            return Err(e);
+        }
+
+        Ok(Self(timer))
+    }
+
+    /// Let the timer expire once, after `due`
+    ///
+    /// A timer which is already running starts over. Returns whether it was
+    /// running
+    pub fn start(&self, due: Duration) -> Result<bool, WdfError> {
+        // relative due times are negative, in 100 ns units
+        let due = i64::try_from(due.as_nanos() / 100).unwrap_or(i64::MAX);
+
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        unsafe { WdfTimerStart(self.0, -due) }.map(|queued| queued != 0)
+    }
+
+    /// Stop the timer, returns whether it was running
+    ///
+    /// With `wait`, this also blocks until a callback which already started
+    /// finished, so it must not be called with it from within the callback
+    pub fn stop(&self, wait: bool) -> Result<bool, WdfError> {
+        // SAFETY: Handle is alive, as guaranteed by the caller of `create`
+        unsafe { WdfTimerStop(self.0, wait) }.map(|queued| queued != 0)
+    }
+}
+
+crate::wdf_callback! {
+    fn timer_callback(timer: WDFTIMER) as PFN_WDF_TIMER {
+        // SAFETY: Context was initialized in `Timer::create`, and is only dropped in cleanup
+        _ = unsafe { TimerContext::get_mut(timer.cast(), |context| (context.expired)()) };
+    }
+}
+
+crate::wdf_callback! {
+    fn timer_cleanup(timer: WDFOBJECT) as PFN_WDF_OBJECT_CONTEXT_CLEANUP {
+        // SAFETY: Called once, after the timer can no longer expire
+        _ = unsafe { TimerContext::drop(timer) };
+    }
+}
+
+// see `work_item_context`
+#[allow(
+    dead_code,
+    unsafe_op_in_unsafe_fn,
+    clippy::pedantic,
+    clippy::multiple_unsafe_ops_per_block
+)]
+mod timer_context {
+    pub struct TimerContext {
+        pub expired: Box<dyn FnMut() + Send>,
+    }
+
+    // SAFETY: `expired` is only called behind the context lock
+    unsafe impl Send for TimerContext {}
+    unsafe impl Sync for TimerContext {}
+
+    crate::WDF_DECLARE_CONTEXT_TYPE!(pub TimerContext);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 0x80070005, HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED)
+    const E_ACCESSDENIED: HRESULT = HRESULT(-2_147_024_891);
+
+    fn d3d_call(hr: HRESULT) -> windows_result::Result<()> {
+        hr.ok()
+    }
+
+    fn wdf_call(status: NTSTATUS) -> Result<(), WdfError> {
+        Ok(status.ok()?)
+    }
+
+    #[test]
+    fn hresults_convert_into_wdf_error() {
+        let nt_error =
+            || -> Result<(), WdfError> { Ok(d3d_call(NTSTATUS::STATUS_NO_MEMORY.into())?) };
+        assert!(matches!(
+            nt_error(),
+            Err(WdfError::CallFailed(NTSTATUS::STATUS_NO_MEMORY))
+        ));
+
+        let win32_error = || -> Result<(), WdfError> { Ok(d3d_call(E_ACCESSDENIED)?) };
+        assert!(matches!(
+            win32_error(),
+            Err(WdfError::Hresult(E_ACCESSDENIED))
+        ));
+        assert_eq!(
+            NTSTATUS::from(WdfError::Hresult(E_ACCESSDENIED)),
+            NTSTATUS::STATUS_UNSUCCESSFUL
+        );
+
+        assert!(d3d_call(HRESULT(0)).is_ok());
+    }
+
+    #[test]
+    fn wdf_error_converts_into_windows_error() {
+        let failed = || -> windows_result::Result<()> { Ok(wdf_call(NTSTATUS::STATUS_NO_MEMORY)?) };
+        let error = failed().unwrap_err();
+        assert_eq!(
+            NTSTATUS::try_from(error.code()),
+            Ok(NTSTATUS::STATUS_NO_MEMORY)
+        );
+
+        let error = windows_result::Error::from(WdfError::Hresult(E_ACCESSDENIED));
+        assert_eq!(error.code(), E_ACCESSDENIED);
+
+        assert!(wdf_call(NTSTATUS::STATUS_SUCCESS).is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    mod context {
+        use crate::{testing, WdfError};
+
+        #[allow(
+            dead_code,
+            unsafe_op_in_unsafe_fn,
+            clippy::pedantic,
+            clippy::multiple_unsafe_ops_per_block
+        )]
+        mod counter {
+            pub struct Counter(pub u32);
+
+            crate::WDF_DECLARE_CONTEXT_TYPE!(pub Counter);
+        }
+
+        use counter::Counter;
+
+        #[test]
+        fn try_init_refuses_initialized_contexts() {
+            let _fakes = testing::install();
+            let object = testing::fake_object();
+
+            let read = || {
+                let mut value = None;
+                unsafe { Counter::get(object.cast(), |counter| value = Some(counter.0)) }
+                    .map(|()| value.unwrap())
+            };
+
+            assert!(matches!(read(), Err(WdfError::NotInitialized)));
+
+            unsafe { Counter(1).try_init(object) }.unwrap();
+            assert!(matches!(
+                unsafe { Counter(2).try_init(object) },
+                Err(WdfError::AlreadyInitialized)
+            ));
+            assert_eq!(read().unwrap(), 1);
+
+            // dropping empties the context, so it can be initialized again
+            unsafe { Counter::drop(object) }.unwrap();
+            assert!(matches!(read(), Err(WdfError::NotInitialized)));
+
+            unsafe { Counter(3).try_init(object) }.unwrap();
+            assert_eq!(read().unwrap(), 3);
+            unsafe { Counter::drop(object) }.unwrap();
+        }
+
+        #[test]
+        fn with_returns_the_closure_result() {
+            let _fakes = testing::install();
+            let object = testing::fake_object();
+
+            assert_eq!(unsafe { Counter::with(object.cast(), |counter| counter.0) }, None);
+
+            unsafe { Counter(1).init(object) }.unwrap();
+            let old = unsafe {
+                Counter::with_mut(object.cast(), |counter| {
+                    counter.0 += 1;
+                    counter.0 - 1
+                })
+            };
+            assert_eq!(old, Some(1));
+            assert_eq!(unsafe { Counter::with(object.cast(), |counter| counter.0) }, Some(2));
+
+            unsafe { Counter::drop(object) }.unwrap();
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    mod memory {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use wdf_umdf_sys::WDFFUNCENUM;
+
+        use super::*;
+        use crate::testing;
+
+        static DELETED: AtomicUsize = AtomicUsize::new(0);
+
+        // WdfMemoryCreate, the buffer is filled with garbage like pool memory may be
+        unsafe extern "C" fn memory_create(
+            _globals: *mut c_void,
+            _attributes: PWDF_OBJECT_ATTRIBUTES,
+            _pool_type: POOL_TYPE,
+            _pool_tag: u32,
+            len: usize,
+            memory: *mut WDFMEMORY,
+            buffer: *mut *mut c_void,
+        ) -> NTSTATUS {
+            let data = Box::leak(Box::new(vec![0xAAu8; len]));
+
+            // SAFETY: WDF requires valid out pointers, `buffer` is optional
+            unsafe { *memory = std::ptr::from_mut(data).cast() };
+            if !buffer.is_null() {
+                // SAFETY: Checked above
+                unsafe { *buffer = data.as_mut_ptr().cast() };
+            }
+
+            NTSTATUS::STATUS_SUCCESS
+        }
+
+        // WdfMemoryGetBuffer
+        unsafe extern "C" fn memory_get_buffer(
+            _globals: *mut c_void,
+            memory: WDFMEMORY,
+            len: *mut usize,
+        ) -> *mut c_void {
+            // SAFETY: Made by `memory_create`
+            let data = unsafe { &mut *memory.cast::<Vec<u8>>() };
+
+            if !len.is_null() {
+                // SAFETY: Checked above
+                unsafe { *len = data.len() };
+            }
+
+            data.as_mut_ptr().cast()
+        }
+
+        // WdfObjectDelete, the buffers are leaked instead
+        unsafe extern "C" fn object_delete(_globals: *mut c_void, _object: WDFOBJECT) {
+            DELETED.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn install() -> testing::Fakes {
+            let fakes = testing::install();
+
+            let fakes_of: [(WDFFUNCENUM, *const ()); 3] = [
+                (
+                    WDFFUNCENUM::WdfMemoryCreateTableIndex,
+                    memory_create as *const (),
+                ),
+                (
+                    WDFFUNCENUM::WdfMemoryGetBufferTableIndex,
+                    memory_get_buffer as *const (),
+                ),
+                (
+                    WDFFUNCENUM::WdfObjectDeleteTableIndex,
+                    object_delete as *const (),
+                ),
+            ];
+            for (index, f) in fakes_of {
+                // SAFETY: The fake has the signature of the function it stands in for
+                unsafe { testing::set_wdf_function(index, f) };
+            }
+
+            DELETED.store(0, Ordering::Relaxed);
+            fakes
+        }
+
+        #[test]
+        fn memory_owns_its_buffer() {
+            let _fakes = install();
+
+            let mut memory = Memory::new(4).unwrap();
+            assert_eq!(&*memory, &[0; 4]);
+            memory[1] = 7;
+
+            // handed out, so not deleted
+            let raw = memory.as_raw();
+            assert_eq!(memory.into_raw(), raw);
+            assert_eq!(DELETED.load(Ordering::Relaxed), 0);
+
+            let memory = unsafe { Memory::from_raw(raw) }.unwrap();
+            assert_eq!(&*memory, &[0, 7, 0, 0]);
+
+            drop(memory);
+            assert_eq!(DELETED.load(Ordering::Relaxed), 1);
+        }
+
+        #[test]
+        fn memory_copies_slices() {
+            let _fakes = install();
+
+            let memory = Memory::from_slice(b"EDID").unwrap();
+            assert_eq!(&*memory, b"EDID");
+
+            assert!(matches!(
+                Memory::from_slice(&[]),
+                Err(WdfError::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reference_strings_fit_unicode_strings() {
+        assert!(matches!(reference_string(None), Ok(None)));
+
+        let mut buffer = reference_string(Some("Control")).unwrap().unwrap();
+        let unicode = unicode_string(&mut buffer);
+        assert_eq!(unicode.Length, 14);
+        assert_eq!(unicode.MaximumLength, 14);
+        assert_eq!(unicode.Buffer, buffer.as_mut_ptr());
+
+        for bad in ["", "a\0b", "a".repeat(40_000).as_str()] {
+            assert!(matches!(
+                reference_string(Some(bad)),
+                Err(WdfError::InvalidArgument(_))
+            ));
+        }
+    }
+}